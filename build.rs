@@ -0,0 +1,152 @@
+//! Embeds the kernel's function symbols for backtraces (see `src/symbols.rs`),
+//! and with the `multiboot2` feature adds the linker script that places the
+//! Multiboot2 header.
+//!
+//! A kernel can't read its own symbol table, and the addresses are only
+//! known once it is linked, so this takes them from the kernel the previous
+//! build linked. The table lands in a writable section of its own, which
+//! the linker places after the code: a new table does not move a single
+//! function. After a code change the embedded table is one build behind;
+//! the kernel notices and ignores it, and the next build catches up.
+//!
+//! Table layout, little endian: "KSYM", the entry count (u32), the address
+//! of `symbols::resolve` and of `etext` in the kernel the table came from
+//! (u64 each), then `count` entries of address (u64), name offset and name
+//! length (u32 each), sorted by address, then the names.
+//!
+//! It also writes `target/storage-test.img`, the disk the integration tests
+//! run with (see `build/testdisk.rs` and the test arguments in Cargo.toml),
+//! and `target/virtio-console.in`, the line the host types into their
+//! virtconsole.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[path = "tests/storage/image.rs"]
+mod image;
+#[path = "build/testdisk.rs"]
+mod testdisk;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    // OUT_DIR is <target>/<profile>/build/<pkg>-<hash>/out
+    let profile_dir = out_dir.ancestors().nth(3).unwrap().to_path_buf();
+    let kernel = profile_dir.join(env::var("CARGO_PKG_NAME").unwrap());
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_MULTIBOOT2").is_some() {
+        // the header has to sit in the first 32 KiB of the file
+        let script = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("multiboot2.ld");
+        println!("cargo:rerun-if-changed={}", script.display());
+        println!("cargo:rustc-link-arg-bins=-T{}", script.display());
+    }
+    println!("cargo:rerun-if-changed={}", kernel.display());
+    println!("cargo:rerun-if-changed=build/testdisk.rs");
+    println!("cargo:rerun-if-changed=tests/storage/image.rs");
+
+    let table = fs::read(&kernel).ok().and_then(|image| build_table(&image)).unwrap_or_else(empty_table);
+    write_if_changed(&out_dir.join("ksyms.bin"), &table);
+
+    let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("target");
+    fs::create_dir_all(&target).unwrap();
+    write_if_changed(&target.join("storage-test.img"), &testdisk::build());
+    write_if_changed(&target.join("virtio-console.in"), b"echo virtio-ok\r");
+}
+
+/// Rewriting an identical file would only force a rebuild.
+fn write_if_changed(path: &Path, contents: &[u8]) {
+    if fs::read(path).ok().as_deref() != Some(contents) {
+        fs::write(path, contents).unwrap();
+    }
+}
+
+fn empty_table() -> Vec<u8> {
+    let mut table = MAGIC.to_vec();
+    table.extend_from_slice(&[0; 4 + 8 + 8]);
+    table
+}
+
+fn u16_at(b: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(b.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(b: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(b.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(b: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(b.get(at..at + 8)?.try_into().ok()?))
+}
+
+struct Section {
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+}
+
+fn section(image: &[u8], index: usize) -> Option<Section> {
+    let table = u64_at(image, 0x28)? as usize;
+    let entry_size = usize::from(u16_at(image, 0x3a)?);
+    let at = table + index * entry_size;
+    Some(Section {
+        kind: u32_at(image, at + 4)?,
+        offset: u64_at(image, at + 0x18)? as usize,
+        size: u64_at(image, at + 0x20)? as usize,
+        link: u32_at(image, at + 0x28)? as usize,
+    })
+}
+
+fn c_str(bytes: &[u8], at: usize) -> Option<&str> {
+    let rest = bytes.get(at..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&rest[..len]).ok()
+}
+
+/// Function symbols from an ELF64 image's `.symtab`, plus `etext` and the
+/// address of `symbols::resolve`.
+fn build_table(image: &[u8]) -> Option<Vec<u8>> {
+    if image.get(..4)? != b"\x7fELF" || image[4] != 2 {
+        return None;
+    }
+    let count = usize::from(u16_at(image, 0x3c)?);
+    let symtab = (0..count).filter_map(|i| section(image, i)).find(|s| s.kind == SHT_SYMTAB)?;
+    let strtab = section(image, symtab.link)?;
+    let strings = image.get(strtab.offset..strtab.offset + strtab.size)?;
+
+    let mut functions = Vec::new();
+    let (mut marker, mut etext) = (0, 0);
+    for at in (symtab.offset..symtab.offset + symtab.size).step_by(24) {
+        let name = c_str(strings, u32_at(image, at)? as usize)?;
+        let info = *image.get(at + 4)?;
+        let value = u64_at(image, at + 8)?;
+        if name == "etext" {
+            etext = value;
+        } else if info & 0xf == STT_FUNC && value != 0 && !name.is_empty() {
+            if name.ends_with("7symbols7resolve") || name.contains("7symbols7resolve17h") {
+                marker = value;
+            }
+            functions.push((value, name));
+        }
+    }
+    functions.sort();
+    functions.dedup_by_key(|&mut (address, _)| address);
+
+    let mut table = MAGIC.to_vec();
+    table.extend_from_slice(&(functions.len() as u32).to_le_bytes());
+    table.extend_from_slice(&marker.to_le_bytes());
+    table.extend_from_slice(&etext.to_le_bytes());
+    let mut names = Vec::new();
+    for (address, name) in &functions {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+    Some(table)
+}
@@ -0,0 +1,162 @@
+//! Writes the disk image the storage tests boot with: an MBR with one
+//! FAT16 partition holding the files in `tests/storage/image.rs`. Every
+//! field, timestamp and serial number is fixed, so each build produces the
+//! same bytes.
+
+use super::image::*;
+
+const SECTOR: usize = 512;
+const RESERVED: u64 = 1;
+const FAT_COUNT: u64 = 2;
+const ROOT_ENTRIES: u64 = 512;
+const FAT_SECTORS: u64 = ((PARTITION_SECTORS + 2) * 2).div_ceil(SECTOR as u64);
+const ROOT_START: u64 = RESERVED + FAT_COUNT * FAT_SECTORS;
+const DATA_START: u64 = ROOT_START + ROOT_ENTRIES * 32 / SECTOR as u64;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_LONG_NAME: u8 = 0x0f;
+/// 2024-01-01 12:00:00 in FAT's encoding.
+const DATE: u16 = ((2024 - 1980) << 9) | (1 << 5) | 1;
+const TIME: u16 = 12 << 11;
+
+struct Volume {
+    image: Vec<u8>,
+    fat: Vec<u16>,
+}
+
+impl Volume {
+    fn offset(&self, lba: u64) -> usize {
+        ((PARTITION_START + lba) as usize) * SECTOR
+    }
+
+    /// Writes `data` into newly allocated clusters and returns the first.
+    fn store(&mut self, data: &[u8]) -> u16 {
+        if data.is_empty() {
+            return 0;
+        }
+        let first = self.fat.len() as u16;
+        let clusters = data.len().div_ceil(SECTOR);
+        for i in 0..clusters {
+            let cluster = first + i as u16;
+            let next = if i + 1 == clusters { 0xffff } else { cluster + 1 };
+            self.fat.push(next);
+            let at = self.offset(DATA_START + u64::from(cluster) - 2);
+            let chunk = &data[i * SECTOR..data.len().min((i + 1) * SECTOR)];
+            self.image[at..at + chunk.len()].copy_from_slice(chunk);
+        }
+        first
+    }
+}
+
+fn entry(name: &[u8; 11], attributes: u8, cluster: u16, size: usize) -> [u8; 32] {
+    let mut entry = [0u8; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    for at in [14, 22] {
+        entry[at..at + 2].copy_from_slice(&TIME.to_le_bytes());
+    }
+    for at in [16, 18, 24] {
+        entry[at..at + 2].copy_from_slice(&DATE.to_le_bytes());
+    }
+    entry[26..28].copy_from_slice(&cluster.to_le_bytes());
+    entry[28..32].copy_from_slice(&(size as u32).to_le_bytes());
+    entry
+}
+
+/// The long-name entries for `name`, in disk order, then its short entry.
+fn long_entries(name: &str, short: [u8; 32]) -> Vec<[u8; 32]> {
+    let checksum = short[..11].iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c));
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if !units.len().is_multiple_of(13) {
+        units.push(0);
+    }
+    units.resize(units.len().div_ceil(13) * 13, 0xffff);
+    let parts = units.len() / 13;
+    let mut entries = Vec::new();
+    for part in (0..parts).rev() {
+        let mut entry = [0u8; 32];
+        entry[0] = (part + 1) as u8 | if part + 1 == parts { 0x40 } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let slots = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+        for (slot, unit) in slots.zip(&units[part * 13..(part + 1) * 13]) {
+            entry[slot..slot + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entries.push(entry);
+    }
+    entries.push(short);
+    entries
+}
+
+fn big() -> Vec<u8> {
+    (0..BIG_SIZE).map(big_byte).collect()
+}
+
+pub fn build() -> Vec<u8> {
+    let mut volume = Volume { image: vec![0; DISK_SECTORS as usize * SECTOR], fat: vec![0xfff8, 0xffff] };
+
+    // MBR: the signature in place of boot code, then one LBA-only entry
+    let mbr = &mut volume.image[..SECTOR];
+    mbr[..SIGNATURE.len()].copy_from_slice(SIGNATURE);
+    mbr[440..444].copy_from_slice(&0x6265_7272u32.to_le_bytes());
+    let part = &mut mbr[446..462];
+    part[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    part[4] = PARTITION_TYPE;
+    part[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    part[8..12].copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+    part[12..16].copy_from_slice(&(PARTITION_SECTORS as u32).to_le_bytes());
+    mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let readme = volume.store(README);
+    let mut docs = [
+        entry(b".          ", ATTR_DIRECTORY, 0, 0),
+        entry(b"..         ", ATTR_DIRECTORY, 0, 0),
+        entry(b"README  TXT", 0, readme, README.len()),
+    ];
+    let docs_cluster = volume.fat.len() as u16;
+    docs[0][26..28].copy_from_slice(&docs_cluster.to_le_bytes());
+    volume.store(&docs.concat());
+
+    let hello = volume.store(HELLO);
+    let big_cluster = volume.store(&big());
+    let long = volume.store(LONG_CONTENTS);
+    let mut root = vec![
+        entry(LABEL, ATTR_VOLUME_ID, 0, 0),
+        entry(b"HELLO   TXT", 0, hello, HELLO.len()),
+        entry(b"DOCS       ", ATTR_DIRECTORY, docs_cluster, 0),
+        entry(b"BIG     BIN", 0, big_cluster, BIG_SIZE),
+    ];
+    root.extend(long_entries(LONG_NAME, entry(b"LONGFI~1TXT", 0, long, LONG_CONTENTS.len())));
+    let at = volume.offset(ROOT_START);
+    volume.image[at..at + root.len() * 32].copy_from_slice(&root.concat());
+
+    let mut fat: Vec<u8> = volume.fat.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+    fat.resize(FAT_SECTORS as usize * SECTOR, 0);
+    for copy in 0..FAT_COUNT {
+        let at = volume.offset(RESERVED + copy * FAT_SECTORS);
+        volume.image[at..at + fat.len()].copy_from_slice(&fat);
+    }
+
+    let at = volume.offset(0);
+    let boot = &mut volume.image[at..at + SECTOR];
+    boot[..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+    boot[3..11].copy_from_slice(b"BERRYOS ");
+    boot[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&(RESERVED as u16).to_le_bytes());
+    boot[16] = FAT_COUNT as u8;
+    boot[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+    boot[19..21].copy_from_slice(&(PARTITION_SECTORS as u16).to_le_bytes());
+    boot[21] = 0xf8;
+    boot[22..24].copy_from_slice(&(FAT_SECTORS as u16).to_le_bytes());
+    boot[24..26].copy_from_slice(&63u16.to_le_bytes());
+    boot[26..28].copy_from_slice(&255u16.to_le_bytes());
+    boot[28..32].copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+    boot[36] = 0x80;
+    boot[38] = 0x29;
+    boot[39..43].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    boot[43..54].copy_from_slice(LABEL);
+    boot[54..62].copy_from_slice(b"FAT16   ");
+    boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+    volume.image
+}
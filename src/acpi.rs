@@ -0,0 +1,628 @@
+//! ACPI table discovery.
+//!
+//! Finds the RSDP (from a bootloader hint or by scanning the EBDA and the
+//! BIOS area), follows the RSDT/XSDT through the physical memory mapping and
+//! keeps every table whose header and checksum check out. Tables that don't
+//! are reported and skipped, never parsed.
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Once;
+use x86_64::VirtAddr;
+use crate::bytes::{checksum, le_u16, le_u32, le_u64};
+use crate::println;
+
+pub mod aml;
+pub mod madt;
+
+pub use madt::SystemTopology;
+
+#[cfg(test)]
+mod testdata;
+
+/// Size of the common header every system description table starts with.
+pub const SDT_HEADER_LEN: usize = 36;
+/// Sanity limit for a table length read from firmware memory.
+const MAX_TABLE_LEN: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    RsdpNotFound,
+    BadSignature,
+    BadChecksum([u8; 4]),
+    Truncated([u8; 4]),
+    /// `acpi::init` has not run (or failed).
+    NotInitialized,
+    /// No valid table with this signature.
+    Missing([u8; 4]),
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::RsdpNotFound => write!(f, "RSDP not found"),
+            AcpiError::BadSignature => write!(f, "bad signature"),
+            AcpiError::BadChecksum(sig) => write!(f, "{}: bad checksum", Signature(*sig)),
+            AcpiError::Truncated(sig) => write!(f, "{}: truncated table", Signature(*sig)),
+            AcpiError::NotInitialized => write!(f, "ACPI not initialized"),
+            AcpiError::Missing(sig) => write!(f, "no {} table", Signature(*sig)),
+        }
+    }
+}
+
+/// Prints a 4-byte table signature as text.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub [u8; 4]);
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &b in &self.0 {
+            let c = if b.is_ascii_graphic() { b as char } else { '?' };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+// ==========================================================
+// RSDP
+// ==========================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rsdp {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub rsdt_address: u32,
+    /// Only present for ACPI 2.0+ (revision >= 2).
+    pub xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    pub const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+    const V1_LEN: usize = 20;
+    const V2_LEN: usize = 36;
+
+    pub fn parse(bytes: &[u8]) -> Result<Rsdp, AcpiError> {
+        const SIG: [u8; 4] = *b"RSDP";
+        if bytes.len() < Self::V1_LEN {
+            return Err(AcpiError::Truncated(SIG));
+        }
+        if &bytes[0..8] != Self::SIGNATURE {
+            return Err(AcpiError::BadSignature);
+        }
+        if checksum(&bytes[..Self::V1_LEN]) != 0 {
+            return Err(AcpiError::BadChecksum(SIG));
+        }
+        let revision = bytes[15];
+        let mut oem_id = [0u8; 6];
+        oem_id.copy_from_slice(&bytes[9..15]);
+        let rsdt_address = le_u32(bytes, 16).ok_or(AcpiError::Truncated(SIG))?;
+
+        let xsdt_address = if revision >= 2 {
+            let length = le_u32(bytes, 20).ok_or(AcpiError::Truncated(SIG))? as usize;
+            if length < Self::V2_LEN || bytes.len() < length {
+                return Err(AcpiError::Truncated(SIG));
+            }
+            if checksum(&bytes[..length]) != 0 {
+                return Err(AcpiError::BadChecksum(SIG));
+            }
+            le_u64(bytes, 24).filter(|&addr| addr != 0)
+        } else {
+            None
+        };
+
+        Ok(Rsdp { revision, oem_id, rsdt_address, xsdt_address })
+    }
+}
+
+/// Scans `area` on 16-byte boundaries for a valid RSDP and returns its
+/// offset inside the area.
+pub fn find_rsdp_in(area: &[u8]) -> Option<(usize, Rsdp)> {
+    (0..area.len().saturating_sub(Rsdp::V1_LEN - 1))
+        .step_by(16)
+        .find_map(|offset| match Rsdp::parse(&area[offset..]) {
+            Ok(rsdp) => Some((offset, rsdp)),
+            Err(_) => None,
+        })
+}
+
+// ==========================================================
+// CABECERA COMÚN Y VALIDACIÓN
+// ==========================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdtHeader {
+    pub signature: Signature,
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+}
+
+impl SdtHeader {
+    pub fn parse(bytes: &[u8]) -> Option<SdtHeader> {
+        if bytes.len() < SDT_HEADER_LEN {
+            return None;
+        }
+        let mut signature = [0u8; 4];
+        signature.copy_from_slice(&bytes[0..4]);
+        let mut oem_id = [0u8; 6];
+        oem_id.copy_from_slice(&bytes[10..16]);
+        let mut oem_table_id = [0u8; 8];
+        oem_table_id.copy_from_slice(&bytes[16..24]);
+        Some(SdtHeader {
+            signature: Signature(signature),
+            length: le_u32(bytes, 4)?,
+            revision: bytes[8],
+            oem_id,
+            oem_table_id,
+        })
+    }
+}
+
+/// Checks the header length and checksum of the table at the start of
+/// `bytes` and returns the header plus the table trimmed to its length.
+pub fn validate_table(bytes: &[u8]) -> Result<(SdtHeader, &[u8]), AcpiError> {
+    let header = SdtHeader::parse(bytes).ok_or(AcpiError::Truncated(*b"????"))?;
+    let sig = header.signature.0;
+    let length = header.length as usize;
+    if length < SDT_HEADER_LEN || length > bytes.len() {
+        return Err(AcpiError::Truncated(sig));
+    }
+    let table = &bytes[..length];
+    if checksum(table) != 0 {
+        return Err(AcpiError::BadChecksum(sig));
+    }
+    Ok((header, table))
+}
+
+/// Physical addresses listed by an RSDT (4-byte entries) or XSDT (8-byte).
+fn root_entries(table: &[u8], entry_size: usize) -> impl Iterator<Item = u64> + '_ {
+    table[SDT_HEADER_LEN..]
+        .chunks_exact(entry_size)
+        .map(move |chunk| match entry_size {
+            4 => u64::from(le_u32(chunk, 0).unwrap_or(0)),
+            _ => le_u64(chunk, 0).unwrap_or(0),
+        })
+}
+
+// ==========================================================
+// TABLAS CONCRETAS
+// ==========================================================
+
+/// ACPI Generic Address Structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+
+    fn parse(bytes: &[u8], offset: usize) -> Option<GenericAddress> {
+        let raw = bytes.get(offset..offset + 12)?;
+        Some(GenericAddress {
+            address_space: raw[0],
+            bit_width: raw[1],
+            bit_offset: raw[2],
+            access_size: raw[3],
+            address: le_u64(raw, 4)?,
+        })
+    }
+}
+
+/// Fixed ACPI Description Table ("FACP"): power management ports and the
+/// DSDT pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub dsdt: u64,
+    pub sci_interrupt: u16,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub pm1_control_length: u8,
+    pub century_register: u8,
+    pub boot_arch_flags: u16,
+    pub flags: u32,
+    /// ACPI 2.0+ reset register and the value to write to it.
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    pub const SIGNATURE: [u8; 4] = *b"FACP";
+    /// Length of the ACPI 1.0 layout; everything we need lives in it.
+    const V1_LEN: usize = 116;
+    const RESET_SUPPORTED: u32 = 1 << 10;
+
+    pub fn parse(table: &[u8]) -> Result<Fadt, AcpiError> {
+        let truncated = AcpiError::Truncated(Self::SIGNATURE);
+        if table.len() < Self::V1_LEN {
+            return Err(truncated);
+        }
+        let u32_at = |offset| le_u32(table, offset).ok_or(truncated);
+        let flags = u32_at(112)?;
+
+        // X_DSDT wins over the 32-bit field when the table is long enough.
+        let x_dsdt = le_u64(table, 140).filter(|&addr| addr != 0);
+        let dsdt = x_dsdt.unwrap_or(u64::from(u32_at(40)?));
+
+        let reset = match (GenericAddress::parse(table, 116), table.get(128)) {
+            (Some(reg), Some(&value)) if flags & Self::RESET_SUPPORTED != 0 => Some((reg, value)),
+            _ => None,
+        };
+
+        Ok(Fadt {
+            dsdt,
+            sci_interrupt: le_u16(table, 46).ok_or(truncated)?,
+            smi_command: u32_at(48)?,
+            acpi_enable: table[52],
+            acpi_disable: table[53],
+            pm1a_event_block: u32_at(56)?,
+            pm1b_event_block: u32_at(60)?,
+            pm1a_control_block: u32_at(64)?,
+            pm1b_control_block: u32_at(68)?,
+            pm_timer_block: u32_at(76)?,
+            pm1_control_length: table[89],
+            century_register: table[108],
+            boot_arch_flags: le_u16(table, 109).ok_or(truncated)?,
+            flags,
+            reset,
+        })
+    }
+}
+
+/// Multiple APIC Description Table ("APIC"), fixed part only; the
+/// entries are in `madt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: u32,
+    pub flags: u32,
+}
+
+impl Madt {
+    pub const SIGNATURE: [u8; 4] = *b"APIC";
+    /// The system also has dual 8259 PICs that must be masked.
+    pub const PCAT_COMPAT: u32 = 1;
+
+    pub fn parse(table: &[u8]) -> Result<Madt, AcpiError> {
+        let truncated = AcpiError::Truncated(Self::SIGNATURE);
+        Ok(Madt {
+            local_apic_address: le_u32(table, 36).ok_or(truncated)?,
+            flags: le_u32(table, 40).ok_or(truncated)?,
+        })
+    }
+}
+
+/// HPET description table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub min_periodic_tick: u16,
+}
+
+impl Hpet {
+    pub const SIGNATURE: [u8; 4] = *b"HPET";
+
+    pub fn parse(table: &[u8]) -> Result<Hpet, AcpiError> {
+        let truncated = AcpiError::Truncated(Self::SIGNATURE);
+        Ok(Hpet {
+            event_timer_block_id: le_u32(table, 36).ok_or(truncated)?,
+            base_address: GenericAddress::parse(table, 40).ok_or(truncated)?,
+            hpet_number: *table.get(52).ok_or(truncated)?,
+            min_periodic_tick: le_u16(table, 53).ok_or(truncated)?,
+        })
+    }
+
+    pub fn pci_vendor_id(&self) -> u16 {
+        (self.event_timer_block_id >> 16) as u16
+    }
+
+    pub fn comparator_count(&self) -> u8 {
+        ((self.event_timer_block_id >> 8) & 0x1f) as u8 + 1
+    }
+
+    pub fn counter_is_64bit(&self) -> bool {
+        self.event_timer_block_id & (1 << 13) != 0
+    }
+}
+
+// ==========================================================
+// DESCUBRIMIENTO
+// ==========================================================
+
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+    pub header: SdtHeader,
+    pub phys_addr: u64,
+    pub data: &'static [u8],
+}
+
+#[derive(Debug)]
+pub struct AcpiTables {
+    pub rsdp: Rsdp,
+    pub rsdp_addr: u64,
+    pub tables: Vec<TableInfo>,
+    /// Tables that failed validation, with the reason.
+    pub skipped: Vec<(u64, AcpiError)>,
+    pub fadt: Option<Fadt>,
+    pub madt: Option<Madt>,
+    /// Parsed from the MADT entries.
+    pub topology: Option<SystemTopology>,
+    pub hpet: Option<Hpet>,
+}
+
+impl AcpiTables {
+    /// Raw bytes of the first valid table with this signature.
+    pub fn find(&self, signature: &[u8; 4]) -> Option<&TableInfo> {
+        self.tables.iter().find(|t| &t.header.signature.0 == signature)
+    }
+
+    /// Walks the root table and validates every entry. `read(phys, len)`
+    /// must return the bytes at that physical address, or `None` when the
+    /// range is not accessible.
+    pub fn discover<F>(rsdp: Rsdp, rsdp_addr: u64, read: F) -> Result<AcpiTables, AcpiError>
+    where
+        F: Fn(u64, usize) -> Option<&'static [u8]>,
+    {
+        let (root_addr, entry_size) = match rsdp.xsdt_address {
+            Some(xsdt) => (xsdt, 8),
+            None => (u64::from(rsdp.rsdt_address), 4),
+        };
+        let (_, root) = read_table(&read, root_addr)?;
+
+        let mut acpi = AcpiTables {
+            rsdp,
+            rsdp_addr,
+            tables: Vec::new(),
+            skipped: Vec::new(),
+            fadt: None,
+            madt: None,
+            topology: None,
+            hpet: None,
+        };
+
+        for phys_addr in root_entries(root, entry_size) {
+            let (header, data) = match read_table(&read, phys_addr) {
+                Ok(table) => table,
+                Err(err) => {
+                    acpi.skipped.push((phys_addr, err));
+                    continue;
+                }
+            };
+            let parsed = match header.signature.0 {
+                Fadt::SIGNATURE => Fadt::parse(data).map(|t| acpi.fadt = Some(t)),
+                Madt::SIGNATURE => Madt::parse(data)
+                    .and_then(|t| Ok((t, SystemTopology::parse(data)?)))
+                    .map(|(t, topology)| {
+                        acpi.madt = Some(t);
+                        acpi.topology = Some(topology);
+                    }),
+                Hpet::SIGNATURE => Hpet::parse(data).map(|t| acpi.hpet = Some(t)),
+                _ => Ok(()),
+            };
+            match parsed {
+                Ok(()) => acpi.tables.push(TableInfo { header, phys_addr, data }),
+                Err(err) => acpi.skipped.push((phys_addr, err)),
+            }
+        }
+        Ok(acpi)
+    }
+}
+
+fn read_table<F>(read: &F, phys_addr: u64) -> Result<(SdtHeader, &'static [u8]), AcpiError>
+where
+    F: Fn(u64, usize) -> Option<&'static [u8]>,
+{
+    let header_bytes = read(phys_addr, SDT_HEADER_LEN).ok_or(AcpiError::Truncated(*b"????"))?;
+    let header = SdtHeader::parse(header_bytes).ok_or(AcpiError::Truncated(*b"????"))?;
+    let length = header.length as usize;
+    if !(SDT_HEADER_LEN..=MAX_TABLE_LEN).contains(&length) {
+        return Err(AcpiError::Truncated(header.signature.0));
+    }
+    let bytes = read(phys_addr, length).ok_or(AcpiError::Truncated(header.signature.0))?;
+    validate_table(bytes)
+}
+
+static TABLES: Once<AcpiTables> = Once::new();
+static PHYS_OFFSET: Once<VirtAddr> = Once::new();
+
+/// Returns a slice over physical memory through the bootloader's mapping.
+///
+/// The bootloader maps all physical memory at `physical_memory_offset`, so
+/// any address below the top of RAM is readable. ACPI tables live there.
+pub fn phys_bytes(phys_addr: u64, len: usize) -> Option<&'static [u8]> {
+    let offset = PHYS_OFFSET.get()?;
+    let virt = *offset + phys_addr;
+    Some(unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), len) })
+}
+
+fn locate_rsdp(rsdp_hint: Option<u64>) -> Result<(u64, Rsdp), AcpiError> {
+    if let Some(addr) = rsdp_hint {
+        let bytes = phys_bytes(addr, Rsdp::V2_LEN).ok_or(AcpiError::RsdpNotFound)?;
+        return Rsdp::parse(bytes).map(|rsdp| (addr, rsdp));
+    }
+
+    // First KiB of the EBDA, whose segment is stored at 0x40E in the BDA.
+    let ebda_segment = phys_bytes(0x40e, 2).and_then(|b| le_u16(b, 0)).unwrap_or(0);
+    let ebda = u64::from(ebda_segment) << 4;
+    let areas = [(ebda, 1024), (0xe0000, 0x20000)];
+    for &(start, len) in areas.iter().filter(|&&(start, _)| start != 0) {
+        let area = phys_bytes(start, len).ok_or(AcpiError::RsdpNotFound)?;
+        if let Some((offset, rsdp)) = find_rsdp_in(area) {
+            return Ok((start + offset as u64, rsdp));
+        }
+    }
+    Err(AcpiError::RsdpNotFound)
+}
+
+/// Locates and validates the ACPI tables. Call once the heap is up.
+pub fn init(
+    physical_memory_offset: VirtAddr,
+    rsdp_hint: Option<u64>,
+) -> Result<&'static AcpiTables, AcpiError> {
+    PHYS_OFFSET.call_once(|| physical_memory_offset);
+    let (rsdp_addr, rsdp) = locate_rsdp(rsdp_hint)?;
+    let tables = AcpiTables::discover(rsdp, rsdp_addr, phys_bytes)?;
+    Ok(TABLES.call_once(|| tables))
+}
+
+/// Reads and validates a table that isn't listed in the root table, such as
+/// the DSDT referenced from the FADT.
+pub fn load_table(phys_addr: u64) -> Result<(SdtHeader, &'static [u8]), AcpiError> {
+    if PHYS_OFFSET.get().is_none() {
+        return Err(AcpiError::NotInitialized);
+    }
+    read_table(&phys_bytes, phys_addr)
+}
+
+pub fn tables() -> Result<&'static AcpiTables, AcpiError> {
+    TABLES.get().ok_or(AcpiError::NotInitialized)
+}
+
+/// CPUs and interrupt controllers from the MADT.
+pub fn topology() -> Result<&'static SystemTopology, AcpiError> {
+    tables()?.topology.as_ref().ok_or(AcpiError::Missing(Madt::SIGNATURE))
+}
+
+fn oem_str(oem: &[u8]) -> &str {
+    core::str::from_utf8(oem).unwrap_or("?").trim_end()
+}
+
+pub fn print_summary() {
+    let acpi = match tables() {
+        Ok(acpi) => acpi,
+        Err(err) => {
+            println!("ACPI: {}", err);
+            return;
+        }
+    };
+    let root = if acpi.rsdp.xsdt_address.is_some() { "XSDT" } else { "RSDT" };
+    println!(
+        "ACPI: RSDP at {:#x} rev {} OEM {} using {}",
+        acpi.rsdp_addr, acpi.rsdp.revision, oem_str(&acpi.rsdp.oem_id), root
+    );
+    for table in &acpi.tables {
+        println!(
+            "ACPI: {} at {:#x} len {} rev {}",
+            table.header.signature, table.phys_addr, table.header.length, table.header.revision
+        );
+    }
+    for (addr, err) in &acpi.skipped {
+        println!("ACPI: skipped table at {:#x}: {}", addr, err);
+    }
+    if let Some(topology) = &acpi.topology {
+        println!("{} CPUs detected ({} enabled)", topology.cpus.len(), topology.enabled_cpus());
+    }
+}
+
+//test case
+#[cfg(test)]
+fn fake_phys(phys_addr: u64, len: usize) -> Option<&'static [u8]> {
+    let blob: &'static [u8] = match phys_addr {
+        0x07fe_14d5 => &testdata::RSDT,
+        0x07fe_1370 => &testdata::FADT,
+        0x07fe_13e4 => &testdata::MADT_SMP1,
+        0x07fe_1474 => &testdata::HPET,
+        _ => return None,
+    };
+    blob.get(..len)
+}
+
+#[test_case]
+fn test_rsdp_parse() {
+    let rsdp = Rsdp::parse(&testdata::RSDP).unwrap();
+    assert_eq!(rsdp.revision, 0);
+    assert_eq!(&rsdp.oem_id, b"BOCHS ");
+    assert_eq!(rsdp.rsdt_address, 0x07fe_14d5);
+    assert_eq!(rsdp.xsdt_address, None);
+}
+
+#[test_case]
+fn test_rsdp_bad_checksum() {
+    let mut bytes = testdata::RSDP;
+    bytes[16] ^= 0xff;
+    assert_eq!(Rsdp::parse(&bytes), Err(AcpiError::BadChecksum(*b"RSDP")));
+}
+
+#[test_case]
+fn test_find_rsdp_in_area() {
+    let mut area = [0u8; 256];
+    area[0x40..0x40 + testdata::RSDP.len()].copy_from_slice(&testdata::RSDP);
+    let (offset, rsdp) = find_rsdp_in(&area).unwrap();
+    assert_eq!(offset, 0x40);
+    assert_eq!(rsdp.rsdt_address, 0x07fe_14d5);
+    assert!(find_rsdp_in(&area[0x48..]).is_none());
+}
+
+#[test_case]
+fn test_fadt_fields() {
+    let (header, table) = validate_table(&testdata::FADT).unwrap();
+    assert_eq!(header.signature.0, Fadt::SIGNATURE);
+    let fadt = Fadt::parse(table).unwrap();
+    assert_eq!(fadt.dsdt, 0x07fe_0040);
+    assert_eq!(fadt.sci_interrupt, 9);
+    assert_eq!(fadt.smi_command, 0xb2);
+    assert_eq!(fadt.acpi_enable, 0xf1);
+    assert_eq!(fadt.pm1a_control_block, 0x604);
+    assert_eq!(fadt.pm1b_control_block, 0);
+    assert_eq!(fadt.pm_timer_block, 0x608);
+    assert_eq!(fadt.century_register, 0x32);
+    assert_eq!(fadt.reset, None);
+}
+
+#[test_case]
+fn test_madt_and_hpet_fields() {
+    let (_, madt) = validate_table(&testdata::MADT_SMP1).unwrap();
+    let madt = Madt::parse(madt).unwrap();
+    assert_eq!(madt.local_apic_address, 0xfee0_0000);
+    assert_eq!(madt.flags & Madt::PCAT_COMPAT, Madt::PCAT_COMPAT);
+
+    let (_, hpet) = validate_table(&testdata::HPET).unwrap();
+    let hpet = Hpet::parse(hpet).unwrap();
+    assert_eq!(hpet.base_address.address, 0xfed0_0000);
+    assert_eq!(hpet.base_address.address_space, GenericAddress::SYSTEM_MEMORY);
+    assert_eq!(hpet.pci_vendor_id(), 0x8086);
+    assert_eq!(hpet.comparator_count(), 3);
+    assert!(hpet.counter_is_64bit());
+}
+
+#[test_case]
+fn test_malformed_tables_rejected() {
+    let mut corrupt = testdata::HPET;
+    corrupt[40] ^= 1;
+    assert_eq!(validate_table(&corrupt).unwrap_err(), AcpiError::BadChecksum(*b"HPET"));
+    assert_eq!(
+        validate_table(&testdata::FADT[..100]).unwrap_err(),
+        AcpiError::Truncated(*b"FACP")
+    );
+    assert!(Fadt::parse(&testdata::FADT[..80]).is_err());
+}
+
+#[test_case]
+fn test_discover_from_rsdt() {
+    let rsdp = Rsdp::parse(&testdata::RSDP).unwrap();
+    let acpi = AcpiTables::discover(rsdp, 0xf5b00, fake_phys).unwrap();
+    assert_eq!(acpi.tables.len(), 3);
+    assert!(acpi.skipped.is_empty());
+    assert!(acpi.fadt.is_some() && acpi.madt.is_some() && acpi.hpet.is_some());
+    assert_eq!(acpi.topology.as_ref().map(|t| t.enabled_cpus()), Some(1));
+    assert_eq!(acpi.find(b"HPET").unwrap().phys_addr, 0x07fe_1474);
+}
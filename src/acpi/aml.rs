@@ -0,0 +1,153 @@
+//! Just enough AML to pull the `\_S5` sleep type values out of the DSDT.
+//!
+//! Full AML interpretation is out of scope: we look for a `Name(_S5, Package
+//! {...})` definition and decode the first two integer elements, which is how
+//! essentially every firmware encodes it.
+
+use core::fmt;
+
+const NAME_OP: u8 = 0x08;
+const ROOT_CHAR: u8 = 0x5c;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const QWORD_PREFIX: u8 = 0x0e;
+const ONES_OP: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmlError {
+    /// No `_S5_` name definition in the table.
+    NotFound,
+    /// `_S5_` exists but isn't a package (e.g. defined through a method).
+    NotAPackage,
+    /// The package ended before two integer elements were decoded.
+    Truncated,
+    /// An element uses an encoding we don't decode.
+    UnsupportedElement(u8),
+}
+
+impl fmt::Display for AmlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmlError::NotFound => write!(f, "\\_S5 not found in DSDT"),
+            AmlError::NotAPackage => write!(f, "\\_S5 is not a package"),
+            AmlError::Truncated => write!(f, "\\_S5 package truncated"),
+            AmlError::UnsupportedElement(op) => write!(f, "unsupported AML element {:#04x}", op),
+        }
+    }
+}
+
+/// Decodes a PkgLength at the start of `bytes`; returns (length, bytes used).
+pub fn pkg_length(bytes: &[u8]) -> Option<(usize, usize)> {
+    let lead = *bytes.first()?;
+    let follow = usize::from(lead >> 6);
+    if follow == 0 {
+        return Some((usize::from(lead & 0x3f), 1));
+    }
+    let mut length = usize::from(lead & 0x0f);
+    for i in 0..follow {
+        length |= usize::from(*bytes.get(1 + i)?) << (4 + 8 * i);
+    }
+    Some((length, 1 + follow))
+}
+
+/// Decodes one integer data object; returns (value, bytes used).
+fn integer(bytes: &[u8]) -> Result<(u64, usize), AmlError> {
+    let op = *bytes.first().ok_or(AmlError::Truncated)?;
+    let width = match op {
+        ZERO_OP => return Ok((0, 1)),
+        ONE_OP => return Ok((1, 1)),
+        ONES_OP => return Ok((u64::MAX, 1)),
+        BYTE_PREFIX => 1,
+        WORD_PREFIX => 2,
+        DWORD_PREFIX => 4,
+        QWORD_PREFIX => 8,
+        other => return Err(AmlError::UnsupportedElement(other)),
+    };
+    let raw = bytes.get(1..1 + width).ok_or(AmlError::Truncated)?;
+    let value = raw.iter().rev().fold(0u64, |acc, &b| (acc << 8) | u64::from(b));
+    Ok((value, 1 + width))
+}
+
+/// Returns `(SLP_TYPa, SLP_TYPb)` from the `\_S5` package in `aml`.
+pub fn find_s5_sleep_types(aml: &[u8]) -> Result<(u8, u8), AmlError> {
+    let position = aml
+        .windows(5)
+        .enumerate()
+        .find(|(i, window)| {
+            &window[1..] == b"_S5_"
+                && (window[0] == NAME_OP
+                    || (window[0] == ROOT_CHAR && *i > 0 && aml[i - 1] == NAME_OP))
+        })
+        .map(|(i, _)| i + 5)
+        .ok_or(AmlError::NotFound)?;
+
+    let rest = &aml[position..];
+    if rest.first() != Some(&PACKAGE_OP) {
+        return Err(AmlError::NotAPackage);
+    }
+    let (length, used) = pkg_length(&rest[1..]).ok_or(AmlError::Truncated)?;
+    // PkgLength counts its own bytes but not the PackageOp.
+    let package = rest.get(1 + used..1 + length).ok_or(AmlError::Truncated)?;
+    let (&num_elements, mut elements) = package.split_first().ok_or(AmlError::Truncated)?;
+    if num_elements < 2 {
+        return Err(AmlError::Truncated);
+    }
+
+    let mut values = [0u8; 2];
+    for value in values.iter_mut() {
+        let (number, used) = integer(elements)?;
+        *value = (number & 0x7) as u8;
+        elements = &elements[used..];
+    }
+    Ok((values[0], values[1]))
+}
+
+//test case
+#[test_case]
+fn test_s5_qemu_zero_ops() {
+    // SeaBIOS / QEMU: Name (_S5, Package (0x04) { Zero, Zero, Zero, Zero })
+    let dsdt = [
+        0x10, 0x05, 0x5c, 0x00, 0x08, 0x5f, 0x53, 0x35, 0x5f, 0x12, 0x06, 0x04, 0x00, 0x00,
+        0x00, 0x00,
+    ];
+    assert_eq!(find_s5_sleep_types(&dsdt), Ok((0, 0)));
+}
+
+#[test_case]
+fn test_s5_byte_prefix_root_name() {
+    // Name (\_S5, Package (0x04) { 0x07, 0x07, Zero, Zero }) as emitted by
+    // many desktop firmwares.
+    let dsdt = [
+        0x08, 0x5c, 0x5f, 0x53, 0x35, 0x5f, 0x12, 0x08, 0x04, 0x0a, 0x07, 0x0a, 0x07, 0x00,
+        0x00,
+    ];
+    assert_eq!(find_s5_sleep_types(&dsdt), Ok((7, 7)));
+}
+
+#[test_case]
+fn test_s5_mixed_encodings_and_long_pkglength() {
+    // Two-byte PkgLength (0x40 | 0x0b, 0x00) and a word element.
+    let dsdt = [
+        0x08, 0x5f, 0x53, 0x35, 0x5f, 0x12, 0x4b, 0x00, 0x02, 0x0b, 0x05, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x00,
+    ];
+    assert_eq!(find_s5_sleep_types(&dsdt), Ok((5, 1)));
+    assert_eq!(pkg_length(&[0x4b, 0x00]), Some((0x0b, 2)));
+    assert_eq!(pkg_length(&[0x81, 0x23]), Some((0x231, 2)));
+}
+
+#[test_case]
+fn test_s5_errors() {
+    assert_eq!(find_s5_sleep_types(&[0x00; 16]), Err(AmlError::NotFound));
+    // Method (_S5_, 0) is not something we evaluate.
+    let method = [0x14, 0x5f, 0x53, 0x35, 0x5f, 0x00];
+    assert_eq!(find_s5_sleep_types(&method), Err(AmlError::NotFound));
+    let not_package = [0x08, 0x5f, 0x53, 0x35, 0x5f, 0x0a, 0x05];
+    assert_eq!(find_s5_sleep_types(&not_package), Err(AmlError::NotAPackage));
+    let truncated = [0x08, 0x5f, 0x53, 0x35, 0x5f, 0x12, 0x06, 0x04, 0x0a];
+    assert_eq!(find_s5_sleep_types(&truncated), Err(AmlError::Truncated));
+}
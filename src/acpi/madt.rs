@@ -0,0 +1,255 @@
+//! MADT interrupt controller structures: which CPUs exist, where the I/O
+//! APICs are and how ISA IRQs are routed to them.
+
+use alloc::vec::Vec;
+use crate::bytes::{le_u16, le_u32, le_u64};
+use super::{AcpiError, Madt, SDT_HEADER_LEN};
+
+/// Entries start after the header, the local APIC address and the flags.
+const ENTRIES_OFFSET: usize = SDT_HEADER_LEN + 8;
+
+const TYPE_LOCAL_APIC: u8 = 0;
+const TYPE_IO_APIC: u8 = 1;
+const TYPE_INTERRUPT_OVERRIDE: u8 = 2;
+const TYPE_LOCAL_APIC_ADDRESS: u8 = 5;
+const TYPE_LOCAL_X2APIC: u8 = 9;
+
+const LAPIC_ENABLED: u32 = 1 << 0;
+/// ACPI 6.3: the CPU is disabled now but may be brought online.
+const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    pub online_capable: bool,
+    /// Described by an x2APIC entry (APIC id may exceed 255).
+    pub x2apic: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// First global system interrupt this I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    /// ISA IRQ.
+    pub source: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3.
+    pub flags: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry<'a> {
+    Cpu(Cpu),
+    IoApic(IoApic),
+    InterruptOverride(InterruptOverride),
+    LocalApicAddress(u64),
+    /// Any other type, kept raw (type, bytes including the 2-byte header).
+    Other(u8, &'a [u8]),
+}
+
+/// Iterator over the variable-length entries. Stops with an error if an
+/// entry's length is impossible, since nothing after it can be trusted.
+pub struct Entries<'a> {
+    table: &'a [u8],
+    offset: usize,
+}
+
+pub fn entries(table: &[u8]) -> Entries<'_> {
+    Entries { table, offset: ENTRIES_OFFSET }
+}
+
+fn parse_entry(kind: u8, bytes: &[u8]) -> Option<Entry<'_>> {
+    let entry = match kind {
+        TYPE_LOCAL_APIC => {
+            let flags = le_u32(bytes, 4)?;
+            Entry::Cpu(Cpu {
+                processor_id: u32::from(*bytes.get(2)?),
+                apic_id: u32::from(*bytes.get(3)?),
+                enabled: flags & LAPIC_ENABLED != 0,
+                online_capable: flags & LAPIC_ONLINE_CAPABLE != 0,
+                x2apic: false,
+            })
+        }
+        TYPE_LOCAL_X2APIC => {
+            let flags = le_u32(bytes, 8)?;
+            Entry::Cpu(Cpu {
+                processor_id: le_u32(bytes, 12)?,
+                apic_id: le_u32(bytes, 4)?,
+                enabled: flags & LAPIC_ENABLED != 0,
+                online_capable: flags & LAPIC_ONLINE_CAPABLE != 0,
+                x2apic: true,
+            })
+        }
+        TYPE_IO_APIC => Entry::IoApic(IoApic {
+            id: *bytes.get(2)?,
+            address: le_u32(bytes, 4)?,
+            gsi_base: le_u32(bytes, 8)?,
+        }),
+        TYPE_INTERRUPT_OVERRIDE => Entry::InterruptOverride(InterruptOverride {
+            bus: *bytes.get(2)?,
+            source: *bytes.get(3)?,
+            gsi: le_u32(bytes, 4)?,
+            flags: le_u16(bytes, 8)?,
+        }),
+        TYPE_LOCAL_APIC_ADDRESS => Entry::LocalApicAddress(le_u64(bytes, 4)?),
+        other => Entry::Other(other, bytes),
+    };
+    Some(entry)
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, AcpiError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.table.get(self.offset..)?;
+        if rest.is_empty() {
+            return None;
+        }
+        let truncated = AcpiError::Truncated(Madt::SIGNATURE);
+        let len = match rest.get(1) {
+            Some(&len) if len >= 2 && usize::from(len) <= rest.len() => usize::from(len),
+            _ => {
+                self.offset = self.table.len();
+                return Some(Err(truncated));
+            }
+        };
+        self.offset += len;
+        let bytes = &rest[..len];
+        // a known type that is too short for its fields is malformed too
+        Some(parse_entry(bytes[0], bytes).ok_or(truncated))
+    }
+}
+
+/// Everything the MADT says about CPUs and interrupt routing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemTopology {
+    pub local_apic_address: u64,
+    pub pcat_compat: bool,
+    pub cpus: Vec<Cpu>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    /// Entries of types we don't use.
+    pub skipped_entries: usize,
+}
+
+impl SystemTopology {
+    /// Parses a validated MADT (header included).
+    pub fn parse(table: &[u8]) -> Result<SystemTopology, AcpiError> {
+        let madt = Madt::parse(table)?;
+        let mut topology = SystemTopology {
+            local_apic_address: u64::from(madt.local_apic_address),
+            pcat_compat: madt.flags & Madt::PCAT_COMPAT != 0,
+            cpus: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            skipped_entries: 0,
+        };
+        for entry in entries(table) {
+            match entry? {
+                Entry::Cpu(cpu) => topology.cpus.push(cpu),
+                Entry::IoApic(io_apic) => topology.io_apics.push(io_apic),
+                Entry::InterruptOverride(over) => topology.overrides.push(over),
+                Entry::LocalApicAddress(address) => topology.local_apic_address = address,
+                Entry::Other(..) => topology.skipped_entries += 1,
+            }
+        }
+        Ok(topology)
+    }
+
+    pub fn enabled_cpus(&self) -> usize {
+        self.cpus.iter().filter(|cpu| cpu.enabled).count()
+    }
+
+    /// The GSI an ISA IRQ arrives on, after overrides.
+    pub fn isa_irq_gsi(&self, irq: u8) -> u32 {
+        self.overrides
+            .iter()
+            .find(|o| o.bus == 0 && o.source == irq)
+            .map_or(u32::from(irq), |o| o.gsi)
+    }
+}
+
+//test case
+#[cfg(test)]
+fn topology_of(blob: &[u8]) -> SystemTopology {
+    let (_, table) = super::validate_table(blob).unwrap();
+    SystemTopology::parse(table).unwrap()
+}
+
+#[test_case]
+fn test_madt_smp1() {
+    let topology = topology_of(&super::testdata::MADT_SMP1);
+    assert_eq!(topology.local_apic_address, 0xfee0_0000);
+    assert!(topology.pcat_compat);
+    assert_eq!(topology.cpus, [Cpu { processor_id: 0, apic_id: 0, enabled: true, online_capable: false, x2apic: false }]);
+    assert_eq!(topology.io_apics, [IoApic { id: 0, address: 0xfec0_0000, gsi_base: 0 }]);
+    assert_eq!(topology.overrides.len(), 5);
+    assert_eq!(topology.isa_irq_gsi(0), 2);
+    assert_eq!(topology.isa_irq_gsi(1), 1);
+    assert_eq!(topology.overrides[1].flags, 0x0d);
+    // the local APIC NMI entry
+    assert_eq!(topology.skipped_entries, 1);
+}
+
+#[test_case]
+fn test_madt_smp2_and_smp4() {
+    let smp2 = topology_of(&super::testdata::MADT_SMP2);
+    assert_eq!((smp2.cpus.len(), smp2.enabled_cpus()), (2, 2));
+    let smp4 = topology_of(&super::testdata::MADT_SMP4);
+    assert_eq!((smp4.cpus.len(), smp4.enabled_cpus()), (4, 4));
+    let apic_ids: Vec<u32> = smp4.cpus.iter().map(|cpu| cpu.apic_id).collect();
+    assert_eq!(apic_ids, [0, 1, 2, 3]);
+    assert_eq!(smp4.io_apics.len(), 1);
+}
+
+#[cfg(test)]
+fn madt_with_entries(entries: &[&[u8]]) -> Vec<u8> {
+    let mut table = alloc::vec![0u8; ENTRIES_OFFSET];
+    table[..4].copy_from_slice(&Madt::SIGNATURE);
+    table[36..40].copy_from_slice(&0xfee0_0000u32.to_le_bytes());
+    for entry in entries {
+        table.extend_from_slice(entry);
+    }
+    let len = table.len() as u32;
+    table[4..8].copy_from_slice(&len.to_le_bytes());
+    table
+}
+
+#[test_case]
+fn test_madt_x2apic_and_unknown_entries() {
+    let x2apic = [9, 16, 0, 0, 0x00, 0x01, 0, 0, 2, 0, 0, 0, 7, 0, 0, 0];
+    // an unknown type with a payload that looks like a local APIC entry
+    let unknown = [0x7f, 12, 0, 5, 5, 0, 0, 0, 1, 0, 0, 0];
+    let disabled = [0, 8, 1, 1, 0, 0, 0, 0];
+    let table = madt_with_entries(&[&x2apic, &unknown, &disabled]);
+    let topology = SystemTopology::parse(&table).unwrap();
+    assert_eq!(topology.cpus, [
+        Cpu { processor_id: 7, apic_id: 256, enabled: false, online_capable: true, x2apic: true },
+        Cpu { processor_id: 1, apic_id: 1, enabled: false, online_capable: false, x2apic: false },
+    ]);
+    assert_eq!(topology.enabled_cpus(), 0);
+    assert_eq!(topology.skipped_entries, 1);
+}
+
+#[test_case]
+fn test_madt_bad_entry_length() {
+    let zero_len = [0x7f, 0];
+    assert_eq!(SystemTopology::parse(&madt_with_entries(&[&zero_len])),
+        Err(AcpiError::Truncated(Madt::SIGNATURE)));
+    // runs past the end of the table
+    let long = [0, 20, 0, 0, 1, 0, 0, 0];
+    assert_eq!(SystemTopology::parse(&madt_with_entries(&[&long])),
+        Err(AcpiError::Truncated(Madt::SIGNATURE)));
+    // a local APIC entry too short for its flags
+    let short = [0, 4, 0, 0];
+    assert!(entries(&madt_with_entries(&[&short])).next().unwrap().is_err());
+}
@@ -0,0 +1,80 @@
+//! ACPI tables laid out the way QEMU builds them for `-machine pc` (SeaBIOS,
+//! OEM id "BOCHS "), used as fixtures for the parser tests. The RSDT points
+//! at the FADT, MADT and HPET in that order.
+
+pub static RSDP: [u8; 20] = [
+    0x52, 0x53, 0x44, 0x20, 0x50, 0x54, 0x52, 0x20, 0x64, 0x42, 0x4f, 0x43,
+    0x48, 0x53, 0x20, 0x00, 0xd5, 0x14, 0xfe, 0x07,
+];
+
+pub static RSDT: [u8; 48] = [
+    0x52, 0x53, 0x44, 0x54, 0x30, 0x00, 0x00, 0x00, 0x01, 0x59, 0x42, 0x4f,
+    0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x52, 0x53, 0x44, 0x54,
+    0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+    0x70, 0x13, 0xfe, 0x07, 0xe4, 0x13, 0xfe, 0x07, 0x74, 0x14, 0xfe, 0x07,
+];
+
+pub static FADT: [u8; 116] = [
+    0x46, 0x41, 0x43, 0x50, 0x74, 0x00, 0x00, 0x00, 0x01, 0x58, 0x42, 0x4f,
+    0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x46, 0x41, 0x43, 0x50,
+    0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0xfe, 0x07, 0x40, 0x00, 0xfe, 0x07, 0x00, 0x00, 0x09, 0x00,
+    0xb2, 0x00, 0x00, 0x00, 0xf1, 0xf0, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x04, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x08, 0x06, 0x00, 0x00, 0xe0, 0xaf, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x04, 0x02, 0x00, 0x04, 0x04, 0x00, 0x00, 0x00,
+    0xff, 0x0f, 0xff, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x32, 0x00, 0x00, 0x00, 0xa5, 0x80, 0x00, 0x00,
+];
+
+pub static MADT_SMP1: [u8; 120] = [
+    0x41, 0x50, 0x49, 0x43, 0x78, 0x00, 0x00, 0x00, 0x01, 0xed, 0x42, 0x4f,
+    0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x41, 0x50, 0x49, 0x43,
+    0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x01, 0x0c, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xfe,
+    0x00, 0x00, 0x00, 0x00, 0x02, 0x0a, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x02, 0x0a, 0x00, 0x05, 0x05, 0x00, 0x00, 0x00, 0x0d, 0x00,
+    0x02, 0x0a, 0x00, 0x09, 0x09, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a,
+    0x00, 0x0a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x0b,
+    0x0b, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x04, 0x06, 0xff, 0x00, 0x00, 0x01,
+];
+
+/// QEMU `-smp 2`.
+pub static MADT_SMP2: [u8; 128] = [
+    0x41, 0x50, 0x49, 0x43, 0x80, 0x00, 0x00, 0x00, 0x01, 0xda, 0x42, 0x4f,
+    0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x41, 0x50, 0x49, 0x43,
+    0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00,
+    0x01, 0x0c, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xfe, 0x00, 0x00, 0x00, 0x00,
+    0x02, 0x0a, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x0a,
+    0x00, 0x05, 0x05, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x09,
+    0x09, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x0a, 0x0a, 0x00,
+    0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x0b, 0x0b, 0x00, 0x00, 0x00,
+    0x0d, 0x00, 0x04, 0x06, 0xff, 0x00, 0x00, 0x01,
+];
+
+/// QEMU `-smp 4`.
+pub static MADT_SMP4: [u8; 144] = [
+    0x41, 0x50, 0x49, 0x43, 0x90, 0x00, 0x00, 0x00, 0x01, 0xae, 0x42, 0x4f,
+    0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x41, 0x50, 0x49, 0x43,
+    0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x08, 0x02, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x03, 0x03,
+    0x01, 0x00, 0x00, 0x00, 0x01, 0x0c, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xfe,
+    0x00, 0x00, 0x00, 0x00, 0x02, 0x0a, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x02, 0x0a, 0x00, 0x05, 0x05, 0x00, 0x00, 0x00, 0x0d, 0x00,
+    0x02, 0x0a, 0x00, 0x09, 0x09, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a,
+    0x00, 0x0a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x0b,
+    0x0b, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x04, 0x06, 0xff, 0x00, 0x00, 0x01,
+];
+
+pub static HPET: [u8; 56] = [
+    0x48, 0x50, 0x45, 0x54, 0x38, 0x00, 0x00, 0x00, 0x01, 0x03, 0x42, 0x4f,
+    0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x48, 0x50, 0x45, 0x54,
+    0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+    0x01, 0xa2, 0x86, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xd0, 0xfe,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
@@ -0,0 +1,615 @@
+//! AHCI SATA driver (PCI class 01:06), the disk controller of QEMU's q35
+//! machine.
+//!
+//! The HBA's registers (ABAR, BAR5) are mapped uncached and the controller
+//! is switched to AHCI mode. Every implemented port with a SATA disk behind
+//! it gets one DMA page holding its command list, received-FIS area and a
+//! single command table, plus a bounce buffer for the data; only command
+//! slot 0 is used. Commands are IDENTIFY and the 48-bit READ/WRITE DMA EXT,
+//! completed by polling the slot's bit in PxCI and the error bits in PxIS.
+//!
+//! Starting and stopping a port follows section 10.3 of the spec: ST is
+//! cleared and CR waited out before FRE is cleared and FR waited out; on
+//! start FRE goes on before ST, and only once the task file is idle. A
+//! port that stays busy gets a COMRESET through PxSCTL first.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::memory::{BootInfoFrameAllocator, DmaRegion};
+use crate::pci::{self, Bar};
+use crate::time;
+
+pub const CLASS_MASS_STORAGE: u8 = 0x01;
+pub const SUBCLASS_SATA: u8 = 0x06;
+
+/// Generic host control registers.
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_PI: u64 = 0x0c;
+const HBA_VS: u64 = 0x10;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+
+/// Port registers, at `0x100 + 0x80 * port`.
+const PORT_BASE: u64 = 0x100;
+const PORT_STRIDE: u64 = 0x80;
+const PORT_COUNT: u32 = 32;
+/// Bytes of ABAR covering all 32 ports.
+const ABAR_SIZE: u64 = PORT_BASE + PORT_STRIDE * PORT_COUNT as u64;
+
+const PX_CLB: u64 = 0x00;
+const PX_CLBU: u64 = 0x04;
+const PX_FB: u64 = 0x08;
+const PX_FBU: u64 = 0x0c;
+const PX_IS: u64 = 0x10;
+const PX_IE: u64 = 0x14;
+const PX_CMD: u64 = 0x18;
+const PX_TFD: u64 = 0x20;
+const PX_SIG: u64 = 0x24;
+const PX_SSTS: u64 = 0x28;
+const PX_SCTL: u64 = 0x2c;
+const PX_SERR: u64 = 0x30;
+const PX_CI: u64 = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+/// Task file error status.
+const IS_TFES: u32 = 1 << 30;
+/// Host bus data, host bus fatal and interface fatal errors.
+const IS_FATAL: u32 = (1 << 29) | (1 << 28) | (1 << 27);
+
+const SSTS_DET_PRESENT: u32 = 3;
+const SSTS_IPM_ACTIVE: u32 = 1;
+const SCTL_DET_COMRESET: u32 = 1;
+
+pub const SIG_SATA: u32 = 0x0000_0101;
+pub const SIG_ATAPI: u32 = 0xeb14_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// The FIS carries a command, not a device control update.
+const FIS_COMMAND: u8 = 0x80;
+const FIS_LEN: usize = 20;
+const DEVICE_LBA: u8 = 1 << 6;
+
+pub const ATA_IDENTIFY: u8 = 0xec;
+pub const ATA_READ_DMA_EXT: u8 = 0x25;
+pub const ATA_WRITE_DMA_EXT: u8 = 0x35;
+
+/// Layout of the per-port DMA page: the 32-slot command list (1 KiB
+/// aligned), the received-FIS area (256 byte aligned), then the command
+/// table for slot 0 (128 byte aligned) with its PRDT at 0x80.
+const COMMAND_LIST_OFFSET: usize = 0x000;
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x500;
+const PRDT_OFFSET: usize = 0x80;
+pub const MAX_PRDT_ENTRIES: usize = 8;
+
+/// Most bytes one PRDT entry can describe.
+pub const MAX_PRD_BYTES: usize = 4 << 20;
+
+const DATA_PAGES: usize = 8;
+const MAX_SECTORS_PER_COMMAND: usize = DATA_PAGES * 4096 / SECTOR_SIZE;
+
+const STOP_TIMEOUT_MS: u64 = 500;
+const IDLE_TIMEOUT_MS: u64 = 1000;
+const COMMAND_TIMEOUT_MS: u64 = 5000;
+/// Polls before giving up even if the clock isn't moving (a shell command
+/// runs with interrupts off, and the tick counter with them).
+const POLL_LIMIT: usize = 50_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AhciError {
+    NoController,
+    /// BAR5 is missing or not a memory BAR.
+    NoAbar,
+    MapFailed,
+    OutOfMemory,
+}
+
+impl fmt::Display for AhciError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AhciError::NoController => f.write_str("no AHCI controller"),
+            AhciError::NoAbar => f.write_str("controller has no memory BAR5"),
+            AhciError::MapFailed => f.write_str("could not map registers"),
+            AhciError::OutOfMemory => f.write_str("out of DMA memory"),
+        }
+    }
+}
+
+/// A Register Host to Device FIS carrying `command` for `count` sectors
+/// from `lba`.
+pub fn command_fis(command: u8, lba: u64, count: u16) -> [u8; FIS_LEN] {
+    let lba = lba.to_le_bytes();
+    let count = count.to_le_bytes();
+    let mut fis = [0; FIS_LEN];
+    fis[0] = FIS_TYPE_REG_H2D;
+    fis[1] = FIS_COMMAND;
+    fis[2] = command;
+    fis[4..7].copy_from_slice(&lba[0..3]);
+    fis[7] = DEVICE_LBA;
+    fis[8..11].copy_from_slice(&lba[3..6]);
+    fis[12..14].copy_from_slice(&count);
+    fis
+}
+
+/// One Physical Region Descriptor Table entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PrdEntry {
+    pub address: u64,
+    pub byte_count: u32,
+}
+
+impl PrdEntry {
+    pub fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[0..8].copy_from_slice(&self.address.to_le_bytes());
+        // the count is stored minus one
+        bytes[12..16].copy_from_slice(&(self.byte_count - 1).to_le_bytes());
+        bytes
+    }
+}
+
+/// Describes the physically contiguous `(address, len)` segments in
+/// `out`, splitting any longer than an entry can take. Returns how many
+/// entries it used; `None` if they don't fit or a length is odd (the
+/// count's low bit must be set, so lengths are always even).
+pub fn build_prdt(segments: &[(u64, usize)], out: &mut [PrdEntry]) -> Option<usize> {
+    let mut count = 0;
+    for &(address, len) in segments {
+        if len == 0 || len % 2 != 0 || address % 2 != 0 {
+            return None;
+        }
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(MAX_PRD_BYTES);
+            *out.get_mut(count)? = PrdEntry { address: address + done as u64, byte_count: chunk as u32 };
+            count += 1;
+            done += chunk;
+        }
+    }
+    Some(count)
+}
+
+/// The command header for a table at `table_phys`: FIS length in dwords,
+/// the write bit and the PRDT length, in the header's eight dwords.
+pub fn command_header(write: bool, prdt_len: usize, table_phys: u64) -> [u8; 32] {
+    let mut flags = (FIS_LEN / 4) as u32;
+    if write {
+        flags |= 1 << 6;
+    }
+    flags |= (prdt_len as u32) << 16;
+    let mut header = [0; 32];
+    header[0..4].copy_from_slice(&flags.to_le_bytes());
+    header[8..16].copy_from_slice(&table_phys.to_le_bytes());
+    header
+}
+
+/// Fills a command table: the FIS at the start, the PRDT at 0x80.
+pub fn write_command_table(table: &mut [u8], fis: &[u8; FIS_LEN], prdt: &[PrdEntry]) -> Option<()> {
+    let end = PRDT_OFFSET + 16 * prdt.len();
+    let table = table.get_mut(..end)?;
+    table.fill(0);
+    table[..FIS_LEN].copy_from_slice(fis);
+    for (slot, entry) in table[PRDT_OFFSET..].chunks_exact_mut(16).zip(prdt) {
+        slot.copy_from_slice(&entry.to_bytes());
+    }
+    Some(())
+}
+
+/// The parts of the IDENTIFY DEVICE data we use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identify {
+    pub sectors: u64,
+    pub lba48: bool,
+    pub model: String,
+}
+
+impl Identify {
+    pub fn parse(data: &[u8]) -> Option<Identify> {
+        let word = |index: usize| -> Option<u16> {
+            Some(u16::from_le_bytes([*data.get(2 * index)?, *data.get(2 * index + 1)?]))
+        };
+        let lba48 = word(83)? & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (0..4).try_fold(0u64, |total, i| Some(total | u64::from(word(100 + i)?) << (16 * i)))?
+        } else {
+            u64::from(word(60)?) | u64::from(word(61)?) << 16
+        };
+        // ATA strings hold two characters per word, high byte first
+        let mut model = String::new();
+        for index in 27..47 {
+            let [high, low] = word(index)?.to_be_bytes();
+            model.push(char::from(high));
+            model.push(char::from(low));
+        }
+        let model = String::from(model.trim_end());
+        Some(Identify { sectors, lba48, model })
+    }
+}
+
+/// Whether PxSSTS shows a device present with the link up.
+pub fn link_up(ssts: u32) -> bool {
+    ssts & 0xf == SSTS_DET_PRESENT && (ssts >> 8) & 0xf == SSTS_IPM_ACTIVE
+}
+
+#[derive(Clone, Copy)]
+struct Registers {
+    base: VirtAddr,
+}
+
+impl Registers {
+    fn read(&self, reg: u64) -> u32 {
+        unsafe { ptr::read_volatile((self.base + reg).as_ptr::<u32>()) }
+    }
+
+    fn write(&self, reg: u64, value: u32) {
+        unsafe { ptr::write_volatile((self.base + reg).as_mut_ptr::<u32>(), value) }
+    }
+
+    fn set(&self, reg: u64, bits: u32) {
+        self.write(reg, self.read(reg) | bits);
+    }
+
+    fn clear(&self, reg: u64, bits: u32) {
+        self.write(reg, self.read(reg) & !bits);
+    }
+
+    /// Waits until `done` holds for `reg`, for at most `timeout_ms`.
+    fn wait(&self, reg: u64, timeout_ms: u64, done: impl Fn(u32) -> bool) -> bool {
+        let deadline = time::now_ns() + timeout_ms * 1_000_000;
+        for _ in 0..POLL_LIMIT {
+            if done(self.read(reg)) {
+                return true;
+            }
+            if time::now_ns() > deadline {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        done(self.read(reg))
+    }
+}
+
+// ==========================================================
+// Ports
+// ==========================================================
+
+pub struct AhciDisk {
+    port: u8,
+    regs: Registers,
+    memory: DmaRegion,
+    bounce: DmaRegion,
+    info: Identify,
+}
+
+impl AhciDisk {
+    fn new(
+        port: u8,
+        regs: Registers,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        physical_memory_offset: VirtAddr,
+    ) -> Result<Option<AhciDisk>, AhciError> {
+        let memory = DmaRegion::allocate(frame_allocator, physical_memory_offset, 1).ok_or(AhciError::OutOfMemory)?;
+        let bounce = DmaRegion::allocate(frame_allocator, physical_memory_offset, DATA_PAGES)
+            .ok_or(AhciError::OutOfMemory)?;
+        let mut disk = AhciDisk {
+            port,
+            regs,
+            memory,
+            bounce,
+            info: Identify { sectors: 0, lba48: false, model: String::new() },
+        };
+        if !disk.stop() {
+            // a port that won't stop gets a COMRESET and one more try
+            disk.comreset();
+            if !disk.stop() {
+                return Ok(None);
+            }
+        }
+        let phys = disk.memory.phys_addr().as_u64();
+        let command_list = phys + COMMAND_LIST_OFFSET as u64;
+        let received_fis = phys + RECEIVED_FIS_OFFSET as u64;
+        regs.write(PX_CLB, command_list as u32);
+        regs.write(PX_CLBU, (command_list >> 32) as u32);
+        regs.write(PX_FB, received_fis as u32);
+        regs.write(PX_FBU, (received_fis >> 32) as u32);
+        regs.write(PX_SERR, u32::MAX);
+        regs.write(PX_IS, u32::MAX);
+        // completion is polled
+        regs.write(PX_IE, 0);
+        if !disk.start() {
+            return Ok(None);
+        }
+
+        let data = disk.command(ATA_IDENTIFY, 0, 0, 1, false);
+        let info = data.ok().and_then(|()| Identify::parse(disk.data_area(1)));
+        match info {
+            Some(info) if info.lba48 && info.sectors > 0 => {
+                disk.info = info;
+                Ok(Some(disk))
+            }
+            _ => {
+                disk.stop();
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    pub fn model(&self) -> &str {
+        &self.info.model
+    }
+
+    /// Clears ST and waits for CR, then clears FRE and waits for FR.
+    fn stop(&self) -> bool {
+        self.regs.clear(PX_CMD, CMD_ST);
+        if !self.regs.wait(PX_CMD, STOP_TIMEOUT_MS, |cmd| cmd & CMD_CR == 0) {
+            return false;
+        }
+        self.regs.clear(PX_CMD, CMD_FRE);
+        self.regs.wait(PX_CMD, STOP_TIMEOUT_MS, |cmd| cmd & CMD_FR == 0)
+    }
+
+    /// Sets FRE, waits for the task file to go idle, then sets ST. Only
+    /// valid with the command list and FIS area programmed.
+    fn start(&self) -> bool {
+        if !self.regs.wait(PX_CMD, STOP_TIMEOUT_MS, |cmd| cmd & CMD_CR == 0) {
+            return false;
+        }
+        self.regs.set(PX_CMD, CMD_FRE);
+        let idle = |tfd: u32| tfd & (TFD_BSY | TFD_DRQ) == 0;
+        if !self.regs.wait(PX_TFD, IDLE_TIMEOUT_MS, idle) {
+            // COMRESET works with FRE on and ST off
+            self.comreset();
+            self.regs.write(PX_SERR, u32::MAX);
+            if !self.regs.wait(PX_TFD, IDLE_TIMEOUT_MS, idle) {
+                return false;
+            }
+        }
+        self.regs.set(PX_CMD, CMD_ST);
+        true
+    }
+
+    /// Holds DET at 1 for over a millisecond, then waits for the link.
+    fn comreset(&self) {
+        let sctl = self.regs.read(PX_SCTL) & !0xf;
+        self.regs.write(PX_SCTL, sctl | SCTL_DET_COMRESET);
+        time::pit_delay_us(1_100);
+        self.regs.write(PX_SCTL, sctl);
+        self.regs.wait(PX_SSTS, IDLE_TIMEOUT_MS, |ssts| ssts & 0xf == SSTS_DET_PRESENT);
+    }
+
+    /// Runs one command in slot 0 with `sectors` sectors of the bounce
+    /// buffer as its data and waits for it.
+    fn command(&mut self, command: u8, lba: u64, count: u16, sectors: usize, write: bool) -> Result<(), BlockError> {
+        let idle = |tfd: u32| tfd & (TFD_BSY | TFD_DRQ) == 0;
+        if !self.regs.wait(PX_TFD, COMMAND_TIMEOUT_MS, idle) {
+            return Err(BlockError::Timeout);
+        }
+        let mut prdt = [PrdEntry::default(); MAX_PRDT_ENTRIES];
+        let segment = (self.bounce.phys_addr().as_u64(), sectors * SECTOR_SIZE);
+        let prdt_len = build_prdt(&[segment], &mut prdt).ok_or(BlockError::BufferSize)?;
+
+        let table_phys = self.memory.phys_addr().as_u64() + COMMAND_TABLE_OFFSET as u64;
+        let page = unsafe { core::slice::from_raw_parts_mut(self.memory.as_mut_ptr(), self.memory.len()) };
+        write_command_table(&mut page[COMMAND_TABLE_OFFSET..], &command_fis(command, lba, count), &prdt[..prdt_len])
+            .ok_or(BlockError::BufferSize)?;
+        page[COMMAND_LIST_OFFSET..COMMAND_LIST_OFFSET + 32]
+            .copy_from_slice(&command_header(write, prdt_len, table_phys));
+        // the tables must be in memory before the HBA is told to fetch them
+        fence(Ordering::SeqCst);
+
+        self.regs.write(PX_IS, u32::MAX);
+        self.regs.write(PX_CI, 1);
+        let finished = self.regs.wait(PX_IS, COMMAND_TIMEOUT_MS, |is| {
+            is & (IS_TFES | IS_FATAL) != 0 || self.regs.read(PX_CI) & 1 == 0
+        });
+        let is = self.regs.read(PX_IS);
+        if is & (IS_TFES | IS_FATAL) != 0 || self.regs.read(PX_TFD) & TFD_ERR != 0 {
+            self.recover();
+            return Err(BlockError::Io);
+        }
+        if !finished || self.regs.read(PX_CI) & 1 != 0 {
+            self.recover();
+            return Err(BlockError::Timeout);
+        }
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Restarts the port after an error so the next command can run.
+    fn recover(&self) {
+        self.stop();
+        self.regs.write(PX_SERR, u32::MAX);
+        self.regs.write(PX_IS, u32::MAX);
+        self.start();
+    }
+
+    fn data_area(&mut self, sectors: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.bounce.as_mut_ptr(), sectors * SECTOR_SIZE) }
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn sector_count(&self) -> u64 {
+        self.info.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self.info.sectors, lba, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let sectors = chunk.len() / SECTOR_SIZE;
+            let chunk_lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            self.command(ATA_READ_DMA_EXT, chunk_lba, sectors as u16, sectors, false)?;
+            chunk.copy_from_slice(self.data_area(sectors));
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self.info.sectors, lba, buf.len())?;
+        for (i, chunk) in buf.chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let sectors = chunk.len() / SECTOR_SIZE;
+            let chunk_lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            self.data_area(sectors).copy_from_slice(chunk);
+            self.command(ATA_WRITE_DMA_EXT, chunk_lba, sectors as u16, sectors, true)?;
+        }
+        Ok(())
+    }
+}
+
+// ==========================================================
+// Controller
+// ==========================================================
+
+/// SATA disks found by `init`, in port order.
+pub static DEVICES: Mutex<Vec<AhciDisk>> = Mutex::new(Vec::new());
+static VERSION: AtomicU32 = AtomicU32::new(0);
+static PORTS: AtomicU32 = AtomicU32::new(0);
+
+/// Brings up the first AHCI controller and every SATA disk on it. Returns
+/// how many disks are ready.
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    physical_memory_offset: VirtAddr,
+) -> Result<usize, AhciError> {
+    let controller = pci::find_class(CLASS_MASS_STORAGE, SUBCLASS_SATA)
+        .next()
+        .ok_or(AhciError::NoController)?;
+    let abar = match controller.bar(5).ok_or(AhciError::NoAbar)? {
+        Bar::Memory { address, .. } => address,
+        Bar::Io(_) => return Err(AhciError::NoAbar),
+    };
+    controller.enable_bus_mastering();
+    let mut base = None;
+    for offset in (0..ABAR_SIZE).step_by(4096) {
+        let virt = crate::memory::map_mmio(PhysAddr::new(abar + offset), mapper, frame_allocator)
+            .map_err(|_| AhciError::MapFailed)?;
+        base.get_or_insert(virt);
+    }
+    let hba = Registers { base: base.ok_or(AhciError::MapFailed)? };
+    hba.set(HBA_GHC, GHC_AHCI_ENABLE);
+    VERSION.store(hba.read(HBA_VS), Ordering::Relaxed);
+    // CAP.NP is the number of ports minus one
+    PORTS.store((hba.read(HBA_CAP) & 0x1f) + 1, Ordering::Relaxed);
+
+    let implemented = hba.read(HBA_PI);
+    let mut devices = DEVICES.lock();
+    for port in (0..PORT_COUNT).filter(|port| implemented & (1 << port) != 0) {
+        let regs = Registers { base: hba.base + PORT_BASE + PORT_STRIDE * u64::from(port) };
+        if !link_up(regs.read(PX_SSTS)) || regs.read(PX_SIG) != SIG_SATA {
+            continue;
+        }
+        if let Some(disk) = AhciDisk::new(port as u8, regs, frame_allocator, physical_memory_offset)? {
+            crate::block::register("ata", crate::block::Disk::Ahci(devices.len()));
+            devices.push(disk);
+        }
+    }
+    Ok(devices.len())
+}
+
+/// Prints the controller version and the disks found.
+pub fn print_summary() {
+    let version = VERSION.load(Ordering::Relaxed);
+    if version == 0 {
+        return;
+    }
+    crate::println!("AHCI {}.{}: {} port(s)", version >> 16, (version >> 8) & 0xff, PORTS.load(Ordering::Relaxed));
+    for disk in DEVICES.lock().iter() {
+        crate::println!("  port {}: {} ({} sectors)", disk.port, disk.model(), disk.sector_count());
+    }
+}
+
+//test case
+#[test_case]
+fn test_command_fis() {
+    let fis = command_fis(ATA_READ_DMA_EXT, 0x0605_0403_0201, 0x0102);
+    assert_eq!(fis[0..4], [FIS_TYPE_REG_H2D, FIS_COMMAND, ATA_READ_DMA_EXT, 0]);
+    assert_eq!(fis[4..8], [0x01, 0x02, 0x03, DEVICE_LBA]);
+    assert_eq!(fis[8..11], [0x04, 0x05, 0x06]);
+    assert_eq!(fis[12..14], [0x02, 0x01]);
+    assert!(fis[14..].iter().all(|&byte| byte == 0));
+}
+
+#[test_case]
+fn test_prdt_construction() {
+    let mut prdt = [PrdEntry::default(); MAX_PRDT_ENTRIES];
+    // a 9 MiB buffer and a page: three entries for the first
+    let segments = [(0x100_0000, 9 << 20), (0x4000, 4096)];
+    assert_eq!(build_prdt(&segments, &mut prdt), Some(4));
+    assert_eq!(prdt[0], PrdEntry { address: 0x100_0000, byte_count: 4 << 20 });
+    assert_eq!(prdt[1], PrdEntry { address: 0x140_0000, byte_count: 4 << 20 });
+    assert_eq!(prdt[2], PrdEntry { address: 0x180_0000, byte_count: 1 << 20 });
+    assert_eq!(prdt[3], PrdEntry { address: 0x4000, byte_count: 4096 });
+    assert_eq!(prdt[3].to_bytes(), [0x00, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0x0f, 0, 0]);
+
+    assert_eq!(build_prdt(&[(0x1000, 511)], &mut prdt), None);
+    assert_eq!(build_prdt(&[(0x1000, 0)], &mut prdt), None);
+    assert_eq!(build_prdt(&[(0, 40 << 20)], &mut prdt), None, "needs 10 entries");
+}
+
+#[test_case]
+fn test_command_table_and_header() {
+    let mut table = [0xaau8; 0x100];
+    let fis = command_fis(ATA_WRITE_DMA_EXT, 8, 1);
+    let prdt = [PrdEntry { address: 0x20_0000, byte_count: 512 }, PrdEntry { address: 0x30_0000, byte_count: 1024 }];
+    write_command_table(&mut table, &fis, &prdt).unwrap();
+    assert_eq!(table[..FIS_LEN], fis);
+    assert!(table[FIS_LEN..PRDT_OFFSET].iter().all(|&byte| byte == 0));
+    assert_eq!(table[PRDT_OFFSET..PRDT_OFFSET + 16], prdt[0].to_bytes());
+    assert_eq!(table[PRDT_OFFSET + 16..PRDT_OFFSET + 32], prdt[1].to_bytes());
+    assert_eq!(table[PRDT_OFFSET + 32], 0xaa, "past the PRDT is left alone");
+    assert!(write_command_table(&mut table[..0x90], &fis, &prdt).is_none());
+
+    let header = command_header(true, 2, 0x1_2345_6780);
+    assert_eq!(u32::from_le_bytes(header[0..4].try_into().unwrap()), 5 | 1 << 6 | 2 << 16);
+    assert_eq!(u64::from_le_bytes(header[8..16].try_into().unwrap()), 0x1_2345_6780);
+    assert_eq!(command_header(false, 1, 0)[0], 5);
+}
+
+#[test_case]
+fn test_identify_parsing() {
+    let mut data = [0u8; 512];
+    let mut set_word = |index: usize, value: u16| data[2 * index..2 * index + 2].copy_from_slice(&value.to_le_bytes());
+    set_word(83, 1 << 10);
+    set_word(100, 0x0000);
+    set_word(101, 0x0004);
+    set_word(60, 0xffff);
+    set_word(61, 0x0fff);
+    for (i, pair) in b"QEMU HARDDISK                           ".chunks(2).enumerate() {
+        set_word(27 + i, u16::from_be_bytes([pair[0], pair[1]]));
+    }
+    let info = Identify::parse(&data).unwrap();
+    assert!(info.lba48);
+    assert_eq!(info.sectors, 0x4_0000);
+    assert_eq!(info.model, "QEMU HARDDISK");
+    assert_eq!(Identify::parse(&data[..100]), None);
+}
+
+#[test_case]
+fn test_link_detection() {
+    assert!(link_up(0x113));
+    assert!(link_up(0x123));
+    assert!(!link_up(0x000));
+    // device present but the link in a power-saving state
+    assert!(!link_up(0x613));
+    assert!(!link_up(0x101));
+}
@@ -17,11 +17,19 @@ pub const HEAP_SIZE: usize = 100 * 1024;
 pub mod bump;
 pub mod linked_list;
 pub mod fixed_size_block;
+pub mod slab;
 
 
 #[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
+/// The heap is out of room (or not mapped yet): report the request that
+/// failed through the panic handler, which halts.
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!("allocation error: {} bytes aligned to {}", layout.size(), layout.align())
+}
+
 
 unsafe impl GlobalAlloc for Dummy {
     unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
@@ -34,7 +42,20 @@ unsafe impl GlobalAlloc for Dummy {
 }
 
 
-pub fn init_heap(
+/// Maps the heap with the global frame allocator (see
+/// `memory::init_global`) and hands it to the allocator.
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    crate::memory::with_paging(|mapper, frame_allocator| map_heap(mapper, frame_allocator))
+        .unwrap_or(Err(MapToError::FrameAllocationFailed))?;
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let _ = crate::memory::vmm::track("heap", VirtAddr::new(HEAP_START as u64), HEAP_SIZE as u64, flags);
+    Ok(())
+}
+
+fn map_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
@@ -56,25 +77,45 @@ pub fn init_heap(
         };
     }
 
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    Ok(())
+}
+
+/// How full the kernel heap is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+}
+
+impl HeapStats {
+    pub fn free(&self) -> usize {
+        self.size - self.used
     }
+}
 
-    Ok(())
+/// The global allocator's usage, copied out under its lock so nobody
+/// prints while holding it. `None` before `init_heap`.
+pub fn heap_stats() -> Option<HeapStats> {
+    let stats = ALLOCATOR.lock().stats();
+    (stats.size != 0).then_some(stats)
 }
 
+/// An allocator behind an `IrqMutex`: a thread that's preempted holding
+/// the heap, or an interrupt handler that allocates, can't spin forever on
+/// a lock the code below it holds.
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: crate::sync::IrqMutex<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: crate::sync::IrqMutex::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    #[track_caller]
+    pub fn lock(&self) -> crate::sync::IrqMutexGuard<'_, A> {
         self.inner.lock()
     }
 }
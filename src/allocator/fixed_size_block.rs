@@ -6,12 +6,16 @@ use core::{mem, ptr::NonNull};
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    crate::stats::ALLOCATIONS.add(1);
+    crate::stats::ALLOCATED_BYTES.add(layout.size() as u64);
+    crate::time_block!("alloc", {
     let mut allocator = self.lock();
     match list_index(&layout) {
         Some(index) => {
             match allocator.list_heads[index].take() {
                 Some(node) => {
                     allocator.list_heads[index] = node.next.take();
+                    allocator.cached -= BLOCK_SIZES[index];
                     node as *mut ListNode as *mut u8
                 }
                 None => {
@@ -27,9 +31,11 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         }
         None => allocator.fallback_alloc(layout),
     }
+    })
 }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    crate::stats::DEALLOCATIONS.add(1);
     let mut allocator = self.lock();
     match list_index(&layout) {
         Some(index) => {
@@ -44,6 +50,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 new_node_ptr.write(new_node);
                 allocator.list_heads[index] = Some(&mut *new_node_ptr);
             }
+            allocator.cached += BLOCK_SIZES[index];
         }
         None => {
             let ptr = NonNull::new(ptr).unwrap();
@@ -64,6 +71,9 @@ const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    /// Bytes in blocks sitting on the lists: taken from the fallback
+    /// allocator but free for new allocations.
+    cached: usize,
 }
 
 
@@ -73,6 +83,16 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator { 
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(), 
+            cached: 0,
+        }
+    }
+
+    /// Heap size and the bytes in live allocations, counting each block
+    /// at its full block size.
+    pub fn stats(&self) -> super::HeapStats {
+        super::HeapStats {
+            size: self.fallback_allocator.size(),
+            used: self.fallback_allocator.used() - self.cached,
         }
     }
 
@@ -0,0 +1,340 @@
+//! Object caches on top of the heap. A `Cache<T>` takes 4 KiB slabs from
+//! the global allocator, cuts each into slots for `T` and keeps freed
+//! slots for the next `alloc`, so objects that come and go all the time
+//! (threads, cached sectors) keep reusing the same few slabs instead of
+//! cutting the heap into ever smaller pieces.
+//!
+//! A slab is aligned to its size and starts with a header holding its own
+//! free list, so `free` finds the slab by rounding the object's address
+//! down. Each cache keeps one empty slab around for the next burst and
+//! gives any other slab back to the heap as soon as it empties. The heap is
+//! only called with the cache unlocked: `alloc` takes a new slab before it
+//! locks the cache to hand it over, and `free` unlinks an empty slab under
+//! the lock and frees it once the lock is gone. Caches
+//! show up in `caches()` (and the `slabinfo` command) once they have
+//! allocated their first object.
+
+use alloc::alloc::{alloc as heap_alloc, dealloc, Layout};
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub const SLAB_SIZE: usize = 4096;
+
+const SLAB_LAYOUT: Layout = match Layout::from_size_align(SLAB_SIZE, SLAB_SIZE) {
+    Ok(layout) => layout,
+    Err(_) => panic!("bad slab layout"),
+};
+
+/// What a free slot holds.
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+struct SlabHeader {
+    next: *mut SlabHeader,
+    free: *mut FreeSlot,
+    in_use: usize,
+    /// The `Slabs` the slab belongs to, to catch objects freed to the
+    /// wrong cache.
+    owner: *const Slabs,
+}
+
+/// Where the slots of a slab are.
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    /// Offset of the first slot, past the header.
+    first: usize,
+    size: usize,
+    count: usize,
+}
+
+const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+impl Geometry {
+    /// Slots for `T`: room for a free-list link, aligned for both.
+    const fn of<T>() -> Geometry {
+        let align = if align_of::<T>() > align_of::<FreeSlot>() { align_of::<T>() } else { align_of::<FreeSlot>() };
+        let size = if size_of::<T>() > size_of::<FreeSlot>() { size_of::<T>() } else { size_of::<FreeSlot>() };
+        let size = align_up(size, align);
+        let first = align_up(size_of::<SlabHeader>(), align);
+        assert!(first + size <= SLAB_SIZE, "object too big for a slab");
+        Geometry { first, size, count: (SLAB_SIZE - first) / size }
+    }
+}
+
+struct Slabs {
+    head: *mut SlabHeader,
+    slabs: usize,
+    /// Slabs with nothing allocated from them, at most one once a free
+    /// has run.
+    empty: usize,
+    active: usize,
+    allocations: u64,
+}
+
+// the slabs are only reached through the cache's lock
+unsafe impl Send for Slabs {}
+
+impl Slabs {
+    /// A free slot, `None` if every slab is full.
+    fn take(&mut self) -> Option<NonNull<u8>> {
+        let mut slab = self.head;
+        while !slab.is_null() && unsafe { (*slab).free.is_null() } {
+            slab = unsafe { (*slab).next };
+        }
+        if slab.is_null() {
+            return None;
+        }
+        let header = unsafe { &mut *slab };
+        let slot = header.free;
+        header.free = unsafe { (*slot).next };
+        if header.in_use == 0 {
+            self.empty -= 1;
+        }
+        header.in_use += 1;
+        self.active += 1;
+        self.allocations += 1;
+        NonNull::new(slot.cast())
+    }
+
+    /// Cuts `base`, a block of `SLAB_LAYOUT` from the heap, into slots.
+    fn grow(&mut self, base: NonNull<u8>, geometry: Geometry) {
+        let base = base.as_ptr();
+        let mut free = ptr::null_mut();
+        for i in (0..geometry.count).rev() {
+            let slot = unsafe { base.add(geometry.first + i * geometry.size) }.cast::<FreeSlot>();
+            unsafe { slot.write(FreeSlot { next: free }) };
+            free = slot;
+        }
+        let header = base.cast::<SlabHeader>();
+        let owner = self as *const Slabs;
+        unsafe { header.write(SlabHeader { next: self.head, free, in_use: 0, owner }) };
+        self.head = header;
+        self.slabs += 1;
+        self.empty += 1;
+    }
+
+    /// Frees `slot`. Returns its slab if that emptied it and another empty
+    /// one is kept already, unlinked for the caller to give to the heap.
+    fn give_back(&mut self, slot: *mut u8) -> Option<NonNull<u8>> {
+        let slab = (slot as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+        let header = unsafe { &mut *slab };
+        assert!(ptr::eq(header.owner, self), "object freed to the wrong slab cache");
+        let slot = slot.cast::<FreeSlot>();
+        unsafe { slot.write(FreeSlot { next: header.free }) };
+        header.free = slot;
+        header.in_use -= 1;
+        self.active -= 1;
+        if header.in_use != 0 {
+            return None;
+        }
+        if self.empty == 0 {
+            self.empty += 1;
+            return None;
+        }
+        self.unlink(slab);
+        NonNull::new(slab.cast())
+    }
+
+    fn unlink(&mut self, slab: *mut SlabHeader) {
+        let mut link = &mut self.head;
+        while *link != slab {
+            link = unsafe { &mut (**link).next };
+        }
+        *link = unsafe { (*slab).next };
+        self.slabs -= 1;
+    }
+}
+
+/// A cache's numbers, as `slabinfo` shows them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    pub name: &'static str,
+    /// Bytes per slot, at least the object's size.
+    pub object_size: usize,
+    pub per_slab: usize,
+    pub slabs: usize,
+    /// Objects allocated and not freed yet.
+    pub active: usize,
+    /// `alloc` calls since boot.
+    pub allocations: u64,
+}
+
+impl SlabStats {
+    /// Slots in all the cache's slabs, free or not.
+    pub fn total(&self) -> usize {
+        self.slabs * self.per_slab
+    }
+}
+
+trait Stats {
+    fn stats(&self) -> SlabStats;
+}
+
+static CACHES: Mutex<Vec<&'static (dyn Stats + Sync)>> = Mutex::new(Vec::new());
+
+/// Every cache that has allocated something, in the order they started.
+pub fn caches() -> Vec<SlabStats> {
+    let caches = without_interrupts(|| CACHES.lock().clone());
+    caches.iter().map(|cache| cache.stats()).collect()
+}
+
+/// Objects of one type, carved out of slabs. Meant to live in a `static`.
+pub struct Cache<T> {
+    name: &'static str,
+    slabs: Mutex<Slabs>,
+    registered: AtomicBool,
+    _objects: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Sync for Cache<T> {}
+
+impl<T: Send + 'static> Cache<T> {
+    const GEOMETRY: Geometry = Geometry::of::<T>();
+
+    pub const fn new(name: &'static str) -> Cache<T> {
+        // evaluated here so a type that doesn't fit fails to build
+        let _ = Self::GEOMETRY;
+        Cache {
+            name,
+            slabs: Mutex::new(Slabs { head: ptr::null_mut(), slabs: 0, empty: 0, active: 0, allocations: 0 }),
+            registered: AtomicBool::new(false),
+            _objects: PhantomData,
+        }
+    }
+
+    /// Moves `value` into a free slot. `None` if no slot is free and the
+    /// heap has no room for another slab.
+    pub fn alloc(&'static self, value: T) -> Option<NonNull<T>> {
+        let slot = loop {
+            if let Some(slot) = without_interrupts(|| self.slabs.lock().take()) {
+                break slot;
+            }
+            let base = NonNull::new(unsafe { heap_alloc(SLAB_LAYOUT) })?;
+            without_interrupts(|| self.slabs.lock().grow(base, Self::GEOMETRY));
+        };
+        if !self.registered.swap(true, Ordering::AcqRel) {
+            without_interrupts(|| CACHES.lock().push(self));
+        }
+        let object = slot.cast::<T>();
+        unsafe { object.as_ptr().write(value) };
+        Some(object)
+    }
+
+    /// Drops the object and frees its slot.
+    ///
+    /// # Safety
+    ///
+    /// `object` came from `alloc` on this cache and is not used again.
+    pub unsafe fn free(&self, object: NonNull<T>) {
+        unsafe { ptr::drop_in_place(object.as_ptr()) };
+        if let Some(slab) = without_interrupts(|| self.slabs.lock().give_back(object.as_ptr().cast())) {
+            unsafe { dealloc(slab.as_ptr(), SLAB_LAYOUT) };
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let slabs = without_interrupts(|| {
+            let slabs = self.slabs.lock();
+            (slabs.slabs, slabs.active, slabs.allocations)
+        });
+        SlabStats {
+            name: self.name,
+            object_size: Self::GEOMETRY.size,
+            per_slab: Self::GEOMETRY.count,
+            slabs: slabs.0,
+            active: slabs.1,
+            allocations: slabs.2,
+        }
+    }
+}
+
+impl<T: Send + 'static> Stats for Cache<T> {
+    fn stats(&self) -> SlabStats {
+        Cache::stats(self)
+    }
+}
+
+/// A `Box` whose object lives in a slab cache: freed back to the cache
+/// when dropped.
+pub struct SlabBox<T: Send + 'static> {
+    object: NonNull<T>,
+    cache: &'static Cache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Send + Sync> Sync for SlabBox<T> {}
+
+impl<T: Send + 'static> SlabBox<T> {
+    pub fn new(cache: &'static Cache<T>, value: T) -> Option<SlabBox<T>> {
+        Some(SlabBox { object: cache.alloc(value)?, cache })
+    }
+}
+
+impl<T: Send + 'static> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T: Send + 'static> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T: Send + 'static> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe { self.cache.free(self.object) };
+    }
+}
+
+//test case
+#[cfg(test)]
+static TEST_CACHE: Cache<[u64; 8]> = Cache::new("test");
+
+#[test_case]
+fn test_slab_cache() {
+    let per_slab = TEST_CACHE.stats().per_slab;
+    assert_eq!(TEST_CACHE.stats().object_size, 64);
+    assert!(per_slab >= 60);
+    let objects: Vec<_> = (0..per_slab as u64 + 1).map(|i| TEST_CACHE.alloc([i; 8]).unwrap()).collect();
+    let stats = TEST_CACHE.stats();
+    assert_eq!((stats.slabs, stats.active), (2, per_slab + 1));
+    // the first slab's slots all sit in one aligned page
+    let page = objects[0].as_ptr() as usize & !(SLAB_SIZE - 1);
+    assert!(objects[..per_slab].iter().all(|object| object.as_ptr() as usize & !(SLAB_SIZE - 1) == page));
+    assert_eq!(unsafe { objects[5].as_ref() }[0], 5);
+    for object in &objects {
+        unsafe { TEST_CACHE.free(*object) };
+    }
+    // one empty slab stays for the next allocation
+    let stats = TEST_CACHE.stats();
+    assert_eq!((stats.slabs, stats.active), (1, 0));
+    let again = TEST_CACHE.alloc([7; 8]).unwrap();
+    assert_eq!(TEST_CACHE.stats().slabs, 1);
+    unsafe { TEST_CACHE.free(again) };
+    assert!(caches().iter().any(|cache| cache.name == "test"));
+}
+
+#[cfg(test)]
+static DROP_CACHE: Cache<alloc::sync::Arc<()>> = Cache::new("test-drop");
+
+#[test_case]
+fn test_slab_box_drops() {
+    let shared = alloc::sync::Arc::new(());
+    let boxed = SlabBox::new(&DROP_CACHE, shared.clone()).unwrap();
+    assert_eq!(alloc::sync::Arc::strong_count(&boxed), 2);
+    drop(boxed);
+    assert_eq!(alloc::sync::Arc::strong_count(&shared), 1);
+    assert_eq!(DROP_CACHE.stats().active, 0);
+}
@@ -0,0 +1,291 @@
+//! ANSI escape sequences in console output.
+//!
+//! Text printed with `print!` goes to the screen and to terminals alike,
+//! so it can carry the sequences a terminal understands: `\x1b[31m` for
+//! red, `\x1b[2J` to clear, `\x1b[5;10H` to move the cursor. A terminal on
+//! the serial port or the virtio console gets them as they are; on the
+//! screen `vga_buffer::Writer` feeds every character through a `Parser`
+//! and carries out the sequences it finishes.
+//!
+//! Only CSI sequences are recognized (ESC `[`, numeric parameters separated
+//! by `;`, a final byte), and of those only SGR colors, cursor movement and
+//! erasing do anything; any other is swallowed. An ESC not followed by `[`
+//! is dropped.
+
+use crate::vga_buffer::Color;
+
+const ESC: char = '\x1b';
+/// Parameters kept from one sequence; further ones are ignored.
+pub const MAX_PARAMS: usize = 8;
+
+/// A finished CSI sequence: its parameters and final byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    pub final_byte: char,
+}
+
+impl Csi {
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+
+    /// Parameter `index`, or `default` when it is missing or 0, the way
+    /// counts and positions are read.
+    pub fn param_or(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            None | Some(0) => default,
+            Some(&value) => value,
+        }
+    }
+
+    /// What the sequence asks for, if it is one acted on.
+    pub fn command(&self) -> Option<Command> {
+        let count = usize::from(self.param_or(0, 1));
+        Some(match self.final_byte {
+            'm' => Command::Sgr,
+            'A' => Command::CursorUp(count),
+            'B' => Command::CursorDown(count),
+            'C' => Command::CursorForward(count),
+            'D' => Command::CursorBack(count),
+            'H' | 'f' => Command::CursorPosition {
+                row: usize::from(self.param_or(0, 1)) - 1,
+                col: usize::from(self.param_or(1, 1)) - 1,
+            },
+            'J' => Command::EraseDisplay(Erase::from_param(self.params().first().copied().unwrap_or(0))?),
+            'K' => Command::EraseLine(Erase::from_param(self.params().first().copied().unwrap_or(0))?),
+            _ => return None,
+        })
+    }
+}
+
+/// The part of the screen or line an erase covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Erase {
+    /// From the cursor to the end.
+    ToEnd,
+    /// From the start to the cursor, inclusive.
+    ToCursor,
+    All,
+}
+
+impl Erase {
+    fn from_param(param: u16) -> Option<Erase> {
+        match param {
+            0 => Some(Erase::ToEnd),
+            1 => Some(Erase::ToCursor),
+            // 3 also drops a terminal's scrollback; the screen is all there is here
+            2 | 3 => Some(Erase::All),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Set graphic rendition: colors, see `apply_sgr`.
+    Sgr,
+    CursorUp(usize),
+    CursorDown(usize),
+    CursorForward(usize),
+    CursorBack(usize),
+    /// Zero-based, unlike the sequence.
+    CursorPosition { row: usize, col: usize },
+    EraseDisplay(Erase),
+    EraseLine(Erase),
+}
+
+/// What a character fed to the parser turned out to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// Plain text, to be drawn.
+    Char(char),
+    /// The end of a sequence.
+    Csi(Csi),
+    /// Part of a sequence that isn't finished.
+    Pending,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Text,
+    /// After ESC.
+    Escape,
+    /// After ESC [.
+    Csi,
+}
+
+/// Splits output into text and escape sequences, a character at a time, so
+/// a sequence may arrive across several writes.
+#[derive(Debug, Clone, Copy)]
+pub struct Parser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Parser { state: State::Text, params: [0; MAX_PARAMS], len: 0 }
+    }
+
+    pub fn feed(&mut self, c: char) -> Output {
+        match (self.state, c) {
+            (State::Text, ESC) => {
+                self.state = State::Escape;
+                Output::Pending
+            }
+            (State::Text, c) => Output::Char(c),
+            (State::Escape, '[') => {
+                self.state = State::Csi;
+                self.params = [0; MAX_PARAMS];
+                self.len = 0;
+                Output::Pending
+            }
+            (State::Escape, _) => {
+                self.state = State::Text;
+                Output::Pending
+            }
+            (State::Csi, '0'..='9') => {
+                if self.len == 0 {
+                    self.len = 1;
+                }
+                if let Some(param) = self.params.get_mut(self.len - 1) {
+                    let digit = c as u16 - u16::from(b'0');
+                    *param = param.saturating_mul(10).saturating_add(digit);
+                }
+                Output::Pending
+            }
+            (State::Csi, ';') => {
+                // an empty parameter before the `;` counts as a 0
+                self.len = (self.len.max(1) + 1).min(MAX_PARAMS + 1);
+                Output::Pending
+            }
+            // private markers and intermediates (`?`, ` `...) are skipped
+            (State::Csi, '\x20'..='\x3f') => Output::Pending,
+            (State::Csi, c) => {
+                self.state = State::Text;
+                Output::Csi(Csi { params: self.params, len: self.len.min(MAX_PARAMS), final_byte: c })
+            }
+        }
+    }
+}
+
+/// The color an ANSI color number names, 0-7 for the normal ones and 8-15
+/// for the bright ones (what 30-37 and 90-97 choose as foreground).
+pub fn color(index: u16) -> Option<Color> {
+    const COLORS: [Color; 16] = [
+        Color::Black, Color::Red, Color::Green, Color::Brown, Color::Blue, Color::Magenta, Color::Cyan, Color::LightGray,
+        Color::DarkGray, Color::LightRed, Color::LightGreen, Color::Yellow, Color::LightBlue, Color::Pink, Color::LightCyan, Color::White,
+    ];
+    COLORS.get(usize::from(index)).copied()
+}
+
+/// The foreground and background after an SGR sequence with `params`,
+/// starting from `current`. 0 (or no parameters) goes back to `default`,
+/// 1 (bold) makes the foreground bright, 39 and 49 reset one of the two.
+/// Others, like underline or 256-color ones, leave the colors alone.
+pub fn apply_sgr(params: &[u16], current: (Color, Color), default: (Color, Color)) -> (Color, Color) {
+    let (mut foreground, mut background) = current;
+    if params.is_empty() {
+        return default;
+    }
+    // bold holds for a color chosen after it in the same sequence
+    let mut bold = false;
+    for &param in params {
+        match param {
+            0 => {
+                (foreground, background) = default;
+                bold = false;
+            }
+            1 => bold = true,
+            30..=37 => foreground = color(param - 30).unwrap_or(foreground),
+            39 => foreground = default.0,
+            40..=47 => background = color(param - 40).unwrap_or(background),
+            49 => background = default.1,
+            90..=97 => foreground = color(param - 90 + 8).unwrap_or(foreground),
+            100..=107 => background = color(param - 100 + 8).unwrap_or(background),
+            // 256-color and RGB ones: what follows is not SGR codes
+            38 | 48 => break,
+            _ => {}
+        }
+    }
+    if bold {
+        foreground = bright(foreground);
+    }
+    (foreground, background)
+}
+
+/// The bright form of a normal color; bright ones stay.
+fn bright(color: Color) -> Color {
+    (0..8).find(|&i| self::color(i) == Some(color)).and_then(|i| self::color(i + 8)).unwrap_or(color)
+}
+
+//test case
+#[cfg(test)]
+fn parse(text: &str) -> alloc::vec::Vec<Output> {
+    let mut parser = Parser::new();
+    text.chars().map(|c| parser.feed(c)).filter(|output| *output != Output::Pending).collect()
+}
+
+#[test_case]
+fn test_parser() {
+    let outputs = parse("a\x1b[1;31mb\x1b[0m");
+    assert_eq!(outputs[0], Output::Char('a'));
+    let Output::Csi(sgr) = outputs[1] else { panic!("not a sequence: {:?}", outputs[1]) };
+    assert_eq!((sgr.params(), sgr.final_byte), (&[1, 31][..], 'm'));
+    assert_eq!(outputs[2], Output::Char('b'));
+    let Output::Csi(reset) = outputs[3] else { panic!("not a sequence: {:?}", outputs[3]) };
+    assert_eq!(reset.params(), [0]);
+    assert_eq!(outputs.len(), 4);
+
+    // an ESC without `[` is dropped, with the character after it
+    assert_eq!(parse("x\x1b7y"), [Output::Char('x'), Output::Char('y')]);
+    // private sequences end like any other
+    let outputs = parse("\x1b[?25lz");
+    assert_eq!(outputs.len(), 2);
+    assert_eq!(outputs[1], Output::Char('z'));
+}
+
+#[test_case]
+fn test_commands() {
+    let command = |text: &str| match parse(text)[..] {
+        [Output::Csi(csi)] => csi.command(),
+        ref other => panic!("{:?}", other),
+    };
+    assert_eq!(command("\x1b[A"), Some(Command::CursorUp(1)));
+    assert_eq!(command("\x1b[3C"), Some(Command::CursorForward(3)));
+    assert_eq!(command("\x1b[H"), Some(Command::CursorPosition { row: 0, col: 0 }));
+    assert_eq!(command("\x1b[5;10H"), Some(Command::CursorPosition { row: 4, col: 9 }));
+    assert_eq!(command("\x1b[;7f"), Some(Command::CursorPosition { row: 0, col: 6 }));
+    assert_eq!(command("\x1b[2J"), Some(Command::EraseDisplay(Erase::All)));
+    assert_eq!(command("\x1b[K"), Some(Command::EraseLine(Erase::ToEnd)));
+    assert_eq!(command("\x1b[9K"), None);
+    assert_eq!(command("\x1b[6n"), None);
+}
+
+#[test_case]
+fn test_sgr() {
+    let default = (Color::Yellow, Color::Black);
+    let current = (Color::White, Color::Blue);
+    assert_eq!(apply_sgr(&[31], current, default), (Color::Red, Color::Blue));
+    assert_eq!(apply_sgr(&[1, 34, 47], current, default), (Color::LightBlue, Color::LightGray));
+    assert_eq!(apply_sgr(&[92, 101], current, default), (Color::LightGreen, Color::LightRed));
+    assert_eq!(apply_sgr(&[0], current, default), default);
+    assert_eq!(apply_sgr(&[], current, default), default);
+    assert_eq!(apply_sgr(&[39], current, default), (Color::Yellow, Color::Blue));
+    assert_eq!(apply_sgr(&[4, 38, 5, 31], current, default), current);
+    // the sequences `Color::ansi` prints name the color back
+    for index in 0..16 {
+        let color = color(index).unwrap();
+        let code: u16 = color.ansi()[2..color.ansi().len() - 1].parse().unwrap();
+        assert_eq!(apply_sgr(&[code], current, default).0, color);
+    }
+}
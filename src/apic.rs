@@ -0,0 +1,110 @@
+//! Local APIC access: inter-processor interrupts, and the end-of-interrupt
+//! for device interrupts once `interrupts::enable_apic` has them come
+//! through the I/O APIC instead of the 8259 PICs.
+
+use core::ptr;
+use spin::Once;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, OffsetPageTable, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Vector the local APIC raises for spurious interrupts. Those must not be
+/// acknowledged with an EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_PERFMON: usize = 0x340;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+
+static BASE: Once<VirtAddr> = Once::new();
+
+/// Maps the local APIC registers (uncached) and software-enables the APIC.
+///
+/// The registers sit at the same virtual address on every CPU, so one
+/// mapping in the shared page tables serves all of them. Calling it again
+/// only enables the APIC of the calling CPU.
+pub fn init(
+    phys: PhysAddr,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    if let Some(mut apic) = local() {
+        apic.enable();
+        return Ok(());
+    }
+    let virt = crate::memory::map_mmio(phys, mapper, frame_allocator)?;
+    BASE.call_once(|| virt);
+    let mut apic = local().expect("local APIC base just set");
+    apic.enable();
+    Ok(())
+}
+
+/// The calling CPU's local APIC, once `init` has mapped it.
+pub fn local() -> Option<LocalApic> {
+    BASE.get().map(|&base| LocalApic { base })
+}
+
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + reg as u64).as_ptr::<u32>()) }
+    }
+
+    fn write(&mut self, reg: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + reg as u64).as_mut_ptr::<u32>(), value) }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.read(REG_ID) >> 24
+    }
+
+    pub fn enable(&mut self) {
+        let svr = self.read(REG_SPURIOUS) & !0xff;
+        self.write(REG_SPURIOUS, svr | SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+    }
+
+    /// Tells the APIC the interrupt being handled is done, so it delivers
+    /// the next one of the same or lower priority.
+    pub fn eoi(&mut self) {
+        self.write(REG_EOI, 0);
+    }
+
+    /// Has a performance counter overflow raise an NMI. The APIC masks the
+    /// entry each time it delivers one, so the handler unmasks it again.
+    pub fn set_perfmon_nmi(&mut self, masked: bool) {
+        self.write(REG_LVT_PERFMON, LVT_DELIVERY_NMI | if masked { LVT_MASKED } else { 0 });
+    }
+
+    /// Sends an INIT IPI, which puts the target in wait-for-SIPI state.
+    pub fn send_init(&mut self, apic_id: u32) {
+        self.send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Sends a startup IPI: the target starts in real mode at
+    /// `vector * 0x1000`.
+    pub fn send_startup(&mut self, apic_id: u32, vector: u8) {
+        self.send_ipi(apic_id, ICR_STARTUP | u32::from(vector));
+    }
+
+    fn send_ipi(&mut self, apic_id: u32, command: u32) {
+        self.write(REG_ICR_HIGH, apic_id << 24);
+        // writing the low half sends the IPI
+        self.write(REG_ICR_LOW, command);
+        while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
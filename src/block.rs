@@ -0,0 +1,238 @@
+//! Common interface for anything that stores fixed-size sectors.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use crate::partitions::{self, PartitionDevice, PartitionError};
+
+pub mod cache;
+pub mod ramdisk;
+#[cfg(test)]
+pub mod testdata;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the end of the device.
+    OutOfRange,
+    /// The buffer length is not a whole number of sectors.
+    BufferSize,
+    ReadOnly,
+    /// The device reported an I/O error.
+    Io,
+    /// The device never completed the request.
+    Timeout,
+    Unsupported,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            BlockError::OutOfRange => "sector out of range",
+            BlockError::BufferSize => "buffer is not a multiple of the sector size",
+            BlockError::ReadOnly => "device is read-only",
+            BlockError::Io => "I/O error",
+            BlockError::Timeout => "device timed out",
+            BlockError::Unsupported => "operation not supported",
+        };
+        f.write_str(msg)
+    }
+}
+
+pub trait BlockDevice {
+    fn sector_count(&self) -> u64;
+
+    /// Reads `buf.len() / SECTOR_SIZE` sectors starting at `lba`.
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf.len() / SECTOR_SIZE` sectors starting at `lba`.
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Writes out anything held back. Once it returns, every earlier write
+    /// is on the device; filesystems use it to order their updates.
+    /// Drivers that write straight through keep the default.
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// Validates a transfer and returns its length in sectors.
+pub fn check_request(sector_count: u64, lba: u64, len: usize) -> Result<u64, BlockError> {
+    if len == 0 || !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(BlockError::BufferSize);
+    }
+    let count = (len / SECTOR_SIZE) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= sector_count => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// A disk in one of the drivers' device lists, by position. Every transfer
+/// locks that list while it runs, so a `Disk` can be kept anywhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disk {
+    VirtioBlk(usize),
+    Ahci(usize),
+    Nvme(usize),
+    Ide(usize),
+    Ram(usize),
+}
+
+impl Disk {
+    fn with<R>(&self, f: impl FnOnce(&mut dyn BlockDevice) -> R) -> Option<R> {
+        match *self {
+            Disk::VirtioBlk(i) => crate::virtio::blk::DEVICES.lock().get_mut(i).map(|disk| f(disk)),
+            Disk::Ahci(i) => crate::ahci::DEVICES.lock().get_mut(i).map(|disk| f(disk)),
+            Disk::Nvme(i) => crate::nvme::DEVICES.lock().get_mut(i).map(|disk| f(disk)),
+            Disk::Ide(i) => crate::ide::DEVICES.lock().get_mut(i).map(|disk| f(disk)),
+            Disk::Ram(i) => ramdisk::DEVICES.lock().get_mut(i).map(|disk| f(disk)),
+        }
+    }
+}
+
+impl BlockDevice for Disk {
+    fn sector_count(&self) -> u64 {
+        self.with(|disk| disk.sector_count()).unwrap_or(0)
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.with(|disk| disk.read_sectors(lba, buf)).unwrap_or(Err(BlockError::Io))
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.with(|disk| disk.write_sectors(lba, buf)).unwrap_or(Err(BlockError::Io))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError {
+    NoDevice,
+    /// The disk's partition table has no partition with that number.
+    NoPartition,
+    Table(PartitionError),
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            OpenError::NoDevice => "no such device",
+            OpenError::NoPartition => "no such partition",
+            OpenError::Table(err) => return write!(f, "{}", err),
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Block devices by name. Drivers register each disk as the next
+/// `<prefix><n>`: `virtio0`, `ata0`, `nvme0`, `ide0`, `ram0`. A partition is its disk's
+/// name, `p` and its number in the table, `ata0p1`; the table is read when
+/// the partition is opened, so it may change while the disk is registered.
+pub struct Registry<D> {
+    disks: Vec<(String, D)>,
+}
+
+impl<D> Default for Registry<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D> Registry<D> {
+    pub const fn new() -> Self {
+        Registry { disks: Vec::new() }
+    }
+}
+
+impl<D: BlockDevice + Clone> Registry<D> {
+    /// Adds a disk and returns the name it got.
+    pub fn register(&mut self, prefix: &str, disk: D) -> String {
+        let taken = self
+            .disks
+            .iter()
+            .filter_map(|(name, _)| name.strip_prefix(prefix))
+            .filter(|n| n.parse::<usize>().is_ok())
+            .count();
+        let name = alloc::format!("{}{}", prefix, taken);
+        self.disks.push((name.clone(), disk));
+        name
+    }
+
+    /// Whole disks and their sizes in sectors, in registration order.
+    pub fn disks(&self) -> Vec<(String, u64)> {
+        self.disks.iter().map(|(name, disk)| (name.clone(), disk.sector_count())).collect()
+    }
+
+    /// Every name `open` accepts: each disk, followed by its partitions if
+    /// it has a readable table.
+    pub fn volumes(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for (name, disk) in &self.disks {
+            names.push(name.clone());
+            if let Ok(table) = partitions::scan(&mut disk.clone()) {
+                names.extend(table.iter().map(|partition| alloc::format!("{}p{}", name, partition.number)));
+            }
+        }
+        names
+    }
+
+    /// A disk, or one partition of it, as a device of its own.
+    pub fn open(&self, name: &str) -> Result<PartitionDevice<D>, OpenError> {
+        if let Some(disk) = self.find(name) {
+            return Ok(PartitionDevice::whole(disk));
+        }
+        let (disk, number) = name.rsplit_once('p').ok_or(OpenError::NoDevice)?;
+        let (Some(mut disk), Ok(number)) = (self.find(disk), number.parse::<usize>()) else {
+            return Err(OpenError::NoDevice);
+        };
+        let table = partitions::scan(&mut disk).map_err(OpenError::Table)?;
+        let partition = table.iter().find(|partition| partition.number == number).ok_or(OpenError::NoPartition)?;
+        PartitionDevice::new(disk, partition).map_err(|err| OpenError::Table(PartitionError::Io(err)))
+    }
+
+    fn find(&self, name: &str) -> Option<D> {
+        self.disks.iter().find(|(disk, _)| disk == name).map(|(_, disk)| disk.clone())
+    }
+}
+
+/// The drivers' disks.
+pub static DISKS: Mutex<Registry<Disk>> = Mutex::new(Registry::new());
+
+/// Called by a driver for each disk it brings up.
+pub fn register(prefix: &str, disk: Disk) -> String {
+    DISKS.lock().register(prefix, disk)
+}
+
+//test case
+#[test_case]
+fn test_check_request() {
+    assert_eq!(check_request(100, 0, 512), Ok(1));
+    assert_eq!(check_request(100, 98, 1024), Ok(2));
+    assert_eq!(check_request(100, 99, 1024), Err(BlockError::OutOfRange));
+    assert_eq!(check_request(100, u64::MAX, 512), Err(BlockError::OutOfRange));
+    assert_eq!(check_request(100, 0, 100), Err(BlockError::BufferSize));
+    assert_eq!(check_request(100, 0, 0), Err(BlockError::BufferSize));
+}
+
+#[test_case]
+fn test_registry() {
+    use crate::partitions::{testdata, TYPE_FAT16, TYPE_LINUX};
+    use testdata::RamDisk;
+
+    let mut registry = Registry::new();
+    assert_eq!(registry.register("ata", testdata::mbr_disk(&[(TYPE_FAT16, 2048, 100), (TYPE_LINUX, 4096, 50)])), "ata0");
+    assert_eq!(registry.register("virtio", RamDisk::new(64)), "virtio0");
+    assert_eq!(registry.register("ata", RamDisk::new(32)), "ata1");
+    assert_eq!(registry.disks()[2], (String::from("ata1"), 32));
+    assert_eq!(registry.volumes(), ["ata0", "ata0p1", "ata0p2", "virtio0", "ata1"]);
+
+    assert_eq!(registry.open("virtio0").unwrap().sector_count(), 64);
+    assert_eq!(registry.open("ata0p2").unwrap().sector_count(), 50);
+    assert_eq!(registry.open("ata0p3").err(), Some(OpenError::NoPartition));
+    assert_eq!(registry.open("ata1p1").err(), Some(OpenError::Table(PartitionError::NoTable)));
+    for name in ["ata2", "ata0p", "atap1", "ata0px", ""] {
+        assert_eq!(registry.open(name).err(), Some(OpenError::NoDevice), "{}", name);
+    }
+}
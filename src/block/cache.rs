@@ -0,0 +1,267 @@
+//! Write-back sector cache in front of a `BlockDevice`.
+//!
+//! A fixed number of sectors, allocated from a slab cache as they are
+//! first used, evicted least recently used first. Lookups and eviction
+//! scan the entries, which is the cheap choice for the few dozen sectors
+//! this is meant for. A dirty sector is written out before its entry is reused,
+//! and `flush` writes every dirty sector in ascending LBA order; a read of
+//! a dirty sector is answered from the cache, so callers always see their
+//! own writes.
+//!
+//! `BlockCache` is itself a `BlockDevice`, so filesystems mount on top of
+//! it unchanged, and its `flush` is the ordering barrier they rely on.
+
+use alloc::vec::Vec;
+use crate::allocator::slab::{Cache, SlabBox};
+use super::{BlockDevice, BlockError, SECTOR_SIZE};
+
+/// Sectors kept by the filesystems' caches, 16 KiB.
+pub const DEFAULT_CAPACITY: usize = 32;
+
+/// Sector buffers of every cache.
+static SECTORS: Cache<[u8; SECTOR_SIZE]> = Cache::new("sectors");
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Dirty sectors written to the device, on eviction or flush.
+    pub writebacks: u64,
+    pub evictions: u64,
+}
+
+struct Entry {
+    lba: u64,
+    data: SlabBox<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    last_used: u64,
+}
+
+pub struct BlockCache<D: BlockDevice> {
+    device: D,
+    entries: Vec<Entry>,
+    capacity: usize,
+    /// Stamp for the next access; the smallest `last_used` is the LRU.
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl<D: BlockDevice> BlockCache<D> {
+    pub fn new(device: D, capacity: usize) -> Self {
+        assert!(capacity > 0, "a block cache needs at least one entry");
+        BlockCache { device, entries: Vec::new(), capacity, clock: 0, stats: CacheStats::default() }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Flushes and hands the device back.
+    pub fn into_inner(mut self) -> Result<D, BlockError> {
+        self.flush()?;
+        Ok(self.device)
+    }
+
+    pub fn read_sector(&mut self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), BlockError> {
+        if lba >= self.device.sector_count() {
+            return Err(BlockError::OutOfRange);
+        }
+        if let Some(index) = self.lookup(lba) {
+            self.stats.hits += 1;
+            buf.copy_from_slice(&self.entries[index].data[..]);
+            return Ok(());
+        }
+        self.stats.misses += 1;
+        // the entry is only taken once the read succeeded
+        self.device.read_sectors(lba, buf)?;
+        let index = self.slot()?;
+        self.fill(index, lba, buf, false);
+        Ok(())
+    }
+
+    /// Caches a write; the device sees it on eviction or flush.
+    pub fn write_sector(&mut self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), BlockError> {
+        if lba >= self.device.sector_count() {
+            return Err(BlockError::OutOfRange);
+        }
+        let index = match self.lookup(lba) {
+            Some(index) => index,
+            None => self.slot()?,
+        };
+        self.fill(index, lba, buf, true);
+        Ok(())
+    }
+
+    /// Writes one sector out if it is cached and dirty.
+    pub fn flush_sector(&mut self, lba: u64) -> Result<(), BlockError> {
+        match self.entries.iter().position(|entry| entry.lba == lba) {
+            Some(index) => self.write_back(index),
+            None => Ok(()),
+        }
+    }
+
+    fn lookup(&mut self, lba: u64) -> Option<usize> {
+        let index = self.entries.iter().position(|entry| entry.lba == lba)?;
+        self.clock += 1;
+        self.entries[index].last_used = self.clock;
+        Some(index)
+    }
+
+    /// An entry to put a new sector in: a fresh one while below capacity,
+    /// else the least recently used, written out first if dirty.
+    fn slot(&mut self) -> Result<usize, BlockError> {
+        if self.entries.len() < self.capacity {
+            let data = SlabBox::new(&SECTORS, [0; SECTOR_SIZE]).expect("no memory for a cached sector");
+            self.entries.push(Entry { lba: u64::MAX, data, dirty: false, last_used: 0 });
+            return Ok(self.entries.len() - 1);
+        }
+        let (index, _) = self.entries.iter().enumerate().min_by_key(|(_, entry)| entry.last_used).unwrap();
+        self.write_back(index)?;
+        self.stats.evictions += 1;
+        Ok(index)
+    }
+
+    fn fill(&mut self, index: usize, lba: u64, data: &[u8; SECTOR_SIZE], dirty: bool) {
+        self.clock += 1;
+        let entry = &mut self.entries[index];
+        entry.lba = lba;
+        entry.data.copy_from_slice(data);
+        entry.dirty |= dirty;
+        entry.last_used = self.clock;
+    }
+
+    fn write_back(&mut self, index: usize) -> Result<(), BlockError> {
+        let entry = &mut self.entries[index];
+        if entry.dirty {
+            self.device.write_sectors(entry.lba, &entry.data[..])?;
+            entry.dirty = false;
+            self.stats.writebacks += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BlockCache<D> {
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_request(self.device.sector_count(), lba, buf.len())?;
+        for (i, chunk) in buf.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            let chunk: &mut [u8; SECTOR_SIZE] = chunk.try_into().unwrap();
+            self.read_sector(lba + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        super::check_request(self.device.sector_count(), lba, buf.len())?;
+        for (i, chunk) in buf.chunks_exact(SECTOR_SIZE).enumerate() {
+            self.write_sector(lba + i as u64, chunk.try_into().unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty sector, lowest LBA first, then flushes the
+    /// device below.
+    fn flush(&mut self) -> Result<(), BlockError> {
+        let mut dirty: Vec<usize> = (0..self.entries.len()).filter(|&i| self.entries[i].dirty).collect();
+        dirty.sort_unstable_by_key(|&i| self.entries[i].lba);
+        for index in dirty {
+            self.write_back(index)?;
+        }
+        self.device.flush()
+    }
+}
+
+//test case
+/// Eight sectors, each filled with its LBA, and a log of every transfer.
+#[cfg(test)]
+struct Recorder {
+    sectors: [[u8; SECTOR_SIZE]; 8],
+    log: Vec<(char, u64)>,
+}
+
+#[cfg(test)]
+impl Recorder {
+    fn new() -> Self {
+        let mut sectors = [[0; SECTOR_SIZE]; 8];
+        for (lba, sector) in sectors.iter_mut().enumerate() {
+            sector.fill(lba as u8);
+        }
+        Recorder { sectors, log: Vec::new() }
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for Recorder {
+    fn sector_count(&self) -> u64 {
+        8
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.log.push(('r', lba));
+        buf.copy_from_slice(&self.sectors[lba as usize]);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.log.push(('w', lba));
+        self.sectors[lba as usize].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_lru_eviction() {
+    let mut cache = BlockCache::new(Recorder::new(), 2);
+    let mut buf = [0u8; SECTOR_SIZE];
+    cache.read_sector(0, &mut buf).unwrap();
+    cache.read_sector(1, &mut buf).unwrap();
+    cache.read_sector(0, &mut buf).unwrap();
+    // 1 is now the least recently used and makes room for 2
+    cache.read_sector(2, &mut buf).unwrap();
+    cache.read_sector(0, &mut buf).unwrap();
+    assert_eq!(buf, [0; SECTOR_SIZE]);
+    cache.read_sector(1, &mut buf).unwrap();
+    assert_eq!(buf, [1; SECTOR_SIZE]);
+    assert_eq!(cache.device.log, [('r', 0), ('r', 1), ('r', 2), ('r', 1)]);
+    assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 4, writebacks: 0, evictions: 2 });
+    assert_eq!(cache.read_sector(8, &mut buf), Err(BlockError::OutOfRange));
+}
+
+#[test_case]
+fn test_write_back() {
+    let mut cache = BlockCache::new(Recorder::new(), 2);
+    let mut buf = [0u8; SECTOR_SIZE];
+    cache.write_sector(5, &[0x55; SECTOR_SIZE]).unwrap();
+    cache.write_sector(3, &[0x33; SECTOR_SIZE]).unwrap();
+    // a dirty sector reads back from the cache, not the device
+    cache.read_sector(5, &mut buf).unwrap();
+    assert_eq!(buf, [0x55; SECTOR_SIZE]);
+    assert!(cache.device.log.is_empty());
+
+    // evicting dirty 3 writes it out before 6 is read into its entry
+    cache.read_sector(6, &mut buf).unwrap();
+    assert_eq!(cache.device.log, [('r', 6), ('w', 3)]);
+    assert_eq!(cache.device.sectors[3], [0x33; SECTOR_SIZE]);
+
+    cache.write_sector(6, &[0x66; SECTOR_SIZE]).unwrap();
+    cache.flush_sector(6).unwrap();
+    cache.flush_sector(6).unwrap();
+    assert_eq!(cache.device.log[2..], [('w', 6)]);
+    cache.write_sector(6, &[0x67; SECTOR_SIZE]).unwrap();
+    // flush goes in LBA order whatever order the writes came in
+    cache.flush().unwrap();
+    assert_eq!(cache.device.log[3..], [('w', 5), ('w', 6)]);
+    cache.flush().unwrap();
+    assert_eq!(cache.device.log.len(), 5);
+    assert_eq!(cache.stats().writebacks, 4);
+
+    let mut cache = BlockCache::new(Recorder::new(), 4);
+    cache.write_sectors(2, &[0xaa; 2 * SECTOR_SIZE]).unwrap();
+    let device = cache.into_inner().unwrap();
+    assert_eq!(device.log, [('w', 2), ('w', 3)]);
+    assert_eq!(device.sectors[3], [0xaa; SECTOR_SIZE]);
+}
@@ -0,0 +1,94 @@
+//! Disks held on the kernel heap, so filesystems can be tried without a
+//! drive attached. Their contents go with the machine.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::{BlockDevice, BlockError, Disk, SECTOR_SIZE};
+
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    /// A zeroed disk of `sectors` sectors; `None` if the heap can't hold
+    /// it.
+    pub fn new(sectors: u64) -> Option<RamDisk> {
+        let len = usize::try_from(sectors).ok()?.checked_mul(SECTOR_SIZE)?;
+        let mut data = Vec::new();
+        data.try_reserve_exact(len).ok()?;
+        data.resize(len, 0);
+        Some(RamDisk { data })
+    }
+
+    fn range(&self, lba: u64, len: usize) -> Result<core::ops::Range<usize>, BlockError> {
+        super::check_request(self.sector_count(), lba, len)?;
+        let start = lba as usize * SECTOR_SIZE;
+        Ok(start..start + len)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_count(&self) -> u64 {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let range = self.range(lba, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let range = self.range(lba, buf.len())?;
+        self.data[range].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// RAM disks made by `create`, in order.
+pub static DEVICES: Mutex<Vec<RamDisk>> = Mutex::new(Vec::new());
+
+/// Makes a RAM disk of `sectors` sectors and registers it as `ram<n>`.
+/// Returns its name, or `None` if the heap is too small for it.
+///
+/// `DEVICES` is let go before registering: reading a RAM disk locks it
+/// under `DISKS`, so it must never be held while taking `DISKS`.
+pub fn create(sectors: u64) -> Option<String> {
+    let disk = RamDisk::new(sectors)?;
+    let index = {
+        let mut devices = DEVICES.lock();
+        devices.try_reserve(1).ok()?;
+        devices.push(disk);
+        devices.len() - 1
+    };
+    Some(super::register("ram", Disk::Ram(index)))
+}
+
+//test case
+#[test_case]
+fn test_ramdisk() {
+    let mut disk = RamDisk::new(4).unwrap();
+    assert_eq!(disk.sector_count(), 4);
+    let mut sectors = [0xaa; 2 * SECTOR_SIZE];
+    disk.write_sectors(2, &sectors).unwrap();
+    sectors.fill(0);
+    disk.read_sectors(1, &mut sectors).unwrap();
+    assert!(sectors[..SECTOR_SIZE].iter().all(|&b| b == 0));
+    assert!(sectors[SECTOR_SIZE..].iter().all(|&b| b == 0xaa));
+    assert_eq!(disk.write_sectors(3, &sectors), Err(BlockError::OutOfRange));
+    assert_eq!(disk.read_sectors(0, &mut sectors[..100]), Err(BlockError::BufferSize));
+    assert!(RamDisk::new(u64::MAX).is_none());
+}
+
+#[test_case]
+fn test_create_registers_the_disk() {
+    let name = create(8).unwrap();
+    assert!(name.starts_with("ram"));
+    let mut disk = super::DISKS.lock().open(&name).unwrap();
+    assert_eq!(disk.sector_count(), 8);
+    disk.write_sectors(7, &[1; SECTOR_SIZE]).unwrap();
+    let mut sector = [0; SECTOR_SIZE];
+    disk.read_sectors(7, &mut sector).unwrap();
+    assert_eq!(sector, [1; SECTOR_SIZE]);
+}
@@ -0,0 +1,105 @@
+//! A sparse RAM disk for the storage tests, small on the kernel heap
+//! whatever its size.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use spin::Mutex;
+use super::{BlockDevice, BlockError, SECTOR_SIZE};
+
+/// Sectors that were never written read as zeros and take no memory.
+#[derive(Clone)]
+pub struct RamDisk {
+    sectors: u64,
+    data: BTreeMap<u64, Box<[u8; SECTOR_SIZE]>>,
+    /// Sector writes that land; the ones after are dropped, as if the
+    /// power went out.
+    limit: Option<usize>,
+    writes: usize,
+    crashed: bool,
+}
+
+impl RamDisk {
+    pub fn new(sectors: u64) -> Self {
+        RamDisk { sectors, data: BTreeMap::new(), limit: None, writes: 0, crashed: false }
+    }
+
+    pub fn sector(&self, lba: u64) -> [u8; SECTOR_SIZE] {
+        self.data.get(&lba).map_or([0; SECTOR_SIZE], |sector| **sector)
+    }
+
+    pub fn patch(&mut self, lba: u64, offset: usize, bytes: &[u8]) {
+        let mut sector = self.sector(lba);
+        sector[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.data.insert(lba, Box::new(sector));
+    }
+
+    pub fn crash_after(&mut self, writes: usize) {
+        self.limit = Some(writes);
+        self.writes = 0;
+    }
+
+    /// Whether any write was dropped.
+    pub fn crashed(&self) -> bool {
+        self.crashed
+    }
+
+    /// Copies every sector of `image` to this disk, `lba` sectors in.
+    pub fn place(&mut self, lba: u64, image: &RamDisk) {
+        for (&at, sector) in &image.data {
+            self.patch(lba + at, 0, &sector[..]);
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_request(self.sectors, lba, buf.len())?;
+        for (i, chunk) in buf.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            chunk.copy_from_slice(&self.sector(lba + i as u64));
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        super::check_request(self.sectors, lba, buf.len())?;
+        for (i, chunk) in buf.chunks_exact(SECTOR_SIZE).enumerate() {
+            if self.limit.is_some_and(|limit| self.writes >= limit) {
+                self.crashed = true;
+                continue;
+            }
+            self.writes += 1;
+            self.patch(lba + i as u64, 0, chunk);
+        }
+        Ok(())
+    }
+}
+
+/// A `RamDisk` whose clones all see the same sectors, for code that keeps
+/// its own copy of a device.
+#[derive(Clone)]
+pub struct SharedDisk(pub Arc<Mutex<RamDisk>>);
+
+impl SharedDisk {
+    pub fn new(disk: RamDisk) -> Self {
+        SharedDisk(Arc::new(Mutex::new(disk)))
+    }
+}
+
+impl BlockDevice for SharedDisk {
+    fn sector_count(&self) -> u64 {
+        self.0.lock().sector_count()
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.0.lock().read_sectors(lba, buf)
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.0.lock().write_sectors(lba, buf)
+    }
+}
@@ -0,0 +1,476 @@
+//! What the kernel gets from whatever booted it, in the shape of the
+//! bootloader 0.11 API (`bootloader_api::BootInfo`).
+//!
+//! Two loaders can start the kernel, picked by Cargo feature:
+//!
+//! - `bootloader-legacy`, the default: `bootloader` 0.9 through
+//!   bootimage (or the Multiboot2 shim, which builds the same 0.9
+//!   `BootInfo`). Its boot information is converted to the types here.
+//! - `bootloader-api`: `bootloader` 0.11, entered through
+//!   `bootloader_api::entry_point!` with `BOOTLOADER_CONFIG`, which asks
+//!   for the physical memory mapping. Its memory regions are copied and
+//!   its framebuffer's virtual address is turned back into a physical one.
+//!
+//! `entry_point!` wraps whichever entry point is in use and converts the
+//! boot information once, so the rest of the kernel only sees the types
+//! here. They follow 0.11: the physical memory offset is optional, the
+//! memory map is a slice of `MemoryRegion`s with a `MemoryRegionKind`, and
+//! the framebuffer and the RSDP address come along when the loader knows
+//! them.
+
+#[cfg(all(feature = "bootloader-legacy", feature = "bootloader-api"))]
+compile_error!("pick one loader: `bootloader-legacy` (the default) or `bootloader-api` with --no-default-features");
+#[cfg(not(any(feature = "bootloader-legacy", feature = "bootloader-api")))]
+compile_error!("no loader: enable `bootloader-legacy` or `bootloader-api`");
+
+#[cfg(feature = "bootloader-legacy")]
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt;
+use spin::Once;
+use x86_64::VirtAddr;
+use crate::multiboot2::{self, FramebufferKind};
+use crate::println;
+
+#[doc(hidden)]
+#[cfg(feature = "bootloader-legacy")]
+pub use bootloader::{entry_point as legacy_entry_point, BootInfo as LegacyBootInfo};
+#[doc(hidden)]
+#[cfg(feature = "bootloader-api")]
+pub use bootloader_api::{entry_point as api_entry_point, BootInfo as ApiBootInfo};
+
+/// What the kernel asks bootloader 0.11 for: everything the default
+/// config gives, plus all of physical memory mapped somewhere.
+#[cfg(feature = "bootloader-api")]
+pub const BOOTLOADER_CONFIG: bootloader_api::BootloaderConfig = {
+    let mut config = bootloader_api::BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(bootloader_api::config::Mapping::Dynamic);
+    config
+};
+
+/// Most regions kept from the loader's memory map. A 0.9 map holds 64;
+/// UEFI firmware under 0.11 can report more.
+pub const MAX_REGIONS: usize = 256;
+
+/// Who owns a region, as in `bootloader_api::info::MemoryRegionKind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    /// Free for the kernel to use.
+    Usable,
+    /// In use by the loader or the kernel image: its code, stack, page
+    /// tables, the boot information and any loaded package. Never handed
+    /// out by the frame allocator.
+    Bootloader,
+    /// An address range the UEFI firmware reports, with its memory type.
+    UnknownUefi(u32),
+    /// An address range the BIOS (E820) reports, with its type.
+    UnknownBios(u32),
+}
+
+/// E820 types, for the regions the BIOS keeps.
+const E820_RESERVED: u32 = 2;
+#[cfg(feature = "bootloader-legacy")]
+const E820_ACPI_RECLAIMABLE: u32 = 3;
+#[cfg(feature = "bootloader-legacy")]
+const E820_ACPI_NVS: u32 = 4;
+#[cfg(feature = "bootloader-legacy")]
+const E820_BAD_MEMORY: u32 = 5;
+
+/// The 0.11 kind of a 0.9 region type. Empty regions have none and are
+/// dropped.
+#[cfg(feature = "bootloader-legacy")]
+pub fn region_kind(legacy: MemoryRegionType) -> Option<MemoryRegionKind> {
+    use MemoryRegionType::*;
+    Some(match legacy {
+        Usable => MemoryRegionKind::Usable,
+        InUse | Kernel | KernelStack | PageTable | Bootloader | BootInfo | Package => MemoryRegionKind::Bootloader,
+        // 0.11 has no kind for the null frame; it takes it out of usable
+        // memory by leaving it to the BIOS.
+        FrameZero | Reserved => MemoryRegionKind::UnknownBios(E820_RESERVED),
+        AcpiReclaimable => MemoryRegionKind::UnknownBios(E820_ACPI_RECLAIMABLE),
+        AcpiNvs => MemoryRegionKind::UnknownBios(E820_ACPI_NVS),
+        BadMemory => MemoryRegionKind::UnknownBios(E820_BAD_MEMORY),
+        Empty => return None,
+        _ => MemoryRegionKind::UnknownBios(E820_RESERVED),
+    })
+}
+
+/// A physical address range, `start..end`, as in
+/// `bootloader_api::info::MemoryRegion`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryRegionKind,
+}
+
+impl MemoryRegion {
+    pub const fn empty() -> MemoryRegion {
+        MemoryRegion { start: 0, end: 0, kind: MemoryRegionKind::Bootloader }
+    }
+}
+
+/// The kind of a 0.11 region. Its `Bootloader` regions hold the kernel,
+/// its stack, the page tables, the boot information and the ramdisk, so
+/// like 0.9's in-use types they stay out of the frame allocator. Kinds a
+/// later 0.11 adds count as reserved.
+#[cfg(feature = "bootloader-api")]
+pub fn api_region_kind(kind: bootloader_api::info::MemoryRegionKind) -> MemoryRegionKind {
+    use bootloader_api::info::MemoryRegionKind as Api;
+    match kind {
+        Api::Usable => MemoryRegionKind::Usable,
+        Api::Bootloader => MemoryRegionKind::Bootloader,
+        Api::UnknownUefi(kind) => MemoryRegionKind::UnknownUefi(kind),
+        Api::UnknownBios(kind) => MemoryRegionKind::UnknownBios(kind),
+        _ => MemoryRegionKind::UnknownBios(E820_RESERVED),
+    }
+}
+
+/// Copies 0.11 memory regions into `out` like `convert_memory_map`. Empty
+/// ones are dropped.
+#[cfg(feature = "bootloader-api")]
+pub fn convert_regions(regions: &[bootloader_api::info::MemoryRegion], out: &mut [MemoryRegion]) -> usize {
+    let regions = regions
+        .iter()
+        .filter(|region| region.start < region.end)
+        .map(|region| MemoryRegion { start: region.start, end: region.end, kind: api_region_kind(region.kind) });
+    let mut count = 0;
+    for (slot, region) in out.iter_mut().zip(regions) {
+        *slot = region;
+        count += 1;
+    }
+    count
+}
+
+/// Converts a 0.9 memory map into `out`, returning how many regions it
+/// filled. Regions past the end of `out` are dropped.
+#[cfg(feature = "bootloader-legacy")]
+pub fn convert_memory_map(legacy: &MemoryMap, out: &mut [MemoryRegion]) -> usize {
+    let regions = legacy.iter().filter_map(|region| {
+        let kind = region_kind(region.region_type)?;
+        Some(MemoryRegion { start: region.range.start_addr(), end: region.range.end_addr(), kind })
+    });
+    let mut count = 0;
+    for (slot, region) in out.iter_mut().zip(regions) {
+        *slot = region;
+        count += 1;
+    }
+    count
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+    /// One byte per pixel: grayscale, or an index into the palette.
+    U8,
+    Unknown,
+}
+
+/// A linear framebuffer, as in `bootloader_api::info::FrameBufferInfo`,
+/// plus its physical address. Sizes are in pixels and bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBufferInfo {
+    pub address: u64,
+    pub byte_len: usize,
+    pub width: usize,
+    pub height: usize,
+    pub pixel_format: PixelFormat,
+    pub bytes_per_pixel: usize,
+    /// Pixels per line, which can be more than `width`.
+    pub stride: usize,
+}
+
+impl FrameBufferInfo {
+    /// A Multiboot2 framebuffer tag. EGA text mode isn't a framebuffer in
+    /// the 0.11 sense and gives `None`.
+    pub fn from_multiboot2(framebuffer: &multiboot2::Framebuffer) -> Option<FrameBufferInfo> {
+        let pixel_format = match framebuffer.kind {
+            FramebufferKind::Text => return None,
+            FramebufferKind::Indexed => PixelFormat::U8,
+            // GRUB sets up 32-bit modes as BGRX, the common layout; the
+            // tag's field positions aren't read yet
+            FramebufferKind::Rgb if framebuffer.bpp == 32 => PixelFormat::Bgr,
+            FramebufferKind::Rgb | FramebufferKind::Other(_) => PixelFormat::Unknown,
+        };
+        let bytes_per_pixel = usize::from(framebuffer.bpp).div_ceil(8);
+        if bytes_per_pixel == 0 {
+            return None;
+        }
+        let pitch = framebuffer.pitch as usize;
+        Some(FrameBufferInfo {
+            address: framebuffer.address,
+            byte_len: pitch * framebuffer.height as usize,
+            width: framebuffer.width as usize,
+            height: framebuffer.height as usize,
+            pixel_format,
+            bytes_per_pixel,
+            stride: pitch / bytes_per_pixel,
+        })
+    }
+
+    /// A 0.11 framebuffer, which the loader maps at a virtual address;
+    /// `address` is the physical one behind it. 0.11 names a pixel format
+    /// it has no entry for `Unknown`, and so does this.
+    #[cfg(feature = "bootloader-api")]
+    pub fn from_api(info: bootloader_api::info::FrameBufferInfo, address: u64) -> FrameBufferInfo {
+        use bootloader_api::info::PixelFormat as Api;
+        let pixel_format = match info.pixel_format {
+            Api::Rgb => PixelFormat::Rgb,
+            Api::Bgr => PixelFormat::Bgr,
+            Api::U8 => PixelFormat::U8,
+            _ => PixelFormat::Unknown,
+        };
+        FrameBufferInfo {
+            address,
+            byte_len: info.byte_len,
+            width: info.width,
+            height: info.height,
+            pixel_format,
+            bytes_per_pixel: info.bytes_per_pixel,
+            stride: info.stride,
+        }
+    }
+}
+
+impl fmt::Display for FrameBufferInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{} {:?}, {} bytes/pixel, stride {}, at {:#x}",
+            self.width, self.height, self.pixel_format, self.bytes_per_pixel, self.stride, self.address)
+    }
+}
+
+/// The boot information, as in `bootloader_api::BootInfo`.
+#[derive(Debug)]
+pub struct BootInfo {
+    /// Where all of physical memory is mapped, if the loader was asked to.
+    pub physical_memory_offset: Option<u64>,
+    pub memory_regions: &'static [MemoryRegion],
+    pub framebuffer: Option<FrameBufferInfo>,
+    /// Physical address of the ACPI RSDP, if the loader found it.
+    pub rsdp_addr: Option<u64>,
+    /// Physical address of the ramdisk (the initrd), if one was loaded.
+    pub ramdisk_addr: Option<u64>,
+    pub ramdisk_len: u64,
+    /// The kernel command line, if the loader can pass one (0.11 can't;
+    /// GRUB can). `cmdline` falls back to fw_cfg without it.
+    pub cmdline: Option<&'static str>,
+}
+
+impl BootInfo {
+    /// The physical memory offset. The kernel can't do without it: paging,
+    /// the heap and every driver go through that mapping.
+    pub fn physical_memory_offset(&self) -> VirtAddr {
+        match self.physical_memory_offset {
+            Some(offset) => VirtAddr::new(offset),
+            None => panic!("physical memory is not mapped: the boot config must request the physical memory mapping"),
+        }
+    }
+
+    /// Usable memory, in bytes.
+    pub fn usable_bytes(&self) -> u64 {
+        self.memory_regions.iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| region.end - region.start)
+            .sum()
+    }
+
+    /// The ramdisk through the physical memory mapping. Its frames are
+    /// never handed out, so the slice stays valid for the kernel's life.
+    pub fn ramdisk(&self) -> Option<&'static [u8]> {
+        let addr = self.ramdisk_addr?;
+        let virt = self.physical_memory_offset() + addr;
+        Some(unsafe { core::slice::from_raw_parts(virt.as_ptr(), self.ramdisk_len as usize) })
+    }
+
+    /// Prints what the loader passed beyond the memory map.
+    pub fn print_summary(&self) {
+        println!("boot: {} memory regions, {} KiB usable", self.memory_regions.len(), self.usable_bytes() / 1024);
+        if let Some(framebuffer) = &self.framebuffer {
+            println!("boot: framebuffer {}", framebuffer);
+        }
+        if let Some(rsdp) = self.rsdp_addr {
+            println!("boot: RSDP at {:#x}", rsdp);
+        }
+        if let Some(ramdisk) = self.ramdisk_addr {
+            println!("boot: ramdisk at {:#x}, {} bytes", ramdisk, self.ramdisk_len);
+        }
+        if let Some(cmdline) = self.cmdline {
+            println!("boot: command line '{}'", cmdline);
+        }
+    }
+}
+
+static REGIONS: Once<([MemoryRegion; MAX_REGIONS], usize)> = Once::new();
+static BOOT_INFO: Once<BootInfo> = Once::new();
+
+/// Converts the 0.9 boot information. Called by `entry_point!`; later
+/// calls return the first conversion.
+#[cfg(feature = "bootloader-legacy")]
+pub fn from_legacy(legacy: &'static bootloader::BootInfo) -> &'static BootInfo {
+    let (regions, count) = REGIONS.call_once(|| {
+        let mut regions = [MemoryRegion::empty(); MAX_REGIONS];
+        let count = convert_memory_map(&legacy.memory_map, &mut regions);
+        (regions, count)
+    });
+    BOOT_INFO.call_once(|| BootInfo {
+        // the `map_physical_memory` feature is on, so 0.9 always maps it
+        physical_memory_offset: Some(legacy.physical_memory_offset),
+        memory_regions: &regions[..*count],
+        framebuffer: multiboot2::framebuffer_hint().as_ref().and_then(FrameBufferInfo::from_multiboot2),
+        rsdp_addr: multiboot2::rsdp_hint(),
+        ramdisk_addr: multiboot2::ramdisk_hint().map(|(addr, _)| addr),
+        ramdisk_len: multiboot2::ramdisk_hint().map_or(0, |(_, len)| len),
+        cmdline: multiboot2::cmdline_hint(),
+    })
+}
+
+/// Converts the 0.11 boot information. Called by `entry_point!`; later
+/// calls return the first conversion.
+#[cfg(feature = "bootloader-api")]
+pub fn from_api(info: &'static bootloader_api::BootInfo) -> &'static BootInfo {
+    let (regions, count) = REGIONS.call_once(|| {
+        let mut regions = [MemoryRegion::empty(); MAX_REGIONS];
+        let count = convert_regions(&info.memory_regions, &mut regions);
+        (regions, count)
+    });
+    let physical_memory_offset = info.physical_memory_offset.into_option();
+    // the loader's pointers are virtual; the kernel keeps physical ones
+    let physical = |virt: u64| {
+        let offset = VirtAddr::new(physical_memory_offset?);
+        unsafe { crate::memory::translate_addr(VirtAddr::new(virt), offset) }.map(|phys| phys.as_u64())
+    };
+    let framebuffer = info.framebuffer.as_ref().and_then(|framebuffer| {
+        let address = physical(framebuffer.buffer().as_ptr() as u64)?;
+        Some(FrameBufferInfo::from_api(framebuffer.info(), address))
+    });
+    let ramdisk_addr = info.ramdisk_addr.into_option().and_then(physical);
+    BOOT_INFO.call_once(|| BootInfo {
+        physical_memory_offset,
+        memory_regions: &regions[..*count],
+        framebuffer,
+        rsdp_addr: info.rsdp_addr.into_option(),
+        ramdisk_len: if ramdisk_addr.is_some() { info.ramdisk_len } else { 0 },
+        ramdisk_addr,
+        cmdline: None,
+    })
+}
+
+/// The boot information, once `entry_point!` has converted it.
+pub fn boot_info() -> Option<&'static BootInfo> {
+    BOOT_INFO.get()
+}
+
+/// Defines the kernel's entry point. `$path` is a
+/// `fn(&'static boot::BootInfo) -> !`, as with the 0.11 `entry_point!`.
+#[cfg(feature = "bootloader-legacy")]
+#[macro_export]
+macro_rules! entry_point {
+    ($path:path) => {
+        fn __boot_entry(boot_info: &'static $crate::boot::LegacyBootInfo) -> ! {
+            let f: fn(&'static $crate::boot::BootInfo) -> ! = $path;
+            f($crate::boot::from_legacy(boot_info))
+        }
+        $crate::boot::legacy_entry_point!(__boot_entry);
+    };
+}
+
+/// Defines the kernel's entry point. `$path` is a
+/// `fn(&'static boot::BootInfo) -> !`, as with the 0.11 `entry_point!`.
+#[cfg(feature = "bootloader-api")]
+#[macro_export]
+macro_rules! entry_point {
+    ($path:path) => {
+        fn __boot_entry(boot_info: &'static mut $crate::boot::ApiBootInfo) -> ! {
+            let f: fn(&'static $crate::boot::BootInfo) -> ! = $path;
+            f($crate::boot::from_api(boot_info))
+        }
+        $crate::boot::api_entry_point!(__boot_entry, config = &$crate::boot::BOOTLOADER_CONFIG);
+    };
+}
+
+//test case
+#[cfg(feature = "bootloader-legacy")]
+#[test_case]
+fn test_region_kinds() {
+    use MemoryRegionType as Legacy;
+
+    assert_eq!(region_kind(Legacy::Usable), Some(MemoryRegionKind::Usable));
+    for used in [Legacy::InUse, Legacy::Kernel, Legacy::KernelStack, Legacy::PageTable,
+        Legacy::Bootloader, Legacy::BootInfo, Legacy::Package] {
+        assert_eq!(region_kind(used), Some(MemoryRegionKind::Bootloader), "{:?}", used);
+    }
+    assert_eq!(region_kind(Legacy::Reserved), Some(MemoryRegionKind::UnknownBios(2)));
+    assert_eq!(region_kind(Legacy::FrameZero), Some(MemoryRegionKind::UnknownBios(2)));
+    assert_eq!(region_kind(Legacy::AcpiReclaimable), Some(MemoryRegionKind::UnknownBios(3)));
+    assert_eq!(region_kind(Legacy::AcpiNvs), Some(MemoryRegionKind::UnknownBios(4)));
+    assert_eq!(region_kind(Legacy::BadMemory), Some(MemoryRegionKind::UnknownBios(5)));
+    assert_eq!(region_kind(Legacy::Empty), None);
+}
+
+#[cfg(feature = "bootloader-legacy")]
+#[test_case]
+fn test_memory_map_conversion() {
+    use bootloader::bootinfo::{FrameRange, MemoryRegion as LegacyRegion};
+
+    let mut legacy = MemoryMap::new();
+    for (start, end, region_type) in [
+        (0x0, 0x1000, MemoryRegionType::FrameZero),
+        (0x1000, 0x9f000, MemoryRegionType::Usable),
+        (0x10_0000, 0x40_0000, MemoryRegionType::Kernel),
+        (0x40_0000, 0x40_0000, MemoryRegionType::Empty),
+        (0x40_0000, 0x800_0000, MemoryRegionType::Usable),
+    ] {
+        legacy.add_region(LegacyRegion { range: FrameRange::new(start, end), region_type });
+    }
+    let mut out = [MemoryRegion::empty(); 8];
+    let count = convert_memory_map(&legacy, &mut out);
+    assert_eq!(count, 4);
+    assert_eq!(out[1], MemoryRegion { start: 0x1000, end: 0x9f000, kind: MemoryRegionKind::Usable });
+    assert_eq!(out[2].kind, MemoryRegionKind::Bootloader);
+    assert_eq!(out[3], MemoryRegion { start: 0x40_0000, end: 0x800_0000, kind: MemoryRegionKind::Usable });
+    // a short buffer keeps the first regions
+    assert_eq!(convert_memory_map(&legacy, &mut out[..2]), 2);
+}
+
+#[test_case]
+fn test_framebuffer_conversion() {
+    let mut framebuffer = multiboot2::Framebuffer {
+        address: 0xfd00_0000, pitch: 4096, width: 1024, height: 768, bpp: 32, kind: FramebufferKind::Rgb,
+    };
+    let info = FrameBufferInfo::from_multiboot2(&framebuffer).unwrap();
+    assert_eq!((info.width, info.height, info.stride, info.bytes_per_pixel), (1024, 768, 1024, 4));
+    assert_eq!(info.byte_len, 4096 * 768);
+    assert_eq!(info.pixel_format, PixelFormat::Bgr);
+    framebuffer.kind = FramebufferKind::Text;
+    assert_eq!(FrameBufferInfo::from_multiboot2(&framebuffer), None);
+}
+
+#[test_case]
+fn test_always_has_physical_memory_offset() {
+    let info = boot_info().expect("the test kernel boots through entry_point!");
+    assert_eq!(Some(info.physical_memory_offset()), crate::memory::physical_memory_offset());
+    assert!(info.memory_regions.iter().all(|region| region.start <= region.end));
+    assert!(info.usable_bytes() > 0);
+}
+
+#[cfg(feature = "bootloader-api")]
+#[test_case]
+fn test_api_region_conversion() {
+    use bootloader_api::info::{MemoryRegion as Api, MemoryRegionKind as ApiKind};
+
+    let regions = [
+        Api { start: 0x0, end: 0x9f000, kind: ApiKind::Usable },
+        Api { start: 0x9f000, end: 0x10_0000, kind: ApiKind::UnknownBios(2) },
+        Api { start: 0x10_0000, end: 0x10_0000, kind: ApiKind::Usable },
+        Api { start: 0x10_0000, end: 0x40_0000, kind: ApiKind::Bootloader },
+        Api { start: 0x40_0000, end: 0x800_0000, kind: ApiKind::UnknownUefi(7) },
+    ];
+    let mut out = [MemoryRegion::empty(); 8];
+    // the empty region is dropped
+    assert_eq!(convert_regions(&regions, &mut out), 4);
+    assert_eq!(out[0], MemoryRegion { start: 0, end: 0x9f000, kind: MemoryRegionKind::Usable });
+    assert_eq!(out[1].kind, MemoryRegionKind::UnknownBios(2));
+    assert_eq!(out[2], MemoryRegion { start: 0x10_0000, end: 0x40_0000, kind: MemoryRegionKind::Bootloader });
+    assert_eq!(out[3].kind, MemoryRegionKind::UnknownUefi(7));
+    assert_eq!(convert_regions(&regions, &mut out[..1]), 1);
+}
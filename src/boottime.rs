@@ -0,0 +1,150 @@
+//! Boot phase timing.
+//!
+//! `mark(name)` stamps the end of an init step with the TSC and the tick
+//! count; `report()` prints how long each phase took. The TSC rate is not
+//! known until it is calibrated, so raw cycles are stored and only
+//! converted to time when the report is printed (or shown as cycles if
+//! calibration never happened).
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::{print, time, warn};
+
+pub const MAX_MARKS: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark {
+    pub name: &'static str,
+    pub tsc: u64,
+    pub ticks: u64,
+}
+
+struct Marks {
+    marks: [Option<Mark>; MAX_MARKS],
+    len: usize,
+    dropped: usize,
+}
+
+static MARKS: Mutex<Marks> = Mutex::new(Marks { marks: [None; MAX_MARKS], len: 0, dropped: 0 });
+static WARNED: AtomicBool = AtomicBool::new(false);
+/// Records the end of phase `name`. Past `MAX_MARKS` marks are dropped.
+pub fn mark(name: &'static str) {
+    let mark = Mark { name, tsc: time::rdtsc(), ticks: time::ticks() };
+    let stored = interrupts::without_interrupts(|| {
+        let mut table = MARKS.lock();
+        if table.len < MAX_MARKS {
+            let len = table.len;
+            table.marks[len] = Some(mark);
+            table.len += 1;
+            true
+        } else {
+            table.dropped += 1;
+            false
+        }
+    });
+    if !stored && !WARNED.swap(true, Ordering::Relaxed) {
+        warn!("boottime: more than {} marks, dropping '{}' and later ones", MAX_MARKS, name);
+    }
+}
+
+/// A cycle count shown as microseconds when the rate is known.
+struct Duration {
+    cycles: u64,
+    tsc_hz: Option<u64>,
+}
+
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.tsc_hz {
+            Some(hz) => {
+                let micros = u128::from(self.cycles) * 1_000_000 / u128::from(hz);
+                write!(f, "{:>10} us", micros)
+            }
+            None => write!(f, "{:>10} cyc", self.cycles),
+        }
+    }
+}
+
+/// Writes one line per mark: the phase, its own duration (since the
+/// previous mark) and the time since the first mark.
+pub fn write_report(out: &mut dyn Write, marks: &[Mark], dropped: usize, tsc_hz: Option<u64>) -> fmt::Result {
+    let first = match marks.first() {
+        Some(first) => first,
+        None => return writeln!(out, "boottime: no marks"),
+    };
+    writeln!(out, "{:<20} {:>13} {:>13} {:>6}", "phase", "delta", "total", "ticks")?;
+    let mut previous = first;
+    for mark in marks {
+        let delta = Duration { cycles: mark.tsc.saturating_sub(previous.tsc), tsc_hz };
+        let total = Duration { cycles: mark.tsc.saturating_sub(first.tsc), tsc_hz };
+        writeln!(out, "{:<20} {} {} {:>6}", mark.name, delta, total, mark.ticks - first.ticks)?;
+        previous = mark;
+    }
+    if dropped > 0 {
+        writeln!(out, "({} marks dropped)", dropped)?;
+    }
+    Ok(())
+}
+
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
+
+pub fn report() {
+    let _ = report_to(&mut Console);
+}
+
+/// `report`, written to `out` instead of the console.
+pub fn report_to(out: &mut impl Write) -> fmt::Result {
+    let (marks, len, dropped) = interrupts::without_interrupts(|| {
+        let table = MARKS.lock();
+        (table.marks, table.len, table.dropped)
+    });
+    let mut list = [Mark { name: "", tsc: 0, ticks: 0 }; MAX_MARKS];
+    for (slot, mark) in list.iter_mut().zip(marks.iter().flatten()) {
+        *slot = *mark;
+    }
+    write_report(out, &list[..len], dropped, time::tsc_frequency())
+}
+
+//test case
+#[cfg(test)]
+fn synthetic_marks() -> [Mark; 3] {
+    [
+        Mark { name: "entry", tsc: 1_000, ticks: 0 },
+        Mark { name: "gdt", tsc: 3_000, ticks: 0 },
+        Mark { name: "heap", tsc: 11_000, ticks: 2 },
+    ]
+}
+
+#[test_case]
+fn test_report_converts_cycles() {
+    use alloc::string::String;
+    let mut out = String::new();
+    // 2 MHz: 1 cycle = 0.5 us
+    write_report(&mut out, &synthetic_marks(), 0, Some(2_000_000)).unwrap();
+    let lines: alloc::vec::Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[1], "entry                         0 us          0 us      0");
+    assert_eq!(lines[2], "gdt                        1000 us       1000 us      0");
+    assert_eq!(lines[3], "heap                       4000 us       5000 us      2");
+}
+
+#[test_case]
+fn test_report_raw_cycles_and_drops() {
+    use alloc::string::String;
+    let mut out = String::new();
+    write_report(&mut out, &synthetic_marks(), 3, None).unwrap();
+    assert!(out.contains("heap                       8000 cyc      10000 cyc      2"));
+    assert!(out.ends_with("(3 marks dropped)\n"));
+    let mut empty = String::new();
+    write_report(&mut empty, &[], 0, None).unwrap();
+    assert_eq!(empty, "boottime: no marks\n");
+}
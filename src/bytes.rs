@@ -0,0 +1,77 @@
+//! Little-endian field readers for parsing firmware tables and on-disk
+//! structures out of byte slices. Every reader returns `None` instead of
+//! panicking when the field would run past the end of the slice.
+
+pub fn le_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let field = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([field[0], field[1]]))
+}
+
+pub fn le_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let field = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+pub fn le_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let field = bytes.get(offset..offset.checked_add(8)?)?;
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(field);
+    Some(u64::from_le_bytes(raw))
+}
+
+/// Big-endian (network order) readers, for packet headers.
+pub fn be_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let field = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_be_bytes([field[0], field[1]]))
+}
+
+pub fn be_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let field = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+/// Wrapping byte sum, the checksum scheme used by ACPI, SMBIOS and friends:
+/// a table is valid when all of its bytes add up to zero.
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// CRC-32 as used by GPT, zip and Ethernet (reflected polynomial
+/// 0xEDB88320). `crc32_update` continues a running value, starting from
+/// `CRC32_INIT`, so large data can be checked piece by piece; finish with
+/// `!crc`.
+pub const CRC32_INIT: u32 = 0xffff_ffff;
+
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(CRC32_INIT, data)
+}
+
+#[test_case]
+fn test_le_readers() {
+    let bytes = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+    assert_eq!(le_u16(&bytes, 0), Some(0x0201));
+    assert_eq!(le_u32(&bytes, 4), Some(0x0807_0605));
+    assert_eq!(le_u64(&bytes, 0), Some(0x0807_0605_0403_0201));
+    assert_eq!(le_u32(&bytes, 6), None);
+    assert_eq!(le_u16(&bytes, usize::MAX), None);
+    assert_eq!(be_u16(&bytes, 0), Some(0x0102));
+    assert_eq!(be_u32(&bytes, 4), Some(0x0506_0708));
+}
+
+#[test_case]
+fn test_crc32() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b""), 0);
+    let split = crc32_update(crc32_update(CRC32_INIT, b"1234"), b"56789");
+    assert_eq!(!split, 0xcbf4_3926);
+}
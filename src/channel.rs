@@ -0,0 +1,185 @@
+//! Bounded channels: values go in at the senders and come out of the one
+//! receiver in order.
+//!
+//! `send` waits while the channel holds `capacity` values and fails once
+//! the receiver is gone; `recv` waits while it is empty and returns `None`
+//! once every sender is gone too. Both wait on a `sync::WaitQueue`, so a
+//! thread waiting here is off the CPU until the other side moves, and
+//! `recv_async` does the same for an async task.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::fmt;
+use crate::sync::{IrqMutex, WaitQueue, Waiter};
+
+struct State<T> {
+    values: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver_open: bool,
+}
+
+struct Shared<T> {
+    state: IrqMutex<State<T>>,
+    /// The receiver, waiting for a value or the last sender going.
+    readable: WaitQueue,
+    /// Senders waiting for room or the receiver going.
+    writable: WaitQueue,
+}
+
+pub struct Sender<T>(Arc<Shared<T>>);
+
+pub struct Receiver<T>(Arc<Shared<T>>);
+
+/// The value a `send` couldn't deliver, the receiver being gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the receiver is gone")
+    }
+}
+
+/// A channel holding up to `capacity` values, at least one.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let capacity = capacity.max(1);
+    let state = State { values: VecDeque::with_capacity(capacity), capacity, senders: 1, receiver_open: true };
+    let shared = Arc::new(Shared { state: IrqMutex::new(state), readable: WaitQueue::new(), writable: WaitQueue::new() });
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+impl<T> Sender<T> {
+    /// Queues `value`, waiting for room.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        let _ = self.0.writable.wait_until(Waiter::Kernel, || {
+            let mut state = self.0.state.lock();
+            if !state.receiver_open {
+                return true;
+            }
+            if state.values.len() < state.capacity {
+                state.values.extend(value.take());
+                return true;
+            }
+            false
+        });
+        match value {
+            Some(value) => Err(SendError(value)),
+            None => {
+                self.0.readable.notify_one();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// The oldest value, waiting for one; `None` once the channel is empty
+    /// and has no senders.
+    pub fn recv(&self) -> Option<T> {
+        let mut value = None;
+        let _ = self.0.readable.wait_until(Waiter::Kernel, || self.poll(&mut value));
+        self.received(value)
+    }
+
+    /// `recv` for an async task.
+    pub async fn recv_async(&self) -> Option<T> {
+        let mut value = None;
+        self.0.readable.wait(|| self.poll(&mut value)).await;
+        self.received(value)
+    }
+
+    /// The oldest value if there is one; never waits.
+    pub fn try_recv(&self) -> Option<T> {
+        let value = self.0.state.lock().values.pop_front();
+        self.received(value)
+    }
+
+    /// Whether a receive can stop waiting, with what it got in `value`.
+    fn poll(&self, value: &mut Option<T>) -> bool {
+        let mut state = self.0.state.lock();
+        *value = state.values.pop_front();
+        value.is_some() || state.senders == 0
+    }
+
+    fn received(&self, value: Option<T>) -> Option<T> {
+        if value.is_some() {
+            self.0.writable.notify_one();
+        }
+        value
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.state.lock().values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.state.lock().senders += 1;
+        Sender(self.0.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.0.state.lock().senders -= 1;
+        self.0.readable.notify_all();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.0.state.lock().receiver_open = false;
+        self.0.writable.notify_all();
+    }
+}
+
+//test case
+#[test_case]
+fn test_channel_in_order() {
+    let (sender, receiver) = channel(2);
+    assert_eq!(sender.send(1), Ok(()));
+    assert_eq!(sender.send(2), Ok(()));
+    assert_eq!(receiver.len(), 2);
+    assert_eq!(receiver.recv(), Some(1));
+    let second = sender.clone();
+    assert_eq!(second.send(3), Ok(()));
+    assert_eq!(receiver.recv(), Some(2));
+    drop((sender, second));
+    // what was sent still comes out after the senders are gone
+    assert_eq!(receiver.recv(), Some(3));
+    assert_eq!(receiver.recv(), None);
+    assert_eq!(receiver.try_recv(), None);
+}
+
+#[test_case]
+fn test_send_without_a_receiver() {
+    let (sender, receiver) = channel(4);
+    drop(receiver);
+    assert_eq!(sender.send("lost"), Err(SendError("lost")));
+}
+
+#[test_case]
+fn test_recv_waits_for_an_interrupt() {
+    use spin::Mutex;
+
+    static SENDER: Mutex<Option<Sender<u32>>> = Mutex::new(None);
+    fn produce() {
+        if let Some(sender) = SENDER.try_lock().and_then(|mut sender| sender.take()) {
+            assert_eq!(sender.send(99), Ok(()));
+        }
+    }
+    let (sender, receiver) = channel(1);
+    *SENDER.lock() = Some(sender);
+    crate::interrupts::set_tick_hook(produce);
+    let value = receiver.recv();
+    crate::interrupts::set_tick_hook(|| {});
+    assert_eq!(value, Some(99));
+    assert_eq!(receiver.recv(), None);
+}
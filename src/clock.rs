@@ -0,0 +1,406 @@
+//! Wall-clock time. The RTC is read once at boot and the tick counter
+//! carries it forward from there, so `now` is cheap and never touches the
+//! CMOS.
+//!
+//! Every `RESYNC_MINUTES` the clock is compared with the RTC at the instant
+//! a second begins, which the RTC's update interrupt (IRQ 8) marks; while
+//! that interrupt does not arrive the timer interrupt polls the seconds
+//! register for the change instead. Offsets up to
+//! `MAX_SLEW_MS` are slewed away over the next half interval, so time never
+//! jumps; larger ones (the RTC was set, or ticks were lost) are stepped.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::cmos::{self, DateTime};
+use crate::{interrupts, time};
+
+const RESYNC_MINUTES: u64 = 5;
+const RESYNC_TICKS: u64 = time::secs_to_ticks(RESYNC_MINUTES * 60);
+/// A slew is spread over this many ticks, done well before the next resync.
+const SLEW_TICKS: u64 = RESYNC_TICKS / 2;
+const MAX_SLEW_MS: i64 = 2000;
+/// How long a resync waits for the seconds to change before it takes the
+/// RTC to be stopped and leaves the clock alone.
+const EDGE_WAIT_TICKS: u64 = time::secs_to_ticks(2);
+
+const UNIX_EPOCH_YEAR: u16 = 1970;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+// ==========================================================
+// Calendar
+// ==========================================================
+
+pub fn is_leap_year(year: u16) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn days_in_year(year: u16) -> u64 {
+    if is_leap_year(year) { 366 } else { 365 }
+}
+
+/// Seconds from 1970-01-01 00:00:00 to `time`, which must be a valid date
+/// no earlier than that.
+pub fn to_unix(time: DateTime) -> u64 {
+    let days = (UNIX_EPOCH_YEAR..time.year).map(days_in_year).sum::<u64>()
+        + (1..time.month).map(|month| u64::from(days_in_month(time.year, month))).sum::<u64>()
+        + u64::from(time.day - 1);
+    days * SECS_PER_DAY + (u64::from(time.hour) * 60 + u64::from(time.minute)) * 60 + u64::from(time.second)
+}
+
+pub fn from_unix(secs: u64) -> DateTime {
+    let mut days = secs / SECS_PER_DAY;
+    let mut year = UNIX_EPOCH_YEAR;
+    while days >= days_in_year(year) {
+        days -= days_in_year(year);
+        year += 1;
+    }
+    let mut month = 1;
+    while days >= u64::from(days_in_month(year, month)) {
+        days -= u64::from(days_in_month(year, month));
+        month += 1;
+    }
+    let secs = secs % SECS_PER_DAY;
+    DateTime {
+        year,
+        month,
+        day: days as u8 + 1,
+        hour: (secs / 3600) as u8,
+        minute: (secs / 60 % 60) as u8,
+        second: (secs % 60) as u8,
+    }
+}
+
+/// Parses `YYYY-MM-DD HH:MM:SS` with a year the RTC can hold (2000-2099).
+pub fn parse(s: &str) -> Option<DateTime> {
+    let (date, time) = s.trim().split_once([' ', 'T'])?;
+    let mut date = date.split('-').map(str::parse::<u16>);
+    let mut time = time.trim().split(':').map(str::parse::<u8>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if date.next().is_some() || time.next().is_some() {
+        return None;
+    }
+    let valid = (2000..=2099).contains(&year)
+        && (1..=12).contains(&month)
+        && day >= 1
+        && day <= u16::from(days_in_month(year, month as u8))
+        && hour < 24
+        && minute < 60
+        && second < 60;
+    valid.then_some(DateTime { year, month: month as u8, day: day as u8, hour, minute, second })
+}
+
+// ==========================================================
+// Clock
+// ==========================================================
+
+/// What a resync did about the clock's offset from the RTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Correction {
+    /// It was this many ms behind (positive) or ahead and is being walked
+    /// towards the RTC.
+    Slew(i64),
+    /// It was this far off and was set to the RTC's time.
+    Step(i64),
+}
+
+impl Correction {
+    pub fn for_offset(offset_ms: i64) -> Correction {
+        if offset_ms.abs() <= MAX_SLEW_MS {
+            Correction::Slew(offset_ms)
+        } else {
+            Correction::Step(offset_ms)
+        }
+    }
+}
+
+impl fmt::Display for Correction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Correction::Slew(offset) => write!(f, "slewed {:+} ms", offset),
+            Correction::Step(offset) => write!(f, "stepped {:+} ms", offset),
+        }
+    }
+}
+
+/// Milliseconds since the epoch as of one tick, carried forward by the
+/// ticks after it, plus a slew being worked off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clock {
+    base_ms: u64,
+    base_tick: u64,
+    slew_ms: i64,
+}
+
+impl Clock {
+    pub const fn new(epoch_ms: u64, tick: u64) -> Clock {
+        Clock { base_ms: epoch_ms, base_tick: tick, slew_ms: 0 }
+    }
+
+    pub fn now_ms(&self, tick: u64) -> u64 {
+        let elapsed = tick.saturating_sub(self.base_tick);
+        let slewed = self.slew_ms * elapsed.min(SLEW_TICKS) as i64 / SLEW_TICKS as i64;
+        (self.base_ms + time::ticks_to_millis(elapsed)).saturating_add_signed(slewed)
+    }
+
+    /// Brings the clock in line with `rtc_ms`, the RTC's time at `tick`.
+    pub fn sync(&mut self, tick: u64, rtc_ms: u64) -> Correction {
+        let now = self.now_ms(tick);
+        let correction = Correction::for_offset(rtc_ms as i64 - now as i64);
+        *self = match correction {
+            Correction::Slew(offset) => Clock { base_ms: now, base_tick: tick, slew_ms: offset },
+            Correction::Step(_) => Clock::new(rtc_ms, tick),
+        };
+        correction
+    }
+}
+
+struct State {
+    clock: Clock,
+    next_sync: u64,
+    /// The RTC second a resync is waiting to see end, and since when.
+    edge: Option<(u8, u64)>,
+    last: Option<Correction>,
+}
+
+/// Taken with interrupts disabled only, since the timer interrupt takes it.
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+/// Update interrupts from the RTC since boot, and the tick of the last.
+static UPDATES: AtomicU64 = AtomicU64::new(0);
+static LAST_UPDATE_TICK: AtomicU64 = AtomicU64::new(0);
+
+fn set_from_rtc() {
+    let rtc_ms = to_unix(cmos::read_rtc()) * 1000;
+    without_interrupts(|| {
+        let tick = time::ticks();
+        *STATE.lock() = Some(State {
+            clock: Clock::new(rtc_ms, tick),
+            next_sync: tick + RESYNC_TICKS,
+            edge: None,
+            last: None,
+        });
+    });
+}
+
+/// Starts the clock from the RTC and takes its update interrupt. The
+/// reading is only good to the second; the first resync slews the rest
+/// away.
+pub fn init() {
+    set_from_rtc();
+    interrupts::register_irq(8, rtc_interrupt);
+    cmos::set_update_interrupt(true);
+}
+
+/// Sets the RTC to `time` and restarts the clock from it.
+pub fn set(time: DateTime) {
+    cmos::write_rtc(time);
+    set_from_rtc();
+}
+
+/// Milliseconds since 1970-01-01 00:00:00, once `init` has run.
+pub fn now() -> Option<u64> {
+    without_interrupts(|| STATE.lock().as_ref().map(|state| state.clock.now_ms(time::ticks())))
+}
+
+pub fn now_datetime() -> Option<DateTime> {
+    now().map(|ms| from_unix(ms / 1000))
+}
+
+/// What the latest resync found, if one has run since the clock was set.
+pub fn last_correction() -> Option<Correction> {
+    without_interrupts(|| STATE.lock().as_ref().and_then(|state| state.last))
+}
+
+/// Update interrupts the RTC has raised since boot, one a second once
+/// `init` has run.
+pub fn rtc_updates() -> u64 {
+    UPDATES.load(Ordering::Relaxed)
+}
+
+/// IRQ 8: a second has just begun, so a due resync reads the RTC now.
+fn rtc_interrupt() {
+    if !cmos::take_update_interrupt() {
+        return;
+    }
+    let tick = time::ticks();
+    UPDATES.fetch_add(1, Ordering::Relaxed);
+    LAST_UPDATE_TICK.store(tick, Ordering::Relaxed);
+    let mut state = STATE.lock();
+    let Some(state) = state.as_mut() else {
+        return;
+    };
+    if tick >= state.next_sync {
+        let rtc_ms = to_unix(cmos::read_rtc()) * 1000;
+        state.last = Some(state.clock.sync(tick, rtc_ms));
+        state.edge = None;
+        state.next_sync = tick + RESYNC_TICKS;
+    }
+}
+
+/// Called from the timer interrupt: while the RTC's update interrupt is
+/// not arriving, runs a due resync, one seconds register read per tick
+/// until the RTC's second changes.
+pub(crate) fn on_tick() {
+    let tick = time::ticks();
+    if rtc_updates() > 0 && tick.saturating_sub(LAST_UPDATE_TICK.load(Ordering::Relaxed)) <= EDGE_WAIT_TICKS {
+        return;
+    }
+    let mut state = STATE.lock();
+    let Some(state) = state.as_mut() else {
+        return;
+    };
+    if tick < state.next_sync {
+        return;
+    }
+    let Some(second) = cmos::rtc_second() else {
+        return;
+    };
+    match state.edge {
+        None => state.edge = Some((second, tick)),
+        Some((last, _)) if second != last => {
+            // a second has just begun, so the RTC's time is exact here
+            let rtc_ms = to_unix(cmos::read_rtc()) * 1000;
+            state.last = Some(state.clock.sync(tick, rtc_ms));
+            state.edge = None;
+            state.next_sync = tick + RESYNC_TICKS;
+        }
+        Some((_, since)) if tick - since > EDGE_WAIT_TICKS => {
+            state.edge = None;
+            state.next_sync = tick + RESYNC_TICKS;
+        }
+        Some(_) => {}
+    }
+}
+
+//test case
+#[cfg(test)]
+fn datetime(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+    DateTime { year, month, day, hour, minute, second }
+}
+
+#[test_case]
+fn test_leap_years() {
+    for (year, leap) in [(1900, false), (1970, false), (1972, true), (2000, true), (2023, false), (2024, true), (2100, false)] {
+        assert_eq!(is_leap_year(year), leap, "{}", year);
+    }
+    assert_eq!(days_in_month(2000, 2), 29);
+    assert_eq!(days_in_month(2100, 2), 28);
+    assert_eq!(days_in_month(2023, 4), 30);
+    assert_eq!(days_in_month(2023, 12), 31);
+}
+
+#[test_case]
+fn test_unix_conversion() {
+    let known = [
+        (datetime(1970, 1, 1, 0, 0, 0), 0),
+        (datetime(1972, 12, 31, 23, 59, 59), 94_694_399),
+        (datetime(2000, 2, 29, 0, 0, 0), 951_782_400),
+        (datetime(2000, 3, 1, 0, 0, 0), 951_868_800),
+        (datetime(2024, 2, 29, 12, 0, 0), 1_709_208_000),
+        (datetime(2099, 12, 31, 23, 59, 59), 4_102_444_799),
+        (datetime(2100, 3, 1, 0, 0, 0), 4_107_542_400),
+    ];
+    for (time, secs) in known {
+        assert_eq!(to_unix(time), secs, "{}", time);
+        assert_eq!(from_unix(secs), time);
+    }
+    // every day around the leap-year edges comes back as itself, one day on
+    for start in [datetime(1999, 12, 1, 0, 0, 0), datetime(2023, 12, 1, 0, 0, 0), datetime(2099, 12, 1, 0, 0, 0)] {
+        let start = to_unix(start);
+        let mut previous = from_unix(start);
+        for day in 1..500 {
+            let secs = start + day * SECS_PER_DAY + 3_599;
+            let time = from_unix(secs);
+            assert_eq!(to_unix(time), secs);
+            assert!(time.day >= 1 && time.day <= days_in_month(time.year, time.month));
+            assert!(time.day == previous.day + 1 || time.day == 1);
+            previous = time;
+        }
+    }
+}
+
+#[test_case]
+fn test_parse() {
+    assert_eq!(parse("2024-02-29 13:37:42"), Some(datetime(2024, 2, 29, 13, 37, 42)));
+    assert_eq!(parse(" 2030-01-05T00:00:00 "), Some(datetime(2030, 1, 5, 0, 0, 0)));
+    for bad in ["2023-02-29 00:00:00", "2024-13-01 00:00:00", "2024-04-31 00:00:00", "1999-12-31 23:59:59",
+        "2024-01-01 24:00:00", "2024-01-01 12:60:00", "2024-01-01", "2024-01-01 12:00", "2024-01-01 12:00:00:00", "now"]
+    {
+        assert_eq!(parse(bad), None, "{}", bad);
+    }
+}
+
+#[test_case]
+fn test_slew_or_step() {
+    assert_eq!(Correction::for_offset(0), Correction::Slew(0));
+    assert_eq!(Correction::for_offset(-MAX_SLEW_MS), Correction::Slew(-MAX_SLEW_MS));
+    assert_eq!(Correction::for_offset(MAX_SLEW_MS + 1), Correction::Step(MAX_SLEW_MS + 1));
+    assert_eq!(Correction::for_offset(-3_600_000), Correction::Step(-3_600_000));
+
+    let start = 1_709_208_000_000;
+    let mut clock = Clock::new(start, 100);
+    assert_eq!(clock.now_ms(100 + 182), start + time::ticks_to_millis(182));
+
+    // 1.5 s behind: slewed, never going backwards, caught up after SLEW_TICKS
+    let tick = 100 + RESYNC_TICKS;
+    let rtc = clock.now_ms(tick) + 1_500;
+    assert_eq!(clock.sync(tick, rtc), Correction::Slew(1_500));
+    assert_eq!(clock.now_ms(tick), rtc - 1_500);
+    let mut last = 0;
+    for elapsed in 0..=SLEW_TICKS + 10 {
+        let now = clock.now_ms(tick + elapsed);
+        assert!(now >= last);
+        last = now;
+    }
+    assert_eq!(clock.now_ms(tick + SLEW_TICKS), rtc + time::ticks_to_millis(SLEW_TICKS));
+
+    // running ahead slews back without going backwards either
+    let rtc = clock.now_ms(tick + RESYNC_TICKS) - MAX_SLEW_MS as u64;
+    assert_eq!(clock.sync(tick + RESYNC_TICKS, rtc), Correction::Slew(-MAX_SLEW_MS));
+    let mut last = 0;
+    for elapsed in 0..=SLEW_TICKS {
+        let now = clock.now_ms(tick + RESYNC_TICKS + elapsed);
+        assert!(now >= last);
+        last = now;
+    }
+
+    // an hour off is stepped
+    let tick = tick + 2 * RESYNC_TICKS;
+    let rtc = clock.now_ms(tick) + 3_600_000;
+    assert_eq!(clock.sync(tick, rtc), Correction::Step(3_600_000));
+    assert_eq!(clock.now_ms(tick), rtc);
+    assert_eq!(clock.now_ms(tick + 182), rtc + time::ticks_to_millis(182));
+}
+
+#[test_case]
+fn test_clock_is_running() {
+    let today = now_datetime().expect("clock not started");
+    assert!((2000..=2099).contains(&today.year));
+    let rtc = to_unix(cmos::read_rtc()) * 1000;
+    assert!(now().unwrap().abs_diff(rtc) <= 2 * MAX_SLEW_MS as u64);
+}
+
+#[test_case]
+fn test_rtc_update_interrupts() {
+    let before = rtc_updates();
+    let deadline = time::ticks() + time::secs_to_ticks(3);
+    while rtc_updates() == before {
+        assert!(time::ticks() < deadline, "no RTC update interrupt in 3 s");
+        x86_64::instructions::hlt();
+    }
+    // and about one a second
+    let first = rtc_updates();
+    time::sleep_ms(2500);
+    assert!((2..=3).contains(&(rtc_updates() - first)), "{} updates in 2.5 s", rtc_updates() - first);
+}
@@ -0,0 +1,414 @@
+//! Kernel command line.
+//!
+//! When GRUB boots the kernel (see `multiboot2`) the command line is what
+//! follows the kernel on its `multiboot2` line, which comes in the boot
+//! information. bootloader 0.9 cannot pass one, so otherwise it is read
+//! from QEMU's fw_cfg file `opt/berryos/cmdline`:
+//!
+//! ```text
+//! qemu-system-x86_64 ... -fw_cfg name=opt/berryos/cmdline,string='loglevel=debug console=both'
+//! ```
+//!
+//! Some of what it sets: `loglevel=`, `console=` (see `vga_buffer`),
+//! `quantum=` (ticks per time slice, see `scheduler`) and `init=<path>`,
+//! a program to run before the shell takes over.
+//!
+//! Entries are separated by whitespace and are either `key=value` or a bare
+//! `flag`. Double quotes group whitespace, around a whole entry or just its
+//! value: `"title=a b"`, `title="a b"`. When a key repeats, the last value
+//! wins and a warning is logged.
+//!
+//! The table is built by `init` (from `crate::init`) before anything reads
+//! it; before that every lookup sees an empty command line.
+
+use core::fmt;
+use core::str::FromStr;
+use spin::Once;
+use crate::{fw_cfg, warn};
+
+pub const FW_CFG_FILE: &str = "opt/berryos/cmdline";
+pub const MAX_LEN: usize = 1024;
+pub const MAX_ENTRIES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry<'a> {
+    pub key: &'a str,
+    /// `None` for a bare flag.
+    pub value: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// A quote opened at this byte offset is never closed. Nothing after it
+    /// is parsed.
+    UnterminatedQuote(usize),
+    /// An entry at this offset starts with `=`; it is skipped.
+    EmptyKey(usize),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnterminatedQuote(at) => write!(f, "unterminated quote at offset {}", at),
+            ParseError::EmptyKey(at) => write!(f, "entry without a key at offset {}", at),
+        }
+    }
+}
+
+/// Iterator over the entries of a command line.
+pub struct Tokens<'a> {
+    input: &'a str,
+    pos: usize,
+    done: bool,
+}
+
+pub fn tokenize(input: &str) -> Tokens<'_> {
+    Tokens { input, pos: 0, done: false }
+}
+
+impl<'a> Tokens<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).copied()
+    }
+
+    /// Takes a quoted run (the cursor is on the opening quote) or a plain
+    /// run up to whitespace or any byte in `stop`.
+    fn word(&mut self, stop: &[u8]) -> Result<&'a str, ParseError> {
+        let bytes = self.input.as_bytes();
+        if self.peek() == Some(b'"') {
+            let open = self.pos;
+            let close = bytes[open + 1..]
+                .iter()
+                .position(|&b| b == b'"')
+                .ok_or(ParseError::UnterminatedQuote(open))?;
+            self.pos = open + 1 + close + 1;
+            return Ok(&self.input[open + 1..open + 1 + close]);
+        }
+        let start = self.pos;
+        while let Some(b) = self.peek() {
+            if b.is_ascii_whitespace() || stop.contains(&b) {
+                break;
+            }
+            self.pos += 1;
+        }
+        Ok(&self.input[start..self.pos])
+    }
+
+    fn entry(&mut self) -> Result<Entry<'a>, ParseError> {
+        let start = self.pos;
+        let whole_quoted = self.peek() == Some(b'"');
+        let key = self.word(b"=")?;
+        if whole_quoted {
+            // "key=value with spaces"
+            return match key.split_once('=') {
+                Some(("", _)) => Err(ParseError::EmptyKey(start)),
+                Some((key, value)) => Ok(Entry { key, value: Some(value) }),
+                None if key.is_empty() => Err(ParseError::EmptyKey(start)),
+                None => Ok(Entry { key, value: None }),
+            };
+        }
+        let value = if self.peek() == Some(b'=') {
+            self.pos += 1;
+            Some(self.word(b"")?)
+        } else {
+            None
+        };
+        if key.is_empty() {
+            return Err(ParseError::EmptyKey(start));
+        }
+        Ok(Entry { key, value })
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Result<Entry<'a>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+        self.peek()?;
+        let entry = self.entry();
+        if let Err(ParseError::UnterminatedQuote(_)) = entry {
+            self.done = true;
+        }
+        Some(entry)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning<'a> {
+    Syntax(ParseError),
+    /// The key was given again; the new value replaced the old one.
+    Duplicate(&'a str),
+    /// More than `MAX_ENTRIES` distinct keys; this one was dropped.
+    TooMany(&'a str),
+}
+
+impl fmt::Display for Warning<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::Syntax(err) => write!(f, "{}", err),
+            Warning::Duplicate(key) => write!(f, "'{}' given more than once, using the last value", key),
+            Warning::TooMany(key) => write!(f, "too many entries, ignoring '{}'", key),
+        }
+    }
+}
+
+/// Parsed command line. Keys are unique.
+#[derive(Debug)]
+pub struct Table<'a> {
+    entries: [Option<Entry<'a>>; MAX_ENTRIES],
+}
+
+impl<'a> Table<'a> {
+    pub const fn empty() -> Table<'a> {
+        Table { entries: [None; MAX_ENTRIES] }
+    }
+
+    pub fn parse(input: &'a str, mut on_warning: impl FnMut(Warning<'a>)) -> Table<'a> {
+        let mut table = Table::empty();
+        for entry in tokenize(input) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    on_warning(Warning::Syntax(err));
+                    continue;
+                }
+            };
+            let slot = table.entries.iter().position(|e| matches!(e, Some(e) if e.key == entry.key));
+            if let Some(slot) = slot {
+                on_warning(Warning::Duplicate(entry.key));
+                table.entries[slot] = Some(entry);
+            } else if let Some(free) = table.entries.iter().position(Option::is_none) {
+                table.entries[free] = Some(entry);
+            } else {
+                on_warning(Warning::TooMany(entry.key));
+            }
+        }
+        table
+    }
+
+    pub fn entry(&self, key: &str) -> Option<Entry<'a>> {
+        self.entries.iter().flatten().find(|e| e.key == key).copied()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.entry(key)?.value
+    }
+
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        parse_u64(self.get(key)?)
+    }
+
+    /// The value through `FromStr`; `None` if absent or if it doesn't
+    /// parse.
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key)?.parse().ok()
+    }
+
+    /// A comma-separated value's items, with empty ones skipped; nothing
+    /// when absent.
+    pub fn get_list(&self, key: &str) -> impl Iterator<Item = &'a str> {
+        self.get(key).unwrap_or("").split(',').filter(|item| !item.is_empty())
+    }
+
+    /// True for a bare `key`, or a value other than `0`, `no`, `off` or
+    /// `false`. False when absent.
+    pub fn flag(&self, key: &str) -> bool {
+        match self.entry(key) {
+            None => false,
+            Some(Entry { value: None, .. }) => true,
+            Some(Entry { value: Some(value), .. }) => !matches!(value, "0" | "no" | "off" | "false"),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Entry<'a>> + '_ {
+        self.entries.iter().flatten().copied()
+    }
+}
+
+/// Decimal or `0x` hex, with an optional `K`, `M` or `G` (binary) suffix.
+pub fn parse_u64(s: &str) -> Option<u64> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    value.checked_mul(1 << shift)
+}
+
+static RAW: Once<([u8; MAX_LEN], usize)> = Once::new();
+static TABLE: Once<Table<'static>> = Once::new();
+static EMPTY: Table<'static> = Table::empty();
+
+/// Reads and parses the command line. Later calls do nothing.
+pub fn init() {
+    if TABLE.is_completed() {
+        return;
+    }
+    let (buf, len) = RAW.call_once(|| {
+        let mut buf = [0; MAX_LEN];
+        let from_loader = crate::boot::boot_info().and_then(|info| info.cmdline).filter(|line| !line.is_empty());
+        let len = match from_loader {
+            Some(line) => {
+                if line.len() > MAX_LEN {
+                    warn!("cmdline: longer than {} bytes, cut short", MAX_LEN);
+                }
+                // cut at a character boundary, so what's left is still UTF-8
+                let len = (0..=line.len().min(MAX_LEN)).rev().find(|&len| line.is_char_boundary(len)).unwrap_or(0);
+                buf[..len].copy_from_slice(&line.as_bytes()[..len]);
+                len
+            }
+            None => fw_cfg::read_file(FW_CFG_FILE, &mut buf).unwrap_or(0),
+        };
+        (buf, len)
+    });
+    let text = match core::str::from_utf8(&buf[..*len]) {
+        // a trailing NUL or newline from `file=` is harmless whitespace
+        Ok(text) => text.trim_end_matches(['\0', '\n']),
+        Err(_) => {
+            warn!("cmdline: not valid UTF-8, ignoring it");
+            ""
+        }
+    };
+    let mut warnings = [None; 8];
+    let mut count = 0;
+    TABLE.call_once(|| {
+        Table::parse(text, |warning| {
+            if let Some(slot) = warnings.get_mut(count) {
+                *slot = Some(warning);
+            }
+            count += 1;
+        })
+    });
+    for warning in warnings.iter().flatten() {
+        warn!("cmdline: {}", warning);
+    }
+}
+
+/// The raw command line, as read.
+pub fn raw() -> &'static str {
+    RAW.get()
+        .and_then(|(buf, len)| core::str::from_utf8(&buf[..*len]).ok())
+        .unwrap_or("")
+}
+
+pub fn table() -> &'static Table<'static> {
+    TABLE.get().unwrap_or(&EMPTY)
+}
+
+pub fn get(key: &str) -> Option<&'static str> {
+    table().get(key)
+}
+
+pub fn get_u64(key: &str) -> Option<u64> {
+    table().get_u64(key)
+}
+
+pub fn get_parsed<T: FromStr>(key: &str) -> Option<T> {
+    table().get_parsed(key)
+}
+
+pub fn get_list(key: &str) -> impl Iterator<Item = &'static str> {
+    table().get_list(key)
+}
+
+pub fn flag(key: &str) -> bool {
+    table().flag(key)
+}
+
+//test case
+#[cfg(test)]
+fn entries(input: &str) -> alloc::vec::Vec<Result<Entry<'_>, ParseError>> {
+    tokenize(input).collect()
+}
+
+#[test_case]
+fn test_tokenize_basic() {
+    assert_eq!(entries(""), []);
+    assert_eq!(entries("   \t "), []);
+    assert_eq!(
+        entries("  loglevel=debug   quiet\ttest=vga  "),
+        [
+            Ok(Entry { key: "loglevel", value: Some("debug") }),
+            Ok(Entry { key: "quiet", value: None }),
+            Ok(Entry { key: "test", value: Some("vga") }),
+        ]
+    );
+    assert_eq!(entries("empty="), [Ok(Entry { key: "empty", value: Some("") })]);
+    assert_eq!(entries("a=b=c"), [Ok(Entry { key: "a", value: Some("b=c") })]);
+    assert_eq!(
+        entries("=x y"),
+        [Err(ParseError::EmptyKey(0)), Ok(Entry { key: "y", value: None })]
+    );
+}
+
+#[test_case]
+fn test_tokenize_quotes() {
+    assert_eq!(
+        entries("title=\"hello world\" \"motd=a  b\" \"flag\""),
+        [
+            Ok(Entry { key: "title", value: Some("hello world") }),
+            Ok(Entry { key: "motd", value: Some("a  b") }),
+            Ok(Entry { key: "flag", value: None }),
+        ]
+    );
+    assert_eq!(entries("x=\"\""), [Ok(Entry { key: "x", value: Some("") })]);
+    assert_eq!(entries("\"\""), [Err(ParseError::EmptyKey(0))]);
+}
+
+#[test_case]
+fn test_tokenize_unterminated_quote() {
+    assert_eq!(
+        entries("a=1 b=\"oops c=3"),
+        [Ok(Entry { key: "a", value: Some("1") }), Err(ParseError::UnterminatedQuote(6))]
+    );
+    assert_eq!(entries("\"never closed"), [Err(ParseError::UnterminatedQuote(0))]);
+}
+
+#[test_case]
+fn test_table_last_duplicate_wins() {
+    let mut warnings = alloc::vec::Vec::new();
+    let table = Table::parse("loglevel=info x loglevel=trace", |w| warnings.push(w));
+    assert_eq!(table.get("loglevel"), Some("trace"));
+    assert_eq!(warnings, [Warning::Duplicate("loglevel")]);
+    assert!(table.flag("x"));
+    assert!(!table.flag("y"));
+    assert_eq!(table.get("x"), None);
+    assert_eq!(table.iter().count(), 2);
+}
+
+#[test_case]
+fn test_table_values() {
+    let table = Table::parse("heap=4M base=0x1000 off=no on=1 n=12 bad=12Q", |_| {});
+    assert_eq!(table.get_u64("heap"), Some(4 << 20));
+    assert_eq!(table.get_u64("base"), Some(0x1000));
+    assert_eq!(table.get_u64("n"), Some(12));
+    assert_eq!(table.get_u64("bad"), None);
+    assert_eq!(table.get_u64("missing"), None);
+    assert!(!table.flag("off"));
+    assert!(table.flag("on"));
+    assert_eq!(parse_u64("0x"), None);
+    assert_eq!(parse_u64("99999999999G"), None);
+}
+
+#[test_case]
+fn test_typed_values() {
+    let table = Table::parse("init=/bin/sh ratio=-3 signed=x nics=e1000,,virtio empty=", |_| {});
+    assert_eq!(table.get_parsed::<i32>("ratio"), Some(-3));
+    assert_eq!(table.get_parsed::<i32>("signed"), None);
+    assert_eq!(table.get_parsed::<bool>("missing"), None);
+    assert_eq!(table.get("init"), Some("/bin/sh"));
+    assert!(table.get_list("nics").eq(["e1000", "virtio"]));
+    assert_eq!(table.get_list("empty").count(), 0);
+    assert_eq!(table.get_list("missing").count(), 0);
+}
@@ -0,0 +1,425 @@
+//! CMOS NVRAM: the battery-backed bytes behind ports 0x70/0x71.
+//!
+//! Registers 0x00-0x0d belong to the RTC and are refused by `read` and
+//! `write`; 0x0e-0x3f hold firmware configuration. A few free bytes from
+//! `SETTINGS_BASE` up keep the kernel's settings across reboots as a small
+//! record with a magic, a version and a checksum, so a fresh battery or a
+//! first boot reads as "no settings" rather than garbage.
+//!
+//! The RTC registers go through `read_rtc` and `write_rtc` only; `clock`
+//! keeps the wall-clock time from them.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use crate::keyboard::{self, RepeatDelay, RepeatRate};
+use crate::vga_buffer::{self, Console};
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+/// Bit 7 of the index byte masks NMIs.
+const NMI_DISABLE: u8 = 1 << 7;
+
+/// Registers below this are the RTC's.
+const FIRST_NVRAM: u8 = 0x0e;
+const LAST_NVRAM: u8 = 0x7f;
+
+const SETTINGS_BASE: u8 = 0x40;
+const SETTINGS_MAGIC: u8 = 0xb5;
+const SETTINGS_VERSION: u8 = 1;
+/// Magic, version, console, typematic byte, checksum.
+pub const RECORD_LEN: usize = 5;
+
+/// Whether we keep NMIs masked; every index write carries it along.
+static NMI_MASKED: AtomicBool = AtomicBool::new(false);
+
+/// Whether `reg` is open to `read` and `write`.
+pub fn is_accessible(reg: u8) -> bool {
+    (FIRST_NVRAM..=LAST_NVRAM).contains(&reg)
+}
+
+/// Reads an NVRAM byte. Panics for RTC registers.
+pub fn read(reg: u8) -> u8 {
+    assert!(is_accessible(reg), "CMOS register {:#x} is off-limits", reg);
+    read_raw(reg)
+}
+
+/// Writes an NVRAM byte. Panics for RTC registers.
+pub fn write(reg: u8, value: u8) {
+    assert!(is_accessible(reg), "CMOS register {:#x} is off-limits", reg);
+    write_raw(reg, value)
+}
+
+/// Masks or unmasks NMIs through the CMOS index port.
+pub fn set_nmi_masked(masked: bool) {
+    NMI_MASKED.store(masked, Ordering::Relaxed);
+    without_interrupts(|| unsafe { Port::new(INDEX_PORT).write(index_byte(0x0d)) });
+}
+
+fn index_byte(reg: u8) -> u8 {
+    if NMI_MASKED.load(Ordering::Relaxed) {
+        reg | NMI_DISABLE
+    } else {
+        reg & !NMI_DISABLE
+    }
+}
+
+// the index and data accesses must not be split by an interrupt handler
+// that uses the CMOS too
+
+fn read_raw(reg: u8) -> u8 {
+    without_interrupts(|| unsafe {
+        Port::new(INDEX_PORT).write(index_byte(reg));
+        Port::new(DATA_PORT).read()
+    })
+}
+
+fn write_raw(reg: u8, value: u8) {
+    without_interrupts(|| unsafe {
+        Port::new(INDEX_PORT).write(index_byte(reg));
+        Port::new(DATA_PORT).write(value);
+    })
+}
+
+// ==========================================================
+// RTC
+// ==========================================================
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_STATUS_A: u8 = 0x0a;
+const RTC_STATUS_B: u8 = 0x0b;
+const RTC_STATUS_C: u8 = 0x0c;
+const STATUS_A_UPDATING: u8 = 1 << 7;
+/// Stops updates while the time is written.
+const STATUS_B_SET: u8 = 1 << 7;
+/// Raises IRQ 8 when an update ends.
+const STATUS_B_UPDATE_IRQ: u8 = 1 << 4;
+const STATUS_C_UPDATE_ENDED: u8 = 1 << 4;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+/// Wall-clock time as the RTC keeps it, without a time zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Decodes the seconds, minutes, hours, day, month and year registers
+/// (0x00, 0x02, 0x04, 0x07, 0x08, 0x09) under status register B's format
+/// bits. The year is taken to be in 2000-2099.
+pub fn decode_rtc(raw: [u8; 6], status_b: u8) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let value = |byte: u8| if binary { byte } else { from_bcd(byte) };
+    let pm = raw[2] & HOUR_PM != 0;
+    let mut hour = value(raw[2] & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12-hour clock: 12 AM is midnight, 12 PM noon
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+    DateTime {
+        year: 2000 + u16::from(value(raw[5])),
+        month: value(raw[4]),
+        day: value(raw[3]),
+        hour,
+        minute: value(raw[1]),
+        second: value(raw[0]),
+    }
+}
+
+/// The registers `decode_rtc` reads back as `time`, whose year must be in
+/// 2000-2099.
+pub fn encode_rtc(time: DateTime, status_b: u8) -> [u8; 6] {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let value = |byte: u8| if binary { byte } else { to_bcd(byte) };
+    let hour = if status_b & STATUS_B_24_HOUR != 0 {
+        value(time.hour)
+    } else {
+        let pm = if time.hour >= 12 { HOUR_PM } else { 0 };
+        value((time.hour + 11) % 12 + 1) | pm
+    };
+    [
+        value(time.second),
+        value(time.minute),
+        hour,
+        value(time.day),
+        value(time.month),
+        value((time.year - 2000) as u8),
+    ]
+}
+
+/// Reads the current date and time. Waits out an update in progress and
+/// reads until two readings agree, so it never sees a half-updated clock.
+pub fn read_rtc() -> DateTime {
+    let read_all = || {
+        while read_raw(RTC_STATUS_A) & STATUS_A_UPDATING != 0 {
+            core::hint::spin_loop();
+        }
+        [RTC_SECONDS, 0x02, 0x04, 0x07, 0x08, 0x09].map(read_raw)
+    };
+    let mut last = read_all();
+    loop {
+        let current = read_all();
+        if current == last {
+            return decode_rtc(current, read_raw(RTC_STATUS_B));
+        }
+        last = current;
+    }
+}
+
+/// The seconds register as it stands, `None` while an update is in
+/// progress. Cheap enough to poll for the moment a second starts.
+pub fn rtc_second() -> Option<u8> {
+    if read_raw(RTC_STATUS_A) & STATUS_A_UPDATING != 0 {
+        return None;
+    }
+    Some(read_raw(RTC_SECONDS))
+}
+
+/// Sets the date and time, in the RTC's own format. Updates are held off
+/// while the registers are written, so the clock restarts at `time`.
+pub fn write_rtc(time: DateTime) {
+    let status_b = read_raw(RTC_STATUS_B);
+    write_raw(RTC_STATUS_B, status_b | STATUS_B_SET);
+    for (reg, value) in [RTC_SECONDS, 0x02, 0x04, 0x07, 0x08, 0x09].into_iter().zip(encode_rtc(time, status_b)) {
+        write_raw(reg, value);
+    }
+    write_raw(RTC_STATUS_B, status_b & !STATUS_B_SET);
+}
+
+/// Has the RTC raise IRQ 8 each time an update ends, the moment a second
+/// begins, or stops it.
+pub fn set_update_interrupt(enabled: bool) {
+    without_interrupts(|| {
+        let status_b = read_raw(RTC_STATUS_B);
+        let status_b = if enabled { status_b | STATUS_B_UPDATE_IRQ } else { status_b & !STATUS_B_UPDATE_IRQ };
+        write_raw(RTC_STATUS_B, status_b);
+        // a flag already set holds the line until C is read
+        read_raw(RTC_STATUS_C);
+    });
+}
+
+/// Reads status register C, which lets the RTC raise its next interrupt.
+/// `true` if this one was an update ending.
+pub fn take_update_interrupt() -> bool {
+    read_raw(RTC_STATUS_C) & STATUS_C_UPDATE_ENDED != 0
+}
+
+/// What the kernel remembers across reboots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub console: Console,
+    pub repeat_rate: RepeatRate,
+    pub repeat_delay: RepeatDelay,
+}
+
+impl Default for Settings {
+    /// The console default and the keyboard's power-on typematic rate.
+    fn default() -> Settings {
+        Settings {
+            console: Console::Both,
+            repeat_rate: RepeatRate::from_code(0x0b).expect("valid rate code"),
+            repeat_delay: RepeatDelay::Ms500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingsError {
+    /// Nothing was ever stored, or the bytes were lost.
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadChecksum,
+    /// The checksum matches but a field holds an unknown value.
+    BadValue,
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingsError::BadMagic => f.write_str("no settings record"),
+            SettingsError::UnsupportedVersion(version) => write!(f, "settings version {}", version),
+            SettingsError::BadChecksum => f.write_str("bad checksum"),
+            SettingsError::BadValue => f.write_str("invalid value"),
+        }
+    }
+}
+
+impl Settings {
+    /// The settings in effect right now.
+    pub fn current() -> Settings {
+        let (repeat_rate, repeat_delay) = keyboard::typematic();
+        Settings { console: vga_buffer::console(), repeat_rate, repeat_delay }
+    }
+
+    pub fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut record = [
+            SETTINGS_MAGIC,
+            SETTINGS_VERSION,
+            self.console as u8,
+            keyboard::typematic_byte(self.repeat_rate, self.repeat_delay),
+            0,
+        ];
+        // the bytes sum to zero, like an ACPI table
+        record[RECORD_LEN - 1] = crate::bytes::checksum(&record).wrapping_neg();
+        record
+    }
+
+    pub fn decode(record: &[u8; RECORD_LEN]) -> Result<Settings, SettingsError> {
+        if record[0] != SETTINGS_MAGIC {
+            return Err(SettingsError::BadMagic);
+        }
+        if record[1] != SETTINGS_VERSION {
+            return Err(SettingsError::UnsupportedVersion(record[1]));
+        }
+        if crate::bytes::checksum(record) != 0 {
+            return Err(SettingsError::BadChecksum);
+        }
+        let console = match record[2] {
+            0 => Console::Vga,
+            1 => Console::Serial,
+            2 => Console::Both,
+            _ => return Err(SettingsError::BadValue),
+        };
+        let (repeat_rate, repeat_delay) = keyboard::decode_typematic(record[3]).ok_or(SettingsError::BadValue)?;
+        Ok(Settings { console, repeat_rate, repeat_delay })
+    }
+}
+
+pub fn store_settings(settings: &Settings) {
+    for (i, byte) in settings.encode().into_iter().enumerate() {
+        write(SETTINGS_BASE + i as u8, byte);
+    }
+}
+
+pub fn load_settings() -> Result<Settings, SettingsError> {
+    let mut record = [0u8; RECORD_LEN];
+    for (i, byte) in record.iter_mut().enumerate() {
+        *byte = read(SETTINGS_BASE + i as u8);
+    }
+    Settings::decode(&record)
+}
+
+/// Applies the stored settings at boot; `console=` on the command line
+/// still wins. Without a valid record nothing changes, so the firmware's
+/// keyboard defaults stay.
+pub fn init() {
+    match load_settings() {
+        Ok(settings) => {
+            if crate::cmdline::get("console").is_none() {
+                vga_buffer::set_console(settings.console);
+            }
+            if let Err(err) = keyboard::set_typematic(settings.repeat_rate, settings.repeat_delay) {
+                crate::warn!("CMOS: could not restore the keyboard rate: {}", err);
+            }
+        }
+        Err(err) => crate::info!("CMOS: {}, using defaults", err),
+    }
+}
+
+//test case
+#[test_case]
+fn test_settings_round_trip() {
+    let settings = Settings {
+        console: Console::Both,
+        repeat_rate: RepeatRate::SLOWEST,
+        repeat_delay: RepeatDelay::Ms1000,
+    };
+    let record = settings.encode();
+    assert_eq!(&record[..4], &[SETTINGS_MAGIC, SETTINGS_VERSION, 2, 0x7f]);
+    assert_eq!(crate::bytes::checksum(&record), 0);
+    assert_eq!(Settings::decode(&record), Ok(settings));
+    assert_eq!(Settings::decode(&Settings::default().encode()), Ok(Settings::default()));
+}
+
+#[test_case]
+fn test_settings_rejects_damaged_records() {
+    let record = Settings::default().encode();
+    assert_eq!(Settings::decode(&[0; RECORD_LEN]), Err(SettingsError::BadMagic));
+    assert_eq!(Settings::decode(&[0xff; RECORD_LEN]), Err(SettingsError::BadMagic));
+    let mut version = record;
+    version[1] = 2;
+    assert_eq!(Settings::decode(&version), Err(SettingsError::UnsupportedVersion(2)));
+    let mut flipped = record;
+    flipped[2] ^= 1;
+    assert_eq!(Settings::decode(&flipped), Err(SettingsError::BadChecksum));
+    // a checksum-correct record with an unknown console
+    let mut unknown = record;
+    unknown[2] = 9;
+    unknown[4] = unknown[4].wrapping_sub(9);
+    assert_eq!(Settings::decode(&unknown), Err(SettingsError::BadValue));
+}
+
+#[test_case]
+fn test_rtc_registers_are_off_limits() {
+    assert!(!is_accessible(0x00));
+    assert!(!is_accessible(0x0d));
+    assert!(is_accessible(0x0e));
+    assert!(is_accessible(SETTINGS_BASE + RECORD_LEN as u8 - 1));
+    assert!(!is_accessible(0x80));
+}
+
+#[test_case]
+fn test_rtc_decoding() {
+    // 23:59:58 on 2024-02-29, BCD and 24-hour
+    let bcd = decode_rtc([0x58, 0x59, 0x23, 0x29, 0x02, 0x24], STATUS_B_24_HOUR);
+    assert_eq!(bcd, DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 58 });
+    let binary = decode_rtc([58, 59, 23, 29, 2, 24], STATUS_B_24_HOUR | STATUS_B_BINARY);
+    assert_eq!(binary, bcd);
+    // 12-hour clock
+    assert_eq!(decode_rtc([0, 0, 0x12, 1, 1, 0], 0).hour, 0);
+    assert_eq!(decode_rtc([0, 0, 0x12 | HOUR_PM, 1, 1, 0], 0).hour, 12);
+    assert_eq!(decode_rtc([0, 0, 0x11 | HOUR_PM, 1, 1, 0], 0).hour, 23);
+    let now = read_rtc();
+    assert!((1..=12).contains(&now.month) && (1..=31).contains(&now.day) && now.hour < 24);
+}
+
+#[test_case]
+fn test_rtc_encoding() {
+    let time = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 58 };
+    assert_eq!(encode_rtc(time, STATUS_B_24_HOUR), [0x58, 0x59, 0x23, 0x29, 0x02, 0x24]);
+    assert_eq!(encode_rtc(time, STATUS_B_24_HOUR | STATUS_B_BINARY), [58, 59, 23, 29, 2, 24]);
+    assert_eq!(encode_rtc(time, 0)[2], 0x11 | HOUR_PM);
+    for status_b in [0, STATUS_B_24_HOUR, STATUS_B_BINARY, STATUS_B_24_HOUR | STATUS_B_BINARY] {
+        for hour in 0..24 {
+            let time = DateTime { hour, ..time };
+            assert_eq!(decode_rtc(encode_rtc(time, status_b), status_b), time);
+        }
+    }
+    assert_eq!(alloc::format!("{}", time), "2024-02-29 23:59:58");
+}
+
+#[test_case]
+fn test_read_rtc() {
+    let first = read_rtc();
+    let second = read_rtc();
+    assert!((1..=12).contains(&first.month), "{}", first);
+    assert!((1..=31).contains(&first.day), "{}", first);
+    assert!(first.hour < 24 && first.minute < 60 && first.second < 60, "{}", first);
+    // unless a minute ended in between, the second reading is no earlier
+    if (first.year, first.month, first.day, first.hour, first.minute)
+        == (second.year, second.month, second.day, second.hour, second.minute) {
+        assert!(second.second >= first.second, "{} then {}", first, second);
+    }
+}
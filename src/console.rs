@@ -0,0 +1,1030 @@
+//! Console input: what happens to bytes between the devices and the shell.
+//!
+//! Each terminal (the PS/2 keyboard, COM1 and the virtio console) has a
+//! `LineDiscipline`. In cooked mode, the default, it echoes what is typed,
+//! keeps the line being edited, turns CR into NL and the control keys into
+//! their meaning, and queues finished lines for the shell. Left, Right,
+//! Home and End (or Ctrl+A and Ctrl+E) move through the line, and typing,
+//! Backspace and Delete edit it where the cursor is; the rest of the line
+//! is redrawn after the change. Up and Down, from the keyboard or as a
+//! terminal's escape sequence, recall the last `HISTORY_LEN` lines into
+//! the one being edited. Tab asks the `Completer` the shell set for the
+//! words that could finish the one before the cursor: one is typed in,
+//! several are listed under the line, which is then typed again after the
+//! prompt. In raw mode it
+//! only queues the bytes, untouched, for whoever asked for raw mode; the
+//! XMODEM receiver takes COM1 raw for a transfer through `raw`, whose guard
+//! puts the previous mode back.
+//!
+//! COM1's interrupt handler only moves bytes from the UART into a
+//! `ByteQueue`; `serial_input`, a task, feeds them to the discipline, where
+//! the echo and any shell command run.
+//!
+//! Output to COM1 gets NL turned into CRLF in cooked mode too, see
+//! `write_cooked`. The kernel panics with `panic=abort`, so a guard is
+//! never dropped on the way down; the panic handler calls
+//! `restore_after_panic` instead.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::sync::ByteQueue;
+use crate::task::{AtomicWaker, Stream, StreamExt};
+use crate::{interrupts, print, serial};
+
+/// Longest line cooked mode keeps; further characters are dropped.
+pub const MAX_LINE: usize = 256;
+/// Bytes raw mode holds for a reader that is not keeping up.
+pub const MAX_RAW: usize = 4096;
+
+const CTRL_A: char = '\x01';
+const CTRL_C: char = '\x03';
+const CTRL_E: char = '\x05';
+const CTRL_U: char = '\x15';
+const BACKSPACE: char = '\x08';
+const DELETE: char = '\x7f';
+const ESC: u8 = 0x1b;
+/// Erases the character before the cursor on the screen and on terminals.
+const ERASE: &str = "\x08 \x08";
+/// Moves the cursor one column left without erasing, on the screen (see
+/// `ansi`) and on terminals. Moving right is done by echoing the character
+/// the cursor passes.
+const CURSOR_LEFT: &str = "\x1b[D";
+/// Lines the history keeps.
+pub const HISTORY_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Cooked,
+    Raw,
+}
+
+/// A key as the keyboard decoder or an escape sequence gives it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    /// Delete the character under the cursor, not the one before it.
+    Delete,
+}
+
+impl Key {
+    /// What a VT100 terminal sends for the key.
+    fn terminal_bytes(self, out: &mut [u8; 4]) -> &[u8] {
+        match self {
+            Key::Char(c) => c.encode_utf8(out).as_bytes(),
+            Key::Up => b"\x1b[A",
+            Key::Down => b"\x1b[B",
+            Key::Right => b"\x1b[C",
+            Key::Left => b"\x1b[D",
+            Key::Home => b"\x1b[H",
+            Key::End => b"\x1b[F",
+            Key::Delete => b"\x1b[3~",
+        }
+    }
+}
+
+/// Where a terminal's escape sequence is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// After ESC.
+    Started,
+    /// After ESC [ or ESC O, before the final byte, with the first
+    /// parameter so far (`3` in ESC [ 3 ~).
+    Sequence(u16),
+}
+
+/// The last lines entered, oldest first, and which one is on the line
+/// while Up and Down move through them.
+struct History {
+    lines: VecDeque<String>,
+    /// Index into `lines` of the recalled line, `None` for the line being
+    /// typed, which `draft` keeps meanwhile.
+    recalled: Option<usize>,
+    draft: String,
+}
+
+impl History {
+    const fn new() -> Self {
+        History { lines: VecDeque::new(), recalled: None, draft: String::new() }
+    }
+
+    /// Keeps an entered line, unless it is blank or repeats the last one.
+    fn push(&mut self, line: &str) {
+        self.recalled = None;
+        if line.trim().is_empty() || self.lines.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.lines.len() == HISTORY_LEN {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(String::from(line));
+    }
+
+    /// The line Up puts in place of `current`, if there is an older one.
+    fn older(&mut self, current: &str) -> Option<&str> {
+        let index = match self.recalled {
+            None if self.lines.is_empty() => return None,
+            None => {
+                self.draft = String::from(current);
+                self.lines.len() - 1
+            }
+            Some(0) => return None,
+            Some(index) => index - 1,
+        };
+        self.recalled = Some(index);
+        Some(&self.lines[index])
+    }
+
+    /// The line Down puts back: a newer one, or finally the draft.
+    fn newer(&mut self) -> Option<&str> {
+        let index = self.recalled?;
+        if index + 1 < self.lines.len() {
+            self.recalled = Some(index + 1);
+            Some(&self.lines[index + 1])
+        } else {
+            self.recalled = None;
+            Some(&self.draft)
+        }
+    }
+}
+
+/// How Tab finds the words that could finish a line.
+#[derive(Debug, Clone, Copy)]
+pub struct Completer {
+    /// Given the line up to the cursor, the whole words that could stand
+    /// where its last one (after the last space) is.
+    pub candidates: fn(&str) -> Vec<String>,
+    /// Typed again, before the line, under a list of candidates.
+    pub prompt: &'static str,
+}
+
+/// The longest start `a` and `b` share.
+fn common_prefix<'a>(a: &'a str, b: &str) -> &'a str {
+    let len = a.char_indices().zip(b.chars()).find(|&((_, x), y)| x != y).map_or(a.len().min(b.len()), |((i, _), _)| i);
+    &a[..len]
+}
+
+/// What cooked mode hands to the shell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A finished line, without its newline.
+    Line(String),
+    /// Ctrl+C: the line being typed was thrown away.
+    Interrupt,
+}
+
+pub struct LineDiscipline {
+    mode: Mode,
+    line: String,
+    /// Byte index into `line` of the character under the cursor.
+    cursor: usize,
+    /// The last byte was a CR, so an LF right after it is the same Enter.
+    after_cr: bool,
+    interrupted: bool,
+    escape: Escape,
+    history: History,
+    completer: Option<Completer>,
+    events: VecDeque<Event>,
+    raw: VecDeque<u8>,
+}
+
+impl Default for LineDiscipline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineDiscipline {
+    pub const fn new() -> Self {
+        LineDiscipline {
+            mode: Mode::Cooked,
+            line: String::new(),
+            cursor: 0,
+            after_cr: false,
+            interrupted: false,
+            escape: Escape::None,
+            history: History::new(),
+            completer: None,
+            events: VecDeque::new(),
+            raw: VecDeque::new(),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Switches to `mode` and returns the mode it replaced. A line being
+    /// typed when raw mode starts is kept for when cooked mode comes back;
+    /// raw bytes nobody read are dropped then, they were not typed at the
+    /// shell.
+    pub fn set_mode(&mut self, mode: Mode) -> Mode {
+        if mode == Mode::Cooked {
+            self.raw.clear();
+        }
+        self.after_cr = false;
+        self.escape = Escape::None;
+        core::mem::replace(&mut self.mode, mode)
+    }
+
+    /// Takes one byte from a terminal. Only ASCII is cooked; other bytes
+    /// are dropped unless the mode is raw. The escape sequences of the
+    /// arrows, Home, End and Delete are understood, other sequences are
+    /// dropped whole.
+    pub fn receive(&mut self, byte: u8, echo: impl FnMut(&str)) {
+        if self.mode == Mode::Raw {
+            self.push_raw(&[byte]);
+            return;
+        }
+        match (self.escape, byte) {
+            (Escape::None, ESC) => self.escape = Escape::Started,
+            (Escape::None, byte) if byte.is_ascii() => self.cook(Key::Char(char::from(byte)), echo),
+            (Escape::None, _) => {}
+            (Escape::Started, b'[' | b'O') => self.escape = Escape::Sequence(0),
+            (Escape::Started, _) => self.escape = Escape::None,
+            (Escape::Sequence(param), b'0'..=b'9') => {
+                self.escape = Escape::Sequence(param.saturating_mul(10).saturating_add(u16::from(byte - b'0')));
+            }
+            // further parameters and intermediates, until the final byte
+            (Escape::Sequence(_), b';') => self.escape = Escape::Sequence(u16::MAX),
+            (Escape::Sequence(_), 0x20..=0x3f) => {}
+            (Escape::Sequence(param), byte) => {
+                self.escape = Escape::None;
+                let key = match (byte, param) {
+                    (b'A', _) => Key::Up,
+                    (b'B', _) => Key::Down,
+                    (b'C', _) => Key::Right,
+                    (b'D', _) => Key::Left,
+                    (b'H', _) | (b'~', 1 | 7) => Key::Home,
+                    (b'F', _) | (b'~', 4 | 8) => Key::End,
+                    (b'~', 3) => Key::Delete,
+                    _ => return,
+                };
+                self.cook(key, echo);
+            }
+        }
+    }
+
+    /// Takes a key the keyboard decoded; in raw mode what a terminal would
+    /// have sent for it.
+    pub fn receive_key(&mut self, key: Key, echo: impl FnMut(&str)) {
+        match self.mode {
+            Mode::Raw => self.push_raw(key.terminal_bytes(&mut [0; 4])),
+            Mode::Cooked => self.cook(key, echo),
+        }
+    }
+
+    pub fn receive_char(&mut self, c: char, echo: impl FnMut(&str)) {
+        self.receive_key(Key::Char(c), echo);
+    }
+
+    fn push_raw(&mut self, bytes: &[u8]) {
+        let room = MAX_RAW - self.raw.len();
+        self.raw.extend(bytes.iter().take(room));
+    }
+
+    fn cook(&mut self, key: Key, mut echo: impl FnMut(&str)) {
+        let c = match key {
+            Key::Char(c) => c,
+            Key::Up | Key::Down => {
+                self.after_cr = false;
+                let recalled = match key {
+                    Key::Up => self.history.older(&self.line),
+                    _ => self.history.newer(),
+                };
+                if let Some(recalled) = recalled {
+                    let recalled = String::from(recalled);
+                    self.replace_line(recalled, &mut echo);
+                }
+                return;
+            }
+            Key::Left | Key::Right | Key::Home | Key::End | Key::Delete => {
+                self.after_cr = false;
+                match key {
+                    Key::Left => self.move_left(1, &mut echo),
+                    Key::Right => self.move_right(1, &mut echo),
+                    Key::Home => self.move_left(usize::MAX, &mut echo),
+                    Key::End => self.move_right(usize::MAX, &mut echo),
+                    _ => self.delete_forward(&mut echo),
+                }
+                return;
+            }
+        };
+        let after_cr = core::mem::replace(&mut self.after_cr, c == '\r');
+        match c {
+            '\n' if after_cr => {}
+            '\r' | '\n' => {
+                echo("\n");
+                self.history.push(&self.line);
+                self.cursor = 0;
+                self.events.push_back(Event::Line(core::mem::take(&mut self.line)));
+            }
+            BACKSPACE | DELETE if self.cursor == self.line.len() => {
+                if self.line.pop().is_some() {
+                    self.cursor = self.line.len();
+                    echo(ERASE);
+                }
+            }
+            BACKSPACE | DELETE => {
+                if self.cursor > 0 {
+                    self.move_left(1, &mut echo);
+                    self.delete_forward(&mut echo);
+                }
+            }
+            CTRL_A => self.move_left(usize::MAX, &mut echo),
+            CTRL_E => self.move_right(usize::MAX, &mut echo),
+            CTRL_U => {
+                self.move_right(usize::MAX, &mut echo);
+                while self.line.pop().is_some() {
+                    echo(ERASE);
+                }
+                self.cursor = 0;
+            }
+            CTRL_C => {
+                self.line.clear();
+                self.cursor = 0;
+                self.history.recalled = None;
+                self.interrupted = true;
+                echo("^C\n");
+                self.events.push_back(Event::Interrupt);
+            }
+            '\t' if self.completer.is_some() => self.complete(&mut echo),
+            c if c.is_control() && c != '\t' => {}
+            c => self.insert(c.encode_utf8(&mut [0; 4]), &mut echo),
+        }
+    }
+
+    /// Types `text` in at the cursor, if the line has room for it.
+    fn insert(&mut self, text: &str, echo: &mut impl FnMut(&str)) {
+        if self.line.len() + text.len() <= MAX_LINE {
+            self.line.insert_str(self.cursor, text);
+            self.cursor += text.len();
+            echo(text);
+            self.redraw_tail(0, echo);
+        }
+    }
+
+    /// Tab: finishes the word before the cursor as far as every candidate
+    /// agrees, with a space after a word that is the only candidate (unless
+    /// it is a directory, ending in `/`). When that adds nothing the
+    /// candidates are listed and the line typed again.
+    fn complete(&mut self, echo: &mut impl FnMut(&str)) {
+        let Some(completer) = self.completer else { return };
+        let before = &self.line[..self.cursor];
+        let word = &before[before.rfind(' ').map_or(0, |i| i + 1)..];
+        let candidates: Vec<String> = (completer.candidates)(before).into_iter().filter(|c| c.starts_with(word)).collect();
+        let Some(first) = candidates.first() else { return };
+        let common = candidates.iter().fold(first.as_str(), |common, candidate| common_prefix(common, candidate));
+        let mut added = String::from(&common[word.len()..]);
+        if candidates.len() == 1 && !first.ends_with('/') {
+            added.push(' ');
+        }
+        if !added.is_empty() {
+            self.insert(&added, echo);
+        } else if candidates.len() > 1 {
+            echo("\n");
+            echo(&candidates.join("  "));
+            echo("\n");
+            echo(completer.prompt);
+            echo(&self.line);
+            for _ in self.line[self.cursor..].chars() {
+                echo(CURSOR_LEFT);
+            }
+        }
+    }
+
+    /// What Tab completes with; `None` makes it an ordinary character.
+    pub fn set_completer(&mut self, completer: Option<Completer>) {
+        self.completer = completer;
+    }
+
+    /// Moves the cursor up to `count` characters left.
+    fn move_left(&mut self, count: usize, echo: &mut impl FnMut(&str)) {
+        for c in self.line[..self.cursor].chars().rev().take(count) {
+            self.cursor -= c.len_utf8();
+            echo(CURSOR_LEFT);
+        }
+    }
+
+    /// Moves the cursor up to `count` characters right, by echoing them.
+    fn move_right(&mut self, count: usize, echo: &mut impl FnMut(&str)) {
+        let passed: String = self.line[self.cursor..].chars().take(count).collect();
+        self.cursor += passed.len();
+        echo(&passed);
+    }
+
+    /// Deletes the character under the cursor.
+    fn delete_forward(&mut self, echo: &mut impl FnMut(&str)) {
+        if self.cursor == self.line.len() {
+            return;
+        }
+        self.line.remove(self.cursor);
+        self.redraw_tail(1, echo);
+    }
+
+    /// Echoes the line from the cursor on, blanks the `erased` columns it
+    /// got shorter by, and moves back to the cursor.
+    fn redraw_tail(&mut self, erased: usize, echo: &mut impl FnMut(&str)) {
+        let tail = &self.line[self.cursor..];
+        echo(tail);
+        for _ in 0..erased {
+            echo(" ");
+        }
+        for _ in 0..tail.chars().count() + erased {
+            echo(CURSOR_LEFT);
+        }
+    }
+
+    /// Erases the line on the screen and types `line` in its place.
+    fn replace_line(&mut self, line: String, echo: &mut impl FnMut(&str)) {
+        self.move_right(usize::MAX, echo);
+        for _ in self.line.chars() {
+            echo(ERASE);
+        }
+        echo(&line);
+        self.cursor = line.len();
+        self.line = line;
+    }
+
+    /// The lines the history keeps, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.lines.iter().map(String::as_str)
+    }
+
+    /// The line typed so far.
+    pub fn pending_line(&self) -> &str {
+        &self.line
+    }
+
+    pub fn peek_event(&self) -> Option<&Event> {
+        self.events.front()
+    }
+
+    pub fn pop_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Whether Ctrl+C was pressed since the last call.
+    pub fn take_interrupt(&mut self) -> bool {
+        core::mem::take(&mut self.interrupted)
+    }
+
+    /// The next byte queued in raw mode.
+    pub fn read_raw(&mut self) -> Option<u8> {
+        self.raw.pop_front()
+    }
+
+    /// Drops the line being typed, unread lines and raw bytes.
+    pub fn flush(&mut self) {
+        self.line.clear();
+        self.cursor = 0;
+        self.after_cr = false;
+        self.escape = Escape::None;
+        self.history.recalled = None;
+        self.events.clear();
+        self.raw.clear();
+    }
+}
+
+/// Writes `s` for a terminal, with NL as CRLF.
+pub fn write_cooked(s: &str, mut out: impl FnMut(&str)) {
+    let mut lines = s.split('\n');
+    if let Some(first) = lines.next() {
+        out(first);
+    }
+    for line in lines {
+        out("\r\n");
+        out(line);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminal {
+    Keyboard,
+    Serial,
+    Virtio,
+}
+
+const TERMINALS: usize = 3;
+
+static DISCIPLINES: [Mutex<LineDiscipline>; TERMINALS] = [const { Mutex::new(LineDiscipline::new()) }; TERMINALS];
+/// COM1's mode, for the output path, which must not take its lock.
+static SERIAL_RAW: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// The terminal whose line the shell was handed last.
+static ACTIVE: AtomicU8 = AtomicU8::new(Terminal::Keyboard as u8);
+
+fn with<R>(terminal: Terminal, f: impl FnOnce(&mut LineDiscipline) -> R) -> R {
+    without_interrupts(|| f(&mut DISCIPLINES[terminal as usize].lock()))
+}
+
+fn echo(s: &str) {
+    print!("{}", s);
+}
+
+/// Feeds a byte from `terminal` through its discipline, then hands what
+/// it finished to the shell.
+pub fn receive(terminal: Terminal, byte: u8) {
+    with(terminal, |discipline| {
+        discipline.receive(byte, echo);
+        note_interrupt(discipline);
+    });
+    deliver(terminal);
+}
+
+/// Like `receive`, for a key the keyboard decoder produced.
+pub fn receive_key(terminal: Terminal, key: Key) {
+    with(terminal, |discipline| {
+        discipline.receive_key(key, echo);
+        note_interrupt(discipline);
+    });
+    deliver(terminal);
+}
+
+fn note_interrupt(discipline: &mut LineDiscipline) {
+    if discipline.take_interrupt() {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Hands `terminal`'s finished lines to the shell, oldest first. Stops
+/// while a command is running; the rest waits for the next input.
+pub fn deliver(terminal: Terminal) {
+    while let Some(event) = with(terminal, |discipline| discipline.peek_event().cloned()) {
+        ACTIVE.store(terminal as u8, Ordering::Relaxed);
+        let taken = match &event {
+            Event::Line(line) => interrupts::shell_line(line),
+            Event::Interrupt => interrupts::shell_interrupt(),
+        };
+        if !taken {
+            break;
+        }
+        with(terminal, |discipline| discipline.pop_event());
+    }
+}
+
+/// The terminal the command running now (or the last one) was typed on.
+pub fn active_terminal() -> Terminal {
+    match ACTIVE.load(Ordering::Relaxed) {
+        0 => Terminal::Keyboard,
+        1 => Terminal::Serial,
+        _ => Terminal::Virtio,
+    }
+}
+
+/// Gives every terminal the same Tab completion.
+pub fn set_completer(completer: Completer) {
+    for terminal in [Terminal::Keyboard, Terminal::Serial, Terminal::Virtio] {
+        with(terminal, |discipline| discipline.set_completer(Some(completer)));
+    }
+}
+
+/// The lines `terminal`'s history keeps, oldest first.
+pub fn history(terminal: Terminal) -> alloc::vec::Vec<String> {
+    with(terminal, |discipline| discipline.history().map(String::from).collect())
+}
+
+/// Whether Ctrl+C was pressed on any terminal since the last call.
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::Relaxed)
+}
+
+pub fn mode(terminal: Terminal) -> Mode {
+    with(terminal, |discipline| discipline.mode())
+}
+
+/// Switches `terminal` to `mode`, returning the mode it replaced.
+pub fn set_mode(terminal: Terminal, mode: Mode) -> Mode {
+    with(terminal, |discipline| {
+        if terminal == Terminal::Serial {
+            SERIAL_RAW.store(mode == Mode::Raw, Ordering::Relaxed);
+        }
+        discipline.set_mode(mode)
+    })
+}
+
+/// Raw mode on a terminal until the guard is dropped.
+#[must_use]
+pub struct RawMode {
+    terminal: Terminal,
+    previous: Mode,
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        set_mode(self.terminal, self.previous);
+    }
+}
+
+/// Puts `terminal` in raw mode until the returned guard is dropped.
+pub fn raw(terminal: Terminal) -> RawMode {
+    RawMode { terminal, previous: set_mode(terminal, Mode::Raw) }
+}
+
+/// The next raw byte from `terminal`. For COM1 this also takes bytes the
+/// interrupt queued, since the reader is usually a shell command, which
+/// keeps `serial_input` from running, and then the UART itself.
+pub fn read_raw(terminal: Terminal) -> Option<u8> {
+    with(terminal, |discipline| match discipline.read_raw() {
+        None if terminal == Terminal::Serial => SERIAL_INPUT.pop().or_else(serial::try_read_byte),
+        byte => byte,
+    })
+}
+
+/// Whether output to COM1 should get CRLF line ends.
+pub fn serial_output_cooked() -> bool {
+    !SERIAL_RAW.load(Ordering::Relaxed)
+}
+
+/// Puts every terminal back in cooked mode, for the panic handler. A
+/// discipline whose lock is held stays as it is, but COM1 output is
+/// cooked either way.
+pub fn restore_after_panic() {
+    SERIAL_RAW.store(false, Ordering::Relaxed);
+    for discipline in &DISCIPLINES {
+        if let Some(mut discipline) = discipline.try_lock() {
+            discipline.set_mode(Mode::Cooked);
+        }
+    }
+}
+
+/// What COM1's interrupt took from the UART, for `serial_input`.
+static SERIAL_INPUT: ByteQueue = ByteQueue::new();
+static SERIAL_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Starts taking COM1 input, on IRQ 4.
+pub fn init() {
+    interrupts::register_irq(4, serial_interrupt);
+    serial::enable_receive_interrupt();
+}
+
+/// Takes no lock: the bytes wait in `SERIAL_INPUT`, and a full queue
+/// drops them.
+fn serial_interrupt() {
+    let mut queued = false;
+    while let Some(byte) = serial::try_read_byte() {
+        queued |= SERIAL_INPUT.push(byte);
+    }
+    if queued {
+        SERIAL_WAKER.wake();
+    }
+}
+
+/// COM1 bytes lost because `serial_input` fell behind.
+pub fn serial_dropped_count() -> u64 {
+    SERIAL_INPUT.dropped()
+}
+
+struct SerialStream;
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context) -> core::task::Poll<Option<u8>> {
+        use core::task::Poll;
+        if let Some(byte) = SERIAL_INPUT.pop() {
+            return Poll::Ready(Some(byte));
+        }
+        SERIAL_WAKER.register(cx.waker());
+        match SERIAL_INPUT.pop() {
+            Some(byte) => Poll::Ready(Some(byte)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The task that feeds COM1 input to its discipline and the shell.
+pub async fn serial_input() {
+    let mut bytes = SerialStream;
+    while let Some(byte) = bytes.next().await {
+        receive(Terminal::Serial, byte);
+    }
+}
+
+//test case
+/// Feeds `input` to `discipline` and returns what it echoed.
+#[cfg(test)]
+fn feed(discipline: &mut LineDiscipline, input: &[u8]) -> String {
+    let mut echoed = String::new();
+    for &byte in input {
+        discipline.receive(byte, |s| echoed.push_str(s));
+    }
+    echoed
+}
+
+#[cfg(test)]
+fn events(discipline: &mut LineDiscipline) -> alloc::vec::Vec<Event> {
+    core::iter::from_fn(|| discipline.pop_event()).collect()
+}
+
+#[cfg(test)]
+fn line(text: &str) -> Event {
+    Event::Line(String::from(text))
+}
+
+#[test_case]
+fn test_cooked_lines() {
+    let mut discipline = LineDiscipline::new();
+    assert_eq!(feed(&mut discipline, b"ls /\rcat x\r\nhelp\n"), "ls /\ncat x\nhelp\n");
+    // CR, CRLF and LF are one Enter each
+    assert_eq!(events(&mut discipline), [line("ls /"), line("cat x"), line("help")]);
+    // an empty Enter is still a line, and two LFs are two
+    feed(&mut discipline, b"\r\n\n");
+    assert_eq!(events(&mut discipline), [line(""), line("")]);
+}
+
+#[test_case]
+fn test_cooked_editing() {
+    let mut discipline = LineDiscipline::new();
+    let echoed = feed(&mut discipline, b"lx\x08s\x7f\x7f\x7f ");
+    assert_eq!(echoed, "lx\x08 \x08s\x08 \x08\x08 \x08 ");
+    assert_eq!(discipline.pending_line(), " ");
+    // Ctrl+U erases what is left, other controls but tab are dropped
+    assert_eq!(feed(&mut discipline, b"\x15\x1bdate\x07\tx\r"), "\x08 \x08date\tx\n");
+    assert_eq!(events(&mut discipline), [line("date\tx")]);
+
+    // the keyboard's non-ASCII keys are kept whole, serial's bytes are not
+    discipline.receive_char('ñ', |_| {});
+    feed(&mut discipline, &[0xc3, 0xb1]);
+    discipline.receive_char(BACKSPACE, |_| {});
+    discipline.receive_char('o', |_| {});
+    assert_eq!(discipline.pending_line(), "o");
+}
+
+#[test_case]
+fn test_cooked_line_limit() {
+    let mut discipline = LineDiscipline::new();
+    let long = [b'a'; MAX_LINE + 10];
+    assert_eq!(feed(&mut discipline, &long).len(), MAX_LINE);
+    feed(&mut discipline, b"\x08b\r");
+    let mut expected = String::from_utf8(long[..MAX_LINE - 1].to_vec()).unwrap();
+    expected.push('b');
+    assert_eq!(events(&mut discipline), [Event::Line(expected)]);
+}
+
+#[test_case]
+fn test_ctrl_c() {
+    let mut discipline = LineDiscipline::new();
+    assert_eq!(feed(&mut discipline, b"rm -rf\x03ls\r"), "rm -rf^C\nls\n");
+    assert_eq!(events(&mut discipline), [Event::Interrupt, line("ls")]);
+    assert!(discipline.take_interrupt());
+    assert!(!discipline.take_interrupt());
+}
+
+#[test_case]
+fn test_raw_passthrough() {
+    let mut discipline = LineDiscipline::new();
+    assert_eq!(discipline.set_mode(Mode::Raw), Mode::Cooked);
+    let bytes = [0x01, 0x01, 0xfe, b'\r', b'\n', 0x03, 0x08, 0x7f, 0xff, 0x00];
+    assert_eq!(feed(&mut discipline, &bytes), "");
+    assert_eq!(core::iter::from_fn(|| discipline.read_raw()).collect::<alloc::vec::Vec<_>>(), bytes);
+    assert!(discipline.peek_event().is_none());
+    assert!(!discipline.take_interrupt());
+
+    discipline.receive_char('é', |_| {});
+    assert_eq!((discipline.read_raw(), discipline.read_raw()), (Some(0xc3), Some(0xa9)));
+    // a reader that falls behind loses the newest bytes
+    feed(&mut discipline, &[7; MAX_RAW + 5]);
+    assert_eq!(core::iter::from_fn(|| discipline.read_raw()).count(), MAX_RAW);
+}
+
+#[test_case]
+fn test_mode_changes_mid_line() {
+    let mut discipline = LineDiscipline::new();
+    feed(&mut discipline, b"echo half");
+    discipline.set_mode(Mode::Raw);
+    // the transfer's bytes are not typed into the line
+    feed(&mut discipline, b" way\r");
+    assert_eq!(discipline.pending_line(), "echo half");
+    assert!(discipline.peek_event().is_none());
+    assert_eq!(discipline.read_raw(), Some(b' '));
+
+    // what the raw reader left behind is dropped, the line goes on
+    assert_eq!(discipline.set_mode(Mode::Cooked), Mode::Raw);
+    assert_eq!(discipline.read_raw(), None);
+    feed(&mut discipline, b"!\r");
+    assert_eq!(events(&mut discipline), [line("echo half!")]);
+
+    // a CR just before raw mode started does not swallow an LF after it
+    feed(&mut discipline, b"a\r");
+    discipline.set_mode(Mode::Raw);
+    discipline.set_mode(Mode::Cooked);
+    feed(&mut discipline, b"\n");
+    assert_eq!(events(&mut discipline), [line("a"), line("")]);
+
+    feed(&mut discipline, b"left over\rpartial");
+    discipline.flush();
+    assert_eq!(discipline.pending_line(), "");
+    assert!(discipline.pop_event().is_none());
+}
+
+#[test_case]
+fn test_raw_guard() {
+    assert_eq!(mode(Terminal::Serial), Mode::Cooked);
+    {
+        let _outer = raw(Terminal::Serial);
+        assert!(!serial_output_cooked());
+        {
+            let _inner = raw(Terminal::Serial);
+            assert_eq!(mode(Terminal::Serial), Mode::Raw);
+        }
+        // the inner guard restores raw, not cooked
+        assert_eq!(mode(Terminal::Serial), Mode::Raw);
+    }
+    assert_eq!(mode(Terminal::Serial), Mode::Cooked);
+    assert!(serial_output_cooked());
+
+    // a panic never drops the guard; the handler restores instead
+    let guard = raw(Terminal::Serial);
+    core::mem::forget(guard);
+    assert_eq!(mode(Terminal::Serial), Mode::Raw);
+    restore_after_panic();
+    assert_eq!(mode(Terminal::Serial), Mode::Cooked);
+    assert!(serial_output_cooked());
+}
+
+#[test_case]
+fn test_cooked_output() {
+    let mut out = String::new();
+    write_cooked("one\ntwo\n\nthree", |s| out.push_str(s));
+    assert_eq!(out, "one\r\ntwo\r\n\r\nthree");
+    out.clear();
+    write_cooked("no newline", |s| out.push_str(s));
+    assert_eq!(out, "no newline");
+}
+
+#[cfg(test)]
+fn press(discipline: &mut LineDiscipline, key: Key) -> String {
+    let mut echoed = String::new();
+    discipline.receive_key(key, |s| echoed.push_str(s));
+    echoed
+}
+
+#[test_case]
+fn test_history_recall() {
+    let mut discipline = LineDiscipline::new();
+    feed(&mut discipline, b"ls /disk\rdate\r\recho hi\recho hi\r");
+    events(&mut discipline);
+    feed(&mut discipline, b"ca");
+    // the repeat and the blank line were not kept
+    assert_eq!(press(&mut discipline, Key::Up), "\x08 \x08\x08 \x08echo hi");
+    assert_eq!(press(&mut discipline, Key::Up), "\x08 \x08".repeat(7) + "date");
+    assert_eq!(press(&mut discipline, Key::Up), "\x08 \x08".repeat(4) + "ls /disk");
+    assert_eq!(press(&mut discipline, Key::Up), "");
+    assert_eq!(discipline.pending_line(), "ls /disk");
+    // the shorter line erases all of the longer one first
+    assert_eq!(press(&mut discipline, Key::Down), "\x08 \x08".repeat(8) + "date");
+    press(&mut discipline, Key::Down);
+    // back to what was being typed
+    assert_eq!(press(&mut discipline, Key::Down), "\x08 \x08".repeat(7) + "ca");
+    assert_eq!(press(&mut discipline, Key::Down), "");
+
+    press(&mut discipline, Key::Up);
+    feed(&mut discipline, b" there\r");
+    assert_eq!(events(&mut discipline), [line("echo hi there")]);
+    press(&mut discipline, Key::Up);
+    assert_eq!(discipline.pending_line(), "echo hi there");
+}
+
+#[test_case]
+fn test_history_keeps_the_newest() {
+    let mut discipline = LineDiscipline::new();
+    for i in 0..HISTORY_LEN + 4 {
+        feed(&mut discipline, alloc::format!("cmd{}\r", i).as_bytes());
+    }
+    for _ in 0..HISTORY_LEN + 4 {
+        press(&mut discipline, Key::Up);
+    }
+    assert_eq!(discipline.pending_line(), "cmd4");
+}
+
+#[test_case]
+fn test_arrow_escape_sequences() {
+    let mut discipline = LineDiscipline::new();
+    feed(&mut discipline, b"first\rsecond\r");
+    events(&mut discipline);
+    // CSI and SS3 arrows, sequences for keys the line has no use for, and
+    // a lone ESC
+    assert!(feed(&mut discipline, b"\x1b[A").ends_with("second"));
+    assert!(feed(&mut discipline, b"\x1bOA").ends_with("first"));
+    assert_eq!(feed(&mut discipline, b"\x1b[1;5P\x1b[15~"), "");
+    assert!(feed(&mut discipline, b"\x1b[B").ends_with("second"));
+    assert_eq!(feed(&mut discipline, b"\x1bx!"), "!");
+    assert_eq!(discipline.pending_line(), "second!");
+
+    // in raw mode the keyboard's arrows become the same sequences
+    discipline.set_mode(Mode::Raw);
+    discipline.receive_key(Key::Up, |_| {});
+    discipline.receive(ESC, |_| {});
+    assert_eq!(core::iter::from_fn(|| discipline.read_raw()).collect::<alloc::vec::Vec<_>>(), b"\x1b[A\x1b");
+}
+
+#[test_case]
+fn test_raw_serial_reads_the_queue() {
+    // what the interrupt queued while a command kept the task from running
+    let raw = raw(Terminal::Serial);
+    let dropped = serial_dropped_count();
+    for &byte in b"\r\x7fbin" {
+        assert!(SERIAL_INPUT.push(byte));
+    }
+    let read: alloc::vec::Vec<u8> = core::iter::from_fn(|| read_raw(Terminal::Serial)).collect();
+    assert_eq!(read, b"\r\x7fbin");
+    assert_eq!(serial_dropped_count(), dropped);
+    drop(raw);
+    assert_eq!(mode(Terminal::Serial), Mode::Cooked);
+}
+
+#[test_case]
+fn test_cursor_editing() {
+    let mut discipline = LineDiscipline::new();
+    feed(&mut discipline, b"ech hi");
+    // back to after "ech", and an inserted character redraws the rest
+    feed(&mut discipline, b"\x1b[D\x1b[D\x1b[D");
+    assert_eq!(feed(&mut discipline, b"o"), "o hi\x1b[D\x1b[D\x1b[D");
+    assert_eq!(discipline.pending_line(), "echo hi");
+    // Home, Delete, and End echoing what it passes
+    assert_eq!(press(&mut discipline, Key::Home), "\x1b[D".repeat(4));
+    assert_eq!(press(&mut discipline, Key::Delete), String::from("cho hi ") + &"\x1b[D".repeat(7));
+    assert_eq!(discipline.pending_line(), "cho hi");
+    assert_eq!(feed(&mut discipline, b"\x1b[F"), "cho hi");
+    // Backspace in the middle pulls the rest back
+    feed(&mut discipline, b"\x01\x1b[C\x1b[C");
+    assert_eq!(feed(&mut discipline, b"\x7f"), "\x1b[Do hi \x1b[D\x1b[D\x1b[D\x1b[D\x1b[D");
+    assert_eq!(discipline.pending_line(), "co hi");
+    // keys that go nowhere echo nothing
+    feed(&mut discipline, b"\x05");
+    assert_eq!(press(&mut discipline, Key::Right), "");
+    assert_eq!(press(&mut discipline, Key::Delete), "");
+    feed(&mut discipline, b"\x1b[1~");
+    assert_eq!(press(&mut discipline, Key::Left), "");
+    feed(&mut discipline, b"\r");
+    assert_eq!(events(&mut discipline), [line("co hi")]);
+    assert_eq!(discipline.history().collect::<alloc::vec::Vec<_>>(), ["co hi"]);
+}
+
+#[test_case]
+fn test_editing_keeps_wide_characters_whole() {
+    let mut discipline = LineDiscipline::new();
+    discipline.receive_char('a', |_| {});
+    discipline.receive_char('ñ', |_| {});
+    discipline.receive_char('o', |_| {});
+    press(&mut discipline, Key::Left);
+    press(&mut discipline, Key::Left);
+    discipline.receive_char(BACKSPACE, |_| {});
+    assert_eq!(discipline.pending_line(), "ño");
+    press(&mut discipline, Key::Delete);
+    assert_eq!(discipline.pending_line(), "o");
+    // Ctrl+U from the middle still clears everything
+    discipline.receive_char('x', |_| {});
+    press(&mut discipline, Key::Home);
+    discipline.receive_char(CTRL_U, |_| {});
+    assert_eq!(discipline.pending_line(), "");
+}
+
+#[test_case]
+fn test_tab_completion() {
+    fn candidates(line: &str) -> Vec<String> {
+        match line.split_once(' ') {
+            None => ["mount", "mouse", "ls"].map(String::from).to_vec(),
+            Some(_) => ["/disk/", "/dev"].map(String::from).to_vec(),
+        }
+    }
+    let mut discipline = LineDiscipline::new();
+    // without a completer a tab is typed like any character
+    assert_eq!(feed(&mut discipline, b"\t"), "\t");
+    discipline.flush();
+    discipline.set_completer(Some(Completer { candidates, prompt: "$ " }));
+
+    // one candidate is finished with a space, several as far as they agree
+    assert_eq!(feed(&mut discipline, b"l\t"), "ls ");
+    assert_eq!(feed(&mut discipline, b"/d\t"), "/d\n/disk/  /dev\n$ ls /d");
+    assert_eq!(feed(&mut discipline, b"i\t"), "isk/");
+    assert_eq!(discipline.pending_line(), "ls /disk/");
+    discipline.flush();
+    assert_eq!(feed(&mut discipline, b"m\t"), "mou");
+    // then they are listed, and the line typed again with the cursor kept
+    feed(&mut discipline, b" x\x1b[D\x1b[D");
+    assert_eq!(feed(&mut discipline, b"\t"), "\nmount  mouse\n$ mou x\x1b[D\x1b[D");
+    // a word nothing starts with is left alone
+    assert_eq!(feed(&mut discipline, b"\x1b[Fz\t\r"), " xz\n");
+    assert_eq!(events(&mut discipline), [line("mou xz")]);
+}
+
+#[test_case]
+fn test_common_prefix() {
+    assert_eq!(common_prefix("mount", "mouse"), "mou");
+    assert_eq!(common_prefix("mou", "mouse"), "mou");
+    assert_eq!(common_prefix("mouse", "mo"), "mo");
+    assert_eq!(common_prefix("añb", "añc"), "añ");
+    assert_eq!(common_prefix("", "x"), "");
+}
@@ -0,0 +1,821 @@
+//! CPU setup and model-specific registers.
+//!
+//! `enable_sse` turns on the x87 FPU and SSE. The kernel itself is built
+//! soft-float, so only user programs and explicit `asm!` touch those
+//! registers; the interrupt stubs save them with `fxsave64` around every
+//! handler, and each process keeps its own copy (see `FpuState`).
+//!
+//! `rdmsr` and `wrmsr` are the bare instructions: touching an MSR the CPU
+//! lacks raises #GP, which is fatal in the kernel. `try_rdmsr` and
+//! `try_wrmsr` arm a fixup first, so the #GP handler skips the faulting
+//! instruction and the caller gets `MsrError::Unavailable` instead. The
+//! fixup is global, so the checked versions run with interrupts off.
+//!
+//! `halt` is the idle loop's `hlt`, with the time spent halted counted;
+//! `idle_stats` reports it. Only the boot CPU is accounted.
+//!
+//! `info` is what CPUID reported at boot: vendor, brand string, family
+//! and model, the TSC frequency and the features in `Feature`. Code that
+//! depends on one asks `has(Feature::...)` rather than running CPUID
+//! itself.
+
+use core::arch::x86_64::CpuidResult;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::interrupts::registers::ExceptionFrame;
+use crate::time;
+
+pub const IA32_TSC: u32 = 0x10;
+pub const IA32_APIC_BASE: u32 = 0x1b;
+pub const IA32_FEATURE_CONTROL: u32 = 0x3a;
+pub const IA32_BIOS_UPDT_TRIG: u32 = 0x79;
+pub const IA32_BIOS_SIGN_ID: u32 = 0x8b;
+pub const IA32_MISC_ENABLE: u32 = 0x1a0;
+pub const IA32_PAT: u32 = 0x277;
+pub const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+pub const IA32_EFER: u32 = 0xc000_0080;
+pub const IA32_STAR: u32 = 0xc000_0081;
+pub const IA32_LSTAR: u32 = 0xc000_0082;
+pub const IA32_FMASK: u32 = 0xc000_0084;
+pub const IA32_FS_BASE: u32 = 0xc000_0100;
+pub const IA32_GS_BASE: u32 = 0xc000_0101;
+pub const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+pub const IA32_TSC_AUX: u32 = 0xc000_0103;
+
+/// MSRs the shell knows by name.
+pub const NAMED_MSRS: &[(&str, u32)] = &[
+    ("IA32_TSC", IA32_TSC),
+    ("IA32_APIC_BASE", IA32_APIC_BASE),
+    ("IA32_FEATURE_CONTROL", IA32_FEATURE_CONTROL),
+    ("IA32_BIOS_UPDT_TRIG", IA32_BIOS_UPDT_TRIG),
+    ("IA32_BIOS_SIGN_ID", IA32_BIOS_SIGN_ID),
+    ("IA32_MISC_ENABLE", IA32_MISC_ENABLE),
+    ("IA32_PAT", IA32_PAT),
+    ("IA32_PERF_GLOBAL_CTRL", IA32_PERF_GLOBAL_CTRL),
+    ("IA32_EFER", IA32_EFER),
+    ("IA32_STAR", IA32_STAR),
+    ("IA32_LSTAR", IA32_LSTAR),
+    ("IA32_FMASK", IA32_FMASK),
+    ("IA32_FS_BASE", IA32_FS_BASE),
+    ("IA32_GS_BASE", IA32_GS_BASE),
+    ("IA32_KERNEL_GS_BASE", IA32_KERNEL_GS_BASE),
+    ("IA32_TSC_AUX", IA32_TSC_AUX),
+];
+
+/// Never written from the shell, even with `-f`: a bad write to the
+/// microcode trigger or a locked feature control lasts until reset.
+pub const WRITE_DENYLIST: &[u32] = &[IA32_BIOS_UPDT_TRIG, IA32_FEATURE_CONTROL];
+
+pub fn msr_name(msr: u32) -> Option<&'static str> {
+    NAMED_MSRS.iter().find(|&&(_, number)| number == msr).map(|&(name, _)| name)
+}
+
+/// An MSR by name (any case) or as a hex number, with or without `0x`.
+pub fn parse_msr(text: &str) -> Option<u32> {
+    if let Some(&(_, number)) = NAMED_MSRS.iter().find(|(name, _)| name.eq_ignore_ascii_case(text)) {
+        return Some(number);
+    }
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u32::from_str_radix(digits, 16).ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrError {
+    /// The access raised #GP: the MSR does not exist or refused the value.
+    Unavailable,
+    /// On `WRITE_DENYLIST`.
+    Denied,
+}
+
+impl fmt::Display for MsrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MsrError::Unavailable => f.write_str("MSR not available"),
+            MsrError::Denied => f.write_str("writing this MSR is not allowed"),
+        }
+    }
+}
+
+/// Reads `msr`. A missing MSR raises #GP.
+///
+/// # Safety
+/// Some MSRs have side effects on read.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    u64::from(high) << 32 | u64::from(low)
+}
+
+/// Writes `msr`. A missing MSR or a reserved bit raises #GP.
+///
+/// # Safety
+/// MSRs control paging, interrupts and the CPU itself.
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    unsafe {
+        core::arch::asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32,
+            options(nostack, preserves_flags));
+    }
+}
+
+/// Address of the armed `rdmsr`/`wrmsr`, 0 when none is.
+static FIXUP_RIP: AtomicU64 = AtomicU64::new(0);
+static FAULTED: AtomicBool = AtomicBool::new(false);
+/// Both instructions are `0f 3x`.
+const MSR_INSN_LEN: u64 = 2;
+
+/// Where to resume after a #GP at `rip`, if it hit the armed instruction.
+pub fn fixup_target(armed: u64, rip: u64) -> Option<u64> {
+    (armed != 0 && rip == armed).then_some(rip + MSR_INSN_LEN)
+}
+
+/// Called by the #GP handler first: skips an armed MSR access and
+/// records the fault.
+pub(crate) fn recover_gp(frame: &mut ExceptionFrame) -> bool {
+    let Some(resume) = fixup_target(FIXUP_RIP.load(Ordering::Relaxed), frame.stack_frame.instruction_pointer.as_u64()) else {
+        return false;
+    };
+    frame.stack_frame.instruction_pointer = x86_64::VirtAddr::new(resume);
+    FAULTED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Reads `msr`, turning a #GP into `MsrError::Unavailable`.
+pub fn try_rdmsr(msr: u32) -> Result<u64, MsrError> {
+    without_interrupts(|| {
+        let (low, high): (u32, u32);
+        FAULTED.store(false, Ordering::Relaxed);
+        unsafe {
+            core::arch::asm!(
+                "lea {tmp}, [rip + 2f]",
+                "mov [rip + {fixup}], {tmp}",
+                "2: rdmsr",
+                "mov qword ptr [rip + {fixup}], 0",
+                fixup = sym FIXUP_RIP,
+                tmp = out(reg) _,
+                in("ecx") msr,
+                out("eax") low,
+                out("edx") high,
+                options(nostack),
+            );
+        }
+        if FAULTED.swap(false, Ordering::Relaxed) {
+            Err(MsrError::Unavailable)
+        } else {
+            Ok(u64::from(high) << 32 | u64::from(low))
+        }
+    })
+}
+
+/// Writes `msr`, turning a #GP into `MsrError::Unavailable`. Refuses
+/// `WRITE_DENYLIST`.
+///
+/// # Safety
+/// As for `wrmsr`.
+pub unsafe fn try_wrmsr(msr: u32, value: u64) -> Result<(), MsrError> {
+    if WRITE_DENYLIST.contains(&msr) {
+        return Err(MsrError::Denied);
+    }
+    without_interrupts(|| {
+        FAULTED.store(false, Ordering::Relaxed);
+        unsafe {
+            core::arch::asm!(
+                "lea {tmp}, [rip + 2f]",
+                "mov [rip + {fixup}], {tmp}",
+                "2: wrmsr",
+                "mov qword ptr [rip + {fixup}], 0",
+                fixup = sym FIXUP_RIP,
+                tmp = out(reg) _,
+                in("ecx") msr,
+                in("eax") value as u32,
+                in("edx") (value >> 32) as u32,
+                options(nostack),
+            );
+        }
+        match FAULTED.swap(false, Ordering::Relaxed) {
+            true => Err(MsrError::Unavailable),
+            false => Ok(()),
+        }
+    })
+}
+
+// ==========================================================
+// FPU / SSE
+// ==========================================================
+
+/// Control words after `fninit`: every x87 and SSE exception masked.
+const DEFAULT_FCW: u16 = 0x037f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// An `fxsave64` image: x87 and SSE registers plus their control words.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(C, align(16))]
+pub struct FpuState(pub [u8; 512]);
+
+impl FpuState {
+    pub fn fcw(&self) -> u16 {
+        u16::from_le_bytes([self.0[0], self.0[1]])
+    }
+
+    pub fn mxcsr(&self) -> u32 {
+        u32::from_le_bytes(self.0[24..28].try_into().unwrap())
+    }
+}
+
+impl Default for FpuState {
+    /// The state a program starts with: zeroed registers, exceptions masked.
+    fn default() -> FpuState {
+        let mut image = [0u8; 512];
+        image[0..2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        image[24..28].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        // MXCSR_MASK: the bits fxrstor may load
+        image[28..32].copy_from_slice(&0xffffu32.to_le_bytes());
+        FpuState(image)
+    }
+}
+
+impl fmt::Debug for FpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FpuState {{ fcw: {:#06x}, mxcsr: {:#010x} }}", self.fcw(), self.mxcsr())
+    }
+}
+
+/// Loads `state` into the FPU and SSE registers.
+pub fn load_fpu(state: &FpuState) {
+    unsafe { core::arch::asm!("fxrstor64 [{}]", in(reg) state, options(nostack, readonly, preserves_flags)) };
+}
+
+/// Enables the FPU and SSE and reports their exceptions as #MF/#XM rather
+/// than through the legacy IRQ 13. With XSAVE, XCR0 gets x87 and SSE only:
+/// AVX stays off because `fxsave64` does not cover the upper YMM halves.
+/// Runs on every CPU before its first interrupt.
+pub fn enable_sse() {
+    use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+    use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+        if has(Feature::Xsave) {
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+            XCr0::write(XCr0Flags::X87 | XCr0Flags::SSE);
+        }
+    }
+    load_fpu(&FpuState::default());
+}
+
+// ==========================================================
+// CPUID
+// ==========================================================
+
+/// CPU features the kernel checks for, each one CPUID bit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Feature {
+    Fpu,
+    Tsc,
+    Apic,
+    Fxsr,
+    Sse,
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse4_1,
+    Sse4_2,
+    Popcnt,
+    Xsave,
+    Avx,
+    Avx2,
+    Rdrand,
+    Rdseed,
+    X2apic,
+    Pcid,
+    Smep,
+    Smap,
+    Hypervisor,
+    Syscall,
+    Nx,
+    Pages1G,
+    Rdtscp,
+    InvariantTsc,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 26] = [
+        Feature::Fpu, Feature::Tsc, Feature::Apic, Feature::Fxsr, Feature::Sse, Feature::Sse2,
+        Feature::Sse3, Feature::Ssse3, Feature::Sse4_1, Feature::Sse4_2, Feature::Popcnt,
+        Feature::Xsave, Feature::Avx, Feature::Avx2, Feature::Rdrand, Feature::Rdseed,
+        Feature::X2apic, Feature::Pcid, Feature::Smep, Feature::Smap, Feature::Hypervisor,
+        Feature::Syscall, Feature::Nx, Feature::Pages1G, Feature::Rdtscp, Feature::InvariantTsc,
+    ];
+
+    /// The name `/proc/cpuinfo` gives it on Linux.
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Fpu => "fpu",
+            Feature::Tsc => "tsc",
+            Feature::Apic => "apic",
+            Feature::Fxsr => "fxsr",
+            Feature::Sse => "sse",
+            Feature::Sse2 => "sse2",
+            Feature::Sse3 => "pni",
+            Feature::Ssse3 => "ssse3",
+            Feature::Sse4_1 => "sse4_1",
+            Feature::Sse4_2 => "sse4_2",
+            Feature::Popcnt => "popcnt",
+            Feature::Xsave => "xsave",
+            Feature::Avx => "avx",
+            Feature::Avx2 => "avx2",
+            Feature::Rdrand => "rdrand",
+            Feature::Rdseed => "rdseed",
+            Feature::X2apic => "x2apic",
+            Feature::Pcid => "pcid",
+            Feature::Smep => "smep",
+            Feature::Smap => "smap",
+            Feature::Hypervisor => "hypervisor",
+            Feature::Syscall => "syscall",
+            Feature::Nx => "nx",
+            Feature::Pages1G => "pdpe1gb",
+            Feature::Rdtscp => "rdtscp",
+            Feature::InvariantTsc => "constant_tsc",
+        }
+    }
+
+    /// Leaf, register and bit that report it.
+    fn location(self) -> (u32, Register, u32) {
+        use Register::*;
+        match self {
+            Feature::Fpu => (1, Edx, 0),
+            Feature::Tsc => (1, Edx, 4),
+            Feature::Apic => (1, Edx, 9),
+            Feature::Fxsr => (1, Edx, 24),
+            Feature::Sse => (1, Edx, 25),
+            Feature::Sse2 => (1, Edx, 26),
+            Feature::Sse3 => (1, Ecx, 0),
+            Feature::Ssse3 => (1, Ecx, 9),
+            Feature::Sse4_1 => (1, Ecx, 19),
+            Feature::Sse4_2 => (1, Ecx, 20),
+            Feature::Popcnt => (1, Ecx, 23),
+            Feature::Xsave => (1, Ecx, 26),
+            Feature::Avx => (1, Ecx, 28),
+            Feature::Rdrand => (1, Ecx, 30),
+            Feature::X2apic => (1, Ecx, 21),
+            Feature::Pcid => (1, Ecx, 17),
+            Feature::Hypervisor => (1, Ecx, 31),
+            Feature::Avx2 => (7, Ebx, 5),
+            Feature::Smep => (7, Ebx, 7),
+            Feature::Rdseed => (7, Ebx, 18),
+            Feature::Smap => (7, Ebx, 20),
+            Feature::Syscall => (0x8000_0001, Edx, 11),
+            Feature::Nx => (0x8000_0001, Edx, 20),
+            Feature::Pages1G => (0x8000_0001, Edx, 26),
+            Feature::Rdtscp => (0x8000_0001, Edx, 27),
+            Feature::InvariantTsc => (0x8000_0007, Edx, 8),
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What CPUID says about the boot CPU, read once. The other CPUs are
+/// taken to be the same.
+#[derive(Debug, Clone)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    brand_len: usize,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    features: u64,
+    /// From leaf 0x15 or 0x16, when the CPU reports it.
+    tsc_hz: Option<u64>,
+}
+
+impl CpuInfo {
+    /// Runs CPUID on this CPU.
+    pub fn detect() -> CpuInfo {
+        CpuInfo::from_leaves(|leaf| core::arch::x86_64::__cpuid_count(leaf, 0))
+    }
+
+    /// Builds the record from `cpuid`, which answers a leaf (subleaf 0).
+    /// Leaves past the maximum leaf 0 or 0x8000_0000 reports are never
+    /// asked for.
+    pub fn from_leaves(cpuid: impl Fn(u32) -> CpuidResult) -> CpuInfo {
+        let basic = cpuid(0);
+        let max_extended = cpuid(0x8000_0000).eax;
+        let leaf = |leaf: u32| {
+            let max = if leaf >= 0x8000_0000 { max_extended } else { basic.eax };
+            (leaf <= max).then(|| cpuid(leaf))
+        };
+
+        let mut vendor = [0; 12];
+        for (i, reg) in [basic.ebx, basic.edx, basic.ecx].into_iter().enumerate() {
+            vendor[4 * i..4 * i + 4].copy_from_slice(&reg.to_le_bytes());
+        }
+
+        let mut brand = [0u8; 48];
+        let mut brand_len = 0;
+        if max_extended >= 0x8000_0004 {
+            for (i, number) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let regs = cpuid(number);
+                for (j, reg) in [regs.eax, regs.ebx, regs.ecx, regs.edx].into_iter().enumerate() {
+                    let at = 16 * i + 4 * j;
+                    brand[at..at + 4].copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+            // trimmed in place, so `brand()` can borrow it
+            let trimmed = core::str::from_utf8(&brand).unwrap_or("").trim_matches(|c: char| c == '\0' || c == ' ');
+            let start = trimmed.as_ptr() as usize - brand.as_ptr() as usize;
+            brand_len = trimmed.len();
+            brand.copy_within(start..start + brand_len, 0);
+        }
+
+        // family and model as the SDM combines them with the extended fields
+        let signature = leaf(1).map_or(0, |regs| regs.eax);
+        let base_family = (signature >> 8) & 0xf;
+        let family = if base_family == 0xf { base_family + ((signature >> 20) & 0xff) } else { base_family };
+        let model = if base_family == 0x6 || base_family == 0xf {
+            ((signature >> 12) & 0xf0) | ((signature >> 4) & 0xf)
+        } else {
+            (signature >> 4) & 0xf
+        };
+
+        let mut features = 0;
+        for feature in Feature::ALL {
+            let (number, register, bit) = feature.location();
+            let Some(regs) = leaf(number) else { continue };
+            let value = match register {
+                Register::Ebx => regs.ebx,
+                Register::Ecx => regs.ecx,
+                Register::Edx => regs.edx,
+            };
+            if value & (1 << bit) != 0 {
+                features |= 1 << feature as u8;
+            }
+        }
+
+        // leaf 0x15 is the TSC to crystal ratio, its ECX the crystal (0
+        // if not given); leaf 0x16 the base frequency in MHz
+        let ratio = leaf(0x15).filter(|regs| regs.eax != 0 && regs.ebx != 0 && regs.ecx != 0);
+        let tsc_hz = match ratio {
+            Some(regs) => Some(u64::from(regs.ecx) * u64::from(regs.ebx) / u64::from(regs.eax)),
+            None => leaf(0x16).map(|regs| u64::from(regs.eax & 0xffff) * 1_000_000).filter(|&hz| hz != 0),
+        };
+
+        CpuInfo { vendor, brand, brand_len, family, model, stepping: signature & 0xf, features, tsc_hz }
+    }
+
+    /// "GenuineIntel", "AuthenticAMD", "TCGTCGTCGTCG" under QEMU's TCG...
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("?")
+    }
+
+    /// The processor name from leaves 0x8000_0002-4, if the CPU has them.
+    pub fn brand(&self) -> Option<&str> {
+        let brand = core::str::from_utf8(&self.brand[..self.brand_len]).ok()?;
+        (!brand.is_empty()).then_some(brand)
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features & (1 << feature as u8) != 0
+    }
+
+    /// The features present, in `Feature::ALL` order.
+    pub fn features(&self) -> impl Iterator<Item = Feature> + '_ {
+        Feature::ALL.into_iter().filter(|&feature| self.has(feature))
+    }
+
+    /// TSC frequency as CPUID reports it, else as `time` measured it
+    /// against the PIT.
+    pub fn tsc_hz(&self) -> Option<u64> {
+        self.tsc_hz.or_else(time::tsc_frequency)
+    }
+}
+
+static INFO: Once<CpuInfo> = Once::new();
+
+/// Reads CPUID; called first thing at boot. Everything below reads it
+/// lazily too, for code that runs earlier.
+pub fn init() {
+    info();
+}
+
+pub fn info() -> &'static CpuInfo {
+    INFO.call_once(CpuInfo::detect)
+}
+
+/// Whether the CPU has `feature`.
+pub fn has(feature: Feature) -> bool {
+    info().has(feature)
+}
+
+/// The processor brand string, for `cpuinfo`.
+pub fn brand_string() -> Option<alloc::string::String> {
+    info().brand().map(alloc::string::String::from)
+}
+
+// ==========================================================
+// Idle accounting
+// ==========================================================
+
+/// Timer ticks between two idle samples, half a second.
+const IDLE_SAMPLE_TICKS: u64 = time::millis_to_ticks(500);
+/// Samples kept, so recent figures cover the last one to two seconds.
+const IDLE_WINDOW: usize = 4;
+
+/// TSC at the last `hlt`, 0 while running.
+static HALTED_SINCE: AtomicU64 = AtomicU64::new(0);
+static HALTED_CYCLES: AtomicU64 = AtomicU64::new(0);
+static WAKEUPS: AtomicU64 = AtomicU64::new(0);
+static IDLE_SAMPLES: Mutex<IdleWindow> = Mutex::new(IdleWindow::new());
+
+/// Halts until the next interrupt and counts the time as idle.
+pub fn halt() {
+    HALTED_SINCE.store(time::rdtsc(), Ordering::Relaxed);
+    x86_64::instructions::hlt();
+    // an exception or NMI woke us: no IRQ handler ended the halt
+    end_halt();
+}
+
+/// `halt` for a caller that checked for work with interrupts disabled:
+/// `sti; hlt` takes an interrupt only after the halt has begun, so one that
+/// arrived after the check still wakes it.
+pub fn enable_and_halt() {
+    HALTED_SINCE.store(time::rdtsc(), Ordering::Relaxed);
+    x86_64::instructions::interrupts::enable_and_hlt();
+    end_halt();
+}
+
+/// Called first thing in every IRQ handler, so the handler's own time
+/// counts as busy.
+pub(crate) fn end_halt() {
+    let since = HALTED_SINCE.swap(0, Ordering::Relaxed);
+    if since != 0 {
+        HALTED_CYCLES.fetch_add(time::rdtsc().wrapping_sub(since), Ordering::Relaxed);
+        WAKEUPS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The idle counters at one moment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IdleSample {
+    pub tsc: u64,
+    pub ticks: u64,
+    /// TSC cycles spent halted.
+    pub halted: u64,
+    pub wakeups: u64,
+}
+
+impl IdleSample {
+    pub fn now() -> IdleSample {
+        IdleSample {
+            tsc: time::rdtsc(),
+            ticks: time::ticks(),
+            halted: HALTED_CYCLES.load(Ordering::Relaxed),
+            wakeups: WAKEUPS.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleStats {
+    /// Share of the recent window spent halted.
+    pub halted_pct_recent: u8,
+    pub wakeups_per_sec: u64,
+    /// TSC cycles spent halted since boot.
+    pub total_halted: u64,
+}
+
+impl IdleStats {
+    /// What happened from `old` to `new`. Every counter may have wrapped
+    /// in between.
+    pub fn between(old: IdleSample, new: IdleSample) -> IdleStats {
+        let cycles = new.tsc.wrapping_sub(old.tsc);
+        let halted = new.halted.wrapping_sub(old.halted).min(cycles);
+        let ticks = new.ticks.wrapping_sub(old.ticks);
+        let wakeups = new.wakeups.wrapping_sub(old.wakeups);
+        let halted_pct_recent = match cycles {
+            0 => 0,
+            _ => (u128::from(halted) * 100 / u128::from(cycles)) as u8,
+        };
+        let wakeups_per_sec = match ticks {
+            0 => 0,
+            _ => (u128::from(wakeups) * u128::from(time::PIT_FREQUENCY_HZ)
+                / (u128::from(ticks) * u128::from(time::PIT_DIVISOR))) as u64,
+        };
+        IdleStats { halted_pct_recent, wakeups_per_sec, total_halted: new.halted }
+    }
+}
+
+/// The last `IDLE_WINDOW` samples.
+#[derive(Debug)]
+pub struct IdleWindow {
+    samples: [IdleSample; IDLE_WINDOW],
+    next: usize,
+    len: usize,
+}
+
+impl IdleWindow {
+    pub const fn new() -> IdleWindow {
+        IdleWindow { samples: [IdleSample { tsc: 0, ticks: 0, halted: 0, wakeups: 0 }; IDLE_WINDOW], next: 0, len: 0 }
+    }
+
+    pub fn push(&mut self, sample: IdleSample) {
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % IDLE_WINDOW;
+        self.len = (self.len + 1).min(IDLE_WINDOW);
+    }
+
+    pub fn oldest(&self) -> Option<IdleSample> {
+        match self.len {
+            0 => None,
+            IDLE_WINDOW => Some(self.samples[self.next]),
+            _ => Some(self.samples[0]),
+        }
+    }
+
+    /// From the oldest sample to `now`; from boot while the window is empty.
+    pub fn stats(&self, now: IdleSample) -> IdleStats {
+        IdleStats::between(self.oldest().unwrap_or_default(), now)
+    }
+}
+
+impl Default for IdleWindow {
+    fn default() -> IdleWindow {
+        IdleWindow::new()
+    }
+}
+
+/// Called from the timer interrupt handler only.
+pub(crate) fn sample_idle() {
+    if time::ticks().is_multiple_of(IDLE_SAMPLE_TICKS) {
+        // `idle_stats` holds the lock with interrupts off, so this only
+        // fails on another CPU; the sample is then skipped
+        if let Some(mut window) = IDLE_SAMPLES.try_lock() {
+            window.push(IdleSample::now());
+        }
+    }
+}
+
+pub fn idle_stats() -> IdleStats {
+    without_interrupts(|| IDLE_SAMPLES.lock().stats(IdleSample::now()))
+}
+
+//test case
+#[test_case]
+fn test_parse_msr() {
+    assert_eq!(parse_msr("IA32_EFER"), Some(IA32_EFER));
+    assert_eq!(parse_msr("ia32_lstar"), Some(IA32_LSTAR));
+    assert_eq!(parse_msr("0xc0000080"), Some(IA32_EFER));
+    assert_eq!(parse_msr("1B"), Some(IA32_APIC_BASE));
+    assert_eq!(parse_msr("100000000"), None);
+    assert_eq!(parse_msr("IA32_NOPE"), None);
+    assert_eq!(msr_name(IA32_APIC_BASE), Some("IA32_APIC_BASE"));
+    assert_eq!(msr_name(0x1234), None);
+    // every listed MSR parses back to itself
+    for &(name, number) in NAMED_MSRS {
+        assert_eq!(parse_msr(name), Some(number));
+    }
+}
+
+#[test_case]
+fn test_fixup_target() {
+    assert_eq!(fixup_target(0, 0x1000), None);
+    assert_eq!(fixup_target(0x1000, 0x1004), None);
+    assert_eq!(fixup_target(0x1000, 0x1000), Some(0x1002));
+}
+
+#[test_case]
+fn test_missing_msr_is_an_error() {
+    // outside every range Intel, AMD and the hypervisors use
+    assert_eq!(try_rdmsr(0xdead_0000), Err(MsrError::Unavailable));
+    assert_eq!(try_rdmsr(IA32_EFER).map(|efer| efer & 1 << 10 != 0), Ok(true));
+    assert_eq!(unsafe { try_wrmsr(IA32_BIOS_UPDT_TRIG, 0) }, Err(MsrError::Denied));
+    // the fixup is disarmed again, so this is an ordinary read
+    assert_eq!(unsafe { rdmsr(IA32_EFER) }, try_rdmsr(IA32_EFER).unwrap());
+}
+
+#[test_case]
+fn test_default_fpu_state() {
+    let state = FpuState::default();
+    assert_eq!(state.fcw(), 0x037f);
+    assert_eq!(state.mxcsr(), 0x1f80);
+    // fxrstor faults on misaligned images
+    assert_eq!(core::mem::align_of::<FpuState>(), 16);
+    // the live state after init is what fninit would give
+    let mut live = FpuState([0; 512]);
+    unsafe { core::arch::asm!("fxsave64 [{}]", in(reg) &mut live, options(nostack, preserves_flags)) };
+    assert_eq!((live.fcw(), live.mxcsr()), (0x037f, 0x1f80));
+}
+
+#[test_case]
+fn test_idle_stats_between_samples() {
+    let old = IdleSample { tsc: 1_000, ticks: 10, halted: 200, wakeups: 5 };
+    // 2 s of ticks, 3/4 of the cycles halted, 36 wakeups
+    let new = IdleSample { tsc: 5_000, ticks: 10 + time::secs_to_ticks(2), halted: 3_200, wakeups: 41 };
+    let stats = IdleStats::between(old, new);
+    assert_eq!(stats.halted_pct_recent, 75);
+    assert_eq!(stats.wakeups_per_sec, 18);
+    assert_eq!(stats.total_halted, 3_200);
+    // nothing elapsed
+    assert_eq!(IdleStats::between(old, old).halted_pct_recent, 0);
+    assert_eq!(IdleStats::between(old, old).wakeups_per_sec, 0);
+}
+
+#[test_case]
+fn test_idle_stats_across_wrap() {
+    let old = IdleSample { tsc: u64::MAX - 99, ticks: u64::MAX, halted: u64::MAX - 9, wakeups: u64::MAX };
+    let new = IdleSample { tsc: 100, ticks: 35, halted: 40, wakeups: 35 };
+    let stats = IdleStats::between(old, new);
+    assert_eq!(stats.halted_pct_recent, 25);
+    // a wakeup every tick
+    assert_eq!(stats.wakeups_per_sec, time::secs_to_ticks(1));
+    // a halted count ahead of the clock is capped at 100%
+    let skewed = IdleSample { halted: new.halted + 1_000, ..new };
+    assert_eq!(IdleStats::between(old, skewed).halted_pct_recent, 100);
+}
+
+#[test_case]
+fn test_idle_window_slides() {
+    let sample = |i: u64| IdleSample { tsc: i * 100, ticks: i * 9, halted: i * 50, wakeups: i };
+    let mut window = IdleWindow::new();
+    assert_eq!(window.oldest(), None);
+    assert_eq!(window.stats(sample(2)).halted_pct_recent, 50);
+    window.push(sample(1));
+    window.push(sample(2));
+    assert_eq!(window.oldest(), Some(sample(1)));
+    for i in 3..=6 {
+        window.push(sample(i));
+    }
+    // the last IDLE_WINDOW samples remain
+    assert_eq!(window.oldest(), Some(sample(7 - IDLE_WINDOW as u64)));
+    let now = IdleSample { tsc: 800, ticks: 72, halted: 300, wakeups: 8 };
+    // from sample 3: 500 cycles, 150 halted
+    assert_eq!(window.stats(now).halted_pct_recent, 30);
+}
+
+#[test_case]
+fn test_cpuid_decoding() {
+    let regs = |eax, ebx, ecx, edx| CpuidResult { eax, ebx, ecx, edx };
+    // a Skylake-ish CPU without leaves 0x15/0x16 or an extended leaf 7
+    let info = CpuInfo::from_leaves(|leaf| match leaf {
+        0 => regs(7, u32::from_le_bytes(*b"Genu"), u32::from_le_bytes(*b"ntel"), u32::from_le_bytes(*b"ineI")),
+        1 => regs(0x0005_06e3, 0, (1 << 30) | (1 << 26) | 1, (1 << 26) | (1 << 25)),
+        7 => regs(0, (1 << 18) | (1 << 5), 0, 0),
+        0x8000_0000 => regs(0x8000_0004, 0, 0, 0),
+        0x8000_0001 => regs(0, 0, 0, 1 << 20),
+        0x8000_0002 => regs(u32::from_le_bytes(*b"  Fa"), u32::from_le_bytes(*b"ke C"), u32::from_le_bytes(*b"PU\0\0"), 0),
+        // out of range: never asked for
+        0x8000_0007 | 0x15 | 0x16 => regs(!0, !0, !0, !0),
+        _ => regs(0, 0, 0, 0),
+    });
+    assert_eq!(info.vendor(), "GenuineIntel");
+    assert_eq!(info.brand(), Some("Fake CPU"));
+    assert_eq!((info.family, info.model, info.stepping), (6, 0x5e, 3));
+    let features: alloc::vec::Vec<_> = info.features().collect();
+    assert_eq!(features, [Feature::Sse, Feature::Sse2, Feature::Sse3, Feature::Xsave, Feature::Avx2, Feature::Rdrand, Feature::Rdseed, Feature::Nx]);
+    assert!(!info.has(Feature::InvariantTsc));
+    assert_eq!(info.tsc_hz, None);
+
+    let info = CpuInfo::from_leaves(|leaf| match leaf {
+        0 => regs(0x16, 0, 0, 0),
+        0x15 => regs(2, 200, 24_000_000, 0),
+        _ => regs(0, 0, 0, 0),
+    });
+    assert_eq!((info.brand(), info.tsc_hz), (None, Some(2_400_000_000)));
+    // no crystal frequency in 0x15: the base frequency from 0x16
+    let info = CpuInfo::from_leaves(|leaf| match leaf {
+        0 => regs(0x16, 0, 0, 0),
+        0x15 => regs(2, 200, 0, 0),
+        0x16 => regs(3100, 0, 0, 0),
+        _ => regs(0, 0, 0, 0),
+    });
+    assert_eq!(info.tsc_hz, Some(3_100_000_000));
+}
+
+#[test_case]
+fn test_boot_cpu_features() {
+    // what x86_64 and this kernel can't do without
+    for feature in [Feature::Fpu, Feature::Tsc, Feature::Fxsr, Feature::Sse, Feature::Sse2, Feature::Syscall, Feature::Nx] {
+        assert!(has(feature), "{}", feature);
+    }
+    assert_eq!(has(Feature::Rdrand), core::arch::x86_64::__cpuid(1).ecx & (1 << 30) != 0);
+    assert!(info().tsc_hz().is_some());
+}
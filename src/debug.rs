@@ -0,0 +1,279 @@
+//! Post-mortem helpers.
+//!
+//! Backtraces follow the saved-RBP chain, so they need the kernel built with
+//! frame pointers (`"frame-pointer": "always"` in the target spec). Every
+//! frame pointer is checked before it is read, so a corrupted chain ends the
+//! trace instead of faulting inside the panic handler, and the trace says
+//! so with `<invalid frame>`.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::VirtAddr;
+use crate::{memory, symbols};
+
+pub const MAX_FRAMES: usize = 32;
+
+/// A frame bigger than this means we are reading garbage.
+const MAX_FRAME_SIZE: u64 = 1 << 20;
+
+extern "C" {
+    // Provided by the linker.
+    static __ehdr_start: u8;
+    static etext: u8;
+}
+
+/// Start and end of the kernel image's code.
+pub fn kernel_text() -> core::ops::Range<u64> {
+    (&raw const __ehdr_start) as u64..(&raw const etext) as u64
+}
+
+fn frame_is_readable(rbp: u64) -> bool {
+    rbp != 0
+        && rbp.is_multiple_of(8)
+        && rbp.checked_add(16).is_some()
+        && VirtAddr::try_new(rbp).is_ok()
+        && VirtAddr::try_new(rbp + 15).is_ok()
+        && memory::is_mapped(VirtAddr::new(rbp))
+        && memory::is_mapped(VirtAddr::new(rbp + 15))
+}
+
+/// Why a walk ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// A null frame pointer, or a return address outside the kernel: the
+    /// bottom of the stack.
+    End,
+    /// There was no room for more frames.
+    Full,
+    /// A frame pointer that is not mapped, not aligned, or points down the
+    /// stack or too far up it.
+    Invalid,
+}
+
+/// Walks the frame-pointer chain starting at `rbp`, storing return
+/// addresses into `frames`. Returns how many were stored.
+///
+/// # Safety
+/// `rbp` must be a frame pointer of the current stack (or zero).
+pub unsafe fn walk(rbp: u64, frames: &mut [u64]) -> usize {
+    unsafe { walk_until(rbp, frames) }.0
+}
+
+/// `walk`, also saying why it stopped.
+///
+/// # Safety
+/// As for `walk`.
+pub unsafe fn walk_until(mut rbp: u64, frames: &mut [u64]) -> (usize, Stop) {
+    let text = kernel_text();
+    let mut count = 0;
+    loop {
+        if count == frames.len() {
+            return (count, Stop::Full);
+        }
+        if rbp == 0 {
+            return (count, Stop::End);
+        }
+        if !frame_is_readable(rbp) {
+            return (count, Stop::Invalid);
+        }
+        let saved_rbp = unsafe { (rbp as *const u64).read() };
+        let return_address = unsafe { ((rbp + 8) as *const u64).read() };
+        if !text.contains(&return_address) {
+            return (count, Stop::End);
+        }
+        frames[count] = return_address;
+        count += 1;
+        // the caller's frame is always higher up the stack
+        if saved_rbp != 0 && (saved_rbp <= rbp || saved_rbp - rbp > MAX_FRAME_SIZE) {
+            return (count, Stop::Invalid);
+        }
+        rbp = saved_rbp;
+    }
+}
+
+fn current_rbp() -> u64 {
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// Return addresses of the current call chain, innermost (our caller)
+/// first.
+#[inline(never)]
+pub fn capture(frames: &mut [u64]) -> usize {
+    unsafe { walk(current_rbp(), frames) }
+}
+
+pub fn write_frames(out: &mut dyn fmt::Write, frames: &[u64], stop: Stop) -> fmt::Result {
+    writeln!(out, "Backtrace:")?;
+    for (i, address) in frames.iter().enumerate() {
+        write!(out, "  #{:<2} {:#018x}", i, address)?;
+        if let Some((name, offset)) = symbols::resolve(*address) {
+            write!(out, "  {}+{:#x}", symbols::Demangled(name), offset)?;
+        }
+        writeln!(out)?;
+    }
+    match stop {
+        Stop::Invalid => writeln!(out, "  <invalid frame>"),
+        _ if frames.is_empty() => writeln!(out, "  <no frames>"),
+        _ => Ok(()),
+    }
+}
+
+/// Writes the return addresses of the current call chain to `out`.
+#[inline(never)]
+pub fn write_backtrace(out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut frames = [0; MAX_FRAMES];
+    let (count, stop) = unsafe { walk_until(current_rbp(), &mut frames) };
+    write_frames(out, &frames[..count], stop)
+}
+
+/// Set while the page fault handler runs, which never returns to the
+/// kernel code that faulted.
+static IN_PAGE_FAULT: AtomicBool = AtomicBool::new(false);
+
+/// Called by the page fault handler once it knows the fault is the
+/// kernel's, so a panic from there reports CR2.
+pub fn enter_page_fault() {
+    IN_PAGE_FAULT.store(true, Ordering::Relaxed);
+}
+
+/// What a panic report shows besides the backtrace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub rsp: u64,
+    pub rbp: u64,
+    /// Privilege level, from CS.
+    pub cpl: u8,
+    /// The faulting address, if it happened in the page fault handler.
+    pub cr2: Option<u64>,
+}
+
+impl Registers {
+    /// The registers of the function this is inlined into.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let (rsp, rbp): (u64, u64);
+        unsafe {
+            core::arch::asm!("mov {}, rsp", "mov {}, rbp", out(reg) rsp, out(reg) rbp,
+                options(nomem, nostack, preserves_flags));
+        }
+        let cs = <x86_64::instructions::segmentation::CS as x86_64::instructions::segmentation::Segment>::get_reg();
+        let cr2 = IN_PAGE_FAULT.load(Ordering::Relaxed)
+            .then(x86_64::registers::control::Cr2::read_raw);
+        Registers { rsp, rbp, cpl: cs.rpl() as u8, cr2 }
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RSP={:#018x} RBP={:#018x} CPL={}", self.rsp, self.rbp, self.cpl)?;
+        if let Some(cr2) = self.cr2 {
+            write!(f, " CR2={:#018x} (in the page fault handler)", cr2)?;
+        }
+        Ok(())
+    }
+}
+
+struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::print!("{}", s);
+        Ok(())
+    }
+}
+
+/// Prints the current call chain on the console.
+pub fn backtrace() {
+    let _ = write_backtrace(&mut Console);
+}
+
+//test case
+#[cfg(test)]
+#[inline(never)]
+fn nested_1(frames: &mut [u64]) -> usize {
+    core::hint::black_box(nested_2(frames))
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn nested_2(frames: &mut [u64]) -> usize {
+    core::hint::black_box(nested_3(frames))
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn nested_3(frames: &mut [u64]) -> usize {
+    core::hint::black_box(capture(frames))
+}
+
+#[test_case]
+fn test_backtrace_nested() {
+    let mut frames = [0; MAX_FRAMES];
+    let count = nested_1(&mut frames);
+    // nested_3, nested_2, nested_1, this test, then the runner
+    assert!(count >= 4, "only {} frames", count);
+    let text = kernel_text();
+    for address in &frames[..count] {
+        assert!(text.contains(address), "{:#x} outside the kernel", address);
+    }
+    let mut shallow = [0; MAX_FRAMES];
+    assert_eq!(capture(&mut shallow) + 3, count);
+    // everything above this test's own frame is the same
+    assert_eq!(&frames[4..count], &shallow[1..count - 3]);
+}
+
+#[test_case]
+fn test_backtrace_stops_on_bad_frames() {
+    let mut frames = [0; 4];
+    assert_eq!(unsafe { walk(0, &mut frames) }, 0);
+    assert_eq!(unsafe { walk(0x1001, &mut frames) }, 0);
+    // non-canonical
+    assert_eq!(unsafe { walk(0x8000_0000_0000_0000, &mut frames) }, 0);
+    // a frame whose return address is not kernel code
+    let fake = [0u64, 0x1234];
+    assert_eq!(unsafe { walk(fake.as_ptr() as u64, &mut frames) }, 0);
+}
+
+#[test_case]
+fn test_backtrace_stop_reasons() {
+    let code = nested_3 as *const () as u64;
+    let mut frames = [0; 4];
+    // the last frame of a chain
+    let last = [0u64, code];
+    assert_eq!(unsafe { walk_until(last.as_ptr() as u64, &mut frames) }, (1, Stop::End));
+    // a saved frame pointer that points down the stack, or far up it
+    let backwards = [8u64, code];
+    assert_eq!(unsafe { walk_until(backwards.as_ptr() as u64, &mut frames) }, (1, Stop::Invalid));
+    let corrupt = [u64::MAX & !7, code];
+    assert_eq!(unsafe { walk_until(corrupt.as_ptr() as u64, &mut frames) }, (1, Stop::Invalid));
+    // one that can't be read
+    assert_eq!(unsafe { walk_until(0x_7777_0000_0000, &mut frames) }, (0, Stop::Invalid));
+    assert_eq!(unsafe { walk_until(backwards.as_ptr() as u64 + 1, &mut frames) }, (0, Stop::Invalid));
+    assert_eq!(unsafe { walk_until(0, &mut frames) }, (0, Stop::End));
+    assert_eq!(unsafe { walk_until(current_rbp(), &mut frames[..1]) }, (1, Stop::Full));
+
+    let mut out = alloc::string::String::new();
+    write_frames(&mut out, &[], Stop::Invalid).unwrap();
+    assert_eq!(out, "Backtrace:\n  <invalid frame>\n");
+    out.clear();
+    write_frames(&mut out, &[], Stop::End).unwrap();
+    assert_eq!(out, "Backtrace:\n  <no frames>\n");
+}
+
+#[test_case]
+fn test_registers() {
+    let registers = Registers::capture();
+    assert_eq!(registers.cpl, 0);
+    assert!(registers.rsp <= registers.rbp && registers.rbp - registers.rsp < MAX_FRAME_SIZE);
+    assert_eq!(registers.cr2, None);
+    let shown = alloc::format!("{}", Registers { rsp: 0x10, rbp: 0x20, cpl: 3, cr2: Some(0xdead) });
+    assert_eq!(shown, "RSP=0x0000000000000010 RBP=0x0000000000000020 CPL=3 CR2=0x000000000000dead (in the page fault handler)");
+}
+
+#[test_case]
+fn test_backtrace_limit() {
+    let mut frames = [0; 2];
+    assert_eq!(nested_1(&mut frames), 2);
+}
@@ -0,0 +1,443 @@
+//! Intel 8254x (e1000) Gigabit Ethernet driver: QEMU's `-device e1000`
+//! (82540EM) and the 82545EM and 82574L found on real boards, all of which
+//! speak the legacy descriptor format.
+//!
+//! The registers sit in memory BAR 0. Receive and transmit each have a ring
+//! of 16-byte descriptors and 2 KiB buffers in DMA memory: the card fills
+//! receive buffers from the head while the driver hands them back at the
+//! tail, and sends what the driver queues up to the transmit tail. As with
+//! the RTL8139, the interrupt handler only acknowledges the card and notes
+//! that frames arrived; `receive()` takes them off the ring in normal
+//! context.
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::interrupts;
+use crate::memory::{BootInfoFrameAllocator, DmaRegion};
+use crate::pci::{self, Bar};
+use crate::rtl8139::{NicStats, TxError};
+
+pub const VENDOR_ID: u16 = 0x8086;
+/// 82540EM, 82545EM (copper) and 82574L.
+pub const DEVICE_IDS: [u16; 3] = [0x100e, 0x100f, 0x10d3];
+
+const REG_CTRL: u64 = 0x0000;
+const REG_EERD: u64 = 0x0014;
+const REG_ICR: u64 = 0x00c0;
+const REG_IMS: u64 = 0x00d0;
+const REG_IMC: u64 = 0x00d8;
+const REG_RCTL: u64 = 0x0100;
+const REG_TCTL: u64 = 0x0400;
+const REG_TIPG: u64 = 0x0410;
+const REG_RDBAL: u64 = 0x2800;
+const REG_RDBAH: u64 = 0x2804;
+const REG_RDLEN: u64 = 0x2808;
+const REG_RDH: u64 = 0x2810;
+const REG_RDT: u64 = 0x2818;
+const REG_TDBAL: u64 = 0x3800;
+const REG_TDBAH: u64 = 0x3804;
+const REG_TDLEN: u64 = 0x3808;
+const REG_TDH: u64 = 0x3810;
+const REG_TDT: u64 = 0x3818;
+const REG_MTA: u64 = 0x5200;
+const REG_RAL0: u64 = 0x5400;
+const REG_RAH0: u64 = 0x5404;
+/// Pages of BAR 0 that hold the registers above.
+const REGISTER_PAGES: u64 = 6;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+/// Buffer size bits left at zero: 2048 bytes.
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+/// IPGT 10, IPGR1 8, IPGR2 6: the manual's values for copper.
+const TIPG_COPPER: u32 = 10 | 8 << 10 | 6 << 20;
+
+const INT_LSC: u32 = 1 << 2;
+const INT_RXDMT0: u32 = 1 << 4;
+const INT_RXO: u32 = 1 << 6;
+const INT_RXT0: u32 = 1 << 7;
+const INT_TXDW: u32 = 1 << 0;
+
+const RAH_VALID: u32 = 1 << 31;
+
+const STATUS_DD: u8 = 1 << 0;
+const STATUS_EOP: u8 = 1 << 1;
+const CMD_EOP: u8 = 1 << 0;
+const CMD_IFCS: u8 = 1 << 1;
+const CMD_RS: u8 = 1 << 3;
+
+pub const RX_DESCRIPTORS: usize = 32;
+pub const TX_DESCRIPTORS: usize = 8;
+const BUFFER_LEN: usize = 2048;
+pub const MAX_FRAME_LEN: usize = 1514;
+const RESET_SPINS: usize = 1_000_000;
+
+/// A legacy receive descriptor, as the card writes it back.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct RxDescriptor {
+    pub addr: u64,
+    pub length: u16,
+    pub checksum: u16,
+    pub status: u8,
+    pub errors: u8,
+    pub special: u16,
+}
+
+/// A legacy transmit descriptor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct TxDescriptor {
+    pub addr: u64,
+    pub length: u16,
+    pub cso: u8,
+    pub cmd: u8,
+    pub status: u8,
+    pub css: u8,
+    pub special: u16,
+}
+
+impl TxDescriptor {
+    /// One whole frame, with the CRC added by the card and a status report
+    /// asked for, so `status` tells when the slot is free again.
+    pub fn frame(addr: u64, len: usize) -> TxDescriptor {
+        TxDescriptor { addr, length: len as u16, cmd: CMD_EOP | CMD_IFCS | CMD_RS, ..TxDescriptor::default() }
+    }
+
+    pub fn done(&self) -> bool {
+        self.status & STATUS_DD != 0
+    }
+}
+
+/// What a written-back receive descriptor holds: `None` while the card
+/// still owns it, `Some(Err(()))` for a frame to drop (an error, or one
+/// spread over several buffers, which 2 KiB buffers never need).
+pub fn rx_status(desc: &RxDescriptor) -> Option<Result<usize, ()>> {
+    if desc.status & STATUS_DD == 0 {
+        return None;
+    }
+    if desc.status & STATUS_EOP == 0 || desc.errors != 0 {
+        return Some(Err(()));
+    }
+    Some(Ok(usize::from(desc.length)))
+}
+
+/// The MAC address in receive address 0, if the EEPROM loaded one.
+pub fn mac_from_receive_address(ral: u32, rah: u32) -> Option<[u8; 6]> {
+    if rah & RAH_VALID == 0 {
+        return None;
+    }
+    let [a, b, c, d] = ral.to_le_bytes();
+    let [e, f, _, _] = rah.to_le_bytes();
+    Some([a, b, c, d, e, f])
+}
+
+pub struct E1000 {
+    base: VirtAddr,
+    mac: [u8; 6],
+    rx_ring: DmaRegion,
+    rx_buffers: DmaRegion,
+    rx_next: usize,
+    tx_ring: DmaRegion,
+    tx_buffers: DmaRegion,
+    tx_next: usize,
+    stats: NicStats,
+}
+
+static BASE: AtomicU64 = AtomicU64::new(0);
+static RX_PENDING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static TX_OK: AtomicU64 = AtomicU64::new(0);
+
+fn read_register(base: VirtAddr, reg: u64) -> u32 {
+    unsafe { read_volatile((base + reg).as_ptr::<u32>()) }
+}
+
+fn write_register(base: VirtAddr, reg: u64, value: u32) {
+    unsafe { write_volatile((base + reg).as_mut_ptr::<u32>(), value) }
+}
+
+/// Reading ICR acknowledges everything it reports.
+fn interrupt_handler() {
+    let base = VirtAddr::new(BASE.load(Ordering::Relaxed));
+    let cause = read_register(base, REG_ICR);
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    if cause & (INT_RXT0 | INT_RXO | INT_RXDMT0) != 0 {
+        RX_PENDING.store(true, Ordering::Release);
+    }
+    if cause & INT_TXDW != 0 {
+        TX_OK.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl E1000 {
+    fn new(
+        base: VirtAddr,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        physical_memory_offset: VirtAddr,
+    ) -> Option<E1000> {
+        let allocate = |frame_allocator: &mut BootInfoFrameAllocator, bytes: usize| {
+            DmaRegion::allocate(frame_allocator, physical_memory_offset, bytes.div_ceil(4096))
+        };
+        let mut nic = E1000 {
+            base,
+            mac: [0; 6],
+            rx_ring: allocate(frame_allocator, RX_DESCRIPTORS * 16)?,
+            rx_buffers: allocate(frame_allocator, RX_DESCRIPTORS * BUFFER_LEN)?,
+            rx_next: 0,
+            tx_ring: allocate(frame_allocator, TX_DESCRIPTORS * 16)?,
+            tx_buffers: allocate(frame_allocator, TX_DESCRIPTORS * BUFFER_LEN)?,
+            tx_next: 0,
+            stats: NicStats::default(),
+        };
+        nic.reset()?;
+        Some(nic)
+    }
+
+    fn read(&self, reg: u64) -> u32 {
+        read_register(self.base, reg)
+    }
+
+    fn write(&self, reg: u64, value: u32) {
+        write_register(self.base, reg, value)
+    }
+
+    /// Word `address` of the EEPROM, through EERD.
+    fn read_eeprom(&self, address: u8) -> Option<u16> {
+        self.write(REG_EERD, u32::from(address) << 8 | EERD_START);
+        for _ in 0..RESET_SPINS {
+            let value = self.read(REG_EERD);
+            if value & EERD_DONE != 0 {
+                return Some((value >> 16) as u16);
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
+
+    fn reset(&mut self) -> Option<()> {
+        self.write(REG_IMC, u32::MAX);
+        self.write(REG_CTRL, self.read(REG_CTRL) | CTRL_RST);
+        let mut spins = 0;
+        while self.read(REG_CTRL) & CTRL_RST != 0 {
+            spins += 1;
+            if spins > RESET_SPINS {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+        // the reset unmasks nothing, but may leave causes behind
+        self.write(REG_IMC, u32::MAX);
+        self.read(REG_ICR);
+        self.write(REG_CTRL, self.read(REG_CTRL) | CTRL_SLU | CTRL_ASDE);
+
+        self.mac = match mac_from_receive_address(self.read(REG_RAL0), self.read(REG_RAH0)) {
+            Some(mac) => mac,
+            None => {
+                let mut mac = [0; 6];
+                for (word, pair) in mac.chunks_mut(2).enumerate() {
+                    pair.copy_from_slice(&self.read_eeprom(word as u8)?.to_le_bytes());
+                }
+                let ral = u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]);
+                self.write(REG_RAL0, ral);
+                self.write(REG_RAH0, u32::from(u16::from_le_bytes([mac[4], mac[5]])) | RAH_VALID);
+                mac
+            }
+        };
+        for i in 0..128 {
+            self.write(REG_MTA + 4 * i, 0);
+        }
+
+        for i in 0..RX_DESCRIPTORS {
+            let addr = self.rx_buffers.phys_addr().as_u64() + (i * BUFFER_LEN) as u64;
+            self.set_rx_descriptor(i, RxDescriptor { addr, ..RxDescriptor::default() });
+        }
+        let rx_ring = self.rx_ring.phys_addr().as_u64();
+        self.write(REG_RDBAL, rx_ring as u32);
+        self.write(REG_RDBAH, (rx_ring >> 32) as u32);
+        self.write(REG_RDLEN, (RX_DESCRIPTORS * 16) as u32);
+        self.write(REG_RDH, 0);
+        // one descriptor stays with the driver: head == tail means empty
+        self.write(REG_RDT, (RX_DESCRIPTORS - 1) as u32);
+        self.rx_next = 0;
+        self.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        for i in 0..TX_DESCRIPTORS {
+            // free until used
+            self.set_tx_descriptor(i, TxDescriptor { status: STATUS_DD, ..TxDescriptor::default() });
+        }
+        let tx_ring = self.tx_ring.phys_addr().as_u64();
+        self.write(REG_TDBAL, tx_ring as u32);
+        self.write(REG_TDBAH, (tx_ring >> 32) as u32);
+        self.write(REG_TDLEN, (TX_DESCRIPTORS * 16) as u32);
+        self.write(REG_TDH, 0);
+        self.write(REG_TDT, 0);
+        self.tx_next = 0;
+        self.write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+        self.write(REG_TIPG, TIPG_COPPER);
+
+        self.write(REG_IMS, INT_RXT0 | INT_RXO | INT_RXDMT0 | INT_LSC | INT_TXDW);
+        Some(())
+    }
+
+    fn rx_descriptor(&self, index: usize) -> RxDescriptor {
+        unsafe { read_volatile((self.rx_ring.as_mut_ptr() as *const RxDescriptor).add(index)) }
+    }
+
+    fn set_rx_descriptor(&mut self, index: usize, desc: RxDescriptor) {
+        unsafe { write_volatile((self.rx_ring.as_mut_ptr() as *mut RxDescriptor).add(index), desc) }
+    }
+
+    fn tx_descriptor(&self, index: usize) -> TxDescriptor {
+        unsafe { read_volatile((self.tx_ring.as_mut_ptr() as *const TxDescriptor).add(index)) }
+    }
+
+    fn set_tx_descriptor(&mut self, index: usize, desc: TxDescriptor) {
+        unsafe { write_volatile((self.tx_ring.as_mut_ptr() as *mut TxDescriptor).add(index), desc) }
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    pub fn stats(&self) -> NicStats {
+        NicStats {
+            tx_packets: TX_OK.load(Ordering::Relaxed),
+            interrupts: INTERRUPTS.load(Ordering::Relaxed),
+            ..self.stats
+        }
+    }
+
+    /// Queues `frame` in the next transmit slot. The card pads short
+    /// frames.
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), TxError> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(TxError::TooLarge);
+        }
+        let slot = self.tx_next;
+        if !self.tx_descriptor(slot).done() {
+            return Err(TxError::Busy);
+        }
+        let buffer = unsafe { self.tx_buffers.as_mut_ptr().add(slot * BUFFER_LEN) };
+        unsafe { core::ptr::copy_nonoverlapping(frame.as_ptr(), buffer, frame.len()) };
+        let addr = self.tx_buffers.phys_addr().as_u64() + (slot * BUFFER_LEN) as u64;
+        self.set_tx_descriptor(slot, TxDescriptor::frame(addr, frame.len()));
+        self.tx_next = (slot + 1) % TX_DESCRIPTORS;
+        // moving the tail hands the slot to the card
+        self.write(REG_TDT, self.tx_next as u32);
+        Ok(())
+    }
+
+    /// Next received frame (destination MAC first, CRC stripped).
+    pub fn receive(&mut self) -> Option<Vec<u8>> {
+        RX_PENDING.store(false, Ordering::Release);
+        loop {
+            let index = self.rx_next;
+            let desc = self.rx_descriptor(index);
+            let status = rx_status(&desc)?;
+            let frame = status.ok().map(|len| {
+                let buffer = unsafe { self.rx_buffers.as_mut_ptr().add(index * BUFFER_LEN) };
+                unsafe { core::slice::from_raw_parts(buffer, len.min(BUFFER_LEN)) }.to_vec()
+            });
+            self.set_rx_descriptor(index, RxDescriptor { addr: desc.addr, ..RxDescriptor::default() });
+            self.rx_next = (index + 1) % RX_DESCRIPTORS;
+            // the descriptor just emptied goes back to the card
+            self.write(REG_RDT, index as u32);
+            match frame {
+                Some(frame) => {
+                    self.stats.rx_packets += 1;
+                    return Some(frame);
+                }
+                None => self.stats.rx_errors += 1,
+            }
+        }
+    }
+}
+
+pub fn rx_pending() -> bool {
+    RX_PENDING.load(Ordering::Acquire)
+}
+
+pub static NIC: Mutex<Option<E1000>> = Mutex::new(None);
+
+/// Finds the first supported card, maps its registers, resets it and
+/// hooks its IRQ. Returns the MAC address.
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    physical_memory_offset: VirtAddr,
+) -> Option<[u8; 6]> {
+    let device = DEVICE_IDS.iter().find_map(|&id| pci::find(VENDOR_ID, id))?;
+    let bar = match device.bar(0)? {
+        Bar::Memory { address, .. } => address,
+        Bar::Io(_) => return None,
+    };
+    let mut base = None;
+    for page in 0..REGISTER_PAGES {
+        let virt = crate::memory::map_mmio(PhysAddr::new(bar + page * 4096), mapper, frame_allocator).ok()?;
+        base.get_or_insert(virt);
+    }
+    device.enable_bus_mastering();
+    let base = base?;
+    let nic = E1000::new(base, frame_allocator, physical_memory_offset)?;
+    let mac = nic.mac();
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+    *NIC.lock() = Some(nic);
+    if device.interrupt_line < 16 {
+        interrupts::register_irq(device.interrupt_line, interrupt_handler);
+    }
+    Some(mac)
+}
+
+/// Sends through the global NIC.
+pub fn send(frame: &[u8]) -> Result<(), TxError> {
+    NIC.lock().as_mut().ok_or(TxError::NoDevice)?.send(frame)
+}
+
+//test case
+#[test_case]
+fn test_descriptor_layout() {
+    assert_eq!(core::mem::size_of::<RxDescriptor>(), 16);
+    assert_eq!(core::mem::size_of::<TxDescriptor>(), 16);
+    assert_eq!(core::mem::offset_of!(RxDescriptor, status), 12);
+    assert_eq!(core::mem::offset_of!(TxDescriptor, cmd), 11);
+    let desc = TxDescriptor::frame(0x1000, 60);
+    assert_eq!((desc.length, desc.cmd), (60, CMD_EOP | CMD_IFCS | CMD_RS));
+    assert!(!desc.done());
+}
+
+#[test_case]
+fn test_rx_status() {
+    let mut desc = RxDescriptor { addr: 0x2000, length: 64, ..RxDescriptor::default() };
+    assert_eq!(rx_status(&desc), None);
+    desc.status = STATUS_DD | STATUS_EOP;
+    assert_eq!(rx_status(&desc), Some(Ok(64)));
+    desc.errors = 1;
+    assert_eq!(rx_status(&desc), Some(Err(())));
+    desc.errors = 0;
+    desc.status = STATUS_DD;
+    assert_eq!(rx_status(&desc), Some(Err(())));
+}
+
+#[test_case]
+fn test_mac_from_receive_address() {
+    // QEMU's default 52:54:00:12:34:56
+    assert_eq!(mac_from_receive_address(0x1200_5452, 0x8000_5634), Some([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]));
+    assert_eq!(mac_from_receive_address(0x1200_5452, 0x5634), None);
+}
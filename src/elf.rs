@@ -0,0 +1,388 @@
+//! ELF64 executable loader.
+//!
+//! `parse` validates the header and every program header up front, so a
+//! malformed file is rejected with an `ElfError` before anything is mapped.
+//! `load` then maps each `PT_LOAD` segment with page flags taken from
+//! `p_flags`. `ET_DYN` files are placed at a caller-chosen base; their
+//! relocations are not processed, so they must be position-independent
+//! without needing any.
+
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::bytes::{le_u16, le_u32, le_u64};
+use crate::loader::{self, EntryPoint, LoadError};
+
+#[cfg(test)]
+mod testdata;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const MACHINE_X86_64: u16 = 0x3e;
+const TYPE_EXEC: u16 = 2;
+const TYPE_DYN: u16 = 3;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const PAGE_SIZE: u64 = 4096;
+/// Segments must stay below the canonical address hole.
+const USER_LIMIT: u64 = 0x0000_8000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    /// Not a 64-bit little-endian file.
+    UnsupportedClass,
+    WrongMachine(u16),
+    UnsupportedType(u16),
+    BadProgramHeaderSize(u16),
+    /// A segment's file bytes reach past the end of the file.
+    SegmentOutOfFile(usize),
+    /// `p_memsz` is smaller than `p_filesz`.
+    BadMemorySize(usize),
+    /// `p_vaddr` and `p_offset` disagree modulo the page size.
+    Misaligned(usize),
+    /// The segment wraps around or leaves the lower half.
+    BadAddress(usize),
+    /// Two segments share a page.
+    Overlap(usize, usize),
+    /// The segment is both writable and executable.
+    WritableExecutable(usize),
+    NoLoadableSegments,
+    /// `e_entry` is not inside an executable segment.
+    BadEntry(u64),
+    Load(LoadError),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "file is truncated"),
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::UnsupportedClass => write!(f, "not a 64-bit little-endian ELF"),
+            ElfError::WrongMachine(machine) => write!(f, "machine {:#x} is not x86_64", machine),
+            ElfError::UnsupportedType(kind) => write!(f, "type {} is not EXEC or DYN", kind),
+            ElfError::BadProgramHeaderSize(size) => write!(f, "program header size {}", size),
+            ElfError::SegmentOutOfFile(i) => write!(f, "segment {} reaches past the end of the file", i),
+            ElfError::BadMemorySize(i) => write!(f, "segment {} is smaller in memory than in the file", i),
+            ElfError::Misaligned(i) => write!(f, "segment {} address and offset are not congruent", i),
+            ElfError::BadAddress(i) => write!(f, "segment {} has an invalid address", i),
+            ElfError::Overlap(a, b) => write!(f, "segments {} and {} overlap", a, b),
+            ElfError::WritableExecutable(i) => write!(f, "segment {} is writable and executable", i),
+            ElfError::NoLoadableSegments => write!(f, "no loadable segments"),
+            ElfError::BadEntry(entry) => write!(f, "entry point {:#x} is not in an executable segment", entry),
+            ElfError::Load(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Exec,
+    Dyn,
+}
+
+/// A `PT_LOAD` program header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub offset: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub flags: u32,
+}
+
+impl Segment {
+    fn parse(header: &[u8]) -> Option<(u32, Segment)> {
+        let kind = le_u32(header, 0)?;
+        let segment = Segment {
+            flags: le_u32(header, 4)?,
+            offset: le_u64(header, 8)?,
+            vaddr: le_u64(header, 16)?,
+            file_size: le_u64(header, 32)?,
+            mem_size: le_u64(header, 40)?,
+        };
+        Some((kind, segment))
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+
+    fn first_page(&self) -> u64 {
+        self.vaddr / PAGE_SIZE
+    }
+
+    /// Last page touched; only valid for segments with a non-zero size.
+    fn last_page(&self) -> u64 {
+        (self.vaddr + self.mem_size - 1) / PAGE_SIZE
+    }
+}
+
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    pub kind: Kind,
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+}
+
+/// Validates `data` as an x86_64 executable. Writable+executable segments
+/// are refused unless `allow_wx` is set.
+pub fn parse(data: &[u8], allow_wx: bool) -> Result<ElfFile<'_>, ElfError> {
+    if data.len() < HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if data[..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    if data[4] != CLASS_64 || data[5] != DATA_LITTLE_ENDIAN {
+        return Err(ElfError::UnsupportedClass);
+    }
+    let truncated = ElfError::Truncated;
+    let kind = match le_u16(data, 16).ok_or(truncated)? {
+        TYPE_EXEC => Kind::Exec,
+        TYPE_DYN => Kind::Dyn,
+        other => return Err(ElfError::UnsupportedType(other)),
+    };
+    let machine = le_u16(data, 18).ok_or(truncated)?;
+    if machine != MACHINE_X86_64 {
+        return Err(ElfError::WrongMachine(machine));
+    }
+    let entry = le_u64(data, 24).ok_or(truncated)?;
+    let ph_offset = le_u64(data, 32).ok_or(truncated)?;
+    let ph_size = le_u16(data, 54).ok_or(truncated)?;
+    let ph_count = le_u16(data, 56).ok_or(truncated)?;
+    if ph_count > 0 && usize::from(ph_size) != PROGRAM_HEADER_SIZE {
+        return Err(ElfError::BadProgramHeaderSize(ph_size));
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..usize::from(ph_count) {
+        let start = usize::try_from(ph_offset)
+            .ok()
+            .and_then(|offset| offset.checked_add(i * PROGRAM_HEADER_SIZE))
+            .ok_or(truncated)?;
+        let header = data.get(start..start + PROGRAM_HEADER_SIZE).ok_or(truncated)?;
+        let (kind, segment) = Segment::parse(header).ok_or(truncated)?;
+        if kind != PT_LOAD || segment.mem_size == 0 {
+            continue;
+        }
+        check_segment(data.len(), i, &segment, allow_wx)?;
+        segments.push((i, segment));
+    }
+    if segments.is_empty() {
+        return Err(ElfError::NoLoadableSegments);
+    }
+    for (a, (index_a, first)) in segments.iter().enumerate() {
+        for (index_b, second) in &segments[a + 1..] {
+            if first.first_page() <= second.last_page() && second.first_page() <= first.last_page() {
+                return Err(ElfError::Overlap(*index_a, *index_b));
+            }
+        }
+    }
+    let segments: Vec<Segment> = segments.into_iter().map(|(_, segment)| segment).collect();
+    let entry_ok = segments
+        .iter()
+        .any(|s| s.executable() && entry >= s.vaddr && entry < s.vaddr + s.mem_size);
+    if !entry_ok {
+        return Err(ElfError::BadEntry(entry));
+    }
+    Ok(ElfFile { data, kind, entry, segments })
+}
+
+fn check_segment(file_len: usize, index: usize, segment: &Segment, allow_wx: bool) -> Result<(), ElfError> {
+    let file_end = segment.offset.checked_add(segment.file_size);
+    if file_end.is_none_or(|end| end > file_len as u64) {
+        return Err(ElfError::SegmentOutOfFile(index));
+    }
+    if segment.mem_size < segment.file_size {
+        return Err(ElfError::BadMemorySize(index));
+    }
+    if segment.vaddr % PAGE_SIZE != segment.offset % PAGE_SIZE {
+        return Err(ElfError::Misaligned(index));
+    }
+    if segment.vaddr.checked_add(segment.mem_size).is_none_or(|end| end > USER_LIMIT) {
+        return Err(ElfError::BadAddress(index));
+    }
+    if segment.writable() && segment.executable() && !allow_wx {
+        return Err(ElfError::WritableExecutable(index));
+    }
+    Ok(())
+}
+
+/// What `load` mapped, needed to run and later unload the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedElf {
+    pub entry: VirtAddr,
+    /// End of the highest segment, rounded up to a page: where a heap
+    /// (brk) can start.
+    pub top: VirtAddr,
+    /// Page ranges mapped per segment, as (first page address, pages).
+    ranges: Vec<(VirtAddr, u64)>,
+}
+
+impl LoadedElf {
+    /// The entry point as a function using the loader call convention.
+    ///
+    /// # Safety
+    /// The program must actually follow that convention.
+    pub unsafe fn entry_point(&self) -> EntryPoint {
+        unsafe { core::mem::transmute::<u64, EntryPoint>(self.entry.as_u64()) }
+    }
+}
+
+/// Maps and copies every loadable segment. `base` is added to all
+/// addresses of `ET_DYN` files and must be page-aligned; it is ignored
+/// for `ET_EXEC`.
+pub fn load(
+    file: &ElfFile,
+    base: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<LoadedElf, ElfError> {
+    load_with_flags(file, base, PageTableFlags::empty(), mapper, frame_allocator)
+}
+
+/// Like `load`, adding `extra_flags` (USER_ACCESSIBLE for ring 3) to every
+/// page. The segments are written through their final addresses, so
+/// `mapper` must be the active page table.
+pub fn load_with_flags(
+    file: &ElfFile,
+    base: u64,
+    extra_flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<LoadedElf, ElfError> {
+    let base = match file.kind {
+        Kind::Exec => 0,
+        Kind::Dyn => base,
+    };
+    if base % PAGE_SIZE != 0 {
+        return Err(ElfError::Load(LoadError::Misaligned));
+    }
+    if file.segments.iter().any(|s| base.checked_add(s.vaddr + s.mem_size).is_none_or(|end| end > USER_LIMIT)) {
+        return Err(ElfError::BadAddress(0));
+    }
+
+    let mut loaded = LoadedElf { entry: VirtAddr::new(base + file.entry), top: VirtAddr::new(0), ranges: Vec::new() };
+    for segment in &file.segments {
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(base + segment.vaddr));
+        let last = Page::containing_address(VirtAddr::new(base + segment.vaddr + segment.mem_size - 1));
+        if let Err(err) = loader::map_zeroed(Page::range_inclusive(first, last), extra_flags, mapper, frame_allocator) {
+            unload(&loaded, mapper);
+            return Err(ElfError::Load(err));
+        }
+        loaded.ranges.push((first.start_address(), last - first + 1));
+
+        let start = segment.offset as usize;
+        let bytes = &file.data[start..start + segment.file_size as usize];
+        unsafe {
+            let dest = VirtAddr::new(base + segment.vaddr).as_mut_ptr::<u8>();
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), dest, bytes.len());
+        }
+        // the tail past p_filesz stays zero from map_zeroed
+
+        let mut flags = PageTableFlags::PRESENT | extra_flags;
+        if segment.writable() {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment.executable() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        for page in Page::range_inclusive(first, last) {
+            if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                flush.flush();
+            }
+        }
+        loaded.top = loaded.top.max((last + 1).start_address());
+    }
+    Ok(loaded)
+}
+
+/// Unmaps everything `load` mapped.
+pub fn unload(loaded: &LoadedElf, mapper: &mut impl Mapper<Size4KiB>) {
+    for &(start, pages) in &loaded.ranges {
+        let first = Page::<Size4KiB>::containing_address(start);
+        loader::unmap_pages(Page::range_inclusive(first, first + (pages - 1)), mapper);
+    }
+}
+
+//test case
+#[test_case]
+fn test_parse_fixtures() {
+    let exec = parse(&testdata::EXEC, false).unwrap();
+    assert_eq!(exec.kind, Kind::Exec);
+    assert_eq!(exec.entry, testdata::EXEC_BASE + 0xb0);
+    assert_eq!(exec.segments.len(), 2);
+    assert_eq!(exec.segments[1].vaddr, testdata::EXEC_BASE + 0x1100);
+    assert_eq!(exec.segments[1].file_size, 8);
+    assert_eq!(exec.segments[1].mem_size, 16);
+    assert!(!exec.segments[1].executable());
+
+    let dyn_file = parse(&testdata::DYN, false).unwrap();
+    assert_eq!(dyn_file.kind, Kind::Dyn);
+    assert_eq!(dyn_file.entry, 0xb0);
+}
+
+#[test_case]
+fn test_parse_rejects_malformed() {
+    let with = |offset: usize, bytes: &[u8]| {
+        let mut file = testdata::EXEC.to_vec();
+        file[offset..offset + bytes.len()].copy_from_slice(bytes);
+        file
+    };
+    let first_ph = 64;
+    let second_ph = 64 + 56;
+    assert_eq!(parse(&testdata::EXEC[..40], false).err(), Some(ElfError::Truncated));
+    assert_eq!(parse(&with(0, b"\x7fELG"), false).err(), Some(ElfError::BadMagic));
+    assert_eq!(parse(&with(4, &[1]), false).err(), Some(ElfError::UnsupportedClass));
+    assert_eq!(parse(&with(18, &[0x28, 0]), false).err(), Some(ElfError::WrongMachine(0x28)));
+    assert_eq!(parse(&with(16, &[1, 0]), false).err(), Some(ElfError::UnsupportedType(1)));
+    // program headers cut off by the end of the file
+    assert_eq!(parse(&with(56, &[40, 0]), false).err(), Some(ElfError::Truncated));
+    // p_filesz past the end of the file
+    assert_eq!(parse(&with(second_ph + 32, &[0xff, 0]), false).err(), Some(ElfError::SegmentOutOfFile(1)));
+    // p_memsz < p_filesz
+    assert_eq!(parse(&with(second_ph + 40, &[4]), false).err(), Some(ElfError::BadMemorySize(1)));
+    // vaddr ending in 0x108 against p_offset 0x100
+    assert_eq!(parse(&with(second_ph + 16, &[0x08]), false).err(), Some(ElfError::Misaligned(1)));
+    // data moved onto the text page
+    let overlapping = with(second_ph + 16, &[0x00, 0x01, 0x00]);
+    assert_eq!(parse(&overlapping, false).err(), Some(ElfError::Overlap(0, 1)));
+    // text made writable
+    let wx = with(first_ph + 4, &[7]);
+    assert_eq!(parse(&wx, false).err(), Some(ElfError::WritableExecutable(0)));
+    assert!(parse(&wx, true).is_ok());
+    // entry pointing into the data segment
+    assert_eq!(parse(&with(24, &[0x00, 0x11]), false).err(), Some(ElfError::BadEntry(testdata::EXEC_BASE + 0x1100)));
+}
+
+#[test_case]
+fn test_load_and_run() {
+    let run = |data: &[u8], base: u64| {
+        let file = parse(data, false).unwrap();
+        let loaded = crate::memory::with_paging(|mapper, frame_allocator| load(&file, base, mapper, frame_allocator))
+            .expect("no global frame allocator")
+            .unwrap();
+        assert_eq!(loaded.top.as_u64() % PAGE_SIZE, 0);
+        assert!(crate::memory::is_mapped(loaded.entry));
+        let result = unsafe { loaded.entry_point() }(&loader::PAYLOAD_API);
+        crate::memory::with_paging(|mapper, _| unload(&loaded, mapper));
+        assert!(!crate::memory::is_mapped(loaded.entry));
+        result
+    };
+    assert_eq!(run(&testdata::EXEC, 0), testdata::RETURN_VALUE);
+    assert_eq!(run(&testdata::DYN, 0x6666_2000_0000), testdata::RETURN_VALUE);
+}
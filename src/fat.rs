@@ -0,0 +1,1516 @@
+//! FAT16/FAT32 filesystem on any `BlockDevice`, with long file names.
+//!
+//! The FAT type is decided by the cluster count, as the specification
+//! requires; FAT12 volumes and sector sizes other than 512 bytes are
+//! refused at mount. `mount_at` puts the volume on a write-back
+//! `BlockCache`; `File::flush` and `unmount` write the directory entry and
+//! the FSInfo hints and then flush the device.
+//!
+//! Writes are ordered so a crash at any point leaves the volume in either
+//! the old or the new state:
+//!
+//! 1. New clusters are allocated as a chain of their own, ending in an
+//!    end-of-chain mark, and written to every FAT copy (FAT1 first). Until
+//!    step 3 nothing points at them, so a crash here only loses them.
+//! 2. The data is written into them and into existing clusters.
+//! 3. The new chain is linked onto the file, with one FAT entry: the old
+//!    end-of-chain entry. An empty file's first cluster lives in its
+//!    directory entry, so for it the link is step 4.
+//! 4. On `File::flush` (or drop) the directory entry gets the new first
+//!    cluster, size and modification time, in one sector write.
+//!
+//! A chain is never reachable before it is complete, and a cluster is
+//! never linked while something else still owns it, so the FAT cannot end
+//! up cross-linked. A crash between 3 and 4 leaves a file whose chain is
+//! longer than its size needs, which is harmless. New files are created
+//! the same way: the long-name entries go first and are ignored until the
+//! short entry after them is written, and a directory cluster is zeroed
+//! before it is linked onto the directory.
+//!
+//! `remove` and `truncate` go the other way round: the directory entry
+//! lets go of the clusters in one write, and only then are they freed, so
+//! a crash in between loses them but leaves nothing cross-linked.
+//!
+//! Each step ends with a `BlockDevice::flush`, so the order holds even
+//! when a cache below reorders the writes within a step.
+//!
+//! Overwriting bytes that already exist happens in place, a sector at a
+//! time; only the file's shape (chain, size, entry) is crash-safe.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use crate::block::cache::{self, BlockCache};
+use crate::block::{BlockDevice, BlockError, SECTOR_SIZE};
+use crate::bytes::{le_u16, le_u32};
+use crate::cmos::{self, DateTime};
+use crate::vfs::{self, FileKind, Metadata, MountTable, Source, VfsError};
+
+#[cfg(test)]
+pub mod testdata;
+
+const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+const END_OF_DIRECTORY: u8 = 0x00;
+const DELETED: u8 = 0xe5;
+/// Stands in for a real 0xe5 as the first byte of a short name.
+const KANJI_E5: u8 = 0x05;
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+const LFN_MAX_ENTRIES: usize = 20;
+const MAX_NAME: usize = 255;
+/// Byte 12 of a short entry: the base or extension is shown lowercase.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXT: u8 = 0x10;
+
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+const FSINFO_TRAIL: u32 = 0xaa55_0000;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+const FAT16_MIN_CLUSTERS: u32 = 4085;
+const FAT32_MIN_CLUSTERS: u32 = 65525;
+const FIRST_CLUSTER: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    Io(BlockError),
+    /// The boot sector does not describe a FAT volume.
+    NotFat,
+    /// FAT12, or a sector size other than 512 bytes.
+    Unsupported,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    InvalidName,
+    /// No free cluster is left, or the fixed FAT16 root directory is full.
+    NoSpace,
+    /// A cluster chain leaves the volume or loops.
+    Corrupt,
+}
+
+impl fmt::Display for FatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            FatError::Io(err) => return write!(f, "I/O error: {}", err),
+            FatError::NotFat => "not a FAT filesystem",
+            FatError::Unsupported => "unsupported FAT variant",
+            FatError::NotFound => "no such file or directory",
+            FatError::NotADirectory => "not a directory",
+            FatError::IsADirectory => "is a directory",
+            FatError::AlreadyExists => "file exists",
+            FatError::InvalidName => "invalid file name",
+            FatError::NoSpace => "no space left on the volume",
+            FatError::Corrupt => "corrupt cluster chain",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl From<BlockError> for FatError {
+    fn from(err: BlockError) -> Self {
+        FatError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// Where everything is, from the BIOS parameter block.
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    fat_start: u64,
+    fat_count: u32,
+    fat_sectors: u64,
+    /// The fixed FAT16 root directory; empty on FAT32.
+    root_start: u64,
+    root_sectors: u64,
+    data_start: u64,
+    cluster_count: u32,
+    /// FAT32 only.
+    root_cluster: u32,
+    fsinfo: Option<u64>,
+}
+
+impl Geometry {
+    fn parse(boot: &[u8]) -> Result<Geometry, FatError> {
+        let field16 = |offset| le_u16(boot, offset).map(u64::from).ok_or(FatError::NotFat);
+        let field32 = |offset| le_u32(boot, offset).map(u64::from).ok_or(FatError::NotFat);
+        if boot.get(510..512) != Some(&[0x55, 0xaa][..]) {
+            return Err(FatError::NotFat);
+        }
+        let bytes_per_sector = field16(11)?;
+        let sectors_per_cluster = u32::from(boot[13]);
+        let reserved = field16(14)?;
+        let fat_count = u32::from(boot[16]);
+        let root_entries = field16(17)?;
+        if !(512..=4096).contains(&bytes_per_sector)
+            || !bytes_per_sector.is_power_of_two()
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+        {
+            return Err(FatError::NotFat);
+        }
+        if bytes_per_sector != SECTOR_SIZE as u64 {
+            return Err(FatError::Unsupported);
+        }
+
+        let fat_sectors = match field16(22)? {
+            0 => field32(36)?,
+            sectors => sectors,
+        };
+        let total = match field16(19)? {
+            0 => field32(32)?,
+            sectors => sectors,
+        };
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(SECTOR_SIZE as u64);
+        let root_start = reserved + u64::from(fat_count) * fat_sectors;
+        let data_start = root_start + root_sectors;
+        if fat_sectors == 0 || data_start >= total {
+            return Err(FatError::NotFat);
+        }
+        let cluster_count = ((total - data_start) / u64::from(sectors_per_cluster)).min(0x0fff_fff0) as u32;
+        let fat_type = match cluster_count {
+            n if n < FAT16_MIN_CLUSTERS => return Err(FatError::Unsupported),
+            n if n < FAT32_MIN_CLUSTERS => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let entry_bytes = if fat_type == FatType::Fat16 { 2 } else { 4 };
+        if fat_sectors * SECTOR_SIZE as u64 / entry_bytes < u64::from(cluster_count) + 2 {
+            return Err(FatError::NotFat);
+        }
+
+        let mut geometry = Geometry {
+            fat_type,
+            sectors_per_cluster,
+            fat_start: reserved,
+            fat_count,
+            fat_sectors,
+            root_start,
+            root_sectors,
+            data_start,
+            cluster_count,
+            root_cluster: 0,
+            fsinfo: None,
+        };
+        match fat_type {
+            FatType::Fat16 if root_sectors == 0 => return Err(FatError::NotFat),
+            FatType::Fat16 => {}
+            FatType::Fat32 => {
+                geometry.root_cluster = field32(44)? as u32;
+                if !geometry.is_valid(geometry.root_cluster) {
+                    return Err(FatError::NotFat);
+                }
+                geometry.fsinfo = match field16(48)? {
+                    0 | 0xffff => None,
+                    sector if sector < reserved => Some(sector),
+                    _ => None,
+                };
+            }
+        }
+        Ok(geometry)
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - FIRST_CLUSTER) * u64::from(self.sectors_per_cluster)
+    }
+
+    fn is_valid(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..self.cluster_count + FIRST_CLUSTER).contains(&cluster)
+    }
+
+    fn is_end(&self, value: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat16 => value >= 0xfff8,
+            FatType::Fat32 => value >= 0x0fff_fff8,
+        }
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    fn entry_bytes(&self) -> u64 {
+        match self.fat_type {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
+}
+
+/// A directory: the fixed FAT16 root, or a cluster chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    FixedRoot,
+    Chain(u32),
+}
+
+/// One 32-byte directory slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slot {
+    lba: u64,
+    index: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The long name when there is a valid one, otherwise the short name.
+    pub name: String,
+    pub short_name: [u8; 11],
+    pub attributes: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// FAT date and time words of the last write.
+    pub write_date: u16,
+    pub write_time: u16,
+    slot: Slot,
+    /// The long-name entries before `slot`, when `name` came from them.
+    long_slots: Vec<Slot>,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    pub fn modified(&self) -> DateTime {
+        decode_timestamp(self.write_date, self.write_time)
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || display_short_name(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+/// Packs a time into the FAT date and time words. FAT counts years from
+/// 1980 and seconds in steps of two.
+pub fn encode_timestamp(time: DateTime) -> (u16, u16) {
+    let year = time.year.clamp(1980, 2107) - 1980;
+    let date = (year << 9) | (u16::from(time.month) << 5) | u16::from(time.day);
+    let clock = (u16::from(time.hour) << 11) | (u16::from(time.minute) << 5) | u16::from(time.second / 2);
+    (date, clock)
+}
+
+pub fn decode_timestamp(date: u16, time: u16) -> DateTime {
+    DateTime {
+        year: 1980 + (date >> 9),
+        month: ((date >> 5) & 0xf) as u8,
+        day: (date & 0x1f) as u8,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3f) as u8,
+        second: ((time & 0x1f) * 2) as u8,
+    }
+}
+
+/// The checksum of a short name that its long-name entries carry.
+fn short_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+fn display_short_name(short: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let len = bytes.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+        bytes[..len]
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                let b = if i == 0 && b == KANJI_E5 { DELETED } else { b };
+                let c = char::from(b);
+                if lower { c.to_ascii_lowercase() } else { c }
+            })
+            .collect()
+    };
+    let mut name = part(&short[..8], case & LOWERCASE_BASE != 0);
+    let ext = part(&short[8..], case & LOWERCASE_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+fn is_short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+fn validate_name(name: &str) -> Result<(), FatError> {
+    let invalid = |c: char| c < ' ' || "\"*/:<>?\\|".contains(c);
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.ends_with(' ')
+        || name.ends_with('.')
+        || name.encode_utf16().count() > MAX_NAME
+        || name.chars().any(invalid)
+    {
+        return Err(FatError::InvalidName);
+    }
+    Ok(())
+}
+
+/// The short entry for a name that fits 8.3 as it is, with the lowercase
+/// flags when a part is all lowercase. Mixed case needs a long name.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.split_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || ext.contains('.') {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    for (part, field, flag) in [(base, 0, LOWERCASE_BASE), (ext, 8, LOWERCASE_EXT)] {
+        let lower = part.chars().any(|c| c.is_ascii_lowercase());
+        if lower && part.chars().any(|c| c.is_ascii_uppercase()) {
+            return None;
+        }
+        for (i, c) in part.chars().enumerate() {
+            let c = c.to_ascii_uppercase();
+            if !is_short_char(c) {
+                return None;
+            }
+            short[field + i] = c as u8;
+        }
+        if lower {
+            case |= flag;
+        }
+    }
+    Some((short, case))
+}
+
+/// Generates the `BASE~N.EXT` short name for a name that needs a long one,
+/// picking the lowest N no entry in the directory uses yet.
+fn generate_short_name(name: &str, taken: &[[u8; 11]]) -> Result<[u8; 11], FatError> {
+    let to_short = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| c.to_ascii_uppercase())
+            .map(|c| if is_short_char(c) { c as u8 } else { b'_' })
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rsplit_once('.') {
+        Some((base, ext)) => (to_short(base), to_short(ext)),
+        None => (to_short(trimmed), Vec::new()),
+    };
+    let base = if base.is_empty() { vec![b'_'] } else { base };
+
+    let mut short = [b' '; 11];
+    for (i, &c) in ext.iter().take(3).enumerate() {
+        short[8 + i] = c;
+    }
+    for n in 1..1_000_000u32 {
+        let mut tail = [0u8; 7];
+        let mut len = 0;
+        let mut digits = n;
+        while digits > 0 {
+            tail[6 - len] = b'0' + (digits % 10) as u8;
+            digits /= 10;
+            len += 1;
+        }
+        tail[6 - len] = b'~';
+        let tail = &tail[6 - len..];
+        let keep = base.len().min(8 - tail.len());
+        short[..8].fill(b' ');
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail);
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(FatError::NoSpace)
+}
+
+/// The long-name entries for `name`, in the order they go on disk: the
+/// last part (flagged `LFN_LAST`) first.
+fn long_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LFN_CHARS);
+    let mut entries = Vec::with_capacity(count);
+    for part in (0..count).rev() {
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[0] = (part + 1) as u8 | if part + 1 == count { LFN_LAST } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let chars = LFN_OFFSETS.iter().enumerate().map(|(i, &offset)| (part * LFN_CHARS + i, offset));
+        for (unit, offset) in chars {
+            let value = match unit.cmp(&units.len()) {
+                core::cmp::Ordering::Less => units[unit],
+                core::cmp::Ordering::Equal => 0,
+                core::cmp::Ordering::Greater => 0xffff,
+            };
+            entry[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+/// Byte offsets of the 13 UTF-16 units in a long-name entry.
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Collects long-name entries while a directory is scanned.
+struct LongName {
+    units: [u16; LFN_MAX_ENTRIES * LFN_CHARS],
+    /// Sequence number of the entry expected next; 0 once complete.
+    next: u8,
+    checksum: u8,
+    active: bool,
+}
+
+impl LongName {
+    fn new() -> Self {
+        LongName { units: [0; LFN_MAX_ENTRIES * LFN_CHARS], next: 0, checksum: 0, active: false }
+    }
+
+    fn push(&mut self, raw: &[u8]) {
+        let ord = raw[0] & 0x1f;
+        if raw[0] & LFN_LAST != 0 {
+            self.active = (1..=LFN_MAX_ENTRIES as u8).contains(&ord);
+            self.next = ord;
+            self.checksum = raw[13];
+            self.units.fill(0xffff);
+        }
+        if !self.active || ord != self.next || ord == 0 || raw[13] != self.checksum {
+            self.active = false;
+            return;
+        }
+        let base = usize::from(ord - 1) * LFN_CHARS;
+        for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+            self.units[base + i] = u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        }
+        self.next -= 1;
+    }
+
+    /// The name, if a complete run matching `short` was collected.
+    fn take(&mut self, short: &[u8; 11]) -> Option<String> {
+        let complete = self.active && self.next == 0 && self.checksum == short_checksum(short);
+        self.active = false;
+        if !complete {
+            return None;
+        }
+        let len = self.units.iter().position(|&u| u == 0 || u == 0xffff).unwrap_or(self.units.len());
+        Some(char::decode_utf16(self.units[..len].iter().copied()).map(|c| c.unwrap_or('\u{fffd}')).collect())
+    }
+}
+
+pub struct FatFs<D: BlockDevice> {
+    device: D,
+    geometry: Geometry,
+    /// The last FAT sector read, so a scan does not reread it per entry.
+    fat_cache: Option<(u64, [u8; SECTOR_SIZE])>,
+    /// FSInfo free-cluster count, `None` while unknown.
+    free_count: Option<u32>,
+    /// Where the next allocation starts looking.
+    next_free: u32,
+    fsinfo_dirty: bool,
+    clock: fn() -> DateTime,
+}
+
+impl<D: BlockDevice> FatFs<D> {
+    pub fn mount(mut device: D) -> Result<Self, FatError> {
+        let mut boot = [0u8; SECTOR_SIZE];
+        device.read_sectors(0, &mut boot)?;
+        let mut geometry = Geometry::parse(&boot)?;
+
+        let mut free_count = None;
+        let mut next_free = FIRST_CLUSTER;
+        if let Some(lba) = geometry.fsinfo {
+            let mut info = [0u8; SECTOR_SIZE];
+            device.read_sectors(lba, &mut info)?;
+            let field = |offset| le_u32(&info, offset).unwrap_or(0);
+            if field(0) == FSINFO_LEAD && field(484) == FSINFO_STRUCT && field(508) == FSINFO_TRAIL {
+                // both are hints and are ignored when out of range
+                free_count = Some(field(488)).filter(|&n| n != FSINFO_UNKNOWN && n <= geometry.cluster_count);
+                if geometry.is_valid(field(492)) {
+                    next_free = field(492);
+                }
+            } else {
+                geometry.fsinfo = None;
+            }
+        }
+        Ok(FatFs {
+            device,
+            geometry,
+            fat_cache: None,
+            free_count,
+            next_free,
+            fsinfo_dirty: false,
+            clock: cmos::read_rtc,
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.geometry.fat_type
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.geometry.cluster_count
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.geometry.cluster_bytes()
+    }
+
+    /// Replaces the RTC as the source of modification times.
+    pub fn set_clock(&mut self, clock: fn() -> DateTime) {
+        self.clock = clock;
+    }
+
+    /// Free clusters, from the FSInfo hint when there is a valid one and
+    /// by counting the FAT otherwise.
+    pub fn free_clusters(&mut self) -> Result<u32, FatError> {
+        if let Some(count) = self.free_count {
+            return Ok(count);
+        }
+        let mut count = 0;
+        for cluster in FIRST_CLUSTER..self.geometry.cluster_count + FIRST_CLUSTER {
+            if self.fat_entry(cluster)? == 0 {
+                count += 1;
+            }
+        }
+        self.free_count = Some(count);
+        Ok(count)
+    }
+
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, FatError> {
+        let dir = self.resolve_dir(path)?;
+        self.scan_dir(dir)
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<File<'_, D>, FatError> {
+        let (parent, name) = split_path(path);
+        let dir = self.resolve_dir(parent)?;
+        let entry = self.find(dir, name)?;
+        if entry.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        let chain = self.chain(entry.first_cluster)?;
+        Ok(File { fs: self, slot: entry.slot, chain, size: entry.size, dirty: false })
+    }
+
+    /// The entry for `path`; the root directory has none.
+    pub fn stat(&mut self, path: &str) -> Result<DirEntry, FatError> {
+        let (parent, name) = split_path(path);
+        let dir = self.resolve_dir(parent)?;
+        self.find(dir, name)
+    }
+
+    /// Creates an empty file. Names that do not fit 8.3 as they are get a
+    /// generated `~N` short name and long-name entries.
+    pub fn create(&mut self, path: &str) -> Result<File<'_, D>, FatError> {
+        let (parent, name) = split_path(path);
+        validate_name(name)?;
+        let dir = self.resolve_dir(parent)?;
+        let entries = self.scan_dir(dir)?;
+        if entries.iter().any(|entry| entry.matches(name)) {
+            return Err(FatError::AlreadyExists);
+        }
+        let (short, case, long) = match exact_short_name(name) {
+            Some((short, case)) => (short, case, Vec::new()),
+            None => {
+                let taken: Vec<[u8; 11]> = entries.iter().map(|entry| entry.short_name).collect();
+                let short = generate_short_name(name, &taken)?;
+                (short, 0, long_entries(name, short_checksum(&short)))
+            }
+        };
+
+        let slots = self.free_slots(dir, long.len() + 1)?;
+        // long entries first: without the short entry they are ignored
+        for (&slot, raw) in slots.iter().zip(&long) {
+            self.write_slot(slot, raw)?;
+        }
+        self.barrier()?;
+        let (date, time) = encode_timestamp((self.clock)());
+        let mut raw = [0u8; ENTRY_SIZE];
+        raw[..11].copy_from_slice(&short);
+        raw[11] = ATTR_ARCHIVE;
+        raw[12] = case;
+        raw[14..16].copy_from_slice(&time.to_le_bytes());
+        raw[16..18].copy_from_slice(&date.to_le_bytes());
+        raw[18..20].copy_from_slice(&date.to_le_bytes());
+        raw[22..24].copy_from_slice(&time.to_le_bytes());
+        raw[24..26].copy_from_slice(&date.to_le_bytes());
+        let slot = slots[long.len()];
+        self.write_slot(slot, &raw)?;
+        Ok(File { fs: self, slot, chain: Vec::new(), size: 0, dirty: false })
+    }
+
+    /// Deletes a file. The short entry is marked deleted first, which is
+    /// the one write that removes the file; its long-name entries go next
+    /// and its clusters are freed last, so a crash in between only loses
+    /// clusters. Directories are refused.
+    pub fn remove(&mut self, path: &str) -> Result<(), FatError> {
+        let entry = self.stat(path)?;
+        if entry.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        let chain = self.chain(entry.first_cluster)?;
+        self.mark_deleted(entry.slot)?;
+        self.barrier()?;
+        for &slot in &entry.long_slots {
+            self.mark_deleted(slot)?;
+        }
+        self.barrier()?;
+        self.release(&chain);
+        self.flush()
+    }
+
+    /// Empties a file and opens it. The entry loses its clusters in one
+    /// write before they are freed, as in `remove`.
+    pub fn truncate(&mut self, path: &str) -> Result<File<'_, D>, FatError> {
+        let entry = self.stat(path)?;
+        if entry.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        let chain = self.chain(entry.first_cluster)?;
+        let mut file = File { fs: self, slot: entry.slot, chain: Vec::new(), size: 0, dirty: true };
+        file.flush()?;
+        file.fs.release(&chain);
+        file.fs.flush()?;
+        Ok(file)
+    }
+
+    /// Writes the FSInfo hints back if they changed and flushes the device.
+    pub fn flush(&mut self) -> Result<(), FatError> {
+        if self.fsinfo_dirty {
+            if let Some(lba) = self.geometry.fsinfo {
+                let mut info = self.read_sector(lba)?;
+                info[488..492].copy_from_slice(&self.free_count.unwrap_or(FSINFO_UNKNOWN).to_le_bytes());
+                info[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+                self.device.write_sectors(lba, &info)?;
+            }
+            self.fsinfo_dirty = false;
+        }
+        self.barrier()
+    }
+
+    /// Flushes and hands the device back.
+    pub fn unmount(mut self) -> Result<D, FatError> {
+        self.flush()?;
+        Ok(self.device)
+    }
+
+    /// Ends a step of the write ordering: everything written so far is on
+    /// the device before anything after.
+    fn barrier(&mut self) -> Result<(), FatError> {
+        Ok(self.device.flush()?)
+    }
+
+    fn read_sector(&mut self, lba: u64) -> Result<[u8; SECTOR_SIZE], FatError> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        self.device.read_sectors(lba, &mut sector)?;
+        Ok(sector)
+    }
+
+    fn root(&self) -> Dir {
+        match self.geometry.fat_type {
+            FatType::Fat16 => Dir::FixedRoot,
+            FatType::Fat32 => Dir::Chain(self.geometry.root_cluster),
+        }
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, FatError> {
+        let offset = u64::from(cluster) * self.geometry.entry_bytes();
+        let lba = self.geometry.fat_start + offset / SECTOR_SIZE as u64;
+        if !matches!(self.fat_cache, Some((cached, _)) if cached == lba) {
+            self.fat_cache = Some((lba, self.read_sector(lba)?));
+        }
+        let sector = self.fat_cache.as_ref().map_or(&[][..], |(_, sector)| &sector[..]);
+        let at = (offset % SECTOR_SIZE as u64) as usize;
+        Ok(match self.geometry.fat_type {
+            FatType::Fat16 => u32::from(le_u16(sector, at).unwrap_or(0)),
+            FatType::Fat32 => le_u32(sector, at).unwrap_or(0) & 0x0fff_ffff,
+        })
+    }
+
+    /// Sets a FAT entry in every copy, FAT1 first.
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), FatError> {
+        let offset = u64::from(cluster) * self.geometry.entry_bytes();
+        let at = (offset % SECTOR_SIZE as u64) as usize;
+        self.fat_cache = None;
+        for copy in 0..u64::from(self.geometry.fat_count) {
+            let lba = self.geometry.fat_start + copy * self.geometry.fat_sectors + offset / SECTOR_SIZE as u64;
+            let mut sector = self.read_sector(lba)?;
+            match self.geometry.fat_type {
+                FatType::Fat16 => sector[at..at + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+                FatType::Fat32 => {
+                    // the top four bits are reserved and kept as they are
+                    let old = le_u32(&sector, at).unwrap_or(0);
+                    let new = (old & 0xf000_0000) | (value & 0x0fff_ffff);
+                    sector[at..at + 4].copy_from_slice(&new.to_le_bytes());
+                }
+            }
+            self.device.write_sectors(lba, &sector)?;
+        }
+        Ok(())
+    }
+
+    fn chain(&mut self, first: u32) -> Result<Vec<u32>, FatError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        if cluster == 0 {
+            return Ok(chain);
+        }
+        loop {
+            if !self.geometry.is_valid(cluster) || chain.len() >= self.geometry.cluster_count as usize {
+                return Err(FatError::Corrupt);
+            }
+            chain.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+            if self.geometry.is_end(cluster) {
+                return Ok(chain);
+            }
+        }
+    }
+
+    /// Allocates `count` free clusters as their own chain ending in an
+    /// end-of-chain mark. Nothing links to them yet.
+    fn allocate(&mut self, count: usize) -> Result<Vec<u32>, FatError> {
+        let end = self.geometry.cluster_count + FIRST_CLUSTER;
+        let start = if self.geometry.is_valid(self.next_free) { self.next_free } else { FIRST_CLUSTER };
+        let mut found = Vec::with_capacity(count);
+        for cluster in (start..end).chain(FIRST_CLUSTER..start) {
+            if found.len() == count {
+                break;
+            }
+            if self.fat_entry(cluster)? == 0 {
+                found.push(cluster);
+            }
+        }
+        if found.len() < count {
+            return Err(FatError::NoSpace);
+        }
+        for (i, &cluster) in found.iter().enumerate() {
+            let next = found.get(i + 1).copied().unwrap_or(self.geometry.end_of_chain());
+            if let Err(err) = self.set_fat_entry(cluster, next) {
+                self.release(&found[..i]);
+                return Err(err);
+            }
+        }
+        if let Err(err) = self.barrier() {
+            self.release(&found);
+            return Err(err);
+        }
+        let last = found[count - 1];
+        self.next_free = if last + 1 < end { last + 1 } else { FIRST_CLUSTER };
+        self.free_count = self.free_count.map(|free| free.saturating_sub(count as u32));
+        self.fsinfo_dirty = true;
+        Ok(found)
+    }
+
+    /// Frees clusters nothing links to, after a failed operation. Best
+    /// effort: if this fails too the clusters are only lost.
+    fn release(&mut self, clusters: &[u32]) {
+        for &cluster in clusters {
+            if self.set_fat_entry(cluster, 0).is_err() {
+                return;
+            }
+            self.free_count = self.free_count.map(|free| free + 1);
+            self.fsinfo_dirty = true;
+        }
+    }
+
+    fn dir_sectors(&mut self, dir: Dir) -> Result<Vec<u64>, FatError> {
+        match dir {
+            Dir::FixedRoot => Ok((self.geometry.root_start..self.geometry.root_start + self.geometry.root_sectors).collect()),
+            Dir::Chain(first) => {
+                let per_cluster = u64::from(self.geometry.sectors_per_cluster);
+                let chain = self.chain(first)?;
+                Ok(chain
+                    .iter()
+                    .flat_map(|&cluster| {
+                        let lba = self.geometry.cluster_lba(cluster);
+                        lba..lba + per_cluster
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Every entry in `dir` except `.`, `..`, volume labels and deleted ones.
+    fn scan_dir(&mut self, dir: Dir) -> Result<Vec<DirEntry>, FatError> {
+        let mut entries = Vec::new();
+        let mut long = LongName::new();
+        let mut long_slots = Vec::new();
+        for lba in self.dir_sectors(dir)? {
+            let sector = self.read_sector(lba)?;
+            for (index, raw) in sector.chunks_exact(ENTRY_SIZE).enumerate() {
+                match raw[0] {
+                    END_OF_DIRECTORY => return Ok(entries),
+                    DELETED => {
+                        long.active = false;
+                        continue;
+                    }
+                    _ => {}
+                }
+                let attributes = raw[11];
+                if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                    if raw[0] & LFN_LAST != 0 {
+                        long_slots.clear();
+                    }
+                    long.push(raw);
+                    long_slots.push(Slot { lba, index });
+                    continue;
+                }
+                let mut short_name = [0u8; 11];
+                short_name.copy_from_slice(&raw[..11]);
+                let name = long.take(&short_name);
+                let long_slots = if name.is_some() { core::mem::take(&mut long_slots) } else { Vec::new() };
+                if attributes & ATTR_VOLUME_ID != 0 || raw[0] == b'.' {
+                    continue;
+                }
+                let field16 = |offset| le_u16(raw, offset).unwrap_or(0);
+                let high = if self.geometry.fat_type == FatType::Fat32 { u32::from(field16(20)) << 16 } else { 0 };
+                entries.push(DirEntry {
+                    name: name.unwrap_or_else(|| display_short_name(&short_name, raw[12])),
+                    short_name,
+                    attributes,
+                    first_cluster: high | u32::from(field16(26)),
+                    size: le_u32(raw, 28).unwrap_or(0),
+                    write_date: field16(24),
+                    write_time: field16(22),
+                    slot: Slot { lba, index },
+                    long_slots,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn find(&mut self, dir: Dir, name: &str) -> Result<DirEntry, FatError> {
+        self.scan_dir(dir)?.into_iter().find(|entry| entry.matches(name)).ok_or(FatError::NotFound)
+    }
+
+    fn resolve_dir(&mut self, path: &str) -> Result<Dir, FatError> {
+        let mut dir = self.root();
+        for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+            let entry = self.find(dir, name)?;
+            if !entry.is_dir() {
+                return Err(FatError::NotADirectory);
+            }
+            // a `..` entry that leads to the root stores cluster 0
+            dir = if entry.first_cluster == 0 { self.root() } else { Dir::Chain(entry.first_cluster) };
+        }
+        Ok(dir)
+    }
+
+    /// Finds `count` consecutive free slots in `dir`, growing a chained
+    /// directory by zeroed clusters when it has none.
+    fn free_slots(&mut self, dir: Dir, count: usize) -> Result<Vec<Slot>, FatError> {
+        loop {
+            let sectors = self.dir_sectors(dir)?;
+            let mut run = Vec::with_capacity(count);
+            let mut past_end = false;
+            for &lba in &sectors {
+                let sector = self.read_sector(lba)?;
+                for (index, raw) in sector.chunks_exact(ENTRY_SIZE).enumerate() {
+                    if run.len() == count {
+                        // keep the directory terminated right after the run
+                        if past_end && raw[0] != END_OF_DIRECTORY {
+                            self.write_slot(Slot { lba, index }, &[0u8; ENTRY_SIZE])?;
+                            self.barrier()?;
+                        }
+                        return Ok(run);
+                    }
+                    past_end |= raw[0] == END_OF_DIRECTORY;
+                    if past_end || raw[0] == DELETED {
+                        run.push(Slot { lba, index });
+                    } else {
+                        run.clear();
+                    }
+                }
+            }
+            if run.len() == count {
+                return Ok(run);
+            }
+
+            let Dir::Chain(first) = dir else {
+                return Err(FatError::NoSpace);
+            };
+            let last = *self.chain(first)?.last().ok_or(FatError::Corrupt)?;
+            let cluster = self.allocate(1)?[0];
+            let zero = vec![0u8; self.geometry.cluster_bytes()];
+            let lba = self.geometry.cluster_lba(cluster);
+            let linked = self.device.write_sectors(lba, &zero).map_err(FatError::from)
+                .and_then(|()| self.barrier())
+                .and_then(|()| self.set_fat_entry(last, cluster));
+            if let Err(err) = linked {
+                self.release(&[cluster]);
+                return Err(err);
+            }
+        }
+    }
+
+    fn mark_deleted(&mut self, slot: Slot) -> Result<(), FatError> {
+        let mut sector = self.read_sector(slot.lba)?;
+        sector[slot.index * ENTRY_SIZE] = DELETED;
+        self.device.write_sectors(slot.lba, &sector)?;
+        Ok(())
+    }
+
+    fn write_slot(&mut self, slot: Slot, raw: &[u8; ENTRY_SIZE]) -> Result<(), FatError> {
+        let mut sector = self.read_sector(slot.lba)?;
+        sector[slot.index * ENTRY_SIZE..][..ENTRY_SIZE].copy_from_slice(raw);
+        self.device.write_sectors(slot.lba, &sector)?;
+        Ok(())
+    }
+}
+
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rsplit_once('/') {
+        Some((parent, name)) => (parent, name),
+        None => ("", path),
+    }
+}
+
+/// An open file. Its directory entry is brought up to date by `flush`,
+/// which dropping the file also does (ignoring errors).
+pub struct File<'a, D: BlockDevice> {
+    fs: &'a mut FatFs<D>,
+    slot: Slot,
+    chain: Vec<u32>,
+    size: u32,
+    dirty: bool,
+}
+
+impl<D: BlockDevice> File<'_, D> {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Reads from `offset` up to the end of the file; returns the number
+    /// of bytes read.
+    pub fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<usize, FatError> {
+        let len = buf.len().min(self.size.saturating_sub(offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let (lba, at, n) = self.locate(offset as usize + done, len - done)?;
+            let sector = self.fs.read_sector(lba)?;
+            buf[done..done + n].copy_from_slice(&sector[at..at + n]);
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// Writes `buf` at `offset`, growing the file as needed; a gap between
+    /// the old end and `offset` reads as zeros.
+    pub fn write(&mut self, offset: u32, buf: &[u8]) -> Result<usize, FatError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.size < offset {
+            let zeros = [0u8; SECTOR_SIZE];
+            let n = (offset - self.size).min(SECTOR_SIZE as u32) as usize;
+            self.write(self.size, &zeros[..n])?;
+        }
+        let end = u64::from(offset) + buf.len() as u64;
+        if end > u64::from(u32::MAX) {
+            return Err(FatError::NoSpace);
+        }
+
+        // step 1: a detached chain for the clusters the file lacks
+        let needed = (end as usize).div_ceil(self.fs.geometry.cluster_bytes());
+        let fresh = if needed > self.chain.len() { self.fs.allocate(needed - self.chain.len())? } else { Vec::new() };
+        let old_len = self.chain.len();
+        self.chain.extend_from_slice(&fresh);
+
+        // step 2: the data
+        let mut done = 0;
+        while done < buf.len() {
+            let written = self.locate(offset as usize + done, buf.len() - done).and_then(|(lba, at, n)| {
+                if n == SECTOR_SIZE {
+                    self.fs.device.write_sectors(lba, &buf[done..done + n])?;
+                } else {
+                    let mut sector = self.fs.read_sector(lba)?;
+                    sector[at..at + n].copy_from_slice(&buf[done..done + n]);
+                    self.fs.device.write_sectors(lba, &sector)?;
+                }
+                Ok(n)
+            });
+            match written {
+                Ok(n) => done += n,
+                Err(err) => {
+                    self.chain.truncate(old_len);
+                    self.fs.release(&fresh);
+                    return Err(err);
+                }
+            }
+        }
+        if let Err(err) = self.fs.barrier() {
+            self.chain.truncate(old_len);
+            self.fs.release(&fresh);
+            return Err(err);
+        }
+
+        // step 3: link; an empty file is linked by its entry on flush
+        if let (Some(&last), Some(&first)) = (self.chain[..old_len].last(), fresh.first()) {
+            if let Err(err) = self.fs.set_fat_entry(last, first).and_then(|()| self.fs.barrier()) {
+                self.chain.truncate(old_len);
+                self.fs.release(&fresh);
+                return Err(err);
+            }
+        }
+        self.size = self.size.max(end as u32);
+        self.dirty = true;
+        Ok(buf.len())
+    }
+
+    /// Step 4: writes the first cluster, size and modification time into
+    /// the directory entry, then the FSInfo hints.
+    pub fn flush(&mut self) -> Result<(), FatError> {
+        if self.dirty {
+            let first = self.chain.first().copied().unwrap_or(0);
+            let (date, time) = encode_timestamp((self.fs.clock)());
+            let mut sector = self.fs.read_sector(self.slot.lba)?;
+            let raw = &mut sector[self.slot.index * ENTRY_SIZE..][..ENTRY_SIZE];
+            raw[11] |= ATTR_ARCHIVE;
+            raw[18..20].copy_from_slice(&date.to_le_bytes());
+            raw[20..22].copy_from_slice(&((first >> 16) as u16).to_le_bytes());
+            raw[22..24].copy_from_slice(&time.to_le_bytes());
+            raw[24..26].copy_from_slice(&date.to_le_bytes());
+            raw[26..28].copy_from_slice(&(first as u16).to_le_bytes());
+            raw[28..32].copy_from_slice(&self.size.to_le_bytes());
+            self.fs.device.write_sectors(self.slot.lba, &sector)?;
+            self.dirty = false;
+        }
+        self.fs.flush()
+    }
+
+    /// The sector holding byte `pos`, the offset in it and how many of
+    /// `len` bytes fit there.
+    fn locate(&self, pos: usize, len: usize) -> Result<(u64, usize, usize), FatError> {
+        let cluster_bytes = self.fs.geometry.cluster_bytes();
+        let cluster = *self.chain.get(pos / cluster_bytes).ok_or(FatError::Corrupt)?;
+        let within = pos % cluster_bytes;
+        let at = within % SECTOR_SIZE;
+        let lba = self.fs.geometry.cluster_lba(cluster) + (within / SECTOR_SIZE) as u64;
+        Ok((lba, at, len.min(SECTOR_SIZE - at)))
+    }
+}
+
+impl<D: BlockDevice> Drop for File<'_, D> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// ==========================================================
+// VFS
+// ==========================================================
+
+/// A mounted FAT volume, shared by the VFS and the files open on it.
+pub struct FatVolume<D: BlockDevice> {
+    fs: Arc<Mutex<FatFs<D>>>,
+}
+
+impl<D: BlockDevice> FatVolume<D> {
+    pub fn new(fs: FatFs<D>) -> Self {
+        FatVolume { fs: Arc::new(Mutex::new(fs)) }
+    }
+}
+
+fn is_root(path: &str) -> bool {
+    path.trim_matches('/').is_empty()
+}
+
+impl<D: BlockDevice + Send + 'static> vfs::FileSystem for FatVolume<D> {
+    fn kind(&self) -> &'static str {
+        match self.fs.lock().fat_type() {
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        }
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn vfs::FileHandle>, VfsError> {
+        let size = self.fs.lock().open(path)?.size();
+        Ok(Box::new(FatHandle { fs: self.fs.clone(), path: String::from(path), size }))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<Box<dyn vfs::DirEntry>>, VfsError> {
+        let entries = self.fs.lock().read_dir(path)?;
+        Ok(entries.into_iter().map(|entry| Box::new(entry) as Box<dyn vfs::DirEntry>).collect())
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, VfsError> {
+        if is_root(path) {
+            return Ok(Metadata::DIRECTORY);
+        }
+        Ok(vfs::DirEntry::metadata(&self.fs.lock().stat(path)?))
+    }
+
+    fn create(&self, path: &str) -> Result<Box<dyn vfs::FileHandle>, VfsError> {
+        let mut fs = self.fs.lock();
+        let truncated = fs.truncate(path).map(drop);
+        match truncated {
+            Err(FatError::NotFound) => drop(fs.create(path)?),
+            result => result?,
+        }
+        Ok(Box::new(FatHandle { fs: self.fs.clone(), path: String::from(path), size: 0 }))
+    }
+
+    fn remove(&self, path: &str) -> Result<(), VfsError> {
+        Ok(self.fs.lock().remove(path)?)
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(self.fs.lock().flush()?)
+    }
+}
+
+/// Whether `device` starts with a FAT boot sector, by the checks `mount`
+/// makes. A FAT volume this driver cannot mount still counts, so the
+/// caller gets `Unsupported` from `mount` rather than "not a filesystem".
+pub fn probe(device: &mut impl BlockDevice) -> Result<bool, BlockError> {
+    let mut boot = [0u8; SECTOR_SIZE];
+    device.read_sectors(0, &mut boot)?;
+    Ok(!matches!(Geometry::parse(&boot), Err(FatError::NotFat)))
+}
+
+/// Mounts the FAT volume on `device` at `path` in `mounts`, usually
+/// `vfs::MOUNTS`, behind a block cache.
+pub fn mount_at<D: BlockDevice + Send + 'static>(
+    mounts: &Mutex<MountTable>,
+    device: D,
+    path: &str,
+    source: Option<Source>,
+) -> Result<FatType, VfsError> {
+    let fs = FatFs::mount(BlockCache::new(device, cache::DEFAULT_CAPACITY))?;
+    let kind = fs.fat_type();
+    mounts.lock().mount_from(path, Arc::new(FatVolume::new(fs)), source)?;
+    Ok(kind)
+}
+
+/// An open file as the VFS sees it. It holds only the path and reopens
+/// the file for every call, so it never keeps the volume locked.
+struct FatHandle<D: BlockDevice> {
+    fs: Arc<Mutex<FatFs<D>>>,
+    path: String,
+    size: u32,
+}
+
+impl<D: BlockDevice + Send> vfs::FileHandle for FatHandle<D> {
+    fn size(&self) -> u64 {
+        u64::from(self.size)
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let Ok(offset) = u32::try_from(offset) else {
+            return Ok(0);
+        };
+        Ok(self.fs.lock().open(&self.path)?.read(offset, buf)?)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        let offset = u32::try_from(offset).map_err(|_| FatError::NoSpace)?;
+        let mut fs = self.fs.lock();
+        let mut file = fs.open(&self.path)?;
+        let written = file.write(offset, buf)?;
+        file.flush()?;
+        self.size = file.size();
+        Ok(written)
+    }
+}
+
+impl vfs::DirEntry for DirEntry {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn metadata(&self) -> Metadata {
+        let kind = if self.is_dir() { FileKind::Directory } else { FileKind::File };
+        Metadata { kind, size: u64::from(self.size) }
+    }
+}
+
+//test case
+#[cfg(test)]
+const TEST_TIME: DateTime = DateTime { year: 2024, month: 5, day: 17, hour: 13, minute: 37, second: 42 };
+
+#[cfg(test)]
+fn test_mount(kind: FatType, sectors: u64, root_entries: u16) -> FatFs<testdata::RamDisk> {
+    let mut fs = FatFs::mount(testdata::format(kind, sectors, root_entries)).unwrap();
+    fs.set_clock(|| TEST_TIME);
+    fs
+}
+
+#[cfg(test)]
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+#[test_case]
+fn test_mount_geometry() {
+    let mut fat16 = test_mount(FatType::Fat16, testdata::FAT16_SECTORS, 512);
+    assert_eq!(fat16.fat_type(), FatType::Fat16);
+    let free = fat16.free_clusters().unwrap();
+    assert_eq!(free, fat16.cluster_count());
+    let mut fat32 = test_mount(FatType::Fat32, testdata::FAT32_SECTORS, 0);
+    assert_eq!(fat32.fat_type(), FatType::Fat32);
+    // the root directory takes one cluster, and the FSInfo hint says so
+    assert_eq!(fat32.free_clusters().unwrap(), fat32.cluster_count() - 1);
+
+    let mut disk = testdata::format(FatType::Fat16, testdata::FAT16_SECTORS, 512);
+    disk.patch(0, 510, &[0, 0]);
+    assert_eq!(FatFs::mount(disk).err(), Some(FatError::NotFat));
+    let mut disk = testdata::format(FatType::Fat16, testdata::FAT16_SECTORS, 512);
+    disk.patch(0, 11, &4096u16.to_le_bytes());
+    assert_eq!(FatFs::mount(disk).err(), Some(FatError::Unsupported));
+}
+
+#[test_case]
+fn test_names() {
+    assert_eq!(exact_short_name("README.TXT"), Some((*b"README  TXT", 0)));
+    assert_eq!(exact_short_name("notes.md"), Some((*b"NOTES   MD ", LOWERCASE_BASE | LOWERCASE_EXT)));
+    assert_eq!(exact_short_name("Notes.md"), None);
+    assert_eq!(exact_short_name("a.tar.gz"), None);
+    assert_eq!(exact_short_name("toolongname"), None);
+    assert_eq!(generate_short_name("Long File Name.text", &[]), Ok(*b"LONGFI~1TEX"));
+    assert_eq!(generate_short_name("Long File Name.text", &[*b"LONGFI~1TEX"]), Ok(*b"LONGFI~2TEX"));
+    assert_eq!(generate_short_name("a.tar.gz", &[]), Ok(*b"ATAR~1  GZ "));
+    assert_eq!(generate_short_name(".bashrc", &[]), Ok(*b"BASHRC~1   "));
+    assert_eq!(short_checksum(b"LONGFI~1TEX"), testdata::checksum(b"LONGFI~1TEX"));
+    assert_eq!(validate_name("a/b"), Err(FatError::InvalidName));
+    assert_eq!(validate_name("trailing."), Err(FatError::InvalidName));
+    assert_eq!(split_path("/dir/file.txt"), ("/dir", "file.txt"));
+    assert_eq!(split_path("file.txt"), ("", "file.txt"));
+    assert_eq!(decode_timestamp(encode_timestamp(TEST_TIME).0, encode_timestamp(TEST_TIME).1), TEST_TIME);
+}
+
+#[test_case]
+fn test_create_and_write() {
+    for kind in [FatType::Fat16, FatType::Fat32] {
+        let sectors = if kind == FatType::Fat16 { testdata::FAT16_SECTORS } else { testdata::FAT32_SECTORS };
+        let mut fs = test_mount(kind, sectors, 512);
+        let before = fs.free_clusters().unwrap();
+        let data = pattern(1300, 7);
+        {
+            let mut file = fs.create("/A Long File Name.txt").unwrap();
+            assert_eq!(file.write(0, &data), Ok(data.len()));
+            file.flush().unwrap();
+        }
+        drop(fs.create("short.md").unwrap());
+        assert_eq!(fs.create("/a long file NAME.txt").err(), Some(FatError::AlreadyExists));
+        assert_eq!(fs.free_clusters().unwrap(), before - 3);
+        let mut back = vec![0u8; 2000];
+        assert_eq!(fs.open("/A LONG FILE NAME.TXT").unwrap().read(0, &mut back), Ok(1300));
+        assert_eq!(&back[..1300], &data[..]);
+        let mut disk = fs.unmount().unwrap();
+
+        // an independent parse of the image sees the same thing
+        let image = testdata::Image::parse(&mut disk);
+        let entries = image.root(&mut disk);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "A Long File Name.txt");
+        assert_eq!(&entries[0].short, b"ALONGF~1TXT");
+        assert_eq!(entries[0].size, 1300);
+        assert_eq!((entries[0].date, entries[0].time), encode_timestamp(TEST_TIME));
+        assert_eq!(image.read_file(&mut disk, &entries[0]), data);
+        assert_eq!(entries[1].name, "short.md");
+        assert_eq!(entries[1].long, false);
+        image.check(&mut disk, &entries);
+    }
+}
+
+#[test_case]
+fn test_extend_and_gap() {
+    let mut fs = test_mount(FatType::Fat32, testdata::FAT32_SECTORS, 0);
+    let first = pattern(700, 1);
+    drop(fs.create("grow.bin").unwrap());
+    fs.open("grow.bin").unwrap().write(0, &first).unwrap();
+    // in place, across the end, and past the end leaving a gap
+    {
+        let mut file = fs.open("grow.bin").unwrap();
+        file.write(100, &[0xaa; 10]).unwrap();
+        file.write(690, &[0xbb; 400]).unwrap();
+        file.write(2000, &[0xcc; 3]).unwrap();
+        assert_eq!(file.size(), 2003);
+    }
+    let mut expected = first.clone();
+    expected[100..110].fill(0xaa);
+    expected.resize(690, 0);
+    expected.extend_from_slice(&[0xbb; 400]);
+    expected.resize(2000, 0);
+    expected.extend_from_slice(&[0xcc; 3]);
+    let mut disk = fs.unmount().unwrap();
+    let image = testdata::Image::parse(&mut disk);
+    let entries = image.root(&mut disk);
+    assert_eq!(image.read_file(&mut disk, &entries[0]), expected);
+    image.check(&mut disk, &entries);
+    // the FSInfo hint was kept in step with the FAT
+    assert_eq!(image.fsinfo_free(&mut disk), Some(image.count_free(&mut disk)));
+}
+
+#[test_case]
+fn test_directory_growth() {
+    // 512-byte clusters hold 16 slots, each of these names takes 3
+    let mut fs = test_mount(FatType::Fat32, testdata::FAT32_SECTORS, 0);
+    let names: Vec<String> = (0..12).map(|i| alloc::format!("a rather long name {:02}", i)).collect();
+    for name in &names {
+        drop(fs.create(name).unwrap());
+    }
+    assert_eq!(fs.read_dir("/").unwrap().len(), 12);
+    let mut disk = fs.unmount().unwrap();
+    let image = testdata::Image::parse(&mut disk);
+    let entries = image.root(&mut disk);
+    let parsed: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(parsed, names.iter().map(String::as_str).collect::<Vec<_>>());
+    image.check(&mut disk, &entries);
+
+    // the fixed FAT16 root cannot grow
+    let mut fs = test_mount(FatType::Fat16, testdata::FAT16_SECTORS, 16);
+    for i in 0..16 {
+        drop(fs.create(&alloc::format!("F{}", i)).unwrap());
+    }
+    assert_eq!(fs.create("one more").err(), Some(FatError::NoSpace));
+}
+
+#[test_case]
+fn test_remove_and_truncate() {
+    for kind in [FatType::Fat16, FatType::Fat32] {
+        let sectors = if kind == FatType::Fat16 { testdata::FAT16_SECTORS } else { testdata::FAT32_SECTORS };
+        let mut fs = test_mount(kind, sectors, 512);
+        let before = fs.free_clusters().unwrap();
+        fs.create("/Going Away Soon.txt").unwrap().write(0, &pattern(1500, 2)).unwrap();
+        fs.create("kept.txt").unwrap().write(0, &pattern(600, 4)).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), before - 5);
+
+        fs.remove("/going away soon.txt").unwrap();
+        assert_eq!(fs.open("/Going Away Soon.txt").err(), Some(FatError::NotFound));
+        assert_eq!(fs.remove("/Going Away Soon.txt"), Err(FatError::NotFound));
+        assert_eq!(fs.free_clusters().unwrap(), before - 2);
+        assert_eq!(fs.truncate("kept.txt").unwrap().size(), 0);
+        assert_eq!(fs.free_clusters().unwrap(), before);
+        fs.open("kept.txt").unwrap().write(0, b"again").unwrap();
+        assert_eq!(fs.truncate("missing").err(), Some(FatError::NotFound));
+        // the freed slots are reused
+        drop(fs.create("Another Long Name").unwrap());
+        let mut disk = fs.unmount().unwrap();
+
+        // no long-name entries are left over from the removed file
+        let image = testdata::Image::parse(&mut disk);
+        let entries = image.root(&mut disk);
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["Another Long Name", "kept.txt"]);
+        assert_eq!(image.read_file(&mut disk, &entries[1]), b"again");
+        assert_eq!(image.count_free(&mut disk), before - 1);
+        image.check(&mut disk, &entries);
+    }
+}
+
+/// An append that allocates three clusters and a create with long-name
+/// entries, cut off after every number of sector writes: the image must
+/// show the old state or the new one.
+#[cfg(test)]
+fn crash_at_every_write<D: BlockDevice>(wrap: fn(testdata::RamDisk) -> D, unwrap: fn(D) -> testdata::RamDisk) {
+    let old = pattern(300, 3);
+    let new = pattern(1600, 9);
+    let base = {
+        let mut fs = test_mount(FatType::Fat32, testdata::FAT32_SECTORS, 0);
+        fs.create("log.txt").unwrap().write(0, &old).unwrap();
+        fs.unmount().unwrap()
+    };
+    for limit in 0.. {
+        let mut disk = base.clone();
+        disk.crash_after(limit);
+        let mut fs = FatFs::mount(wrap(disk)).unwrap();
+        fs.set_clock(|| TEST_TIME);
+        fs.open("log.txt").unwrap().write(300, &new[300..]).unwrap();
+        drop(fs.create("A New Long Name.txt").unwrap());
+        let mut disk = unwrap(fs.unmount().unwrap());
+
+        let image = testdata::Image::parse(&mut disk);
+        let entries = image.root(&mut disk);
+        image.check(&mut disk, &entries);
+        let contents = image.read_file(&mut disk, &entries[0]);
+        assert!(contents == old || contents == new, "torn file after {} writes", limit);
+        match entries.get(1) {
+            Some(created) => assert_eq!((created.name.as_str(), created.size), ("A New Long Name.txt", 0)),
+            None => assert_eq!(entries.len(), 1),
+        }
+        if !disk.crashed() {
+            assert_eq!(contents, new);
+            assert_eq!(entries.len(), 2);
+            break;
+        }
+    }
+}
+
+#[test_case]
+fn test_crash_ordering() {
+    crash_at_every_write(|disk| disk, |disk| disk);
+    // a cache small enough to evict in the middle of every step
+    crash_at_every_write(|disk| BlockCache::new(disk, 4), |cache| cache.into_inner().unwrap());
+}
+
+#[test_case]
+fn test_vfs_backend() {
+    let mut fs = test_mount(FatType::Fat16, testdata::FAT16_SECTORS, 512);
+    fs.create("/Hello World.txt").unwrap().write(0, b"hello from fat").unwrap();
+    let volume: Arc<dyn vfs::FileSystem> = Arc::new(FatVolume::new(fs));
+    let mut table = vfs::MountTable::new();
+    table.mount("/disk", volume.clone()).unwrap();
+
+    assert_eq!(volume.kind(), "fat16");
+    assert_eq!(volume.metadata("/"), Ok(Metadata::DIRECTORY));
+    assert_eq!(volume.metadata("/hello world.txt"), Ok(Metadata { kind: FileKind::File, size: 14 }));
+    assert_eq!(volume.metadata("/missing"), Err(VfsError::NotFound));
+    let names: Vec<String> = volume.read_dir("/").unwrap().iter().map(|e| String::from(e.name())).collect();
+    assert_eq!(names, ["Hello World.txt"]);
+
+    let mut file = volume.open("/Hello World.txt").unwrap();
+    let mut buf = [0u8; 32];
+    assert_eq!(file.read(6, &mut buf), Ok(8));
+    assert_eq!(&buf[..8], b"from fat");
+    assert_eq!(file.write(14, b"!"), Ok(1));
+    assert_eq!(file.size(), 15);
+    assert_eq!(volume.open("/nope").err(), Some(VfsError::NotFound));
+
+    // create empties a file that exists
+    let mut file = volume.create("/Hello World.txt").unwrap();
+    assert_eq!(file.size(), 0);
+    assert_eq!(file.write(0, b"new"), Ok(3));
+    assert_eq!(volume.metadata("/hello world.txt").map(|m| m.size), Ok(3));
+    drop(volume.create("/fresh").unwrap());
+    volume.remove("/fresh").unwrap();
+    assert_eq!(volume.remove("/fresh"), Err(VfsError::NotFound));
+    table.unmount("/disk").unwrap();
+}
+
+#[test_case]
+fn test_probe() {
+    let mut fat = testdata::format(FatType::Fat32, testdata::FAT32_SECTORS, 0);
+    assert_eq!(probe(&mut fat), Ok(true));
+    // 4 KiB sectors: FAT, if not one this driver mounts
+    fat.patch(0, 11, &4096u16.to_le_bytes());
+    assert_eq!(probe(&mut fat), Ok(true));
+    assert_eq!(probe(&mut testdata::RamDisk::new(16)), Ok(false));
+}
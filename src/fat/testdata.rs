@@ -0,0 +1,272 @@
+//! A sparse RAM disk, a minimal mkfs and an independent reader for
+//! checking the images the driver leaves behind. The reader shares no code
+//! with the driver: it works on raw sectors and trusts only FAT1.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::block::SECTOR_SIZE;
+use super::FatType;
+
+pub use crate::block::testdata::RamDisk;
+
+/// 4 MiB, about 8000 one-sector clusters: FAT16.
+pub const FAT16_SECTORS: u64 = 8192;
+/// About 69000 one-sector clusters: FAT32.
+pub const FAT32_SECTORS: u64 = 70000;
+
+/// Formats a volume with one-sector clusters and two FATs. `root_entries`
+/// sizes the FAT16 root directory and is ignored for FAT32.
+pub fn format(kind: FatType, sectors: u64, root_entries: u16) -> RamDisk {
+    let mut disk = RamDisk::new(sectors);
+    let fat32 = kind == FatType::Fat32;
+    let entry = if fat32 { 4 } else { 2 };
+    let reserved: u16 = if fat32 { 32 } else { 1 };
+    let root_entries = if fat32 { 0 } else { root_entries };
+    let fat_sectors = ((sectors + 2) * entry).div_ceil(SECTOR_SIZE as u64) as u32;
+
+    disk.patch(0, 0, &[0xeb, 0x58, 0x90]);
+    disk.patch(0, 3, b"BERRYOS ");
+    disk.patch(0, 11, &(SECTOR_SIZE as u16).to_le_bytes());
+    disk.patch(0, 13, &[1]);
+    disk.patch(0, 14, &reserved.to_le_bytes());
+    disk.patch(0, 16, &[2]);
+    disk.patch(0, 17, &root_entries.to_le_bytes());
+    disk.patch(0, 21, &[0xf8]);
+    disk.patch(0, 32, &(sectors as u32).to_le_bytes());
+    if fat32 {
+        disk.patch(0, 36, &fat_sectors.to_le_bytes());
+        disk.patch(0, 44, &2u32.to_le_bytes());
+        disk.patch(0, 48, &1u16.to_le_bytes());
+        disk.patch(0, 82, b"FAT32   ");
+    } else {
+        disk.patch(0, 22, &(fat_sectors as u16).to_le_bytes());
+        disk.patch(0, 54, b"FAT16   ");
+    }
+    disk.patch(0, 510, &[0x55, 0xaa]);
+
+    let media: &[u8] = if fat32 {
+        // entries 0 and 1, then the end of the root directory's chain
+        &[0xf8, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f]
+    } else {
+        &[0xf8, 0xff, 0xff, 0xff]
+    };
+    for copy in 0..2 {
+        disk.patch(u64::from(reserved) + copy * u64::from(fat_sectors), 0, media);
+    }
+    if fat32 {
+        let data_start = u64::from(reserved) + 2 * u64::from(fat_sectors);
+        let clusters = (sectors - data_start) as u32;
+        disk.patch(1, 0, &0x4161_5252u32.to_le_bytes());
+        disk.patch(1, 484, &0x6141_7272u32.to_le_bytes());
+        disk.patch(1, 488, &(clusters - 1).to_le_bytes());
+        disk.patch(1, 492, &3u32.to_le_bytes());
+        disk.patch(1, 508, &0xaa55_0000u32.to_le_bytes());
+    }
+    disk
+}
+
+pub fn checksum(short: &[u8; 11]) -> u8 {
+    let mut sum = 0u8;
+    for &c in short {
+        sum = sum.rotate_right(1).wrapping_add(c);
+    }
+    sum
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub name: String,
+    /// Whether the name came from long-name entries.
+    pub long: bool,
+    pub short: [u8; 11],
+    pub cluster: u32,
+    pub size: u32,
+    pub date: u16,
+    pub time: u16,
+}
+
+pub struct Image {
+    fat32: bool,
+    fat_start: u64,
+    fat_sectors: u64,
+    root_start: u64,
+    root_sectors: u64,
+    data_start: u64,
+    clusters: u32,
+    root_cluster: u32,
+}
+
+fn u16_at(sector: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([sector[offset], sector[offset + 1]])
+}
+
+fn u32_at(sector: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([sector[offset], sector[offset + 1], sector[offset + 2], sector[offset + 3]])
+}
+
+impl Image {
+    pub fn parse(disk: &mut RamDisk) -> Image {
+        let boot = disk.sector(0);
+        let fat16_size = u64::from(u16_at(&boot, 22));
+        let fat32 = fat16_size == 0;
+        let fat_sectors = if fat32 { u64::from(u32_at(&boot, 36)) } else { fat16_size };
+        let fat_start = u64::from(u16_at(&boot, 14));
+        let root_start = fat_start + 2 * fat_sectors;
+        let root_sectors = u64::from(u16_at(&boot, 17)) * 32 / 512;
+        let data_start = root_start + root_sectors;
+        Image {
+            fat32,
+            fat_start,
+            fat_sectors,
+            root_start,
+            root_sectors,
+            data_start,
+            clusters: (u64::from(u32_at(&boot, 32)) - data_start) as u32,
+            root_cluster: if fat32 { u32_at(&boot, 44) } else { 0 },
+        }
+    }
+
+    fn fat(&self, disk: &RamDisk, copy: u64, cluster: u32) -> u32 {
+        let bytes = if self.fat32 { 4 } else { 2 };
+        let offset = u64::from(cluster) * bytes;
+        let sector = disk.sector(self.fat_start + copy * self.fat_sectors + offset / 512);
+        let at = (offset % 512) as usize;
+        if self.fat32 { u32_at(&sector, at) & 0x0fff_ffff } else { u32::from(u16_at(&sector, at)) }
+    }
+
+    /// Follows a chain in FAT1, panicking on anything out of range.
+    pub fn chain(&self, disk: &RamDisk, first: u32) -> Vec<u32> {
+        let end = if self.fat32 { 0x0fff_fff8 } else { 0xfff8 };
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while first != 0 && cluster < end {
+            assert!((2..self.clusters + 2).contains(&cluster), "chain from {} reaches {}", first, cluster);
+            assert!(chain.len() < self.clusters as usize, "chain from {} loops", first);
+            chain.push(cluster);
+            cluster = self.fat(disk, 0, cluster);
+        }
+        chain
+    }
+
+    pub fn root(&self, disk: &mut RamDisk) -> Vec<Entry> {
+        let sectors: Vec<u64> = if self.fat32 {
+            self.chain(disk, self.root_cluster).iter().map(|&c| self.data_start + u64::from(c) - 2).collect()
+        } else {
+            (self.root_start..self.root_start + self.root_sectors).collect()
+        };
+        let mut entries = Vec::new();
+        let mut long: Vec<[u8; 32]> = Vec::new();
+        for lba in sectors {
+            let sector = disk.sector(lba);
+            for raw in sector.chunks_exact(32) {
+                if raw[0] == 0 {
+                    return entries;
+                }
+                let mut entry = [0u8; 32];
+                entry.copy_from_slice(raw);
+                if raw[0] == 0xe5 {
+                    long.clear();
+                } else if raw[11] == 0x0f {
+                    long.push(entry);
+                } else {
+                    let mut short = [0u8; 11];
+                    short.copy_from_slice(&raw[..11]);
+                    let name = long_name(&long, &short);
+                    long.clear();
+                    entries.push(Entry {
+                        long: name.is_some(),
+                        name: name.unwrap_or_else(|| short_name(&short, raw[12])),
+                        short,
+                        cluster: (u32::from(u16_at(raw, 20)) << 16) | u32::from(u16_at(raw, 26)),
+                        size: u32_at(raw, 28),
+                        date: u16_at(raw, 24),
+                        time: u16_at(raw, 22),
+                    });
+                }
+            }
+        }
+        entries
+    }
+
+    pub fn read_file(&self, disk: &mut RamDisk, entry: &Entry) -> Vec<u8> {
+        let chain = self.chain(disk, entry.cluster);
+        assert!(chain.len() * 512 >= entry.size as usize, "{} is longer than its chain", entry.name);
+        let mut data = Vec::new();
+        for cluster in chain {
+            data.extend_from_slice(&disk.sector(self.data_start + u64::from(cluster) - 2));
+        }
+        data.truncate(entry.size as usize);
+        data
+    }
+
+    /// No cluster belongs to two chains, and when nothing was cut off the
+    /// FAT copies agree.
+    pub fn check(&self, disk: &mut RamDisk, entries: &[Entry]) {
+        let mut used = self.chain(disk, self.root_cluster);
+        for entry in entries {
+            used.extend(self.chain(disk, entry.cluster));
+        }
+        let count = used.len();
+        used.sort_unstable();
+        used.dedup();
+        assert_eq!(used.len(), count, "cross-linked clusters");
+        if !disk.crashed() {
+            for i in 0..self.fat_sectors {
+                assert!(disk.sector(self.fat_start + i) == disk.sector(self.fat_start + self.fat_sectors + i));
+            }
+        }
+    }
+
+    pub fn count_free(&self, disk: &mut RamDisk) -> u32 {
+        let bytes = if self.fat32 { 4 } else { 2 };
+        let mut free = 0;
+        for i in 0..self.fat_sectors {
+            let sector = disk.sector(self.fat_start + i);
+            for (j, entry) in sector.chunks_exact(bytes).enumerate() {
+                let cluster = i as u32 * (512 / bytes as u32) + j as u32;
+                let value = if self.fat32 { u32_at(entry, 0) & 0x0fff_ffff } else { u32::from(u16_at(entry, 0)) };
+                if (2..self.clusters + 2).contains(&cluster) && value == 0 {
+                    free += 1;
+                }
+            }
+        }
+        free
+    }
+
+    pub fn fsinfo_free(&self, disk: &mut RamDisk) -> Option<u32> {
+        let info = disk.sector(1);
+        (self.fat32 && u32_at(&info, 0) == 0x4161_5252).then(|| u32_at(&info, 488))
+    }
+}
+
+fn long_name(parts: &[[u8; 32]], short: &[u8; 11]) -> Option<String> {
+    let sum = checksum(short);
+    if parts.is_empty() || parts.iter().any(|part| part[13] != sum) || parts[0][0] & 0x40 == 0 {
+        return None;
+    }
+    let mut units = Vec::new();
+    // on disk the last part comes first
+    for part in parts.iter().rev() {
+        for range in [1..11, 14..26, 28..32] {
+            units.extend(part[range].chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])));
+        }
+    }
+    let len = units.iter().position(|&u| u == 0).unwrap_or(units.len());
+    String::from_utf16(&units[..len]).ok()
+}
+
+fn short_name(short: &[u8; 11], case: u8) -> String {
+    let mut name = String::new();
+    for &b in short[..8].iter().filter(|&&b| b != b' ') {
+        let c = char::from(b);
+        name.push(if case & 0x08 != 0 { c.to_ascii_lowercase() } else { c });
+    }
+    if short[8] != b' ' {
+        name.push('.');
+        for &b in short[8..].iter().filter(|&&b| b != b' ') {
+            let c = char::from(b);
+            name.push(if case & 0x10 != 0 { c.to_ascii_lowercase() } else { c });
+        }
+    }
+    name
+}
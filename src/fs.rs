@@ -0,0 +1,279 @@
+//! A flat in-memory filesystem, for files the shell makes up as it goes.
+//!
+//! `RAMFS` maps names to their contents; there are no directories, and
+//! everything is gone on reboot. `RamMount` shows it through the VFS (the
+//! kernel mounts it at `/ram`), so `ls` and `cat` see it like any other
+//! mount, but writing only goes through this module.
+//!
+//! `devfs` is the other in-memory filesystem: device files under `/dev`.
+//! `tarfs` is the initrd, read-only, mounted at `/` under everything else.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use crate::vfs::{self, FileKind, Metadata, VfsError};
+
+pub mod devfs;
+pub mod tarfs;
+
+/// The longest name a file can have, in bytes.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Where the kernel mounts `RAMFS`.
+pub const MOUNT_POINT: &str = "/ram";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NameTooLong,
+    /// The name is empty or has a space or a `/` in it.
+    InvalidName,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            FsError::NotFound => "no such file",
+            FsError::AlreadyExists => "file exists",
+            FsError::NameTooLong => "file name too long",
+            FsError::InvalidName => "invalid file name",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl From<FsError> for VfsError {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound => VfsError::NotFound,
+            _ => VfsError::InvalidPath,
+        }
+    }
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.len() > MAX_NAME_LEN {
+        Err(FsError::NameTooLong)
+    } else if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '/') {
+        Err(FsError::InvalidName)
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RamFs {
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl RamFs {
+    pub const fn new() -> Self {
+        RamFs { files: BTreeMap::new() }
+    }
+
+    /// Makes an empty file.
+    pub fn create(&mut self, name: &str) -> Result<(), FsError> {
+        check_name(name)?;
+        if self.files.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        self.files.insert(name.to_string(), Vec::new());
+        Ok(())
+    }
+
+    /// Replaces the file's contents with `data`, or adds `data` to the end
+    /// if `append` is set; the file is created if it is not there.
+    pub fn write(&mut self, name: &str, data: &[u8], append: bool) -> Result<(), FsError> {
+        check_name(name)?;
+        let contents = self.files.entry(name.to_string()).or_default();
+        if !append {
+            contents.clear();
+        }
+        contents.extend_from_slice(data);
+        Ok(())
+    }
+
+    pub fn read(&self, name: &str) -> Option<&[u8]> {
+        self.files.get(name).map(Vec::as_slice)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), FsError> {
+        self.files.remove(name).map(drop).ok_or(FsError::NotFound)
+    }
+
+    /// Every file's name and size, by name.
+    pub fn list(&self) -> Vec<(String, usize)> {
+        self.files.iter().map(|(name, data)| (name.clone(), data.len())).collect()
+    }
+}
+
+pub static RAMFS: Mutex<RamFs> = Mutex::new(RamFs::new());
+
+/// A file in `RAMFS` opened for writing, for the shell's `>` and `>>`:
+/// every write goes at the end, whatever its offset.
+struct RamWriter(String);
+
+impl vfs::FileHandle for RamWriter {
+    fn size(&self) -> u64 {
+        RAMFS.lock().read(&self.0).map_or(0, |data| data.len() as u64)
+    }
+
+    fn read(&mut self, _offset: u64, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::WriteOnly)
+    }
+
+    fn write(&mut self, _offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        RAMFS.lock().write(&self.0, buf, true)?;
+        Ok(buf.len())
+    }
+}
+
+/// Opens the file `name` in `RAMFS` for writing, creating it, and
+/// emptying it unless `append` is set.
+pub fn open_writer(name: &str, append: bool) -> Result<Box<dyn vfs::FileHandle>, FsError> {
+    RAMFS.lock().write(name, &[], append)?;
+    Ok(Box::new(RamWriter(name.to_string())))
+}
+
+/// `RAMFS` as a read-only VFS mount.
+pub struct RamMount;
+
+/// A copy of the file taken when it was opened.
+struct RamFile(Vec<u8>);
+
+impl vfs::FileHandle for RamFile {
+    fn size(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let rest = usize::try_from(offset).ok().and_then(|offset| self.0.get(offset..)).unwrap_or(&[]);
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        Ok(n)
+    }
+}
+
+struct RamDirEntry {
+    name: String,
+    size: usize,
+}
+
+impl vfs::DirEntry for RamDirEntry {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata { kind: FileKind::File, size: self.size as u64 }
+    }
+}
+
+impl vfs::FileSystem for RamMount {
+    fn kind(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn vfs::FileHandle>, VfsError> {
+        match path.strip_prefix('/') {
+            Some("") => Err(VfsError::IsADirectory),
+            Some(name) => {
+                let data = RAMFS.lock().read(name).ok_or(VfsError::NotFound)?.to_vec();
+                Ok(Box::new(RamFile(data)))
+            }
+            None => Err(VfsError::InvalidPath),
+        }
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<Box<dyn vfs::DirEntry>>, VfsError> {
+        if path != "/" {
+            return Err(self.metadata(path).map_or_else(|err| err, |_| VfsError::NotADirectory));
+        }
+        Ok(RAMFS.lock().list().into_iter()
+            .map(|(name, size)| Box::new(RamDirEntry { name, size }) as Box<dyn vfs::DirEntry>)
+            .collect())
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, VfsError> {
+        match path.strip_prefix('/') {
+            Some("") => Ok(Metadata::DIRECTORY),
+            Some(name) => {
+                let size = RAMFS.lock().read(name).ok_or(VfsError::NotFound)?.len();
+                Ok(Metadata { kind: FileKind::File, size: size as u64 })
+            }
+            None => Err(VfsError::InvalidPath),
+        }
+    }
+}
+
+/// Mounts `RAMFS` at `MOUNT_POINT`.
+pub fn mount() -> Result<(), VfsError> {
+    vfs::mount(MOUNT_POINT, alloc::sync::Arc::new(RamMount))
+}
+
+//test case
+#[test_case]
+fn test_round_trip() {
+    let mut fs = RamFs::new();
+    fs.create("notes").unwrap();
+    assert_eq!(fs.read("notes"), Some(&[][..]));
+    assert_eq!(fs.create("notes"), Err(FsError::AlreadyExists));
+    fs.write("notes", b"hello", false).unwrap();
+    assert_eq!(fs.read("notes"), Some(&b"hello"[..]));
+    // write makes the file if it has to
+    fs.write("other", b"x", false).unwrap();
+    assert_eq!(fs.list(), [("notes".to_string(), 5), ("other".to_string(), 1)]);
+    assert_eq!(fs.read("missing"), None);
+}
+
+#[test_case]
+fn test_overwrite_and_append() {
+    let mut fs = RamFs::new();
+    fs.write("log", b"one", false).unwrap();
+    fs.write("log", b" two", true).unwrap();
+    assert_eq!(fs.read("log"), Some(&b"one two"[..]));
+    fs.write("log", b"three", false).unwrap();
+    assert_eq!(fs.read("log"), Some(&b"three"[..]));
+    fs.write("fresh", b"abc", true).unwrap();
+    assert_eq!(fs.read("fresh"), Some(&b"abc"[..]));
+}
+
+#[test_case]
+fn test_remove() {
+    let mut fs = RamFs::new();
+    fs.write("gone", b"soon", false).unwrap();
+    assert_eq!(fs.remove("gone"), Ok(()));
+    assert_eq!(fs.read("gone"), None);
+    assert_eq!(fs.remove("gone"), Err(FsError::NotFound));
+    assert!(fs.list().is_empty());
+}
+
+#[test_case]
+fn test_names() {
+    let mut fs = RamFs::new();
+    let long = "n".repeat(MAX_NAME_LEN);
+    assert_eq!(fs.create(&long), Ok(()));
+    assert_eq!(fs.create(&(long + "n")), Err(FsError::NameTooLong));
+    for bad in ["", "two words", "dir/file", "tab\there"] {
+        assert_eq!(fs.write(bad, b"", false), Err(FsError::InvalidName), "{:?}", bad);
+    }
+}
+
+#[test_case]
+fn test_mounted_view() {
+    use crate::vfs::FileSystem;
+    RAMFS.lock().write("test-mounted", b"seen", false).unwrap();
+    let mut file = RamMount.open("/test-mounted").unwrap();
+    let mut buf = [0; 8];
+    assert_eq!(file.read(0, &mut buf), Ok(4));
+    assert_eq!(&buf[..4], b"seen");
+    assert_eq!(RamMount.metadata("/").map(|m| m.is_dir()), Ok(true));
+    assert!(RamMount.read_dir("/").unwrap().iter().any(|entry| entry.name() == "test-mounted"));
+    assert_eq!(RamMount.read_dir("/test-mounted").err(), Some(VfsError::NotADirectory));
+    assert_eq!(RamMount.open("/missing").err(), Some(VfsError::NotFound));
+    RAMFS.lock().remove("test-mounted").unwrap();
+}
@@ -0,0 +1,226 @@
+//! Device files. A `CharDevice` is something bytes go to and come from
+//! with no size and no position, and `DevFs` gives each one a name, so
+//! the VFS (the kernel mounts it at `/dev`) reads and writes it like any
+//! file: `write /dev/serial hello` goes out of COM1.
+//!
+//! The standard nodes are `null`, which takes everything and reads as
+//! empty, `zero`, which reads as zeros, `tty`, the screen (VGA text or the
+//! framebuffer console, whatever `print!` would draw on) and `serial`,
+//! COM1. Reads never wait: the terminals only hand out bytes while they
+//! are in raw mode, see `console::raw`, and a read with nothing queued
+//! returns 0.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::console::{self, Mode, Terminal};
+use crate::vfs::{self, FileKind, Metadata, VfsError};
+
+/// Where the kernel mounts the standard devices.
+pub const MOUNT_POINT: &str = "/dev";
+
+pub trait CharDevice: Sync {
+    /// Fills the start of `buf` with what the device has, returning how
+    /// many bytes that was.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError>;
+
+    /// Returns how many bytes of `buf` the device took.
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError>;
+}
+
+/// `/dev/null`.
+pub struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError> {
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/zero`; what is written to it is dropped.
+pub struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError> {
+        Ok(buf.len())
+    }
+}
+
+/// What `terminal` queued in raw mode, and nothing in cooked mode, where
+/// its lines belong to the shell.
+fn read_terminal(terminal: Terminal, buf: &mut [u8]) -> usize {
+    if console::mode(terminal) != Mode::Raw {
+        return 0;
+    }
+    let mut n = 0;
+    while n < buf.len() {
+        match console::read_raw(terminal) {
+            Some(byte) => buf[n] = byte,
+            None => break,
+        }
+        n += 1;
+    }
+    n
+}
+
+/// `/dev/tty`: writes go to the screen only, reads come from the keyboard.
+pub struct Tty;
+
+impl CharDevice for Tty {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(read_terminal(Terminal::Keyboard, buf))
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError> {
+        crate::vga_buffer::write_screen(&String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/serial`: COM1, with the same line ends as `serial_print!`.
+pub struct Serial;
+
+impl CharDevice for Serial {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(read_terminal(Terminal::Serial, buf))
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError> {
+        crate::serial::_print(format_args!("{}", String::from_utf8_lossy(buf)));
+        Ok(buf.len())
+    }
+}
+
+/// The nodes `mount` puts in `/dev`.
+pub static STANDARD: [(&str, &dyn CharDevice); 4] = [("null", &Null), ("zero", &Zero), ("tty", &Tty), ("serial", &Serial)];
+
+/// Named devices, as a filesystem with one flat directory.
+pub struct DevFs {
+    devices: Vec<(&'static str, &'static dyn CharDevice)>,
+}
+
+impl DevFs {
+    pub fn new(devices: &[(&'static str, &'static dyn CharDevice)]) -> DevFs {
+        DevFs { devices: Vec::from(devices) }
+    }
+
+    fn find(&self, path: &str) -> Result<&'static dyn CharDevice, VfsError> {
+        match path.strip_prefix('/') {
+            Some("") => Err(VfsError::IsADirectory),
+            Some(name) => self.devices.iter().find(|(node, _)| *node == name).map(|&(_, device)| device).ok_or(VfsError::NotFound),
+            None => Err(VfsError::InvalidPath),
+        }
+    }
+}
+
+/// An open device. Offsets mean nothing to it, and its size is 0.
+struct DeviceFile(&'static dyn CharDevice);
+
+impl vfs::FileHandle for DeviceFile {
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn read(&mut self, _offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        self.0.read(buf)
+    }
+
+    fn write(&mut self, _offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        self.0.write(buf)
+    }
+}
+
+struct DeviceEntry(&'static str);
+
+impl vfs::DirEntry for DeviceEntry {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata::DEVICE
+    }
+}
+
+impl vfs::FileSystem for DevFs {
+    fn kind(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn vfs::FileHandle>, VfsError> {
+        Ok(Box::new(DeviceFile(self.find(path)?)))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<Box<dyn vfs::DirEntry>>, VfsError> {
+        if path != "/" {
+            return Err(self.metadata(path).map_or_else(|err| err, |_| VfsError::NotADirectory));
+        }
+        Ok(self.devices.iter().map(|&(name, _)| Box::new(DeviceEntry(name)) as Box<dyn vfs::DirEntry>).collect())
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, VfsError> {
+        match self.find(path) {
+            Ok(_) => Ok(Metadata::DEVICE),
+            Err(VfsError::IsADirectory) => Ok(Metadata::DIRECTORY),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// A device has nothing to empty, so this opens it; there is no
+    /// making new ones.
+    fn create(&self, path: &str) -> Result<Box<dyn vfs::FileHandle>, VfsError> {
+        match self.open(path) {
+            Err(VfsError::NotFound) => Err(VfsError::ReadOnly),
+            result => result,
+        }
+    }
+}
+
+/// Mounts the `STANDARD` devices at `MOUNT_POINT`.
+pub fn mount() -> Result<(), VfsError> {
+    vfs::mount(MOUNT_POINT, alloc::sync::Arc::new(DevFs::new(&STANDARD)))
+}
+
+//test case
+#[test_case]
+fn test_null_and_zero() {
+    let mut buf = [7u8; 16];
+    assert_eq!(Null.read(&mut buf), Ok(0));
+    assert_eq!(buf, [7; 16]);
+    assert_eq!(Null.write(b"gone"), Ok(4));
+    assert_eq!(Zero.read(&mut buf), Ok(16));
+    assert_eq!(buf, [0; 16]);
+    assert_eq!(Zero.write(b"gone too"), Ok(8));
+    // cooked terminals keep their input for the shell
+    assert_eq!(Tty.read(&mut buf), Ok(0));
+}
+
+#[test_case]
+fn test_devices_through_the_vfs() {
+    use alloc::sync::Arc;
+    use spin::Mutex;
+
+    let mounts = Mutex::new(vfs::MountTable::new());
+    mounts.lock().mount(MOUNT_POINT, Arc::new(DevFs::new(&STANDARD))).unwrap();
+    let mut zero = vfs::open_in(&mounts, "/dev/zero").unwrap();
+    let mut buf = [1u8; 4];
+    assert_eq!((zero.size(), zero.read(1234, &mut buf)), (0, Ok(4)));
+    assert_eq!(buf, [0; 4]);
+    // creating an existing node opens it, so `write` works on it
+    let mut null = vfs::create_in(&mounts, "/dev/null").unwrap();
+    assert_eq!(null.write(0, b"discarded"), Ok(9));
+    assert_eq!(vfs::create_in(&mounts, "/dev/new").err(), Some(VfsError::ReadOnly));
+    assert_eq!(vfs::open_in(&mounts, "/dev/missing").err(), Some(VfsError::NotFound));
+    assert_eq!(vfs::metadata_in(&mounts, "/dev/tty"), Ok(Metadata::DEVICE));
+    assert_eq!(vfs::metadata_in(&mounts, "/dev"), Ok(Metadata::DIRECTORY));
+    assert_eq!(vfs::remove_in(&mounts, "/dev/null"), Err(VfsError::ReadOnly));
+}
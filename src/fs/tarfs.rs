@@ -0,0 +1,170 @@
+//! The initrd as a read-only filesystem. The bootloader loads a ustar
+//! archive next to the kernel (see `BootInfo::ramdisk`), and `TarFs`
+//! shows it through the VFS; the kernel mounts it at `/`, so whatever the
+//! archive ships (configuration, `/bin/...`) is where its paths say, and
+//! the other mounts (`/ram`, `/dev`, `/disk`) sit on top of it.
+//!
+//! Nothing is copied: files are read straight out of the archive with
+//! `tar::TarArchive`, and directories need no entry of their own, any
+//! path with files below it is one. Writing fails with `ReadOnly`.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::tar::{self, EntryKind, TarArchive, TarEntry};
+use crate::vfs::{self, FileKind, Metadata, VfsError};
+
+/// Where the kernel mounts the initrd.
+pub const MOUNT_POINT: &str = "/";
+
+pub struct TarFs {
+    archive: TarArchive<'static>,
+}
+
+impl TarFs {
+    pub fn new(data: &'static [u8]) -> Self {
+        TarFs { archive: TarArchive::new(data) }
+    }
+}
+
+/// Mounts the archive in `data` at `MOUNT_POINT`.
+pub fn mount(data: &'static [u8]) -> Result<(), VfsError> {
+    vfs::mount(MOUNT_POINT, Arc::new(TarFs::new(data)))
+}
+
+struct TarFile {
+    data: &'static [u8],
+}
+
+impl vfs::FileHandle for TarFile {
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let rest = usize::try_from(offset).ok().and_then(|offset| self.data.get(offset..)).unwrap_or(&[]);
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        Ok(n)
+    }
+}
+
+struct TarDirEntry {
+    name: String,
+    metadata: Metadata,
+}
+
+impl vfs::DirEntry for TarDirEntry {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.metadata
+    }
+}
+
+fn entry_metadata(entry: &TarEntry) -> Metadata {
+    match entry.kind {
+        EntryKind::Directory => Metadata::DIRECTORY,
+        _ => Metadata { kind: FileKind::File, size: entry.size as u64 },
+    }
+}
+
+impl vfs::FileSystem for TarFs {
+    fn kind(&self) -> &'static str {
+        "tarfs"
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn vfs::FileHandle>, VfsError> {
+        match self.metadata(path)? {
+            metadata if metadata.is_dir() => Err(VfsError::IsADirectory),
+            _ => Ok(Box::new(TarFile { data: self.archive.find(path).ok_or(VfsError::NotFound)? })),
+        }
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<Box<dyn vfs::DirEntry>>, VfsError> {
+        if !self.metadata(path)?.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        let dir = tar::clean(path);
+        let mut entries: Vec<TarDirEntry> = Vec::new();
+        for entry in self.archive.entries() {
+            let entry = entry?;
+            let rest = if dir.is_empty() { Some(entry.path.as_str()) } else { entry.path.strip_prefix(dir).and_then(|rest| rest.strip_prefix('/')) };
+            let Some(rest) = rest.filter(|rest| !rest.is_empty()) else {
+                continue;
+            };
+            let (name, metadata) = match rest.split_once('/') {
+                Some((name, _)) => (name, Metadata::DIRECTORY),
+                None => (rest, entry_metadata(&entry)),
+            };
+            // a later entry for the same name wins, as when extracting
+            match entries.iter_mut().find(|existing| existing.name == name) {
+                Some(existing) => existing.metadata = metadata,
+                None => entries.push(TarDirEntry { name: String::from(name), metadata }),
+            }
+        }
+        Ok(entries.into_iter().map(|entry| Box::new(entry) as Box<dyn vfs::DirEntry>).collect())
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, VfsError> {
+        let path = tar::clean(path);
+        if path.is_empty() {
+            return Ok(Metadata::DIRECTORY);
+        }
+        let mut found = None;
+        for entry in self.archive.entries() {
+            let entry = entry?;
+            if entry.path == path {
+                found = Some(entry_metadata(&entry));
+            } else if found.is_none() && entry.path.strip_prefix(path).is_some_and(|rest| rest.starts_with('/')) {
+                found = Some(Metadata::DIRECTORY);
+            }
+        }
+        found.ok_or(VfsError::NotFound)
+    }
+}
+
+#[cfg(test)]
+fn leaked(files: &[(&str, u8, &[u8])]) -> TarFs {
+    TarFs::new(Vec::leak(tar::testdata::build(files)))
+}
+
+//test case
+#[test_case]
+fn test_backend() {
+    use vfs::FileSystem;
+    let fs = leaked(&[
+        ("bin/init", b'0', b"\x7fELF"),
+        ("etc/", b'5', b""),
+        ("etc/motd", b'0', b"hi"),
+        ("README", b'0', b"readme"),
+    ]);
+    let names = |path: &str| -> Vec<(String, bool)> {
+        fs.read_dir(path).unwrap().iter().map(|e| (String::from(e.name()), e.metadata().is_dir())).collect()
+    };
+    assert_eq!(names("/"), [(String::from("bin"), true), (String::from("etc"), true), (String::from("README"), false)]);
+    assert_eq!(names("/etc"), [(String::from("motd"), false)]);
+    assert_eq!(fs.metadata("/bin"), Ok(Metadata::DIRECTORY));
+    assert_eq!(fs.metadata("/etc/motd"), Ok(Metadata { kind: FileKind::File, size: 2 }));
+    assert_eq!(fs.metadata("/nope"), Err(VfsError::NotFound));
+    assert_eq!(fs.read_dir("/README").err(), Some(VfsError::NotADirectory));
+    assert!(matches!(fs.open("/etc"), Err(VfsError::IsADirectory)));
+    let mut file = fs.open("/README").unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(file.read(2, &mut buf), Ok(4));
+    assert_eq!(&buf[..4], b"adme");
+    assert!(matches!(fs.create("/new"), Err(VfsError::ReadOnly)));
+}
+
+#[test_case]
+fn test_mounted_at_the_root() {
+    let mounts = spin::Mutex::new(vfs::MountTable::new());
+    let fs = leaked(&[("etc/motd", b'0', b"hi"), ("dev/", b'5', b"")]);
+    mounts.lock().mount(MOUNT_POINT, Arc::new(fs)).unwrap();
+    assert_eq!(vfs::metadata_in(&mounts, "/etc/motd"), Ok(Metadata { kind: FileKind::File, size: 2 }));
+    assert_eq!(vfs::metadata_in(&mounts, "/etc/../etc"), Ok(Metadata::DIRECTORY));
+    assert_eq!(vfs::metadata_in(&mounts, "/missing"), Err(VfsError::NotFound));
+}
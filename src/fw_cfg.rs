@@ -0,0 +1,72 @@
+//! QEMU firmware configuration device (fw_cfg), legacy I/O port interface.
+//!
+//! Only used to read small named blobs passed with
+//! `-fw_cfg name=opt/...,string=...` or `file=...`.
+
+use x86_64::instructions::port::Port;
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+const SELECT_SIGNATURE: u16 = 0x0000;
+const SELECT_FILE_DIR: u16 = 0x0019;
+
+const FILE_NAME_LEN: usize = 56;
+
+fn select(key: u16) {
+    unsafe { Port::<u16>::new(SELECTOR_PORT).write(key) };
+}
+
+fn read_bytes(buf: &mut [u8]) {
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    for byte in buf {
+        *byte = unsafe { data.read() };
+    }
+}
+
+fn read_be_u32() -> u32 {
+    let mut raw = [0; 4];
+    read_bytes(&mut raw);
+    u32::from_be_bytes(raw)
+}
+
+/// Whether a fw_cfg device answers. Reading an unclaimed port returns 0xff,
+/// so this is safe on machines without one.
+pub fn is_present() -> bool {
+    select(SELECT_SIGNATURE);
+    let mut signature = [0; 4];
+    read_bytes(&mut signature);
+    &signature == b"QEMU"
+}
+
+/// Looks `name` up in the file directory. Returns (selector, size).
+pub fn find_file(name: &str) -> Option<(u16, usize)> {
+    if !is_present() {
+        return None;
+    }
+    select(SELECT_FILE_DIR);
+    let count = read_be_u32();
+    for _ in 0..count {
+        let size = read_be_u32();
+        let mut entry = [0; 4];
+        read_bytes(&mut entry);
+        let selector = u16::from_be_bytes([entry[0], entry[1]]);
+        let mut file_name = [0; FILE_NAME_LEN];
+        read_bytes(&mut file_name);
+        let len = file_name.iter().position(|&b| b == 0).unwrap_or(FILE_NAME_LEN);
+        if &file_name[..len] == name.as_bytes() {
+            return Some((selector, size as usize));
+        }
+    }
+    None
+}
+
+/// Reads the start of file `name` into `buf`. Returns the number of bytes
+/// copied, which is less than the file size if `buf` is too small.
+pub fn read_file(name: &str, buf: &mut [u8]) -> Option<usize> {
+    let (selector, size) = find_file(name)?;
+    let len = size.min(buf.len());
+    select(selector);
+    read_bytes(&mut buf[..len]);
+    Some(len)
+}
@@ -0,0 +1,788 @@
+//! A GDB remote stub on COM2, so the kernel can be debugged live without
+//! QEMU's own stub (or on a machine that has none):
+//!
+//! ```text
+//! qemu-system-x86_64 ... -serial stdio -serial tcp::1234,server,nowait \
+//!     -fw_cfg name=opt/berryos/cmdline,string='gdb=wait'
+//! gdb target/x86_64-berryos/debug/tutorial_os -ex 'target remote :1234'
+//! ```
+//!
+//! With `gdb` on the command line, `#BP` and `#DB` come here, and so does
+//! every fatal exception before the kernel halts; `gdb=wait` also stops
+//! at the end of `init`, so breakpoints can be set before the kernel runs
+//! on. Ctrl+C in GDB arrives as a byte on IRQ 3, which stops the kernel
+//! inside that interrupt.
+//!
+//! While stopped, the CPU that trapped serves packets with interrupts off,
+//! polling the UART; other CPUs keep running. Registers are the ones the
+//! exception stub saved, in GDB's amd64 order (no FPU or SSE state).
+//! Memory goes through the mapping of all physical memory, so software
+//! breakpoints can be written into the read-only kernel code. A stop at
+//! one of them is reported with `swbreak`, RIP already moved back onto
+//! the `int3`.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+use crate::interrupts::ExceptionFrame;
+
+pub const COM2: u16 = 0x2F8;
+pub const IRQ: u8 = 3;
+/// The most data bytes in a packet, either way.
+pub const PACKET_SIZE: usize = 1024;
+pub const MAX_BREAKPOINTS: usize = 32;
+/// Registers in a `g` packet: 17 of 64 bits (RAX..R15 and RIP), then
+/// EFLAGS and the six segment selectors, of 32.
+pub const REGISTERS: usize = 24;
+
+pub const SIGINT: u8 = 2;
+pub const SIGILL: u8 = 4;
+pub const SIGTRAP: u8 = 5;
+pub const SIGFPE: u8 = 8;
+pub const SIGSEGV: u8 = 11;
+
+const INT3: u8 = 0xcc;
+const TRAP_FLAG: u64 = 1 << 8;
+/// What GDB sends for Ctrl+C, outside a packet.
+const INTERRUPT: u8 = 0x03;
+const SEND_RETRIES: usize = 8;
+
+// error replies carry an errno, in hex
+const EFAULT: &[u8] = b"E0e";
+const EINVAL: &[u8] = b"E16";
+const ENOSPC: &[u8] = b"E1c";
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static INTERRUPT_REQUESTED: AtomicBool = AtomicBool::new(false);
+static LAST_SIGNAL: AtomicU8 = AtomicU8::new(SIGTRAP);
+static LAST_SWBREAK: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Breakpoint {
+    addr: u64,
+    /// The byte the `int3` replaced.
+    original: u8,
+}
+
+static BREAKPOINTS: Mutex<[Option<Breakpoint>; MAX_BREAKPOINTS]> = Mutex::new([None; MAX_BREAKPOINTS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointError {
+    NotMapped(u64),
+    /// All `MAX_BREAKPOINTS` are set.
+    Full,
+    NotSet(u64),
+}
+
+impl fmt::Display for BreakpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakpointError::NotMapped(addr) => write!(f, "{:#x} is not mapped", addr),
+            BreakpointError::Full => write!(f, "all {} breakpoints are set", MAX_BREAKPOINTS),
+            BreakpointError::NotSet(addr) => write!(f, "no breakpoint at {:#x}", addr),
+        }
+    }
+}
+
+/// Sets up COM2 and starts taking exceptions, if the command line has
+/// `gdb`. Goes after `console::init`, with the PICs set up.
+pub fn init() {
+    if !crate::cmdline::flag("gdb") {
+        return;
+    }
+    unsafe { uart_16550::SerialPort::new(COM2) }.init();
+    ACTIVE.store(true, Ordering::Release);
+    crate::interrupts::register_irq(IRQ, interrupt);
+    crate::println!("gdb: stub listening on COM2");
+    if crate::cmdline::get("gdb") == Some("wait") {
+        crate::println!("gdb: waiting for the debugger");
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+/// Whether `init` found `gdb` on the command line.
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// IRQ 3: GDB's Ctrl+C. The `int3` stops the kernel right here, and
+/// `trap` reports it as SIGINT.
+fn interrupt() {
+    while let Some(byte) = try_read_byte() {
+        if byte == INTERRUPT {
+            INTERRUPT_REQUESTED.store(true, Ordering::Release);
+            x86_64::instructions::interrupts::int3();
+        }
+    }
+}
+
+/// The signal GDB is told a fatal exception (by its report name) stands
+/// for.
+pub fn signal_for(exception: &str) -> u8 {
+    match exception {
+        "DIVIDE ERROR" | "x87 FLOATING POINT" | "SIMD FLOATING POINT" => SIGFPE,
+        "INVALID OPCODE" => SIGILL,
+        _ => SIGSEGV,
+    }
+}
+
+/// Lets the debugger look at a fatal exception before the kernel halts.
+/// Whatever it changes, the kernel still halts afterwards.
+pub fn exception(name: &str, frame: &mut ExceptionFrame) {
+    if active() {
+        trap(signal_for(name), frame);
+    }
+}
+
+/// Tells the debugger the kernel stopped with `signal` and serves its
+/// packets until it resumes. Runs in exception context, with interrupts
+/// off; a step comes back through `#DB`.
+pub fn trap(signal: u8, frame: &mut ExceptionFrame) {
+    frame.stack_frame.cpu_flags &= !TRAP_FLAG;
+    let signal = if INTERRUPT_REQUESTED.swap(false, Ordering::AcqRel) { SIGINT } else { signal };
+    let rip = frame.stack_frame.instruction_pointer.as_u64();
+    let swbreak = signal == SIGTRAP && is_breakpoint(rip.wrapping_sub(1));
+    if swbreak {
+        // run the original instruction once the debugger resumes
+        frame.stack_frame.instruction_pointer = VirtAddr::new(rip - 1);
+    }
+    LAST_SIGNAL.store(signal, Ordering::Relaxed);
+    LAST_SWBREAK.store(swbreak, Ordering::Relaxed);
+    let mut reply = Reply::new();
+    stop_reply(signal, swbreak, &mut reply);
+    send(reply.as_bytes());
+    let mut packet = [0u8; PACKET_SIZE];
+    loop {
+        let len = receive(&mut packet);
+        let mut reply = Reply::new();
+        match handle(&packet[..len], frame, &mut reply) {
+            Action::Reply => send(reply.as_bytes()),
+            Action::Resume => return,
+            Action::Detach => {
+                send(reply.as_bytes());
+                return;
+            }
+        }
+    }
+}
+
+fn stop_reply(signal: u8, swbreak: bool, reply: &mut Reply) {
+    if swbreak {
+        reply.push(b"T");
+        reply.hex(u64::from(signal), 1);
+        reply.push(b"swbreak:;");
+    } else {
+        reply.push(b"S");
+        reply.hex(u64::from(signal), 1);
+    }
+}
+
+// ==========================================================
+// UART
+// ==========================================================
+
+const DATA_READY: u8 = 1 << 0;
+const HOLDING_EMPTY: u8 = 1 << 5;
+
+fn try_read_byte() -> Option<u8> {
+    let mut line_status: Port<u8> = Port::new(COM2 + 5);
+    let mut data: Port<u8> = Port::new(COM2);
+    unsafe {
+        if line_status.read() & DATA_READY != 0 {
+            Some(data.read())
+        } else {
+            None
+        }
+    }
+}
+
+fn read_byte() -> u8 {
+    loop {
+        if let Some(byte) = try_read_byte() {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn write_byte(byte: u8) {
+    let mut line_status: Port<u8> = Port::new(COM2 + 5);
+    let mut data: Port<u8> = Port::new(COM2);
+    unsafe {
+        while line_status.read() & HOLDING_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        data.write(byte);
+    }
+}
+
+// ==========================================================
+// PACKETS
+// ==========================================================
+
+/// The modulo-256 sum after the `#` of `$data#xx`.
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Waits for a packet with a good checksum, acks it and leaves its data
+/// in `buf`. Returns how long it is. Bytes between packets (acks, a late
+/// Ctrl+C) are dropped; a bad checksum or an overlong packet gets a `-`,
+/// and GDB sends it again.
+fn receive(buf: &mut [u8; PACKET_SIZE]) -> usize {
+    loop {
+        while read_byte() != b'$' {}
+        let mut len = 0;
+        let mut overflow = false;
+        loop {
+            match read_byte() {
+                b'#' => break,
+                // a new packet: the last one was cut short
+                b'$' => {
+                    len = 0;
+                    overflow = false;
+                }
+                byte if len < PACKET_SIZE => {
+                    buf[len] = byte;
+                    len += 1;
+                }
+                _ => overflow = true,
+            }
+        }
+        let sum = [read_byte(), read_byte()];
+        if !overflow && parse_hex(&sum) == Some(u64::from(checksum(&buf[..len]))) {
+            write_byte(b'+');
+            return len;
+        }
+        write_byte(b'-');
+    }
+}
+
+/// Sends `$data#xx` until GDB acks it, or gives up after `SEND_RETRIES`.
+fn send(data: &[u8]) {
+    for _ in 0..SEND_RETRIES {
+        write_byte(b'$');
+        data.iter().for_each(|&byte| write_byte(byte));
+        write_byte(b'#');
+        let sum = checksum(data);
+        write_byte(hex_digit(sum >> 4));
+        write_byte(hex_digit(sum & 0xf));
+        loop {
+            match read_byte() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A reply being built, cut short at `PACKET_SIZE`. On the stack: the
+/// heap's lock may be held by whatever stopped.
+pub struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    pub fn new() -> Reply {
+        Reply { buf: [0; PACKET_SIZE], len: 0 }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(PACKET_SIZE - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// The low `bytes` bytes of `value`, little-endian, two digits each:
+    /// how registers and memory go over the wire.
+    fn hex(&mut self, value: u64, bytes: usize) {
+        for byte in value.to_le_bytes().iter().take(bytes) {
+            self.push(&[hex_digit(byte >> 4), hex_digit(byte & 0xf)]);
+        }
+    }
+
+    /// `value` as a number, most significant digit first, as in
+    /// `PacketSize=`.
+    fn number(&mut self, value: u64) {
+        let digits = (64 - (value | 1).leading_zeros()).div_ceil(4);
+        for i in (0..digits).rev() {
+            self.push(&[hex_digit((value >> (4 * i)) as u8 & 0xf)]);
+        }
+    }
+}
+
+impl Default for Reply {
+    fn default() -> Reply {
+        Reply::new()
+    }
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[usize::from(value & 0xf)]
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// A number in hex, most significant digit first: addresses, lengths and
+/// register numbers.
+pub fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, &digit| Some(value << 4 | u64::from(hex_value(digit)?)))
+}
+
+/// Little-endian bytes in hex, two digits each, as registers come.
+fn parse_le(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || !digits.len().is_multiple_of(2) || digits.len() > 16 {
+        return None;
+    }
+    let mut value = 0;
+    for (i, pair) in digits.chunks(2).enumerate() {
+        value |= parse_hex(pair)? << (8 * i);
+    }
+    Some(value)
+}
+
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..at], &bytes[at + 1..]))
+}
+
+// ==========================================================
+// COMMANDS
+// ==========================================================
+
+/// What `trap` does after a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Send the reply and wait for the next packet.
+    Reply,
+    /// Return from the exception, without a reply.
+    Resume,
+    /// Send the reply, then return.
+    Detach,
+}
+
+/// Runs one packet against the stopped `frame`, writing the reply.
+/// Anything not understood gets the empty reply, which GDB takes as
+/// "not supported".
+pub fn handle(packet: &[u8], frame: &mut ExceptionFrame, reply: &mut Reply) -> Action {
+    let Some((&command, args)) = packet.split_first() else {
+        return Action::Reply;
+    };
+    match command {
+        b'?' => stop_reply(LAST_SIGNAL.load(Ordering::Relaxed), LAST_SWBREAK.load(Ordering::Relaxed), reply),
+        b'g' => {
+            for (n, value) in registers(frame).iter().enumerate() {
+                reply.hex(*value, register_size(n));
+            }
+        }
+        b'G' => reply.push(write_registers(frame, args).map_or(EINVAL, |()| b"OK")),
+        b'p' => match parse_hex(args).map(|n| n as usize).filter(|&n| n < REGISTERS) {
+            Some(n) => reply.hex(registers(frame)[n], register_size(n)),
+            None => reply.push(EINVAL),
+        },
+        b'P' => {
+            let written = split(args, b'=').and_then(|(n, value)| {
+                let n = parse_hex(n)? as usize;
+                (n < REGISTERS && value.len() == 2 * register_size(n)).then_some(())?;
+                set_register(frame, n, parse_le(value)?).then_some(())
+            });
+            reply.push(written.map_or(EINVAL, |()| b"OK"));
+        }
+        b'm' => read_memory_command(args, reply),
+        b'M' => reply.push(write_memory_command(args)),
+        b'c' | b's' => {
+            if !args.is_empty() {
+                match parse_hex(args).and_then(|addr| VirtAddr::try_new(addr).ok()) {
+                    Some(addr) => frame.stack_frame.instruction_pointer = addr,
+                    None => {
+                        reply.push(EINVAL);
+                        return Action::Reply;
+                    }
+                }
+            }
+            if command == b's' {
+                frame.stack_frame.cpu_flags |= TRAP_FLAG;
+            }
+            return Action::Resume;
+        }
+        b'Z' | b'z' => breakpoint_command(command == b'Z', args, reply),
+        b'q' => query(args, reply),
+        // one thread, whatever is selected
+        b'H' | b'T' => reply.push(b"OK"),
+        b'D' => {
+            remove_all_breakpoints();
+            reply.push(b"OK");
+            return Action::Detach;
+        }
+        b'k' => {
+            remove_all_breakpoints();
+            return Action::Resume;
+        }
+        _ => {}
+    }
+    Action::Reply
+}
+
+fn query(args: &[u8], reply: &mut Reply) {
+    if args.starts_with(b"Supported") {
+        reply.push(b"PacketSize=");
+        reply.number(PACKET_SIZE as u64);
+        reply.push(b";swbreak+");
+    } else if args == b"Attached" {
+        reply.push(b"1");
+    } else if args == b"C" {
+        reply.push(b"QC1");
+    } else if args == b"fThreadInfo" {
+        reply.push(b"m1");
+    } else if args == b"sThreadInfo" {
+        reply.push(b"l");
+    }
+}
+
+fn register_size(n: usize) -> usize {
+    if n < 17 { 8 } else { 4 }
+}
+
+/// The registers of `frame` in GDB's amd64 order. The data segment
+/// registers are the live ones: the stubs leave them alone.
+pub fn registers(frame: &ExceptionFrame) -> [u64; REGISTERS] {
+    use x86_64::registers::segmentation::{Segment, DS, ES, FS, GS};
+    let r = &frame.registers;
+    let cpu = &frame.stack_frame;
+    [
+        r.rax, r.rbx, r.rcx, r.rdx, r.rsi, r.rdi, r.rbp, cpu.stack_pointer.as_u64(),
+        r.r8, r.r9, r.r10, r.r11, r.r12, r.r13, r.r14, r.r15,
+        cpu.instruction_pointer.as_u64(), cpu.cpu_flags, cpu.code_segment, cpu.stack_segment,
+        u64::from(DS::get_reg().0), u64::from(ES::get_reg().0),
+        u64::from(FS::get_reg().0), u64::from(GS::get_reg().0),
+    ]
+}
+
+/// Sets register `n` (GDB's number) for when `frame` returns. Writes to
+/// the segment registers are accepted and dropped; `false` for a
+/// non-canonical RIP or RSP and numbers past `REGISTERS`.
+pub fn set_register(frame: &mut ExceptionFrame, n: usize, value: u64) -> bool {
+    let r = &mut frame.registers;
+    let cpu = &mut frame.stack_frame;
+    let slot = match n {
+        0 => &mut r.rax,
+        1 => &mut r.rbx,
+        2 => &mut r.rcx,
+        3 => &mut r.rdx,
+        4 => &mut r.rsi,
+        5 => &mut r.rdi,
+        6 => &mut r.rbp,
+        8 => &mut r.r8,
+        9 => &mut r.r9,
+        10 => &mut r.r10,
+        11 => &mut r.r11,
+        12 => &mut r.r12,
+        13 => &mut r.r13,
+        14 => &mut r.r14,
+        15 => &mut r.r15,
+        17 => &mut cpu.cpu_flags,
+        7 | 16 => {
+            let Ok(addr) = VirtAddr::try_new(value) else {
+                return false;
+            };
+            if n == 7 {
+                cpu.stack_pointer = addr;
+            } else {
+                cpu.instruction_pointer = addr;
+            }
+            return true;
+        }
+        18..REGISTERS => return true,
+        _ => return false,
+    };
+    *slot = value;
+    true
+}
+
+/// `G`: every register, as `g` sends them.
+fn write_registers(frame: &mut ExceptionFrame, mut digits: &[u8]) -> Option<()> {
+    let mut values = registers(frame);
+    for (n, value) in values.iter_mut().enumerate() {
+        let len = 2 * register_size(n);
+        if digits.len() < len {
+            break;
+        }
+        *value = parse_le(&digits[..len])?;
+        digits = &digits[len..];
+    }
+    for (n, &value) in values.iter().enumerate() {
+        set_register(frame, n, value).then_some(())?;
+    }
+    Some(())
+}
+
+// ==========================================================
+// MEMORY
+// ==========================================================
+
+/// Where `addr` lies in the mapping of all physical memory, if it is
+/// mapped at all.
+fn physical(addr: u64) -> Option<*mut u8> {
+    let addr = VirtAddr::try_new(addr).ok()?;
+    let offset = crate::memory::physical_memory_offset()?;
+    let phys = unsafe { crate::memory::translate_addr(addr, offset) }?;
+    Some((offset + phys.as_u64()).as_mut_ptr())
+}
+
+/// Copies `buf.len()` bytes from `addr`. `None` if some are not mapped.
+pub fn read_memory(addr: u64, buf: &mut [u8]) -> Option<()> {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = unsafe { physical(addr.checked_add(i as u64)?)?.read_volatile() };
+    }
+    Some(())
+}
+
+/// Writes `bytes` at `addr`, even over read-only pages. Stops at the
+/// first byte that is not mapped.
+pub fn write_memory(addr: u64, bytes: &[u8]) -> Option<()> {
+    for (i, &byte) in bytes.iter().enumerate() {
+        unsafe { physical(addr.checked_add(i as u64)?)?.write_volatile(byte) };
+    }
+    Some(())
+}
+
+/// `m addr,length`
+fn read_memory_command(args: &[u8], reply: &mut Reply) {
+    let Some((addr, len)) = split(args, b',').and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?))) else {
+        reply.push(EINVAL);
+        return;
+    };
+    let mut chunk = [0u8; 64];
+    let mut left = len.min(PACKET_SIZE as u64 / 2);
+    let mut at = addr;
+    while left > 0 {
+        let chunk = &mut chunk[..left.min(64) as usize];
+        if read_memory(at, chunk).is_none() {
+            // GDB wants an error, not the bytes up to the hole
+            reply.clear();
+            reply.push(EFAULT);
+            return;
+        }
+        chunk.iter().for_each(|&byte| reply.hex(u64::from(byte), 1));
+        at += chunk.len() as u64;
+        left -= chunk.len() as u64;
+    }
+}
+
+/// `M addr,length:data`
+fn write_memory_command(args: &[u8]) -> &'static [u8] {
+    let parsed = split(args, b':').and_then(|(place, data)| {
+        let (addr, len) = split(place, b',')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+        (data.len() as u64 == 2 * len).then_some((addr, data))
+    });
+    let Some((addr, data)) = parsed else {
+        return EINVAL;
+    };
+    for (i, pair) in data.chunks(2).enumerate() {
+        let Some(byte) = parse_hex(pair) else {
+            return EINVAL;
+        };
+        if write_memory(addr + i as u64, &[byte as u8]).is_none() {
+            return EFAULT;
+        }
+    }
+    b"OK"
+}
+
+// ==========================================================
+// SOFTWARE BREAKPOINTS
+// ==========================================================
+
+/// Writes an `int3` at `addr`. Setting one twice is not an error.
+pub fn insert_breakpoint(addr: u64) -> Result<(), BreakpointError> {
+    let mut breakpoints = BREAKPOINTS.lock();
+    if breakpoints.iter().flatten().any(|breakpoint| breakpoint.addr == addr) {
+        return Ok(());
+    }
+    let slot = breakpoints.iter_mut().find(|slot| slot.is_none()).ok_or(BreakpointError::Full)?;
+    let mut original = [0];
+    read_memory(addr, &mut original).ok_or(BreakpointError::NotMapped(addr))?;
+    write_memory(addr, &[INT3]).ok_or(BreakpointError::NotMapped(addr))?;
+    *slot = Some(Breakpoint { addr, original: original[0] });
+    Ok(())
+}
+
+/// Puts back the byte the `int3` at `addr` replaced.
+pub fn remove_breakpoint(addr: u64) -> Result<(), BreakpointError> {
+    let mut breakpoints = BREAKPOINTS.lock();
+    let slot = breakpoints
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|breakpoint| breakpoint.addr == addr))
+        .ok_or(BreakpointError::NotSet(addr))?;
+    if let Some(breakpoint) = slot.take() {
+        // unmapped since: nothing to put back
+        let _ = write_memory(addr, &[breakpoint.original]);
+    }
+    Ok(())
+}
+
+pub fn remove_all_breakpoints() {
+    let addrs = BREAKPOINTS.lock().map(|slot| slot.map(|breakpoint| breakpoint.addr));
+    for addr in addrs.into_iter().flatten() {
+        let _ = remove_breakpoint(addr);
+    }
+}
+
+pub fn is_breakpoint(addr: u64) -> bool {
+    BREAKPOINTS.lock().iter().flatten().any(|breakpoint| breakpoint.addr == addr)
+}
+
+/// `Z0,addr,kind` and `z0,addr,kind`; other kinds (hardware breakpoints,
+/// watchpoints) are not supported.
+fn breakpoint_command(insert: bool, args: &[u8], reply: &mut Reply) {
+    let Some(rest) = args.strip_prefix(b"0,") else {
+        return;
+    };
+    let Some(addr) = split(rest, b',').and_then(|(addr, _kind)| parse_hex(addr)) else {
+        reply.push(EINVAL);
+        return;
+    };
+    let result = if insert { insert_breakpoint(addr) } else { remove_breakpoint(addr) };
+    reply.push(match result {
+        Ok(()) => b"OK",
+        Err(BreakpointError::NotMapped(_)) => EFAULT,
+        Err(BreakpointError::Full) => ENOSPC,
+        Err(BreakpointError::NotSet(_)) => EINVAL,
+    });
+}
+
+//test case
+#[cfg(test)]
+fn frame() -> alloc::boxed::Box<ExceptionFrame> {
+    // every field is plain data, and all zeros is a valid frame
+    let mut frame: alloc::boxed::Box<ExceptionFrame> = unsafe { alloc::boxed::Box::new_zeroed().assume_init() };
+    frame.stack_frame.instruction_pointer = VirtAddr::new(0x20_1000);
+    frame.stack_frame.code_segment = 8;
+    frame
+}
+
+#[cfg(test)]
+fn run(packet: &[u8], frame: &mut ExceptionFrame) -> (Action, alloc::vec::Vec<u8>) {
+    let mut reply = Reply::new();
+    let action = handle(packet, frame, &mut reply);
+    (action, reply.as_bytes().to_vec())
+}
+
+#[test_case]
+fn test_checksum_and_hex() {
+    // what GDB sends first after `?`
+    assert_eq!(checksum(b"g"), 0x67);
+    assert_eq!(checksum(b"OK"), 0x9a);
+    assert_eq!(parse_hex(b"ffffffff80001000"), Some(0xffff_ffff_8000_1000));
+    assert_eq!(parse_hex(b"1A"), Some(0x1a));
+    assert_eq!(parse_hex(b""), None);
+    assert_eq!(parse_hex(b"12g"), None);
+    assert_eq!(parse_le(b"3412"), Some(0x1234));
+    assert_eq!(parse_le(b"341"), None);
+    let mut reply = Reply::new();
+    reply.number(0x400);
+    reply.push(b",");
+    reply.hex(0x1234, 2);
+    assert_eq!(reply.as_bytes(), b"400,3412");
+}
+
+#[test_case]
+fn test_register_packets() {
+    let mut frame = frame();
+    frame.registers.rax = 0x1122_3344_5566_7788;
+    frame.stack_frame.cpu_flags = 0x202;
+    let (action, reply) = run(b"g", &mut frame);
+    assert_eq!(action, Action::Reply);
+    assert_eq!(reply.len(), 17 * 16 + 7 * 8);
+    assert!(reply.starts_with(b"8877665544332211"));
+    // RIP, then EFLAGS in 32 bits
+    assert_eq!(&reply[16 * 16..17 * 16 + 8], b"001020000000000002020000");
+
+    assert_eq!(run(b"P10=0020200000000000", &mut frame).1, b"OK");
+    assert_eq!(frame.stack_frame.instruction_pointer.as_u64(), 0x20_2000);
+    assert_eq!(run(b"p10", &mut frame).1, b"0020200000000000");
+    // not canonical
+    assert_eq!(run(b"P10=0000000000800000", &mut frame).1, EINVAL);
+    assert_eq!(run(b"p30", &mut frame).1, EINVAL);
+
+    // `G` with what `g` sent, RBX changed
+    let mut registers = run(b"g", &mut frame).1;
+    registers[16..32].copy_from_slice(b"0100000000000000");
+    let mut packet = alloc::vec![b'G'];
+    packet.extend_from_slice(&registers);
+    assert_eq!(run(&packet, &mut frame).1, b"OK");
+    assert_eq!(frame.registers.rbx, 1);
+    assert_eq!(frame.registers.rax, 0x1122_3344_5566_7788);
+}
+
+#[test_case]
+fn test_resume_packets() {
+    let mut frame = frame();
+    assert_eq!(run(b"s", &mut frame).0, Action::Resume);
+    assert_ne!(frame.stack_frame.cpu_flags & TRAP_FLAG, 0);
+    frame.stack_frame.cpu_flags &= !TRAP_FLAG;
+    assert_eq!(run(b"c201800", &mut frame).0, Action::Resume);
+    assert_eq!(frame.stack_frame.cpu_flags & TRAP_FLAG, 0);
+    assert_eq!(frame.stack_frame.instruction_pointer.as_u64(), 0x20_1800);
+    assert_eq!(run(b"qSupported:multiprocess+;swbreak+", &mut frame).1, b"PacketSize=400;swbreak+");
+    assert_eq!(run(b"vMustReplyEmpty", &mut frame), (Action::Reply, alloc::vec::Vec::new()));
+    assert_eq!(run(b"D", &mut frame), (Action::Detach, b"OK".to_vec()));
+}
+
+#[test_case]
+fn test_memory_packets() {
+    let mut frame = frame();
+    let mut buffer = alloc::vec![0x5au8; 8];
+    let addr = buffer.as_mut_ptr() as u64;
+    let (_, reply) = run(alloc::format!("m{:x},4", addr).as_bytes(), &mut frame);
+    assert_eq!(reply, b"5a5a5a5a");
+    let (_, reply) = run(alloc::format!("M{:x},2:0102", addr + 1).as_bytes(), &mut frame);
+    assert_eq!(reply, b"OK");
+    assert_eq!(buffer[..4], [0x5a, 1, 2, 0x5a]);
+    assert_eq!(run(alloc::format!("M{:x},2:01", addr).as_bytes(), &mut frame).1, EINVAL);
+    // the null page is never mapped
+    assert_eq!(run(b"m0,4", &mut frame).1, EFAULT);
+}
+
+#[test_case]
+fn test_software_breakpoints() {
+    let mut frame = frame();
+    let mut code = alloc::vec![0x90u8; 4];
+    let addr = code.as_mut_ptr() as u64;
+    assert_eq!(run(alloc::format!("Z0,{:x},1", addr + 2).as_bytes(), &mut frame).1, b"OK");
+    assert!(is_breakpoint(addr + 2));
+    assert_eq!(code, [0x90, 0x90, INT3, 0x90]);
+    // again: still one breakpoint, with the right byte to put back
+    assert_eq!(insert_breakpoint(addr + 2), Ok(()));
+    assert_eq!(run(alloc::format!("z0,{:x},1", addr + 2).as_bytes(), &mut frame).1, b"OK");
+    assert_eq!(code, [0x90; 4]);
+    assert_eq!(remove_breakpoint(addr + 2), Err(BreakpointError::NotSet(addr + 2)));
+    assert_eq!(insert_breakpoint(0), Err(BreakpointError::NotMapped(0)));
+    // hardware breakpoints are not supported
+    assert_eq!(run(alloc::format!("Z1,{:x},1", addr).as_bytes(), &mut frame).1, b"");
+    assert_eq!(signal_for("INVALID OPCODE"), SIGILL);
+    assert_eq!(signal_for("PAGE FAULT"), SIGSEGV);
+}
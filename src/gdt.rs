@@ -1,46 +1,127 @@
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
+use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use lazy_static::lazy_static;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// An NMI can arrive anywhere, even at the start of `syscall_entry` while
+/// RSP is still the program's, so it gets a stack of its own too.
+pub const NMI_IST_INDEX: u16 = 1;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK );
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+/// A plain `static mut` rather than a `lazy_static`, because
+/// `set_kernel_stack` rewrites RSP0 before every switch to ring 3.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+fn tss() -> *mut TaskStateSegment {
+    &raw mut TSS
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
+        // kernel data, user data, user code: the order SYSRET expects
+        gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss() }));
+        (gdt, Selectors { code_selector, tss_selector, user_code_selector, user_data_selector })
     };
 }
 
 struct Selectors {
-    code_selector: x86_64::structures::gdt::SegmentSelector,
-    tss_selector: x86_64::structures::gdt::SegmentSelector,
+    code_selector: SegmentSelector,
+    tss_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
 }
 
 pub fn init() {
     use x86_64::instructions::segmentation::{CS, Segment};
     use x86_64::instructions::tables::load_tss;
 
+    const STACK_SIZE: usize = 4096 * 5;
+    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    static mut NMI_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    let stack_end = VirtAddr::from_ptr(&raw const STACK) + STACK_SIZE;
+    let nmi_stack_end = VirtAddr::from_ptr(&raw const NMI_STACK) + STACK_SIZE;
+    unsafe {
+        (*tss()).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end;
+        (*tss()).interrupt_stack_table[NMI_IST_INDEX as usize] = nmi_stack_end;
+    }
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
-}
\ No newline at end of file
+}
+
+/// Code and stack selectors for ring 3, with RPL 3.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+/// IA32_STAR for this GDT. SYSCALL takes CS from bits 32-47 and SS from
+/// the entry after it; SYSRET takes SS from 8 and CS from 16 past bits
+/// 48-63, so user data has to sit between kernel data and user code.
+pub fn star() -> u64 {
+    let (kernel_code, user_data, user_code) =
+        (GDT.1.code_selector.0, GDT.1.user_data_selector.0, GDT.1.user_code_selector.0);
+    assert_eq!(user_code, user_data + 8, "GDT is not in SYSRET order");
+    u64::from(user_data - 8) << 48 | u64::from(kernel_code) << 32
+}
+
+/// Sets RSP0, the stack the CPU switches to when an interrupt or a system
+/// call arrives from ring 3 on the bootstrap processor.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe {
+        (*tss()).privilege_stack_table[0] = top;
+    }
+}
+
+/// GDT and TSS for an application processor. Every CPU needs its own TSS:
+/// the descriptor's busy bit is per table, and IST stacks can't be shared.
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
+}
+
+impl CpuTables {
+    /// Builds the tables on the heap. They are leaked, since the CPU keeps
+    /// using them until reset.
+    pub fn allocate() -> &'static CpuTables {
+        use alloc::boxed::Box;
+        use alloc::vec;
+
+        const STACK_SIZE: usize = 4096 * 5;
+        let stack = Box::leak(vec![0u8; STACK_SIZE].into_boxed_slice());
+        let nmi_stack = Box::leak(vec![0u8; STACK_SIZE].into_boxed_slice());
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            VirtAddr::from_ptr(stack.as_ptr()) + STACK_SIZE;
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = VirtAddr::from_ptr(nmi_stack.as_ptr()) + STACK_SIZE;
+        let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+        let mut gdt = GlobalDescriptorTable::new();
+        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+        let selectors = Selectors { code_selector, tss_selector, user_code_selector, user_data_selector };
+        Box::leak(Box::new(CpuTables { gdt, selectors }))
+    }
+
+    /// Loads the tables on the calling CPU.
+    pub fn load(&'static self) {
+        use x86_64::instructions::segmentation::{CS, Segment};
+        use x86_64::instructions::tables::load_tss;
+
+        self.gdt.load();
+        unsafe {
+            CS::set_reg(self.selectors.code_selector);
+            load_tss(self.selectors.tss_selector);
+        }
+    }
+}
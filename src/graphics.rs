@@ -0,0 +1,332 @@
+//! Drawing on the linear framebuffer the loader set up, for machines that
+//! boot without VGA text mode (UEFI, or GRUB asked for a graphics mode).
+//!
+//! `Canvas` is a framebuffer as bytes plus its layout: pixels, lines,
+//! rectangles and text in the `font`, clipped at the edges. Pixels are
+//! `Rgb` and get packed into whatever format the mode uses: 32-bit RGB or
+//! BGR, or one byte of gray. `console` is a text console on top of it
+//! that `print!` writes to once `init` finds a framebuffer; the legacy
+//! `vga_buffer` stays in use when there is none.
+//!
+//! The `bootloader` 0.9 path always leaves the machine in text mode; to
+//! try this, boot through GRUB (see `multiboot2`) with `set
+//! gfxpayload=1024x768x32` in place of `set gfxpayload=text`.
+
+pub mod console;
+mod font;
+
+use core::fmt;
+use x86_64::PhysAddr;
+use crate::boot::{FrameBufferInfo, PixelFormat};
+use crate::memory::vmm::{self, VmmError};
+use crate::vga_buffer::Color;
+
+/// Pixels a character takes.
+pub const CHAR_WIDTH: usize = 8;
+pub const CHAR_HEIGHT: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+
+    /// What the VGA palette shows for a text mode color.
+    pub fn from_color(color: Color) -> Rgb {
+        const PALETTE: [Rgb; 16] = [
+            Rgb::new(0x00, 0x00, 0x00), Rgb::new(0x00, 0x00, 0xaa), Rgb::new(0x00, 0xaa, 0x00), Rgb::new(0x00, 0xaa, 0xaa),
+            Rgb::new(0xaa, 0x00, 0x00), Rgb::new(0xaa, 0x00, 0xaa), Rgb::new(0xaa, 0x55, 0x00), Rgb::new(0xaa, 0xaa, 0xaa),
+            Rgb::new(0x55, 0x55, 0x55), Rgb::new(0x55, 0x55, 0xff), Rgb::new(0x55, 0xff, 0x55), Rgb::new(0x55, 0xff, 0xff),
+            Rgb::new(0xff, 0x55, 0x55), Rgb::new(0xff, 0x55, 0xff), Rgb::new(0xff, 0xff, 0x55), Rgb::new(0xff, 0xff, 0xff),
+        ];
+        PALETTE[color as usize]
+    }
+
+    /// The gray a one-byte mode shows for it.
+    fn luma(self) -> u8 {
+        ((u32::from(self.r) * 299 + u32::from(self.g) * 587 + u32::from(self.b) * 114) / 1000) as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphicsError {
+    /// A pixel layout `Canvas` can't pack colors into.
+    UnsupportedFormat(PixelFormat, usize),
+    /// The buffer is shorter than the mode's lines need.
+    TooSmall,
+    Map(VmmError),
+}
+
+impl fmt::Display for GraphicsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphicsError::UnsupportedFormat(format, bytes) => {
+                write!(f, "unsupported pixel format {:?} with {} bytes per pixel", format, bytes)
+            }
+            GraphicsError::TooSmall => f.write_str("framebuffer smaller than its mode"),
+            GraphicsError::Map(err) => write!(f, "mapping the framebuffer failed: {}", err),
+        }
+    }
+}
+
+/// A framebuffer to draw on. Coordinates are pixels from the top left;
+/// anything outside the mode is left out.
+pub struct Canvas<'a> {
+    bytes: &'a mut [u8],
+    width: usize,
+    height: usize,
+    /// Pixels from one line to the next.
+    stride: usize,
+    format: PixelFormat,
+    bytes_per_pixel: usize,
+}
+
+impl<'a> Canvas<'a> {
+    pub fn new(bytes: &'a mut [u8], info: &FrameBufferInfo) -> Result<Canvas<'a>, GraphicsError> {
+        match (info.pixel_format, info.bytes_per_pixel) {
+            (PixelFormat::Rgb | PixelFormat::Bgr, 3 | 4) | (PixelFormat::U8, 1) => {}
+            (format, bytes) => return Err(GraphicsError::UnsupportedFormat(format, bytes)),
+        }
+        if info.stride < info.width || bytes.len() < info.stride * info.height * info.bytes_per_pixel {
+            return Err(GraphicsError::TooSmall);
+        }
+        Ok(Canvas {
+            bytes,
+            width: info.width,
+            height: info.height,
+            stride: info.stride,
+            format: info.pixel_format,
+            bytes_per_pixel: info.bytes_per_pixel,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn offset(&self, x: usize, y: usize) -> Option<usize> {
+        (x < self.width && y < self.height).then(|| (y * self.stride + x) * self.bytes_per_pixel)
+    }
+
+    fn pack(&self, color: Rgb) -> [u8; 4] {
+        match self.format {
+            PixelFormat::Rgb => [color.r, color.g, color.b, 0],
+            PixelFormat::Bgr => [color.b, color.g, color.r, 0],
+            _ => [color.luma(), 0, 0, 0],
+        }
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if let Some(offset) = self.offset(x, y) {
+            let pixel = self.pack(color);
+            self.bytes[offset..offset + self.bytes_per_pixel].copy_from_slice(&pixel[..self.bytes_per_pixel]);
+        }
+    }
+
+    /// The color at `x`, `y`, as read back from the buffer. One-byte modes
+    /// give the gray.
+    pub fn pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        let offset = self.offset(x, y)?;
+        let bytes = &self.bytes[offset..offset + self.bytes_per_pixel];
+        Some(match self.format {
+            PixelFormat::Rgb => Rgb::new(bytes[0], bytes[1], bytes[2]),
+            PixelFormat::Bgr => Rgb::new(bytes[2], bytes[1], bytes[0]),
+            _ => Rgb::new(bytes[0], bytes[0], bytes[0]),
+        })
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let (right, bottom) = (x.saturating_add(width).min(self.width), y.saturating_add(height).min(self.height));
+        if x >= right || y >= bottom {
+            return;
+        }
+        let pixel = self.pack(color);
+        let pixel = &pixel[..self.bytes_per_pixel];
+        for line in y..bottom {
+            let start = (line * self.stride + x) * self.bytes_per_pixel;
+            let end = (line * self.stride + right) * self.bytes_per_pixel;
+            for chunk in self.bytes[start..end].chunks_exact_mut(pixel.len()) {
+                chunk.copy_from_slice(pixel);
+            }
+        }
+    }
+
+    /// The outline of a rectangle, one pixel wide.
+    pub fn rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        self.fill_rect(x, y, width, 1, color);
+        self.fill_rect(x, y + height - 1, width, 1, color);
+        self.fill_rect(x, y, 1, height, color);
+        self.fill_rect(x + width - 1, y, 1, height, color);
+    }
+
+    /// A line from one point to the other, both included (Bresenham). The
+    /// ends may be off the canvas.
+    pub fn line(&mut self, from: (isize, isize), to: (isize, isize), color: Rgb) {
+        let (mut x, mut y) = from;
+        let (dx, dy) = ((to.0 - x).abs(), -(to.1 - y).abs());
+        let (step_x, step_y) = ((to.0 - x).signum(), (to.1 - y).signum());
+        let mut error = dx + dy;
+        loop {
+            if x >= 0 && y >= 0 {
+                self.put_pixel(x as usize, y as usize, color);
+            }
+            if (x, y) == to {
+                return;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Draws `c` in the `CHAR_WIDTH` by `CHAR_HEIGHT` cell at `x`, `y`,
+    /// background included.
+    pub fn draw_char(&mut self, x: usize, y: usize, c: char, foreground: Rgb, background: Rgb) {
+        self.fill_rect(x, y, CHAR_WIDTH, CHAR_HEIGHT, background);
+        let glyph = font::glyph(c);
+        for (row, bits) in glyph.iter().enumerate() {
+            // a blank line on top, then every row twice but the descender
+            let (top, height) = if row + 1 < font::ROWS { (1 + 2 * row, 2) } else { (CHAR_HEIGHT - 1, 1) };
+            for col in 0..font::COLUMNS {
+                if bits & (1 << (font::COLUMNS - 1 - col)) != 0 {
+                    self.fill_rect(x + 1 + col, y + top, 1, height, foreground);
+                }
+            }
+        }
+    }
+
+    /// Draws `text` from `x`, `y`, one cell per character, cut at the
+    /// right edge.
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, foreground: Rgb, background: Rgb) {
+        for (index, c) in text.chars().enumerate() {
+            let left = x + index * CHAR_WIDTH;
+            if left >= self.width {
+                break;
+            }
+            self.draw_char(left, y, c, foreground, background);
+        }
+    }
+
+    /// Moves everything up by `lines` pixels and fills the bottom with
+    /// `background`, for scrolling text.
+    pub fn scroll_up(&mut self, lines: usize, background: Rgb) {
+        let lines = lines.min(self.height);
+        let line_bytes = self.stride * self.bytes_per_pixel;
+        self.bytes.copy_within(lines * line_bytes..self.height * line_bytes, 0);
+        self.fill_rect(0, self.height - lines, self.width, lines, background);
+    }
+}
+
+/// Maps the framebuffer `info` describes and makes it the console:
+/// `print!` output goes there instead of the VGA text buffer from now on.
+pub fn init(info: &FrameBufferInfo) -> Result<(), GraphicsError> {
+    let size = (info.stride * info.height * info.bytes_per_pixel) as u64;
+    if size == 0 || info.byte_len < size as usize {
+        return Err(GraphicsError::TooSmall);
+    }
+    let addr = vmm::map_physical(PhysAddr::new(info.address), size).map_err(GraphicsError::Map)?;
+    let bytes = unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr::<u8>(), size as usize) };
+    match Canvas::new(bytes, info) {
+        Ok(canvas) => {
+            console::init(canvas);
+            Ok(())
+        }
+        Err(err) => {
+            // nothing else holds the mapping yet
+            let _ = unsafe { vmm::unmap_region(addr) };
+            Err(err)
+        }
+    }
+}
+
+//test case
+#[cfg(test)]
+fn test_info(format: PixelFormat, bytes_per_pixel: usize, width: usize, height: usize) -> FrameBufferInfo {
+    FrameBufferInfo {
+        address: 0,
+        byte_len: (width + 2) * height * bytes_per_pixel,
+        width,
+        height,
+        pixel_format: format,
+        bytes_per_pixel,
+        stride: width + 2,
+    }
+}
+
+#[test_case]
+fn test_canvas_pixels_and_shapes() {
+    let info = test_info(PixelFormat::Bgr, 4, 16, 8);
+    let mut bytes = alloc::vec![0; info.byte_len];
+    let mut canvas = Canvas::new(&mut bytes, &info).unwrap();
+    let red = Rgb::new(0xff, 0, 0);
+    canvas.put_pixel(1, 0, red);
+    assert_eq!(canvas.pixel(1, 0), Some(red));
+    // off the canvas, and the padding at the end of a line, stay untouched
+    canvas.put_pixel(16, 0, red);
+    assert_eq!(canvas.pixel(16, 0), None);
+
+    canvas.fill_rect(14, 6, 10, 10, red);
+    assert_eq!(canvas.pixel(15, 7), Some(red));
+    canvas.rect(2, 2, 4, 3, Rgb::from_color(Color::White));
+    assert_eq!(canvas.pixel(5, 4), Some(Rgb::new(0xff, 0xff, 0xff)));
+    assert_eq!(canvas.pixel(3, 3), Some(Rgb::new(0, 0, 0)));
+
+    canvas.line((-2, -2), (7, 7), red);
+    assert!((0..8).all(|i| canvas.pixel(i, i) == Some(red)));
+    canvas.line((10, 5), (13, 4), red);
+    assert_eq!((canvas.pixel(10, 5), canvas.pixel(13, 4)), (Some(red), Some(red)));
+    drop(canvas);
+    // BGR with the blue byte first; the pixel at x 16 is padding
+    assert_eq!(bytes[4..8], [0, 0, 0xff, 0]);
+    assert_eq!(bytes[16 * 4..18 * 4], [0; 8]);
+}
+
+#[test_case]
+fn test_canvas_text_and_scrolling() {
+    let info = test_info(PixelFormat::Rgb, 3, 2 * CHAR_WIDTH, 2 * CHAR_HEIGHT);
+    let mut bytes = alloc::vec![0; info.byte_len];
+    let mut canvas = Canvas::new(&mut bytes, &info).unwrap();
+    let (white, blue) = (Rgb::new(0xff, 0xff, 0xff), Rgb::new(0, 0, 0xaa));
+    canvas.draw_text(0, CHAR_HEIGHT, "|_x", white, blue);
+    // '|' is the middle column top to bottom, over a blue cell
+    assert_eq!(canvas.pixel(3, CHAR_HEIGHT + 1), Some(white));
+    assert_eq!(canvas.pixel(3, CHAR_HEIGHT + 14), Some(white));
+    assert_eq!(canvas.pixel(0, CHAR_HEIGHT), Some(blue));
+    // '_' is only the descender row
+    assert_eq!(canvas.pixel(CHAR_WIDTH + 1, 2 * CHAR_HEIGHT - 1), Some(white));
+    assert_eq!(canvas.pixel(CHAR_WIDTH + 1, 2 * CHAR_HEIGHT - 2), Some(blue));
+
+    canvas.scroll_up(CHAR_HEIGHT, Rgb::new(0, 0, 0));
+    assert_eq!(canvas.pixel(3, 1), Some(white));
+    assert_eq!(canvas.pixel(3, CHAR_HEIGHT + 1), Some(Rgb::new(0, 0, 0)));
+
+    let gray = test_info(PixelFormat::U8, 1, 4, 4);
+    let mut bytes = alloc::vec![0; gray.byte_len];
+    let mut canvas = Canvas::new(&mut bytes, &gray).unwrap();
+    canvas.put_pixel(0, 0, white);
+    assert_eq!(canvas.pixel(0, 0), Some(white));
+    assert!(matches!(
+        Canvas::new(&mut [0; 4], &test_info(PixelFormat::Unknown, 4, 1, 1)),
+        Err(GraphicsError::UnsupportedFormat(PixelFormat::Unknown, 4))
+    ));
+    assert!(matches!(Canvas::new(&mut [0; 4], &test_info(PixelFormat::Rgb, 4, 2, 2)), Err(GraphicsError::TooSmall)));
+}
@@ -0,0 +1,292 @@
+//! A text console on the framebuffer: what `vga_buffer::Writer` does for
+//! the text buffer, drawn with the font a cell at a time. The grid is as
+//! many cells as fit the mode (128x48 at 1024x768) and it understands the
+//! same escape sequences, so `print!` output looks the same either way.
+//!
+//! Once `graphics::init` sets one up, `vga_buffer` sends what it would
+//! have put in the text buffer here instead. There is no scrollback and
+//! no blinking cursor; the cell the cursor is on is underlined instead.
+
+use core::fmt;
+use spin::Mutex;
+use crate::ansi;
+use crate::vga_buffer::{ColorCode, DEFAULT_COLOR};
+use super::{Canvas, Rgb, CHAR_HEIGHT, CHAR_WIDTH};
+
+pub struct FramebufferConsole<'a> {
+    canvas: Canvas<'a>,
+    columns: usize,
+    rows: usize,
+    row: usize,
+    col: usize,
+    color_code: ColorCode,
+    escape: ansi::Parser,
+}
+
+impl<'a> FramebufferConsole<'a> {
+    /// Clears the canvas and starts at the top left.
+    pub fn new(canvas: Canvas<'a>) -> FramebufferConsole<'a> {
+        let mut console = FramebufferConsole {
+            columns: canvas.width() / CHAR_WIDTH,
+            rows: canvas.height() / CHAR_HEIGHT,
+            canvas,
+            row: 0,
+            col: 0,
+            color_code: DEFAULT_COLOR,
+            escape: ansi::Parser::new(),
+        };
+        console.clear();
+        console
+    }
+
+    /// Columns and rows of cells.
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    /// Row and column the next character goes to.
+    pub fn position(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+
+    /// Out of range values are clamped to the grid.
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.show_cursor(false);
+        self.move_to(row, col);
+        self.show_cursor(true);
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.row = row.min(self.rows - 1);
+        self.col = col.min(self.columns - 1);
+    }
+
+    pub fn color_code(&self) -> ColorCode {
+        self.color_code
+    }
+
+    pub fn set_color_code(&mut self, color_code: ColorCode) {
+        self.color_code = color_code;
+    }
+
+    fn colors(&self) -> (Rgb, Rgb) {
+        (Rgb::from_color(self.color_code.foreground()), Rgb::from_color(self.color_code.background()))
+    }
+
+    fn draw(&mut self, row: usize, col: usize, c: char) {
+        let (foreground, background) = self.colors();
+        self.canvas.draw_char(col * CHAR_WIDTH, row * CHAR_HEIGHT, c, foreground, background);
+    }
+
+    /// Underlines the cursor's cell, or takes the line away. It sits in
+    /// the bottom row, which only descenders use, and a cell the cursor is
+    /// on is almost always blank.
+    fn show_cursor(&mut self, shown: bool) {
+        if self.col >= self.columns {
+            return;
+        }
+        let (foreground, background) = self.colors();
+        let (x, y) = (self.col * CHAR_WIDTH, self.row * CHAR_HEIGHT + CHAR_HEIGHT - 2);
+        self.canvas.fill_rect(x, y, CHAR_WIDTH, 2, if shown { foreground } else { background });
+    }
+
+    fn new_line(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+        let (_, background) = self.colors();
+        self.canvas.scroll_up(CHAR_HEIGHT, background);
+    }
+
+    fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.col = 0,
+            '\x08' => self.erase_back(),
+            c => {
+                if self.col >= self.columns {
+                    self.new_line();
+                }
+                self.draw(self.row, self.col, c);
+                self.col += 1;
+            }
+        }
+    }
+
+    /// Blanks the character before the cursor and moves back onto it,
+    /// staying on the row.
+    pub fn backspace(&mut self) {
+        self.show_cursor(false);
+        self.erase_back();
+        self.show_cursor(true);
+    }
+
+    fn erase_back(&mut self) {
+        if self.col > 0 {
+            self.col -= 1;
+            self.draw(self.row, self.col, ' ');
+        }
+    }
+
+    /// Blanks the cells in `rows` and `cols` in the current color; ranges
+    /// past the grid are cut at its edge.
+    pub fn clear_region(&mut self, rows: core::ops::Range<usize>, cols: core::ops::Range<usize>) {
+        let (rows, cols) = (rows.start..rows.end.min(self.rows), cols.start..cols.end.min(self.columns));
+        if rows.is_empty() || cols.is_empty() {
+            return;
+        }
+        let (_, background) = self.colors();
+        self.canvas.fill_rect(
+            cols.start * CHAR_WIDTH,
+            rows.start * CHAR_HEIGHT,
+            cols.len() * CHAR_WIDTH,
+            rows.len() * CHAR_HEIGHT,
+            background,
+        );
+    }
+
+    /// Blanks the whole canvas, margins included, and starts again at the
+    /// top left.
+    pub fn clear(&mut self) {
+        let (_, background) = self.colors();
+        let (width, height) = (self.canvas.width(), self.canvas.height());
+        self.canvas.fill_rect(0, 0, width, height, background);
+        self.row = 0;
+        self.col = 0;
+        self.show_cursor(true);
+    }
+
+    /// Writes `s` from `row`, `col` without moving the position; see
+    /// `vga_buffer::Writer::write_at`.
+    pub fn write_at(&mut self, row: usize, col: usize, s: &str) {
+        if row >= self.rows {
+            return;
+        }
+        for (col, c) in (col..self.columns).zip(s.chars()) {
+            self.draw(row, col, c);
+        }
+    }
+
+    /// Same as `vga_buffer::Writer`: SGR colors, cursor movement, erasing.
+    fn run_escape(&mut self, csi: ansi::Csi) {
+        use ansi::{Command, Erase};
+
+        let Some(command) = csi.command() else { return };
+        let (row, col, rows, columns) = (self.row, self.col.min(self.columns - 1), self.rows, self.columns);
+        match command {
+            Command::Sgr => {
+                let current = (self.color_code.foreground(), self.color_code.background());
+                let default = (DEFAULT_COLOR.foreground(), DEFAULT_COLOR.background());
+                let (foreground, background) = ansi::apply_sgr(csi.params(), current, default);
+                self.color_code = ColorCode::new(foreground, background);
+            }
+            Command::CursorUp(count) => self.move_to(row.saturating_sub(count), col),
+            Command::CursorDown(count) => self.move_to(row.saturating_add(count), col),
+            Command::CursorForward(count) => self.move_to(row, col.saturating_add(count)),
+            Command::CursorBack(count) => self.move_to(row, col.saturating_sub(count)),
+            Command::CursorPosition { row, col } => self.move_to(row, col),
+            Command::EraseDisplay(Erase::ToEnd) => {
+                self.clear_region(row..row + 1, col..columns);
+                self.clear_region(row + 1..rows, 0..columns);
+            }
+            Command::EraseDisplay(Erase::ToCursor) => {
+                self.clear_region(0..row, 0..columns);
+                self.clear_region(row..row + 1, 0..col + 1);
+            }
+            Command::EraseDisplay(Erase::All) => self.clear_region(0..rows, 0..columns),
+            Command::EraseLine(Erase::ToEnd) => self.clear_region(row..row + 1, col..columns),
+            Command::EraseLine(Erase::ToCursor) => self.clear_region(row..row + 1, 0..col + 1),
+            Command::EraseLine(Erase::All) => self.clear_region(row..row + 1, 0..columns),
+        }
+    }
+}
+
+impl fmt::Write for FramebufferConsole<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.show_cursor(false);
+        for c in s.chars() {
+            match self.escape.feed(c) {
+                ansi::Output::Char(c) => self.write_char(c),
+                ansi::Output::Csi(csi) => self.run_escape(csi),
+                ansi::Output::Pending => {}
+            }
+        }
+        self.show_cursor(true);
+        Ok(())
+    }
+}
+
+static CONSOLE: Mutex<Option<FramebufferConsole<'static>>> = Mutex::new(None);
+
+/// Makes a console on `canvas` the screen.
+pub(super) fn init(canvas: Canvas<'static>) {
+    let console = FramebufferConsole::new(canvas);
+    let (columns, rows) = console.size();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut console = console;
+        console.set_color_code(crate::vga_buffer::color_code());
+        *CONSOLE.lock() = Some(console);
+    });
+    crate::info!("graphics: {}x{} text console on the framebuffer", columns, rows);
+}
+
+/// Runs `f` on the console, if `graphics::init` set one up. Interrupts
+/// are off while it runs.
+pub fn with<R>(f: impl FnOnce(&mut FramebufferConsole<'static>) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| CONSOLE.lock().as_mut().map(f))
+}
+
+pub fn is_active() -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| CONSOLE.lock().is_some())
+}
+
+//test case
+#[cfg(test)]
+fn test_console(bytes: &mut [u8], columns: usize, rows: usize) -> FramebufferConsole<'_> {
+    let info = crate::boot::FrameBufferInfo {
+        address: 0,
+        byte_len: bytes.len(),
+        width: columns * CHAR_WIDTH,
+        height: rows * CHAR_HEIGHT,
+        pixel_format: crate::boot::PixelFormat::Bgr,
+        bytes_per_pixel: 4,
+        stride: columns * CHAR_WIDTH,
+    };
+    FramebufferConsole::new(Canvas::new(bytes, &info).unwrap())
+}
+
+#[test_case]
+fn test_console_writes_and_scrolls() {
+    use core::fmt::Write;
+    use crate::vga_buffer::Color;
+
+    let mut bytes = alloc::vec![0; 4 * CHAR_WIDTH * 3 * CHAR_HEIGHT * 4];
+    let mut console = test_console(&mut bytes, 4, 3);
+    assert_eq!(console.size(), (4, 3));
+    write!(console, "ab\nabcdef").unwrap();
+    // the second line wrapped onto the third
+    assert_eq!(console.position(), (2, 2));
+    let text = Some(Rgb::from_color(DEFAULT_COLOR.foreground()));
+    // '|' has a pixel in its middle column on the first glyph row
+    write!(console, "\n|").unwrap();
+    assert_eq!(console.position(), (2, 1));
+    assert_eq!(console.canvas.pixel(3, 2 * CHAR_HEIGHT + 1), text);
+    // the first line scrolled off: what was on row 1 is on row 0 now
+    let row_of = |console: &FramebufferConsole, row: usize| (0..CHAR_HEIGHT - 2)
+        .flat_map(|y| (0..4 * CHAR_WIDTH).map(move |x| (x, row * CHAR_HEIGHT + y)))
+        .filter(|&(x, y)| console.canvas.pixel(x, y) == text)
+        .count();
+    assert!(row_of(&console, 0) > 0);
+
+    // colors and erasing go by the escape sequences
+    write!(console, "\x1b[31m\x1b[2J\x1b[1;1Hx\x1b[0m").unwrap();
+    assert_eq!(console.position(), (0, 1));
+    assert_eq!(row_of(&console, 1), 0);
+    assert!(console.canvas.pixel(2, 12) == Some(Rgb::from_color(Color::Red)));
+    console.backspace();
+    assert_eq!((console.position(), row_of(&console, 0)), ((0, 0), 0));
+    // the cursor's underline
+    assert_eq!(console.canvas.pixel(0, CHAR_HEIGHT - 1), text);
+}
@@ -0,0 +1,265 @@
+//! High Precision Event Timer.
+//!
+//! `init` maps the registers from the ACPI HPET table and starts the main
+//! counter, which backs `now_fs`. With `timer=hpet` on the command line,
+//! comparator 0 also takes over the system tick from the PIT. It is wired
+//! through the legacy replacement route, which delivers it as IRQ 0: the
+//! 8259s still handle device interrupts, so the existing timer handler
+//! keeps counting ticks and uptime/sleep don't care which chip drives them.
+
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::acpi::{self, AcpiError, GenericAddress};
+use crate::time::{PIT_DIVISOR, PIT_FREQUENCY_HZ};
+
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_MAIN_COUNTER: u64 = 0x0f0;
+const REG_TIMER0_CONFIG: u64 = 0x100;
+const REG_TIMER0_COMPARATOR: u64 = 0x108;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_64BIT_CAP: u64 = 1 << 5;
+const TIMER_VAL_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+
+/// The spec caps the counter period at 100 ns.
+const MAX_PERIOD_FS: u32 = 100_000_000;
+
+const FS_PER_SEC: u128 = 1_000_000_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    Acpi(AcpiError),
+    /// The table points at I/O space instead of memory.
+    NotMemoryMapped,
+    MapFailed,
+    /// Zero or above the 100 ns the spec allows.
+    BadPeriod(u32),
+    NotInitialized,
+    NoPeriodicMode,
+    NoLegacyRoute,
+    /// The tick period doesn't fit a 32-bit comparator.
+    PeriodTooLong,
+}
+
+impl fmt::Display for HpetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HpetError::Acpi(err) => write!(f, "{}", err),
+            HpetError::NotMemoryMapped => f.write_str("registers are not memory-mapped"),
+            HpetError::MapFailed => f.write_str("could not map registers"),
+            HpetError::BadPeriod(fs) => write!(f, "invalid counter period {} fs", fs),
+            HpetError::NotInitialized => f.write_str("not initialized"),
+            HpetError::NoPeriodicMode => f.write_str("timer 0 has no periodic mode"),
+            HpetError::NoLegacyRoute => f.write_str("no legacy replacement route"),
+            HpetError::PeriodTooLong => f.write_str("tick period too long for a 32-bit comparator"),
+        }
+    }
+}
+
+/// Fields of the general capabilities register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Main counter period in femtoseconds.
+    pub period_fs: u32,
+    pub timers: u8,
+    pub counter_64bit: bool,
+    pub legacy_route: bool,
+    pub vendor_id: u16,
+}
+
+impl Capabilities {
+    pub fn parse(raw: u64) -> Capabilities {
+        Capabilities {
+            period_fs: (raw >> 32) as u32,
+            timers: ((raw >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: raw & (1 << 13) != 0,
+            legacy_route: raw & (1 << 15) != 0,
+            vendor_id: (raw >> 16) as u16,
+        }
+    }
+
+    pub fn frequency_hz(&self) -> u64 {
+        (FS_PER_SEC / u128::from(self.period_fs)) as u64
+    }
+}
+
+/// Counter ticks between interrupts for the system tick rate
+/// (`PIT_FREQUENCY_HZ / PIT_DIVISOR`), so the tick means the same time
+/// whichever timer drives it.
+pub fn tick_period(period_fs: u32) -> u64 {
+    (u128::from(PIT_DIVISOR) * FS_PER_SEC / (u128::from(PIT_FREQUENCY_HZ) * u128::from(period_fs))) as u64
+}
+
+/// Extends a 32-bit counter reading to 64 bits given the last extended
+/// value. Correct as long as readings are less than one wrap apart.
+pub fn extend_32(last: u64, raw: u32) -> u64 {
+    let value = (last & !0xffff_ffff) | u64::from(raw);
+    if value < last {
+        value + (1 << 32)
+    } else {
+        value
+    }
+}
+
+struct Hpet {
+    base: VirtAddr,
+    capabilities: Capabilities,
+}
+
+impl Hpet {
+    fn read(&self, reg: u64) -> u64 {
+        unsafe { ptr::read_volatile((self.base + reg).as_ptr::<u64>()) }
+    }
+
+    fn write(&self, reg: u64, value: u64) {
+        unsafe { ptr::write_volatile((self.base + reg).as_mut_ptr::<u64>(), value) }
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+/// Last extended reading of a 32-bit main counter.
+static LAST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Maps the HPET and starts its main counter. With `timer=hpet` on the
+/// command line it also becomes the tick source.
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Capabilities, HpetError> {
+    let table = acpi::tables().map_err(HpetError::Acpi)?;
+    let address = table.hpet.ok_or(HpetError::Acpi(AcpiError::Missing(acpi::Hpet::SIGNATURE)))?.base_address;
+    if address.address_space != GenericAddress::SYSTEM_MEMORY {
+        return Err(HpetError::NotMemoryMapped);
+    }
+    let base = crate::memory::map_mmio(PhysAddr::new(address.address), mapper, frame_allocator)
+        .map_err(|_| HpetError::MapFailed)?;
+    let capabilities = Capabilities::parse(unsafe { ptr::read_volatile((base + REG_CAPABILITIES).as_ptr::<u64>()) });
+    if capabilities.period_fs == 0 || capabilities.period_fs > MAX_PERIOD_FS {
+        return Err(HpetError::BadPeriod(capabilities.period_fs));
+    }
+
+    let hpet = HPET.call_once(|| Hpet { base, capabilities });
+    hpet.write(REG_CONFIG, hpet.read(REG_CONFIG) | CONFIG_ENABLE);
+
+    if crate::cmdline::get("timer") == Some("hpet") {
+        use_for_ticks()?;
+        crate::println!("HPET: driving the system tick");
+    }
+    Ok(capabilities)
+}
+
+pub fn capabilities() -> Option<Capabilities> {
+    HPET.get().map(|hpet| hpet.capabilities)
+}
+
+/// Main counter value, extended to 64 bits on 32-bit counters.
+pub fn counter() -> Option<u64> {
+    let hpet = HPET.get()?;
+    let raw = hpet.read(REG_MAIN_COUNTER);
+    if hpet.capabilities.counter_64bit {
+        return Some(raw);
+    }
+    let mut last = LAST_COUNT.load(Ordering::Relaxed);
+    loop {
+        let value = extend_32(last, raw as u32);
+        if value <= last {
+            return Some(last);
+        }
+        match LAST_COUNT.compare_exchange_weak(last, value, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return Some(value),
+            Err(current) => last = current,
+        }
+    }
+}
+
+/// Femtoseconds since the counter was started. A 32-bit counter must be
+/// read at least once per wrap (42 s at 100 MHz); the timer interrupt
+/// takes care of that.
+pub fn now_fs() -> Option<u128> {
+    let hpet = HPET.get()?;
+    Some(u128::from(counter()?) * u128::from(hpet.capabilities.period_fs))
+}
+
+/// Called from the timer interrupt so 32-bit counters are read often
+/// enough to extend them.
+pub(crate) fn on_tick() {
+    if let Some(hpet) = HPET.get() {
+        if !hpet.capabilities.counter_64bit {
+            counter();
+        }
+    }
+}
+
+/// Makes comparator 0 fire periodically at the system tick rate through
+/// the legacy replacement route, which takes IRQ 0 away from the PIT.
+pub fn use_for_ticks() -> Result<(), HpetError> {
+    let hpet = HPET.get().ok_or(HpetError::NotInitialized)?;
+    if !hpet.capabilities.legacy_route {
+        return Err(HpetError::NoLegacyRoute);
+    }
+    let timer = hpet.read(REG_TIMER0_CONFIG);
+    if timer & TIMER_PERIODIC_CAP == 0 {
+        return Err(HpetError::NoPeriodicMode);
+    }
+    let period = tick_period(hpet.capabilities.period_fs);
+    let wide = timer & TIMER_64BIT_CAP != 0 && hpet.capabilities.counter_64bit;
+    if !wide && period > u64::from(u32::MAX) {
+        return Err(HpetError::PeriodTooLong);
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let config = hpet.read(REG_CONFIG);
+        hpet.write(REG_CONFIG, config & !CONFIG_ENABLE);
+        let mut timer_config = (timer & !TIMER_32BIT_MODE) | TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_VAL_SET;
+        if !wide {
+            timer_config |= TIMER_32BIT_MODE;
+        }
+        hpet.write(REG_TIMER0_CONFIG, timer_config);
+        // with VAL_SET the first write sets the comparator, the second
+        // the period it is advanced by
+        hpet.write(REG_TIMER0_COMPARATOR, hpet.read(REG_MAIN_COUNTER) + period);
+        hpet.write(REG_TIMER0_COMPARATOR, period);
+        hpet.write(REG_CONFIG, config | CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    });
+    Ok(())
+}
+
+//test case
+#[test_case]
+fn test_capabilities() {
+    // what QEMU reports: 100 MHz, 3 timers, 64-bit, legacy route
+    let caps = Capabilities::parse(0x0098_9680_8086_a201);
+    assert_eq!(caps.period_fs, 10_000_000);
+    assert_eq!(caps.timers, 3);
+    assert!(caps.counter_64bit);
+    assert!(caps.legacy_route);
+    assert_eq!(caps.vendor_id, 0x8086);
+    assert_eq!(caps.frequency_hz(), 100_000_000);
+}
+
+#[test_case]
+fn test_tick_period() {
+    // 100 MHz: 11932 / 1193182 s = 10.00015 ms
+    assert_eq!(tick_period(10_000_000), 1_000_015);
+    // 14.31818 MHz, the classic HPET crystal
+    assert_eq!(tick_period(69_841_279), 143_183);
+}
+
+#[test_case]
+fn test_extend_32() {
+    assert_eq!(extend_32(0, 5), 5);
+    assert_eq!(extend_32(0xffff_fff0, 0x10), 0x1_0000_0010);
+    assert_eq!(extend_32(0x3_0000_0100, 0x200), 0x3_0000_0200);
+    assert_eq!(extend_32(0x3_ffff_ffff, 0), 0x4_0000_0000);
+}
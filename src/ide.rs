@@ -0,0 +1,355 @@
+//! ATA disks on the two legacy IDE channels, primary (ports 0x1f0-0x1f7 and
+//! 0x3f6) and secondary (0x170-0x177 and 0x376), in PIO mode: the drive the
+//! i440fx machine boots from, and up to three next to it.
+//!
+//! Each of master and slave that answers IDENTIFY as an ATA device (not
+//! ATAPI, whose signature is left in the LBA registers) is registered as
+//! `ide<n>`, primary channel first. Transfers are 28-bit READ/WRITE
+//! SECTORS, at most 256 sectors a command, with every byte of data going
+//! through the data port. IRQs 14 and 15 are turned off (nIEN) and
+//! completion polled; each wait on the status register is bounded, so a
+//! drive that stops answering gives `Timeout`.
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use crate::ahci::Identify;
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::time;
+
+// registers, from a channel's command block base
+const DATA: u16 = 0;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE_HEAD: u16 = 6;
+/// Status when read, command when written.
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+/// What a bus with nothing on it reads as.
+const FLOATING: u8 = 0xff;
+const CONTROL_NIEN: u8 = 1 << 1;
+const DRIVE_LBA: u8 = 0xe0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_CACHE_FLUSH: u8 = 0xe7;
+const CMD_IDENTIFY: u8 = 0xec;
+
+/// Sectors a 28-bit LBA reaches.
+pub const MAX_LBA28_SECTORS: u64 = 1 << 28;
+/// A sector count of 0 in the task file means 256.
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+
+const IDENTIFY_TIMEOUT_MS: u64 = 1000;
+const COMMAND_TIMEOUT_MS: u64 = 5000;
+/// Polls before giving up even if the clock isn't moving, as in `ahci`.
+const POLL_LIMIT: usize = 50_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdeError {
+    /// The status registers float: no controller, or no drive on either
+    /// channel.
+    NoBus,
+}
+
+impl fmt::Display for IdeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdeError::NoBus => f.write_str("nothing on the IDE channels"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Primary,
+    Secondary,
+}
+
+impl Channel {
+    /// The command block: data, task file, status and command.
+    pub fn base(self) -> u16 {
+        match self {
+            Channel::Primary => 0x1f0,
+            Channel::Secondary => 0x170,
+        }
+    }
+
+    /// Alternate status when read, device control when written.
+    pub fn control(self) -> u16 {
+        match self {
+            Channel::Primary => 0x3f6,
+            Channel::Secondary => 0x376,
+        }
+    }
+
+    fn read(self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base() + reg).read() }
+    }
+
+    fn write(self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base() + reg).write(value) }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Channel::Primary => "primary",
+            Channel::Secondary => "secondary",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+impl Drive {
+    fn select_bits(self) -> u8 {
+        match self {
+            Drive::Master => DRIVE_LBA,
+            Drive::Slave => DRIVE_LBA | DRIVE_SLAVE,
+        }
+    }
+}
+
+impl fmt::Display for Drive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Drive::Master => "master",
+            Drive::Slave => "slave",
+        })
+    }
+}
+
+/// The sector count, LBA low, mid and high and drive/head registers for a
+/// transfer of `count` sectors (1-256) from `lba`, which must be below
+/// `MAX_LBA28_SECTORS`.
+pub fn task_file(drive: Drive, lba: u64, count: usize) -> [u8; 5] {
+    [
+        // 256 wraps to 0, which is how the drive reads it
+        count as u8,
+        lba as u8,
+        (lba >> 8) as u8,
+        (lba >> 16) as u8,
+        drive.select_bits() | ((lba >> 24) & 0x0f) as u8,
+    ]
+}
+
+/// The 400 ns a drive needs after being selected before its status means
+/// anything: four reads of the alternate status.
+fn settle(channel: Channel) {
+    for _ in 0..4 {
+        unsafe { Port::<u8>::new(channel.control()).read() };
+    }
+}
+
+/// Waits until `done` holds for the status register, for at most
+/// `timeout_ms`, and returns the status it stopped at.
+fn wait(channel: Channel, timeout_ms: u64, done: impl Fn(u8) -> bool) -> Result<u8, BlockError> {
+    let deadline = time::now_ns() + timeout_ms * 1_000_000;
+    for _ in 0..POLL_LIMIT {
+        let status = channel.read(STATUS);
+        if done(status) {
+            return Ok(status);
+        }
+        if time::now_ns() > deadline {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    let status = channel.read(STATUS);
+    if done(status) { Ok(status) } else { Err(BlockError::Timeout) }
+}
+
+/// Waits for the drive to stop being busy; an error or device fault it
+/// reports then is `Io`.
+fn wait_idle(channel: Channel, timeout_ms: u64) -> Result<(), BlockError> {
+    let status = wait(channel, timeout_ms, |status| status & STATUS_BSY == 0)?;
+    if status & (STATUS_ERR | STATUS_DF) != 0 {
+        return Err(BlockError::Io);
+    }
+    Ok(())
+}
+
+/// Waits for the drive to want the next sector's data.
+fn wait_data(channel: Channel) -> Result<(), BlockError> {
+    let status = wait(channel, COMMAND_TIMEOUT_MS, |status| {
+        status & STATUS_BSY == 0 && status & (STATUS_DRQ | STATUS_ERR | STATUS_DF) != 0
+    })?;
+    if status & (STATUS_ERR | STATUS_DF) != 0 {
+        return Err(BlockError::Io);
+    }
+    Ok(())
+}
+
+fn read_sector(channel: Channel, buf: &mut [u8]) {
+    let mut data = Port::<u16>::new(channel.base() + DATA);
+    for word in buf.chunks_exact_mut(2) {
+        word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+    }
+}
+
+fn write_sector(channel: Channel, buf: &[u8]) {
+    let mut data = Port::<u16>::new(channel.base() + DATA);
+    for word in buf.chunks_exact(2) {
+        unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+    }
+}
+
+/// Selects `drive` on `channel` and sends `command` for `count` sectors
+/// from `lba`.
+fn start(channel: Channel, drive: Drive, command: u8, lba: u64, count: usize) -> Result<(), BlockError> {
+    wait_idle(channel, COMMAND_TIMEOUT_MS)?;
+    let [count, low, mid, high, drive_head] = task_file(drive, lba, count);
+    channel.write(DRIVE_HEAD, drive_head);
+    settle(channel);
+    wait_idle(channel, COMMAND_TIMEOUT_MS)?;
+    channel.write(SECTOR_COUNT, count);
+    channel.write(LBA_LOW, low);
+    channel.write(LBA_MID, mid);
+    channel.write(LBA_HIGH, high);
+    channel.write(COMMAND, command);
+    Ok(())
+}
+
+/// IDENTIFY DEVICE; `None` if nothing answers or it is not an ATA disk.
+fn identify(channel: Channel, drive: Drive) -> Option<Identify> {
+    channel.write(DRIVE_HEAD, drive.select_bits());
+    settle(channel);
+    for reg in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+        channel.write(reg, 0);
+    }
+    channel.write(COMMAND, CMD_IDENTIFY);
+    if channel.read(STATUS) == 0 {
+        return None;
+    }
+    wait(channel, IDENTIFY_TIMEOUT_MS, |status| status & STATUS_BSY == 0).ok()?;
+    if channel.read(LBA_MID) != 0 || channel.read(LBA_HIGH) != 0 {
+        // ATAPI or SATA signature
+        return None;
+    }
+    wait_data(channel).ok()?;
+    let mut data = [0u8; SECTOR_SIZE];
+    read_sector(channel, &mut data);
+    Identify::parse(&data).filter(|info| info.sectors > 0)
+}
+
+pub struct IdeDisk {
+    channel: Channel,
+    drive: Drive,
+    info: Identify,
+}
+
+impl IdeDisk {
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    pub fn drive(&self) -> Drive {
+        self.drive
+    }
+
+    pub fn model(&self) -> &str {
+        &self.info.model
+    }
+}
+
+impl BlockDevice for IdeDisk {
+    /// What 28-bit LBA reaches of the disk.
+    fn sector_count(&self) -> u64 {
+        self.info.sectors.min(MAX_LBA28_SECTORS)
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self.sector_count(), lba, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let chunk_lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            start(self.channel, self.drive, CMD_READ_SECTORS, chunk_lba, chunk.len() / SECTOR_SIZE)?;
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                wait_data(self.channel)?;
+                read_sector(self.channel, sector);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self.sector_count(), lba, buf.len())?;
+        for (i, chunk) in buf.chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let chunk_lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            start(self.channel, self.drive, CMD_WRITE_SECTORS, chunk_lba, chunk.len() / SECTOR_SIZE)?;
+            for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                wait_data(self.channel)?;
+                write_sector(self.channel, sector);
+            }
+            wait_idle(self.channel, COMMAND_TIMEOUT_MS)?;
+        }
+        self.flush()
+    }
+
+    /// The drive may hold writes in its cache until told otherwise.
+    fn flush(&mut self) -> Result<(), BlockError> {
+        start(self.channel, self.drive, CMD_CACHE_FLUSH, 0, 0)?;
+        wait_idle(self.channel, COMMAND_TIMEOUT_MS)
+    }
+}
+
+/// ATA disks found by `init`, primary master first. Master and slave
+/// share a channel, so a transfer holds the lock on all of them.
+pub static DEVICES: Mutex<Vec<IdeDisk>> = Mutex::new(Vec::new());
+
+/// Finds the ATA disks on both channels. Returns how many are ready.
+pub fn init() -> Result<usize, IdeError> {
+    let channels: Vec<Channel> = [Channel::Primary, Channel::Secondary]
+        .into_iter()
+        .filter(|channel| channel.read(STATUS) != FLOATING)
+        .collect();
+    if channels.is_empty() {
+        return Err(IdeError::NoBus);
+    }
+    let mut devices = DEVICES.lock();
+    for channel in channels {
+        unsafe { Port::<u8>::new(channel.control()).write(CONTROL_NIEN) };
+        for drive in [Drive::Master, Drive::Slave] {
+            if let Some(info) = identify(channel, drive) {
+                crate::block::register("ide", crate::block::Disk::Ide(devices.len()));
+                devices.push(IdeDisk { channel, drive, info });
+            }
+        }
+    }
+    Ok(devices.len())
+}
+
+/// Prints the disks found.
+pub fn print_summary() {
+    for disk in DEVICES.lock().iter() {
+        crate::println!("IDE {} {}: {} ({} sectors)", disk.channel, disk.drive, disk.model(), disk.sector_count());
+    }
+}
+
+//test case
+#[test_case]
+fn test_task_file() {
+    assert_eq!(task_file(Drive::Master, 0x0123_4567, 1), [1, 0x67, 0x45, 0x23, 0xe1]);
+    assert_eq!(task_file(Drive::Slave, 5, 256), [0, 5, 0, 0, 0xf0]);
+    assert_eq!(task_file(Drive::Slave, MAX_LBA28_SECTORS - 1, 2), [2, 0xff, 0xff, 0xff, 0xff]);
+}
+
+#[test_case]
+fn test_channel_ports() {
+    assert_eq!((Channel::Primary.base(), Channel::Primary.control()), (0x1f0, 0x3f6));
+    assert_eq!((Channel::Secondary.base(), Channel::Secondary.control()), (0x170, 0x376));
+}
@@ -4,7 +4,14 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, Pag
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use crate::{print, println};
+use crate::shell::Shell;
+use x86_64::VirtAddr;
+
+pub mod registers;
+
+pub use registers::{ExceptionFrame, SavedRegisters};
 // Usamos crate:: para referirnos a nuestra propia librería definida en lib.rs
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -29,21 +36,242 @@ impl InterruptIndex {
     }
 }
 
+// ==========================================================
+// IRQs REGISTRADAS POR LOS DRIVERS
+// ==========================================================
+
+/// Handlers installed at runtime for the PIC lines that don't have a
+/// dedicated handler. Stored as plain function addresses (0 = none) so the
+/// interrupt path never takes a lock to find them.
+static IRQ_HANDLERS: [AtomicUsize; 16] = [const { AtomicUsize::new(0) }; 16];
+
+crate::per_cpu! {
+    /// Interrupts taken per PIC line since boot.
+    static IRQ_COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+}
+
+/// Interrupts taken on PIC line `irq` since boot, on every CPU.
+pub fn irq_count(irq: u8) -> u64 {
+    IRQ_COUNTS.all().iter().map(|counts| counts[usize::from(irq)].load(Ordering::Relaxed)).sum()
+}
+
+fn count_irq(irq: u8) {
+    crate::cpu::end_halt();
+    IRQ_COUNTS.get()[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+}
+
+// ==========================================================
+// CONTROLADOR: 8259 O APIC
+// ==========================================================
+
+/// Whether IRQs come through the I/O APIC, and are acknowledged at the
+/// local APIC, rather than through the 8259s. Only `enable_apic` sets it.
+static APIC_MODE: AtomicBool = AtomicBool::new(false);
+
+pub fn using_apic() -> bool {
+    APIC_MODE.load(Ordering::Acquire)
+}
+
+#[derive(Debug)]
+pub enum ApicError {
+    Acpi(crate::acpi::AcpiError),
+    /// The local APIC's registers could not be mapped.
+    Map(x86_64::structures::paging::mapper::MapToError<x86_64::structures::paging::Size4KiB>),
+    IoApic(crate::ioapic::IoApicError),
+}
+
+impl core::fmt::Display for ApicError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ApicError::Acpi(err) => write!(f, "{}", err),
+            ApicError::Map(err) => write!(f, "could not map the local APIC: {:?}", err),
+            ApicError::IoApic(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Moves the IRQs from the 8259s to the I/O APIC. Each ISA IRQ keeps the
+/// vector it had (`PIC_1_OFFSET + irq`) and whether it was masked, and goes
+/// to this CPU; the handlers and `register_irq`, `mask_irq` and
+/// `unmask_irq` work as before. The 8259s are left fully masked. Needs
+/// the ACPI tables; on any error nothing has changed.
+pub fn enable_apic(
+    mapper: &mut x86_64::structures::paging::OffsetPageTable,
+    frame_allocator: &mut impl x86_64::structures::paging::FrameAllocator<x86_64::structures::paging::Size4KiB>,
+) -> Result<(), ApicError> {
+    if using_apic() {
+        return Ok(());
+    }
+    let topology = crate::acpi::topology().map_err(ApicError::Acpi)?;
+    crate::apic::init(x86_64::PhysAddr::new(topology.local_apic_address), mapper, frame_allocator).map_err(ApicError::Map)?;
+    crate::ioapic::init(topology, mapper, frame_allocator).map_err(ApicError::IoApic)?;
+    let apic_id = crate::apic::local().expect("local APIC mapped").id() as u8;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let masks = unsafe { pics.read_masks() };
+        // 2 is only the cascade between the 8259s
+        for irq in (0..16).filter(|&irq| irq != 2) {
+            let masked = masks[usize::from(irq / 8)] & (1 << (irq % 8)) != 0;
+            crate::ioapic::route_isa(irq, PIC_1_OFFSET + irq, apic_id, masked).map_err(ApicError::IoApic)?;
+        }
+        unsafe { pics.write_masks(0xff, 0xff) };
+        APIC_MODE.store(true, Ordering::Release);
+        Ok(())
+    })
+}
+
+/// Acknowledges `irq` at whichever controller delivered it.
+fn end_of_interrupt(irq: u8) {
+    if using_apic() {
+        if let Some(mut apic) = crate::apic::local() {
+            apic.eoi();
+        }
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq) };
+    }
+}
+
+/// Installs `handler` for PIC line `irq` (0-15) and unmasks the line.
+/// The handler runs in interrupt context; end-of-interrupt is sent for it.
+pub fn register_irq(irq: u8, handler: fn()) {
+    assert!(irq < 16 && irq > 1, "IRQ {} can't be registered", irq);
+    IRQ_HANDLERS[usize::from(irq)].store(handler as usize, Ordering::Release);
+    unmask_irq(irq);
+}
+
+pub fn unmask_irq(irq: u8) {
+    set_irq_masked(irq, false);
+    if irq >= 8 && !using_apic() {
+        // the slave PIC only reaches the CPU through the cascade line
+        set_irq_masked(2, false);
+    }
+}
+
+pub fn mask_irq(irq: u8) {
+    set_irq_masked(irq, true);
+}
+
+fn set_irq_masked(irq: u8, masked: bool) {
+    if using_apic() {
+        // every ISA IRQ but the cascade got a pin in `enable_apic`
+        let _ = crate::ioapic::set_isa_masked(irq, masked);
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let mut masks = unsafe { pics.read_masks() };
+        let (chip, bit) = (usize::from(irq / 8), irq % 8);
+        if masked {
+            masks[chip] |= 1 << bit;
+        } else {
+            masks[chip] &= !(1 << bit);
+        }
+        unsafe { pics.write_masks(masks[0], masks[1]) };
+    });
+}
+
+fn dispatch_irq(irq: u8) {
+    count_irq(irq);
+    let handler = IRQ_HANDLERS[usize::from(irq)].load(Ordering::Acquire);
+    if handler != 0 {
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+    end_of_interrupt(irq);
+}
+
+macro_rules! irq_stubs {
+    ($($irq:literal => $name:ident / $stub:ident),* $(,)?) => {
+        $(
+            extern "C" fn $name(_frame: &mut ExceptionFrame) {
+                dispatch_irq($irq);
+            }
+            registers::exception_stub!($stub => $name);
+        )*
+        const IRQ_STUBS: &[(u8, extern "C" fn())] = &[$(($irq, $stub)),*];
+    };
+}
+
+irq_stubs! {
+    2 => irq2_handler / irq2_stub, 3 => irq3_handler / irq3_stub,
+    4 => irq4_handler / irq4_stub, 5 => irq5_handler / irq5_stub,
+    6 => irq6_handler / irq6_stub, 7 => irq7_handler / irq7_stub,
+    8 => irq8_handler / irq8_stub, 9 => irq9_handler / irq9_stub,
+    10 => irq10_handler / irq10_stub, 11 => irq11_handler / irq11_stub,
+    12 => irq12_handler / irq12_stub, 13 => irq13_handler / irq13_stub,
+    14 => irq14_handler / irq14_stub, 15 => irq15_handler / irq15_stub,
+}
+
 lazy_static! {
     // Definimos el Shell dentro de un Mutex para que sea seguro acceder desde la interrupción
     static ref SHELL: Mutex<Shell> = Mutex::new(Shell::new());
 }
 
+/// Whether a shell command is running right now.
+pub fn shell_busy() -> bool {
+    SHELL.is_locked()
+}
+
+/// Runs a line from the console on the shell. Returns `false`, with the
+/// line not taken, while a command is running.
+pub fn shell_line(line: &str) -> bool {
+    match SHELL.try_lock() {
+        Some(mut shell) => {
+            shell.handle_line(line);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The shell's working directory; `None` while a command is running.
+pub fn shell_cwd() -> Option<alloc::string::String> {
+    SHELL.try_lock().map(|shell| alloc::string::String::from(shell.cwd()))
+}
+
+/// Tells the shell Ctrl+C was pressed; `false` while a command is running.
+pub fn shell_interrupt() -> bool {
+    match SHELL.try_lock() {
+        Some(mut shell) => {
+            shell.interrupt();
+            true
+        }
+        None => false,
+    }
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            use registers::*;
+            idt.debug.set_handler_addr(stub_addr(debug_stub));
+            idt.breakpoint.set_handler_addr(stub_addr(breakpoint_stub));
+            idt.divide_error.set_handler_addr(stub_addr(divide_error_stub));
+            idt.invalid_opcode.set_handler_addr(stub_addr(invalid_opcode_stub));
+            idt.double_fault.set_handler_addr(stub_addr(double_fault_stub))
+                .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt.set_handler_addr(stub_addr(nmi_stub))
+                .set_stack_index(crate::gdt::NMI_IST_INDEX);
+            idt.segment_not_present.set_handler_addr(stub_addr(segment_not_present_stub));
+            idt.stack_segment_fault.set_handler_addr(stub_addr(stack_segment_fault_stub));
+            idt.general_protection_fault.set_handler_addr(stub_addr(general_protection_fault_stub));
+            idt.page_fault.set_handler_addr(stub_addr(page_fault_stub));
+            idt.x87_floating_point.set_handler_addr(stub_addr(x87_floating_point_stub));
+            idt.simd_floating_point.set_handler_addr(stub_addr(simd_floating_point_stub));
+            idt[usize::from(crate::syscall::VECTOR)]
+                .set_handler_addr(stub_addr(syscall_stub))
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+        }
+        unsafe {
+            idt[InterruptIndex::Timer.as_usize()].set_handler_addr(stub_addr(registers::timer_stub));
+            idt[InterruptIndex::Keyboard.as_usize()].set_handler_addr(stub_addr(registers::keyboard_stub));
+            for &(irq, stub) in IRQ_STUBS {
+                idt[usize::from(PIC_1_OFFSET + irq)].set_handler_addr(stub_addr(stub));
+            }
+        }
+        idt[usize::from(crate::apic::SPURIOUS_VECTOR)]
+            .set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -52,139 +280,264 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: InterruptStackFrame)
-{
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Stops in the debugger when there is one (see `gdbstub`); otherwise
+/// only reports the `int3` and goes on after it.
+extern "C" fn breakpoint_handler(frame: &mut ExceptionFrame) {
+    if crate::gdbstub::active() {
+        crate::gdbstub::trap(crate::gdbstub::SIGTRAP, frame);
+        return;
+    }
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", frame.stack_frame);
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64) -> !
-{
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+/// Where the steps the debugger asks for end, with the trap flag set.
+extern "C" fn debug_handler(frame: &mut ExceptionFrame) {
+    if crate::gdbstub::active() {
+        crate::gdbstub::trap(crate::gdbstub::SIGTRAP, frame);
+        return;
+    }
+    frame.stack_frame.cpu_flags &= !(1 << 8);
+    println!("EXCEPTION: DEBUG\n{:#?}", frame.stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
-{
-    // Opcional: imprimir un punto para ver que el timer funciona
-    // print!(".");
+/// Raised by the local APIC when an interrupt goes away before it is
+/// delivered. No EOI is sent for these.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+fn stub_addr(stub: extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+/// Prints the report shared by all fatal exceptions.
+/// Says whose stack overflowed if a fault from the kernel touched
+/// `address` in a guard page: one below a thread stack (see
+/// `memory::KernelStack`), or the page right under the interrupted RSP,
+/// where the boot stack has the bootloader's.
+fn report_stack_overflow(address: VirtAddr, frame: &ExceptionFrame) -> bool {
+    if frame.stack_frame.code_segment & 3 != 0 {
+        return false;
     }
+    let thread = match crate::memory::stack_guard_hit(address) {
+        Some(top) => crate::scheduler::stack_owner(top),
+        None => {
+            let rsp = frame.stack_frame.stack_pointer.as_u64();
+            if address.as_u64() >= rsp || rsp - address.as_u64() > 4096 {
+                return false;
+            }
+            crate::scheduler::try_current()
+        }
+    };
+    match thread {
+        Some(thread) => println!("stack overflow in thread {}", thread),
+        None => println!("stack overflow in a kernel thread"),
+    }
+    true
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
+fn report_exception(name: &str, frame: &ExceptionFrame) {
+    println!("EXCEPTION: {}", name);
+    println!("Error Code: {:#x}", frame.error_code);
+    print!("{}", frame.registers);
+    println!("{:#?}", frame.stack_frame);
+}
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    loop { x86_64::instructions::hlt(); }
+/// Reports the exception and halts for good, unless it came from a
+/// process: then only the process is killed and this returns.
+fn fatal_exception(name: &str, frame: &mut ExceptionFrame) {
+    if crate::process::user_fault(name, frame, None) {
+        return;
+    }
+    report_exception(name, frame);
+    crate::gdbstub::exception(name, frame);
+    registers::run_fatal_hook(name, frame);
+    crate::debug::backtrace();
+    crate::hlt_loop();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
-{
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use x86_64::instructions::port::Port;
+extern "C" fn divide_error_handler(frame: &mut ExceptionFrame) {
+    fatal_exception("DIVIDE ERROR", frame);
+}
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                ScancodeSet1::new(),
-                layouts::Us104Key,
-                HandleControl::Ignore
-            ));
-    }
+extern "C" fn invalid_opcode_handler(frame: &mut ExceptionFrame) {
+    fatal_exception("INVALID OPCODE", frame);
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-    
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => {
-                    // Llamamos al shell para que procese la tecla
-                    spin::Mutex::lock(&SHELL).handle_key(character);
-                },
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
+extern "C" fn segment_not_present_handler(frame: &mut ExceptionFrame) {
+    fatal_exception("SEGMENT NOT PRESENT", frame);
+}
+
+extern "C" fn stack_segment_fault_handler(frame: &mut ExceptionFrame) {
+    fatal_exception("STACK SEGMENT FAULT", frame);
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+extern "C" fn general_protection_fault_handler(frame: &mut ExceptionFrame) {
+    if crate::cpu::recover_gp(frame) {
+        return;
     }
+    fatal_exception("GENERAL PROTECTION FAULT", frame);
+}
+
+extern "C" fn x87_floating_point_handler(frame: &mut ExceptionFrame) {
+    fatal_exception("x87 FLOATING POINT", frame);
 }
 
+extern "C" fn simd_floating_point_handler(frame: &mut ExceptionFrame) {
+    fatal_exception("SIMD FLOATING POINT", frame);
+}
 
-//Workaround for shell.rs not importing, might fix later
-use alloc::string::String;
+/// On its own IST stack. The watchdog decides what the NMI was for; see
+/// `watchdog::nmi`.
+extern "C" fn nmi_handler(frame: &mut ExceptionFrame) {
+    crate::watchdog::nmi(frame);
+}
 
-pub struct Shell {
-    input: String,
+/// Runs on its own IST stack, so it also catches a kernel stack overflow:
+/// the page fault for the guard page can't push its frame and turns into
+/// this, with CR2 still naming the guard page.
+extern "C" fn double_fault_handler(frame: &mut ExceptionFrame) {
+    report_stack_overflow(x86_64::registers::control::Cr2::read(), frame);
+    report_exception("DOUBLE FAULT", frame);
+    crate::gdbstub::exception("DOUBLE FAULT", frame);
+    registers::run_fatal_hook("DOUBLE FAULT", frame);
+    // the panic handler prints the backtrace
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", frame.stack_frame);
 }
 
-impl Shell {
-    pub fn new() -> Self {
-        Shell {
-            input: String::new(),
-        }
+/// Goes through a register-saving stub so the scheduler can swap the
+/// interrupted process for another one.
+extern "C" fn timer_interrupt_handler(frame: &mut ExceptionFrame) {
+    // Opcional: imprimir un punto para ver que el timer funciona
+    // print!(".");
+    count_irq(0);
+    crate::time::tick();
+    crate::time::run_periodic();
+    crate::cpu::sample_idle();
+    crate::rng::add_interrupt_event();
+    crate::hpet::on_tick();
+    crate::clock::on_tick();
+    crate::watchdog::check(frame);
+    run_tick_hook();
+    crate::testing::check_timeout();
+
+    end_of_interrupt(0);
+    crate::process::preempt(frame);
+    crate::scheduler::preempt(frame);
+}
+
+static TICK_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs `hook` in every timer interrupt, before the EOI. It must not block
+/// or allocate. Meant for integration tests that need work done inside an
+/// interrupt.
+pub fn set_tick_hook(hook: fn()) {
+    TICK_HOOK.store(hook as usize, Ordering::Release);
+}
+
+fn run_tick_hook() {
+    let hook = TICK_HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        let hook: fn() = unsafe { core::mem::transmute(hook) };
+        hook();
     }
+}
 
-    pub fn handle_key(&mut self, key: char) {
-        match key {
-            '\n' => {
-                println!();
-                self.execute();
-                print!("> ");
-            }
-            '\x08' => {
-                self.input.pop();
-                print!("{}", key);
-            }
-            c => {
-                self.input.push(c);
-                print!("{}", c);
-            }
+/// What a page fault error code says happened, as a sentence: who made
+/// the access, what kind of access, and whether the page was missing or
+/// only off limits.
+pub struct PageFaultCause(pub PageFaultErrorCode);
+
+impl core::fmt::Display for PageFaultCause {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let code = self.0;
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" };
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+        let page = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "a page it may not access"
+        } else {
+            "a not-present page"
+        };
+        write!(f, "{}-mode {} of {}", mode, access, page)?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            f.write_str(", reserved bit set in a page table entry")?;
         }
+        Ok(())
     }
+}
 
-    fn execute(&mut self) {
-    match self.input.trim() {  // ← AÑADE .trim()
-        "help" => println!("Commands: help, clear, echo, info, exit"),
-        "clear" => {
-            for _ in 0..50 {
-                println!();
-            }
-        }
-        cmd if cmd.starts_with("echo ") => {
-            println!("{}", &cmd[5..]);
-        }
-        "info" => {
-            println!("Kernel v0.1.0 | berryOS v0.1.0 - x86_64");
-        }
-        "exit" => {
-            println!("shuting down...");
-            use x86_64::instructions::port::Port;
-            unsafe {
-                let mut port = Port::new(0x604);
-                port.write(0x2000 as u16);
-                println!("If it doesn't shut down in a second please, shutdown manually")
-            }
-        }
-        _ => println!("Command not found: {}", self.input),
+/// Maps the page if the address is in a lazy region (see
+/// `memory::register_lazy_region`) and copies a copy-on-write page written
+/// to (see `usermode`); otherwise reports the fault and halts.
+/// A test that faults on purpose registers a `registers::set_fatal_hook` to
+/// check the report and exit QEMU.
+extern "C" fn page_fault_handler(frame: &mut ExceptionFrame) {
+    use x86_64::registers::control::Cr2;
+
+    let address = Cr2::read();
+    let code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let lazy = crate::memory::handle_lazy_fault(address, code);
+    if let crate::memory::LazyFault::Mapped(_) = lazy {
+        // the faulting instruction runs again, now with the page there
+        return;
+    }
+    if crate::usermode::handle_cow_fault(address, code) {
+        return;
+    }
+    if crate::process::user_fault("PAGE FAULT", frame, Some(address)) {
+        return;
     }
-    self.input.clear();
+    crate::debug::enter_page_fault();
+    println!("EXCEPTION: PAGE FAULT");
+    report_stack_overflow(address, frame);
+    println!("Accessed Address: {:?}", address);
+    println!("Error Code: {:#x}: {}", frame.error_code, PageFaultCause(code));
+    println!("Instruction: {:#x}", frame.stack_frame.instruction_pointer.as_u64());
+    // the page tables as they are now, which may disagree with the error
+    // code if someone changed them without flushing the TLB
+    match lazy {
+        crate::memory::LazyFault::OutOfFrames(name) => println!("Lazy region '{}': out of frames", name),
+        crate::memory::LazyFault::Locked(name) => println!("Lazy region '{}': the page tables were locked", name),
+        _ => {}
+    }
+    match crate::memory::page_flags(address) {
+        None => println!("Mapping: not mapped"),
+        Some(flags) => println!("Mapping: mapped with {:?}, so the access was not allowed", flags),
+    }
+    print!("{}", frame.registers);
+    println!("{:#?}", frame.stack_frame);
+    crate::gdbstub::exception("PAGE FAULT", frame);
+    registers::run_fatal_hook("PAGE FAULT", frame);
+    loop { x86_64::instructions::hlt(); }
+}
+
+/// Only queues the scancode: decoding, echo and shell commands happen in
+/// the keyboard task, outside the interrupt, so nothing here can
+/// wait on a lock the interrupted code holds.
+extern "C" fn keyboard_interrupt_handler(_frame: &mut ExceptionFrame) {
+    use x86_64::instructions::port::Port;
+
+    count_irq(1);
+    crate::rng::add_interrupt_event();
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    crate::keyboard::queue_scancode(scancode);
+    end_of_interrupt(1);
+}
+
+//test case
+#[test_case]
+fn test_page_fault_cause() {
+    use alloc::string::ToString;
+    let cause = |bits| PageFaultCause(PageFaultErrorCode::from_bits_truncate(bits)).to_string();
+    assert_eq!(cause(0b0000), "kernel-mode read of a not-present page");
+    assert_eq!(cause(0b0010), "kernel-mode write of a not-present page");
+    assert_eq!(cause(0b0011), "kernel-mode write of a page it may not access");
+    assert_eq!(cause(0b0101), "user-mode read of a page it may not access");
+    assert_eq!(cause(0b1_0100), "user-mode instruction fetch of a not-present page");
+    assert_eq!(cause(0b1001), "kernel-mode read of a page it may not access, reserved bit set in a page table entry");
 }
-}
\ No newline at end of file
@@ -0,0 +1,202 @@
+//! Entry stubs that save every general-purpose register: for the fatal
+//! exception vectors, so reports can show what the code was doing, for
+//! the timer and system calls, which read or replace a process's context,
+//! and for the other IRQs, which may arrive in ring 3 too.
+//!
+//! Each stub normalizes the stack (pushing a zero for vectors without an
+//! error code), pushes RAX..R15, saves the FPU and SSE state below them
+//! with `fxsave64` and calls a handler with a pointer to the resulting
+//! `ExceptionFrame`. If the handler returns, everything is restored and
+//! the stub `iretq`s. Coming from ring 3 and going back there, the stub
+//! also runs `swapgs`, so handlers always see the kernel's GS base.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::structures::idt::InterruptStackFrameValue;
+use crate::cpu::FpuState;
+
+/// Laid out in the order the stubs leave them on the stack (RAX pushed
+/// last, so it comes first).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SavedRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+impl SavedRegisters {
+    pub fn named(&self) -> [(&'static str, u64); 15] {
+        [
+            ("RAX", self.rax), ("RBX", self.rbx), ("RCX", self.rcx), ("RDX", self.rdx),
+            ("RSI", self.rsi), ("RDI", self.rdi), ("RBP", self.rbp), ("R8", self.r8),
+            ("R9", self.r9), ("R10", self.r10), ("R11", self.r11), ("R12", self.r12),
+            ("R13", self.r13), ("R14", self.r14), ("R15", self.r15),
+        ]
+    }
+}
+
+/// Four registers per line: `RAX=0000000000000000 RBX=...`.
+impl fmt::Display for SavedRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in self.named().chunks(4) {
+            for (i, (name, value)) in row.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(" ")?;
+                }
+                write!(f, "{:>3}={:016x}", name, value)?;
+            }
+            f.write_str("\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub fpu: FpuState,
+    /// Keeps `fpu` 16-byte aligned: the CPU frame, error code and registers
+    /// are 21 words.
+    _pad: u64,
+    pub registers: SavedRegisters,
+    /// Zero for vectors that do not push one.
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+/// The part every stub shares once the CPU's frame and an error code are
+/// on the stack: pushes the registers and the FPU state, calls the
+/// operand named `handler` with the `ExceptionFrame`, restores everything
+/// and drops the error code. Expects RSP 16-byte aligned below the frame.
+/// The CS pushed by the CPU sits two words above the error code; its
+/// privilege bits say whether GS holds the program's base.
+macro_rules! call_handler {
+    () => {
+        concat!(
+            "test byte ptr [rsp + 16], 3\n",
+            "jz 7f\n",
+            "swapgs\n",
+            "7:\n",
+            "push r15\n", "push r14\n", "push r13\n", "push r12\n",
+            "push r11\n", "push r10\n", "push r9\n", "push r8\n",
+            "push rbp\n", "push rdi\n", "push rsi\n", "push rdx\n",
+            "push rcx\n", "push rbx\n", "push rax\n",
+            // 6 words from the CPU (with the error code) + 15 registers:
+            // a word of padding brings RSP back to a 16-byte boundary for
+            // the fxsave area and the call
+            "sub rsp, 520\n",
+            "fxsave64 [rsp]\n",
+            "mov rdi, rsp\n",
+            "cld\n",
+            "call {handler}\n",
+            "fxrstor64 [rsp]\n",
+            "add rsp, 520\n",
+            "pop rax\n", "pop rbx\n", "pop rcx\n", "pop rdx\n",
+            "pop rsi\n", "pop rdi\n", "pop rbp\n", "pop r8\n",
+            "pop r9\n", "pop r10\n", "pop r11\n", "pop r12\n",
+            "pop r13\n", "pop r14\n", "pop r15\n",
+            // the frame may now be another process's, but it is for ring 3
+            // exactly when the one it replaced was
+            "test byte ptr [rsp + 16], 3\n",
+            "jz 8f\n",
+            "swapgs\n",
+            "8:\n",
+            "add rsp, 8\n",
+        )
+    };
+}
+pub(crate) use call_handler;
+
+macro_rules! exception_stub {
+    (@body $name:ident, $handler:path, $push_error_code:literal) => {
+        #[unsafe(naked)]
+        pub extern "C" fn $name() {
+            core::arch::naked_asm!(
+                $push_error_code,
+                $crate::interrupts::registers::call_handler!(),
+                "iretq",
+                handler = sym $handler,
+            );
+        }
+    };
+    ($name:ident => $handler:path, error_code) => {
+        $crate::interrupts::registers::exception_stub!(@body $name, $handler, "");
+    };
+    ($name:ident => $handler:path) => {
+        $crate::interrupts::registers::exception_stub!(@body $name, $handler, "push 0");
+    };
+}
+
+exception_stub!(divide_error_stub => super::divide_error_handler);
+exception_stub!(debug_stub => super::debug_handler);
+exception_stub!(nmi_stub => super::nmi_handler);
+exception_stub!(breakpoint_stub => super::breakpoint_handler);
+exception_stub!(invalid_opcode_stub => super::invalid_opcode_handler);
+exception_stub!(double_fault_stub => super::double_fault_handler, error_code);
+exception_stub!(segment_not_present_stub => super::segment_not_present_handler, error_code);
+exception_stub!(stack_segment_fault_stub => super::stack_segment_fault_handler, error_code);
+exception_stub!(general_protection_fault_stub => super::general_protection_fault_handler, error_code);
+exception_stub!(page_fault_stub => super::page_fault_handler, error_code);
+exception_stub!(x87_floating_point_stub => super::x87_floating_point_handler);
+exception_stub!(simd_floating_point_stub => super::simd_floating_point_handler);
+exception_stub!(timer_stub => super::timer_interrupt_handler);
+exception_stub!(syscall_stub => crate::syscall::handler);
+exception_stub!(keyboard_stub => super::keyboard_interrupt_handler);
+pub(crate) use exception_stub;
+
+static FATAL_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs `hook` after a fatal exception has been reported, before the
+/// kernel halts. Meant for integration tests that provoke faults on
+/// purpose.
+pub fn set_fatal_hook(hook: fn(&str, &ExceptionFrame)) {
+    FATAL_HOOK.store(hook as usize, Ordering::Release);
+}
+
+pub(super) fn run_fatal_hook(name: &str, frame: &ExceptionFrame) {
+    let hook = FATAL_HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        let hook: fn(&str, &ExceptionFrame) = unsafe { core::mem::transmute(hook) };
+        hook(name, frame);
+    }
+}
+
+//test case
+#[test_case]
+fn test_register_block_format() {
+    use alloc::string::ToString;
+    let registers = SavedRegisters {
+        rax: 1,
+        r8: 0xdead_beef,
+        r15: u64::MAX,
+        ..SavedRegisters::default()
+    };
+    let text = registers.to_string();
+    let lines: alloc::vec::Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], "RAX=0000000000000001 RBX=0000000000000000 RCX=0000000000000000 RDX=0000000000000000");
+    assert_eq!(lines[1], "RSI=0000000000000000 RDI=0000000000000000 RBP=0000000000000000  R8=00000000deadbeef");
+    assert_eq!(lines[3], "R13=0000000000000000 R14=0000000000000000 R15=ffffffffffffffff");
+}
+
+#[test_case]
+fn test_exception_frame_layout() {
+    assert_eq!(core::mem::size_of::<SavedRegisters>(), 15 * 8);
+    assert_eq!(core::mem::offset_of!(ExceptionFrame, registers), 512 + 8);
+    assert_eq!(core::mem::offset_of!(ExceptionFrame, error_code), 512 + 16 * 8);
+    assert_eq!(core::mem::offset_of!(ExceptionFrame, stack_frame), 512 + 17 * 8);
+    // the stubs reserve 520 bytes below the registers
+    assert_eq!(core::mem::size_of::<ExceptionFrame>(), 512 + 22 * 8);
+}
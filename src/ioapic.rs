@@ -0,0 +1,262 @@
+//! The I/O APIC: the router between device interrupt lines and the local
+//! APICs. Each of its pins is a global system interrupt (GSI) from
+//! `gsi_base` on, and a redirection entry per pin says which vector to
+//! raise on which CPU, with what polarity and trigger mode, and whether
+//! the pin is masked.
+//!
+//! ISA IRQ n arrives on GSI n unless the MADT overrides it (the PIT's
+//! IRQ 0 usually sits on GSI 2); the overrides also give the pin's
+//! polarity and trigger mode, which otherwise are the ISA ones, active
+//! high and edge triggered. `interrupts::enable_apic` routes every ISA IRQ
+//! here to the vector the 8259s used for it.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, OffsetPageTable, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::acpi::SystemTopology;
+
+const REG_SELECT: u64 = 0x00;
+const REG_WINDOW: u64 = 0x10;
+
+const IOAPIC_VERSION: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+// MPS INTI flags of an interrupt source override
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MASK: u16 = 0b11 << 2;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+#[derive(Debug)]
+pub enum IoApicError {
+    /// The MADT lists none.
+    NoIoApic,
+    Map(MapToError<Size4KiB>),
+    /// No I/O APIC has a pin for this GSI.
+    NoPin(u32),
+    /// `init` has not run (or failed).
+    NotInitialized,
+}
+
+impl fmt::Display for IoApicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IoApicError::NoIoApic => write!(f, "no I/O APIC in the MADT"),
+            IoApicError::Map(err) => write!(f, "could not map the I/O APIC: {:?}", err),
+            IoApicError::NoPin(gsi) => write!(f, "no I/O APIC pin for GSI {}", gsi),
+            IoApicError::NotInitialized => write!(f, "I/O APIC not initialized"),
+        }
+    }
+}
+
+/// Where an ISA IRQ comes in and how its line signals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level: bool,
+}
+
+impl Route {
+    pub fn for_isa(topology: &SystemTopology, irq: u8) -> Route {
+        let flags = topology
+            .overrides
+            .iter()
+            .find(|o| o.bus == 0 && o.source == irq)
+            .map_or(0, |o| o.flags);
+        Route {
+            gsi: topology.isa_irq_gsi(irq),
+            // "conforming to the bus" is active high and edge for ISA
+            active_low: flags & POLARITY_MASK == POLARITY_ACTIVE_LOW,
+            level: flags & TRIGGER_MASK == TRIGGER_LEVEL,
+        }
+    }
+}
+
+/// A redirection entry for fixed delivery of `vector` to the CPU whose
+/// local APIC has id `apic_id`.
+pub fn redirection_entry(vector: u8, apic_id: u8, route: Route, masked: bool) -> u64 {
+    let mut entry = u64::from(vector) | u64::from(apic_id) << 56;
+    if route.active_low {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+    if route.level {
+        entry |= ENTRY_LEVEL;
+    }
+    if masked {
+        entry |= ENTRY_MASKED;
+    }
+    entry
+}
+
+pub struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl IoApic {
+    /// The register pair is one select-then-access sequence, so callers
+    /// hold `LOCK` with interrupts off.
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + REG_SELECT).as_mut_ptr::<u32>(), reg);
+            ptr::read_volatile((self.base + REG_WINDOW).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + REG_SELECT).as_mut_ptr::<u32>(), reg);
+            ptr::write_volatile((self.base + REG_WINDOW).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    /// Redirection entries, one per pin.
+    pub fn pins(&self) -> u32 {
+        self.pins
+    }
+
+    fn pin(&self, gsi: u32) -> Option<u32> {
+        gsi.checked_sub(self.gsi_base).filter(|&pin| pin < self.pins)
+    }
+
+    fn entry(&self, pin: u32) -> u64 {
+        let reg = REDIRECTION_TABLE + 2 * pin;
+        u64::from(self.read(reg)) | u64::from(self.read(reg + 1)) << 32
+    }
+
+    /// The pin is masked while the halves are written, so it is never
+    /// live with one half old and the other new.
+    fn set_entry(&self, pin: u32, entry: u64) {
+        let reg = REDIRECTION_TABLE + 2 * pin;
+        self.write(reg, (entry as u32) | ENTRY_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+static IO_APICS: Once<Vec<IoApic>> = Once::new();
+static ISA_ROUTES: Once<[Route; 16]> = Once::new();
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Maps every I/O APIC in the MADT and masks all their pins. Returns how
+/// many there are.
+pub fn init(
+    topology: &SystemTopology,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, IoApicError> {
+    if topology.io_apics.is_empty() {
+        return Err(IoApicError::NoIoApic);
+    }
+    let mut io_apics = Vec::new();
+    for io_apic in &topology.io_apics {
+        let base = crate::memory::map_mmio(PhysAddr::new(u64::from(io_apic.address)), mapper, frame_allocator)
+            .map_err(IoApicError::Map)?;
+        let mut io_apic = IoApic { base, gsi_base: io_apic.gsi_base, pins: 0 };
+        io_apic.pins = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        io_apics.push(io_apic);
+    }
+    without_interrupts(|| {
+        let _lock = LOCK.lock();
+        for io_apic in &io_apics {
+            for pin in 0..io_apic.pins {
+                io_apic.set_entry(pin, io_apic.entry(pin) | ENTRY_MASKED);
+            }
+        }
+    });
+    let count = io_apics.len();
+    IO_APICS.call_once(|| io_apics);
+    ISA_ROUTES.call_once(|| core::array::from_fn(|irq| Route::for_isa(topology, irq as u8)));
+    Ok(count)
+}
+
+/// The I/O APICs `init` found.
+pub fn io_apics() -> &'static [IoApic] {
+    IO_APICS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Where ISA IRQ `irq` (0-15) comes in, once `init` has run.
+pub fn isa_route(irq: u8) -> Option<Route> {
+    ISA_ROUTES.get().map(|routes| routes[usize::from(irq)])
+}
+
+fn with_pin<R>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> R) -> Result<R, IoApicError> {
+    let io_apics = IO_APICS.get().ok_or(IoApicError::NotInitialized)?;
+    let (io_apic, pin) = io_apics
+        .iter()
+        .find_map(|io_apic| io_apic.pin(gsi).map(|pin| (io_apic, pin)))
+        .ok_or(IoApicError::NoPin(gsi))?;
+    Ok(without_interrupts(|| {
+        let _lock = LOCK.lock();
+        f(io_apic, pin)
+    }))
+}
+
+/// Sends ISA IRQ `irq` to the CPU with local APIC `apic_id` as `vector`.
+pub fn route_isa(irq: u8, vector: u8, apic_id: u8, masked: bool) -> Result<(), IoApicError> {
+    let route = isa_route(irq).ok_or(IoApicError::NotInitialized)?;
+    with_pin(route.gsi, |io_apic, pin| io_apic.set_entry(pin, redirection_entry(vector, apic_id, route, masked)))
+}
+
+/// Masks or unmasks the pin ISA IRQ `irq` comes in on.
+pub fn set_isa_masked(irq: u8, masked: bool) -> Result<(), IoApicError> {
+    let route = isa_route(irq).ok_or(IoApicError::NotInitialized)?;
+    with_pin(route.gsi, |io_apic, pin| {
+        let entry = io_apic.entry(pin);
+        io_apic.set_entry(pin, if masked { entry | ENTRY_MASKED } else { entry & !ENTRY_MASKED });
+    })
+}
+
+/// Whether the pin ISA IRQ `irq` comes in on is masked; `None` before
+/// `init`.
+pub fn isa_masked(irq: u8) -> Option<bool> {
+    let route = isa_route(irq)?;
+    with_pin(route.gsi, |io_apic, pin| io_apic.entry(pin) & ENTRY_MASKED != 0).ok()
+}
+
+//test case
+#[cfg(test)]
+fn topology(overrides: &[(u8, u32, u16)]) -> SystemTopology {
+    use crate::acpi::madt::InterruptOverride;
+    SystemTopology {
+        local_apic_address: 0xfee0_0000,
+        pcat_compat: true,
+        cpus: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: overrides.iter().map(|&(source, gsi, flags)| InterruptOverride { bus: 0, source, gsi, flags }).collect(),
+        skipped_entries: 0,
+    }
+}
+
+#[test_case]
+fn test_isa_routes() {
+    // what QEMU's MADT says: the PIT on GSI 2, the PCI links level triggered
+    let topology = topology(&[(0, 2, 0), (5, 5, 0x0d), (9, 9, 0x0d), (11, 11, 0x0f)]);
+    assert_eq!(Route::for_isa(&topology, 0), Route { gsi: 2, active_low: false, level: false });
+    assert_eq!(Route::for_isa(&topology, 1), Route { gsi: 1, active_low: false, level: false });
+    assert_eq!(Route::for_isa(&topology, 9), Route { gsi: 9, active_low: false, level: true });
+    assert_eq!(Route::for_isa(&topology, 11), Route { gsi: 11, active_low: true, level: true });
+}
+
+#[test_case]
+fn test_redirection_entry() {
+    let edge = Route { gsi: 1, active_low: false, level: false };
+    assert_eq!(redirection_entry(33, 0, edge, false), 33);
+    assert_eq!(redirection_entry(33, 3, edge, true), 33 | 1 << 16 | 3 << 56);
+    let level_low = Route { gsi: 11, active_low: true, level: true };
+    assert_eq!(redirection_entry(43, 1, level_low, false), 43 | 1 << 13 | 1 << 15 | 1 << 56);
+}
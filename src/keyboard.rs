@@ -0,0 +1,340 @@
+//! The PS/2 keyboard: scancode input and commands to the keyboard.
+//!
+//! The IRQ 1 handler in `interrupts` only reads the scancode and queues it
+//! here; the `task::keyboard` task decodes the queue and feeds the keys to
+//! the console, so shell commands run outside the interrupt. While a command to the keyboard runs, IRQ 1 is masked and the
+//! replies are polled from the controller instead.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use crate::interrupts;
+use crate::sync::ByteQueue;
+
+pub mod layout;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer came from the mouse.
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+const CMD_SET_TYPEMATIC: u8 = 0xf3;
+const REPLY_ACK: u8 = 0xfa;
+const REPLY_RESEND: u8 = 0xfe;
+
+const MAX_RESENDS: u32 = 3;
+/// Status polls before giving up on the controller, roughly 100 ms.
+const POLL_LIMIT: u32 = 100_000;
+
+/// Repeat rate in tenths of characters per second, indexed by the 5-bit
+/// rate code: (8 + bits 0-2) * 2^(bits 3-4) * 4.17 ms between repeats.
+const RATE_TENTHS: [u16; 32] = [
+    300, 267, 240, 218, 200, 185, 171, 160, 150, 133, 120, 109, 100, 92, 86, 80,
+    75, 67, 60, 55, 50, 46, 43, 40, 37, 33, 30, 27, 25, 23, 21, 20,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepeatRate(u8);
+
+impl RepeatRate {
+    pub const FASTEST: RepeatRate = RepeatRate(0x00);
+    pub const SLOWEST: RepeatRate = RepeatRate(0x1f);
+
+    pub fn from_code(code: u8) -> Option<RepeatRate> {
+        (code <= Self::SLOWEST.0).then_some(RepeatRate(code))
+    }
+
+    /// The rate closest to `tenths` tenths of a character per second, if
+    /// it is within the 2.0-30.0 range the keyboard supports.
+    pub fn from_tenths(tenths: u16) -> Option<RepeatRate> {
+        if !(Self::SLOWEST.tenths()..=Self::FASTEST.tenths()).contains(&tenths) {
+            return None;
+        }
+        let code = (0..RATE_TENTHS.len())
+            .min_by_key(|&code| RATE_TENTHS[code].abs_diff(tenths))
+            .expect("the rate table is not empty");
+        Some(RepeatRate(code as u8))
+    }
+
+    /// Parses characters per second with at most one decimal, like `10.9`.
+    pub fn parse(text: &str) -> Option<RepeatRate> {
+        let (whole, fraction) = text.split_once('.').unwrap_or((text, "0"));
+        if fraction.len() != 1 {
+            return None;
+        }
+        let tenths = whole.parse::<u16>().ok()?.checked_mul(10)?.checked_add(fraction.parse().ok()?)?;
+        Self::from_tenths(tenths)
+    }
+
+    pub fn code(&self) -> u8 {
+        self.0
+    }
+
+    pub fn tenths(&self) -> u16 {
+        RATE_TENTHS[usize::from(self.0)]
+    }
+}
+
+impl fmt::Display for RepeatRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{} cps", self.tenths() / 10, self.tenths() % 10)
+    }
+}
+
+/// Time a key is held before it starts repeating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepeatDelay {
+    Ms250 = 0,
+    Ms500 = 1,
+    Ms750 = 2,
+    Ms1000 = 3,
+}
+
+impl RepeatDelay {
+    pub fn from_millis(millis: u32) -> Option<RepeatDelay> {
+        match millis {
+            250 => Some(RepeatDelay::Ms250),
+            500 => Some(RepeatDelay::Ms500),
+            750 => Some(RepeatDelay::Ms750),
+            1000 => Some(RepeatDelay::Ms1000),
+            _ => None,
+        }
+    }
+
+    pub fn millis(&self) -> u32 {
+        250 * (*self as u32 + 1)
+    }
+}
+
+/// The argument byte of the set-typematic command: delay in bits 5-6,
+/// rate in bits 0-4, bit 7 zero.
+pub fn typematic_byte(rate: RepeatRate, delay: RepeatDelay) -> u8 {
+    (delay as u8) << 5 | rate.code()
+}
+
+/// Splits a typematic byte back up; `None` if bit 7 is set.
+pub fn decode_typematic(byte: u8) -> Option<(RepeatRate, RepeatDelay)> {
+    if byte & 0x80 != 0 {
+        return None;
+    }
+    let delay = match byte >> 5 {
+        0 => RepeatDelay::Ms250,
+        1 => RepeatDelay::Ms500,
+        2 => RepeatDelay::Ms750,
+        _ => RepeatDelay::Ms1000,
+    };
+    Some((RepeatRate(byte & 0x1f), delay))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyboardError {
+    /// The controller never took the byte or the keyboard never answered.
+    Timeout,
+    /// The keyboard kept asking for the byte again.
+    TooManyResends,
+    /// Neither ACK nor resend.
+    UnexpectedReply(u8),
+}
+
+impl fmt::Display for KeyboardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyboardError::Timeout => f.write_str("keyboard did not answer"),
+            KeyboardError::TooManyResends => f.write_str("keyboard kept asking for a resend"),
+            KeyboardError::UnexpectedReply(byte) => write!(f, "unexpected reply {:#04x}", byte),
+        }
+    }
+}
+
+/// Last typematic byte sent; starts at the keyboard's power-on default
+/// of 10.9 cps after 500 ms.
+static TYPEMATIC: AtomicU8 = AtomicU8::new(0x2b);
+
+/// Sets how fast held keys repeat and after how long.
+pub fn set_typematic(rate: RepeatRate, delay: RepeatDelay) -> Result<(), KeyboardError> {
+    let byte = typematic_byte(rate, delay);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // keep the IRQ 1 handler from eating the ACKs
+        interrupts::mask_irq(1);
+        let result = send(CMD_SET_TYPEMATIC).and_then(|()| send(byte));
+        interrupts::unmask_irq(1);
+        result
+    })?;
+    TYPEMATIC.store(byte, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The rate and delay last set with `set_typematic`.
+pub fn typematic() -> (RepeatRate, RepeatDelay) {
+    decode_typematic(TYPEMATIC.load(Ordering::Relaxed)).expect("only valid bytes are stored")
+}
+
+/// Scancodes read by the interrupt handler and not decoded yet; the
+/// keyboard task is the only reader.
+static SCANCODES: ByteQueue = ByteQueue::new();
+
+lazy_static! {
+    static ref DECODER: Mutex<layout::Decoder> =
+        Mutex::new(layout::Decoder::new(layout::Layout::from_cmdline()));
+}
+
+/// Queues a scancode the IRQ 1 handler read and wakes the keyboard task.
+/// Never blocks.
+pub fn queue_scancode(scancode: u8) {
+    if SCANCODES.push(scancode) {
+        crate::task::keyboard::WAKER.wake();
+    }
+}
+
+/// The oldest queued scancode, for the keyboard task.
+pub(crate) fn next_scancode() -> Option<u8> {
+    SCANCODES.pop()
+}
+
+/// Scancodes lost because the keyboard task fell behind.
+pub fn dropped_count() -> u64 {
+    SCANCODES.dropped()
+}
+
+/// Decodes a scancode and feeds any key it finishes to the console, which
+/// may run a shell command. Shift+PgUp and Shift+PgDn scroll the screen
+/// half a page instead.
+pub fn handle_scancode(scancode: u8) {
+    use crate::console::{self, Key, Terminal};
+    use crate::vga_buffer::{self, BUFFER_HEIGHT};
+    use pc_keyboard::{DecodedKey, KeyCode};
+
+    let mut decoder = DECODER.lock();
+    // one byte finishes at most one key, and it is not shift
+    let shift = decoder.shift_held();
+    decoder.add_byte(scancode, |decoded| {
+        let key = match decoded {
+            DecodedKey::RawKey(KeyCode::ArrowUp) => Key::Up,
+            DecodedKey::RawKey(KeyCode::ArrowDown) => Key::Down,
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => Key::Left,
+            DecodedKey::RawKey(KeyCode::ArrowRight) => Key::Right,
+            DecodedKey::RawKey(KeyCode::Home) => Key::Home,
+            DecodedKey::RawKey(KeyCode::End) => Key::End,
+            // the decoder gives Delete as DEL, which terminals send for Backspace
+            DecodedKey::Unicode('\x7f') => Key::Delete,
+            DecodedKey::Unicode(character) => Key::Char(character),
+            DecodedKey::RawKey(KeyCode::PageUp) if shift => return vga_buffer::scroll_up(BUFFER_HEIGHT / 2),
+            DecodedKey::RawKey(KeyCode::PageDown) if shift => return vga_buffer::scroll_down(BUFFER_HEIGHT / 2),
+            DecodedKey::RawKey(_) => return,
+        };
+        console::receive_key(Terminal::Keyboard, key);
+    });
+}
+
+/// Sends one byte and waits for the ACK, repeating it when the keyboard
+/// asks for a resend.
+fn send(byte: u8) -> Result<(), KeyboardError> {
+    for _ in 0..=MAX_RESENDS {
+        write_data(byte)?;
+        match read_data()? {
+            REPLY_ACK => return Ok(()),
+            REPLY_RESEND => continue,
+            other => return Err(KeyboardError::UnexpectedReply(other)),
+        }
+    }
+    Err(KeyboardError::TooManyResends)
+}
+
+/// Takes a byte the keyboard sent, if one is waiting, straight from the
+/// controller: no locks and no IRQ 1, so it works from the panic handler.
+/// Mouse bytes are read and dropped.
+pub fn poll_raw() -> Option<u8> {
+    loop {
+        let status = unsafe { Port::<u8>::new(STATUS_PORT).read() };
+        if status & STATUS_OUTPUT_FULL == 0 {
+            return None;
+        }
+        let byte = unsafe { Port::<u8>::new(DATA_PORT).read() };
+        if status & STATUS_AUX_DATA == 0 {
+            return Some(byte);
+        }
+    }
+}
+
+pub(crate) fn write_data(byte: u8) -> Result<(), KeyboardError> {
+    let mut status = Port::<u8>::new(STATUS_PORT);
+    for _ in 0..POLL_LIMIT {
+        if unsafe { status.read() } & STATUS_INPUT_FULL == 0 {
+            unsafe { Port::new(DATA_PORT).write(byte) };
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(KeyboardError::Timeout)
+}
+
+/// Sends a command to the controller itself rather than the keyboard.
+pub(crate) fn write_command(byte: u8) -> Result<(), KeyboardError> {
+    let mut status = Port::<u8>::new(STATUS_PORT);
+    for _ in 0..POLL_LIMIT {
+        if unsafe { status.read() } & STATUS_INPUT_FULL == 0 {
+            unsafe { Port::new(STATUS_PORT).write(byte) };
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(KeyboardError::Timeout)
+}
+
+pub(crate) fn read_data() -> Result<u8, KeyboardError> {
+    let mut status = Port::<u8>::new(STATUS_PORT);
+    for _ in 0..POLL_LIMIT {
+        if unsafe { status.read() } & STATUS_OUTPUT_FULL != 0 {
+            return Ok(unsafe { Port::new(DATA_PORT).read() });
+        }
+        core::hint::spin_loop();
+    }
+    Err(KeyboardError::Timeout)
+}
+
+//test case
+#[test_case]
+fn test_rate_table_bounds() {
+    assert_eq!(RepeatRate::FASTEST.tenths(), 300);
+    assert_eq!(RepeatRate::SLOWEST.tenths(), 20);
+    assert_eq!(RepeatRate::from_code(0x1f), Some(RepeatRate::SLOWEST));
+    assert_eq!(RepeatRate::from_code(0x20), None);
+    // the table follows the spec formula to a tenth
+    for code in 0..32u32 {
+        let period_us = (8 + (code & 7)) * (1 << (code >> 3)) * 4170;
+        let tenths = (10_000_000 + period_us / 2) / period_us;
+        assert!(u32::from(RATE_TENTHS[code as usize]).abs_diff(tenths) <= 1, "code {:#x}", code);
+    }
+}
+
+#[test_case]
+fn test_rate_from_cps() {
+    assert_eq!(RepeatRate::from_tenths(300), Some(RepeatRate::FASTEST));
+    assert_eq!(RepeatRate::from_tenths(20), Some(RepeatRate::SLOWEST));
+    assert_eq!(RepeatRate::from_tenths(301), None);
+    assert_eq!(RepeatRate::from_tenths(19), None);
+    assert_eq!(RepeatRate::parse("30"), Some(RepeatRate::FASTEST));
+    assert_eq!(RepeatRate::parse("2.0"), Some(RepeatRate::SLOWEST));
+    assert_eq!(RepeatRate::parse("10.9").map(|r| r.code()), Some(0x0b));
+    assert_eq!(RepeatRate::parse("10.95"), None);
+    assert_eq!(RepeatRate::parse("fast"), None);
+    assert_eq!(RepeatRate::parse("1.9"), None);
+}
+
+#[test_case]
+fn test_delay_and_byte() {
+    assert_eq!(RepeatDelay::from_millis(250), Some(RepeatDelay::Ms250));
+    assert_eq!(RepeatDelay::from_millis(1000), Some(RepeatDelay::Ms1000));
+    assert_eq!(RepeatDelay::from_millis(300), None);
+    assert_eq!(RepeatDelay::from_millis(0), None);
+    assert_eq!(RepeatDelay::Ms750.millis(), 750);
+    assert_eq!(typematic_byte(RepeatRate::FASTEST, RepeatDelay::Ms250), 0x00);
+    assert_eq!(typematic_byte(RepeatRate::SLOWEST, RepeatDelay::Ms1000), 0x7f);
+    assert_eq!(typematic_byte(RepeatRate(0x0b), RepeatDelay::Ms500), 0x2b);
+    assert_eq!(decode_typematic(0x7f), Some((RepeatRate::SLOWEST, RepeatDelay::Ms1000)));
+    assert_eq!(decode_typematic(0x80), None);
+}
@@ -0,0 +1,363 @@
+//! Scancodes to characters: the layouts `keymap=us|es` picks from, and
+//! dead keys for the ones that have them.
+//!
+//! On the Spanish layout ´, ¨ (shifted ´) and ` are dead: they print
+//! nothing until the next key. A vowel then comes out accented; space or
+//! the same dead key again gives the accent on its own; any other key gives
+//! the accent followed by that key. Backspace and Escape drop the accent.
+
+use pc_keyboard::layouts::Us104Key;
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, KeyboardLayout, Modifiers, ScancodeSet1};
+
+/// A Spanish ISO keyboard. Keys the US layout shares are left to it.
+pub struct Es105Key;
+
+impl KeyboardLayout for Es105Key {
+    fn map_keycode(&self, keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        let pick = |plain, shifted| DecodedKey::Unicode(if modifiers.is_shifted() { shifted } else { plain });
+        let letter = |lower, upper| DecodedKey::Unicode(if modifiers.is_caps() { upper } else { lower });
+        if modifiers.alt_gr {
+            let c = match keycode {
+                KeyCode::Oem8 => Some('\\'),
+                KeyCode::Key1 => Some('|'),
+                KeyCode::Key2 => Some('@'),
+                KeyCode::Key3 => Some('#'),
+                KeyCode::Key4 => Some('~'),
+                KeyCode::Key5 | KeyCode::E => Some('€'),
+                KeyCode::Key6 => Some('¬'),
+                KeyCode::Oem4 => Some('['),
+                KeyCode::Oem6 => Some(']'),
+                KeyCode::Oem3 => Some('{'),
+                KeyCode::Oem7 => Some('}'),
+                _ => None,
+            };
+            if let Some(c) = c {
+                return DecodedKey::Unicode(c);
+            }
+        }
+        match keycode {
+            KeyCode::Oem8 => pick('º', 'ª'),
+            KeyCode::Key2 => pick('2', '"'),
+            KeyCode::Key3 => pick('3', '·'),
+            KeyCode::Key6 => pick('6', '&'),
+            KeyCode::Key7 => pick('7', '/'),
+            KeyCode::Key8 => pick('8', '('),
+            KeyCode::Key9 => pick('9', ')'),
+            KeyCode::Key0 => pick('0', '='),
+            KeyCode::OemMinus => pick('\'', '?'),
+            KeyCode::OemPlus => pick('¡', '¿'),
+            KeyCode::Oem4 => pick('`', '^'),
+            KeyCode::Oem6 => pick('+', '*'),
+            KeyCode::Oem1 => letter('ñ', 'Ñ'),
+            KeyCode::Oem3 => pick('´', '¨'),
+            KeyCode::Oem7 => letter('ç', 'Ç'),
+            KeyCode::Oem5 => pick('<', '>'),
+            KeyCode::OemComma => pick(',', ';'),
+            KeyCode::OemPeriod => pick('.', ':'),
+            KeyCode::Oem2 => pick('-', '_'),
+            _ => Us104Key.map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Es105,
+}
+
+impl Layout {
+    pub fn parse(name: &str) -> Option<Layout> {
+        match name {
+            "us" => Some(Layout::Us104),
+            "es" => Some(Layout::Es105),
+            _ => None,
+        }
+    }
+
+    /// `keymap=` from the command line, US when it is missing or unknown.
+    pub fn from_cmdline() -> Layout {
+        match crate::cmdline::get("keymap") {
+            None => Layout::Us104,
+            Some(name) => Layout::parse(name).unwrap_or_else(|| {
+                crate::warn!("keyboard: unknown keymap '{}'", name);
+                Layout::Us104
+            }),
+        }
+    }
+
+    pub fn has_dead_keys(self) -> bool {
+        self == Layout::Es105
+    }
+}
+
+impl KeyboardLayout for Layout {
+    fn map_keycode(&self, keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        match self {
+            Layout::Us104 => Us104Key.map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Es105 => Es105Key.map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accent {
+    Acute,
+    Diaeresis,
+    Grave,
+}
+
+/// Each vowel with its acute, diaeresis and grave forms.
+const COMPOSITIONS: [(char, [char; 3]); 10] = [
+    ('a', ['á', 'ä', 'à']),
+    ('e', ['é', 'ë', 'è']),
+    ('i', ['í', 'ï', 'ì']),
+    ('o', ['ó', 'ö', 'ò']),
+    ('u', ['ú', 'ü', 'ù']),
+    ('A', ['Á', 'Ä', 'À']),
+    ('E', ['É', 'Ë', 'È']),
+    ('I', ['Í', 'Ï', 'Ì']),
+    ('O', ['Ó', 'Ö', 'Ò']),
+    ('U', ['Ú', 'Ü', 'Ù']),
+];
+
+impl Accent {
+    /// The accent a dead key's character stands for.
+    pub fn from_char(c: char) -> Option<Accent> {
+        match c {
+            '´' => Some(Accent::Acute),
+            '¨' => Some(Accent::Diaeresis),
+            '`' => Some(Accent::Grave),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(self) -> char {
+        match self {
+            Accent::Acute => '´',
+            Accent::Diaeresis => '¨',
+            Accent::Grave => '`',
+        }
+    }
+
+    /// `base` with this accent, if it takes one.
+    pub fn compose(self, base: char) -> Option<char> {
+        let (_, forms) = COMPOSITIONS.iter().find(|(vowel, _)| *vowel == base)?;
+        Some(forms[self as usize])
+    }
+}
+
+/// The dead-key state: at most one accent waiting for its letter.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeadKeys {
+    pending: Option<Accent>,
+}
+
+impl DeadKeys {
+    pub const fn new() -> Self {
+        DeadKeys { pending: None }
+    }
+
+    pub fn pending(&self) -> Option<Accent> {
+        self.pending
+    }
+
+    /// Feeds one decoded character and emits what it completes, nothing
+    /// to two characters.
+    pub fn feed(&mut self, c: char, mut emit: impl FnMut(char)) {
+        let Some(accent) = self.pending.take() else {
+            match Accent::from_char(c) {
+                Some(accent) => self.pending = Some(accent),
+                None => emit(c),
+            }
+            return;
+        };
+        if let Some(next) = Accent::from_char(c) {
+            emit(accent.as_char());
+            // a different dead key starts over; the same one twice is done
+            if next != accent {
+                self.pending = Some(next);
+            }
+            return;
+        }
+        match c {
+            ' ' => emit(accent.as_char()),
+            '\x08' | '\x1b' => {}
+            c => match accent.compose(c) {
+                Some(composed) => emit(composed),
+                None => {
+                    emit(accent.as_char());
+                    emit(c);
+                }
+            },
+        }
+    }
+}
+
+/// Keyboard bytes to keys: the decoder for a layout and, when the layout
+/// has them, its dead keys.
+pub struct Decoder {
+    keyboard: Keyboard<Layout, ScancodeSet1>,
+    dead_keys: Option<DeadKeys>,
+    /// Whether either shift key is down; the decoder keeps its own copy
+    /// private.
+    shift: [bool; 2],
+}
+
+impl Decoder {
+    pub fn new(layout: Layout) -> Self {
+        Decoder {
+            keyboard: Keyboard::new(ScancodeSet1::new(), layout, HandleControl::MapLettersToUnicode),
+            dead_keys: layout.has_dead_keys().then(DeadKeys::new),
+            shift: [false; 2],
+        }
+    }
+
+    /// Whether a shift key is held, as of the last byte fed.
+    pub fn shift_held(&self) -> bool {
+        self.shift[0] || self.shift[1]
+    }
+
+    /// Feeds one byte from the keyboard and emits the keys it completes.
+    pub fn add_byte(&mut self, scancode: u8, mut emit: impl FnMut(DecodedKey)) {
+        let Ok(Some(event)) = self.keyboard.add_byte(scancode) else {
+            return;
+        };
+        let side = match event.code {
+            KeyCode::LShift => Some(0),
+            KeyCode::RShift => Some(1),
+            _ => None,
+        };
+        if let Some(side) = side {
+            self.shift[side] = event.state != KeyState::Up;
+        }
+        match (self.keyboard.process_keyevent(event), &mut self.dead_keys) {
+            (Some(DecodedKey::Unicode(c)), Some(dead_keys)) => dead_keys.feed(c, |c| emit(DecodedKey::Unicode(c))),
+            (Some(key), _) => emit(key),
+            (None, _) => {}
+        }
+    }
+}
+
+//test case
+#[cfg(test)]
+const SHIFT_DOWN: u8 = 0x2a;
+#[cfg(test)]
+const SHIFT_UP: u8 = 0xaa;
+#[cfg(test)]
+const ACUTE_KEY: u8 = 0x28;
+#[cfg(test)]
+const GRAVE_KEY: u8 = 0x1a;
+
+/// What a tap of each key in `keys` types, with shift held for the
+/// `true` ones.
+#[cfg(test)]
+fn type_keys(layout: Layout, keys: &[(u8, bool)]) -> alloc::string::String {
+    let mut decoder = Decoder::new(layout);
+    let mut typed = alloc::string::String::new();
+    let mut add = |decoder: &mut Decoder, byte| {
+        decoder.add_byte(byte, |key| {
+            if let DecodedKey::Unicode(c) = key {
+                typed.push(c);
+            }
+        })
+    };
+    for &(scancode, shifted) in keys {
+        if shifted {
+            add(&mut decoder, SHIFT_DOWN);
+        }
+        add(&mut decoder, scancode);
+        add(&mut decoder, scancode | 0x80);
+        if shifted {
+            add(&mut decoder, SHIFT_UP);
+        }
+    }
+    typed
+}
+
+#[test_case]
+fn test_compose_table() {
+    let accents = [Accent::Acute, Accent::Diaeresis, Accent::Grave];
+    for (vowel, forms) in COMPOSITIONS {
+        for (accent, form) in accents.iter().zip(forms) {
+            assert_eq!(accent.compose(vowel), Some(form));
+        }
+    }
+    for accent in accents {
+        assert_eq!(Accent::from_char(accent.as_char()), Some(accent));
+        for base in ['y', 'n', 'B', ' ', '1', 'á'] {
+            assert_eq!(accent.compose(base), None);
+        }
+    }
+}
+
+#[test_case]
+fn test_dead_key_scancodes() {
+    // a e i o u on set 1
+    let vowels = [0x1e, 0x12, 0x17, 0x18, 0x16];
+    // (dead key, shifted) for acute, diaeresis and grave
+    let dead = [(ACUTE_KEY, false), (ACUTE_KEY, true), (GRAVE_KEY, false)];
+    for (i, &vowel) in vowels.iter().enumerate() {
+        for (j, &(key, shifted)) in dead.iter().enumerate() {
+            let lower = type_keys(Layout::Es105, &[(key, shifted), (vowel, false)]);
+            assert_eq!(lower.chars().collect::<alloc::vec::Vec<_>>(), [COMPOSITIONS[i].1[j]]);
+            let upper = type_keys(Layout::Es105, &[(key, shifted), (vowel, true)]);
+            assert_eq!(upper.chars().collect::<alloc::vec::Vec<_>>(), [COMPOSITIONS[i + 5].1[j]]);
+        }
+    }
+}
+
+#[test_case]
+fn test_dead_key_cancel() {
+    const A: (u8, bool) = (0x1e, false);
+    const SPACE: (u8, bool) = (0x39, false);
+    const ACUTE: (u8, bool) = (ACUTE_KEY, false);
+    const DIAERESIS: (u8, bool) = (ACUTE_KEY, true);
+    const GRAVE: (u8, bool) = (GRAVE_KEY, false);
+    assert_eq!(type_keys(Layout::Es105, &[ACUTE]), "");
+    assert_eq!(type_keys(Layout::Es105, &[ACUTE, SPACE]), "´");
+    assert_eq!(type_keys(Layout::Es105, &[DIAERESIS, SPACE]), "¨");
+    assert_eq!(type_keys(Layout::Es105, &[GRAVE, SPACE]), "`");
+    // twice gives the accent itself, and the third press is dead again
+    assert_eq!(type_keys(Layout::Es105, &[ACUTE, ACUTE]), "´");
+    assert_eq!(type_keys(Layout::Es105, &[GRAVE, GRAVE, A]), "`a");
+    assert_eq!(type_keys(Layout::Es105, &[ACUTE, ACUTE, ACUTE, A]), "´á");
+    // another dead key gives the first accent and waits with the second
+    assert_eq!(type_keys(Layout::Es105, &[ACUTE, GRAVE, A]), "´à");
+    // keys that take no accent come after it
+    assert_eq!(type_keys(Layout::Es105, &[ACUTE, (0x31, false)]), "´n");
+    assert_eq!(type_keys(Layout::Es105, &[DIAERESIS, (0x02, false)]), "¨1");
+    assert_eq!(type_keys(Layout::Es105, &[ACUTE, (0x27, false)]), "´ñ");
+    // backspace and escape drop it
+    assert_eq!(type_keys(Layout::Es105, &[ACUTE, (0x0e, false), A]), "a");
+    assert_eq!(type_keys(Layout::Es105, &[GRAVE, (0x01, false), A]), "a");
+    // shift on its own keeps the accent waiting
+    assert_eq!(type_keys(Layout::Es105, &[ACUTE, (SHIFT_DOWN, false), A]), "á");
+    // the US layout has no dead keys
+    assert_eq!(type_keys(Layout::Us104, &[(GRAVE_KEY, false), A]), "[a");
+    assert_eq!(type_keys(Layout::Us104, &[(0x29, false), A]), "`a");
+}
+
+#[test_case]
+fn test_spanish_layout() {
+    assert_eq!(type_keys(Layout::Es105, &[(0x27, false), (0x27, true), (0x2b, false)]), "ñÑç");
+    assert_eq!(type_keys(Layout::Es105, &[(0x0d, false), (0x0d, true), (0x0c, true)]), "¡¿?");
+    assert_eq!(type_keys(Layout::Es105, &[(0x03, true), (0x04, true), (0x08, true), (0x0b, true)]), "\"·/=");
+    assert_eq!(type_keys(Layout::Es105, &[(0x35, false), (0x56, true), (0x1b, true)]), "->*");
+    assert_eq!(Layout::parse("es"), Some(Layout::Es105));
+    assert_eq!(Layout::parse("fr"), None);
+}
+
+#[test_case]
+fn test_shift_held() {
+    const RIGHT_SHIFT_DOWN: u8 = 0x36;
+    let mut decoder = Decoder::new(Layout::Us104);
+    assert!(!decoder.shift_held());
+    decoder.add_byte(SHIFT_DOWN, |_| {});
+    decoder.add_byte(RIGHT_SHIFT_DOWN, |_| {});
+    decoder.add_byte(SHIFT_UP, |_| {});
+    // the right one is still down
+    assert!(decoder.shift_held());
+    decoder.add_byte(RIGHT_SHIFT_DOWN | 0x80, |_| {});
+    assert!(!decoder.shift_held());
+}
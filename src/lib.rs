@@ -4,6 +4,8 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(never_type)]
+#![feature(alloc_error_handler)]
 #![allow(unused_imports)]
 
 extern crate alloc;
@@ -13,20 +15,105 @@ pub mod shell;
 pub use shell::Shell;
 
 pub mod serial;
+pub mod console;
+pub mod debugcon;
 pub mod vga_buffer;
+pub mod ansi;
+pub mod graphics;
 pub mod interrupts;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod bytes;
+pub mod acpi;
+pub mod smbios;
+pub mod multiboot2;
+pub mod boot;
+pub mod power;
+pub mod pci;
+pub mod block;
+pub mod partitions;
+pub mod fat;
+pub mod vfs;
+pub mod tar;
+pub mod fs;
+pub mod pipe;
+pub mod virtio;
+pub mod ahci;
+pub mod nvme;
+pub mod ide;
+pub mod rtl8139;
+pub mod e1000;
+pub mod time;
+pub mod clock;
+pub mod net;
+pub mod fw_cfg;
+pub mod cmdline;
+pub mod log;
+pub mod debug;
+pub mod gdbstub;
+pub mod panic;
+pub mod watchdog;
+pub mod boottime;
+pub mod apic;
+pub mod ioapic;
+pub mod smp;
+pub mod hpet;
+pub mod xmodem;
+pub mod loader;
+pub mod elf;
+pub mod syscall;
+pub mod usermode;
+pub mod process;
+pub mod scheduler;
+pub mod sync;
+pub mod channel;
+pub mod percpu;
+pub mod keyboard;
+pub mod mouse;
+pub mod cmos;
+pub mod rng;
+pub mod perf;
+pub mod stats;
+pub mod cpu;
+pub mod symbols;
+pub mod task;
+pub mod testing;
+
+pub use testing::{exit_qemu, test_panic_handler, test_runner, QemuExitCode, Testable};
 
 
 
 
 pub fn init() {  // ← ahora se llama init
+    cmdline::init();
+    log::init();
+    panic::init();
+    vga_buffer::init_console();
+    cpu::init();
+    // before the first exception: the stubs save FPU state
+    cpu::enable_sse();
     gdt::init();
+    percpu::init(0);
+    boottime::mark("gdt");
     interrupts::init_idt();
+    syscall::init();
+    boottime::mark("idt");
     println!("PIC initializing...");
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init_pit();
+    console::init();
+    gdbstub::init();
+    boottime::mark("pic");
+    cmos::init();
+    clock::init();
+    match time::calibrate_tsc() {
+        Ok(hz) => println!("TSC: {} MHz", hz / 1_000_000),
+        Err(err) => crate::warn!("TSC: {}, using timer ticks", err),
+    }
+    boottime::mark("tsc");
+    rng::init();
+    perf::init();
     println!("PIC initialized, enabling interrupts...");
     x86_64::instructions::interrupts::enable();
     println!("Interrupts enabled!");
@@ -36,52 +123,24 @@ pub fn init() {  // ← ahora se llama init
 
 pub fn hlt_loop() -> ! {
     loop{
-        x86_64::instructions::hlt();
-    }
-}
-
-
-
-pub trait Testable {
-    fn run(&self) -> ();
-}
-
-impl<T> Testable for T
-where
-    T: Fn(),
-{
-    fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
+        cpu::halt();
     }
 }
 
-pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
-    }
-    exit_qemu(QemuExitCode::Success);
-}
 
-pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-    hlt_loop();
-}
 
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use boot::BootInfo;
 
 #[cfg(test)]
-entry_point!(test_kernel_main);
+crate::entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
     test_main();
     hlt_loop();
 }
@@ -93,18 +152,3 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum QemuExitCode {
-    Success = 0x10,
-    Failed = 0x11,
-}
-
-pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
-
-    unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
-    }
-}
\ No newline at end of file
@@ -0,0 +1,281 @@
+//! Loads position-independent flat binaries into a scratch region and runs
+//! them. Meant for quick experiments: the payload runs in ring 0 with the
+//! kernel's page tables, so it can do anything the kernel can.
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::structures::paging::{
+    mapper::UnmapError, page::PageRangeInclusive, FrameAllocator, Mapper, Page, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+use crate::{println, time, xmodem};
+
+/// Where `run-serial` puts payloads. Nothing else lives in this PML4 slot.
+pub const SCRATCH_START: u64 = 0x_6666_0000_0000;
+/// Largest payload accepted.
+pub const MAX_FLAT_SIZE: usize = 64 * 1024;
+
+/// Functions a payload can call back into. Passed as the entry point's
+/// only argument; payloads that don't need it can ignore RDI and be plain
+/// `extern "C" fn() -> u64`s.
+#[repr(C)]
+pub struct PayloadApi {
+    /// Prints `len` bytes of UTF-8 at `ptr` followed by a newline.
+    pub println: extern "C" fn(ptr: *const u8, len: usize),
+    pub ticks: extern "C" fn() -> u64,
+}
+
+pub type EntryPoint = extern "C" fn(api: &PayloadApi) -> u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Empty,
+    TooLarge(usize),
+    /// `dest` is not page-aligned.
+    Misaligned,
+    /// Something is already mapped in the destination range.
+    AlreadyMapped,
+    OutOfMemory,
+    /// The global frame allocator has not been installed yet.
+    NoPaging,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Empty => f.write_str("empty payload"),
+            LoadError::TooLarge(len) => write!(f, "payload of {} bytes exceeds {} bytes", len, MAX_FLAT_SIZE),
+            LoadError::Misaligned => f.write_str("destination is not page-aligned"),
+            LoadError::AlreadyMapped => f.write_str("destination already mapped"),
+            LoadError::OutOfMemory => f.write_str("out of physical memory"),
+            LoadError::NoPaging => f.write_str("paging is not available yet"),
+        }
+    }
+}
+
+/// Frames given back by `unload_flat`. The frame allocator can't take
+/// frames back, so the loader reuses them for the next payload.
+static SPARE_FRAMES: Mutex<Vec<PhysFrame>> = Mutex::new(Vec::new());
+
+struct Recycler<'a, A> {
+    spare: &'a mut Vec<PhysFrame>,
+    inner: &'a mut A,
+}
+
+unsafe impl<A: FrameAllocator<Size4KiB>> FrameAllocator<Size4KiB> for Recycler<'_, A> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.spare.pop().or_else(|| self.inner.allocate_frame())
+    }
+}
+
+fn pages(dest: VirtAddr, len: usize) -> PageRangeInclusive {
+    let first = Page::<Size4KiB>::containing_address(dest);
+    let last = Page::containing_address(dest + (len.max(1) - 1) as u64);
+    Page::range_inclusive(first, last)
+}
+
+/// Maps zeroed, writable frames at `pages`, reusing frames returned by
+/// `unmap_pages` first. `extra_flags` are added to PRESENT | WRITABLE;
+/// USER_ACCESSIBLE there also reaches the page tables created on the way.
+/// On failure nothing stays mapped.
+pub(crate) fn map_zeroed(
+    pages: PageRangeInclusive,
+    extra_flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), LoadError> {
+    if pages.into_iter().any(|page| mapper.translate_page(page).is_ok()) {
+        return Err(LoadError::AlreadyMapped);
+    }
+    let (mapped, result) = {
+        let mut spare = SPARE_FRAMES.lock();
+        let mut frames = Recycler { spare: &mut spare, inner: frame_allocator };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | extra_flags;
+        let mut mapped = 0;
+        let mut result = Ok(());
+        for page in pages {
+            let Some(frame) = frames.allocate_frame() else {
+                result = Err(LoadError::OutOfMemory);
+                break;
+            };
+            match unsafe { mapper.map_to(page, frame, flags, &mut frames) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    frames.spare.push(frame);
+                    result = Err(LoadError::OutOfMemory);
+                    break;
+                }
+            }
+            unsafe { core::ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, 4096) };
+            mapped += 1;
+        }
+        (mapped, result)
+    };
+    if result.is_err() && mapped > 0 {
+        unmap_pages(Page::range_inclusive(pages.start, pages.start + (mapped - 1)), mapper);
+    }
+    result
+}
+
+/// Unmaps `pages`, keeping the frames for later loads. Returns how many
+/// were mapped.
+pub(crate) fn unmap_pages(pages: PageRangeInclusive, mapper: &mut impl Mapper<Size4KiB>) -> usize {
+    let mut spare = SPARE_FRAMES.lock();
+    let mut unmapped = 0;
+    for page in pages {
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                spare.push(frame);
+                unmapped += 1;
+            }
+            Err(UnmapError::PageNotMapped) => {}
+            Err(err) => println!("loader: could not unmap {:?}: {:?}", page, err),
+        }
+    }
+    unmapped
+}
+
+/// A frame for a caller that manages the page itself, preferring
+/// recycled ones.
+pub(crate) fn allocate_frame(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Option<PhysFrame> {
+    let mut spare = SPARE_FRAMES.lock();
+    Recycler { spare: &mut spare, inner: frame_allocator }.allocate_frame()
+}
+
+/// Keeps a frame that is no longer mapped anywhere for later loads.
+pub(crate) fn recycle_frame(frame: PhysFrame) {
+    SPARE_FRAMES.lock().push(frame);
+}
+
+/// Maps writable, executable pages at `dest` for `data`, copies it there
+/// and returns its first byte as the entry point. Undo with `unload_flat`.
+pub fn load_flat(
+    dest: VirtAddr,
+    data: &[u8],
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<EntryPoint, LoadError> {
+    if data.is_empty() {
+        return Err(LoadError::Empty);
+    }
+    if data.len() > MAX_FLAT_SIZE {
+        return Err(LoadError::TooLarge(data.len()));
+    }
+    if !dest.is_aligned(4096u64) {
+        return Err(LoadError::Misaligned);
+    }
+    map_zeroed(pages(dest, data.len()), PageTableFlags::empty(), mapper, frame_allocator)?;
+    unsafe {
+        let base = dest.as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(data.as_ptr(), base, data.len());
+        Ok(core::mem::transmute::<*mut u8, EntryPoint>(base))
+    }
+}
+
+/// Unmaps the pages `load_flat` mapped for a `len`-byte payload at `dest`.
+/// Returns how many were unmapped.
+pub fn unload_flat(dest: VirtAddr, len: usize, mapper: &mut impl Mapper<Size4KiB>) -> usize {
+    unmap_pages(pages(dest, len), mapper)
+}
+
+extern "C" fn api_println(ptr: *const u8, len: usize) {
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+    match core::str::from_utf8(bytes) {
+        Ok(text) => println!("{}", text),
+        Err(_) => println!("<{} bytes of invalid UTF-8>", len),
+    }
+}
+
+extern "C" fn api_ticks() -> u64 {
+    time::ticks()
+}
+
+pub static PAYLOAD_API: PayloadApi = PayloadApi { println: api_println, ticks: api_ticks };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunError {
+    Transfer(xmodem::XmodemError),
+    Load(LoadError),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Transfer(err) => write!(f, "transfer failed: {}", err),
+            RunError::Load(err) => write!(f, "load failed: {}", err),
+        }
+    }
+}
+
+/// Receives a payload over COM1 with XMODEM, runs it at `SCRATCH_START`
+/// and unmaps it again. Returns the payload's result.
+///
+/// Nothing may print to the serial port during the transfer, so the
+/// console should be VGA-only while it runs.
+pub fn run_from_serial() -> Result<u64, RunError> {
+    // the main loop can't pet the watchdog while we wait
+    let _watchdog = crate::watchdog::suspend();
+    let _raw = crate::console::raw(crate::console::Terminal::Serial);
+    let mut payload = Vec::new();
+    let were_enabled = x86_64::instructions::interrupts::are_enabled();
+    // timeouts need the timer, even when called from the keyboard handler
+    x86_64::instructions::interrupts::enable();
+    let received = xmodem::receive(&mut xmodem::SerialChannel, &mut payload, MAX_FLAT_SIZE);
+    if !were_enabled {
+        x86_64::instructions::interrupts::disable();
+    }
+    received.map_err(RunError::Transfer)?;
+
+    let dest = VirtAddr::new(SCRATCH_START);
+    let entry = crate::memory::with_paging(|mapper, frame_allocator| {
+        load_flat(dest, &payload, mapper, frame_allocator)
+    })
+    .unwrap_or(Err(LoadError::NoPaging))
+    .map_err(RunError::Load)?;
+    println!("loader: {} bytes at {:#x}", payload.len(), SCRATCH_START);
+    let result = entry(&PAYLOAD_API);
+    crate::memory::with_paging(|mapper, _| unload_flat(dest, payload.len(), mapper));
+    Ok(result)
+}
+
+//test case
+#[test_case]
+fn test_load_call_unload() {
+    // mov rax, 0x1234_5678_9abc_def0; ret
+    let payload = [0x48, 0xb8, 0xf0, 0xde, 0xbc, 0x9a, 0x78, 0x56, 0x34, 0x12, 0xc3];
+    let dest = VirtAddr::new(SCRATCH_START + 0x10_0000);
+    let entry = crate::memory::with_paging(|mapper, frame_allocator| {
+        load_flat(dest, &payload, mapper, frame_allocator)
+    })
+    .expect("no global frame allocator")
+    .unwrap();
+    assert!(crate::memory::is_mapped(dest));
+    assert_eq!(entry(&PAYLOAD_API), 0x1234_5678_9abc_def0);
+
+    let again = crate::memory::with_paging(|mapper, frame_allocator| {
+        load_flat(dest, &payload, mapper, frame_allocator)
+    });
+    assert_eq!(again.map(|r| r.err()), Some(Some(LoadError::AlreadyMapped)));
+
+    let unmapped = crate::memory::with_paging(|mapper, _| unload_flat(dest, payload.len(), mapper));
+    assert_eq!(unmapped, Some(1));
+    assert!(!crate::memory::is_mapped(dest));
+}
+
+#[test_case]
+fn test_load_rejects_bad_requests() {
+    let result = crate::memory::with_paging(|mapper, frame_allocator| {
+        [
+            load_flat(VirtAddr::new(SCRATCH_START), &[], mapper, frame_allocator).err(),
+            load_flat(VirtAddr::new(SCRATCH_START + 1), &[0xc3], mapper, frame_allocator).err(),
+            load_flat(VirtAddr::new(SCRATCH_START), &[0; MAX_FLAT_SIZE + 1], mapper, frame_allocator).err(),
+        ]
+    });
+    assert_eq!(
+        result,
+        Some([Some(LoadError::Empty), Some(LoadError::Misaligned), Some(LoadError::TooLarge(MAX_FLAT_SIZE + 1))])
+    );
+}
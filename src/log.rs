@@ -0,0 +1,300 @@
+//! Leveled kernel messages on the console. The threshold comes from
+//! `loglevel=` on the command line (`error`, `warn`, `info`, `debug`,
+//! `trace`, or 0-4); the default is `info`, and the `loglevel` shell command
+//! changes it at runtime. Messages are stamped with the wall-clock time, or
+//! the seconds since boot until the clock is set. Errors and warnings are
+//! shown in color on every console, debug and trace messages only on serial.
+//! Every message shown anywhere is also kept, as plain text, in a ring of
+//! `BUFFER_SIZE` bytes the `dmesg` shell command reads back.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::vga_buffer::{self, Color, ANSI_RESET};
+use crate::{clock, cmdline, println, serial, serial_println, time};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl Level {
+    pub const ALL: [Level; 5] = [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Level> {
+        if let Ok(n) = s.parse::<usize>() {
+            return Level::ALL.get(n).copied();
+        }
+        Level::ALL.iter().copied().find(|level| level.name().eq_ignore_ascii_case(s))
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn max_level() -> Level {
+    Level::ALL[usize::from(MAX_LEVEL.load(Ordering::Relaxed))]
+}
+
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= max_level()
+}
+
+/// Applies `loglevel=` from the command line.
+pub fn init() {
+    if let Some(value) = cmdline::get("loglevel") {
+        match Level::parse(value) {
+            Some(level) => set_max_level(level),
+            None => crate::warn!("log: unknown loglevel '{}'", value),
+        }
+    }
+}
+
+/// A message with its stamp and level in front.
+struct Record<'a> {
+    level: Level,
+    args: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match clock::now() {
+            Some(ms) => {
+                let now = clock::from_unix(ms / 1000);
+                write!(f, "[{:02}:{:02}:{:02}.{:03}] ", now.hour, now.minute, now.second, ms % 1000)?;
+            }
+            None => {
+                let ns = time::now_ns();
+                write!(f, "[{:>5}.{:06}] ", ns / 1_000_000_000, ns / 1000 % 1_000_000)?;
+            }
+        }
+        write!(f, "[{}] {}", self.level, self.args)
+    }
+}
+
+/// Where messages of a level go: errors and warnings in color on every
+/// console, info plainly on every console, debug and trace to serial only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink {
+    Colored(Color),
+    Console,
+    Serial,
+}
+
+pub fn sink(level: Level) -> Sink {
+    match level {
+        Level::Error => Sink::Colored(Color::LightRed),
+        Level::Warn => Sink::Colored(Color::Brown),
+        Level::Info => Sink::Console,
+        Level::Debug | Level::Trace => Sink::Serial,
+    }
+}
+
+/// Bytes of messages kept for `dmesg`.
+pub const BUFFER_SIZE: usize = 16 * 1024;
+
+/// The newest `N` bytes written to it; older ones are overwritten.
+struct Buffer<const N: usize> {
+    data: [u8; N],
+    /// Index of the oldest byte.
+    start: usize,
+    len: usize,
+    /// Whether anything was overwritten, so the oldest line may be cut.
+    overwritten: bool,
+}
+
+impl<const N: usize> Buffer<N> {
+    const fn new() -> Self {
+        Buffer { data: [0; N], start: 0, len: 0, overwritten: false }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.len == N {
+                self.data[self.start] = byte;
+                self.start = (self.start + 1) % N;
+                self.overwritten = true;
+            } else {
+                self.data[(self.start + self.len) % N] = byte;
+                self.len += 1;
+            }
+        }
+    }
+
+    /// What is kept, oldest first, without a line cut short at the start.
+    fn contents(&self) -> String {
+        let (tail, head) = self.data.split_at(self.start);
+        let mut bytes: alloc::vec::Vec<u8> = head.iter().chain(tail).take(self.len).copied().collect();
+        if self.overwritten {
+            let cut = bytes.iter().position(|&byte| byte == b'\n').map_or(bytes.len(), |newline| newline + 1);
+            bytes.drain(..cut);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+        self.overwritten = false;
+    }
+}
+
+impl<const N: usize> fmt::Write for Buffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+static BUFFER: Mutex<Buffer<BUFFER_SIZE>> = Mutex::new(Buffer::new());
+
+/// The messages kept so far, one per line, oldest first.
+pub fn dmesg() -> String {
+    without_interrupts(|| BUFFER.lock().contents())
+}
+
+/// Forgets the messages kept so far.
+pub fn clear_dmesg() {
+    without_interrupts(|| BUFFER.lock().clear());
+}
+
+/// Whoever holds the writer locks prints with interrupts off, so only an
+/// exception or an NMI in the middle of printing finds one held here; it
+/// would wait forever, so the message goes out on the raw UART instead.
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let record = Record { level, args };
+    without_interrupts(|| {
+        // only held with interrupts off, so only an exception finds it taken
+        if let Some(mut buffer) = BUFFER.try_lock() {
+            let _ = writeln!(buffer, "{}", record);
+        }
+    });
+    if vga_buffer::WRITER.is_locked() || serial::SERIAL1.is_locked() {
+        let _ = writeln!(serial::RawSerial, "{}", record);
+        return;
+    }
+    match sink(level) {
+        // the escape sequences color it on the screen and on terminals alike
+        Sink::Colored(color) => println!("{}{}{}", color.ansi(), record, ANSI_RESET),
+        Sink::Console => println!("{}", record),
+        Sink::Serial => {
+            serial_println!("{}", record);
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ($crate::log::_log($level, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
+
+//test case
+#[test_case]
+fn test_level_parse() {
+    assert_eq!(Level::parse("debug"), Some(Level::Debug));
+    assert_eq!(Level::parse("WARN"), Some(Level::Warn));
+    assert_eq!(Level::parse("4"), Some(Level::Trace));
+    assert_eq!(Level::parse("loud"), None);
+    assert!(Level::Error < Level::Trace);
+}
+
+#[test_case]
+fn test_sinks() {
+    assert_eq!(sink(Level::Error), Sink::Colored(Color::LightRed));
+    assert_eq!(sink(Level::Warn), Sink::Colored(Color::Brown));
+    assert_eq!(sink(Level::Info), Sink::Console);
+    assert_eq!(sink(Level::Debug), Sink::Serial);
+    assert_eq!(sink(Level::Trace), Sink::Serial);
+}
+
+#[test_case]
+fn test_max_level_filters() {
+    let before = max_level();
+    set_max_level(Level::Warn);
+    assert!(enabled(Level::Error) && enabled(Level::Warn));
+    assert!(!enabled(Level::Info) && !enabled(Level::Trace));
+    set_max_level(Level::Trace);
+    assert!(Level::ALL.iter().all(|&level| enabled(level)));
+    set_max_level(before);
+}
+
+#[test_case]
+fn test_buffer_keeps_the_newest() {
+    let mut buffer = Buffer::<14>::new();
+    buffer.push(b"one\ntwo\n");
+    assert_eq!(buffer.contents(), "one\ntwo\n");
+    buffer.push(b"three\nfour\n");
+    // "one" went, and what is left of "two" is dropped with it
+    assert_eq!(buffer.contents(), "three\nfour\n");
+    buffer.clear();
+    assert_eq!(buffer.contents(), "");
+}
+
+#[test_case]
+fn test_dmesg_keeps_messages() {
+    crate::info!("dmesg test message");
+    let kept = dmesg();
+    let line = kept.lines().last().unwrap();
+    assert!(line.ends_with("[INFO] dmesg test message"), "{}", line);
+    // below the threshold nothing is kept
+    let before = max_level();
+    set_max_level(Level::Warn);
+    crate::info!("dmesg hidden message");
+    set_max_level(before);
+    assert!(!dmesg().contains("hidden"));
+}
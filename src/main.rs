@@ -1,59 +1,56 @@
 #![no_std]
 #![no_main]
-#![feature(abi_x86_interrupt)] 
 #![feature(custom_test_frameworks)]
-#![test_runner(crate::test_runner)]
+#![test_runner(tutorial_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-mod vga_buffer;
-mod serial;
-mod interrupts;
-mod gdt;
-
 use core::panic::PanicInfo;
-use bootloader::{BootInfo, entry_point};
-use tutorial_os::allocator;
-use x86_64::structures::paging::mapper;
+use tutorial_os::{boot::BootInfo, entry_point};
+use tutorial_os::{allocator, boottime, debugcon_println, println};
+use tutorial_os::task::{Executor, Task};
 use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
 extern crate alloc;
 
 
 entry_point!(kernel_main);
+#[cfg(feature = "multiboot2")]
+tutorial_os::multiboot2_entry!(kernel_main);
 
 #[no_mangle]
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     use tutorial_os::memory;
-    use x86_64::{structures::paging::Translate, VirtAddr, structures::paging::Page};
-    use tutorial_os::memory::BootInfoFrameAllocator;
-
+    use x86_64::PhysAddr;
 
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset)};
-    let mut frame_allocator = memory::EmptyFrameAllocator;
 
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
+    tutorial_os::debugcon::init();
+    debugcon_println!("berryOS: kernel_main entered");
+    boottime::mark("entry");
+    let phys_mem_offset = boot_info.physical_memory_offset();
+    unsafe { memory::init_global(boot_info) };
+    boottime::mark("paging");
+    allocator::init_heap().expect("heap initialization failed");
+    boottime::mark("heap");
 
 
-    let page = Page::containing_address(VirtAddr::new(0));
-    memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
-    println!("Mapping created!");
+    let vga = memory::vmm::map_physical(PhysAddr::new(0xb8000), 4096).expect("mapping the VGA buffer failed");
+    tutorial_os::debug!("Mapping created at {:#x}", vga.as_u64());
 
-    let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
+    let page_ptr: *mut u64 = vga.as_mut_ptr();
     unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e)};
+    unsafe { memory::vmm::unmap_region(vga) }.expect("unmapping the VGA buffer failed");
+
+    if let Some(framebuffer) = &boot_info.framebuffer {
+        if let Err(err) = tutorial_os::graphics::init(framebuffer) {
+            println!("graphics: {}", err);
+        }
+    }
 
     println!("Hello World!");
+    boot_info.print_summary();
     
     tutorial_os::init();
 
-    let addresses = [
-        0xb8000,
-        0x201008,
-        0x0100_0020_1a10,
-        boot_info.physical_memory_offset,
-    ];
     
     
     // El breakpoint está comentado para que no aparezca siempre
@@ -63,66 +60,183 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use x86_64::registers::control::Cr3;
 
     let (level_4_page_table, _) = Cr3::read();
-    println!("Level 4 page page table at: {:?}", level_4_page_table.start_address());
+    tutorial_os::debug!("Level 4 page page table at: {:?}", level_4_page_table.start_address());
     
     
 
     
 
-    for &address in &addresses {
-        let virt = VirtAddr::new(address);
-        let phys = mapper.translate_addr(virt);
-        println!("{:?} -> {:?}", virt, phys);
-    }
 
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
     let heap_value = Box::new(41);
-    println!("heap_value at {:p}", heap_value);
+    tutorial_os::debug!("heap_value at {:p}", heap_value);
 
     let mut vec = Vec::new();
     for i in 0..500 {
         vec.push(i);
     }
-    println!("vec at {:p}", vec.as_slice());
+    tutorial_os::debug!("vec at {:p}", vec.as_slice());
 
     let reference_counted = Rc::new(vec![1, 2, 3]);
     let cloned_reference = reference_counted.clone();
-    println!("current reference count is {}", Rc::strong_count(&cloned_reference));
+    tutorial_os::debug!("current reference count is {}", Rc::strong_count(&cloned_reference));
     core::mem::drop(reference_counted);
-    println!("reference count is {} now", Rc::strong_count(&cloned_reference));
+    tutorial_os::debug!("reference count is {} now", Rc::strong_count(&cloned_reference));
+
+    if let Err(err) = tutorial_os::acpi::init(phys_mem_offset, boot_info.rsdp_addr) {
+        println!("ACPI: {}", err);
+    }
+    tutorial_os::acpi::print_summary();
+    boottime::mark("acpi");
+    if let Err(err) = tutorial_os::smbios::init() {
+        println!("SMBIOS: {}", err);
+    }
+
+    if let Some(Err(err)) = memory::with_paging(tutorial_os::hpet::init) {
+        println!("HPET: {}", err);
+    }
+
+    match memory::with_paging(tutorial_os::interrupts::enable_apic) {
+        Some(Ok(())) => println!("APIC: IRQs routed through the I/O APIC"),
+        Some(Err(err)) => println!("APIC: {}, staying on the 8259 PICs", err),
+        None => {}
+    }
+    memory::with_paging(tutorial_os::smp::init);
+    boottime::mark("smp");
+
+    let disks = memory::with_paging(|_, frame_allocator| tutorial_os::virtio::blk::init(frame_allocator, phys_mem_offset)).unwrap_or(0);
+    for disk in tutorial_os::virtio::blk::DEVICES.lock().iter() {
+        use tutorial_os::block::BlockDevice;
+        println!("virtio-blk: {} sectors{}", disk.sector_count(),
+            if disk.is_read_only() { " (read-only)" } else { "" });
+    }
+    println!("{} virtio disk(s)", disks);
+    boottime::mark("virtio-blk");
+
+    match memory::with_paging(|mapper, frame_allocator| tutorial_os::ahci::init(mapper, frame_allocator, phys_mem_offset)) {
+        Some(Ok(_)) => tutorial_os::ahci::print_summary(),
+        Some(Err(tutorial_os::ahci::AhciError::NoController)) | None => {}
+        Some(Err(err)) => println!("AHCI: {}", err),
+    }
+    match tutorial_os::ide::init() {
+        Ok(_) => tutorial_os::ide::print_summary(),
+        Err(err) => println!("IDE: {}", err),
+    }
+    match memory::with_paging(|mapper, frame_allocator| tutorial_os::nvme::init(mapper, frame_allocator, phys_mem_offset)) {
+        Some(Ok(_)) => tutorial_os::nvme::print_summary(),
+        Some(Err(tutorial_os::nvme::NvmeError::NoController)) | None => {}
+        Some(Err(err)) => println!("NVMe: {}", err),
+    }
+    if let Err(err) = tutorial_os::fs::mount() {
+        println!("vfs: ramfs: {}", err);
+    }
+    if let Err(err) = tutorial_os::fs::devfs::mount() {
+        println!("vfs: devfs: {}", err);
+    }
+    if let Some(ramdisk) = boot_info.ramdisk() {
+        match tutorial_os::fs::tarfs::mount(ramdisk) {
+            Ok(()) => println!("vfs: initrd mounted at {}", tutorial_os::fs::tarfs::MOUNT_POINT),
+            Err(err) => println!("vfs: initrd: {}", err),
+        }
+    }
+    // the first disk or partition holding a FAT volume becomes /disk
+    let disks = tutorial_os::block::DISKS.lock();
+    for name in disks.volumes() {
+        let Ok(mut device) = disks.open(&name) else { continue };
+        if tutorial_os::fat::probe(&mut device) != Ok(true) {
+            continue;
+        }
+        let source = tutorial_os::vfs::Source { device: name.clone(), sectors: tutorial_os::block::BlockDevice::sector_count(&device) };
+        if let Ok(kind) = tutorial_os::fat::mount_at(&tutorial_os::vfs::MOUNTS, device, "/disk", Some(source)) {
+            println!("vfs: {} ({:?}) mounted at /disk", name, kind);
+            break;
+        }
+    }
+    drop(disks);
+
+    if memory::with_paging(|_, frame_allocator| tutorial_os::virtio::console::init(frame_allocator, phys_mem_offset)) == Some(true) {
+        let open = tutorial_os::virtio::console::with_console(|console| console.is_open());
+        println!("virtio-console: port 0 {}", if open == Some(true) { "open" } else { "not opened" });
+    }
+
+    let nic = memory::with_paging(|mapper, frame_allocator| {
+        use tutorial_os::net::Nic;
+        tutorial_os::virtio::net::init(frame_allocator, phys_mem_offset).map(|mac| (Nic::VirtioNet, mac))
+            .or_else(|| tutorial_os::e1000::init(mapper, frame_allocator, phys_mem_offset).map(|mac| (Nic::E1000, mac)))
+            .or_else(|| tutorial_os::rtl8139::init(frame_allocator, phys_mem_offset).map(|mac| (Nic::Rtl8139, mac)))
+    }).flatten();
+    if let Some((nic, mac)) = nic {
+        println!("{}: MAC {}", nic, tutorial_os::rtl8139::MacAddr(mac));
+        tutorial_os::net::init(nic, mac, tutorial_os::net::DEFAULT_CONFIG);
+        // `ip=dhcp` asks for an address instead of using the static one
+        if tutorial_os::cmdline::get("ip") == Some("dhcp") {
+            if let Err(err) = tutorial_os::net::dhcp() {
+                println!("dhcp: {}", err);
+            }
+        }
+        tutorial_os::net::print_summary();
+    }
+    boottime::mark("network");
 
     //--------
     #[cfg(test)]
     test_main();
     
+    if let Err(err) = tutorial_os::mouse::init() {
+        println!("mouse: {}", err);
+    }
     println!("It did not crash!");
-    tutorial_os::hlt_loop();
+    tutorial_os::watchdog::init();
+    tutorial_os::shell::init();
+    boottime::mark("shell ready");
+    boottime::report();
+    // `init=<path>` runs a program first, as if typed at the prompt
+    if let Some(init) = tutorial_os::cmdline::get("init") {
+        println!("init: running {}", init);
+        tutorial_os::interrupts::shell_line(&alloc::format!("run {}", init));
+    }
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(housekeeping()));
+    executor.spawn(Task::new(tutorial_os::task::keyboard::print_keypresses()));
+    executor.spawn(Task::new(tutorial_os::console::serial_input()));
+    executor.spawn(Task::new(tutorial_os::task::mouse::track_mouse()));
+    executor.spawn(Task::new(tutorial_os::task::mouse::show_state()));
+    executor.run();
+}
+
+/// What the main loop used to do between halts, once a tick.
+async fn housekeeping() {
+    loop {
+        tutorial_os::watchdog::pet();
+        tutorial_os::net::poll();
+        tutorial_os::task::timer::sleep(1).await;
+    }
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    tutorial_os::hlt_loop();
+    use core::sync::atomic::{AtomicBool, Ordering};
+    let registers = tutorial_os::debug::Registers::capture();
+    static PANICKING: AtomicBool = AtomicBool::new(false);
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        // the first panic may hold the console locks
+        debugcon_println!("panic while panicking: {}", info);
+        if let tutorial_os::panic::PanicPolicy::Reboot(_) = tutorial_os::panic::policy() {
+            tutorial_os::power::reboot();
+        }
+        tutorial_os::hlt_loop();
+    }
+    tutorial_os::console::restore_after_panic();
+    tutorial_os::println_colored!(tutorial_os::vga_buffer::Color::LightRed, "{}", info);
+    tutorial_os::panic::report(&registers);
+    tutorial_os::panic::finish();
 }
 
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-    loop {}
-}
-
-#[cfg(test)]
-pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
-    }
-    exit_qemu(QemuExitCode::Success);
+    tutorial_os::test_panic_handler(info)
 }
 
 #[test_case]
@@ -134,34 +248,3 @@ fn trivial_assertion() {
 fn test_println_simple() {
     println!("test_println_simple output");
 }
-
-pub trait Testable {
-    fn run(&self) -> ();
-}
-
-impl<T> Testable for T
-where
-    T: Fn(),
-{
-    fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum QemuExitCode {
-    Success = 0x10,
-    Failed = 0x11,
-}
-
-pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
-
-    unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
-    }
-}
\ No newline at end of file
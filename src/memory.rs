@@ -1,21 +1,33 @@
 use x86_64::{
     structures::paging::{
-        PageTable, OffsetPageTable, PhysFrame, Size4KiB,
-        FrameAllocator, Mapper, Page, PageTableFlags,
+        PageTable, OffsetPageTable, PhysFrame, PageSize, Size4KiB, Size2MiB, Size1GiB,
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        mapper::{MapToError, UnmapError},
     },
     VirtAddr, PhysAddr,
     registers::control::Cr3,
     structures::paging::page_table::{FrameError, PageTableEntry},
+    structures::idt::PageFaultErrorCode,
 };
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
-use crate::println;
+use crate::boot::{MemoryRegion, MemoryRegionKind};
+use spin::Once;
+
+pub mod vmm;
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
 
 /// Inicializa un nuevo OffsetPageTable.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Offset del mapeo de la memoria física, una vez llamado `init`.
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    PHYSICAL_MEMORY_OFFSET.get().copied()
+}
+
 /// Devuelve una referencia mutable a la tabla de nivel 4 activa.
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     let (level_4_table_frame, _) = Cr3::read();
@@ -40,7 +52,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     ];
     let mut frame = level_4_table_frame;
 
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
         let table = unsafe { &*table_ptr };
@@ -48,60 +60,382 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            // En la P4 el bit no significa nada válido; en la P1 es el PAT
+            // y la entrada sigue siendo de 4 KiB.
+            Err(FrameError::HugeFrame) if level == 0 => return None,
+            Err(FrameError::HugeFrame) => return Some(leaf_address(entry.addr(), addr, level)),
         };
     }
 
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
 
-// ==========================================================
-// FRAME ALLOCATOR VACÍO (para pruebas)
-// ==========================================================
+/// La dirección física de `addr` cuando la entrada hoja está en el nivel
+/// `level` del recorrido (1 = P3, páginas de 1 GiB; 2 = P2, de 2 MiB;
+/// 3 = P1, de 4 KiB) y apunta a `base`: los bits que no indexan tablas son
+/// el desplazamiento dentro de la página.
+fn leaf_address(base: PhysAddr, addr: VirtAddr, level: usize) -> PhysAddr {
+    let offset_bits = 12 + 9 * (3 - level as u64);
+    base + (addr.as_u64() & ((1 << offset_bits) - 1))
+}
 
-pub struct EmptyFrameAllocator;
+/// Indica si `addr` está mapeada, con el offset que guardó `init` (las
+/// páginas grandes cuentan como mapeadas). Se puede usar desde el panic
+/// handler. Antes de `init` devuelve `false`.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    let physical_memory_offset = match physical_memory_offset() {
+        Some(offset) => offset,
+        None => return false,
+    };
+    let (mut frame, _) = Cr3::read();
+    let table_indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for &index in &table_indexes {
+        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let table = unsafe { &*virt.as_ptr::<PageTable>() };
+        frame = match table[index].frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return false,
+            Err(FrameError::HugeFrame) => return true,
+        };
+    }
+    true
+}
 
-unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        None
+/// Flags de la entrada hoja que mapea `addr` en las tablas activas, o
+/// `None` si no está mapeada. Para páginas grandes devuelve los de esa
+/// entrada; los niveles superiores no se miran.
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    let physical_memory_offset = physical_memory_offset()?;
+    let (mut frame, _) = Cr3::read();
+    let table_indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let table = unsafe { &*virt.as_ptr::<PageTable>() };
+        let entry = &table[index];
+        frame = match entry.frame() {
+            Ok(_) if level == 3 => return Some(entry.flags()),
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => return Some(entry.flags()),
+        };
     }
+    None
 }
 
 // ==========================================================
-// FRAME ALLOCATOR BASADO EN MEMORY MAP (para cuando tengas boot_info)
+// FRAME ALLOCATOR BUDDY SOBRE EL MEMORY MAP
 // ==========================================================
 
+const LOW_MEMORY_END: u64 = 0x10_0000;
+/// El orden más grande: bloques de 2^10 marcos (4 MiB), que alcanzan para
+/// una página de 2 MiB alineada.
+pub const MAX_ORDER: usize = 10;
+pub const ORDERS: usize = MAX_ORDER + 1;
+/// Fin de lista en `FreeBlock`: el marco 0 nunca se entrega.
+const NIL: u64 = 0;
+
+/// Los bytes de un bloque de `order`.
+const fn block_size(order: usize) -> u64 {
+    4096 << order
+}
+
+/// Las direcciones de marco que da una región: nada si no es `Usable`.
+fn usable_range(region: &MemoryRegion) -> core::ops::Range<u64> {
+    if region.kind != MemoryRegionKind::Usable {
+        return 0..0;
+    }
+    // Las regiones de 0.11 no vienen alineadas a página. La memoria baja
+    // (< 1 MiB) queda reservada para el trampolín de arranque de los otros
+    // núcleos, que tiene que vivir ahí.
+    let start = ((region.start + 0xfff) & !0xfff).max(LOW_MEMORY_END);
+    let end = region.end & !0xfff;
+    start..end.max(start)
+}
+
+/// Lo que guarda un bloque libre en su primer marco: los vecinos en la
+/// lista de su orden.
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// Allocator buddy sobre las regiones `Usable` del memory map.
+///
+/// La memoria libre está en bloques de 2^orden marcos alineados a su
+/// tamaño, con una lista doblemente enlazada por orden que vive dentro de
+/// los mismos bloques (a través del mapeo de la memoria física). Pedir un
+/// orden parte el bloque más chico que alcance; devolverlo lo une con su
+/// compañero (`addr ^ tamaño`) mientras el compañero esté libre, hasta
+/// `MAX_ORDER`. Para saberlo hay un byte por marco, en los primeros
+/// marcos de la primera región donde entran: el orden + 1 si ahí empieza
+/// un bloque libre, 0 si no.
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+    memory_regions: &'static [MemoryRegion],
+    physical_memory_offset: VirtAddr,
+    /// El marco al que corresponde la primera etiqueta.
+    base: u64,
+    tags: VirtAddr,
+    tag_count: usize,
+    heads: [u64; ORDERS],
+    free_blocks: [usize; ORDERS],
+    /// Marcos entregados y todavía no devueltos.
+    allocated: usize,
+    /// Marcos que había en total al empezar.
+    total: usize,
+    /// Marcos devueltos en total.
+    freed: usize,
 }
 
 impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+    /// Solo entrega marcos de regiones `Usable`: las `Bootloader` tienen
+    /// el kernel, su pila, las tablas de páginas y el propio BootInfo.
+    ///
+    /// # Safety
+    ///
+    /// Las regiones `Usable` tienen que estar libres de verdad y mapeadas
+    /// en `physical_memory_offset`.
+    pub unsafe fn init(memory_regions: &'static [MemoryRegion], physical_memory_offset: VirtAddr) -> Self {
+        let ranges = || memory_regions.iter().map(usable_range).filter(|range| !range.is_empty());
+        let base = ranges().map(|range| range.start).min().unwrap_or(0);
+        let end = ranges().map(|range| range.end).max().unwrap_or(base);
+        let tag_count = ((end - base) / 4096) as usize;
+        let tag_bytes = (tag_count as u64 + 0xfff) & !0xfff;
+        let tag_phys = ranges().find(|range| range.end - range.start >= tag_bytes).map(|range| range.start);
+        let mut allocator = BootInfoFrameAllocator {
+            memory_regions,
+            physical_memory_offset,
+            base,
+            tags: physical_memory_offset + tag_phys.unwrap_or(0),
+            tag_count: if tag_phys.is_some() { tag_count } else { 0 },
+            heads: [NIL; ORDERS],
+            free_blocks: [0; ORDERS],
+            allocated: 0,
+            total: 0,
+            freed: 0,
+        };
+        // sin lugar para las etiquetas no se entrega nada
+        let Some(tag_phys) = tag_phys else { return allocator };
+        unsafe { core::ptr::write_bytes(allocator.tags.as_mut_ptr::<u8>(), 0, tag_count) };
+        for range in ranges() {
+            let start = if range.start == tag_phys { range.start + tag_bytes } else { range.start };
+            allocator.add_range(start, range.end);
         }
+        allocator
+    }
+
+    /// Reparte `start..end` en los bloques alineados más grandes posibles.
+    fn add_range(&mut self, start: u64, end: u64) {
+        let mut addr = start;
+        while addr < end {
+            let mut order = MAX_ORDER;
+            while order > 0 && (!addr.is_multiple_of(block_size(order)) || addr + block_size(order) > end) {
+                order -= 1;
+            }
+            self.push(addr, order);
+            self.total += 1 << order;
+            addr += block_size(order);
+        }
+    }
+
+    fn tag(&self, addr: u64) -> Option<*mut u8> {
+        let index = (addr.checked_sub(self.base)? / 4096) as usize;
+        (index < self.tag_count).then(|| unsafe { self.tags.as_mut_ptr::<u8>().add(index) })
+    }
+
+    fn set_tag(&mut self, addr: u64, value: u8) {
+        let tag = self.tag(addr).expect("bloque fuera del allocator");
+        unsafe { *tag = value };
+    }
+
+    fn is_free_block(&self, addr: u64, order: usize) -> bool {
+        self.tag(addr).is_some_and(|tag| unsafe { *tag } == order as u8 + 1)
+    }
+
+    fn node(&self, addr: u64) -> *mut FreeBlock {
+        (self.physical_memory_offset + addr).as_mut_ptr()
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges
-            .flat_map(|r| r.step_by(4096));
-        frame_addresses
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    fn push(&mut self, addr: u64, order: usize) {
+        let head = self.heads[order];
+        unsafe { self.node(addr).write(FreeBlock { next: head, prev: NIL }) };
+        if head != NIL {
+            unsafe { (*self.node(head)).prev = addr };
+        }
+        self.heads[order] = addr;
+        self.free_blocks[order] += 1;
+        self.set_tag(addr, order as u8 + 1);
+    }
+
+    fn remove(&mut self, addr: u64, order: usize) {
+        let FreeBlock { next, prev } = unsafe { self.node(addr).read() };
+        match prev {
+            NIL => self.heads[order] = next,
+            prev => unsafe { (*self.node(prev)).next = next },
+        }
+        if next != NIL {
+            unsafe { (*self.node(next)).prev = prev };
+        }
+        self.free_blocks[order] -= 1;
+        self.set_tag(addr, 0);
+    }
+
+    /// Un bloque de 2^`order` marcos contiguos, alineado a su tamaño.
+    pub fn alloc_contiguous(&mut self, order: usize) -> Option<PhysFrame> {
+        let from = (order..ORDERS).find(|&k| self.heads[k] != NIL)?;
+        let addr = self.heads[from];
+        self.remove(addr, from);
+        // las mitades de arriba que sobran vuelven a las listas
+        for k in (order..from).rev() {
+            self.push(addr + block_size(k), k);
+        }
+        self.allocated += 1 << order;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Devuelve un bloque de `alloc_contiguous`.
+    ///
+    /// # Safety
+    ///
+    /// El bloque salió de este allocator con el mismo `order` y ya no lo
+    /// usa nadie.
+    pub unsafe fn free(&mut self, frame: PhysFrame, order: usize) {
+        self.allocated -= 1 << order;
+        self.freed += 1 << order;
+        self.release(frame.start_address().as_u64(), order);
+    }
+
+    /// Mete el bloque en su lista, unido con todos los compañeros libres.
+    fn release(&mut self, mut addr: u64, mut order: usize) {
+        let tag = self.tag(addr).map(|tag| unsafe { *tag });
+        assert!(tag == Some(0), "marco {:#x} devuelto dos veces o ajeno al allocator", addr);
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        self.alloc_contiguous(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// # Safety
+    ///
+    /// El marco tiene que haber salido de este allocator y no estar
+    /// mapeado ni en uso en ningún otro lado.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        unsafe { self.free(frame, 0) };
+    }
+}
+
+impl BootInfoFrameAllocator {
+    /// Cuántos marcos entregados siguen sin devolver. Sirve para detectar
+    /// fugas: si no crece, nadie se está quedando con marcos nuevos.
+    pub fn allocated_count(&self) -> usize {
+        self.allocated
+    }
+
+    /// Cuántos marcos quedan por entregar.
+    pub fn frames_remaining(&self) -> usize {
+        self.total - self.allocated
+    }
+
+    /// Cuántos marcos había para entregar al arrancar.
+    pub fn total_count(&self) -> usize {
+        self.total
+    }
+
+    /// Cuántos marcos se devolvieron desde el arranque.
+    pub fn freed_count(&self) -> usize {
+        self.freed
+    }
+
+    /// Cuántos bloques libres hay de cada orden.
+    pub fn free_blocks(&self) -> [usize; ORDERS] {
+        self.free_blocks
+    }
+
+    /// Los bytes de todas las regiones `Usable` del mapa de memoria, tal
+    /// cual: incluye la memoria baja, los bordes sin alinear y las
+    /// etiquetas, que el allocator no entrega.
+    pub fn usable_bytes(&self) -> u64 {
+        self.memory_regions.iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| region.end - region.start)
+            .sum()
+    }
+
+    /// Reserva `count` marcos físicamente contiguos y devuelve el primero:
+    /// un bloque del orden que alcance, con los marcos que sobran al final
+    /// devueltos enseguida.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        if count == 0 {
+            return None;
+        }
+        let order = count.next_power_of_two().trailing_zeros() as usize;
+        let first = self.alloc_contiguous(order)?;
+        for extra in count..1 << order {
+            self.release(first.start_address().as_u64() + extra as u64 * 4096, 0);
+        }
+        self.allocated -= (1 << order) - count;
+        Some(first)
+    }
+}
+
+// ==========================================================
+// MEMORIA DMA (marcos físicamente contiguos)
+// ==========================================================
+
+/// Buffer físicamente contiguo para que los dispositivos accedan por DMA.
+/// Se usa a través del mapeo de toda la memoria física del bootloader, así
+/// que no hace falta crear páginas nuevas.
+pub struct DmaRegion {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    len: usize,
+}
+
+impl DmaRegion {
+    pub fn allocate(
+        frame_allocator: &mut BootInfoFrameAllocator,
+        physical_memory_offset: VirtAddr,
+        pages: usize,
+    ) -> Option<DmaRegion> {
+        let first = frame_allocator.allocate_contiguous(pages)?;
+        let phys = first.start_address();
+        let virt = physical_memory_offset + phys.as_u64();
+        let len = pages * 4096;
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, len) };
+        Some(DmaRegion { phys, virt, len })
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.virt.as_mut_ptr()
     }
 }
 
@@ -109,43 +443,1528 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 // FUNCIÓN PARA CREAR UN MAPPING DE EJEMPLO (opcional)
 // ==========================================================
 
-pub fn create_example_mapping(
-    page: Page,
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
+/// Mapea `page` al búfer VGA con las tablas globales y devuelve el marco.
+/// Ese marco no salió del allocator: después de `unmap_page` no hay que
+/// devolverlo.
+pub fn create_example_mapping(page: Page) -> PhysFrame {
     let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
-    let map_to_result = unsafe {
+    let map_to_result = with_paging(|mapper, frame_allocator| unsafe {
         mapper.map_to(page, frame, flags, frame_allocator)
-    };
-    map_to_result.expect("map_to failed").flush();
+    });
+    map_to_result.expect("no frame allocator").expect("map_to failed").flush();
+    frame
+}
+
+/// Quita el mapeo de `page`, limpia su entrada del TLB y devuelve el
+/// marco al que apuntaba, que queda libre para `deallocate_frame` si vino
+/// del allocator. Las tablas intermedias se quedan aunque queden vacías.
+pub fn unmap_page(page: Page, mapper: &mut impl Mapper<Size4KiB>) -> Result<PhysFrame, UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    Ok(frame)
+}
+
+// ==========================================================
+// ESTADÍSTICAS
+// ==========================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Lo que suman las regiones `Usable` del mapa de memoria.
+    pub usable_bytes: u64,
+    /// Los marcos que el allocator podía entregar al arrancar.
+    pub frames_total: usize,
+    /// Marcos entregados desde el arranque, devueltos o no.
+    pub frames_allocated: usize,
+    pub frames_freed: usize,
+    pub frames_remaining: usize,
+    /// Bloques libres de cada orden del buddy.
+    pub free_blocks: [usize; ORDERS],
+    /// `None` hasta que existe el heap.
+    pub heap: Option<crate::allocator::HeapStats>,
+}
+
+impl MemoryStats {
+    pub fn frames_in_use(&self) -> usize {
+        self.frames_allocated - self.frames_freed
+    }
+
+    /// Qué porcentaje de la memoria libre no sirve para un bloque de
+    /// `order`, por estar en bloques más chicos: 0 sin fragmentación.
+    pub fn fragmentation(&self, order: usize) -> usize {
+        let frames = |blocks: &[usize]| blocks.iter().enumerate().map(|(k, count)| count << k).sum::<usize>();
+        let free = frames(&self.free_blocks);
+        if free == 0 {
+            return 0;
+        }
+        frames(&self.free_blocks[..order.min(ORDERS)]) * 100 / free
+    }
+
+    /// El orden del bloque libre más grande.
+    pub fn largest_free_order(&self) -> Option<usize> {
+        self.free_blocks.iter().rposition(|&count| count > 0)
+    }
+}
+
+/// Una foto de la memoria, o `None` si todavía no se instaló el
+/// allocator de marcos. Los locks se sueltan antes de devolverla, así que
+/// se puede imprimir tranquilamente.
+pub fn stats() -> Option<MemoryStats> {
+    let mut stats = with_paging(|_, frames| MemoryStats {
+        usable_bytes: frames.usable_bytes(),
+        frames_total: frames.total_count(),
+        frames_allocated: frames.allocated_count() + frames.freed_count(),
+        frames_freed: frames.freed_count(),
+        frames_remaining: frames.frames_remaining(),
+        free_blocks: frames.free_blocks(),
+        heap: None,
+    })?;
+    stats.heap = crate::allocator::heap_stats();
+    Some(stats)
+}
+
+/// Un tamaño en bytes, con la unidad más grande que quepa al menos una
+/// vez y un decimal si no es exacto: `512 B`, `12 KiB`, `1.5 MiB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl core::fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        const UNITS: [(u64, &str); 3] = [(1 << 30, "GiB"), (1 << 20, "MiB"), (1 << 10, "KiB")];
+        let bytes = self.0;
+        for (unit, name) in UNITS {
+            if bytes >= unit {
+                let tenths = bytes % unit * 10 / unit;
+                return match tenths {
+                    0 => write!(f, "{} {}", bytes / unit, name),
+                    _ => write!(f, "{}.{} {}", bytes / unit, tenths, name),
+                };
+            }
+        }
+        write!(f, "{} B", bytes)
+    }
+}
+
+// ==========================================================
+// MAPEO DE RANGOS
+// ==========================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapRangeError {
+    /// No quedan marcos, para una página o para una tabla intermedia.
+    OutOfFrames,
+    /// Esta página del rango ya estaba mapeada.
+    AlreadyMapped(Page),
+    /// Una página grande cubre esta página del rango.
+    HugePage(Page),
+}
+
+impl core::fmt::Display for MapRangeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            MapRangeError::OutOfFrames => f.write_str("out of physical frames"),
+            MapRangeError::AlreadyMapped(page) => write!(f, "{:#x} is already mapped", page.start_address().as_u64()),
+            MapRangeError::HugePage(page) => write!(f, "{:#x} is inside a huge page", page.start_address().as_u64()),
+        }
+    }
+}
+
+/// Las páginas que tocan `size` bytes desde `start`.
+fn page_range(start: VirtAddr, size: u64) -> x86_64::structures::paging::page::PageRange {
+    let first = Page::containing_address(start);
+    let end = Page::containing_address((start + size).align_up(4096u64));
+    Page::range(first, end.max(first))
+}
+
+/// Mapea `size` bytes desde `start`, redondeados a páginas enteras, cada
+/// página a un marco nuevo con `flags`.
+///
+/// Antes de tocar nada mira que ninguna página del rango esté mapeada. Si
+/// algo falla a mitad de camino, deshace lo que ya había mapeado y
+/// devuelve los marcos, así que el rango queda entero o vacío; las tablas
+/// intermedias que se hayan creado se quedan.
+pub fn map_range<A>(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut A,
+) -> Result<(), MapRangeError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let pages = page_range(start, size);
+    for page in pages {
+        if mapper.translate_page(page).is_ok() {
+            return Err(MapRangeError::AlreadyMapped(page));
+        }
+    }
+    for (mapped, page) in pages.enumerate() {
+        let result = match frame_allocator.allocate_frame() {
+            None => Err(MapRangeError::OutOfFrames),
+            Some(frame) => match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    Ok(())
+                }
+                Err(err) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    Err(match err {
+                        MapToError::FrameAllocationFailed => MapRangeError::OutOfFrames,
+                        MapToError::ParentEntryHugePage => MapRangeError::HugePage(page),
+                        MapToError::PageAlreadyMapped(_) => MapRangeError::AlreadyMapped(page),
+                    })
+                }
+            },
+        };
+        if let Err(err) = result {
+            unmap_range_pages(pages.take(mapped), mapper, frame_allocator);
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Quita el mapeo de `size` bytes desde `start`, como los redondea
+/// `map_range`, y devuelve los marcos. Las páginas que no estaban
+/// mapeadas se saltan.
+///
+/// # Safety
+///
+/// Los marcos tienen que haber salido de `frame_allocator` y nadie puede
+/// seguir usando el rango.
+pub unsafe fn unmap_range(
+    start: VirtAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    unmap_range_pages(page_range(start, size), mapper, frame_allocator);
+}
+
+fn unmap_range_pages(
+    pages: impl Iterator<Item = Page>,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    for page in pages {
+        if let Ok(frame) = unmap_page(page, mapper) {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+// ==========================================================
+// PÁGINAS GRANDES
+// ==========================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapHugeError {
+    /// La dirección virtual, la física o el tamaño no son múltiplos del
+    /// tamaño de página, o el tamaño es cero.
+    Unaligned,
+    /// La CPU no tiene páginas de 1 GiB.
+    Unsupported,
+    /// No quedan marcos para una tabla intermedia.
+    OutOfFrames,
+    /// Ya hay algo en esta dirección: otra página grande, o una tabla con
+    /// páginas más chicas.
+    AlreadyMapped(VirtAddr),
+    /// Una página todavía más grande cubre esta dirección.
+    HugePage(VirtAddr),
+}
+
+impl core::fmt::Display for MapHugeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            MapHugeError::Unaligned => f.write_str("the range is empty or not aligned to the page size"),
+            MapHugeError::Unsupported => f.write_str("the CPU has no 1 GiB pages"),
+            MapHugeError::OutOfFrames => f.write_str("out of physical frames"),
+            MapHugeError::AlreadyMapped(addr) => write!(f, "{:#x} is already mapped", addr.as_u64()),
+            MapHugeError::HugePage(addr) => write!(f, "{:#x} is inside a bigger page", addr.as_u64()),
+        }
+    }
+}
+
+/// Si la CPU tiene páginas de 1 GiB (CPUID 0x8000_0001, EDX bit 26).
+pub fn has_1gib_pages() -> bool {
+    crate::cpu::has(crate::cpu::Feature::Pages1G)
+}
+
+/// Mapea `size` bytes desde `virt` a los mismos desde `phys` con páginas
+/// de 2 MiB; las tres cosas tienen que estar alineadas a 2 MiB. Los marcos
+/// no salen del allocator (es memoria que ya existe: el framebuffer, la
+/// memoria física); `frame_allocator` solo da las tablas intermedias.
+///
+/// Como `map_range`, el rango queda entero o vacío.
+pub fn map_huge_2mib(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size2MiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapHugeError> {
+    map_huge(virt, phys, size, flags, mapper, frame_allocator)
+}
+
+/// Como `map_huge_2mib`, con páginas de 1 GiB, si la CPU las tiene.
+pub fn map_huge_1gib(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size1GiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapHugeError> {
+    if !has_1gib_pages() {
+        return Err(MapHugeError::Unsupported);
+    }
+    map_huge(virt, phys, size, flags, mapper, frame_allocator)
+}
+
+fn map_huge<S: PageSize>(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<S>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapHugeError> {
+    if size == 0 || !virt.is_aligned(S::SIZE) || !phys.is_aligned(S::SIZE) || !size.is_multiple_of(S::SIZE) {
+        return Err(MapHugeError::Unaligned);
+    }
+    let first = Page::<S>::containing_address(virt);
+    let pages = Page::range(first, first + size / S::SIZE);
+    let flags = flags | PageTableFlags::PRESENT;
+    for (mapped, page) in pages.enumerate() {
+        let frame = PhysFrame::<S>::containing_address(phys + mapped as u64 * S::SIZE);
+        let result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+        let err = match result {
+            Ok(flush) => {
+                flush.flush();
+                continue;
+            }
+            Err(MapToError::FrameAllocationFailed) => MapHugeError::OutOfFrames,
+            Err(MapToError::ParentEntryHugePage) => MapHugeError::HugePage(page.start_address()),
+            Err(MapToError::PageAlreadyMapped(_)) => MapHugeError::AlreadyMapped(page.start_address()),
+        };
+        for page in pages.take(mapped) {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Quita el mapeo de `size` bytes desde `virt` hecho con páginas de tamaño
+/// `S` (`map_huge_2mib` o `map_huge_1gib`). Los marcos no se devuelven:
+/// no eran del allocator. Lo que no estaba mapeado así se salta.
+///
+/// # Safety
+///
+/// Nadie puede seguir usando el rango.
+pub unsafe fn unmap_huge<S: PageSize>(virt: VirtAddr, size: u64, mapper: &mut impl Mapper<S>) {
+    let first = Page::<S>::containing_address(virt);
+    for page in Page::range(first, first + size / S::SIZE) {
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    }
 }
 
 // ==========================================================
-// FUNCIÓN PARA IMPRIMIR LA TABLA DE PÁGINAS (opcional)
+// VOLCADO DE LAS TABLAS DE PÁGINAS
 // ==========================================================
 
+/// Los flags de una entrada en letras: `P W U NX H A D`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryFlags(pub PageTableFlags);
+
+impl core::fmt::Display for EntryFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        const LETTERS: [(PageTableFlags, &str); 7] = [
+            (PageTableFlags::PRESENT, "P"),
+            (PageTableFlags::WRITABLE, "W"),
+            (PageTableFlags::USER_ACCESSIBLE, "U"),
+            (PageTableFlags::NO_EXECUTE, "NX"),
+            (PageTableFlags::HUGE_PAGE, "H"),
+            (PageTableFlags::ACCESSED, "A"),
+            (PageTableFlags::DIRTY, "D"),
+        ];
+        let mut first = true;
+        for (flag, letter) in LETTERS {
+            if self.0.contains(flag) {
+                if !first {
+                    f.write_str(" ")?;
+                }
+                f.write_str(letter)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Qué parte de las tablas muestra `dump_page_tables`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DumpFilter {
+    /// Solo las entradas que cubren algo de `start..=end`.
+    pub start: u64,
+    pub end: u64,
+    /// El nivel más bajo al que se baja (4 = solo la P4, 1 = hasta la P1).
+    pub deepest_level: u8,
+}
+
+impl DumpFilter {
+    /// P4 y P3 de todo el espacio de direcciones: lo que cabe en pantalla.
+    pub const SUMMARY: DumpFilter = DumpFilter { start: 0, end: u64::MAX, deepest_level: 3 };
+
+    /// Todo, hasta la P1, de las direcciones `start..=end`.
+    pub const fn range(start: u64, end: u64) -> DumpFilter {
+        DumpFilter { start, end, deepest_level: 1 }
+    }
+}
+
+/// Bytes que cubre una entrada del nivel `level` (4 = P4).
+const fn level_size(level: u8) -> u64 {
+    1 << (12 + 9 * (level as u64 - 1))
+}
+
+/// Páginas seguidas con los mismos flags, mapeadas a memoria física
+/// también seguida.
+struct Run {
+    start: u64,
+    /// La última dirección, para que la del final del espacio no desborde.
+    last: u64,
+    phys: u64,
+    flags: PageTableFlags,
+    level: u8,
+}
+
+impl Run {
+    /// Si la hoja en `virt` sigue a esta racha.
+    fn continues(&self, virt: u64, phys: u64, flags: PageTableFlags, level: u8) -> bool {
+        self.level == level
+            && self.flags == flags
+            && self.last.checked_add(1) == Some(virt)
+            && self.phys + (virt - self.start) == phys
+    }
+}
+
+struct Dump<'a> {
+    out: &'a mut dyn core::fmt::Write,
+    offset: VirtAddr,
+    filter: DumpFilter,
+    run: Option<Run>,
+}
+
+impl Dump<'_> {
+    fn flush(&mut self) -> core::fmt::Result {
+        let Some(run) = self.run.take() else { return Ok(()) };
+        let size = match run.level {
+            3 => "1 GiB",
+            2 => "2 MiB",
+            _ => "4 KiB",
+        };
+        writeln!(self.out, "{:width$}{:#018x} - {:#018x} -> phys {:#x} [{}] ({} pages)",
+            "", run.start, run.last, run.phys, EntryFlags(run.flags), size, width = 2 * (4 - run.level as usize))
+    }
+
+    fn leaf(&mut self, virt: u64, phys: u64, flags: PageTableFlags, level: u8) -> core::fmt::Result {
+        if let Some(run) = &mut self.run {
+            if run.continues(virt, phys, flags, level) {
+                run.last = virt + (level_size(level) - 1);
+                return Ok(());
+            }
+        }
+        self.flush()?;
+        self.run = Some(Run { start: virt, last: virt + (level_size(level) - 1), phys, flags, level });
+        Ok(())
+    }
+
+    /// Recorre la tabla del nivel `level` en `table`, que empieza a cubrir
+    /// desde `base`.
+    fn table(&mut self, table: PhysAddr, level: u8, base: u64) -> core::fmt::Result {
+        let table = unsafe { &*(self.offset + table.as_u64()).as_ptr::<PageTable>() };
+        let size = level_size(level);
+        for (index, entry) in table.iter().enumerate() {
+            if entry.is_unused() {
+                continue;
+            }
+            // la mitad de arriba de la P4 es la de las direcciones negativas
+            let virt = VirtAddr::new_truncate(base + index as u64 * size).as_u64();
+            let last = virt + (size - 1);
+            if last < self.filter.start || virt > self.filter.end {
+                continue;
+            }
+            let flags = entry.flags();
+            let leaf = level == 1 || (level < 4 && flags.contains(PageTableFlags::HUGE_PAGE));
+            if !flags.contains(PageTableFlags::PRESENT) {
+                self.flush()?;
+                writeln!(self.out, "{:width$}L{} {:>3}: {:#018x} not present", "", level, index, virt,
+                    width = 2 * (4 - level as usize))?;
+            } else if leaf {
+                self.leaf(virt, entry.addr().as_u64(), flags, level)?;
+            } else {
+                self.flush()?;
+                writeln!(self.out, "{:width$}L{} {:>3}: {:#018x} table at {:#x} [{}]", "", level, index, virt,
+                    entry.addr().as_u64(), EntryFlags(flags), width = 2 * (4 - level as usize))?;
+                if level > self.filter.deepest_level {
+                    self.table(entry.addr(), level - 1, virt)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Escribe en `out` las tablas de páginas activas que deja ver `filter`:
+/// cada tabla intermedia en una línea, y las páginas juntas en rangos
+/// cuando siguen una a la otra con los mismos flags. Las páginas grandes
+/// se muestran como rangos, sin bajar por ellas.
+pub fn dump_page_tables(
+    physical_memory_offset: VirtAddr,
+    filter: DumpFilter,
+    out: &mut dyn core::fmt::Write,
+) -> core::fmt::Result {
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut dump = Dump { out, offset: physical_memory_offset, filter, run: None };
+    dump.table(level_4_table_frame.start_address(), 4, 0)?;
+    dump.flush()
+}
+
+/// El resumen de `dump_page_tables` en el log, a nivel debug.
 pub fn print_page_table(physical_memory_offset: VirtAddr) {
-    let level_4_table = unsafe { active_level_4_table(physical_memory_offset) };
-
-    for (i, entry) in level_4_table.iter().enumerate() {
-        if !entry.is_unused() {
-            println!("L4 Entry {}: {:?}", i, entry);
-
-            if let Ok(frame) = entry.frame() {
-                let phys = frame.start_address();
-                let virt = physical_memory_offset + phys.as_u64();
-                let ptr = virt.as_mut_ptr();
-                let l3_table: &PageTable = unsafe { &*ptr };
-
-                for (j, l3_entry) in l3_table.iter().enumerate() {
-                    if !l3_entry.is_unused() {
-                        println!("  L3 Entry {}: {:?}", j, l3_entry);
-                    }
+    let mut out = alloc::string::String::new();
+    let _ = dump_page_tables(physical_memory_offset, DumpFilter::SUMMARY, &mut out);
+    for line in out.lines() {
+        crate::debug!("{}", line);
+    }
+}
+
+/// Una entrada del camino de `walk`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkStep {
+    pub level: u8,
+    pub index: u16,
+    pub flags: PageTableFlags,
+    /// La tabla siguiente, o el marco si la entrada es la hoja.
+    pub addr: PhysAddr,
+}
+
+/// Lo que recorre la CPU para traducir una dirección.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageWalk {
+    pub addr: VirtAddr,
+    /// Hasta la hoja, o hasta la entrada no presente donde fallaría.
+    pub steps: alloc::vec::Vec<WalkStep>,
+    /// `None` si el acceso falla.
+    pub phys: Option<PhysAddr>,
+}
+
+impl PageWalk {
+    /// Lo que permite el camino entero: escribir o entrar desde ring 3
+    /// tiene que estar permitido en todos los niveles, y basta un NX.
+    pub fn effective_flags(&self) -> PageTableFlags {
+        let inherited = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut flags = if self.phys.is_some() { inherited } else { PageTableFlags::empty() };
+        for step in &self.steps {
+            flags = (flags & (step.flags | !inherited)) | (step.flags & PageTableFlags::NO_EXECUTE);
+        }
+        flags
+    }
+}
+
+impl core::fmt::Display for PageWalk {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for step in &self.steps {
+            write!(f, "L{} {:>3}: ", step.level, step.index)?;
+            if !step.flags.contains(PageTableFlags::PRESENT) {
+                writeln!(f, "not present, the access faults here")?;
+            } else if self.phys.is_some() && step == self.steps.last().unwrap() {
+                writeln!(f, "page at {:#x} [{}]", step.addr.as_u64(), EntryFlags(step.flags))?;
+            } else {
+                writeln!(f, "table at {:#x} [{}]", step.addr.as_u64(), EntryFlags(step.flags))?;
+            }
+        }
+        match self.phys {
+            Some(phys) => writeln!(f, "{:#x} -> phys {:#x} [{}]", self.addr.as_u64(), phys.as_u64(), EntryFlags(self.effective_flags())),
+            None => writeln!(f, "{:#x} is not mapped", self.addr.as_u64()),
+        }
+    }
+}
+
+/// Recorre las tablas activas para `addr` como lo haría la CPU, con el
+/// offset que guardó `init`.
+pub fn walk(addr: VirtAddr) -> Option<PageWalk> {
+    let offset = physical_memory_offset()?;
+    let (mut frame, _) = Cr3::read();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut walk = PageWalk { addr, steps: alloc::vec::Vec::new(), phys: None };
+    for (depth, &index) in indexes.iter().enumerate() {
+        let table = unsafe { &*(offset + frame.start_address().as_u64()).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        let level = 4 - depth as u8;
+        walk.steps.push(WalkStep { level, index: u16::from(index), flags: entry.flags(), addr: entry.addr() });
+        frame = match entry.frame() {
+            Ok(_) if level == 1 => {
+                walk.phys = Some(leaf_address(entry.addr(), addr, depth));
+                break;
+            }
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => break,
+            Err(FrameError::HugeFrame) if level == 4 => break,
+            Err(FrameError::HugeFrame) => {
+                walk.phys = Some(leaf_address(entry.addr(), addr, depth));
+                break;
+            }
+        };
+    }
+    Some(walk)
+}
+
+// ==========================================================
+// REGISTROS MMIO
+// ==========================================================
+
+/// Mapea una página de registros de un dispositivo (sin caché) donde la
+/// pondría el mapeo de toda la memoria física, y devuelve su dirección
+/// virtual. Si el bootloader ya la mapeó no hace nada.
+pub fn map_mmio(
+    phys: PhysAddr,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, x86_64::structures::paging::mapper::MapToError<Size4KiB>> {
+    use x86_64::structures::paging::Translate;
+
+    let virt = mapper.phys_offset() + phys.as_u64();
+    if mapper.translate_addr(virt) != Some(phys) {
+        let page = Page::<Size4KiB>::containing_address(virt);
+        let frame = PhysFrame::containing_address(phys);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(virt)
+}
+
+// ==========================================================
+// ACCESO GLOBAL A LA PAGINACIÓN
+// ==========================================================
+
+// Un solo lock guarda el allocator y, con él, las tablas de páginas: quien
+// lo tiene es el único que las toca, y no hay orden de locks que respetar.
+// No se puede llamar a estas funciones desde una interrupción (la
+// interrupción podría llegar con el lock tomado) ni desde dentro de
+// `with_paging`, que ya lo tiene.
+static FRAME_ALLOCATOR: spin::Mutex<Option<BootInfoFrameAllocator>> = spin::Mutex::new(None);
+
+/// Prepara la paginación global a partir de lo que dejó el bootloader: el
+/// offset de la memoria física y un allocator sobre su mapa de memoria.
+/// Después, todo el kernel mapea y pide marcos con `with_paging`,
+/// `allocate_frame` y `deallocate_frame`.
+///
+/// # Safety
+///
+/// Se llama una sola vez, y `boot_info` tiene que ser el que dio el
+/// bootloader: la memoria física mapeada en su offset y las regiones
+/// `Usable` libres de verdad.
+pub unsafe fn init_global(boot_info: &'static crate::boot::BootInfo) {
+    init(boot_info.physical_memory_offset());
+    let frame_allocator = BootInfoFrameAllocator::init(boot_info.memory_regions, boot_info.physical_memory_offset());
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Ejecuta `f` con las tablas de páginas activas y el allocator global.
+/// Devuelve `None` si todavía no se llamó a `init_global`.
+///
+/// Las interrupciones siguen habilitadas mientras corre `f`, porque hay
+/// inicializaciones (la de SMP) que esperan al timer.
+pub fn with_paging<R>(f: impl FnOnce(&mut OffsetPageTable, &mut BootInfoFrameAllocator) -> R) -> Option<R> {
+    let offset = physical_memory_offset()?;
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut()?;
+    let mut mapper = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    Some(f(&mut mapper, frame_allocator))
+}
+
+/// Un marco del allocator global, o `None` si no quedan (o no hay
+/// allocator todavía).
+pub fn allocate_frame() -> Option<PhysFrame> {
+    with_paging(|_, frame_allocator| frame_allocator.allocate_frame()).flatten()
+}
+
+/// Devuelve un marco al allocator global.
+///
+/// # Safety
+///
+/// El marco salió de `allocate_frame` (o de `with_paging`) y ya no lo usa
+/// nadie.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    with_paging(|_, frame_allocator| frame_allocator.deallocate_frame(frame));
+}
+
+/// Como `with_paging`, pero sin esperar el lock: devuelve `None` si está
+/// tomado. Es lo que puede usar un manejador de excepciones.
+pub(crate) fn try_with_paging<R>(f: impl FnOnce(&mut OffsetPageTable, &mut BootInfoFrameAllocator) -> R) -> Option<R> {
+    let offset = physical_memory_offset()?;
+    let mut frame_allocator = FRAME_ALLOCATOR.try_lock()?;
+    let frame_allocator = frame_allocator.as_mut()?;
+    let mut mapper = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    Some(f(&mut mapper, frame_allocator))
+}
+
+// ==========================================================
+// MARCOS COMPARTIDOS (copy-on-write)
+// ==========================================================
+
+/// Bit libre de las entradas de página que marca las páginas
+/// copy-on-write: están mapeadas de solo lectura aunque el dueño pueda
+/// escribirlas, y la primera escritura le da una copia propia del marco.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Cuántos espacios de direcciones mapean cada marco compartido, por
+/// dirección física. Un marco que no está acá tiene un solo dueño, así
+/// que la tabla solo crece con lo que comparte `fork`.
+static FRAME_REFS: spin::Mutex<alloc::collections::BTreeMap<u64, usize>> =
+    spin::Mutex::new(alloc::collections::BTreeMap::new());
+
+/// Cuántos mapean `frame`; 1 si nadie lo compartió.
+pub fn frame_refs(frame: PhysFrame) -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_REFS.lock().get(&frame.start_address().as_u64()).copied().unwrap_or(1)
+    })
+}
+
+/// Anota que otro espacio de direcciones mapea `frame`.
+pub fn share_frame(frame: PhysFrame) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *FRAME_REFS.lock().entry(frame.start_address().as_u64()).or_insert(1) += 1;
+    });
+}
+
+/// Anota que un espacio de direcciones dejó de mapear `frame`. Devuelve
+/// `true` si era el último, y entonces quien llama lo libera.
+pub fn release_frame(frame: PhysFrame) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut refs = FRAME_REFS.lock();
+        let addr = frame.start_address().as_u64();
+        match refs.get_mut(&addr) {
+            None => true,
+            Some(count) => {
+                *count -= 1;
+                if *count == 1 {
+                    refs.remove(&addr);
                 }
+                false
+            }
+        }
+    })
+}
+
+/// Marcos que tienen más de un dueño.
+pub fn shared_frames() -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| FRAME_REFS.lock().len())
+}
+
+// ==========================================================
+// PILAS DEL KERNEL CON PÁGINA DE GUARDA
+// ==========================================================
+
+/// Donde viven las pilas de los hilos del kernel. Está en la misma
+/// entrada del PML4 que el heap, así que los espacios de direcciones de
+/// los procesos, que copian esas entradas, también las ven.
+pub const KERNEL_STACKS_START: u64 = 0x_4444_8000_0000;
+/// Páginas de cada pila, sin contar la de guarda.
+pub const KERNEL_STACK_PAGES: u64 = 4;
+/// Cuántas pilas puede haber a la vez. Como las regiones perezosas, es un
+/// arreglo fijo: devolver una pila no toca el heap.
+pub const MAX_KERNEL_STACKS: usize = 64;
+/// Cada pila ocupa su página de guarda, sin mapear, y sus páginas encima.
+const STACK_SLOT_SIZE: u64 = (KERNEL_STACK_PAGES + 1) * 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StackSlot {
+    /// Nunca se mapeó.
+    Empty,
+    /// Mapeada y libre para la próxima.
+    Free,
+    InUse,
+}
+
+static STACK_SLOTS: spin::Mutex<[StackSlot; MAX_KERNEL_STACKS]> =
+    spin::Mutex::new([StackSlot::Empty; MAX_KERNEL_STACKS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelStackError {
+    /// Ya hay `MAX_KERNEL_STACKS` pilas en uso.
+    Full,
+    Map(MapRangeError),
+    /// Todavía no se llamó a `init_global`.
+    NoPaging,
+}
+
+impl core::fmt::Display for KernelStackError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            KernelStackError::Full => write!(f, "all {} kernel stacks are in use", MAX_KERNEL_STACKS),
+            KernelStackError::Map(err) => write!(f, "could not map a kernel stack: {}", err),
+            KernelStackError::NoPaging => f.write_str("paging is not available yet"),
+        }
+    }
+}
+
+/// Una pila con una página sin mapear debajo: quien se pasa de ella
+/// produce un page fault en esa página en vez de pisar lo que haya abajo.
+/// Al soltarla sus páginas quedan mapeadas para la próxima, así que se
+/// puede soltar desde una interrupción.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Usa una pila que alguien soltó o mapea una nueva. No se puede
+    /// llamar desde una interrupción (ver `with_paging`).
+    pub fn allocate() -> Result<KernelStack, KernelStackError> {
+        let (slot, state) = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut slots = STACK_SLOTS.lock();
+            let slot = slots.iter().position(|&slot| slot == StackSlot::Free)
+                .or_else(|| slots.iter().position(|&slot| slot == StackSlot::Empty))
+                .ok_or(KernelStackError::Full)?;
+            let state = core::mem::replace(&mut slots[slot], StackSlot::InUse);
+            Ok((slot, state))
+        })?;
+        if state == StackSlot::Empty {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            let mapped = with_paging(|mapper, frame_allocator| {
+                map_range(Self::slot_start(slot) + 4096u64, KERNEL_STACK_PAGES * 4096, flags, mapper, frame_allocator)
+            });
+            let error = match mapped {
+                Some(Ok(())) => None,
+                Some(Err(err)) => Some(KernelStackError::Map(err)),
+                None => Some(KernelStackError::NoPaging),
+            };
+            if let Some(error) = error {
+                // nada quedó mapeado: el lugar vuelve a estar vacío
+                x86_64::instructions::interrupts::without_interrupts(|| STACK_SLOTS.lock()[slot] = StackSlot::Empty);
+                return Err(error);
             }
+            // queda mapeada aunque la suelten, así que se anota una vez
+            let _ = vmm::track("kernel stack", Self::slot_start(slot) + 4096u64, KERNEL_STACK_PAGES * 4096, flags);
+        }
+        Ok(KernelStack { slot })
+    }
+
+    fn slot_start(slot: usize) -> VirtAddr {
+        VirtAddr::new(KERNEL_STACKS_START + slot as u64 * STACK_SLOT_SIZE)
+    }
+
+    /// La página de guarda.
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(Self::slot_start(self.slot))
+    }
+
+    /// La primera dirección de la pila, justo encima de la guarda.
+    pub fn bottom(&self) -> VirtAddr {
+        Self::slot_start(self.slot) + 4096u64
+    }
+
+    /// El fin de la pila, donde arranca RSP.
+    pub fn top(&self) -> VirtAddr {
+        Self::slot_start(self.slot) + STACK_SLOT_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        x86_64::instructions::interrupts::without_interrupts(|| STACK_SLOTS.lock()[self.slot] = StackSlot::Free);
+    }
+}
+
+/// Si `addr` cae en la página de guarda de alguna pila del kernel, el
+/// fin de esa pila (su `top`), que identifica a quién pertenece.
+pub fn stack_guard_hit(addr: VirtAddr) -> Option<VirtAddr> {
+    let offset = addr.as_u64().checked_sub(KERNEL_STACKS_START)?;
+    let slot = offset / STACK_SLOT_SIZE;
+    (slot < MAX_KERNEL_STACKS as u64 && offset % STACK_SLOT_SIZE < 4096)
+        .then(|| KernelStack::slot_start(slot as usize) + STACK_SLOT_SIZE)
+}
+
+// ==========================================================
+// REGIONES PEREZOSAS (memoria bajo demanda)
+// ==========================================================
+
+/// Cuántas regiones perezosas puede haber a la vez. Es un arreglo fijo y
+/// no un `Vec` para que el manejador de page faults no dependa del heap,
+/// que puede ser justamente lo que está creciendo.
+pub const MAX_LAZY_REGIONS: usize = 8;
+
+/// Un rango de direcciones virtuales cuyas páginas no existen hasta que
+/// alguien las toca: el primer acceso a cada una produce un page fault, y
+/// el manejador mapea ahí un marco nuevo, lleno de ceros, con `flags`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LazyRegion {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+}
+
+impl LazyRegion {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    fn overlaps(&self, other: &LazyRegion) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LazyRegionError {
+    /// El rango está vacío o no empieza y termina en límites de página.
+    Unaligned,
+    /// Ya están registradas `MAX_LAZY_REGIONS` regiones.
+    Full,
+    /// Se pisa con la región de ese nombre.
+    Overlaps(&'static str),
+}
+
+impl core::fmt::Display for LazyRegionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            LazyRegionError::Unaligned => f.write_str("the region is empty or not page aligned"),
+            LazyRegionError::Full => write!(f, "all {} lazy regions are in use", MAX_LAZY_REGIONS),
+            LazyRegionError::Overlaps(name) => write!(f, "overlaps the lazy region '{}'", name),
+        }
+    }
+}
+
+/// Lo que hizo el manejador de page faults con un fallo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LazyFault {
+    /// La dirección no cae en ninguna región, o el fallo no es de los que
+    /// se resuelven mapeando (una violación de protección, o un acceso de
+    /// modo usuario a una región del kernel).
+    NotLazy,
+    /// Se mapeó la página; la instrucción se puede volver a ejecutar.
+    Mapped(Page),
+    /// La dirección es de la región, pero no quedan marcos.
+    OutOfFrames(&'static str),
+    /// La dirección es de la región, pero el fallo llegó con las tablas de
+    /// páginas tomadas (desde dentro de `with_paging`, o mientras otro hilo
+    /// las tenía) y esperarlas no terminaría nunca.
+    Locked(&'static str),
+}
+
+static LAZY_REGIONS: spin::Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> = spin::Mutex::new([None; MAX_LAZY_REGIONS]);
+static LAZY_FAULTS: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+
+/// Registra `size` bytes desde `start` como región perezosa. No mapea
+/// nada: cada página aparece al primer acceso.
+pub fn register_lazy_region(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), LazyRegionError> {
+    if size == 0 || !start.is_aligned(4096u64) || !size.is_multiple_of(4096) {
+        return Err(LazyRegionError::Unaligned);
+    }
+    let region = LazyRegion { name, start, size, flags: flags | PageTableFlags::PRESENT };
+    // el manejador solo usa try_lock, pero no tiene sentido hacerle fallar
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut regions = LAZY_REGIONS.lock();
+        if let Some(other) = regions.iter().flatten().find(|other| other.overlaps(&region)) {
+            return Err(LazyRegionError::Overlaps(other.name));
+        }
+        let slot = regions.iter_mut().find(|slot| slot.is_none()).ok_or(LazyRegionError::Full)?;
+        *slot = Some(region);
+        Ok(())
+    })
+}
+
+/// Saca la región que empieza en `start`. Las páginas ya mapeadas se
+/// quedan: quien la registró las devuelve con `unmap_range`.
+pub fn unregister_lazy_region(start: VirtAddr) -> Option<LazyRegion> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut regions = LAZY_REGIONS.lock();
+        let slot = regions.iter_mut().find(|slot| slot.is_some_and(|region| region.start == start))?;
+        slot.take()
+    })
+}
+
+/// Las regiones registradas.
+pub fn lazy_regions() -> alloc::vec::Vec<LazyRegion> {
+    x86_64::instructions::interrupts::without_interrupts(|| LAZY_REGIONS.lock().iter().flatten().copied().collect())
+}
+
+/// Páginas mapeadas por un page fault desde el arranque.
+pub fn lazy_faults() -> u64 {
+    LAZY_FAULTS.load(core::sync::atomic::Ordering::Relaxed)
+}
+
+/// Si un fallo con este código en una región con estos flags se resuelve
+/// mapeando la página: solo si no estaba presente, y si viene de modo
+/// usuario, solo en una región accesible desde ahí.
+fn lazy_fault_allowed(code: PageFaultErrorCode, flags: PageTableFlags) -> bool {
+    !code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && !code.contains(PageFaultErrorCode::MALFORMED_TABLE)
+        && (!code.contains(PageFaultErrorCode::USER_MODE) || flags.contains(PageTableFlags::USER_ACCESSIBLE))
+}
+
+/// Lo llama el manejador de page faults con CR2 y el código de error. Si
+/// la dirección es de una región perezosa, mapea ahí un marco en ceros.
+///
+/// Corre dentro de la excepción, así que no espera ningún lock: si las
+/// tablas o la lista de regiones están tomadas, el fallo no se resuelve.
+pub(crate) fn handle_lazy_fault(addr: VirtAddr, code: PageFaultErrorCode) -> LazyFault {
+    let region = LAZY_REGIONS
+        .try_lock()
+        .and_then(|regions| regions.iter().flatten().find(|region| region.contains(addr)).copied());
+    let Some(region) = region else { return LazyFault::NotLazy };
+    if !lazy_fault_allowed(code, region.flags) {
+        return LazyFault::NotLazy;
+    }
+    let Some(offset) = physical_memory_offset() else { return LazyFault::Locked(region.name) };
+    let Some(mut frame_allocator) = FRAME_ALLOCATOR.try_lock() else { return LazyFault::Locked(region.name) };
+    let Some(frame_allocator) = frame_allocator.as_mut() else { return LazyFault::Locked(region.name) };
+    let mut mapper = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    let page = Page::containing_address(addr);
+    let Some(frame) = frame_allocator.allocate_frame() else { return LazyFault::OutOfFrames(region.name) };
+    // a través del mapeo de la memoria física, por si la región no es escribible
+    unsafe { core::ptr::write_bytes((offset + frame.start_address().as_u64()).as_mut_ptr::<u8>(), 0, 4096) };
+    match unsafe { mapper.map_to(page, frame, region.flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        // otro camino la mapeó entre el fallo y ahora: basta con reintentar
+        Err(MapToError::PageAlreadyMapped(_)) => unsafe { frame_allocator.deallocate_frame(frame) },
+        // una página grande encima estaría presente y no habría fallo, así
+        // que lo que falta es un marco para una tabla intermedia
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            return LazyFault::OutOfFrames(region.name);
+        }
+    }
+    LAZY_FAULTS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    LazyFault::Mapped(page)
+}
+
+//test case
+#[cfg(test)]
+const fn region(start: u64, end: u64, kind: MemoryRegionKind) -> MemoryRegion {
+    MemoryRegion { start, end, kind }
+}
+
+/// Un allocator sobre 4 MiB de memoria de verdad, sacados del global y
+/// repartidos en regiones como las de un memory map. Recibe también la
+/// dirección física del bloque.
+#[cfg(test)]
+fn with_test_allocator(f: impl FnOnce(&mut BootInfoFrameAllocator, u64)) {
+    let block = with_paging(|_, frames| frames.alloc_contiguous(MAX_ORDER))
+        .flatten()
+        .expect("4 MiB contiguous for the test allocator");
+    let base = block.start_address().as_u64();
+    let regions = alloc::boxed::Box::leak(alloc::boxed::Box::new([
+        // memoria baja: nunca se entrega
+        region(0x1000, 0x9f000, MemoryRegionKind::Usable),
+        region(base, base + 0x1_0000, MemoryRegionKind::Bootloader),
+        // ni el principio ni el final alineados
+        region(base + 0x1_0123, base + 0x20_0fff, MemoryRegionKind::Usable),
+        region(base + 0x20_0fff, base + 0x28_0000, MemoryRegionKind::UnknownUefi(3)),
+        region(base + 0x30_0000, base + 0x40_0000, MemoryRegionKind::Usable),
+    ]));
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(regions, physical_memory_offset().unwrap()) };
+    f(&mut frame_allocator, base);
+    with_paging(|_, frames| unsafe { frames.free(block, MAX_ORDER) });
+}
+
+#[test_case]
+fn test_translate_4kib_page() {
+    let offset = physical_memory_offset().unwrap();
+    // el búfer VGA está mapeado a sí mismo con páginas de 4 KiB
+    let vga = unsafe { translate_addr(VirtAddr::new(0xb8000), offset) };
+    assert_eq!(vga, Some(PhysAddr::new(0xb8000)));
+    assert_eq!(unsafe { translate_addr(VirtAddr::new(0xb8f9c), offset) }, Some(PhysAddr::new(0xb8f9c)));
+}
+
+#[test_case]
+fn test_translate_huge_pages() {
+    // el bootloader mapea toda la memoria física con páginas grandes
+    let offset = physical_memory_offset().unwrap();
+    for phys in [0, 0x1234, 0x20_0000, 0x3f_ffff, 0x12_3456] {
+        assert_eq!(unsafe { translate_addr(offset + phys, offset) }, Some(PhysAddr::new(phys)));
+    }
+    assert_eq!(unsafe { translate_addr(VirtAddr::new(0x_7777_0000_0000), offset) }, None);
+}
+
+#[test_case]
+fn test_leaf_address() {
+    let addr = VirtAddr::new(0x_1234_5678_9abc);
+    assert_eq!(leaf_address(PhysAddr::new(0x4000_0000), addr, 1), PhysAddr::new(0x4000_0000 + 0x1678_9abc));
+    assert_eq!(leaf_address(PhysAddr::new(0x20_0000), addr, 2), PhysAddr::new(0x20_0000 + 0x18_9abc));
+    assert_eq!(leaf_address(PhysAddr::new(0x5000), addr, 3), PhysAddr::new(0x5abc));
+}
+
+#[test_case]
+fn test_frame_allocator_thousands() {
+    use alloc::collections::BTreeSet;
+    with_test_allocator(|frame_allocator, base| {
+        // base + 0x11000..0x200000 y base + 0x300000..0x400000, menos el
+        // marco de las etiquetas
+        let expected = 0x1ef - 1 + 0x100;
+        assert_eq!(frame_allocator.frames_remaining(), expected);
+        let mut seen = BTreeSet::new();
+        while let Some(frame) = frame_allocator.allocate_frame() {
+            let addr = frame.start_address().as_u64() - base;
+            assert!((0x1_2000..0x20_0000).contains(&addr) || (0x30_0000..0x40_0000).contains(&addr), "{:#x}", addr);
+            assert!(seen.insert(addr), "{:#x} handed out twice", addr);
+        }
+        assert_eq!(seen.len(), expected);
+        assert_eq!(frame_allocator.allocated_count(), expected);
+        assert_eq!(frame_allocator.frames_remaining(), 0);
+        assert_eq!(frame_allocator.allocate_frame(), None);
+        assert_eq!(frame_allocator.free_blocks(), [0; ORDERS]);
+    });
+}
+
+#[test_case]
+fn test_buddy_split_and_merge() {
+    with_test_allocator(|frame_allocator, base| {
+        // 0x12000 orden 1, 0x14000 orden 2, ... hasta 0x100000 orden 8, y
+        // 0x300000 orden 8
+        let initial = [0, 1, 1, 1, 0, 1, 1, 1, 2, 0, 0];
+        assert_eq!(frame_allocator.free_blocks(), initial);
+
+        // el bloque más chico que alcanza se parte en dos
+        let frame = frame_allocator.allocate_frame().unwrap();
+        assert_eq!(frame.start_address().as_u64(), base + 0x1_2000);
+        assert_eq!(frame_allocator.free_blocks()[..2], [1, 0]);
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        assert_eq!(frame_allocator.free_blocks(), initial);
+
+        // los bloques salen alineados a su tamaño
+        let big = frame_allocator.alloc_contiguous(8).unwrap();
+        assert!(big.start_address().is_aligned(block_size(8)));
+        assert_eq!(frame_allocator.allocated_count(), 256);
+        assert_eq!(frame_allocator.alloc_contiguous(9), None);
+        assert_eq!(frame_allocator.alloc_contiguous(MAX_ORDER + 1), None);
+        let small = frame_allocator.alloc_contiguous(2).unwrap();
+        assert_eq!(small.start_address().as_u64(), base + 0x1_4000);
+        unsafe { frame_allocator.free(big, 8) };
+        unsafe { frame_allocator.free(small, 2) };
+        assert_eq!(frame_allocator.free_blocks(), initial);
+        assert_eq!((frame_allocator.allocated_count(), frame_allocator.freed_count()), (0, 261));
+    });
+}
+
+#[test_case]
+fn test_allocate_contiguous_gives_back_the_rest() {
+    with_test_allocator(|frame_allocator, base| {
+        let remaining = frame_allocator.frames_remaining();
+        // tres marcos salen de un bloque de cuatro; el cuarto vuelve
+        let run = frame_allocator.allocate_contiguous(3).unwrap();
+        assert_eq!(run.start_address().as_u64(), base + 0x1_4000);
+        assert_eq!(frame_allocator.allocated_count(), 3);
+        assert_eq!(frame_allocator.frames_remaining(), remaining - 3);
+        assert_eq!(frame_allocator.allocate_frame(), Some(run + 3));
+        assert_eq!(frame_allocator.allocate_contiguous(0), None);
+        assert_eq!(frame_allocator.allocate_contiguous(2000), None);
+    });
+}
+
+#[test_case]
+fn test_frame_allocator_from_boot() {
+    // el de verdad, si ya está instalado: miles de marcos distintos
+    let Some(frames) = with_paging(|_, frame_allocator| {
+        let before = (frame_allocator.allocated_count(), frame_allocator.frames_remaining());
+        let frames: alloc::vec::Vec<_> = (0..2000).map_while(|_| frame_allocator.allocate_frame()).collect();
+        assert_eq!(frame_allocator.allocated_count(), before.0 + frames.len());
+        assert_eq!(frame_allocator.frames_remaining(), before.1 - frames.len());
+        frames
+    }) else {
+        return;
+    };
+    assert_eq!(frames.len(), 2000);
+    assert!(frames.iter().all(|frame| frame.start_address().is_aligned(4096u64)));
+    let distinct: alloc::collections::BTreeSet<_> = frames.iter().collect();
+    assert_eq!(distinct.len(), frames.len());
+}
+
+#[test_case]
+fn test_unmap_and_reuse_frame() {
+    // una dirección que nadie usa
+    let page = Page::containing_address(VirtAddr::new(0x_5555_0000_0000));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    with_paging(|mapper, frame_allocator| {
+        let frame = frame_allocator.allocate_frame().unwrap();
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator) }.unwrap().flush();
+        let ptr: *mut u64 = page.start_address().as_mut_ptr();
+        unsafe { ptr.write_volatile(0x_dead_beef) };
+        let through_phys: *const u64 = (physical_memory_offset().unwrap() + frame.start_address().as_u64()).as_ptr();
+        assert_eq!(unsafe { through_phys.read_volatile() }, 0x_dead_beef);
+
+        assert_eq!(unmap_page(page, mapper).unwrap(), frame);
+        assert!(!is_mapped(page.start_address()));
+        assert!(matches!(unmap_page(page, mapper), Err(UnmapError::PageNotMapped)));
+        let in_use = frame_allocator.allocated_count();
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        assert_eq!(frame_allocator.allocated_count(), in_use - 1);
+
+        // la página nueva recibe el mismo marco
+        let other = page + 1;
+        let reused = frame_allocator.allocate_frame().unwrap();
+        assert_eq!(reused, frame);
+        unsafe { mapper.map_to(other, reused, flags, frame_allocator) }.unwrap().flush();
+        assert_eq!(unsafe { translate_addr(other.start_address(), physical_memory_offset().unwrap()) }, Some(frame.start_address()));
+        let frame = unmap_page(other, mapper).unwrap();
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }).expect("the frame allocator is installed for tests");
+}
+
+#[test_case]
+fn test_map_range() {
+    let start = VirtAddr::new(0x_5555_1000_0000);
+    let size = 64 * 1024;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    with_paging(|mapper, frame_allocator| {
+        let in_use = frame_allocator.allocated_count();
+        map_range(start, size, flags, mapper, frame_allocator).unwrap();
+        let words = (size / 8) as usize;
+        let base: *mut u64 = start.as_mut_ptr();
+        for i in 0..words {
+            unsafe { base.add(i).write_volatile(i as u64 * 0x0101) };
         }
+        for i in 0..words {
+            assert_eq!(unsafe { base.add(i).read_volatile() }, i as u64 * 0x0101);
+        }
+        assert_eq!(page_flags(start + (size - 1)), Some(flags));
+
+        // se pisa con la última página: no mapea nada y deja lo de antes
+        let overlap = start + (size - 4096);
+        let remaining = frame_allocator.frames_remaining();
+        assert_eq!(
+            map_range(overlap, 3 * 4096, flags, mapper, frame_allocator),
+            Err(MapRangeError::AlreadyMapped(Page::containing_address(overlap)))
+        );
+        assert_eq!(frame_allocator.frames_remaining(), remaining);
+        assert!(!is_mapped(overlap + 4096u64));
+        assert_eq!(unsafe { base.add(words - 1).read_volatile() }, (words as u64 - 1) * 0x0101);
+
+        unsafe { unmap_range(start, size, mapper, frame_allocator) };
+        assert!(!is_mapped(start));
+        // las tablas nuevas siguen ahí; los 16 marcos de datos no
+        assert!(frame_allocator.allocated_count() - in_use < 16);
+    }).expect("the frame allocator is installed for tests");
+}
+
+#[test_case]
+fn test_map_range_rounds_and_rolls_back() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    // 2 bytes a caballo de dos páginas
+    let start = VirtAddr::new(0x_5555_2000_0fff);
+    let pages = page_range(start, 2);
+    assert_eq!(pages.count(), 2);
+    assert_eq!(page_range(start, 0).count(), 0);
+    with_paging(|mapper, frame_allocator| {
+        // la tercera página está ocupada: falla sin tocar las otras
+        let taken = Page::containing_address(VirtAddr::new(0x_5555_2000_2000));
+        map_range(taken.start_address(), 1, flags, mapper, frame_allocator).unwrap();
+        assert_eq!(
+            map_range(VirtAddr::new(0x_5555_2000_0000), 4 * 4096, flags, mapper, frame_allocator),
+            Err(MapRangeError::AlreadyMapped(taken))
+        );
+        assert!(!is_mapped(VirtAddr::new(0x_5555_2000_0000)));
+        unsafe { unmap_range(taken.start_address(), 1, mapper, frame_allocator) };
+
+        map_range(start, 2, flags, mapper, frame_allocator).unwrap();
+        assert!(is_mapped(VirtAddr::new(0x_5555_2000_0000)) && is_mapped(VirtAddr::new(0x_5555_2000_1fff)));
+        assert!(!is_mapped(VirtAddr::new(0x_5555_2000_2000)));
+        unsafe { unmap_range(start, 2, mapper, frame_allocator) };
+    }).expect("the frame allocator is installed for tests");
+
+    // los marcos se acaban en la tercera página: se deshacen las dos
+    // primeras y los marcos vuelven
+    with_paging(|mapper, frame_allocator| {
+        let remaining = frame_allocator.frames_remaining();
+        let mut limited = Limited { inner: frame_allocator, left: 2 };
+        let first = VirtAddr::new(0x_5555_2000_0000);
+        assert_eq!(map_range(first, 4 * 4096, flags, mapper, &mut limited), Err(MapRangeError::OutOfFrames));
+        assert!((0..4u64).all(|i| !is_mapped(first + i * 4096)));
+        assert_eq!(frame_allocator.frames_remaining(), remaining);
+    }).expect("the frame allocator is installed for tests");
+}
+
+/// Un allocator que se queda sin marcos después de `left`.
+#[cfg(test)]
+struct Limited<'a> {
+    inner: &'a mut BootInfoFrameAllocator,
+    left: usize,
+}
+
+#[cfg(test)]
+unsafe impl FrameAllocator<Size4KiB> for Limited<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.left = self.left.checked_sub(1)?;
+        self.inner.allocate_frame()
+    }
+}
+
+#[cfg(test)]
+impl FrameDeallocator<Size4KiB> for Limited<'_> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.inner.deallocate_frame(frame);
     }
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_byte_size() {
+    use alloc::string::ToString;
+    assert_eq!(ByteSize(0).to_string(), "0 B");
+    assert_eq!(ByteSize(1023).to_string(), "1023 B");
+    assert_eq!(ByteSize(1024).to_string(), "1 KiB");
+    assert_eq!(ByteSize(12 * 1024 + 100).to_string(), "12 KiB");
+    assert_eq!(ByteSize(100 * 1024).to_string(), "100 KiB");
+    assert_eq!(ByteSize(3 << 19).to_string(), "1.5 MiB");
+    assert_eq!(ByteSize(128 << 20).to_string(), "128 MiB");
+    assert_eq!(ByteSize((2 << 30) + (1 << 29)).to_string(), "2.5 GiB");
+}
+
+#[test_case]
+fn test_stats_count_frames() {
+    let before = stats().expect("the frame allocator is installed for tests");
+    assert!(before.usable_bytes > 0);
+    let heap = before.heap.expect("the heap is set up for tests");
+    assert_eq!(heap.size, crate::allocator::HEAP_SIZE);
+    let block = alloc::boxed::Box::new([0u8; 256]);
+    let used = crate::allocator::heap_stats().unwrap().used;
+    assert_eq!(used, heap.used + 256);
+    drop(block);
+    assert_eq!(crate::allocator::heap_stats().unwrap().used, heap.used);
+
+    let frame = allocate_frame().unwrap();
+    let after = stats().unwrap();
+    assert_eq!(after.frames_allocated, before.frames_allocated + 1);
+    assert_eq!(after.frames_in_use(), before.frames_in_use() + 1);
+    assert_eq!(after.frames_remaining, before.frames_remaining - 1);
+
+    unsafe { deallocate_frame(frame) };
+    let freed = stats().unwrap();
+    assert_eq!(freed.frames_freed, before.frames_freed + 1);
+    assert_eq!(freed.frames_in_use(), before.frames_in_use());
+}
+
+#[test_case]
+fn test_global_map_and_unmap() {
+    // todo por la API global, como lo haría un driver
+    let page = Page::containing_address(VirtAddr::new(0x_5556_0000_0000));
+    let frame = allocate_frame().expect("the frame allocator is installed for tests");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    with_paging(|mapper, frame_allocator| unsafe { mapper.map_to(page, frame, flags, frame_allocator) })
+        .unwrap()
+        .unwrap()
+        .flush();
+    assert!(page_flags(page.start_address()).is_some_and(|found| found.contains(flags)));
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe { ptr.write_volatile(0x_5eed) };
+    let offset = physical_memory_offset().unwrap();
+    let through_phys: *const u64 = (offset + frame.start_address().as_u64()).as_ptr();
+    assert_eq!(unsafe { through_phys.read_volatile() }, 0x_5eed);
+
+    let unmapped = with_paging(|mapper, _| unmap_page(page, mapper)).unwrap();
+    assert_eq!(unmapped.ok(), Some(frame));
+    assert!(!is_mapped(page.start_address()));
+    unsafe { deallocate_frame(frame) };
+    // el marco devuelto es el próximo en salir
+    assert_eq!(allocate_frame(), Some(frame));
+    unsafe { deallocate_frame(frame) };
+}
+
+#[test_case]
+fn test_example_mapping() {
+    let page = Page::containing_address(VirtAddr::new(0x_5557_0000_0000));
+    let frame = create_example_mapping(page);
+    let offset = physical_memory_offset().unwrap();
+    assert_eq!(unsafe { translate_addr(page.start_address() + 0x10u64, offset) }, Some(PhysAddr::new(0xb8010)));
+    assert_eq!(with_paging(|mapper, _| unmap_page(page, mapper).ok()).flatten(), Some(frame));
+}
+
+#[test_case]
+fn test_entry_flags() {
+    use alloc::string::ToString;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    assert_eq!(EntryFlags(flags).to_string(), "P W NX");
+    assert_eq!(EntryFlags(PageTableFlags::all()).to_string(), "P W U NX H A D");
+    assert_eq!(EntryFlags(PageTableFlags::empty()).to_string(), "");
+}
+
+#[test_case]
+fn test_walk_vga_buffer() {
+    let walk = walk(VirtAddr::new(0xb8000)).unwrap();
+    assert_eq!(walk.phys, Some(PhysAddr::new(0xb8000)));
+    let flags = walk.effective_flags();
+    assert!(flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE), "{:?}", flags);
+    assert_eq!(walk.steps.len(), 4);
+    assert_eq!(walk.steps.iter().map(|step| step.level).collect::<alloc::vec::Vec<_>>(), [4, 3, 2, 1]);
+    assert_eq!(walk.steps[3].index, 0xb8);
+    let shown = alloc::format!("{}", walk);
+    assert!(shown.lines().last().unwrap().starts_with("0xb8000 -> phys 0xb8000 [P W"), "{}", shown);
+}
+
+#[test_case]
+fn test_walk_unmapped_and_huge() {
+    let unmapped = walk(VirtAddr::new(0x_7777_0000_0000)).unwrap();
+    assert_eq!(unmapped.phys, None);
+    assert_eq!(unmapped.effective_flags(), PageTableFlags::empty());
+    let last = unmapped.steps.last().unwrap();
+    assert!(!last.flags.contains(PageTableFlags::PRESENT));
+    assert!(alloc::format!("{}", unmapped).contains("not present, the access faults here"));
+
+    // la memoria física está mapeada con páginas grandes: la hoja es la P3 o la P2
+    let offset = physical_memory_offset().unwrap();
+    let huge = walk(offset + 0x20_0000u64).unwrap();
+    assert_eq!(huge.phys, Some(PhysAddr::new(0x20_0000)));
+    assert!(huge.steps.len() < 4);
+    assert!(huge.steps.last().unwrap().flags.contains(PageTableFlags::HUGE_PAGE));
+}
+
+#[test_case]
+fn test_dump_page_tables() {
+    let offset = physical_memory_offset().unwrap();
+    let mut summary = alloc::string::String::new();
+    dump_page_tables(offset, DumpFilter::SUMMARY, &mut summary).unwrap();
+    assert!(summary.lines().any(|line| line.starts_with("L4   0: 0x0000000000000000 table at")));
+    assert!(!summary.contains("L2 "));
+
+    // las 4 KiB del búfer VGA, hasta la P1
+    let mut vga = alloc::string::String::new();
+    dump_page_tables(offset, DumpFilter::range(0xb8000, 0xb8fff), &mut vga).unwrap();
+    let tables = vga.lines().filter(|line| line.contains("table at")).count();
+    assert_eq!(tables, 3, "{}", vga);
+    let page = vga.lines().find(|line| line.contains("-> phys")).unwrap();
+    assert!(page.trim_start().starts_with("0x00000000000b8000 - 0x00000000000b8fff -> phys 0xb8000 [P W"), "{}", page);
+    assert!(page.ends_with("(4 KiB pages)"));
+
+    // el mapeo de la memoria física son páginas grandes, juntas en rangos
+    // salvo donde cambian los bits A y D: a lo sumo una por página de 2 MiB
+    let mut physical = alloc::string::String::new();
+    dump_page_tables(offset, DumpFilter::range(offset.as_u64(), offset.as_u64() + 0x3ff_ffff), &mut physical).unwrap();
+    let runs: alloc::vec::Vec<&str> = physical.lines().filter(|line| line.contains("-> phys")).collect();
+    assert!(!runs.is_empty() && runs.len() <= 32, "{}", physical);
+    assert!(runs.iter().all(|run| !run.ends_with("(4 KiB pages)")));
+}
+
+#[test_case]
+fn test_lazy_region() {
+    let start = VirtAddr::new(0x_5558_0000_0000);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    register_lazy_region("test", start, 4 * 4096, flags).unwrap();
+    assert!(!is_mapped(start));
+    let faults = lazy_faults();
+    // leer la primera vez ya la mapea, en ceros
+    let first: *mut u64 = (start + 8u64).as_mut_ptr();
+    assert_eq!(unsafe { first.read_volatile() }, 0);
+    unsafe { first.write_volatile(0x_ba2e) };
+    assert_eq!(unsafe { first.read_volatile() }, 0x_ba2e);
+    let last: *mut u8 = (start + (4 * 4096 - 1u64)).as_mut_ptr();
+    unsafe { last.write_volatile(7) };
+    assert_eq!(lazy_faults(), faults + 2);
+    assert!(page_flags(start).is_some_and(|found| found.contains(flags)));
+    // las páginas del medio no se tocaron
+    assert!(!is_mapped(start + 4096u64));
+
+    assert_eq!(register_lazy_region("other", start + 4096u64, 4096, flags), Err(LazyRegionError::Overlaps("test")));
+    assert_eq!(register_lazy_region("other", start + 1u64, 4096, flags), Err(LazyRegionError::Unaligned));
+    assert_eq!(unregister_lazy_region(start).map(|region| region.name), Some("test"));
+    assert!(lazy_regions().iter().all(|region| region.name != "test"));
+    with_paging(|mapper, frame_allocator| unsafe { unmap_range(start, 4 * 4096, mapper, frame_allocator) });
+    assert!(!is_mapped(start));
+}
+
+#[test_case]
+fn test_lazy_fault_allowed() {
+    let kernel = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let user = kernel | PageTableFlags::USER_ACCESSIBLE;
+    assert!(lazy_fault_allowed(PageFaultErrorCode::CAUSED_BY_WRITE, kernel));
+    assert!(!lazy_fault_allowed(PageFaultErrorCode::PROTECTION_VIOLATION, kernel));
+    assert!(!lazy_fault_allowed(PageFaultErrorCode::USER_MODE, kernel));
+    assert!(lazy_fault_allowed(PageFaultErrorCode::USER_MODE, user));
+}
+
+#[test_case]
+fn test_map_huge_2mib() {
+    // los primeros 2 MiB de memoria física, con el buffer VGA adentro
+    let virt = VirtAddr::new(0x_5559_0000_0000);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    with_paging(|mapper, frame_allocator| map_huge_2mib(virt, PhysAddr::new(0), 2 << 20, flags, mapper, frame_allocator))
+        .unwrap()
+        .unwrap();
+    let offset = physical_memory_offset().unwrap();
+    assert_eq!(unsafe { translate_addr(virt + 0xb8010u64, offset) }, Some(PhysAddr::new(0xb8010)));
+    assert!(page_flags(virt).is_some_and(|found| found.contains(flags | PageTableFlags::HUGE_PAGE)));
+    let through_huge: *const u16 = (virt + 0xb8000u64).as_ptr();
+    let through_phys: *const u16 = (offset + 0xb8000u64).as_ptr();
+    assert_eq!(unsafe { through_huge.read_volatile() }, unsafe { through_phys.read_volatile() });
+
+    let again = with_paging(|mapper, frame_allocator| map_huge_2mib(virt, PhysAddr::new(0), 2 << 20, flags, mapper, frame_allocator));
+    assert_eq!(again, Some(Err(MapHugeError::AlreadyMapped(virt))));
+    let unaligned = with_paging(|mapper, frame_allocator| map_huge_2mib(virt + 4096u64, PhysAddr::new(0), 2 << 20, flags, mapper, frame_allocator));
+    assert_eq!(unaligned, Some(Err(MapHugeError::Unaligned)));
+    with_paging(|mapper, _| unsafe { unmap_huge::<Size2MiB>(virt, 2 << 20, mapper) });
+    assert!(!is_mapped(virt));
+}
+
+#[test_case]
+fn test_map_huge_1gib() {
+    let virt = VirtAddr::new(0x_555a_0000_0000);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mapped = with_paging(|mapper, frame_allocator| map_huge_1gib(virt, PhysAddr::new(0), 1 << 30, flags, mapper, frame_allocator)).unwrap();
+    if !has_1gib_pages() {
+        assert_eq!(mapped, Err(MapHugeError::Unsupported));
+        return;
+    }
+    mapped.unwrap();
+    let offset = physical_memory_offset().unwrap();
+    assert_eq!(unsafe { translate_addr(virt + 0x12_3456u64, offset) }, Some(PhysAddr::new(0x12_3456)));
+    with_paging(|mapper, _| unsafe { unmap_huge::<Size1GiB>(virt, 1 << 30, mapper) });
+    assert!(!is_mapped(virt));
+}
+
+#[test_case]
+fn test_frame_refs() {
+    // muy por encima de la memoria de QEMU: nadie más lo comparte
+    let frame = PhysFrame::containing_address(PhysAddr::new(0x7f_0000_0000));
+    assert_eq!(frame_refs(frame), 1);
+    share_frame(frame);
+    share_frame(frame);
+    assert_eq!(frame_refs(frame), 3);
+    assert!(!release_frame(frame));
+    assert!(!release_frame(frame));
+    assert_eq!(frame_refs(frame), 1);
+    assert!(release_frame(frame));
+}
+
+#[test_case]
+fn test_kernel_stack_guard_page() {
+    let stack = KernelStack::allocate().unwrap();
+    assert!(is_mapped(stack.bottom()));
+    assert!(is_mapped(stack.top() - 1u64));
+    assert!(!is_mapped(stack.guard_page().start_address()));
+    assert_eq!(stack.top() - stack.bottom(), KERNEL_STACK_PAGES * 4096);
+    assert_eq!(stack_guard_hit(stack.bottom() - 8u64), Some(stack.top()));
+    assert_eq!(stack_guard_hit(stack.bottom()), None);
+    assert_eq!(stack_guard_hit(VirtAddr::new(0xb8000)), None);
+    // la que se suelta es la próxima que se entrega
+    let top = stack.top();
+    drop(stack);
+    let again = KernelStack::allocate().unwrap();
+    assert_eq!(again.top(), top);
+}
@@ -0,0 +1,338 @@
+//! Memoria virtual del kernel al estilo de `mmap`: se pide un tamaño y se
+//! recibe un rango nuevo, ya mapeado, de una ventana que no usa nadie más.
+//!
+//! Cada rango queda anotado como `VirtualRegion`, así que `regions()` (y
+//! el comando `vmmap`) muestra todo lo que se mapeó por acá, más lo que se
+//! mapeó por otro lado y se anotó con `track`, como el heap. Entre dos
+//! regiones queda siempre una página sin mapear, para que pasarse del
+//! final sea un page fault y no pisar al vecino.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use super::MapRangeError;
+
+/// La ventana de la que salen las regiones: 16 GiB después de las pilas
+/// del kernel.
+pub const VMM_START: u64 = 0x_4444_c000_0000;
+pub const VMM_SIZE: u64 = 16 << 30;
+const GUARD_SIZE: u64 = 4096;
+
+/// Qué hay detrás de una región.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Marcos nuevos, en cero, que se devuelven al quitarla.
+    Anonymous,
+    /// Memoria física de otro (MMIO, el búfer VGA) desde esta dirección:
+    /// al quitarla los marcos no se tocan.
+    Physical(PhysAddr),
+    /// Mapeada por otro lado y solo anotada con `track`.
+    Fixed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualRegion {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    /// En bytes, múltiplo de página.
+    pub size: u64,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl VirtualRegion {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    fn pages(&self) -> x86_64::structures::paging::page::PageRange {
+        super::page_range(self.start, self.size)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    /// El tamaño es cero.
+    Empty,
+    /// Todavía no hay paginación global.
+    NoPaging,
+    /// No queda un hueco de ese tamaño en la ventana.
+    OutOfSpace,
+    Map(MapRangeError),
+    /// Ninguna región que se pueda quitar contiene esa dirección.
+    NotFound(VirtAddr),
+    /// Se pisa con la región de ese nombre.
+    Overlaps(&'static str),
+}
+
+impl core::fmt::Display for VmmError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            VmmError::Empty => f.write_str("the region is empty"),
+            VmmError::NoPaging => f.write_str("paging is not set up yet"),
+            VmmError::OutOfSpace => f.write_str("no room left in the kernel virtual window"),
+            VmmError::Map(err) => write!(f, "{}", err),
+            VmmError::NotFound(addr) => write!(f, "no region to unmap at {:#x}", addr.as_u64()),
+            VmmError::Overlaps(name) => write!(f, "overlaps the region '{}'", name),
+        }
+    }
+}
+
+/// Las regiones por dirección de inicio.
+static REGIONS: spin::Mutex<BTreeMap<u64, VirtualRegion>> = spin::Mutex::new(BTreeMap::new());
+
+fn page_align(size: u64) -> u64 {
+    (size + 0xfff) & !0xfff
+}
+
+/// El primer hueco de la ventana donde entra `size` más la página de
+/// guarda, lejos de las regiones que ya hay.
+fn find_gap(regions: &BTreeMap<u64, VirtualRegion>, size: u64) -> Option<VirtAddr> {
+    let end = VMM_START + VMM_SIZE;
+    let mut candidate = VMM_START;
+    for region in regions.values() {
+        let region_end = region.end().as_u64();
+        if region_end <= candidate || region.start.as_u64() >= end {
+            continue;
+        }
+        if region.start.as_u64() >= candidate + size + GUARD_SIZE {
+            break;
+        }
+        candidate = region_end + GUARD_SIZE;
+    }
+    (candidate + size <= end).then(|| VirtAddr::new(candidate))
+}
+
+/// Aparta un rango de la ventana y lo anota, todavía sin mapear.
+fn reserve(name: &'static str, size: u64, flags: PageTableFlags, backing: Backing) -> Result<VirtualRegion, VmmError> {
+    if size == 0 {
+        return Err(VmmError::Empty);
+    }
+    let size = page_align(size);
+    without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let start = find_gap(&regions, size).ok_or(VmmError::OutOfSpace)?;
+        let region = VirtualRegion { name, start, size, flags, backing };
+        regions.insert(start.as_u64(), region);
+        Ok(region)
+    })
+}
+
+fn forget(start: VirtAddr) -> Option<VirtualRegion> {
+    without_interrupts(|| REGIONS.lock().remove(&start.as_u64()))
+}
+
+/// `size` bytes nuevos, redondeados a páginas, mapeados con `flags` a
+/// marcos en cero. Se devuelven con `unmap_region`.
+pub fn alloc_region(size: u64, flags: PageTableFlags) -> Result<VirtualRegion, VmmError> {
+    let flags = flags | PageTableFlags::PRESENT;
+    let region = reserve("anonymous", size, flags, Backing::Anonymous)?;
+    let mapped = super::with_paging(|mapper, frame_allocator| {
+        super::map_range(region.start, region.size, flags, mapper, frame_allocator)
+    });
+    let result = match mapped {
+        None => Err(VmmError::NoPaging),
+        Some(result) => result.map_err(VmmError::Map),
+    };
+    if let Err(err) = result {
+        forget(region.start);
+        return Err(err);
+    }
+    let offset = super::physical_memory_offset().ok_or(VmmError::NoPaging)?;
+    // map_range no limpia los marcos
+    for page in region.pages() {
+        if let Some(phys) = unsafe { super::translate_addr(page.start_address(), offset) } {
+            unsafe { core::ptr::write_bytes((offset + phys.as_u64()).as_mut_ptr::<u8>(), 0, 4096) };
+        }
+    }
+    Ok(region)
+}
+
+/// Mapea `size` bytes de memoria física desde `phys`, sin caché, como
+/// hace falta para los registros de un dispositivo. Devuelve la dirección
+/// virtual de `phys`, que no tiene por qué estar alineada.
+pub fn map_physical(phys: PhysAddr, size: u64) -> Result<VirtAddr, VmmError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let first = phys.align_down(4096u64);
+    let in_page = phys - first;
+    let region = reserve("physical", in_page + size, flags, Backing::Physical(first))?;
+    let mapped = super::with_paging(|mapper, frame_allocator| {
+        for (i, page) in region.pages().enumerate() {
+            let frame = PhysFrame::<Size4KiB>::containing_address(first + i as u64 * 4096);
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    unmap_pages(region.pages().take(i), mapper);
+                    return Err(match err {
+                        MapToError::FrameAllocationFailed => MapRangeError::OutOfFrames,
+                        MapToError::ParentEntryHugePage => MapRangeError::HugePage(page),
+                        MapToError::PageAlreadyMapped(_) => MapRangeError::AlreadyMapped(page),
+                    });
+                }
+            }
+        }
+        Ok(())
+    });
+    let result = match mapped {
+        None => Err(VmmError::NoPaging),
+        Some(result) => result.map_err(VmmError::Map),
+    };
+    match result {
+        Ok(()) => Ok(region.start + in_page),
+        Err(err) => {
+            forget(region.start);
+            Err(err)
+        }
+    }
+}
+
+/// Quita los mapeos sin devolver los marcos.
+fn unmap_pages(pages: impl Iterator<Item = Page>, mapper: &mut impl Mapper<Size4KiB>) {
+    for page in pages {
+        let _ = super::unmap_page(page, mapper);
+    }
+}
+
+/// Quita la región que contiene `addr` (la dirección que dieron
+/// `alloc_region` o `map_physical` sirve) y la devuelve. Los marcos de una
+/// región anónima vuelven al allocator.
+///
+/// # Safety
+///
+/// Nadie puede seguir usando la región.
+pub unsafe fn unmap_region(addr: VirtAddr) -> Result<VirtualRegion, VmmError> {
+    let region = find(addr).filter(|region| region.backing != Backing::Fixed).ok_or(VmmError::NotFound(addr))?;
+    forget(region.start);
+    super::with_paging(|mapper, frame_allocator| match region.backing {
+        Backing::Anonymous => unsafe { super::unmap_range(region.start, region.size, mapper, frame_allocator) },
+        _ => unmap_pages(region.pages(), mapper),
+    })
+    .ok_or(VmmError::NoPaging)?;
+    Ok(region)
+}
+
+/// Anota un mapeo hecho por otro lado (el heap, el de ejemplo de
+/// `kernel_main`) para que aparezca en `regions()`. No mapea nada, y
+/// puede estar fuera de la ventana; `untrack` lo borra.
+pub fn track(name: &'static str, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), VmmError> {
+    if size == 0 {
+        return Err(VmmError::Empty);
+    }
+    let region = VirtualRegion { name, start, size: page_align(size), flags, backing: Backing::Fixed };
+    without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let end = region.end();
+        if let Some(other) = regions.values().find(|other| other.start < end && region.start < other.end()) {
+            return Err(VmmError::Overlaps(other.name));
+        }
+        regions.insert(start.as_u64(), region);
+        Ok(())
+    })
+}
+
+/// Borra la anotación de `track` que empieza en `start`.
+pub fn untrack(start: VirtAddr) -> Option<VirtualRegion> {
+    without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        match regions.get(&start.as_u64()) {
+            Some(region) if region.backing == Backing::Fixed => regions.remove(&start.as_u64()),
+            _ => None,
+        }
+    })
+}
+
+/// La región que contiene `addr`.
+pub fn find(addr: VirtAddr) -> Option<VirtualRegion> {
+    without_interrupts(|| {
+        let regions = REGIONS.lock();
+        regions.range(..=addr.as_u64()).next_back().map(|(_, region)| *region).filter(|region| region.contains(addr))
+    })
+}
+
+/// Todas las regiones, por dirección.
+pub fn regions() -> Vec<VirtualRegion> {
+    without_interrupts(|| REGIONS.lock().values().copied().collect())
+}
+
+//test case
+#[test_case]
+fn test_find_gap() {
+    let mut regions = BTreeMap::new();
+    assert_eq!(find_gap(&regions, 0x2000), Some(VirtAddr::new(VMM_START)));
+    let region = |start, size| VirtualRegion {
+        name: "test",
+        start: VirtAddr::new(start),
+        size,
+        flags: PageTableFlags::PRESENT,
+        backing: Backing::Anonymous,
+    };
+    regions.insert(VMM_START, region(VMM_START, 0x3000));
+    // una página de guarda después de cada región
+    assert_eq!(find_gap(&regions, 0x1000), Some(VirtAddr::new(VMM_START + 0x4000)));
+    regions.insert(VMM_START + 0x6000, region(VMM_START + 0x6000, 0x1000));
+    // entra justo entre las dos, con guarda a cada lado
+    assert_eq!(find_gap(&regions, 0x1000), Some(VirtAddr::new(VMM_START + 0x4000)));
+    assert_eq!(find_gap(&regions, 0x2000), Some(VirtAddr::new(VMM_START + 0x8000)));
+    // las anotaciones fuera de la ventana no molestan
+    regions.insert(0x1000, region(0x1000, 0x1000));
+    assert_eq!(find_gap(&regions, 0x1000), Some(VirtAddr::new(VMM_START + 0x4000)));
+    assert_eq!(find_gap(&regions, VMM_SIZE), None);
+}
+
+#[test_case]
+fn test_alloc_and_unmap_region() {
+    let in_use = super::stats().unwrap().frames_in_use();
+    let region = alloc_region(0x2800, PageTableFlags::WRITABLE).unwrap();
+    assert_eq!(region.size, 0x3000);
+    assert_eq!(find(region.start + 0x2fffu64), Some(region));
+    let ptr = region.start.as_mut_ptr::<u64>();
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    unsafe { ptr.write_volatile(0x_1234) };
+    assert!(regions().contains(&region));
+    // la siguiente deja una página de guarda
+    let next = alloc_region(1, PageTableFlags::WRITABLE).unwrap();
+    assert!(next.start >= region.end() + GUARD_SIZE);
+    assert!(!super::is_mapped(region.end()));
+
+    assert_eq!(unsafe { unmap_region(region.start) }, Ok(region));
+    assert_eq!(unsafe { unmap_region(next.start) }, Ok(next));
+    assert!(!super::is_mapped(region.start));
+    assert_eq!(find(region.start), None);
+    assert_eq!(unsafe { unmap_region(region.start) }, Err(VmmError::NotFound(region.start)));
+    // los marcos de las dos, no las tablas intermedias que hayan quedado
+    assert!(super::stats().unwrap().frames_in_use() <= in_use + 3);
+}
+
+#[test_case]
+fn test_map_physical_vga() {
+    let in_use = super::stats().unwrap().frames_in_use();
+    let virt = map_physical(PhysAddr::new(0xb8010), 16).unwrap();
+    assert!(virt.as_u64() & 0xfff == 0x10);
+    let offset = super::physical_memory_offset().unwrap();
+    assert_eq!(unsafe { super::translate_addr(virt, offset) }, Some(PhysAddr::new(0xb8010)));
+    let region = find(virt).unwrap();
+    assert_eq!((region.backing, region.size), (Backing::Physical(PhysAddr::new(0xb8000)), 0x1000));
+    // el búfer VGA no es del allocator: quitarla no devuelve nada
+    assert_eq!(unsafe { unmap_region(virt) }.map(|region| region.start), Ok(region.start));
+    assert!(!super::is_mapped(virt));
+    assert!(super::stats().unwrap().frames_in_use() <= in_use + 3);
+}
+
+#[test_case]
+fn test_track() {
+    let start = VirtAddr::new(0x_5558_0000_0000);
+    track("test", start, 0x1800, PageTableFlags::PRESENT).unwrap();
+    assert_eq!(find(start + 0x1fffu64).map(|region| region.size), Some(0x2000));
+    assert_eq!(track("other", start + 0x1000u64, 0x1000, PageTableFlags::PRESENT), Err(VmmError::Overlaps("test")));
+    // lo que no mapeó el vmm tampoco lo quita
+    assert_eq!(unsafe { unmap_region(start) }, Err(VmmError::NotFound(start)));
+    assert_eq!(untrack(start).map(|region| region.backing), Some(Backing::Fixed));
+    assert_eq!(find(start), None);
+}
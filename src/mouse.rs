@@ -0,0 +1,596 @@
+//! The PS/2 mouse on the controller's second port, shown as a cursor on
+//! the text screen.
+//!
+//! Like the keyboard, the IRQ 12 handler only queues the bytes the mouse
+//! sends. The `task::mouse` task cuts them into packets, moves the
+//! pointer and redraws the cursor, which is the cell under the pointer
+//! with its colors swapped, then hands each event to the tasks that
+//! called `task::mouse::subscribe`. Bit 3 of a packet's first byte is
+//! always set, so a decoder that lost its place skips bytes until it sees
+//! one.
+//!
+//! Packets are 3 bytes, or 4 when `init` finds a wheel mouse: the fourth
+//! holds the wheel's movement in its low nibble.
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use crate::keyboard::{self, KeyboardError};
+use crate::shell::{self, ShellError};
+use crate::sync::ByteQueue;
+use crate::vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::interrupts;
+
+const DATA_PORT: u16 = 0x60;
+
+// commands to the controller
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_ENABLE_AUX: u8 = 0xa8;
+/// The next byte written goes to the mouse instead of the keyboard.
+const CMD_WRITE_AUX: u8 = 0xd4;
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_OFF: u8 = 1 << 5;
+
+// commands to the mouse
+const MOUSE_GET_ID: u8 = 0xf2;
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xf3;
+const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+const REPLY_ACK: u8 = 0xfa;
+/// What `MOUSE_GET_ID` answers once the wheel is on.
+const ID_WHEEL: u8 = 3;
+
+/// Packets per second asked of the mouse.
+pub const SAMPLE_RATE: u8 = 100;
+
+// bits of a packet's first byte
+const BUTTON_LEFT: u8 = 1 << 0;
+const BUTTON_RIGHT: u8 = 1 << 1;
+const BUTTON_MIDDLE: u8 = 1 << 2;
+const ALWAYS_SET: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// Mouse counts it takes to move the cursor one cell; cells are about
+/// twice as tall as they are wide.
+const COUNTS_PER_COLUMN: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseError {
+    /// Nothing answered on the second port.
+    NoMouse,
+    /// The mouse answered a command with something other than an ACK.
+    UnexpectedReply(u8),
+}
+
+impl fmt::Display for MouseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MouseError::NoMouse => f.write_str("no mouse answered"),
+            MouseError::UnexpectedReply(byte) => write!(f, "unexpected reply {:#04x} from the mouse", byte),
+        }
+    }
+}
+
+impl From<KeyboardError> for MouseError {
+    fn from(err: KeyboardError) -> Self {
+        match err {
+            KeyboardError::Timeout => MouseError::NoMouse,
+            KeyboardError::TooManyResends => MouseError::UnexpectedReply(0xfe),
+            KeyboardError::UnexpectedReply(byte) => MouseError::UnexpectedReply(byte),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Left,
+    Right,
+    Middle,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+impl Buttons {
+    fn from_flags(flags: u8) -> Buttons {
+        Buttons {
+            left: flags & BUTTON_LEFT != 0,
+            right: flags & BUTTON_RIGHT != 0,
+            middle: flags & BUTTON_MIDDLE != 0,
+        }
+    }
+
+    /// The buttons down in `self` that were up in `before`.
+    pub fn pressed_since(self, before: Buttons) -> impl Iterator<Item = Button> {
+        [
+            (Button::Left, self.left && !before.left),
+            (Button::Right, self.right && !before.right),
+            (Button::Middle, self.middle && !before.middle),
+        ]
+        .into_iter()
+        .filter_map(|(button, pressed)| pressed.then_some(button))
+    }
+}
+
+impl fmt::Display for Buttons {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let down: alloc::vec::Vec<&str> = [(self.left, "left"), (self.middle, "middle"), (self.right, "right")]
+            .into_iter()
+            .filter_map(|(down, name)| down.then_some(name))
+            .collect();
+        if down.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&down.join(", "))
+        }
+    }
+}
+
+/// One packet: how far the mouse moved, right and up being positive, and
+/// which buttons are down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub buttons: Buttons,
+    /// Wheel clicks, away from the user being positive; always 0 without
+    /// a wheel.
+    pub wheel: i8,
+}
+
+/// Decodes a packet. A delta that overflowed is dropped rather than
+/// trusted.
+pub fn decode_packet(packet: [u8; 3]) -> MouseEvent {
+    let flags = packet[0];
+    let delta = |byte: u8, sign: u8, overflow: u8| match (flags & overflow != 0, flags & sign != 0) {
+        (true, _) => 0,
+        (false, true) => i16::from(byte) - 256,
+        (false, false) => i16::from(byte),
+    };
+    MouseEvent {
+        dx: delta(packet[1], X_SIGN, X_OVERFLOW),
+        dy: delta(packet[2], Y_SIGN, Y_OVERFLOW),
+        buttons: Buttons::from_flags(flags),
+        wheel: 0,
+    }
+}
+
+/// Decodes a wheel mouse's packet. The wheel sends "towards the user" as
+/// positive, 4 bits of two's complement.
+pub fn decode_wheel_packet(packet: [u8; 4]) -> MouseEvent {
+    let wheel = ((packet[3] << 4) as i8) >> 4;
+    MouseEvent { wheel: -wheel, ..decode_packet([packet[0], packet[1], packet[2]]) }
+}
+
+/// Cuts the byte stream into packets.
+#[derive(Debug)]
+pub struct PacketDecoder {
+    packet: [u8; 4],
+    len: usize,
+    size: usize,
+}
+
+impl Default for PacketDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PacketDecoder {
+    /// For 3-byte packets.
+    pub const fn new() -> Self {
+        PacketDecoder { packet: [0; 4], len: 0, size: 3 }
+    }
+
+    /// For a wheel mouse's 4-byte packets.
+    pub const fn with_wheel() -> Self {
+        PacketDecoder { packet: [0; 4], len: 0, size: 4 }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<MouseEvent> {
+        if self.len == 0 && byte & ALWAYS_SET == 0 {
+            // not a first byte: out of step, wait for one
+            return None;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < self.size {
+            return None;
+        }
+        self.len = 0;
+        let [flags, dx, dy, _] = self.packet;
+        Some(if self.size == 4 { decode_wheel_packet(self.packet) } else { decode_packet([flags, dx, dy]) })
+    }
+}
+
+/// Where the pointer is, in mouse counts so slow movements add up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pointer {
+    x: i32,
+    y: i32,
+    buttons: Buttons,
+}
+
+impl Default for Pointer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pointer {
+    /// In the middle of the screen, no buttons down.
+    pub const fn new() -> Self {
+        Pointer {
+            x: BUFFER_WIDTH as i32 / 2 * COUNTS_PER_COLUMN,
+            y: BUFFER_HEIGHT as i32 / 2 * COUNTS_PER_ROW,
+            buttons: Buttons { left: false, right: false, middle: false },
+        }
+    }
+
+    /// Moves by the event, staying on the screen; the buttons it pressed
+    /// come back.
+    pub fn apply(&mut self, event: MouseEvent) -> Buttons {
+        let max_x = BUFFER_WIDTH as i32 * COUNTS_PER_COLUMN - 1;
+        let max_y = BUFFER_HEIGHT as i32 * COUNTS_PER_ROW - 1;
+        self.x = (self.x + i32::from(event.dx)).clamp(0, max_x);
+        // the mouse counts up, the screen's rows down
+        self.y = (self.y - i32::from(event.dy)).clamp(0, max_y);
+        let (now, before) = (event.buttons, core::mem::replace(&mut self.buttons, event.buttons));
+        Buttons { left: now.left && !before.left, right: now.right && !before.right, middle: now.middle && !before.middle }
+    }
+
+    /// The cell under the pointer, as (column, row).
+    pub fn position(&self) -> (usize, usize) {
+        ((self.x / COUNTS_PER_COLUMN) as usize, (self.y / COUNTS_PER_ROW) as usize)
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+}
+
+/// The attribute a cursor cell shows: foreground and background swapped.
+fn inverted(attribute: u8) -> u8 {
+    attribute.rotate_left(4)
+}
+
+/// The cell the cursor is drawn on and the attribute it had before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Drawn {
+    at: (usize, usize),
+    saved: u8,
+}
+
+/// Moves the cursor from `drawn` to `at`. The old cell gets its attribute
+/// back only if it still shows the cursor: text written there since has
+/// its own colors.
+fn draw_cursor(writer: &mut vga_buffer::Writer, drawn: Option<Drawn>, at: (usize, usize)) -> Drawn {
+    if let Some(old) = drawn {
+        if old.at == at {
+            return old;
+        }
+        let (col, row) = old.at;
+        if writer.attribute(row, col) == inverted(old.saved) {
+            writer.set_attribute(row, col, old.saved);
+        }
+    }
+    let (col, row) = at;
+    let saved = writer.attribute(row, col);
+    writer.set_attribute(row, col, inverted(saved));
+    Drawn { at, saved }
+}
+
+struct State {
+    decoder: PacketDecoder,
+    pointer: Pointer,
+    drawn: Option<Drawn>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State { decoder: PacketDecoder::new(), pointer: Pointer::new(), drawn: None });
+
+/// Bytes read by the interrupt handler and not decoded yet.
+static BYTES: ByteQueue = ByteQueue::new();
+
+static CLICK_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the mouse sends a fourth byte for its wheel.
+static WHEEL: AtomicBool = AtomicBool::new(false);
+
+/// Whether `mouse live` keeps the state in the top right corner.
+static LIVE: AtomicBool = AtomicBool::new(false);
+
+/// Columns the live state takes at the end of the top row.
+const STATUS_WIDTH: usize = 28;
+
+/// Runs `hook` with the button and the cell under the pointer each time a
+/// button goes down. It runs in the mouse task, not the interrupt.
+pub fn set_click_hook(hook: fn(Button, (usize, usize))) {
+    CLICK_HOOK.store(hook as usize, Ordering::Release);
+}
+
+fn run_click_hook(button: Button, at: (usize, usize)) {
+    let hook = CLICK_HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        let hook: fn(Button, (usize, usize)) = unsafe { core::mem::transmute(hook) };
+        hook(button, at);
+    }
+}
+
+/// The cell under the pointer, as (column, row).
+pub fn position() -> (usize, usize) {
+    without_interrupts(|| STATE.lock().pointer.position())
+}
+
+pub fn buttons() -> Buttons {
+    without_interrupts(|| STATE.lock().pointer.buttons())
+}
+
+/// Whether `init` found a wheel mouse.
+pub fn has_wheel() -> bool {
+    WHEEL.load(Ordering::Relaxed)
+}
+
+/// Bytes lost because the mouse task fell behind.
+pub fn dropped_count() -> u64 {
+    BYTES.dropped()
+}
+
+/// The oldest queued byte, for the mouse task.
+pub(crate) fn next_byte() -> Option<u8> {
+    BYTES.pop()
+}
+
+fn interrupt() {
+    let byte = unsafe { Port::<u8>::new(DATA_PORT).read() };
+    if BYTES.push(byte) {
+        crate::task::mouse::WAKER.wake();
+    }
+}
+
+/// Feeds a byte to the decoder and, once it finishes a packet, moves the
+/// cursor, reports any clicks and hands the event to the subscribers.
+pub fn handle_byte(byte: u8) {
+    let Some((event, pressed, at)) = without_interrupts(|| {
+        let mut state = STATE.lock();
+        let event = state.decoder.feed(byte)?;
+        let pressed = state.pointer.apply(event);
+        let at = state.pointer.position();
+        state.drawn = Some(draw_cursor(&mut vga_buffer::WRITER.lock(), state.drawn, at));
+        Some((event, pressed, at))
+    }) else {
+        return;
+    };
+    for button in pressed.pressed_since(Buttons::default()) {
+        run_click_hook(button, at);
+    }
+    crate::task::mouse::publish(event);
+}
+
+/// What `mouse live` shows for an event with the pointer at `at`: the
+/// cell, the buttons held and the event's wheel clicks.
+fn status_line(at: (usize, usize), event: MouseEvent) -> String {
+    let button = |down: bool, name: char| if down { name } else { '-' };
+    let buttons = event.buttons;
+    let status = format!(
+        "mouse {},{} {}{}{} wheel {:+}",
+        at.0,
+        at.1,
+        button(buttons.left, 'L'),
+        button(buttons.middle, 'M'),
+        button(buttons.right, 'R'),
+        event.wheel
+    );
+    format!("{:>width$}", status, width = STATUS_WIDTH)
+}
+
+/// Redraws the live state for `event`, if `mouse live` turned it on.
+pub fn show_live(event: MouseEvent) {
+    if LIVE.load(Ordering::Relaxed) {
+        vga_buffer::write_at(0, BUFFER_WIDTH - STATUS_WIDTH, &status_line(position(), event));
+    }
+}
+
+/// Sends one byte to the mouse and waits for the ACK.
+fn send(byte: u8) -> Result<(), MouseError> {
+    keyboard::write_command(CMD_WRITE_AUX)?;
+    keyboard::write_data(byte)?;
+    match keyboard::read_data()? {
+        REPLY_ACK => Ok(()),
+        other => Err(MouseError::UnexpectedReply(other)),
+    }
+}
+
+/// The IntelliMouse knock: sample rates 200, 100 and 80 in a row, after
+/// which a wheel mouse answers `MOUSE_GET_ID` with 3 instead of 0.
+fn enable_wheel() -> Result<bool, MouseError> {
+    for rate in [200, 100, 80] {
+        send(MOUSE_SET_SAMPLE_RATE)?;
+        send(rate)?;
+    }
+    send(MOUSE_GET_ID)?;
+    Ok(keyboard::read_data()? == ID_WHEEL)
+}
+
+/// Whether the mouse has a wheel comes back.
+fn enable() -> Result<bool, MouseError> {
+    keyboard::write_command(CMD_ENABLE_AUX)?;
+    keyboard::write_command(CMD_READ_CONFIG)?;
+    let config = keyboard::read_data()?;
+    keyboard::write_command(CMD_WRITE_CONFIG)?;
+    keyboard::write_data((config | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_OFF)?;
+    send(MOUSE_SET_DEFAULTS)?;
+    let wheel = enable_wheel()?;
+    send(MOUSE_SET_SAMPLE_RATE)?;
+    send(SAMPLE_RATE)?;
+    send(MOUSE_ENABLE_REPORTING)?;
+    Ok(wheel)
+}
+
+/// Turns on the second port, the wheel if there is one and the mouse's
+/// reports, installs the IRQ 12 handler and draws the cursor in the
+/// middle of the screen.
+pub fn init() -> Result<(), MouseError> {
+    let wheel = without_interrupts(|| {
+        // keep the IRQ 1 handler from eating the replies
+        interrupts::mask_irq(1);
+        let result = enable();
+        interrupts::unmask_irq(1);
+        result
+    })?;
+    WHEEL.store(wheel, Ordering::Relaxed);
+    without_interrupts(|| {
+        STATE.lock().decoder = if wheel { PacketDecoder::with_wheel() } else { PacketDecoder::new() };
+    });
+    interrupts::register_irq(12, interrupt);
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        let at = state.pointer.position();
+        state.drawn = Some(draw_cursor(&mut vga_buffer::WRITER.lock(), state.drawn, at));
+    });
+    // the only failure is being registered by an earlier call already
+    let _ = shell::register_command("mouse", "where the mouse is and its buttons; `mouse live` to watch", mouse_command);
+    Ok(())
+}
+
+/// `mouse`: the cell under the pointer and the buttons held. `mouse live`
+/// keeps them in the top right corner, updated by `task::mouse::show_state`,
+/// until it is run again.
+fn mouse_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    match args {
+        [] => {
+            let (col, row) = position();
+            let wheel = if has_wheel() { ", has a wheel" } else { "" };
+            let _ = writeln!(out, "column {}, row {}, buttons: {}{}", col, row, buttons(), wheel);
+        }
+        ["live"] => {
+            let live = !LIVE.fetch_xor(true, Ordering::Relaxed);
+            if live {
+                show_live(MouseEvent { dx: 0, dy: 0, buttons: buttons(), wheel: 0 });
+            } else {
+                vga_buffer::clear_region(0..1, BUFFER_WIDTH - STATUS_WIDTH..BUFFER_WIDTH);
+            }
+            let _ = writeln!(out, "live mouse state {}", if live { "on" } else { "off" });
+        }
+        _ => return Err(ShellError::Usage("mouse [live]")),
+    }
+    Ok(())
+}
+
+//test case
+#[cfg(test)]
+use alloc::string::ToString;
+
+#[test_case]
+fn test_packet_decoding() {
+    // left down, 5 right and 3 up
+    let event = decode_packet([ALWAYS_SET | BUTTON_LEFT, 5, 3]);
+    assert_eq!(event, MouseEvent { dx: 5, dy: 3, buttons: Buttons { left: true, right: false, middle: false }, wheel: 0 });
+    // negative deltas are 9-bit two's complement
+    let event = decode_packet([ALWAYS_SET | X_SIGN | Y_SIGN | BUTTON_RIGHT | BUTTON_MIDDLE, 0xfe, 0x80]);
+    assert_eq!((event.dx, event.dy), (-2, -128));
+    assert!(event.buttons.right && event.buttons.middle && !event.buttons.left);
+    // an overflowed delta is dropped, the other kept
+    let event = decode_packet([ALWAYS_SET | X_OVERFLOW, 0xff, 7]);
+    assert_eq!((event.dx, event.dy), (0, 7));
+}
+
+#[test_case]
+fn test_decoder_resyncs() {
+    let mut decoder = PacketDecoder::new();
+    // two stray bytes without bit 3 are skipped
+    assert_eq!(decoder.feed(0x01), None);
+    assert_eq!(decoder.feed(0x02), None);
+    assert_eq!(decoder.feed(ALWAYS_SET), None);
+    assert_eq!(decoder.feed(4), None);
+    assert_eq!(decoder.feed(0xfc).map(|event| (event.dx, event.dy)), Some((4, 252)));
+    // the next packet starts right after
+    assert_eq!(decoder.feed(ALWAYS_SET | BUTTON_LEFT), None);
+    assert_eq!(decoder.feed(0), None);
+    assert!(decoder.feed(0).is_some_and(|event| event.buttons.left));
+}
+
+#[test_case]
+fn test_wheel_packets() {
+    let mut decoder = PacketDecoder::with_wheel();
+    assert_eq!(decoder.feed(ALWAYS_SET | BUTTON_MIDDLE), None);
+    assert_eq!(decoder.feed(1), None);
+    assert_eq!(decoder.feed(2), None);
+    // 0x0f is one click towards the user; only the low nibble counts
+    let event = decoder.feed(0xff).unwrap();
+    assert_eq!((event.dx, event.dy, event.wheel), (1, 2, 1));
+    assert!(event.buttons.middle);
+    assert_eq!(decode_wheel_packet([ALWAYS_SET, 0, 0, 0x01]).wheel, -1);
+    assert_eq!(decode_wheel_packet([ALWAYS_SET, 0, 0, 0x08]).wheel, 8);
+    // a 3-byte decoder never waits for a fourth byte
+    let mut decoder = PacketDecoder::new();
+    decoder.feed(ALWAYS_SET);
+    decoder.feed(0);
+    assert_eq!(decoder.feed(0).map(|event| event.wheel), Some(0));
+}
+
+#[test_case]
+fn test_status_line() {
+    let event = MouseEvent { dx: 0, dy: 0, buttons: Buttons { left: true, right: true, middle: false }, wheel: -2 };
+    let status = status_line((41, 3), event);
+    assert_eq!(status.len(), STATUS_WIDTH);
+    assert_eq!(status.trim_start(), "mouse 41,3 L-R wheel -2");
+}
+
+#[test_case]
+fn test_pointer_moves_and_clamps() {
+    let still = |buttons| MouseEvent { dx: 0, dy: 0, buttons, wheel: 0 };
+    let mut pointer = Pointer::new();
+    assert_eq!(pointer.position(), (40, 12));
+    // counts add up until they make a whole cell
+    for _ in 0..7 {
+        pointer.apply(MouseEvent { dx: 1, dy: 0, buttons: Buttons::default(), wheel: 0 });
+    }
+    assert_eq!(pointer.position(), (40, 12));
+    pointer.apply(MouseEvent { dx: 1, dy: -16, buttons: Buttons::default(), wheel: 0 });
+    assert_eq!(pointer.position(), (41, 13));
+    pointer.apply(MouseEvent { dx: i16::MAX, dy: i16::MIN, buttons: Buttons::default(), wheel: 0 });
+    assert_eq!(pointer.position(), (BUFFER_WIDTH - 1, BUFFER_HEIGHT - 1));
+    pointer.apply(MouseEvent { dx: i16::MIN, dy: i16::MAX, buttons: Buttons::default(), wheel: 0 });
+    assert_eq!(pointer.position(), (0, 0));
+
+    let left = Buttons { left: true, ..Buttons::default() };
+    assert_eq!(pointer.apply(still(left)), left);
+    // held, not pressed again
+    assert_eq!(pointer.apply(still(left)), Buttons::default());
+    assert_eq!(pointer.buttons(), left);
+    assert_eq!(pointer.apply(still(Buttons::default())), Buttons::default());
+    assert_eq!(left.to_string(), "left");
+    assert_eq!(Buttons::default().to_string(), "none");
+}
+
+#[test_case]
+fn test_cursor_inverts_and_restores() {
+    let mut writer = vga_buffer::WRITER.lock();
+    let (a, b) = ((3, 1), (4, 1));
+    writer.set_attribute(1, 3, 0x0e);
+    writer.set_attribute(1, 4, 0x1f);
+    let drawn = draw_cursor(&mut writer, None, a);
+    assert_eq!(writer.attribute(1, 3), 0xe0);
+    let drawn = draw_cursor(&mut writer, Some(drawn), b);
+    assert_eq!(writer.attribute(1, 3), 0x0e);
+    assert_eq!(writer.attribute(1, 4), 0xf1);
+    // text written under the cursor keeps its colors when it leaves
+    writer.set_attribute(1, 4, 0x02);
+    draw_cursor(&mut writer, Some(drawn), a);
+    assert_eq!(writer.attribute(1, 4), 0x02);
+    assert_eq!(writer.attribute(1, 3), 0xe0);
+    writer.set_attribute(1, 3, 0x0e);
+}
@@ -0,0 +1,796 @@
+//! Booting from GRUB with Multiboot2, behind the `multiboot2` feature.
+//!
+//! The default path stays the `bootloader` crate. With the feature on, the
+//! kernel also carries a Multiboot2 header asking for the memory map, the
+//! framebuffer and the modules, and `multiboot2_entry!` adds the entry GRUB
+//! jumps to. GRUB enters in 32-bit protected mode with paging off, so the
+//! shim builds its own page tables first:
+//!
+//! - the first GiB identity-mapped, for the kernel image (linked at 2 MiB)
+//!   and the VGA buffer, with the page at 0 left unmapped;
+//! - the first 4 GiB of physical memory at `PHYS_OFFSET`, which takes the
+//!   place of the bootloader's `physical_memory_offset`; memory above
+//!   4 GiB is left out of the memory map.
+//!
+//! It then switches to long mode, turns GRUB's information structure into
+//! a `BootInfo` and calls the same `kernel_main` as the bootloader path.
+//! The tag parser and the memory map adapter are built either way, so the
+//! tests cover them without the feature; the adapter builds a 0.9 map, so
+//! it needs the `bootloader-legacy` loader.
+//!
+//! To boot it, build with `--features multiboot2` and put the kernel ELF in
+//! a GRUB ISO:
+//!
+//! ```text
+//! mkdir -p iso/boot/grub
+//! cp target/x86_64-berryos/debug/tutorial_os iso/boot/berryos
+//! printf 'set timeout=0\nmenuentry berryOS {\n  set gfxpayload=text\n  multiboot2 /boot/berryos\n  boot\n}\n' > iso/boot/grub/grub.cfg
+//! grub-mkrescue -o berryos.iso iso
+//! qemu-system-x86_64 -cdrom berryos.iso -serial stdio
+//! ```
+
+#[cfg(feature = "bootloader-legacy")]
+use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::bytes::{le_u32, le_u64};
+
+#[cfg(test)]
+mod testdata;
+
+/// What the shim gets in EAX from a Multiboot2 loader.
+pub const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+pub const HEADER_MAGIC: u32 = 0xe852_50d6;
+/// Where the shim maps physical memory. PML4 entry 256, away from the heap
+/// and from user space.
+pub const PHYS_OFFSET: u64 = 0xffff_8000_0000_0000;
+/// How much physical memory the shim maps at `PHYS_OFFSET`.
+pub const MAPPED_PHYS_LIMIT: u64 = 4 << 30;
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOTLOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_BASIC_MEMINFO: u32 = 4;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+#[cfg(feature = "bootloader-legacy")]
+const PAGE_SIZE: u64 = 4096;
+#[cfg(feature = "bootloader-legacy")]
+const MAX_REGIONS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Multiboot2Error {
+    /// The loader isn't Multiboot2 (wrong magic in EAX).
+    BadMagic(u32),
+    /// `total_size` is below the minimum or past the buffer.
+    BadSize,
+    /// A tag runs past the structure.
+    BadTag { offset: usize },
+    /// No end tag before the end of the structure.
+    MissingEnd,
+}
+
+impl fmt::Display for Multiboot2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Multiboot2Error::BadMagic(magic) => write!(f, "bad boot magic {:#x}", magic),
+            Multiboot2Error::BadSize => write!(f, "bad information structure size"),
+            Multiboot2Error::BadTag { offset } => write!(f, "malformed tag at offset {:#x}", offset),
+            Multiboot2Error::MissingEnd => write!(f, "no end tag"),
+        }
+    }
+}
+
+// ==========================================================
+// Information structure
+// ==========================================================
+
+/// One tag: its type and what follows the 8-byte tag header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tag<'a> {
+    pub kind: u32,
+    pub body: &'a [u8],
+}
+
+/// Iterates the tags up to the end tag. Each tag starts 8-byte aligned;
+/// the sizes don't include that padding.
+pub struct Tags<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Result<Tag<'a>, Multiboot2Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        if offset >= self.bytes.len() {
+            return None;
+        }
+        let header = le_u32(self.bytes, offset).zip(le_u32(self.bytes, offset + 4));
+        let tag = header.and_then(|(kind, size)| {
+            let body = self.bytes.get(offset + 8..offset.checked_add(size as usize)?)?;
+            Some(Tag { kind, body })
+        });
+        let Some(tag) = tag else {
+            self.offset = self.bytes.len();
+            return Some(Err(Multiboot2Error::BadTag { offset }));
+        };
+        self.offset = if tag.kind == TAG_END {
+            self.bytes.len()
+        } else {
+            (offset + 8 + tag.body.len()).next_multiple_of(8)
+        };
+        Some(Ok(tag))
+    }
+}
+
+/// The information structure GRUB leaves in EBX.
+#[derive(Debug, Clone, Copy)]
+pub struct BootInformation<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> BootInformation<'a> {
+    /// Checks the size and that every tag fits, up to an end tag.
+    pub fn parse(bytes: &'a [u8]) -> Result<BootInformation<'a>, Multiboot2Error> {
+        let total_size = le_u32(bytes, 0).ok_or(Multiboot2Error::BadSize)? as usize;
+        if total_size < 16 || total_size > bytes.len() {
+            return Err(Multiboot2Error::BadSize);
+        }
+        let info = BootInformation { bytes: &bytes[..total_size] };
+        let mut ended = false;
+        for tag in info.tags() {
+            ended = tag?.kind == TAG_END;
+        }
+        if ended { Ok(info) } else { Err(Multiboot2Error::MissingEnd) }
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn tags(&self) -> Tags<'a> {
+        Tags { bytes: self.bytes, offset: 8 }
+    }
+
+    fn tag(&self, kind: u32) -> Option<Tag<'a>> {
+        self.tags().filter_map(Result::ok).find(|tag| tag.kind == kind)
+    }
+
+    /// Offset of the first `kind` tag's body inside the structure.
+    fn tag_offset(&self, kind: u32) -> Option<usize> {
+        let tag = self.tag(kind)?;
+        Some(tag.body.as_ptr() as usize - self.bytes.as_ptr() as usize)
+    }
+
+    pub fn command_line(&self) -> Option<&'a str> {
+        self.tag(TAG_CMDLINE).and_then(|tag| c_str(tag.body))
+    }
+
+    /// The command line without the kernel's path GRUB puts first.
+    pub fn kernel_arguments(&self) -> Option<&'a str> {
+        let line = self.command_line()?.trim_start();
+        match line.split_once(' ') {
+            Some((path, rest)) if path.starts_with('/') => Some(rest.trim_start()),
+            None if line.starts_with('/') => Some(""),
+            _ => Some(line),
+        }
+    }
+
+    pub fn bootloader_name(&self) -> Option<&'a str> {
+        self.tag(TAG_BOOTLOADER_NAME).and_then(|tag| c_str(tag.body))
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = Module<'a>> + 'a {
+        self.tags().filter_map(Result::ok).filter(|tag| tag.kind == TAG_MODULE).filter_map(|tag| {
+            Some(Module {
+                start: le_u32(tag.body, 0)?,
+                end: le_u32(tag.body, 4)?,
+                name: tag.body.get(8..).and_then(c_str).unwrap_or(""),
+            })
+        })
+    }
+
+    /// `mem_lower` and `mem_upper` in KiB: below 1 MiB and from 1 MiB up to
+    /// the first hole.
+    pub fn basic_memory(&self) -> Option<(u32, u32)> {
+        let tag = self.tag(TAG_BASIC_MEMINFO)?;
+        le_u32(tag.body, 0).zip(le_u32(tag.body, 4))
+    }
+
+    pub fn memory_areas(&self) -> Option<MemoryAreas<'a>> {
+        let tag = self.tag(TAG_MMAP)?;
+        let entry_size = le_u32(tag.body, 0)? as usize;
+        // entries are at least base, length and type
+        if entry_size < 20 {
+            return None;
+        }
+        Some(MemoryAreas { entries: tag.body.get(8..)?, entry_size })
+    }
+
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        let tag = self.tag(TAG_FRAMEBUFFER)?;
+        Some(Framebuffer {
+            address: le_u64(tag.body, 0)?,
+            pitch: le_u32(tag.body, 8)?,
+            width: le_u32(tag.body, 12)?,
+            height: le_u32(tag.body, 16)?,
+            bpp: *tag.body.get(20)?,
+            kind: FramebufferKind::from_code(*tag.body.get(21)?),
+        })
+    }
+
+    /// Offset of GRUB's copy of the RSDP in the structure, the ACPI 2.0
+    /// one if present.
+    pub fn rsdp_offset(&self) -> Option<usize> {
+        self.tag_offset(TAG_ACPI_NEW).or_else(|| self.tag_offset(TAG_ACPI_OLD))
+    }
+}
+
+fn c_str(bytes: &[u8]) -> Option<&str> {
+    let len = bytes.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Module<'a> {
+    pub start: u32,
+    /// Exclusive.
+    pub end: u32,
+    /// The module's command line, its path unless the config says more.
+    pub name: &'a str,
+}
+
+/// Type codes of the memory map, the same as E820's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaKind {
+    Available,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Defective,
+    Other(u32),
+}
+
+impl AreaKind {
+    pub fn from_code(code: u32) -> AreaKind {
+        match code {
+            1 => AreaKind::Available,
+            2 => AreaKind::Reserved,
+            3 => AreaKind::AcpiReclaimable,
+            4 => AreaKind::AcpiNvs,
+            5 => AreaKind::Defective,
+            other => AreaKind::Other(other),
+        }
+    }
+
+    #[cfg(feature = "bootloader-legacy")]
+    fn region_type(self) -> MemoryRegionType {
+        match self {
+            AreaKind::Available => MemoryRegionType::Usable,
+            AreaKind::AcpiReclaimable => MemoryRegionType::AcpiReclaimable,
+            AreaKind::AcpiNvs => MemoryRegionType::AcpiNvs,
+            AreaKind::Defective => MemoryRegionType::BadMemory,
+            AreaKind::Reserved | AreaKind::Other(_) => MemoryRegionType::Reserved,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryArea {
+    pub base: u64,
+    pub len: u64,
+    pub kind: AreaKind,
+}
+
+impl MemoryArea {
+    pub fn end(&self) -> u64 {
+        self.base.saturating_add(self.len)
+    }
+}
+
+pub struct MemoryAreas<'a> {
+    entries: &'a [u8],
+    entry_size: usize,
+}
+
+impl Iterator for MemoryAreas<'_> {
+    type Item = MemoryArea;
+
+    fn next(&mut self) -> Option<MemoryArea> {
+        let entry = self.entries.get(..self.entry_size)?;
+        self.entries = &self.entries[self.entry_size..];
+        Some(MemoryArea {
+            base: le_u64(entry, 0)?,
+            len: le_u64(entry, 8)?,
+            kind: AreaKind::from_code(le_u32(entry, 16)?),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramebufferKind {
+    Indexed,
+    Rgb,
+    /// EGA text mode: `width` and `height` are in characters.
+    Text,
+    Other(u8),
+}
+
+impl FramebufferKind {
+    fn from_code(code: u8) -> FramebufferKind {
+        match code {
+            0 => FramebufferKind::Indexed,
+            1 => FramebufferKind::Rgb,
+            2 => FramebufferKind::Text,
+            other => FramebufferKind::Other(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub address: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    pub kind: FramebufferKind,
+}
+
+// ==========================================================
+// BootInfo adapter
+// ==========================================================
+
+/// The region types taken out of available memory, by priority: where
+/// reservations overlap, the first one listed wins.
+#[cfg(feature = "bootloader-legacy")]
+pub fn memory_map(
+    areas: impl Iterator<Item = MemoryArea>,
+    reserved: &[(u64, u64, MemoryRegionType)],
+) -> MemoryMap {
+    let mut map = MemoryMap::new();
+    let mut add = |start: u64, end: u64, region_type| {
+        // the bootloader crate's map holds 64 regions; past that memory
+        // is left out rather than failing the boot
+        if start < end && map.len() < MAX_REGIONS {
+            map.add_region(MemoryRegion { range: FrameRange::new(start, end), region_type });
+        }
+    };
+    for area in areas {
+        let end = area.end().min(MAPPED_PHYS_LIMIT);
+        if area.kind != AreaKind::Available {
+            add(area.base / PAGE_SIZE * PAGE_SIZE, end.next_multiple_of(PAGE_SIZE), area.kind.region_type());
+            continue;
+        }
+        // only whole frames are usable
+        let mut start = area.base.next_multiple_of(PAGE_SIZE);
+        let end = end / PAGE_SIZE * PAGE_SIZE;
+        while start < end {
+            // the first reservation that still overlaps what's left
+            let overlap = reserved
+                .iter()
+                .map(|&(from, to, kind)| (from / PAGE_SIZE * PAGE_SIZE, to.next_multiple_of(PAGE_SIZE), kind))
+                .filter(|&(from, to, _)| from < end && to > start)
+                .min_by_key(|&(from, _, _)| from);
+            match overlap {
+                Some((from, to, kind)) => {
+                    add(start, from.max(start), MemoryRegionType::Usable);
+                    let taken = to.min(end);
+                    add(from.max(start), taken, kind);
+                    start = taken;
+                }
+                None => {
+                    add(start, end, MemoryRegionType::Usable);
+                    start = end;
+                }
+            }
+        }
+    }
+    map
+}
+
+/// Available memory from the basic meminfo tag, for loaders that give no
+/// memory map.
+pub fn basic_memory_areas(lower_kib: u32, upper_kib: u32) -> [MemoryArea; 2] {
+    [
+        MemoryArea { base: 0, len: u64::from(lower_kib) * 1024, kind: AreaKind::Available },
+        MemoryArea { base: 1 << 20, len: u64::from(upper_kib) * 1024, kind: AreaKind::Available },
+    ]
+}
+
+/// Physical address of the RSDP copy GRUB passed, if the kernel came up
+/// through Multiboot2. A hint for `acpi::init` on firmware whose RSDP the
+/// BIOS-area scan can't find.
+static RSDP_HINT: AtomicU64 = AtomicU64::new(0);
+
+pub fn rsdp_hint() -> Option<u64> {
+    match RSDP_HINT.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(addr),
+    }
+}
+
+static FRAMEBUFFER_HINT: spin::Once<Framebuffer> = spin::Once::new();
+
+/// The framebuffer GRUB set up, if the kernel came up through Multiboot2.
+pub fn framebuffer_hint() -> Option<Framebuffer> {
+    FRAMEBUFFER_HINT.get().copied()
+}
+
+static RAMDISK_HINT: spin::Once<(u64, u64)> = spin::Once::new();
+static CMDLINE_HINT: spin::Once<&'static str> = spin::Once::new();
+
+/// The arguments GRUB passed, if the kernel came up through Multiboot2.
+/// They stay in the information structure, whose frames are reserved.
+pub fn cmdline_hint() -> Option<&'static str> {
+    CMDLINE_HINT.get().copied()
+}
+
+/// Physical start and length of the first GRUB module, taken as the
+/// ramdisk.
+pub fn ramdisk_hint() -> Option<(u64, u64)> {
+    RAMDISK_HINT.get().copied()
+}
+
+// ==========================================================
+// Entry shim
+// ==========================================================
+
+#[cfg(feature = "multiboot2")]
+pub use shim::*;
+
+#[cfg(feature = "multiboot2")]
+mod shim {
+    use bootloader::bootinfo::MemoryRegionType;
+    use bootloader::BootInfo;
+    use core::sync::atomic::Ordering;
+    use spin::Once;
+    use x86_64::structures::paging::PageTable;
+    use super::*;
+
+    pub const STACK_SIZE: usize = 64 * 1024;
+
+    #[repr(C, align(16))]
+    #[doc(hidden)]
+    pub struct Stack(pub [u8; STACK_SIZE]);
+
+    // The shim's page tables and stack, in .bss like the rest of the
+    // image.
+    #[doc(hidden)]
+    pub static mut PML4: PageTable = PageTable::new();
+    #[doc(hidden)]
+    pub static mut PDPT_LOW: PageTable = PageTable::new();
+    #[doc(hidden)]
+    pub static mut PD_LOW: PageTable = PageTable::new();
+    #[doc(hidden)]
+    pub static mut PT_LOW: PageTable = PageTable::new();
+    #[doc(hidden)]
+    pub static mut PDPT_HIGH: PageTable = PageTable::new();
+    #[doc(hidden)]
+    pub static mut PD_HIGH: [PageTable; 4] = [const { PageTable::new() }; 4];
+    #[doc(hidden)]
+    pub static mut STACK: Stack = Stack([0; STACK_SIZE]);
+
+    static BOOT_INFO: Once<BootInfo> = Once::new();
+
+    extern "C" {
+        // Provided by the linker.
+        static __ehdr_start: u8;
+        static _end: u8;
+    }
+
+    fn fail(err: Multiboot2Error) -> ! {
+        crate::debugcon::init();
+        crate::debugcon_println!("multiboot2: {}", err);
+        crate::hlt_loop();
+    }
+
+    /// Turns GRUB's information structure into the `BootInfo` the
+    /// bootloader would have passed. Runs once, from the entry shim.
+    pub fn boot_info(info_addr: u64, magic: u32) -> &'static BootInfo {
+        if magic != BOOTLOADER_MAGIC {
+            fail(Multiboot2Error::BadMagic(magic));
+        }
+        let virt = PHYS_OFFSET + info_addr;
+        let total_size = unsafe { (virt as *const u32).read() } as usize;
+        let bytes: &'static [u8] = unsafe { core::slice::from_raw_parts(virt as *const u8, total_size) };
+        let info = BootInformation::parse(bytes).unwrap_or_else(|err| fail(err));
+
+        let kernel = ((&raw const __ehdr_start) as u64, (&raw const _end) as u64);
+        let info_range = (info_addr, info_addr + total_size as u64);
+        let mut reserved = [(0, 0, MemoryRegionType::Empty); 16];
+        reserved[0] = (0, PAGE_SIZE, MemoryRegionType::FrameZero);
+        reserved[1] = (kernel.0, kernel.1, MemoryRegionType::Kernel);
+        reserved[2] = (info_range.0, info_range.1, MemoryRegionType::BootInfo);
+        let mut count = 3;
+        for module in info.modules().take(reserved.len() - count) {
+            reserved[count] = (u64::from(module.start), u64::from(module.end), MemoryRegionType::Package);
+            count += 1;
+        }
+        let memory_map = match (info.memory_areas(), info.basic_memory()) {
+            (Some(areas), _) => memory_map(areas, &reserved[..count]),
+            (None, Some((lower, upper))) => memory_map(basic_memory_areas(lower, upper).into_iter(), &reserved[..count]),
+            (None, None) => memory_map(core::iter::empty(), &[]),
+        };
+        if let Some(offset) = info.rsdp_offset() {
+            RSDP_HINT.store(info_addr + offset as u64, Ordering::Relaxed);
+        }
+        if let Some(framebuffer) = info.framebuffer() {
+            FRAMEBUFFER_HINT.call_once(|| framebuffer);
+        }
+        if let Some(arguments) = info.kernel_arguments() {
+            CMDLINE_HINT.call_once(|| arguments);
+        }
+        if let Some(module) = info.modules().next() {
+            let start = u64::from(module.start);
+            RAMDISK_HINT.call_once(|| (start, u64::from(module.end).saturating_sub(start)));
+        }
+        BOOT_INFO.call_once(|| BootInfo::new(memory_map, None, 0, PHYS_OFFSET))
+    }
+}
+
+/// Adds the Multiboot2 header and entry shim that call `$path`, the same
+/// `fn(&'static boot::BootInfo) -> !` that `entry_point!` takes.
+#[cfg(feature = "multiboot2")]
+#[macro_export]
+macro_rules! multiboot2_entry {
+    ($path:path) => {
+        #[no_mangle]
+        extern "C" fn multiboot2_main(info_addr: u64, magic: u32) -> ! {
+            let f: fn(&'static $crate::boot::BootInfo) -> ! = $path;
+            f($crate::boot::from_legacy($crate::multiboot2::boot_info(info_addr, magic)))
+        }
+
+        core::arch::global_asm!(
+            ".section .multiboot2, \"a\"",
+            ".balign 8",
+            "2:",
+            ".long {magic}",
+            ".long 0",
+            ".long 3f - 2b",
+            ".long 0x100000000 - ({magic} + (3f - 2b))",
+            // information request: modules, memory map, framebuffer
+            ".short 1, 0",
+            ".long 20",
+            ".long 3, 6, 8",
+            ".balign 8",
+            // entry address
+            ".short 3, 0",
+            ".long 12",
+            ".long multiboot2_start",
+            ".balign 8",
+            // framebuffer, optional, no preferred mode
+            ".short 5, 1",
+            ".long 20",
+            ".long 0, 0, 0",
+            ".balign 8",
+            ".short 0, 0",
+            ".long 8",
+            "3:",
+
+            ".section .rodata.multiboot2_gdt, \"a\"",
+            ".balign 8",
+            "multiboot2_gdt:",
+            ".quad 0",
+            // 64-bit ring 0 code
+            ".quad 0x00af9a000000ffff",
+            "multiboot2_gdt_pointer:",
+            ".short 15",
+            ".quad multiboot2_gdt",
+
+            ".section .text.multiboot2_start, \"ax\"",
+            ".code32",
+            ".global multiboot2_start",
+            "multiboot2_start:",
+            "cli",
+            "cld",
+            "mov edi, eax",
+            "lea esp, [{stack} + {stack_size}]",
+            // PML4: the first GiB identity-mapped, all of the first 4 GiB
+            // at PHYS_OFFSET
+            "mov eax, offset {pdpt_low}",
+            "or eax, 3",
+            "mov [{pml4}], eax",
+            "mov eax, offset {pdpt_high}",
+            "or eax, 3",
+            "mov [{pml4} + 256 * 8], eax",
+            "mov eax, offset {pd_low}",
+            "or eax, 3",
+            "mov [{pdpt_low}], eax",
+            "mov eax, offset {pt_low}",
+            "or eax, 3",
+            "mov [{pd_low}], eax",
+            // 4 KiB pages below 2 MiB, all but the page at 0
+            "mov ecx, 1",
+            "4:",
+            "mov eax, ecx",
+            "shl eax, 12",
+            "or eax, 3",
+            "mov [{pt_low} + ecx * 8], eax",
+            "inc ecx",
+            "cmp ecx, 512",
+            "jne 4b",
+            // 2 MiB pages for the rest of the first GiB
+            "mov ecx, 1",
+            "5:",
+            "mov eax, ecx",
+            "shl eax, 21",
+            "or eax, 0x83",
+            "mov [{pd_low} + ecx * 8], eax",
+            "inc ecx",
+            "cmp ecx, 512",
+            "jne 5b",
+            // the four high page directories, then 2048 pages of 2 MiB
+            "xor ecx, ecx",
+            "6:",
+            "mov eax, ecx",
+            "shl eax, 12",
+            "add eax, offset {pd_high}",
+            "or eax, 3",
+            "mov [{pdpt_high} + ecx * 8], eax",
+            "inc ecx",
+            "cmp ecx, 4",
+            "jne 6b",
+            "xor ecx, ecx",
+            "7:",
+            "mov eax, ecx",
+            "shl eax, 21",
+            "or eax, 0x83",
+            "mov [{pd_high} + ecx * 8], eax",
+            "inc ecx",
+            "cmp ecx, 2048",
+            "jne 7b",
+            "mov eax, offset {pml4}",
+            "mov cr3, eax",
+            // PAE, then long mode and no-execute in EFER, then paging and
+            // write protection
+            "mov eax, cr4",
+            "or eax, 1 << 5",
+            "mov cr4, eax",
+            "mov ecx, 0xc0000080",
+            "rdmsr",
+            "or eax, (1 << 8) | (1 << 11)",
+            "wrmsr",
+            "mov eax, cr0",
+            "or eax, 0x80010001",
+            "mov cr0, eax",
+            "lgdt [multiboot2_gdt_pointer]",
+            "mov eax, offset multiboot2_long_mode",
+            "push 0x08",
+            "push eax",
+            "retf",
+
+            ".code64",
+            "multiboot2_long_mode:",
+            "xor eax, eax",
+            "mov ds, ax",
+            "mov es, ax",
+            "mov ss, ax",
+            "mov fs, ax",
+            "mov gs, ax",
+            "mov esi, edi",
+            "mov edi, ebx",
+            "lea rsp, [rip + {stack} + {stack_size}]",
+            "xor ebp, ebp",
+            "call multiboot2_main",
+            "ud2",
+            magic = const $crate::multiboot2::HEADER_MAGIC,
+            stack = sym $crate::multiboot2::STACK,
+            stack_size = const $crate::multiboot2::STACK_SIZE,
+            pml4 = sym $crate::multiboot2::PML4,
+            pdpt_low = sym $crate::multiboot2::PDPT_LOW,
+            pd_low = sym $crate::multiboot2::PD_LOW,
+            pt_low = sym $crate::multiboot2::PT_LOW,
+            pdpt_high = sym $crate::multiboot2::PDPT_HIGH,
+            pd_high = sym $crate::multiboot2::PD_HIGH,
+        );
+    };
+}
+
+//test case
+#[test_case]
+fn test_tag_walk() {
+    let info = BootInformation::parse(&testdata::INFO).unwrap();
+    assert_eq!(info.total_size(), testdata::INFO.len());
+    let kinds: alloc::vec::Vec<u32> = info.tags().map(|tag| tag.unwrap().kind).collect();
+    assert_eq!(kinds, [1, 2, 3, 4, 5, 6, 8, 14, 21, 0]);
+    // every tag starts 8-byte aligned, whatever its size
+    for tag in info.tags() {
+        let offset = tag.unwrap().body.as_ptr() as usize - testdata::INFO.as_ptr() as usize;
+        assert_eq!((offset - 8) % 8, 0);
+    }
+}
+
+#[test_case]
+fn test_grub_tags() {
+    let info = BootInformation::parse(&testdata::INFO).unwrap();
+    assert_eq!(info.command_line(), Some("/boot/berryos loglevel=debug"));
+    assert_eq!(info.kernel_arguments(), Some("loglevel=debug"));
+    assert_eq!(info.bootloader_name(), Some("GRUB 2.12"));
+    let modules: alloc::vec::Vec<Module> = info.modules().collect();
+    assert_eq!(modules, [Module { start: 0x10b000, end: 0x10c234, name: "/boot/initrd.tar" }]);
+    assert_eq!(info.basic_memory(), Some((639, 129920)));
+    let framebuffer = info.framebuffer().unwrap();
+    assert_eq!(framebuffer.kind, FramebufferKind::Text);
+    assert_eq!((framebuffer.address, framebuffer.width, framebuffer.height), (0xb8000, 80, 25));
+    let rsdp = info.rsdp_offset().unwrap();
+    assert_eq!(&testdata::INFO[rsdp..rsdp + 8], b"RSD PTR ");
+
+    let areas: alloc::vec::Vec<MemoryArea> = info.memory_areas().unwrap().collect();
+    assert_eq!(areas.len(), 8);
+    assert_eq!(areas[0], MemoryArea { base: 0, len: 0x9fc00, kind: AreaKind::Available });
+    assert_eq!(areas[3], MemoryArea { base: 0x10_0000, len: 0x7ee_0000, kind: AreaKind::Available });
+    assert_eq!(areas[6].kind, AreaKind::Reserved);
+}
+
+#[test_case]
+fn test_malformed_info() {
+    assert_eq!(BootInformation::parse(&testdata::INFO[..4]).err(), Some(Multiboot2Error::BadSize));
+    // total_size claims more than there is
+    assert_eq!(BootInformation::parse(&testdata::INFO[..100]).err(), Some(Multiboot2Error::BadSize));
+    // a tag whose size runs past the end
+    let mut bytes = testdata::INFO;
+    bytes[12..16].copy_from_slice(&0x1000u32.to_le_bytes());
+    assert_eq!(BootInformation::parse(&bytes).err(), Some(Multiboot2Error::BadTag { offset: 8 }));
+    // cut before the end tag, with total_size to match
+    let mut bytes = testdata::INFO;
+    let cut = bytes.len() - 8;
+    bytes[..4].copy_from_slice(&(cut as u32).to_le_bytes());
+    assert_eq!(BootInformation::parse(&bytes[..cut]).err(), Some(Multiboot2Error::MissingEnd));
+}
+
+#[cfg(feature = "bootloader-legacy")]
+#[test_case]
+fn test_memory_map_adapter() {
+    let info = BootInformation::parse(&testdata::INFO).unwrap();
+    let reserved = [
+        (0, PAGE_SIZE, MemoryRegionType::FrameZero),
+        (0x20_0000, 0x3c_5123, MemoryRegionType::Kernel),
+        (0x10_d000, 0x10_d1c0, MemoryRegionType::BootInfo),
+        (0x10_b000, 0x10_c234, MemoryRegionType::Package),
+    ];
+    let map = memory_map(info.memory_areas().unwrap(), &reserved);
+    let regions: alloc::vec::Vec<(u64, u64, MemoryRegionType)> = map
+        .iter()
+        .map(|region| (region.range.start_addr(), region.range.end_addr(), region.region_type))
+        .collect();
+    use MemoryRegionType::*;
+    assert_eq!(
+        regions,
+        [
+            (0, 0x1000, FrameZero),
+            (0x1000, 0x9f000, Usable),
+            // the partial frame at the end of low memory is dropped
+            (0x9f000, 0xa0000, Reserved),
+            (0xf0000, 0x100000, Reserved),
+            (0x100000, 0x10b000, Usable),
+            (0x10b000, 0x10d000, Package),
+            (0x10d000, 0x10e000, BootInfo),
+            (0x10e000, 0x200000, Usable),
+            (0x200000, 0x3c6000, Kernel),
+            (0x3c6000, 0x7fe0000, Usable),
+            (0x7fe0000, 0x8000000, Reserved),
+            (0xfeffc000, 0xff000000, Reserved),
+            (0xfffc0000, 0x100000000, Reserved),
+        ]
+    );
+}
+
+#[cfg(feature = "bootloader-legacy")]
+#[test_case]
+fn test_basic_memory_fallback() {
+    let map = memory_map(basic_memory_areas(639, 129920).into_iter(), &[(0, PAGE_SIZE, MemoryRegionType::FrameZero)]);
+    let usable: u64 = map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .map(|region| region.range.end_addr() - region.range.start_addr())
+        .sum();
+    // 639 KiB rounds down to 159 frames, one of them frame zero
+    assert_eq!(usable, 158 * PAGE_SIZE + 129920 * 1024);
+}
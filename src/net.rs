@@ -0,0 +1,756 @@
+//! A very small IPv4 stack: ARP, ICMP echo in both directions, UDP with
+//! an echo service on port 7, and a DHCP client, for a single interface
+//! on top of the RTL8139, an e1000 or a virtio-net device. The address is the
+//! static `DEFAULT_CONFIG` until `dhcp` asks for one.
+//!
+//! Frame handling (`Interface::handle_frame`) is pure: bytes in, optional
+//! reply frame out. `poll` is the glue that feeds it from the NIC.
+
+pub mod arp;
+pub mod dhcp;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod udp;
+#[cfg(test)]
+mod testdata;
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::rtl8139::{self, MacAddr, TxError};
+use crate::{e1000, virtio};
+use crate::{println, time};
+use self::arp::{ArpCache, ArpPacket};
+use self::icmp::{Echo, EchoKind};
+pub use self::ipv4::Ipv4Addr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+}
+
+impl Config {
+    /// The subnet's broadcast address.
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr((self.ip.to_u32() | !self.netmask.to_u32()).to_be_bytes())
+    }
+}
+
+/// QEMU user-mode networking (slirp) hands out these addresses.
+pub const DEFAULT_CONFIG: Config = Config {
+    ip: Ipv4Addr([10, 0, 2, 15]),
+    netmask: Ipv4Addr([255, 255, 255, 0]),
+    gateway: Ipv4Addr([10, 0, 2, 2]),
+};
+
+const TIMEOUT_TICKS: u64 = time::secs_to_ticks(2);
+const PING_DATA: &[u8] = b"berryOS ping payload 0123456789abcdef";
+
+/// Datagrams to it are sent straight back (RFC 862).
+pub const ECHO_PORT: u16 = 7;
+/// Where the local ports `bind_ephemeral` hands out start.
+const EPHEMERAL_PORTS: u16 = 49152;
+/// Datagrams kept for bound ports until someone takes them.
+const UDP_QUEUE_LIMIT: usize = 16;
+
+/// The driver the interface sends and receives through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nic {
+    Rtl8139,
+    E1000,
+    VirtioNet,
+}
+
+impl Nic {
+    pub fn send(self, frame: &[u8]) -> Result<(), TxError> {
+        match self {
+            Nic::Rtl8139 => rtl8139::send(frame),
+            Nic::E1000 => e1000::send(frame),
+            Nic::VirtioNet => virtio::net::send(frame),
+        }
+    }
+
+    pub fn receive(self) -> Option<Vec<u8>> {
+        match self {
+            Nic::Rtl8139 => rtl8139::NIC.lock().as_mut()?.receive(),
+            Nic::E1000 => e1000::NIC.lock().as_mut()?.receive(),
+            Nic::VirtioNet => virtio::net::receive(),
+        }
+    }
+}
+
+impl fmt::Display for Nic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Nic::Rtl8139 => write!(f, "RTL8139"),
+            Nic::E1000 => write!(f, "e1000"),
+            Nic::VirtioNet => write!(f, "virtio-net"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetStats {
+    pub arp: u64,
+    pub ipv4: u64,
+    pub icmp: u64,
+    pub udp: u64,
+    /// Frames for other protocols, or not addressed to us.
+    pub dropped: u64,
+    /// Truncated frames and bad checksums.
+    pub errors: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EchoReply {
+    pub from: Ipv4Addr,
+    pub ident: u16,
+    pub seq: u16,
+    pub received: u64,
+}
+
+/// A datagram to a bound port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpMessage {
+    pub from: Ipv4Addr,
+    pub from_port: u16,
+    pub port: u16,
+    pub data: Vec<u8>,
+    pub received: u64,
+}
+
+pub struct Interface {
+    mac: [u8; 6],
+    config: Config,
+    arp_cache: ArpCache,
+    stats: NetStats,
+    last_echo_reply: Option<EchoReply>,
+    next_ip_ident: u16,
+    udp_ports: Vec<u16>,
+    udp_queue: VecDeque<UdpMessage>,
+    next_ephemeral_port: u16,
+}
+
+impl Interface {
+    pub fn new(mac: [u8; 6], config: Config) -> Interface {
+        Interface {
+            mac,
+            config,
+            arp_cache: ArpCache::default(),
+            stats: NetStats::default(),
+            last_echo_reply: None,
+            next_ip_ident: 1,
+            udp_ports: Vec::new(),
+            udp_queue: VecDeque::new(),
+            next_ephemeral_port: EPHEMERAL_PORTS,
+        }
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    pub fn stats(&self) -> NetStats {
+        self.stats
+    }
+
+    pub fn arp_cache(&self) -> &ArpCache {
+        &self.arp_cache
+    }
+
+    /// Processes one received frame and returns the frame to send back, if
+    /// any. `now` timestamps ARP entries and echo replies.
+    pub fn handle_frame(&mut self, bytes: &[u8], now: u64) -> Option<Vec<u8>> {
+        let frame = match ethernet::parse(bytes) {
+            Some(frame) => frame,
+            None => {
+                self.stats.errors += 1;
+                return None;
+            }
+        };
+        if frame.dst != self.mac && frame.dst != ethernet::BROADCAST {
+            self.stats.dropped += 1;
+            return None;
+        }
+        match frame.ethertype {
+            ethernet::ETHERTYPE_ARP => {
+                self.stats.arp += 1;
+                self.handle_arp(frame.payload, now)
+            }
+            ethernet::ETHERTYPE_IPV4 => {
+                self.stats.ipv4 += 1;
+                self.handle_ipv4(frame.src, frame.payload, now)
+            }
+            _ => {
+                self.stats.dropped += 1;
+                None
+            }
+        }
+    }
+
+    fn handle_arp(&mut self, payload: &[u8], now: u64) -> Option<Vec<u8>> {
+        let packet = match ArpPacket::parse(payload) {
+            Some(packet) => packet,
+            None => {
+                self.stats.errors += 1;
+                return None;
+            }
+        };
+        if packet.target_ip != self.config.ip {
+            return None;
+        }
+        self.arp_cache.insert(packet.sender_ip, packet.sender_mac, now);
+        if packet.op != arp::OP_REQUEST {
+            return None;
+        }
+        let reply = ArpPacket {
+            op: arp::OP_REPLY,
+            sender_mac: self.mac,
+            sender_ip: self.config.ip,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        Some(ethernet::build(packet.sender_mac, self.mac, ethernet::ETHERTYPE_ARP, &reply.to_bytes()))
+    }
+
+    fn handle_ipv4(&mut self, src_mac: [u8; 6], payload: &[u8], now: u64) -> Option<Vec<u8>> {
+        let packet = match ipv4::parse(payload) {
+            Ok(packet) => packet,
+            Err(ipv4::Ipv4Error::Fragmented) => {
+                self.stats.dropped += 1;
+                return None;
+            }
+            Err(_) => {
+                self.stats.errors += 1;
+                return None;
+            }
+        };
+        let for_us = packet.dst == self.config.ip;
+        let broadcast = packet.dst == Ipv4Addr::BROADCAST || packet.dst == self.config.broadcast();
+        match packet.protocol {
+            ipv4::PROTOCOL_ICMP if for_us => {
+                self.stats.icmp += 1;
+                self.handle_icmp(src_mac, &packet, now)
+            }
+            ipv4::PROTOCOL_UDP if for_us || broadcast => {
+                self.stats.udp += 1;
+                self.handle_udp(src_mac, &packet, now)
+            }
+            _ => {
+                self.stats.dropped += 1;
+                None
+            }
+        }
+    }
+
+    fn handle_icmp(&mut self, src_mac: [u8; 6], packet: &ipv4::Packet, now: u64) -> Option<Vec<u8>> {
+        let echo = match icmp::parse(packet.payload) {
+            Ok(echo) => echo,
+            Err(icmp::IcmpError::Unsupported(..)) => {
+                self.stats.dropped += 1;
+                return None;
+            }
+            Err(_) => {
+                self.stats.errors += 1;
+                return None;
+            }
+        };
+        match echo.kind {
+            EchoKind::Request => {
+                let reply = icmp::build(&Echo { kind: EchoKind::Reply, ..echo });
+                Some(self.ipv4_frame(src_mac, packet.src, ipv4::PROTOCOL_ICMP, &reply))
+            }
+            EchoKind::Reply => {
+                self.last_echo_reply = Some(EchoReply {
+                    from: packet.src,
+                    ident: echo.ident,
+                    seq: echo.seq,
+                    received: now,
+                });
+                None
+            }
+        }
+    }
+
+    /// Echoes datagrams to `ECHO_PORT` and queues those to bound ports.
+    fn handle_udp(&mut self, src_mac: [u8; 6], packet: &ipv4::Packet, now: u64) -> Option<Vec<u8>> {
+        let datagram = match udp::parse(packet.src, packet.dst, packet.payload) {
+            Ok(datagram) => datagram,
+            Err(_) => {
+                self.stats.errors += 1;
+                return None;
+            }
+        };
+        if datagram.dst_port == ECHO_PORT && packet.dst == self.config.ip {
+            let reply = udp::build(self.config.ip, packet.src, ECHO_PORT, datagram.src_port, datagram.payload);
+            return Some(self.ipv4_frame(src_mac, packet.src, ipv4::PROTOCOL_UDP, &reply));
+        }
+        if !self.udp_ports.contains(&datagram.dst_port) || self.udp_queue.len() >= UDP_QUEUE_LIMIT {
+            self.stats.dropped += 1;
+            return None;
+        }
+        self.udp_queue.push_back(UdpMessage {
+            from: packet.src,
+            from_port: datagram.src_port,
+            port: datagram.dst_port,
+            data: datagram.payload.to_vec(),
+            received: now,
+        });
+        None
+    }
+
+    fn ipv4_frame(&mut self, dst_mac: [u8; 6], dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let ident = self.next_ip_ident;
+        self.next_ip_ident = ident.wrapping_add(1);
+        let packet = ipv4::build(self.config.ip, dst, protocol, ident, payload);
+        ethernet::build(dst_mac, self.mac, ethernet::ETHERTYPE_IPV4, &packet)
+    }
+
+    /// The address whose MAC we need to reach `dst`: itself on the local
+    /// subnet, the gateway otherwise.
+    pub fn next_hop(&self, dst: Ipv4Addr) -> Ipv4Addr {
+        if dst.same_subnet(self.config.ip, self.config.netmask) {
+            dst
+        } else {
+            self.config.gateway
+        }
+    }
+
+    pub fn arp_request(&self, ip: Ipv4Addr) -> Vec<u8> {
+        let request = ArpPacket {
+            op: arp::OP_REQUEST,
+            sender_mac: self.mac,
+            sender_ip: self.config.ip,
+            target_mac: [0; 6],
+            target_ip: ip,
+        };
+        ethernet::build(ethernet::BROADCAST, self.mac, ethernet::ETHERTYPE_ARP, &request.to_bytes())
+    }
+
+    pub fn echo_request(&mut self, dst_mac: [u8; 6], dst: Ipv4Addr, ident: u16, seq: u16) -> Vec<u8> {
+        let message = icmp::build(&Echo { kind: EchoKind::Request, ident, seq, data: PING_DATA });
+        self.ipv4_frame(dst_mac, dst, ipv4::PROTOCOL_ICMP, &message)
+    }
+
+    pub fn udp_frame(&mut self, dst_mac: [u8; 6], dst: Ipv4Addr, src_port: u16, dst_port: u16, data: &[u8]) -> Vec<u8> {
+        let segment = udp::build(self.config.ip, dst, src_port, dst_port, data);
+        self.ipv4_frame(dst_mac, dst, ipv4::PROTOCOL_UDP, &segment)
+    }
+
+    /// A DHCP client's datagram: from 0.0.0.0 to everyone, as the interface
+    /// may not have an address yet.
+    pub fn dhcp_frame(&mut self, message: &[u8]) -> Vec<u8> {
+        let (src, dst) = (Ipv4Addr::UNSPECIFIED, Ipv4Addr::BROADCAST);
+        let segment = udp::build(src, dst, dhcp::CLIENT_PORT, dhcp::SERVER_PORT, message);
+        let ident = self.next_ip_ident;
+        self.next_ip_ident = ident.wrapping_add(1);
+        let packet = ipv4::build(src, dst, ipv4::PROTOCOL_UDP, ident, &segment);
+        ethernet::build(ethernet::BROADCAST, self.mac, ethernet::ETHERTYPE_IPV4, &packet)
+    }
+
+    /// Keeps datagrams to `port` for `take_udp`. `false` if it is already
+    /// bound, or is the echo port.
+    pub fn bind_udp(&mut self, port: u16) -> bool {
+        if port == ECHO_PORT || self.udp_ports.contains(&port) {
+            return false;
+        }
+        self.udp_ports.push(port);
+        true
+    }
+
+    /// Binds the next free port from 49152 up and returns it.
+    pub fn bind_ephemeral(&mut self) -> u16 {
+        loop {
+            let port = self.next_ephemeral_port;
+            self.next_ephemeral_port = port.checked_add(1).unwrap_or(EPHEMERAL_PORTS);
+            if self.bind_udp(port) {
+                return port;
+            }
+        }
+    }
+
+    /// Drops the binding and whatever is still queued for it.
+    pub fn unbind_udp(&mut self, port: u16) {
+        self.udp_ports.retain(|&bound| bound != port);
+        self.udp_queue.retain(|message| message.port != port);
+    }
+
+    /// The oldest datagram queued for `port`.
+    pub fn take_udp(&mut self, port: u16) -> Option<UdpMessage> {
+        let index = self.udp_queue.iter().position(|message| message.port == port)?;
+        self.udp_queue.remove(index)
+    }
+
+    /// Returns the last echo reply if it answers `(ident, seq)`.
+    pub fn take_echo_reply(&mut self, ident: u16, seq: u16) -> Option<EchoReply> {
+        match self.last_echo_reply {
+            Some(reply) if reply.ident == ident && reply.seq == seq => self.last_echo_reply.take(),
+            _ => None,
+        }
+    }
+}
+
+pub static INTERFACE: Mutex<Option<Interface>> = Mutex::new(None);
+static DRIVER: Mutex<Option<Nic>> = Mutex::new(None);
+
+/// Brings up the interface on `nic`, whose MAC address is `mac`.
+pub fn init(nic: Nic, mac: [u8; 6], config: Config) {
+    interrupts::without_interrupts(|| {
+        *INTERFACE.lock() = Some(Interface::new(mac, config));
+        *DRIVER.lock() = Some(nic);
+    });
+}
+
+fn driver() -> Option<Nic> {
+    interrupts::without_interrupts(|| *DRIVER.lock())
+}
+
+fn send(frame: &[u8]) -> Result<(), NetError> {
+    driver().ok_or(NetError::NoInterface)?.send(frame).map_err(NetError::Tx)
+}
+
+/// Runs `f` on the interface with interrupts off, so an interrupt handler
+/// that runs shell commands (the virtio console's) can never find the lock
+/// held.
+fn with_interface<T>(f: impl FnOnce(&mut Interface) -> T) -> Option<T> {
+    interrupts::without_interrupts(|| INTERFACE.lock().as_mut().map(f))
+}
+
+/// Drains the NIC receive queue through the interface, sending any replies.
+/// Returns the number of frames processed.
+pub fn poll() -> usize {
+    interrupts::without_interrupts(|| {
+        let mut interface = INTERFACE.lock();
+        let (Some(interface), Some(nic)) = (interface.as_mut(), driver()) else {
+            return 0;
+        };
+        let mut handled = 0;
+        while let Some(frame) = nic.receive() {
+            if let Some(reply) = interface.handle_frame(&frame, time::ticks()) {
+                // a lost reply is no worse than a lost request
+                let _ = nic.send(&reply);
+            }
+            handled += 1;
+        }
+        handled
+    })
+}
+
+/// Polls until `check` succeeds or `TIMEOUT_TICKS` pass. Halts between
+/// polls with interrupts enabled so the tick counter keeps moving, even when
+/// called from an interrupt handler (the timer IRQ outranks every other IRQ
+/// on the PIC, so it is still delivered); the interrupt flag is restored on
+/// the way out.
+fn wait_for<T>(mut check: impl FnMut() -> Option<T>) -> Option<T> {
+    let start = time::ticks();
+    let were_enabled = interrupts::are_enabled();
+    let result = loop {
+        poll();
+        if let Some(value) = check() {
+            break Some(value);
+        }
+        if time::ticks() - start >= TIMEOUT_TICKS {
+            break None;
+        }
+        interrupts::enable_and_hlt();
+    };
+    if !were_enabled {
+        interrupts::disable();
+    }
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    NoInterface,
+    /// Nobody answered the ARP request for the next hop.
+    Unreachable(Ipv4Addr),
+    Tx(TxError),
+    /// No DHCP server answered.
+    NoDhcpServer,
+    /// The server took back the address it offered.
+    DhcpDeclined(Ipv4Addr),
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::NoInterface => write!(f, "network is not configured"),
+            NetError::Unreachable(ip) => write!(f, "no ARP reply from {}", ip),
+            NetError::Tx(err) => write!(f, "send failed: {}", err),
+            NetError::NoDhcpServer => write!(f, "no answer from a DHCP server"),
+            NetError::DhcpDeclined(ip) => write!(f, "the DHCP server declined {}", ip),
+        }
+    }
+}
+
+/// MAC address for `ip`, asking with ARP if it is not cached.
+pub fn resolve(ip: Ipv4Addr) -> Result<[u8; 6], NetError> {
+    let (hop, cached, request) = with_interface(|interface| {
+        let hop = interface.next_hop(ip);
+        (hop, interface.arp_cache.lookup(hop), interface.arp_request(hop))
+    })
+    .ok_or(NetError::NoInterface)?;
+    if let Some(mac) = cached {
+        return Ok(mac);
+    }
+    send(&request)?;
+    wait_for(|| with_interface(|interface| interface.arp_cache.lookup(hop)).flatten())
+        .ok_or(NetError::Unreachable(hop))
+}
+
+/// Sends `count` echo requests to `ip`, writing each reply's round trip in
+/// timer ticks to `out`.
+pub fn ping(ip: Ipv4Addr, count: u16, out: &mut impl fmt::Write) -> Result<(), NetError> {
+    // the main loop can't pet the watchdog while we wait
+    let _watchdog = crate::watchdog::suspend();
+    static NEXT_IDENT: Mutex<u16> = Mutex::new(0x4242);
+    let ident = interrupts::without_interrupts(|| {
+        let mut next = NEXT_IDENT.lock();
+        *next = next.wrapping_add(1);
+        *next
+    });
+
+    let mac = resolve(ip)?;
+    let mut received = 0;
+    for seq in 1..=count {
+        let request = with_interface(|interface| interface.echo_request(mac, ip, ident, seq))
+            .ok_or(NetError::NoInterface)?;
+        let sent = time::ticks();
+        send(&request)?;
+        match wait_for(|| with_interface(|interface| interface.take_echo_reply(ident, seq)).flatten()) {
+            Some(reply) => {
+                received += 1;
+                let _ = writeln!(out, "reply from {}: seq={} time={} ticks", reply.from, seq, reply.received - sent);
+            }
+            None => {
+                let _ = writeln!(out, "request timed out: seq={}", seq);
+            }
+        }
+    }
+    let _ = writeln!(out, "{} sent, {} received", count, received);
+    Ok(())
+}
+
+/// Sends `data` from an ephemeral port to `ip:port` and waits for the
+/// first datagram back; `None` if none came.
+pub fn udp_exchange(ip: Ipv4Addr, port: u16, data: &[u8]) -> Result<Option<UdpMessage>, NetError> {
+    let _watchdog = crate::watchdog::suspend();
+    let local = with_interface(Interface::bind_ephemeral).ok_or(NetError::NoInterface)?;
+    let result = resolve(ip).and_then(|mac| {
+        let frame = with_interface(|interface| interface.udp_frame(mac, ip, local, port, data))
+            .ok_or(NetError::NoInterface)?;
+        send(&frame)?;
+        Ok(wait_for(|| with_interface(|interface| interface.take_udp(local)).flatten()))
+    });
+    with_interface(|interface| interface.unbind_udp(local));
+    result
+}
+
+/// Asks a DHCP server for an address and moves the interface to it.
+pub fn dhcp() -> Result<Config, NetError> {
+    let _watchdog = crate::watchdog::suspend();
+    let xid = crate::rng::u64() as u32;
+    let mac = with_interface(|interface| {
+        interface.bind_udp(dhcp::CLIENT_PORT);
+        interface.mac
+    })
+    .ok_or(NetError::NoInterface)?;
+    let result = dhcp_exchange(xid, mac);
+    with_interface(|interface| {
+        interface.unbind_udp(dhcp::CLIENT_PORT);
+        if let Ok(config) = result {
+            interface.set_config(config);
+        }
+    });
+    result
+}
+
+/// DISCOVER, OFFER, REQUEST, ACK.
+fn dhcp_exchange(xid: u32, mac: [u8; 6]) -> Result<Config, NetError> {
+    let discover = with_interface(|interface| interface.dhcp_frame(&dhcp::discover(xid, mac)))
+        .ok_or(NetError::NoInterface)?;
+    send(&discover)?;
+    let offer = wait_for(|| dhcp_reply(xid, &[dhcp::OFFER])).ok_or(NetError::NoDhcpServer)?;
+    let request = with_interface(|interface| interface.dhcp_frame(&dhcp::request(xid, mac, &offer)))
+        .ok_or(NetError::NoInterface)?;
+    send(&request)?;
+    let ack = wait_for(|| dhcp_reply(xid, &[dhcp::ACK, dhcp::NAK])).ok_or(NetError::NoDhcpServer)?;
+    if ack.kind == dhcp::NAK {
+        return Err(NetError::DhcpDeclined(offer.your_ip));
+    }
+    Ok(Config {
+        ip: ack.your_ip,
+        netmask: ack.netmask.unwrap_or(DEFAULT_CONFIG.netmask),
+        gateway: ack.router.or(ack.server).unwrap_or(Ipv4Addr::UNSPECIFIED),
+    })
+}
+
+/// The first queued server reply for transaction `xid` of one of `kinds`;
+/// anything else queued on the client port is dropped.
+fn dhcp_reply(xid: u32, kinds: &[u8]) -> Option<dhcp::Reply> {
+    with_interface(|interface| loop {
+        let message = interface.take_udp(dhcp::CLIENT_PORT)?;
+        if let Some(reply) = dhcp::parse(&message.data).filter(|reply| reply.xid == xid && kinds.contains(&reply.kind)) {
+            return Some(reply);
+        }
+    })
+    .flatten()
+}
+
+pub fn print_summary() {
+    let Some(nic) = driver() else {
+        return;
+    };
+    with_interface(|interface| {
+        let config = interface.config;
+        println!("net: {} mask {} gateway {} on {} {}", config.ip, config.netmask, config.gateway,
+            nic, MacAddr(interface.mac));
+    });
+}
+
+//test case
+#[cfg(test)]
+fn test_interface() -> Interface {
+    Interface::new(testdata::GUEST_MAC, DEFAULT_CONFIG)
+}
+
+#[test_case]
+fn test_arp_request_gets_reply() {
+    use testdata::{ARP_REQUEST, GATEWAY_MAC, GUEST_MAC};
+    let mut interface = test_interface();
+    let reply = interface.handle_frame(&ARP_REQUEST, 5).unwrap();
+    let frame = ethernet::parse(&reply).unwrap();
+    assert_eq!((frame.dst, frame.src, frame.ethertype), (GATEWAY_MAC, GUEST_MAC, ethernet::ETHERTYPE_ARP));
+    let packet = ArpPacket::parse(frame.payload).unwrap();
+    assert_eq!(packet.op, arp::OP_REPLY);
+    assert_eq!((packet.sender_mac, packet.sender_ip), (GUEST_MAC, DEFAULT_CONFIG.ip));
+    assert_eq!((packet.target_mac, packet.target_ip), (GATEWAY_MAC, DEFAULT_CONFIG.gateway));
+    // the requester was learned on the way
+    assert_eq!(interface.arp_cache().lookup(DEFAULT_CONFIG.gateway), Some(GATEWAY_MAC));
+
+    // requests for somebody else are ignored
+    let mut other = ARP_REQUEST;
+    other[41] = 99;
+    assert_eq!(interface.handle_frame(&other, 6), None);
+}
+
+#[test_case]
+fn test_icmp_echo_gets_reply() {
+    use testdata::{GATEWAY_MAC, GUEST_MAC, ICMP_ECHO_REQUEST};
+    let mut interface = test_interface();
+    let reply = interface.handle_frame(&ICMP_ECHO_REQUEST, 0).unwrap();
+    let frame = ethernet::parse(&reply).unwrap();
+    assert_eq!((frame.dst, frame.src, frame.ethertype), (GATEWAY_MAC, GUEST_MAC, ethernet::ETHERTYPE_IPV4));
+    // parse() only succeeds when both checksums verify
+    let packet = ipv4::parse(frame.payload).unwrap();
+    assert_eq!((packet.src, packet.dst), (DEFAULT_CONFIG.ip, DEFAULT_CONFIG.gateway));
+    let echo = icmp::parse(packet.payload).unwrap();
+    assert_eq!(echo.kind, EchoKind::Reply);
+    assert_eq!((echo.ident, echo.seq), (0x1c46, 1));
+    assert_eq!(echo.data, &ICMP_ECHO_REQUEST[42..]);
+    assert_eq!(interface.stats().icmp, 1);
+}
+
+#[test_case]
+fn test_echo_reply_is_recorded() {
+    use testdata::GATEWAY_MAC;
+    let mut interface = test_interface();
+    let mut peer = Interface::new(GATEWAY_MAC, Config { ip: DEFAULT_CONFIG.gateway, ..DEFAULT_CONFIG });
+    let request = interface.echo_request(GATEWAY_MAC, DEFAULT_CONFIG.gateway, 7, 3);
+    let reply = peer.handle_frame(&request, 0).unwrap();
+    assert_eq!(interface.handle_frame(&reply, 42), None);
+    assert_eq!(interface.take_echo_reply(7, 2), None);
+    let recorded = interface.take_echo_reply(7, 3).unwrap();
+    assert_eq!((recorded.from, recorded.received), (DEFAULT_CONFIG.gateway, 42));
+    assert_eq!(interface.take_echo_reply(7, 3), None);
+}
+
+#[test_case]
+fn test_other_frames_are_dropped() {
+    use testdata::{ICMP_ECHO_REQUEST, GUEST_MAC};
+    let mut interface = test_interface();
+    // IPv6 ethertype
+    let ipv6 = ethernet::build(GUEST_MAC, [2; 6], 0x86dd, &[0; 40]);
+    assert_eq!(interface.handle_frame(&ipv6, 0), None);
+    // unicast to another MAC
+    let mut elsewhere = ICMP_ECHO_REQUEST;
+    elsewhere[5] = 0x57;
+    assert_eq!(interface.handle_frame(&elsewhere, 0), None);
+    assert_eq!(interface.stats().dropped, 2);
+    // a corrupted IP header is an error, not a reply
+    let mut corrupt = ICMP_ECHO_REQUEST;
+    corrupt[24] ^= 0x01;
+    assert_eq!(interface.handle_frame(&corrupt, 0), None);
+    assert_eq!(interface.stats().errors, 1);
+}
+
+#[test_case]
+fn test_next_hop() {
+    let interface = test_interface();
+    assert_eq!(interface.next_hop(Ipv4Addr([10, 0, 2, 3])), Ipv4Addr([10, 0, 2, 3]));
+    assert_eq!(interface.next_hop(Ipv4Addr([1, 1, 1, 1])), DEFAULT_CONFIG.gateway);
+}
+
+#[test_case]
+fn test_udp_echo_service() {
+    use testdata::{GATEWAY_MAC, GUEST_MAC};
+    let mut interface = test_interface();
+    let mut peer = Interface::new(GATEWAY_MAC, Config { ip: DEFAULT_CONFIG.gateway, ..DEFAULT_CONFIG });
+    let port = peer.bind_ephemeral();
+    assert_eq!(port, EPHEMERAL_PORTS);
+    let request = peer.udp_frame(GUEST_MAC, DEFAULT_CONFIG.ip, port, ECHO_PORT, b"hola");
+    let reply = interface.handle_frame(&request, 0).unwrap();
+    assert_eq!(interface.stats().udp, 1);
+    assert_eq!(peer.handle_frame(&reply, 9), None);
+    let message = peer.take_udp(port).unwrap();
+    assert_eq!((message.from, message.from_port, message.port), (DEFAULT_CONFIG.ip, ECHO_PORT, port));
+    assert_eq!((message.data.as_slice(), message.received), (&b"hola"[..], 9));
+    assert_eq!(peer.take_udp(port), None);
+
+    // nothing bound there: dropped
+    let request = peer.udp_frame(GUEST_MAC, DEFAULT_CONFIG.ip, port, 5555, b"hola");
+    assert_eq!(interface.handle_frame(&request, 0), None);
+    assert_eq!(interface.stats().dropped, 1);
+    assert!(interface.bind_udp(5555));
+    assert!(!interface.bind_udp(5555));
+    assert_eq!(interface.handle_frame(&request, 0), None);
+    assert_eq!(interface.take_udp(5555).map(|message| message.data), Some(b"hola".to_vec()));
+    interface.unbind_udp(5555);
+    assert_eq!(interface.handle_frame(&request, 0), None);
+    assert_eq!(interface.take_udp(5555), None);
+}
+
+#[test_case]
+fn test_dhcp_frames_are_broadcast() {
+    use testdata::{GATEWAY_MAC, GUEST_MAC};
+    let mut interface = test_interface();
+    let frame = interface.dhcp_frame(&dhcp::discover(1, GUEST_MAC));
+    let ethernet = ethernet::parse(&frame).unwrap();
+    assert_eq!(ethernet.dst, ethernet::BROADCAST);
+    let packet = ipv4::parse(ethernet.payload).unwrap();
+    assert_eq!((packet.src, packet.dst), (Ipv4Addr::UNSPECIFIED, Ipv4Addr::BROADCAST));
+    let datagram = udp::parse(packet.src, packet.dst, packet.payload).unwrap();
+    assert_eq!((datagram.src_port, datagram.dst_port), (dhcp::CLIENT_PORT, dhcp::SERVER_PORT));
+
+    // the server's broadcast answer reaches the bound client port
+    let mut server = Interface::new(GATEWAY_MAC, Config { ip: DEFAULT_CONFIG.gateway, ..DEFAULT_CONFIG });
+    let answer = server.udp_frame(ethernet::BROADCAST, Ipv4Addr::BROADCAST, dhcp::SERVER_PORT, dhcp::CLIENT_PORT, b"offer");
+    assert!(interface.bind_udp(dhcp::CLIENT_PORT));
+    assert_eq!(interface.handle_frame(&answer, 0), None);
+    assert_eq!(interface.take_udp(dhcp::CLIENT_PORT).map(|message| message.data), Some(b"offer".to_vec()));
+    assert_eq!(DEFAULT_CONFIG.broadcast(), Ipv4Addr([10, 0, 2, 255]));
+}
@@ -0,0 +1,132 @@
+//! ARP for IPv4 over Ethernet, plus a small fixed-size cache.
+
+use crate::bytes::be_u16;
+use super::ipv4::Ipv4Addr;
+
+pub const OP_REQUEST: u16 = 1;
+pub const OP_REPLY: u16 = 2;
+
+pub const PACKET_LEN: usize = 28;
+const HTYPE_ETHERNET: u16 = 1;
+const PTYPE_IPV4: u16 = 0x0800;
+
+pub const CACHE_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub op: u16,
+    pub sender_mac: [u8; 6],
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: [u8; 6],
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Accepts only Ethernet/IPv4 packets; trailing padding is ignored.
+    pub fn parse(bytes: &[u8]) -> Option<ArpPacket> {
+        if bytes.len() < PACKET_LEN
+            || be_u16(bytes, 0)? != HTYPE_ETHERNET
+            || be_u16(bytes, 2)? != PTYPE_IPV4
+            || bytes[4] != 6
+            || bytes[5] != 4
+        {
+            return None;
+        }
+        let mut sender_mac = [0; 6];
+        let mut target_mac = [0; 6];
+        sender_mac.copy_from_slice(&bytes[8..14]);
+        target_mac.copy_from_slice(&bytes[18..24]);
+        Some(ArpPacket {
+            op: be_u16(bytes, 6)?,
+            sender_mac,
+            sender_ip: Ipv4Addr::from_bytes(&bytes[14..18]),
+            target_mac,
+            target_ip: Ipv4Addr::from_bytes(&bytes[24..28]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; PACKET_LEN] {
+        let mut bytes = [0u8; PACKET_LEN];
+        bytes[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        bytes[2..4].copy_from_slice(&PTYPE_IPV4.to_be_bytes());
+        bytes[4] = 6;
+        bytes[5] = 4;
+        bytes[6..8].copy_from_slice(&self.op.to_be_bytes());
+        bytes[8..14].copy_from_slice(&self.sender_mac);
+        bytes[14..18].copy_from_slice(&self.sender_ip.0);
+        bytes[18..24].copy_from_slice(&self.target_mac);
+        bytes[24..28].copy_from_slice(&self.target_ip.0);
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CacheEntry {
+    ip: Ipv4Addr,
+    mac: [u8; 6],
+    updated: u64,
+}
+
+/// IP to MAC mappings. When full, the least recently updated entry is
+/// replaced.
+#[derive(Debug, Default)]
+pub struct ArpCache {
+    entries: [Option<CacheEntry>; CACHE_SIZE],
+}
+
+impl ArpCache {
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<[u8; 6]> {
+        self.entries.iter().flatten().find(|e| e.ip == ip).map(|e| e.mac)
+    }
+
+    pub fn insert(&mut self, ip: Ipv4Addr, mac: [u8; 6], now: u64) {
+        let entry = Some(CacheEntry { ip, mac, updated: now });
+        let slot = match self.entries.iter().position(|e| matches!(e, Some(e) if e.ip == ip)) {
+            Some(existing) => existing,
+            None => match self.entries.iter().position(Option::is_none) {
+                Some(free) => free,
+                None => self.oldest(),
+            },
+        };
+        self.entries[slot] = entry;
+    }
+
+    fn oldest(&self) -> usize {
+        (0..CACHE_SIZE)
+            .min_by_key(|&i| self.entries[i].map_or(0, |e| e.updated))
+            .unwrap_or(0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Ipv4Addr, [u8; 6])> + '_ {
+        self.entries.iter().flatten().map(|e| (e.ip, e.mac))
+    }
+}
+
+//test case
+#[test_case]
+fn test_arp_parse() {
+    use super::testdata::{ARP_REQUEST, GATEWAY_MAC};
+    let packet = ArpPacket::parse(&ARP_REQUEST[14..]).unwrap();
+    assert_eq!(packet.op, OP_REQUEST);
+    assert_eq!(packet.sender_mac, GATEWAY_MAC);
+    assert_eq!(packet.sender_ip, Ipv4Addr([10, 0, 2, 2]));
+    assert_eq!(packet.target_ip, Ipv4Addr([10, 0, 2, 15]));
+    assert_eq!(&packet.to_bytes()[..], &ARP_REQUEST[14..14 + PACKET_LEN]);
+    assert_eq!(ArpPacket::parse(&ARP_REQUEST[14..30]), None);
+}
+
+#[test_case]
+fn test_arp_cache_replacement() {
+    let mut cache = ArpCache::default();
+    for i in 0..CACHE_SIZE as u8 {
+        cache.insert(Ipv4Addr([10, 0, 0, i]), [i; 6], u64::from(i) + 10);
+    }
+    // refreshing an entry keeps a single copy of it
+    cache.insert(Ipv4Addr([10, 0, 0, 0]), [0xaa; 6], 100);
+    assert_eq!(cache.iter().count(), CACHE_SIZE);
+    assert_eq!(cache.lookup(Ipv4Addr([10, 0, 0, 0])), Some([0xaa; 6]));
+    // full: 10.0.0.1 is now the oldest
+    cache.insert(Ipv4Addr([10, 0, 1, 0]), [0xbb; 6], 101);
+    assert_eq!(cache.lookup(Ipv4Addr([10, 0, 0, 1])), None);
+    assert_eq!(cache.lookup(Ipv4Addr([10, 0, 1, 0])), Some([0xbb; 6]));
+}
@@ -0,0 +1,163 @@
+//! DHCP client messages (RFC 2131): DISCOVER and REQUEST out, OFFER and
+//! ACK or NAK in. A message is a BOOTP header followed by options; only
+//! the ones a single interface needs are read.
+
+use alloc::vec::Vec;
+use crate::bytes::be_u32;
+use super::ipv4::Ipv4Addr;
+
+pub const CLIENT_PORT: u16 = 68;
+pub const SERVER_PORT: u16 = 67;
+
+pub const DISCOVER: u8 = 1;
+pub const OFFER: u8 = 2;
+pub const REQUEST: u8 = 3;
+pub const ACK: u8 = 5;
+pub const NAK: u8 = 6;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+/// Asks the server to broadcast its answers: until it has an address the
+/// interface only takes IP packets to it and to the broadcast address.
+const FLAG_BROADCAST: u16 = 0x8000;
+/// op through file, before the magic cookie.
+const BOOTP_LEN: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+/// What a server's reply says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply {
+    pub kind: u8,
+    pub xid: u32,
+    /// The address offered or acknowledged.
+    pub your_ip: Ipv4Addr,
+    pub server: Option<Ipv4Addr>,
+    pub netmask: Option<Ipv4Addr>,
+    pub router: Option<Ipv4Addr>,
+    pub lease_secs: Option<u32>,
+}
+
+fn message(kind: u8, xid: u32, mac: [u8; 6], options: &[u8]) -> Vec<u8> {
+    let mut bytes = alloc::vec![0; BOOTP_LEN];
+    bytes[..4].copy_from_slice(&[BOOTREQUEST, HTYPE_ETHERNET, 6, 0]);
+    bytes[4..8].copy_from_slice(&xid.to_be_bytes());
+    bytes[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    bytes[28..34].copy_from_slice(&mac);
+    bytes.extend_from_slice(&MAGIC_COOKIE);
+    bytes.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind]);
+    bytes.extend_from_slice(options);
+    bytes.extend_from_slice(&[OPTION_PARAMETERS, 2, OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_END]);
+    bytes
+}
+
+pub fn discover(xid: u32, mac: [u8; 6]) -> Vec<u8> {
+    message(DISCOVER, xid, mac, &[])
+}
+
+/// Takes the address `offer` made.
+pub fn request(xid: u32, mac: [u8; 6], offer: &Reply) -> Vec<u8> {
+    let mut options = Vec::new();
+    options.extend_from_slice(&[OPTION_REQUESTED_IP, 4]);
+    options.extend_from_slice(&offer.your_ip.0);
+    if let Some(server) = offer.server {
+        options.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+        options.extend_from_slice(&server.0);
+    }
+    message(REQUEST, xid, mac, &options)
+}
+
+/// A server's reply, or `None` for anything else (requests, BOOTP without
+/// a message type, truncated options).
+pub fn parse(bytes: &[u8]) -> Option<Reply> {
+    if bytes.len() < BOOTP_LEN + 4 || bytes[0] != BOOTREPLY || bytes[BOOTP_LEN..BOOTP_LEN + 4] != MAGIC_COOKIE {
+        return None;
+    }
+    let mut reply = Reply {
+        kind: 0,
+        xid: be_u32(bytes, 4)?,
+        your_ip: Ipv4Addr::from_bytes(&bytes[16..20]),
+        server: None,
+        netmask: None,
+        router: None,
+        lease_secs: None,
+    };
+    let mut options = &bytes[BOOTP_LEN + 4..];
+    loop {
+        let (&code, rest) = options.split_first()?;
+        match code {
+            OPTION_END => break,
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            _ => {}
+        }
+        let (&len, rest) = rest.split_first()?;
+        let value = rest.get(..usize::from(len))?;
+        let address = (value.len() >= 4).then(|| Ipv4Addr::from_bytes(value));
+        match code {
+            OPTION_MESSAGE_TYPE => reply.kind = *value.first()?,
+            OPTION_SERVER_ID => reply.server = address,
+            OPTION_SUBNET_MASK => reply.netmask = address,
+            OPTION_ROUTER => reply.router = address,
+            OPTION_LEASE_TIME => reply.lease_secs = be_u32(value, 0),
+            _ => {}
+        }
+        options = &rest[usize::from(len)..];
+    }
+    (reply.kind != 0).then_some(reply)
+}
+
+//test case
+#[cfg(test)]
+fn server_reply(kind: u8, xid: u32) -> Vec<u8> {
+    // what slirp answers: 10.0.2.15, server and router 10.0.2.2
+    let mut bytes = alloc::vec![0; BOOTP_LEN];
+    bytes[..4].copy_from_slice(&[BOOTREPLY, HTYPE_ETHERNET, 6, 0]);
+    bytes[4..8].copy_from_slice(&xid.to_be_bytes());
+    bytes[16..20].copy_from_slice(&[10, 0, 2, 15]);
+    bytes.extend_from_slice(&MAGIC_COOKIE);
+    bytes.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind, OPTION_PAD]);
+    bytes.extend_from_slice(&[OPTION_SERVER_ID, 4, 10, 0, 2, 2, OPTION_LEASE_TIME, 4, 0, 1, 0x51, 0x80]);
+    bytes.extend_from_slice(&[OPTION_SUBNET_MASK, 4, 255, 255, 255, 0, OPTION_ROUTER, 4, 10, 0, 2, 2, OPTION_END]);
+    bytes
+}
+
+#[test_case]
+fn test_dhcp_messages() {
+    let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    let discover = discover(0x1234_5678, mac);
+    assert_eq!(discover[0], BOOTREQUEST);
+    assert_eq!(discover[4..8], [0x12, 0x34, 0x56, 0x78]);
+    assert_eq!(discover[28..34], mac);
+    assert_eq!(discover[BOOTP_LEN..BOOTP_LEN + 7], [99, 130, 83, 99, OPTION_MESSAGE_TYPE, 1, DISCOVER]);
+    // our own requests are not replies
+    assert_eq!(parse(&discover), None);
+
+    let offer = parse(&server_reply(OFFER, 0x1234_5678)).unwrap();
+    assert_eq!(offer.kind, OFFER);
+    assert_eq!(offer.your_ip, Ipv4Addr([10, 0, 2, 15]));
+    assert_eq!(offer.server, Some(Ipv4Addr([10, 0, 2, 2])));
+    assert_eq!(offer.netmask, Some(Ipv4Addr([255, 255, 255, 0])));
+    assert_eq!(offer.lease_secs, Some(86400));
+
+    let request = request(0x1234_5678, mac, &offer);
+    let options = &request[BOOTP_LEN + 7..];
+    assert_eq!(options[..12], [OPTION_REQUESTED_IP, 4, 10, 0, 2, 15, OPTION_SERVER_ID, 4, 10, 0, 2, 2]);
+
+    // options running past the end
+    let mut truncated = server_reply(ACK, 1);
+    truncated.truncate(truncated.len() - 3);
+    assert_eq!(parse(&truncated), None);
+}
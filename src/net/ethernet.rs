@@ -0,0 +1,50 @@
+//! Ethernet II framing.
+
+use alloc::vec::Vec;
+use crate::bytes::be_u16;
+
+pub const HEADER_LEN: usize = 14;
+pub const BROADCAST: [u8; 6] = [0xff; 6];
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub dst: [u8; 6],
+    pub src: [u8; 6],
+    pub ethertype: u16,
+    /// Everything after the header, including any padding to 60 bytes.
+    pub payload: &'a [u8],
+}
+
+pub fn parse(bytes: &[u8]) -> Option<Frame<'_>> {
+    let ethertype = be_u16(bytes, 12)?;
+    let mut dst = [0; 6];
+    let mut src = [0; 6];
+    dst.copy_from_slice(&bytes[0..6]);
+    src.copy_from_slice(&bytes[6..12]);
+    Some(Frame { dst, src, ethertype, payload: &bytes[HEADER_LEN..] })
+}
+
+pub fn build(dst: [u8; 6], src: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&src);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+//test case
+#[test_case]
+fn test_ethernet_roundtrip() {
+    use super::testdata::{ARP_REQUEST, GATEWAY_MAC};
+    let frame = parse(&ARP_REQUEST).unwrap();
+    assert_eq!(frame.dst, BROADCAST);
+    assert_eq!(frame.src, GATEWAY_MAC);
+    assert_eq!(frame.ethertype, ETHERTYPE_ARP);
+    assert_eq!(frame.payload.len(), 46);
+    assert_eq!(build(frame.dst, frame.src, frame.ethertype, frame.payload), &ARP_REQUEST[..]);
+    assert_eq!(parse(&ARP_REQUEST[..13]), None);
+}
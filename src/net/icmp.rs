@@ -0,0 +1,91 @@
+//! ICMP echo request/reply, the only ICMP messages we speak.
+
+use alloc::vec::Vec;
+use crate::bytes::be_u16;
+use super::ipv4::internet_checksum;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoKind {
+    Request,
+    Reply,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Echo<'a> {
+    pub kind: EchoKind,
+    pub ident: u16,
+    pub seq: u16,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpError {
+    Truncated,
+    BadChecksum,
+    /// A valid message that is not an echo (type, code).
+    Unsupported(u8, u8),
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Echo<'_>, IcmpError> {
+    if bytes.len() < HEADER_LEN {
+        return Err(IcmpError::Truncated);
+    }
+    if internet_checksum(bytes) != 0 {
+        return Err(IcmpError::BadChecksum);
+    }
+    let kind = match (bytes[0], bytes[1]) {
+        (TYPE_ECHO_REQUEST, 0) => EchoKind::Request,
+        (TYPE_ECHO_REPLY, 0) => EchoKind::Reply,
+        (kind, code) => return Err(IcmpError::Unsupported(kind, code)),
+    };
+    Ok(Echo {
+        kind,
+        ident: be_u16(bytes, 4).ok_or(IcmpError::Truncated)?,
+        seq: be_u16(bytes, 6).ok_or(IcmpError::Truncated)?,
+        data: &bytes[HEADER_LEN..],
+    })
+}
+
+pub fn build(echo: &Echo) -> Vec<u8> {
+    let kind = match echo.kind {
+        EchoKind::Request => TYPE_ECHO_REQUEST,
+        EchoKind::Reply => TYPE_ECHO_REPLY,
+    };
+    let mut message = Vec::with_capacity(HEADER_LEN + echo.data.len());
+    message.extend_from_slice(&[kind, 0, 0, 0]);
+    message.extend_from_slice(&echo.ident.to_be_bytes());
+    message.extend_from_slice(&echo.seq.to_be_bytes());
+    message.extend_from_slice(echo.data);
+    let checksum = internet_checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+//test case
+#[test_case]
+fn test_icmp_echo_parse() {
+    use super::testdata::ICMP_ECHO_REQUEST;
+    let echo = parse(&ICMP_ECHO_REQUEST[34..]).unwrap();
+    assert_eq!(echo.kind, EchoKind::Request);
+    assert_eq!((echo.ident, echo.seq), (0x1c46, 1));
+    assert_eq!(echo.data.len(), 40);
+    // rebuilding the same message reproduces the captured checksum
+    assert_eq!(build(&echo), &ICMP_ECHO_REQUEST[34..]);
+
+    let mut corrupt = ICMP_ECHO_REQUEST;
+    corrupt[50] ^= 0xff;
+    assert_eq!(parse(&corrupt[34..]), Err(IcmpError::BadChecksum));
+}
+
+#[test_case]
+fn test_icmp_unsupported() {
+    // destination unreachable, port unreachable
+    let mut message = [3u8, 3, 0, 0, 0, 0, 0, 0];
+    let checksum = internet_checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    assert_eq!(parse(&message), Err(IcmpError::Unsupported(3, 3)));
+}
@@ -0,0 +1,183 @@
+//! IPv4 header parsing and building. Options are skipped, fragments are
+//! not reassembled.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use crate::bytes::be_u16;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_UDP: u8 = 17;
+
+const MIN_HEADER_LEN: usize = 20;
+const DEFAULT_TTL: u8 = 64;
+/// More-fragments flag and fragment offset, in the flags/offset word.
+const FRAGMENT_MASK: u16 = 0x3fff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255; 4]);
+
+    pub fn from_bytes(bytes: &[u8]) -> Ipv4Addr {
+        Ipv4Addr([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn same_subnet(self, other: Ipv4Addr, netmask: Ipv4Addr) -> bool {
+        self.to_u32() & netmask.to_u32() == other.to_u32() & netmask.to_u32()
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Ipv4Addr, ()> {
+        let mut octets = [0u8; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            *octet = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        }
+        if parts.next().is_some() {
+            return Err(());
+        }
+        Ok(Ipv4Addr(octets))
+    }
+}
+
+/// RFC 1071 ones' complement sum, folded and inverted. A buffer that
+/// includes a correct checksum field sums to zero.
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in data.chunks(2) {
+        let word = match *chunk {
+            [hi, lo] => u16::from_be_bytes([hi, lo]),
+            [hi] => u16::from_be_bytes([hi, 0]),
+            _ => unreachable!(),
+        };
+        sum += u32::from(word);
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv4Error {
+    Truncated,
+    NotVersion4,
+    BadChecksum,
+    Fragmented,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub payload: &'a [u8],
+}
+
+/// Validates the header (version, length, checksum) and strips it. The
+/// payload is cut at the total length, dropping Ethernet padding.
+pub fn parse(bytes: &[u8]) -> Result<Packet<'_>, Ipv4Error> {
+    let first = *bytes.first().ok_or(Ipv4Error::Truncated)?;
+    if first >> 4 != 4 {
+        return Err(Ipv4Error::NotVersion4);
+    }
+    let header_len = usize::from(first & 0x0f) * 4;
+    let total_len = usize::from(be_u16(bytes, 2).ok_or(Ipv4Error::Truncated)?);
+    if header_len < MIN_HEADER_LEN || total_len < header_len || bytes.len() < total_len {
+        return Err(Ipv4Error::Truncated);
+    }
+    if internet_checksum(&bytes[..header_len]) != 0 {
+        return Err(Ipv4Error::BadChecksum);
+    }
+    if be_u16(bytes, 6).ok_or(Ipv4Error::Truncated)? & FRAGMENT_MASK != 0 {
+        return Err(Ipv4Error::Fragmented);
+    }
+    Ok(Packet {
+        src: Ipv4Addr::from_bytes(&bytes[12..16]),
+        dst: Ipv4Addr::from_bytes(&bytes[16..20]),
+        protocol: bytes[9],
+        ttl: bytes[8],
+        payload: &bytes[header_len..total_len],
+    })
+}
+
+/// Builds a 20-byte header (don't-fragment set) followed by `payload`.
+pub fn build(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, ident: u16, payload: &[u8]) -> Vec<u8> {
+    let total_len = (MIN_HEADER_LEN + payload.len()) as u16;
+    let mut packet = Vec::with_capacity(usize::from(total_len));
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&ident.to_be_bytes());
+    packet.extend_from_slice(&0x4000u16.to_be_bytes());
+    packet.extend_from_slice(&[DEFAULT_TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&src.0);
+    packet.extend_from_slice(&dst.0);
+    let checksum = internet_checksum(&packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+//test case
+#[test_case]
+fn test_internet_checksum() {
+    // RFC 1071 section 3 example
+    let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+    assert_eq!(internet_checksum(&data), !0xddf2);
+    // odd lengths are padded with a zero byte
+    assert_eq!(internet_checksum(&[0x01]), !0x0100);
+    assert_eq!(internet_checksum(&[]), 0xffff);
+}
+
+#[test_case]
+fn test_ipv4_parse() {
+    use super::testdata::ICMP_ECHO_REQUEST;
+    let packet = parse(&ICMP_ECHO_REQUEST[14..]).unwrap();
+    assert_eq!(packet.src, Ipv4Addr([10, 0, 2, 2]));
+    assert_eq!(packet.dst, Ipv4Addr([10, 0, 2, 15]));
+    assert_eq!(packet.protocol, PROTOCOL_ICMP);
+    assert_eq!(packet.payload.len(), 48);
+
+    let mut corrupt = ICMP_ECHO_REQUEST;
+    corrupt[14 + 8] = 1; // TTL, not covered by a fixed checksum
+    assert_eq!(parse(&corrupt[14..]), Err(Ipv4Error::BadChecksum));
+    assert_eq!(parse(&ICMP_ECHO_REQUEST[14..40]), Err(Ipv4Error::Truncated));
+}
+
+#[test_case]
+fn test_ipv4_build() {
+    let src = Ipv4Addr([10, 0, 2, 15]);
+    let dst = Ipv4Addr([10, 0, 2, 2]);
+    let packet = build(src, dst, PROTOCOL_ICMP, 7, &[1, 2, 3]);
+    assert_eq!(packet.len(), 23);
+    let parsed = parse(&packet).unwrap();
+    assert_eq!((parsed.src, parsed.dst, parsed.ttl), (src, dst, DEFAULT_TTL));
+    assert_eq!(parsed.payload, &[1, 2, 3]);
+}
+
+#[test_case]
+fn test_ipv4_addr_from_str() {
+    assert_eq!("10.0.2.15".parse(), Ok(Ipv4Addr([10, 0, 2, 15])));
+    assert_eq!("10.0.2".parse::<Ipv4Addr>(), Err(()));
+    assert_eq!("10.0.2.256".parse::<Ipv4Addr>(), Err(()));
+    assert_eq!("10.0.2.1.1".parse::<Ipv4Addr>(), Err(()));
+    assert_eq!(alloc::format!("{}", Ipv4Addr([192, 168, 0, 1])), "192.168.0.1");
+}
@@ -0,0 +1,26 @@
+//! Frames as seen on QEMU user-mode networking: the slirp gateway
+//! (10.0.2.2, 52:55:0a:00:02:02) looking for the guest at 10.0.2.15 and
+//! pinging it. The guest NIC uses QEMU's default MAC 52:54:00:12:34:56.
+
+pub const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+pub const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+
+/// Broadcast ARP request "who has 10.0.2.15? tell 10.0.2.2", padded to 60 bytes.
+pub static ARP_REQUEST: [u8; 60] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02,
+    0x08, 0x06, 0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x52, 0x55,
+    0x0a, 0x00, 0x02, 0x02, 0x0a, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x0a, 0x00, 0x02, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// ICMP echo request, id 0x1c46 seq 1, 40 data bytes.
+pub static ICMP_ECHO_REQUEST: [u8; 82] = [
+    0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02,
+    0x08, 0x00, 0x45, 0x00, 0x00, 0x44, 0x7a, 0x3c, 0x40, 0x00, 0x40, 0x01,
+    0xa8, 0x6c, 0x0a, 0x00, 0x02, 0x02, 0x0a, 0x00, 0x02, 0x0f, 0x08, 0x00,
+    0x1c, 0xe6, 0x1c, 0x46, 0x00, 0x01, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15,
+    0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f, 0x20, 0x21,
+    0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x2d,
+    0x2e, 0x2f, 0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37,
+];
@@ -0,0 +1,760 @@
+//! NVMe driver (PCI class 01:08): the admin queue pair, one I/O queue pair
+//! and the first active namespace, with 512-byte LBAs.
+//!
+//! BAR0 is mapped uncached; the controller is disabled, given the admin
+//! queues and enabled again, then identified. Commands are posted to a
+//! submission queue and rung in with its tail doorbell; completion is found
+//! by polling the completion queue for an entry whose phase bit matches
+//! the expected phase, which flips every time the queue wraps. Data goes
+//! through a bounce buffer described with PRP entries: PRP2 is the second
+//! page when a transfer touches two, and a PRP list once it touches more.
+//!
+//! QEMU: `-drive file=<image>,if=none,id=nvm,format=raw -device nvme,serial=berry,drive=nvm`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::memory::{BootInfoFrameAllocator, DmaRegion};
+use crate::pci::{self, Bar};
+use crate::time;
+
+pub const CLASS_MASS_STORAGE: u8 = 0x01;
+pub const SUBCLASS_NVME: u8 = 0x08;
+
+pub const PAGE_SIZE: usize = 4096;
+
+const REG_CAP: u64 = 0x00;
+const REG_VS: u64 = 0x08;
+const REG_INTMS: u64 = 0x0c;
+const REG_CC: u64 = 0x14;
+const REG_CSTS: u64 = 0x1c;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+const DOORBELL_BASE: u64 = 0x1000;
+
+const CC_ENABLE: u32 = 1 << 0;
+/// 64-byte submission and 16-byte completion entries, as log2.
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+pub const ADMIN_CREATE_SQ: u8 = 0x01;
+pub const ADMIN_CREATE_CQ: u8 = 0x05;
+pub const ADMIN_IDENTIFY: u8 = 0x06;
+pub const IO_WRITE: u8 = 0x01;
+pub const IO_READ: u8 = 0x02;
+
+const CNS_NAMESPACE: u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+const CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+
+const ADMIN_QUEUE_SIZE: u16 = 32;
+const IO_QUEUE_SIZE: u16 = 64;
+const IO_QUEUE_ID: u16 = 1;
+
+const DATA_PAGES: usize = 8;
+const POLL_LIMIT: usize = 50_000_000;
+const COMMAND_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeError {
+    NoController,
+    /// BAR0 is missing or not a memory BAR.
+    NoBar,
+    MapFailed,
+    OutOfMemory,
+    /// The controller never reached the state we waited for.
+    Timeout,
+    /// CSTS.CFS: the controller hit a fatal error.
+    Fatal,
+    /// A command completed with this status field (SCT and SC).
+    Command(u16),
+    NoNamespace,
+    /// Only 512-byte LBAs are handled.
+    LbaSize(u32),
+}
+
+impl fmt::Display for NvmeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NvmeError::NoController => f.write_str("no NVMe controller"),
+            NvmeError::NoBar => f.write_str("controller has no memory BAR0"),
+            NvmeError::MapFailed => f.write_str("could not map registers"),
+            NvmeError::OutOfMemory => f.write_str("out of DMA memory"),
+            NvmeError::Timeout => f.write_str("controller timed out"),
+            NvmeError::Fatal => f.write_str("controller fatal status"),
+            NvmeError::Command(status) => write!(f, "command failed with status {:#x}", status),
+            NvmeError::NoNamespace => f.write_str("no active namespace"),
+            NvmeError::LbaSize(size) => write!(f, "unsupported LBA size {}", size),
+        }
+    }
+}
+
+impl From<NvmeError> for BlockError {
+    fn from(err: NvmeError) -> BlockError {
+        match err {
+            NvmeError::Timeout => BlockError::Timeout,
+            _ => BlockError::Io,
+        }
+    }
+}
+
+/// Fields of the capabilities register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Largest queue the controller takes, in entries.
+    pub max_queue_entries: u32,
+    /// Bytes between doorbells.
+    pub doorbell_stride: u64,
+    /// Worst-case time to become ready, in milliseconds.
+    pub timeout_ms: u64,
+}
+
+impl Capabilities {
+    pub fn parse(cap: u64) -> Capabilities {
+        Capabilities {
+            max_queue_entries: (cap & 0xffff) as u32 + 1,
+            doorbell_stride: 4 << ((cap >> 32) & 0xf),
+            timeout_ms: ((cap >> 24) & 0xff).max(1) * 500,
+        }
+    }
+
+    /// Offset of the submission queue tail doorbell of queue `qid`; the
+    /// completion queue head doorbell follows it.
+    pub fn doorbell(&self, qid: u16, completion: bool) -> u64 {
+        DOORBELL_BASE + (2 * u64::from(qid) + u64::from(completion)) * self.doorbell_stride
+    }
+}
+
+/// A 64-byte submission queue entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Command {
+    pub opcode: u8,
+    pub cid: u16,
+    pub nsid: u32,
+    pub prp1: u64,
+    pub prp2: u64,
+    /// Command dwords 10 to 15.
+    pub cdw: [u32; 6],
+}
+
+impl Command {
+    pub fn identify(cns: u32, nsid: u32, buffer: u64) -> Command {
+        Command { opcode: ADMIN_IDENTIFY, nsid, prp1: buffer, cdw: [cns, 0, 0, 0, 0, 0], ..Command::default() }
+    }
+
+    /// A physically contiguous, polled completion queue.
+    pub fn create_cq(qid: u16, size: u16, address: u64) -> Command {
+        let cdw10 = u32::from(size - 1) << 16 | u32::from(qid);
+        Command { opcode: ADMIN_CREATE_CQ, prp1: address, cdw: [cdw10, 1, 0, 0, 0, 0], ..Command::default() }
+    }
+
+    /// A physically contiguous submission queue completing on `cqid`.
+    pub fn create_sq(qid: u16, size: u16, address: u64, cqid: u16) -> Command {
+        let cdw10 = u32::from(size - 1) << 16 | u32::from(qid);
+        let cdw11 = u32::from(cqid) << 16 | 1;
+        Command { opcode: ADMIN_CREATE_SQ, prp1: address, cdw: [cdw10, cdw11, 0, 0, 0, 0], ..Command::default() }
+    }
+
+    /// Reads or writes `blocks` LBAs from `lba`.
+    pub fn io(opcode: u8, nsid: u32, lba: u64, blocks: u16, prps: (u64, u64)) -> Command {
+        let cdw = [lba as u32, (lba >> 32) as u32, u32::from(blocks - 1), 0, 0, 0];
+        Command { opcode, nsid, prp1: prps.0, prp2: prps.1, cdw, ..Command::default() }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 64] {
+        let mut bytes = [0; 64];
+        bytes[0] = self.opcode;
+        bytes[2..4].copy_from_slice(&self.cid.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.nsid.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.prp1.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.prp2.to_le_bytes());
+        for (i, dword) in self.cdw.iter().enumerate() {
+            bytes[40 + 4 * i..44 + 4 * i].copy_from_slice(&dword.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// A 16-byte completion queue entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Completion {
+    pub result: u32,
+    pub sq_head: u16,
+    pub cid: u16,
+    pub phase: bool,
+    /// The status field without the phase bit; 0 is success.
+    pub status: u16,
+}
+
+impl Completion {
+    pub fn parse(bytes: &[u8; 16]) -> Completion {
+        let dword = |i: usize| u32::from_le_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+        let status = dword(3) >> 16;
+        Completion {
+            result: dword(0),
+            sq_head: dword(2) as u16,
+            cid: dword(3) as u16,
+            phase: status & 1 != 0,
+            status: (status >> 1) as u16 & 0x7fff,
+        }
+    }
+}
+
+/// The first and second PRP entries for `len` bytes at physical address
+/// `phys`. Past two pages the rest of the page addresses go into `list`,
+/// whose physical address becomes PRP2. `None` when they don't fit.
+pub fn build_prps(phys: u64, len: usize, list: &mut [u64], list_phys: u64) -> Option<(u64, u64)> {
+    if len == 0 {
+        return None;
+    }
+    let page = PAGE_SIZE as u64;
+    let first_page = phys & !(page - 1);
+    let end = phys + len as u64;
+    let pages = (end - first_page).div_ceil(page);
+    match pages {
+        1 => Some((phys, 0)),
+        2 => Some((phys, first_page + page)),
+        _ => {
+            // one list page, without chaining
+            let rest = list.get_mut(..pages as usize - 1)?;
+            for (i, entry) in rest.iter_mut().enumerate() {
+                *entry = first_page + (i as u64 + 1) * page;
+            }
+            Some((phys, list_phys))
+        }
+    }
+}
+
+/// Submission queue state: entries go in at the tail.
+pub struct SubmissionQueue {
+    base: *mut u8,
+    size: u16,
+    tail: u16,
+    /// The controller's head, from the last completion.
+    head: u16,
+}
+
+impl SubmissionQueue {
+    /// # Safety
+    /// `base` must point to `size` 64-byte entries that outlive the queue.
+    pub unsafe fn new(base: *mut u8, size: u16) -> SubmissionQueue {
+        SubmissionQueue { base, size, tail: 0, head: 0 }
+    }
+
+    pub fn is_full(&self) -> bool {
+        (self.tail + 1) % self.size == self.head
+    }
+
+    /// Copies `command` in and returns the new tail for the doorbell.
+    pub fn push(&mut self, command: &Command) -> Option<u16> {
+        if self.is_full() {
+            return None;
+        }
+        let entry = unsafe { self.base.add(64 * usize::from(self.tail)) };
+        for (i, byte) in command.to_bytes().into_iter().enumerate() {
+            unsafe { ptr::write_volatile(entry.add(i), byte) };
+        }
+        self.tail = (self.tail + 1) % self.size;
+        Some(self.tail)
+    }
+
+    pub fn set_head(&mut self, head: u16) {
+        self.head = head % self.size;
+    }
+}
+
+/// Completion queue state. An entry is new when its phase bit matches
+/// `phase`, which starts at 1 and flips each time `head` wraps.
+pub struct CompletionQueue {
+    base: *mut u8,
+    size: u16,
+    head: u16,
+    phase: bool,
+}
+
+impl CompletionQueue {
+    /// # Safety
+    /// `base` must point to `size` zeroed 16-byte entries that outlive the
+    /// queue.
+    pub unsafe fn new(base: *mut u8, size: u16) -> CompletionQueue {
+        CompletionQueue { base, size, head: 0, phase: true }
+    }
+
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    /// Takes the next new entry, if the controller posted one.
+    pub fn pop(&mut self) -> Option<Completion> {
+        let mut bytes = [0; 16];
+        let entry = unsafe { self.base.add(16 * usize::from(self.head)) };
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile(entry.add(i)) };
+        }
+        let completion = Completion::parse(&bytes);
+        if completion.phase != self.phase {
+            return None;
+        }
+        self.head += 1;
+        if self.head == self.size {
+            self.head = 0;
+            self.phase = !self.phase;
+        }
+        Some(completion)
+    }
+}
+
+// The raw pointers target DMA memory owned by the queue pair.
+unsafe impl Send for SubmissionQueue {}
+unsafe impl Send for CompletionQueue {}
+
+#[derive(Clone, Copy)]
+struct Registers {
+    base: VirtAddr,
+}
+
+impl Registers {
+    fn read32(&self, reg: u64) -> u32 {
+        unsafe { ptr::read_volatile((self.base + reg).as_ptr::<u32>()) }
+    }
+
+    fn write32(&self, reg: u64, value: u32) {
+        unsafe { ptr::write_volatile((self.base + reg).as_mut_ptr::<u32>(), value) }
+    }
+
+    fn read64(&self, reg: u64) -> u64 {
+        u64::from(self.read32(reg)) | u64::from(self.read32(reg + 4)) << 32
+    }
+
+    fn write64(&self, reg: u64, value: u64) {
+        self.write32(reg, value as u32);
+        self.write32(reg + 4, (value >> 32) as u32);
+    }
+
+    /// Waits for CSTS.RDY to read `ready`.
+    fn wait_ready(&self, ready: bool, timeout_ms: u64) -> Result<(), NvmeError> {
+        let deadline = time::now_ns() + timeout_ms * 1_000_000;
+        for _ in 0..POLL_LIMIT {
+            let csts = self.read32(REG_CSTS);
+            if csts & CSTS_FATAL != 0 && ready {
+                return Err(NvmeError::Fatal);
+            }
+            if (csts & CSTS_READY != 0) == ready {
+                return Ok(());
+            }
+            if time::now_ns() > deadline {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        Err(NvmeError::Timeout)
+    }
+}
+
+struct QueuePair {
+    sq: SubmissionQueue,
+    cq: CompletionQueue,
+    sq_doorbell: u64,
+    cq_doorbell: u64,
+    next_cid: u16,
+}
+
+impl QueuePair {
+    /// Posts `command` and polls for its completion.
+    fn run(&mut self, regs: &Registers, mut command: Command) -> Result<Completion, NvmeError> {
+        command.cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+        let tail = self.sq.push(&command).ok_or(NvmeError::Timeout)?;
+        fence(Ordering::SeqCst);
+        regs.write32(self.sq_doorbell, u32::from(tail));
+
+        let deadline = time::now_ns() + COMMAND_TIMEOUT_MS * 1_000_000;
+        for _ in 0..POLL_LIMIT {
+            if let Some(completion) = self.cq.pop() {
+                fence(Ordering::SeqCst);
+                self.sq.set_head(completion.sq_head);
+                regs.write32(self.cq_doorbell, u32::from(self.cq.head()));
+                if completion.cid != command.cid {
+                    // a leftover from a command that timed out
+                    continue;
+                }
+                return match completion.status {
+                    0 => Ok(completion),
+                    status => Err(NvmeError::Command(status)),
+                };
+            }
+            if time::now_ns() > deadline || regs.read32(REG_CSTS) & CSTS_FATAL != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        Err(NvmeError::Timeout)
+    }
+}
+
+pub struct NvmeDisk {
+    regs: Registers,
+    admin: QueuePair,
+    io: QueuePair,
+    _queue_memory: DmaRegion,
+    bounce: DmaRegion,
+    prp_list: DmaRegion,
+    nsid: u32,
+    sectors: u64,
+    max_sectors: usize,
+    model: String,
+}
+
+/// Controller model, from IDENTIFY CONTROLLER.
+fn ascii_field(bytes: &[u8]) -> String {
+    String::from(core::str::from_utf8(bytes).unwrap_or("").trim_end())
+}
+
+impl NvmeDisk {
+    fn new(
+        regs: Registers,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        physical_memory_offset: VirtAddr,
+    ) -> Result<NvmeDisk, NvmeError> {
+        let capabilities = Capabilities::parse(regs.read64(REG_CAP));
+        let allocate = |frame_allocator: &mut BootInfoFrameAllocator, pages| {
+            DmaRegion::allocate(frame_allocator, physical_memory_offset, pages).ok_or(NvmeError::OutOfMemory)
+        };
+        // admin SQ, admin CQ, I/O SQ, I/O CQ: a page each
+        let queue_memory = allocate(frame_allocator, 4)?;
+        let bounce = allocate(frame_allocator, DATA_PAGES)?;
+        let prp_list = allocate(frame_allocator, 1)?;
+        let queue_phys = |index: usize| queue_memory.phys_addr().as_u64() + (index * PAGE_SIZE) as u64;
+        let queue_ptr = |index: usize| unsafe { queue_memory.as_mut_ptr().add(index * PAGE_SIZE) };
+
+        regs.write32(REG_CC, regs.read32(REG_CC) & !CC_ENABLE);
+        regs.wait_ready(false, capabilities.timeout_ms)?;
+        // everything is polled
+        regs.write32(REG_INTMS, u32::MAX);
+        let admin_size = ADMIN_QUEUE_SIZE.min(capabilities.max_queue_entries as u16);
+        let admin_entries = u32::from(admin_size - 1);
+        regs.write32(REG_AQA, admin_entries << 16 | admin_entries);
+        regs.write64(REG_ASQ, queue_phys(0));
+        regs.write64(REG_ACQ, queue_phys(1));
+        regs.write32(REG_CC, CC_ENABLE | CC_IOSQES | CC_IOCQES);
+        regs.wait_ready(true, capabilities.timeout_ms)?;
+
+        let mut admin = QueuePair {
+            sq: unsafe { SubmissionQueue::new(queue_ptr(0), admin_size) },
+            cq: unsafe { CompletionQueue::new(queue_ptr(1), admin_size) },
+            sq_doorbell: capabilities.doorbell(0, false),
+            cq_doorbell: capabilities.doorbell(0, true),
+            next_cid: 0,
+        };
+
+        let buffer = bounce.phys_addr().as_u64();
+        let data = |len: usize| unsafe { core::slice::from_raw_parts(bounce.as_mut_ptr(), len) };
+
+        admin.run(&regs, Command::identify(CNS_CONTROLLER, 0, buffer))?;
+        let controller = data(PAGE_SIZE);
+        let model = ascii_field(&controller[24..64]);
+        // MDTS is a power of two of the minimum page size; 0 means no limit
+        let max_bytes = match controller[77] {
+            0 => usize::MAX,
+            mdts => PAGE_SIZE << mdts,
+        };
+        let max_sectors = max_bytes.min(DATA_PAGES * PAGE_SIZE) / SECTOR_SIZE;
+
+        admin.run(&regs, Command::identify(CNS_ACTIVE_NAMESPACES, 0, buffer))?;
+        let nsid = u32::from_le_bytes(data(4).try_into().unwrap());
+        if nsid == 0 {
+            return Err(NvmeError::NoNamespace);
+        }
+        admin.run(&regs, Command::identify(CNS_NAMESPACE, nsid, buffer))?;
+        let namespace = Namespace::parse(data(PAGE_SIZE)).ok_or(NvmeError::NoNamespace)?;
+        if namespace.lba_size != SECTOR_SIZE as u32 {
+            return Err(NvmeError::LbaSize(namespace.lba_size));
+        }
+
+        let io_size = IO_QUEUE_SIZE.min(capabilities.max_queue_entries as u16);
+        admin.run(&regs, Command::create_cq(IO_QUEUE_ID, io_size, queue_phys(3)))?;
+        admin.run(&regs, Command::create_sq(IO_QUEUE_ID, io_size, queue_phys(2), IO_QUEUE_ID))?;
+        let io = QueuePair {
+            sq: unsafe { SubmissionQueue::new(queue_ptr(2), io_size) },
+            cq: unsafe { CompletionQueue::new(queue_ptr(3), io_size) },
+            sq_doorbell: capabilities.doorbell(IO_QUEUE_ID, false),
+            cq_doorbell: capabilities.doorbell(IO_QUEUE_ID, true),
+            next_cid: 0,
+        };
+
+        Ok(NvmeDisk {
+            regs,
+            admin,
+            io,
+            _queue_memory: queue_memory,
+            bounce,
+            prp_list,
+            nsid,
+            sectors: namespace.blocks,
+            max_sectors,
+            model,
+        })
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn namespace(&self) -> u32 {
+        self.nsid
+    }
+
+    pub fn version(&self) -> u32 {
+        self.regs.read32(REG_VS)
+    }
+
+    /// Runs an admin command, for commands the driver doesn't wrap.
+    pub fn admin_command(&mut self, command: Command) -> Result<Completion, NvmeError> {
+        self.admin.run(&self.regs, command)
+    }
+
+    fn transfer(&mut self, opcode: u8, lba: u64, sectors: usize) -> Result<(), BlockError> {
+        let list = unsafe { core::slice::from_raw_parts_mut(self.prp_list.as_mut_ptr() as *mut u64, PAGE_SIZE / 8) };
+        let prps = build_prps(self.bounce.phys_addr().as_u64(), sectors * SECTOR_SIZE, list,
+            self.prp_list.phys_addr().as_u64()).ok_or(BlockError::BufferSize)?;
+        let command = Command::io(opcode, self.nsid, lba, sectors as u16, prps);
+        self.io.run(&self.regs, command)?;
+        Ok(())
+    }
+
+    fn data_area(&mut self, sectors: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.bounce.as_mut_ptr(), sectors * SECTOR_SIZE) }
+    }
+}
+
+/// The parts of IDENTIFY NAMESPACE we use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Namespace {
+    pub blocks: u64,
+    pub lba_size: u32,
+}
+
+impl Namespace {
+    pub fn parse(data: &[u8]) -> Option<Namespace> {
+        let blocks = u64::from_le_bytes(data.get(0..8)?.try_into().ok()?);
+        // FLBAS picks the LBA format in use
+        let format = usize::from(*data.get(26)? & 0xf);
+        let lba_format = data.get(128 + 4 * format..132 + 4 * format)?;
+        let lba_size = 1u32.checked_shl(u32::from(lba_format[2]))?;
+        Some(Namespace { blocks, lba_size })
+    }
+}
+
+impl BlockDevice for NvmeDisk {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self.sectors, lba, buf.len())?;
+        let per_command = self.max_sectors;
+        for (i, chunk) in buf.chunks_mut(per_command * SECTOR_SIZE).enumerate() {
+            let sectors = chunk.len() / SECTOR_SIZE;
+            self.transfer(IO_READ, lba + (i * per_command) as u64, sectors)?;
+            chunk.copy_from_slice(self.data_area(sectors));
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self.sectors, lba, buf.len())?;
+        let per_command = self.max_sectors;
+        for (i, chunk) in buf.chunks(per_command * SECTOR_SIZE).enumerate() {
+            let sectors = chunk.len() / SECTOR_SIZE;
+            self.data_area(sectors).copy_from_slice(chunk);
+            self.transfer(IO_WRITE, lba + (i * per_command) as u64, sectors)?;
+        }
+        Ok(())
+    }
+}
+
+/// NVMe disks found by `init`.
+pub static DEVICES: Mutex<Vec<NvmeDisk>> = Mutex::new(Vec::new());
+
+/// Brings up every NVMe controller on the PCI bus. Returns how many disks
+/// are ready; a controller that fails is reported and skipped.
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    physical_memory_offset: VirtAddr,
+) -> Result<usize, NvmeError> {
+    let controllers: Vec<_> = pci::find_class(CLASS_MASS_STORAGE, SUBCLASS_NVME).copied().collect();
+    if controllers.is_empty() {
+        return Err(NvmeError::NoController);
+    }
+    let mut devices = DEVICES.lock();
+    for controller in controllers {
+        let result = map_registers(&controller, mapper, frame_allocator)
+            .and_then(|regs| NvmeDisk::new(regs, frame_allocator, physical_memory_offset));
+        match result {
+            Ok(disk) => {
+                crate::block::register("nvme", crate::block::Disk::Nvme(devices.len()));
+                devices.push(disk);
+            }
+            Err(err) => crate::println!("NVMe {:02x}:{:02x}.{}: {}",
+                controller.bus, controller.device, controller.function, err),
+        }
+    }
+    Ok(devices.len())
+}
+
+/// Maps the registers and the doorbells of the admin and I/O queues.
+fn map_registers(
+    controller: &pci::PciDevice,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<Registers, NvmeError> {
+    let bar = match controller.bar(0).ok_or(NvmeError::NoBar)? {
+        Bar::Memory { address, .. } => address,
+        Bar::Io(_) => return Err(NvmeError::NoBar),
+    };
+    controller.enable_bus_mastering();
+    let map = |offset: u64, mapper: &mut OffsetPageTable, frame_allocator: &mut BootInfoFrameAllocator| {
+        crate::memory::map_mmio(PhysAddr::new(bar + offset), mapper, frame_allocator).map_err(|_| NvmeError::MapFailed)
+    };
+    let base = map(0, mapper, frame_allocator)?;
+    let capabilities = Capabilities::parse(Registers { base }.read64(REG_CAP));
+    let last_doorbell = capabilities.doorbell(IO_QUEUE_ID, true);
+    for offset in (PAGE_SIZE as u64..=last_doorbell).step_by(PAGE_SIZE) {
+        map(offset, mapper, frame_allocator)?;
+    }
+    Ok(Registers { base })
+}
+
+pub fn print_summary() {
+    for disk in DEVICES.lock().iter() {
+        let version = disk.version();
+        crate::println!("NVMe {}.{}: {} namespace {} ({} sectors)",
+            version >> 16, (version >> 8) & 0xff, disk.model(), disk.namespace(), disk.sector_count());
+    }
+}
+
+//test case
+#[test_case]
+fn test_capabilities() {
+    // MQES 2047, TO 15 (7.5 s), DSTRD 0
+    let caps = Capabilities::parse(0x0000_0020_0f00_07ff);
+    assert_eq!(caps.max_queue_entries, 2048);
+    assert_eq!(caps.timeout_ms, 7500);
+    assert_eq!(caps.doorbell_stride, 4);
+    assert_eq!(caps.doorbell(0, false), 0x1000);
+    assert_eq!(caps.doorbell(0, true), 0x1004);
+    assert_eq!(caps.doorbell(1, false), 0x1008);
+    // DSTRD 2: 16 bytes apart
+    assert_eq!(Capabilities::parse(2 << 32).doorbell(1, true), 0x1000 + 3 * 16);
+}
+
+#[test_case]
+fn test_command_encoding() {
+    let mut read = Command::io(IO_READ, 1, 0x1_0000_0002, 8, (0x20_0000, 0x30_0000));
+    read.cid = 0x1234;
+    let bytes = read.to_bytes();
+    assert_eq!(bytes[0], IO_READ);
+    assert_eq!(bytes[2..4], [0x34, 0x12]);
+    assert_eq!(bytes[4..8], [1, 0, 0, 0]);
+    assert_eq!(u64::from_le_bytes(bytes[24..32].try_into().unwrap()), 0x20_0000);
+    assert_eq!(u64::from_le_bytes(bytes[32..40].try_into().unwrap()), 0x30_0000);
+    assert_eq!(bytes[40..52], [2, 0, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0]);
+
+    let cq = Command::create_cq(1, 64, 0x5000);
+    assert_eq!(cq.cdw[0], 63 << 16 | 1);
+    assert_eq!(cq.cdw[1], 1, "contiguous, no interrupts");
+    let sq = Command::create_sq(1, 64, 0x4000, 1);
+    assert_eq!((sq.cdw[0], sq.cdw[1]), (63 << 16 | 1, 1 << 16 | 1));
+    assert_eq!(Command::identify(CNS_CONTROLLER, 0, 0x6000).cdw[0], 1);
+}
+
+#[test_case]
+fn test_prp_building() {
+    let mut list = [0u64; 8];
+    let list_phys = 0x9000;
+    // inside one page
+    assert_eq!(build_prps(0x10_0000, 4096, &mut list, list_phys), Some((0x10_0000, 0)));
+    assert_eq!(build_prps(0x10_0200, 512, &mut list, list_phys), Some((0x10_0200, 0)));
+    // crossing into a second page
+    assert_eq!(build_prps(0x10_0e00, 1024, &mut list, list_phys), Some((0x10_0e00, 0x10_1000)));
+    assert_eq!(build_prps(0x10_0000, 8192, &mut list, list_phys), Some((0x10_0000, 0x10_1000)));
+    // three pages and more need the list
+    assert_eq!(build_prps(0x10_0000, 8193, &mut list, list_phys), Some((0x10_0000, list_phys)));
+    assert_eq!(list[..2], [0x10_1000, 0x10_2000]);
+    assert_eq!(build_prps(0x10_0800, 4 * 4096, &mut list, list_phys), Some((0x10_0800, list_phys)));
+    assert_eq!(list[..4], [0x10_1000, 0x10_2000, 0x10_3000, 0x10_4000]);
+    // more pages than the list holds
+    assert_eq!(build_prps(0x10_0000, 10 * 4096, &mut list, list_phys), None);
+    assert_eq!(build_prps(0x10_0000, 0, &mut list, list_phys), None);
+}
+
+#[test_case]
+fn test_completion_phase() {
+    use alloc::vec;
+
+    const SIZE: u16 = 4;
+    let mut memory = vec![0u8; 16 * usize::from(SIZE)];
+    let mut cq = unsafe { CompletionQueue::new(memory.as_mut_ptr(), SIZE) };
+    let post = |memory: &mut [u8], slot: usize, cid: u16, phase: bool, status: u16| {
+        let entry = &mut memory[16 * slot..16 * slot + 16];
+        entry[8..10].copy_from_slice(&(slot as u16 + 1).to_le_bytes());
+        let dword3 = u32::from(cid) | (u32::from(status) << 1 | u32::from(phase)) << 16;
+        entry[12..16].copy_from_slice(&dword3.to_le_bytes());
+    };
+    // a zeroed queue has phase 0 everywhere: nothing new
+    assert_eq!(cq.pop(), None);
+    for cid in 0..SIZE {
+        post(&mut memory, usize::from(cid), cid, true, 0);
+        let completion = cq.pop().unwrap();
+        assert_eq!((completion.cid, completion.sq_head, completion.status), (cid, cid + 1, 0));
+    }
+    assert_eq!(cq.head(), 0);
+    // after the wrap the old phase-1 entries don't count
+    assert_eq!(cq.pop(), None);
+    post(&mut memory, 0, 9, false, 0x281);
+    let completion = cq.pop().unwrap();
+    assert_eq!((completion.cid, completion.phase, completion.status), (9, false, 0x281));
+    assert_eq!(cq.pop(), None);
+}
+
+#[test_case]
+fn test_submission_queue_fills() {
+    use alloc::vec;
+
+    let mut memory = vec![0u8; 64 * 4];
+    let mut sq = unsafe { SubmissionQueue::new(memory.as_mut_ptr(), 4) };
+    let command = Command::identify(CNS_NAMESPACE, 1, 0x1000);
+    assert_eq!(sq.push(&command), Some(1));
+    assert_eq!(sq.push(&command), Some(2));
+    assert_eq!(sq.push(&command), Some(3));
+    // one slot always stays empty
+    assert_eq!(sq.push(&command), None);
+    sq.set_head(2);
+    assert_eq!(sq.push(&command), Some(0));
+    assert_eq!(memory[0], ADMIN_IDENTIFY);
+    assert_eq!(memory[4], 1);
+}
+
+#[test_case]
+fn test_namespace_parsing() {
+    let mut data = [0u8; 4096];
+    data[0..8].copy_from_slice(&0x20_0000u64.to_le_bytes());
+    data[26] = 1;
+    // format 0 is 4 KiB, format 1 (in use) 512 bytes
+    data[128 + 2] = 12;
+    data[132 + 2] = 9;
+    assert_eq!(Namespace::parse(&data), Some(Namespace { blocks: 0x20_0000, lba_size: 512 }));
+    data[26] = 0;
+    assert_eq!(Namespace::parse(&data).unwrap().lba_size, 4096);
+    assert_eq!(Namespace::parse(&data[..100]), None);
+}
@@ -0,0 +1,247 @@
+//! What the kernel does once a panic has been reported: `panic=halt` (the
+//! default) stops in `hlt_loop`, `panic=reboot:N` counts down N seconds
+//! and reboots unless a key is pressed first.
+//!
+//! By then interrupts may be off, the timer dead and any lock held by
+//! whoever panicked, so the countdown waits on PIT channel 2, reads the
+//! keyboard controller directly and only ever `try_lock`s the VGA writer;
+//! serial output goes straight to the UART.
+
+use core::fmt::{self, Write};
+use spin::Once;
+use crate::{debug, keyboard, power, serial, time, vga_buffer};
+
+/// Seconds `panic=reboot` waits without an explicit count.
+pub const DEFAULT_REBOOT_SECS: u32 = 10;
+/// Keyboard polls per second of countdown.
+const POLLS_PER_SEC: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicPolicy {
+    Halt,
+    /// Reboot after a countdown of this many seconds.
+    Reboot(u32),
+}
+
+impl PanicPolicy {
+    /// Parses `halt`, `reboot` or `reboot:N`.
+    pub fn parse(text: &str) -> Option<PanicPolicy> {
+        match text.split_once(':') {
+            None if text == "halt" => Some(PanicPolicy::Halt),
+            None if text == "reboot" => Some(PanicPolicy::Reboot(DEFAULT_REBOOT_SECS)),
+            Some(("reboot", secs)) => secs.parse().ok().map(PanicPolicy::Reboot),
+            _ => None,
+        }
+    }
+}
+
+static POLICY: Once<PanicPolicy> = Once::new();
+
+/// Reads `panic=` from the command line; halting when it is missing or
+/// unknown.
+pub fn init() {
+    let policy = match crate::cmdline::get("panic") {
+        None => PanicPolicy::Halt,
+        Some(text) => PanicPolicy::parse(text).unwrap_or_else(|| {
+            crate::warn!("panic: unknown policy '{}', halting on panic", text);
+            PanicPolicy::Halt
+        }),
+    };
+    POLICY.call_once(|| policy);
+}
+
+pub fn policy() -> PanicPolicy {
+    POLICY.get().copied().unwrap_or(PanicPolicy::Halt)
+}
+
+/// A scancode that means a key went down: not a release (bit 7), a
+/// prefix or a controller reply, which all have bit 7 set too.
+pub fn is_key_press(scancode: u8) -> bool {
+    scancode != 0 && scancode & 0x80 == 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Countdown {
+    /// Nobody pressed a key.
+    Elapsed,
+    /// A key was pressed; stay halted.
+    Cancelled,
+    /// The PIT did not answer, so there was no way to wait.
+    NoTimer,
+}
+
+/// What the countdown runs on, so it can be driven by fakes in tests.
+pub trait CountdownIo {
+    /// The next scancode from the keyboard, if any.
+    fn poll_key(&mut self) -> Option<u8>;
+    /// Waits `ms` milliseconds; `false` if it could not.
+    fn delay_ms(&mut self, ms: u32) -> bool;
+    /// Shows the seconds left.
+    fn show(&mut self, secs_left: u32);
+}
+
+/// Counts `secs` seconds down, showing each one, until a key is pressed.
+pub fn countdown(secs: u32, io: &mut dyn CountdownIo) -> Countdown {
+    for left in (1..=secs).rev() {
+        io.show(left);
+        for _ in 0..POLLS_PER_SEC {
+            if io.poll_key().is_some_and(is_key_press) {
+                return Countdown::Cancelled;
+            }
+            if !io.delay_ms(1000 / POLLS_PER_SEC) {
+                return Countdown::NoTimer;
+            }
+        }
+    }
+    Countdown::Elapsed
+}
+
+/// Console output that needs no lock: VGA if nobody holds the writer,
+/// serial through the raw UART.
+struct RawConsole;
+
+impl Write for RawConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(mut writer) = vga_buffer::WRITER.try_lock() {
+            let _ = writer.write_str(s);
+        }
+        s.bytes().for_each(serial::write_byte_raw);
+        Ok(())
+    }
+}
+
+struct HardwareCountdown;
+
+impl CountdownIo for HardwareCountdown {
+    fn poll_key(&mut self) -> Option<u8> {
+        keyboard::poll_raw()
+    }
+
+    fn delay_ms(&mut self, ms: u32) -> bool {
+        time::pit_delay_us(u64::from(ms) * 1000)
+    }
+
+    fn show(&mut self, secs_left: u32) {
+        let _ = write!(RawConsole, "{}.. ", secs_left);
+    }
+}
+
+/// The rest of the panic report, after the message: `registers` (as the
+/// panic handler found them) and the backtrace, on the screen and COM1
+/// both, whatever `console=` says.
+pub fn report(registers: &debug::Registers) {
+    let _ = writeln!(RawConsole, "{}", registers);
+    let _ = debug::write_backtrace(&mut RawConsole);
+}
+
+/// Carries out the policy after the panic report has been printed.
+pub fn finish() -> ! {
+    x86_64::instructions::interrupts::disable();
+    serial::flush();
+    let PanicPolicy::Reboot(secs) = policy() else {
+        crate::hlt_loop();
+    };
+    // whatever was typed before the panic does not count
+    while keyboard::poll_raw().is_some() {}
+    let _ = writeln!(RawConsole, "rebooting in {}s, press any key to halt instead", secs);
+    match countdown(secs, &mut HardwareCountdown) {
+        Countdown::Cancelled => {
+            let _ = writeln!(RawConsole, "\nreboot cancelled, halting");
+            crate::hlt_loop();
+        }
+        Countdown::NoTimer => {
+            let _ = writeln!(RawConsole, "\nno timer for the countdown, rebooting now");
+        }
+        Countdown::Elapsed => {
+            let _ = writeln!(RawConsole);
+        }
+    }
+    serial::flush();
+    power::reboot();
+}
+
+//test case
+#[cfg(test)]
+struct FakeCountdown {
+    /// Scancodes returned by successive polls; `None` after the last.
+    keys: alloc::vec::Vec<Option<u8>>,
+    polls: usize,
+    waited_ms: u32,
+    /// Delays that succeed before the timer "dies".
+    delays_left: Option<u32>,
+    shown: alloc::vec::Vec<u32>,
+}
+
+#[cfg(test)]
+impl FakeCountdown {
+    fn new(keys: &[Option<u8>]) -> Self {
+        FakeCountdown { keys: keys.to_vec(), polls: 0, waited_ms: 0, delays_left: None, shown: alloc::vec::Vec::new() }
+    }
+}
+
+#[cfg(test)]
+impl CountdownIo for FakeCountdown {
+    fn poll_key(&mut self) -> Option<u8> {
+        self.polls += 1;
+        self.keys.get(self.polls - 1).copied().flatten()
+    }
+
+    fn delay_ms(&mut self, ms: u32) -> bool {
+        match &mut self.delays_left {
+            Some(0) => return false,
+            Some(left) => *left -= 1,
+            None => {}
+        }
+        self.waited_ms += ms;
+        true
+    }
+
+    fn show(&mut self, secs_left: u32) {
+        self.shown.push(secs_left);
+    }
+}
+
+#[test_case]
+fn test_policy_parsing() {
+    assert_eq!(PanicPolicy::parse("halt"), Some(PanicPolicy::Halt));
+    assert_eq!(PanicPolicy::parse("reboot"), Some(PanicPolicy::Reboot(DEFAULT_REBOOT_SECS)));
+    assert_eq!(PanicPolicy::parse("reboot:3"), Some(PanicPolicy::Reboot(3)));
+    assert_eq!(PanicPolicy::parse("reboot:0"), Some(PanicPolicy::Reboot(0)));
+    for bad in ["", "reboot:", "reboot:-1", "reboot:ten", "halt:5", "poweroff", "Reboot"] {
+        assert_eq!(PanicPolicy::parse(bad), None, "{}", bad);
+    }
+    // no panic= on the test command line
+    assert_eq!(policy(), PanicPolicy::Halt);
+}
+
+#[test_case]
+fn test_countdown_runs_out() {
+    let mut io = FakeCountdown::new(&[]);
+    assert_eq!(countdown(3, &mut io), Countdown::Elapsed);
+    assert_eq!(io.shown, [3, 2, 1]);
+    assert_eq!(io.waited_ms, 3000);
+    assert_eq!(countdown(0, &mut FakeCountdown::new(&[])), Countdown::Elapsed);
+}
+
+#[test_case]
+fn test_key_press_cancels() {
+    // releases, an extended prefix and an ACK do not count, the 'A' does
+    let mut io = FakeCountdown::new(&[None, Some(0x9e), Some(0xe0), Some(0xfa), None, Some(0x1e)]);
+    assert_eq!(countdown(10, &mut io), Countdown::Cancelled);
+    assert_eq!(io.polls, 6);
+    assert_eq!(io.shown, [10]);
+    assert_eq!(io.waited_ms, 5 * 1000 / POLLS_PER_SEC);
+
+    let mut late = FakeCountdown::new(&[None; 45]);
+    late.keys.push(Some(0x39));
+    assert_eq!(countdown(5, &mut late), Countdown::Cancelled);
+    assert_eq!(late.shown, [5, 4, 3]);
+}
+
+#[test_case]
+fn test_dead_timer_reboots_at_once() {
+    let mut io = FakeCountdown::new(&[]);
+    io.delays_left = Some(2);
+    assert_eq!(countdown(10, &mut io), Countdown::NoTimer);
+    assert_eq!(io.polls, 3);
+}
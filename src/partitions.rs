@@ -0,0 +1,409 @@
+//! MBR and GPT partition tables.
+//!
+//! `scan` reads the MBR in sector 0. A protective entry (type 0xEE) means
+//! the real table is the GPT whose header is in sector 1: both its header
+//! CRC and the CRC of its entry array are checked, and a mismatch is an
+//! error of its own rather than "no table". The entry array is read and
+//! checked a sector at a time, so a large table costs no heap. Extended
+//! MBR partitions are listed as they are; their logical partitions are not
+//! followed. The backup GPT is not consulted.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::bytes::{crc32, crc32_update, le_u32, le_u64, CRC32_INIT};
+
+#[cfg(test)]
+pub mod testdata;
+
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+pub const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+pub const TYPE_FAT16: u8 = 0x06;
+pub const TYPE_FAT32_LBA: u8 = 0x0c;
+pub const TYPE_LINUX: u8 = 0x83;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_LBA: u64 = 1;
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// Entries beyond this are not read; the specification asks for room
+/// for 128 and nothing uses more.
+const GPT_MAX_ENTRIES: u32 = 1024;
+const GPT_NAME_UNITS: usize = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    Io(BlockError),
+    /// Sector 0 holds no partition table.
+    NoTable,
+    /// The GPT header does not match its CRC.
+    BadHeaderCrc,
+    /// The GPT entry array does not match the CRC in the header.
+    BadEntriesCrc,
+    /// The GPT header's fields are out of range.
+    BadHeader,
+    /// This GPT entry reaches outside the usable LBA range.
+    BadEntry(usize),
+}
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            PartitionError::Io(err) => return write!(f, "I/O error: {}", err),
+            PartitionError::NoTable => "no partition table",
+            PartitionError::BadHeaderCrc => "GPT header CRC mismatch",
+            PartitionError::BadEntriesCrc => "GPT partition entry array CRC mismatch",
+            PartitionError::BadHeader => "invalid GPT header",
+            PartitionError::BadEntry(i) => return write!(f, "GPT entry {} is outside the usable range", i),
+        };
+        f.write_str(msg)
+    }
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(err: BlockError) -> Self {
+        PartitionError::Io(err)
+    }
+}
+
+/// A GUID in its on-disk byte order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const EFI_SYSTEM: Guid = Guid::from_fields(0xc12a_7328, 0xf81f, 0x11d2, 0xba4b_00a0_c93e_c93b);
+    pub const BASIC_DATA: Guid = Guid::from_fields(0xebd0_a0a2, 0xb9e5, 0x4433, 0x87c0_68b6_b726_99c7);
+    pub const LINUX_FILESYSTEM: Guid = Guid::from_fields(0x0fc6_3daf, 0x8483, 0x4772, 0x8e79_3d69_d847_7de4);
+
+    /// From the textual groups: the first three are stored little-endian,
+    /// the last eight bytes as written.
+    pub const fn from_fields(a: u32, b: u16, c: u16, d: u64) -> Guid {
+        let (a, b, c, d) = (a.to_le_bytes(), b.to_le_bytes(), c.to_le_bytes(), d.to_be_bytes());
+        Guid([a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let g = &self.0;
+        write!(f, "{:08X}-{:04X}-{:04X}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]), u16::from_le_bytes([g[4], g[5]]), u16::from_le_bytes([g[6], g[7]]))?;
+        for (i, byte) in g[8..].iter().enumerate() {
+            if i == 2 {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// An MBR entry and its type byte.
+    Mbr(u8),
+    Gpt { type_guid: Guid, unique_guid: Guid, attributes: u64, name: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// 1-based position in the table, as in `ata0p1`.
+    pub number: usize,
+    pub start_lba: u64,
+    pub sectors: u64,
+    pub kind: PartitionKind,
+}
+
+/// Reads the partition table of `device`.
+pub fn scan(device: &mut impl BlockDevice) -> Result<Vec<Partition>, PartitionError> {
+    let mut mbr = [0u8; SECTOR_SIZE];
+    device.read_sectors(0, &mut mbr)?;
+    let primary = parse_mbr(&mbr, device.sector_count())?;
+    if primary.iter().any(|p| p.kind == PartitionKind::Mbr(TYPE_GPT_PROTECTIVE)) {
+        return scan_gpt(device);
+    }
+    Ok(primary)
+}
+
+/// The primary entries of an MBR. A sector without the signature, with a
+/// status byte other than 0x00/0x80 or with an entry past the end of the
+/// disk is not an MBR; a FAT boot sector on a superfloppy usually fails
+/// one of those.
+pub fn parse_mbr(sector: &[u8; SECTOR_SIZE], disk_sectors: u64) -> Result<Vec<Partition>, PartitionError> {
+    if sector[510..512] != [0x55, 0xaa] {
+        return Err(PartitionError::NoTable);
+    }
+    let mut partitions = Vec::new();
+    for (i, entry) in sector[MBR_ENTRIES..MBR_ENTRIES + 4 * MBR_ENTRY_SIZE].chunks_exact(MBR_ENTRY_SIZE).enumerate() {
+        if entry[0] != 0x00 && entry[0] != 0x80 {
+            return Err(PartitionError::NoTable);
+        }
+        let kind = entry[4];
+        let start = u64::from(le_u32(entry, 8).unwrap_or(0));
+        let sectors = u64::from(le_u32(entry, 12).unwrap_or(0));
+        if kind == 0 {
+            continue;
+        }
+        // a protective entry covers the disk, or as much as 32 bits reach
+        if kind != TYPE_GPT_PROTECTIVE && (sectors == 0 || start == 0 || start + sectors > disk_sectors) {
+            return Err(PartitionError::NoTable);
+        }
+        partitions.push(Partition { number: i + 1, start_lba: start, sectors, kind: PartitionKind::Mbr(kind) });
+    }
+    if partitions.is_empty() {
+        return Err(PartitionError::NoTable);
+    }
+    Ok(partitions)
+}
+
+/// The header fields `scan_gpt` needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct GptHeader {
+    first_usable: u64,
+    last_usable: u64,
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+fn parse_gpt_header(sector: &[u8; SECTOR_SIZE], disk_sectors: u64) -> Result<GptHeader, PartitionError> {
+    if &sector[..8] != GPT_SIGNATURE {
+        return Err(PartitionError::NoTable);
+    }
+    let field32 = |offset| le_u32(sector, offset).unwrap_or(0);
+    let field64 = |offset| le_u64(sector, offset).unwrap_or(0);
+    let header_size = field32(12) as usize;
+    if !(GPT_MIN_HEADER_SIZE..=SECTOR_SIZE).contains(&header_size) {
+        return Err(PartitionError::BadHeader);
+    }
+    let mut header = [0u8; SECTOR_SIZE];
+    header[..header_size].copy_from_slice(&sector[..header_size]);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != field32(16) {
+        return Err(PartitionError::BadHeaderCrc);
+    }
+
+    let parsed = GptHeader {
+        first_usable: field64(40),
+        last_usable: field64(48),
+        entries_lba: field64(72),
+        entry_count: field32(80),
+        entry_size: field32(84) as usize,
+        entries_crc: field32(88),
+    };
+    let entry_size_ok = parsed.entry_size >= GPT_MIN_ENTRY_SIZE
+        && parsed.entry_size.is_power_of_two()
+        && parsed.entry_size <= SECTOR_SIZE;
+    let array_sectors = (u64::from(parsed.entry_count) * parsed.entry_size as u64).div_ceil(SECTOR_SIZE as u64);
+    if field64(24) != GPT_HEADER_LBA
+        || !entry_size_ok
+        || parsed.entry_count > GPT_MAX_ENTRIES
+        || parsed.first_usable > parsed.last_usable
+        || parsed.last_usable >= disk_sectors
+        || parsed.entries_lba <= GPT_HEADER_LBA
+        || parsed.entries_lba + array_sectors > disk_sectors
+    {
+        return Err(PartitionError::BadHeader);
+    }
+    Ok(parsed)
+}
+
+fn parse_gpt_entry(entry: &[u8], number: usize, header: &GptHeader) -> Result<Option<Partition>, PartitionError> {
+    let guid = |offset: usize| {
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&entry[offset..offset + 16]);
+        Guid(bytes)
+    };
+    let type_guid = guid(0);
+    if type_guid.is_zero() {
+        return Ok(None);
+    }
+    let first = le_u64(entry, 32).unwrap_or(0);
+    let last = le_u64(entry, 40).unwrap_or(0);
+    if first > last || first < header.first_usable || last > header.last_usable {
+        return Err(PartitionError::BadEntry(number));
+    }
+    let units = entry[56..56 + 2 * GPT_NAME_UNITS]
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .take_while(|&unit| unit != 0);
+    Ok(Some(Partition {
+        number,
+        start_lba: first,
+        sectors: last - first + 1,
+        kind: PartitionKind::Gpt {
+            type_guid,
+            unique_guid: guid(16),
+            attributes: le_u64(entry, 48).unwrap_or(0),
+            name: char::decode_utf16(units).map(|c| c.unwrap_or('\u{fffd}')).collect(),
+        },
+    }))
+}
+
+fn scan_gpt(device: &mut impl BlockDevice) -> Result<Vec<Partition>, PartitionError> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    device.read_sectors(GPT_HEADER_LBA, &mut sector)?;
+    let header = parse_gpt_header(&sector, device.sector_count())?;
+
+    let mut partitions = Vec::new();
+    let mut first_bad = None;
+    let mut crc = CRC32_INIT;
+    let mut remaining = header.entry_count as usize * header.entry_size;
+    let mut lba = header.entries_lba;
+    let mut number = 1;
+    while remaining > 0 {
+        device.read_sectors(lba, &mut sector)?;
+        let len = remaining.min(SECTOR_SIZE);
+        crc = crc32_update(crc, &sector[..len]);
+        for entry in sector[..len].chunks_exact(header.entry_size) {
+            // the CRC decides first: a bad entry in a corrupt array is the
+            // array's fault
+            match parse_gpt_entry(entry, number, &header) {
+                Ok(Some(partition)) => partitions.push(partition),
+                Ok(None) => {}
+                Err(err) => first_bad = first_bad.or(Some(err)),
+            }
+            number += 1;
+        }
+        remaining -= len;
+        lba += 1;
+    }
+    if !crc != header.entries_crc {
+        return Err(PartitionError::BadEntriesCrc);
+    }
+    match first_bad {
+        Some(err) => Err(err),
+        None => Ok(partitions),
+    }
+}
+
+/// One partition of a device, as a device of its own: LBA 0 is the
+/// partition's first sector and nothing past its end can be reached.
+pub struct PartitionDevice<D: BlockDevice> {
+    device: D,
+    start: u64,
+    sectors: u64,
+}
+
+impl<D: BlockDevice> PartitionDevice<D> {
+    /// Fails with `OutOfRange` if the partition does not fit the device.
+    pub fn new(device: D, partition: &Partition) -> Result<Self, BlockError> {
+        block::check_request(device.sector_count(), partition.start_lba, (partition.sectors as usize) * SECTOR_SIZE)?;
+        Ok(PartitionDevice { device, start: partition.start_lba, sectors: partition.sectors })
+    }
+
+    /// The whole device, for a filesystem with no partition table around it.
+    pub fn whole(device: D) -> Self {
+        let sectors = device.sector_count();
+        PartitionDevice { device, start: 0, sectors }
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for PartitionDevice<D> {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self.sectors, lba, buf.len())?;
+        self.device.read_sectors(self.start + lba, buf)
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self.sectors, lba, buf.len())?;
+        self.device.write_sectors(self.start + lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+//test case
+#[test_case]
+fn test_guid() {
+    assert_eq!(alloc::format!("{}", Guid::BASIC_DATA), "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7");
+    assert_eq!(Guid::BASIC_DATA.0[..4], [0xa2, 0xa0, 0xd0, 0xeb]);
+    assert!(Guid([0; 16]).is_zero());
+}
+
+#[test_case]
+fn test_mbr() {
+    let mut disk = testdata::mbr_disk(&[(TYPE_FAT32_LBA, 2048, 4096), (TYPE_LINUX, 6144, 2048)]);
+    let partitions = scan(&mut disk).unwrap();
+    assert_eq!(partitions, [
+        Partition { number: 1, start_lba: 2048, sectors: 4096, kind: PartitionKind::Mbr(TYPE_FAT32_LBA) },
+        Partition { number: 2, start_lba: 6144, sectors: 2048, kind: PartitionKind::Mbr(TYPE_LINUX) },
+    ]);
+
+    // not a table: no signature, a blank table, a bad status byte, or an
+    // entry past the end of the disk
+    let mut blank = testdata::mbr_disk(&[]);
+    assert_eq!(scan(&mut blank), Err(PartitionError::NoTable));
+    blank.patch(0, 510, &[0, 0]);
+    assert_eq!(scan(&mut blank), Err(PartitionError::NoTable));
+    let mut status = testdata::mbr_disk(&[(TYPE_FAT16, 63, 100)]);
+    status.patch(0, MBR_ENTRIES, &[0x12]);
+    assert_eq!(scan(&mut status), Err(PartitionError::NoTable));
+    let mut past_end = testdata::mbr_disk(&[(TYPE_FAT16, 63, testdata::DISK_SECTORS)]);
+    assert_eq!(scan(&mut past_end), Err(PartitionError::NoTable));
+}
+
+#[test_case]
+fn test_gpt() {
+    let entries = [
+        (Guid::EFI_SYSTEM, 2048, 4095, "EFI system"),
+        (Guid::BASIC_DATA, 4096, 8191, "data"),
+    ];
+    let mut disk = testdata::gpt_disk(&entries);
+    let partitions = scan(&mut disk).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert_eq!((partitions[1].number, partitions[1].start_lba, partitions[1].sectors), (2, 4096, 4096));
+    match &partitions[1].kind {
+        PartitionKind::Gpt { type_guid, unique_guid, name, .. } => {
+            assert_eq!(*type_guid, Guid::BASIC_DATA);
+            assert_eq!(*unique_guid, testdata::unique_guid(2));
+            assert_eq!(name, "data");
+        }
+        kind => panic!("GPT partition parsed as {:?}", kind),
+    }
+
+    // one flipped byte in an entry breaks the array CRC, not the header's
+    let mut corrupt = testdata::gpt_disk(&entries);
+    corrupt.patch(2, 128 + 33, &[0x11]);
+    assert_eq!(scan(&mut corrupt), Err(PartitionError::BadEntriesCrc));
+    let mut bad_header = testdata::gpt_disk(&entries);
+    bad_header.patch(1, 40, &[0x01]);
+    assert_eq!(scan(&mut bad_header), Err(PartitionError::BadHeaderCrc));
+    // a consistent table whose entry overlaps the header area
+    let mut overlap = testdata::gpt_disk(&[(Guid::BASIC_DATA, 1, 100, "x")]);
+    assert_eq!(scan(&mut overlap), Err(PartitionError::BadEntry(1)));
+    let mut missing = testdata::mbr_disk(&[(TYPE_GPT_PROTECTIVE, 1, testdata::DISK_SECTORS - 1)]);
+    assert_eq!(scan(&mut missing), Err(PartitionError::NoTable));
+}
+
+#[test_case]
+fn test_partition_device() {
+    let mut disk = testdata::mbr_disk(&[(TYPE_FAT16, 100, 10)]);
+    disk.patch(105, 0, b"inside");
+    disk.patch(110, 0, b"outside");
+    let partition = scan(&mut disk).unwrap().remove(0);
+    let mut device = PartitionDevice::new(disk, &partition).unwrap();
+    assert_eq!(device.sector_count(), 10);
+    let mut buf = [0u8; SECTOR_SIZE];
+    device.read_sectors(5, &mut buf).unwrap();
+    assert_eq!(&buf[..6], b"inside");
+    assert_eq!(device.read_sectors(10, &mut buf), Err(BlockError::OutOfRange));
+    assert_eq!(device.read_sectors(9, &mut [0u8; 2 * SECTOR_SIZE]), Err(BlockError::OutOfRange));
+    device.write_sectors(0, &[0x77; SECTOR_SIZE]).unwrap();
+
+    let too_big = Partition { number: 1, start_lba: testdata::DISK_SECTORS - 1, sectors: 2, kind: PartitionKind::Mbr(TYPE_FAT16) };
+    assert!(PartitionDevice::new(testdata::mbr_disk(&[]), &too_big).is_err());
+}
@@ -0,0 +1,70 @@
+//! Sparse disk images with an MBR or a GPT written by hand.
+
+use alloc::vec;
+use crate::block::SECTOR_SIZE;
+use crate::bytes::crc32;
+use super::{Guid, GPT_MIN_HEADER_SIZE, MBR_ENTRIES, MBR_ENTRY_SIZE, TYPE_GPT_PROTECTIVE};
+
+pub use crate::block::testdata::RamDisk;
+
+/// 8 MiB.
+pub const DISK_SECTORS: u64 = 16384;
+const ENTRY_COUNT: u32 = 128;
+const ENTRY_SIZE: usize = 128;
+/// The entry array fills sectors 2 through 33, as most tools leave it.
+const ARRAY_SECTORS: u64 = (ENTRY_COUNT as u64 * ENTRY_SIZE as u64) / SECTOR_SIZE as u64;
+
+/// An MBR with up to four `(type, start, sectors)` entries.
+pub fn mbr_disk(entries: &[(u8, u64, u64)]) -> RamDisk {
+    let mut disk = RamDisk::new(DISK_SECTORS);
+    for (i, &(kind, start, sectors)) in entries.iter().enumerate() {
+        let offset = MBR_ENTRIES + i * MBR_ENTRY_SIZE;
+        disk.patch(0, offset + 4, &[kind]);
+        disk.patch(0, offset + 8, &(start as u32).to_le_bytes());
+        disk.patch(0, offset + 12, &(sectors as u32).to_le_bytes());
+    }
+    disk.patch(0, 510, &[0x55, 0xaa]);
+    disk
+}
+
+pub fn unique_guid(number: usize) -> Guid {
+    Guid::from_fields(0x6265_7272, 0x7900, 0x4000, 0x8000_0000_0000_0000 | number as u64)
+}
+
+/// A protective MBR and a primary GPT with `(type, first, last, name)`
+/// entries, both CRCs correct.
+pub fn gpt_disk(entries: &[(Guid, u64, u64, &str)]) -> RamDisk {
+    let mut disk = mbr_disk(&[(TYPE_GPT_PROTECTIVE, 1, DISK_SECTORS - 1)]);
+    let mut array = vec![0u8; ENTRY_COUNT as usize * ENTRY_SIZE];
+    for (i, &(type_guid, first, last, name)) in entries.iter().enumerate() {
+        let entry = &mut array[i * ENTRY_SIZE..(i + 1) * ENTRY_SIZE];
+        entry[..16].copy_from_slice(&type_guid.0);
+        entry[16..32].copy_from_slice(&unique_guid(i + 1).0);
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        for (j, unit) in name.encode_utf16().enumerate() {
+            entry[56 + 2 * j..58 + 2 * j].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    for (i, sector) in array.chunks_exact(SECTOR_SIZE).enumerate() {
+        disk.patch(2 + i as u64, 0, sector);
+    }
+
+    let mut header = [0u8; GPT_MIN_HEADER_SIZE];
+    header[..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&(GPT_MIN_HEADER_SIZE as u32).to_le_bytes());
+    header[24..32].copy_from_slice(&1u64.to_le_bytes());
+    header[32..40].copy_from_slice(&(DISK_SECTORS - 1).to_le_bytes());
+    header[40..48].copy_from_slice(&(2 + ARRAY_SECTORS).to_le_bytes());
+    header[48..56].copy_from_slice(&(DISK_SECTORS - 2 - ARRAY_SECTORS).to_le_bytes());
+    header[56..72].copy_from_slice(&unique_guid(0).0);
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&ENTRY_COUNT.to_le_bytes());
+    header[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+    header[88..92].copy_from_slice(&crc32(&array).to_le_bytes());
+    let crc = crc32(&header);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    disk.patch(1, 0, &header);
+    disk
+}
@@ -0,0 +1,277 @@
+//! PCI configuration space access (mechanism #1, ports 0xCF8/0xCFC) and a
+//! brute-force bus scan.
+//!
+//! `devices` is the list drivers look through: the bus is scanned the
+//! first time it is asked for, and nothing is hot-plugged afterwards.
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// Command register bits.
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// Value written to CONFIG_ADDRESS to select a dword of config space.
+pub fn config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    0x8000_0000
+        | (u32::from(bus) << 16)
+        | (u32::from(device & 0x1f) << 11)
+        | (u32::from(function & 0x7) << 8)
+        | u32::from(offset & 0xfc)
+}
+
+pub fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    unsafe {
+        Port::new(CONFIG_ADDRESS).write(config_address(bus, device, function, offset));
+        Port::new(CONFIG_DATA).read()
+    }
+}
+
+pub fn write_config(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    unsafe {
+        Port::new(CONFIG_ADDRESS).write(config_address(bus, device, function, offset));
+        Port::new(CONFIG_DATA).write(value);
+    }
+}
+
+/// A decoded Base Address Register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io(u16),
+    Memory { address: u64, prefetchable: bool, is_64bit: bool },
+}
+
+/// Decodes BAR `index` from the raw BAR dwords of a type 0 header. A 64-bit
+/// memory BAR consumes the following dword as its upper half.
+pub fn decode_bar(raw: &[u32; 6], index: usize) -> Option<Bar> {
+    let low = raw[index];
+    if low == 0 {
+        return None;
+    }
+    if low & 1 == 1 {
+        return Some(Bar::Io((low & 0xffff_fffc) as u16));
+    }
+    let prefetchable = low & 0x8 != 0;
+    match (low >> 1) & 0x3 {
+        0x0 => Some(Bar::Memory {
+            address: u64::from(low & 0xffff_fff0),
+            prefetchable,
+            is_64bit: false,
+        }),
+        0x2 => {
+            let high = *raw.get(index + 1)?;
+            Some(Bar::Memory {
+                address: (u64::from(high) << 32) | u64::from(low & 0xffff_fff0),
+                prefetchable,
+                is_64bit: true,
+            })
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    pub raw_bars: [u32; 6],
+}
+
+impl PciDevice {
+    fn probe(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
+        let id = read_config(bus, device, function, 0x00);
+        let vendor_id = id as u16;
+        if vendor_id == 0xffff {
+            return None;
+        }
+        let class = read_config(bus, device, function, 0x08);
+        let header = read_config(bus, device, function, 0x0c);
+        let header_type = (header >> 16) as u8;
+        let mut raw_bars = [0u32; 6];
+        if header_type & 0x7f == 0 {
+            for (i, bar) in raw_bars.iter_mut().enumerate() {
+                *bar = read_config(bus, device, function, 0x10 + 4 * i as u8);
+            }
+        }
+        Some(PciDevice {
+            bus,
+            device,
+            function,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            header_type,
+            interrupt_line: read_config(bus, device, function, 0x3c) as u8,
+            raw_bars,
+        })
+    }
+
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        decode_bar(&self.raw_bars, index)
+    }
+
+    pub fn read(&self, offset: u8) -> u32 {
+        read_config(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn write(&self, offset: u8, value: u32) {
+        write_config(self.bus, self.device, self.function, offset, value)
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read(0x04) as u16
+    }
+
+    /// Sets bits in the command register (the status half is left alone
+    /// because its bits are write-one-to-clear).
+    pub fn enable(&self, bits: u16) {
+        let command = self.command() | bits;
+        self.write(0x04, u32::from(command));
+    }
+
+    pub fn enable_bus_mastering(&self) {
+        self.enable(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+    }
+}
+
+impl fmt::Display for PciDevice {
+    /// One `lspci` line: `bus:device.function class: vendor:device name`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{} {:02x}{:02x}: {:04x}:{:04x} {}",
+            self.bus, self.device, self.function, self.class, self.subclass,
+            self.vendor_id, self.device_id, class_name(self.class, self.subclass))
+    }
+}
+
+/// What a class code means, as far as the kernel cares; the subclass only
+/// for the classes it has drivers or users for.
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVM controller",
+        (0x01, _) => "Storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, _) => "Display controller",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "Serial bus controller",
+        _ => "Unclassified device",
+    }
+}
+
+/// Enumerates every function on every bus.
+pub fn scan() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255u8 {
+        for device in 0..32u8 {
+            let first = match PciDevice::probe(bus, device, 0) {
+                Some(first) => first,
+                None => continue,
+            };
+            let multifunction = first.header_type & 0x80 != 0;
+            devices.push(first);
+            if multifunction {
+                devices.extend((1..8).filter_map(|function| PciDevice::probe(bus, device, function)));
+            }
+        }
+    }
+    devices
+}
+
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+
+/// Every function on the bus, from a scan made on the first call.
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.call_once(scan)
+}
+
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    devices()
+        .iter()
+        .copied()
+        .find(|d| d.vendor_id == vendor_id && d.device_id == device_id)
+}
+
+/// The functions with class code `class` and `subclass`, in bus order.
+pub fn find_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static PciDevice> {
+    devices().iter().filter(move |d| d.class == class && d.subclass == subclass)
+}
+
+//test case
+#[test_case]
+fn test_config_address() {
+    assert_eq!(config_address(0, 0, 0, 0), 0x8000_0000);
+    assert_eq!(config_address(1, 2, 3, 0x10), 0x8001_1310);
+    // low two offset bits are not part of the address
+    assert_eq!(config_address(0, 31, 7, 0xff), 0x8000_fffc);
+}
+
+#[test_case]
+fn test_decode_bars() {
+    let raw = [0xc041, 0xfebf_1000, 0xfe00_000c, 0x0000_0001, 0, 0];
+    assert_eq!(decode_bar(&raw, 0), Some(Bar::Io(0xc040)));
+    assert_eq!(
+        decode_bar(&raw, 1),
+        Some(Bar::Memory { address: 0xfebf_1000, prefetchable: false, is_64bit: false })
+    );
+    assert_eq!(
+        decode_bar(&raw, 2),
+        Some(Bar::Memory { address: 0x1_fe00_0000, prefetchable: true, is_64bit: true })
+    );
+    assert_eq!(decode_bar(&raw, 4), None);
+}
+
+#[test_case]
+fn test_lspci_line() {
+    let device = PciDevice {
+        bus: 0,
+        device: 3,
+        function: 0,
+        vendor_id: 0x10ec,
+        device_id: 0x8139,
+        class: 0x02,
+        subclass: 0x00,
+        prog_if: 0,
+        header_type: 0,
+        interrupt_line: 11,
+        raw_bars: [0; 6],
+    };
+    assert_eq!(alloc::format!("{}", device), "00:03.0 0200: 10ec:8139 Ethernet controller");
+    assert_eq!(class_name(0x01, 0x08), "NVM controller");
+    assert_eq!(class_name(0xff, 0x00), "Unclassified device");
+}
+
+#[test_case]
+fn test_devices_are_scanned_once() {
+    let devices = devices();
+    assert!(core::ptr::eq(devices, self::devices()));
+    // QEMU's q35 and i440fx both have a host bridge at 00:00.0
+    assert_eq!(find_class(0x06, 0x00).next().map(|d| (d.bus, d.device)), Some((0, 0)));
+    assert_eq!(devices.len(), scan().len());
+}
@@ -0,0 +1,166 @@
+//! Per-CPU data, reached through the GS base.
+//!
+//! Every CPU has a `PerCpu` block in `CPUS`, and while it runs kernel code
+//! IA32_GS_BASE holds the block's address. The block starts with a pointer
+//! to itself, so `current` is a single GS-relative load. In ring 3 the
+//! program's GS base is live instead and the kernel's waits in
+//! IA32_KERNEL_GS_BASE: each way into the kernel from ring 3 (`syscall`,
+//! and the interrupt stubs, which look at the interrupted CS) starts with
+//! `swapgs`, and each way back ends with it.
+//!
+//! Variables that just need one slot per CPU are declared with `per_cpu!`
+//! rather than added to `PerCpu`.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use crate::cpu::{self, IA32_GS_BASE, IA32_KERNEL_GS_BASE};
+
+/// CPUs with per-CPU data; `smp` starts no more than this.
+pub const MAX_CPUS: usize = 16;
+
+#[repr(C)]
+pub struct PerCpu {
+    /// The block's own address, at offset 0 for `current`.
+    this: AtomicU64,
+    index: AtomicUsize,
+    apic_id: AtomicU32,
+    // what `syscall_entry` reads and writes through GS
+    pub(crate) syscall_kernel_rsp: AtomicU64,
+    pub(crate) syscall_user_rsp: AtomicU64,
+    pub(crate) user_cs: AtomicU64,
+    pub(crate) user_ss: AtomicU64,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            this: AtomicU64::new(0),
+            index: AtomicUsize::new(0),
+            apic_id: AtomicU32::new(0),
+            syscall_kernel_rsp: AtomicU64::new(0),
+            syscall_user_rsp: AtomicU64::new(0),
+            user_cs: AtomicU64::new(0),
+            user_ss: AtomicU64::new(0),
+        }
+    }
+
+    /// 0 for the bootstrap processor, then in the order `smp` started them.
+    pub fn index(&self) -> usize {
+        self.index.load(Ordering::Relaxed)
+    }
+
+    /// The initial APIC id CPUID reports.
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn address(&self) -> u64 {
+        self as *const PerCpu as u64
+    }
+}
+
+static CPUS: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+/// Set once the boot CPU has run `init`; the other CPUs run it before
+/// any code that could ask.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Points this CPU's GS base at block `index` and clears the user GS base.
+/// Runs once per CPU, before its first interrupt.
+pub fn init(index: usize) {
+    let cpu = &CPUS[index];
+    cpu.index.store(index, Ordering::Relaxed);
+    let apic_id = core::arch::x86_64::__cpuid(1).ebx >> 24;
+    cpu.apic_id.store(apic_id, Ordering::Relaxed);
+    // `online` takes a set `this` to mean the rest is filled in
+    cpu.this.store(cpu.address(), Ordering::Release);
+    unsafe {
+        cpu::wrmsr(IA32_GS_BASE, cpu.address());
+        cpu::wrmsr(IA32_KERNEL_GS_BASE, 0);
+    }
+    READY.store(true, Ordering::Release);
+}
+
+/// This CPU's block. Only valid in kernel code after `init`.
+pub fn current() -> &'static PerCpu {
+    let this: u64;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly));
+        &*(this as *const PerCpu)
+    }
+}
+
+/// This CPU's index, or `None` this early in boot, for code that may run
+/// before `init`.
+pub fn try_index() -> Option<usize> {
+    READY.load(Ordering::Acquire).then(|| current().index())
+}
+
+/// The blocks of the CPUs that have run `init`, by index.
+pub fn online() -> impl Iterator<Item = &'static PerCpu> {
+    CPUS.iter().filter(|cpu| cpu.this.load(Ordering::Acquire) != 0)
+}
+
+/// One `T` per CPU; declared with `per_cpu!`.
+pub struct PerCpuVar<T> {
+    slots: [T; MAX_CPUS],
+}
+
+impl<T> PerCpuVar<T> {
+    pub const fn new(slots: [T; MAX_CPUS]) -> Self {
+        PerCpuVar { slots }
+    }
+
+    /// This CPU's slot.
+    pub fn get(&self) -> &T {
+        &self.slots[current().index()]
+    }
+
+    /// Every CPU's slot, by CPU index, for totals.
+    pub fn all(&self) -> &[T; MAX_CPUS] {
+        &self.slots
+    }
+}
+
+/// Declares a static with one value per CPU, each starting as `init`:
+///
+/// ```ignore
+/// per_cpu! {
+///     static WAKEUPS: AtomicU64 = AtomicU64::new(0);
+/// }
+/// WAKEUPS.get().fetch_add(1, Ordering::Relaxed);
+/// ```
+#[macro_export]
+macro_rules! per_cpu {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::percpu::PerCpuVar<$ty> =
+            $crate::percpu::PerCpuVar::new([const { $init }; $crate::percpu::MAX_CPUS]);
+    };
+}
+
+//test case
+#[test_case]
+fn test_current_is_the_bsp() {
+    let cpu = current();
+    assert_eq!(cpu.index(), 0);
+    assert_eq!(cpu.address(), CPUS[0].address());
+    assert_eq!(unsafe { cpu::rdmsr(IA32_GS_BASE) }, cpu.address());
+    assert_eq!(cpu.apic_id(), core::arch::x86_64::__cpuid(1).ebx >> 24);
+}
+
+#[test_case]
+fn test_per_cpu_variables() {
+    crate::per_cpu! {
+        static COUNTER: AtomicU64 = AtomicU64::new(5);
+    }
+    COUNTER.get().fetch_add(1, Ordering::Relaxed);
+    assert_eq!(COUNTER.all()[0].load(Ordering::Relaxed), 6);
+    assert!(COUNTER.all()[1..].iter().all(|slot| slot.load(Ordering::Relaxed) == 5));
+}
+
+#[test_case]
+fn test_online_cpus() {
+    let online: alloc::vec::Vec<_> = online().collect();
+    assert_eq!(online.len(), crate::smp::online_cpus());
+    assert_eq!(online[0].address(), current().address());
+    assert!(online.iter().enumerate().all(|(i, cpu)| cpu.index() == i));
+}
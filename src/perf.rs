@@ -0,0 +1,328 @@
+//! Hardware performance counters, through Intel's architectural
+//! performance monitoring (CPUID leaf 0xA).
+//!
+//! `Counter::start` takes a free general-purpose counter, programs its
+//! IA32_PERFEVTSELx for the event and reads it with `rdpmc`. Without the
+//! feature, with the event missing or with every counter taken, a counter
+//! falls back to TSC cycles; `Counter::hardware` tells which one you got
+//! and `report` says what the CPU offers. A `Sampler` interrupts every so
+//! many events instead of being read; the watchdog's NMI comes from one.
+//! Counters are per CPU and only the boot CPU is set up.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Once;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use crate::cpu::{self, IA32_PERF_GLOBAL_CTRL};
+use crate::time;
+
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// Counters handed out at most; `IN_USE` has one bit each.
+const MAX_COUNTERS: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    CoreCycles,
+    InstructionsRetired,
+    LlcMisses,
+}
+
+impl Event {
+    pub const ALL: [Event; 3] = [Event::CoreCycles, Event::InstructionsRetired, Event::LlcMisses];
+
+    /// Event number and unit mask from the SDM's architectural events.
+    fn code(self) -> (u8, u8) {
+        match self {
+            Event::CoreCycles => (0x3c, 0x00),
+            Event::InstructionsRetired => (0xc0, 0x00),
+            Event::LlcMisses => (0x2e, 0x41),
+        }
+    }
+
+    /// Bit in CPUID.0xA:EBX that is set when the event is missing.
+    fn unavailable_bit(self) -> u32 {
+        match self {
+            Event::CoreCycles => 0,
+            Event::InstructionsRetired => 1,
+            Event::LlcMisses => 4,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::CoreCycles => "cycles",
+            Event::InstructionsRetired => "instructions",
+            Event::LlcMisses => "llc-misses",
+        }
+    }
+}
+
+/// IA32_PERFEVTSELx value counting `event` in both rings.
+pub fn event_select(event: Event) -> u64 {
+    let (number, umask) = event.code();
+    u64::from(number) | u64::from(umask) << 8 | EVTSEL_USR | EVTSEL_OS | EVTSEL_EN
+}
+
+/// What CPUID leaf 0xA reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: u8,
+    pub counters: u8,
+    pub counter_bits: u8,
+    /// CPUID.0xA:EBX with the bits past the vector length set, so a set
+    /// bit always means "missing".
+    unavailable: u32,
+}
+
+impl Capabilities {
+    /// `None` when the CPU has no architectural performance monitoring.
+    pub fn from_cpuid(eax: u32, ebx: u32) -> Option<Capabilities> {
+        let version = eax as u8;
+        let counters = (eax >> 8) as u8;
+        if version == 0 || counters == 0 {
+            return None;
+        }
+        let vector_len = (eax >> 24) as u8;
+        let past_vector = u32::MAX.checked_shl(u32::from(vector_len)).unwrap_or(0);
+        Some(Capabilities {
+            version,
+            counters: counters.min(MAX_COUNTERS),
+            counter_bits: (eax >> 16) as u8,
+            unavailable: ebx | past_vector,
+        })
+    }
+
+    pub fn supports(&self, event: Event) -> bool {
+        self.unavailable & (1 << event.unavailable_bit()) == 0
+    }
+
+    /// Difference of two raw counter reads, across a wrap.
+    pub fn delta(&self, start: u64, end: u64) -> u64 {
+        let mask = u64::MAX.checked_shr(64 - u32::from(self.counter_bits)).unwrap_or(0);
+        end.wrapping_sub(start) & mask
+    }
+}
+
+static CAPABILITIES: Once<Option<Capabilities>> = Once::new();
+static IN_USE: AtomicU8 = AtomicU8::new(0);
+
+/// Reads CPUID leaf 0xA and enables `rdpmc` in CR4.
+pub fn init() {
+    let caps = *CAPABILITIES.call_once(|| {
+        use core::arch::x86_64::__cpuid;
+        if __cpuid(0).eax < 0xa {
+            return None;
+        }
+        let leaf = __cpuid(0xa);
+        Capabilities::from_cpuid(leaf.eax, leaf.ebx)
+    });
+    if caps.is_some() {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PERFORMANCE_MONITOR_COUNTER)) };
+    }
+}
+
+pub fn capabilities() -> Option<Capabilities> {
+    CAPABILITIES.get().copied().flatten()
+}
+
+/// Writes what is measured in hardware and what falls back to the TSC.
+pub fn report(out: &mut impl fmt::Write) -> fmt::Result {
+    let Some(caps) = capabilities() else {
+        return writeln!(out, "perf: no architectural PMU, counters measure TSC cycles");
+    };
+    writeln!(
+        out,
+        "perf: PMU version {}, {} counters of {} bits",
+        caps.version, caps.counters, caps.counter_bits
+    )?;
+    for event in Event::ALL {
+        let source = if caps.supports(event) { "hardware" } else { "TSC cycles" };
+        writeln!(out, "  {:<13}{}", event.name(), source)?;
+    }
+    Ok(())
+}
+
+/// A running measurement of one event.
+pub struct Counter {
+    event: Event,
+    /// General-purpose counter index, `None` on the TSC fallback.
+    index: Option<u8>,
+    start: u64,
+}
+
+impl Counter {
+    pub fn start(event: Event) -> Counter {
+        let index = capabilities().filter(|caps| caps.supports(event)).and_then(|caps| claim(caps.counters));
+        let Some(index) = index else {
+            return Counter { event, index: None, start: time::rdtsc() };
+        };
+        unsafe {
+            cpu::wrmsr(IA32_PERFEVTSEL0 + u32::from(index), event_select(event));
+            cpu::wrmsr(IA32_PMC0 + u32::from(index), 0);
+            if capabilities().is_some_and(|caps| caps.version >= 2) {
+                cpu::wrmsr(IA32_PERF_GLOBAL_CTRL, cpu::rdmsr(IA32_PERF_GLOBAL_CTRL) | 1 << index);
+            }
+        }
+        Counter { event, index: Some(index), start: rdpmc(index) }
+    }
+
+    pub fn event(&self) -> Event {
+        self.event
+    }
+
+    /// Whether this counts the event itself rather than TSC cycles.
+    pub fn hardware(&self) -> bool {
+        self.index.is_some()
+    }
+
+    /// Stops counting and returns the count since `start`.
+    pub fn stop(self) -> u64 {
+        let Some(index) = self.index else {
+            return time::rdtsc() - self.start;
+        };
+        let end = rdpmc(index);
+        capabilities().map_or(0, |caps| caps.delta(self.start, end))
+    }
+}
+
+impl Drop for Counter {
+    /// Disables the counter and hands it back, also when `stop` never ran.
+    fn drop(&mut self) {
+        if let Some(index) = self.index {
+            unsafe { cpu::wrmsr(IA32_PERFEVTSEL0 + u32::from(index), 0) };
+            IN_USE.fetch_and(!(1 << index), Ordering::Release);
+        }
+    }
+}
+
+/// A counter that overflows every `period` events and raises the local
+/// APIC's performance monitoring interrupt; the watchdog has that
+/// delivered as an NMI. Hardware only, there is no TSC fallback.
+pub struct Sampler {
+    index: u8,
+    period: u64,
+}
+
+impl Sampler {
+    /// `None` without the event in hardware or a free counter. `period`
+    /// is cut to 2^31 - 1: a write to the counter only sets its low 32
+    /// bits and sign-extends them.
+    pub fn start(event: Event, period: u64) -> Option<Sampler> {
+        let caps = capabilities().filter(|caps| caps.supports(event))?;
+        let index = claim(caps.counters)?;
+        let sampler = Sampler { index, period: period.clamp(1, i32::MAX as u64) };
+        sampler.rearm();
+        unsafe {
+            cpu::wrmsr(IA32_PERFEVTSEL0 + u32::from(index), event_select(event) | EVTSEL_INT);
+            if caps.version >= 2 {
+                cpu::wrmsr(IA32_PERF_GLOBAL_CTRL, cpu::rdmsr(IA32_PERF_GLOBAL_CTRL) | 1 << index);
+            }
+        }
+        Some(sampler)
+    }
+
+    /// Whether the counter went past its period since `rearm`. It starts
+    /// `period` below the wrap, so its top bit is clear once it has.
+    pub fn overflowed(&self) -> bool {
+        let top = capabilities().map_or(63, |caps| caps.counter_bits.clamp(1, 64) - 1);
+        rdpmc(self.index) & 1 << top == 0
+    }
+
+    /// Starts the next period and clears the overflow.
+    pub fn rearm(&self) {
+        unsafe {
+            cpu::wrmsr(IA32_PMC0 + u32::from(self.index), self.period.wrapping_neg());
+            if capabilities().is_some_and(|caps| caps.version >= 2) {
+                cpu::wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1 << self.index);
+            }
+        }
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        unsafe { cpu::wrmsr(IA32_PERFEVTSEL0 + u32::from(self.index), 0) };
+        IN_USE.fetch_and(!(1 << self.index), Ordering::Release);
+    }
+}
+
+/// Runs `f` and returns its result with the `event` count it took.
+pub fn measure<R>(event: Event, f: impl FnOnce() -> R) -> (R, u64) {
+    let counter = Counter::start(event);
+    let result = f();
+    (result, counter.stop())
+}
+
+/// Takes the lowest free counter below `counters`.
+fn claim(counters: u8) -> Option<u8> {
+    let mut taken = None;
+    IN_USE
+        .fetch_update(Ordering::Acquire, Ordering::Relaxed, |used| {
+            let index = (!used).trailing_zeros() as u8;
+            taken = (index < counters).then_some(index);
+            taken.map(|index| used | 1 << index)
+        })
+        .ok()?;
+    taken
+}
+
+fn rdpmc(index: u8) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdpmc", in("ecx") u32::from(index), out("eax") low, out("edx") high,
+            options(nomem, nostack, preserves_flags));
+    }
+    u64::from(high) << 32 | u64::from(low)
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+//test case
+#[test_case]
+fn test_event_select_encoding() {
+    // event 0x3c, umask 0, USR, OS, EN
+    assert_eq!(event_select(Event::CoreCycles), 0x0043_003c);
+    assert_eq!(event_select(Event::InstructionsRetired), 0x0043_00c0);
+    assert_eq!(event_select(Event::LlcMisses), 0x0043_412e);
+}
+
+#[test_case]
+fn test_capabilities_from_cpuid() {
+    // Skylake: version 4, 4 counters of 48 bits, 7 events, all present
+    let caps = Capabilities::from_cpuid(0x0730_0804, 0x0000_0000).unwrap();
+    assert_eq!((caps.version, caps.counters, caps.counter_bits), (4, 4, 48));
+    assert!(Event::ALL.iter().all(|&event| caps.supports(event)));
+    // LLC misses flagged missing
+    let caps = Capabilities::from_cpuid(0x0730_0804, 0x10).unwrap();
+    assert!(!caps.supports(Event::LlcMisses));
+    assert!(caps.supports(Event::InstructionsRetired));
+    // a vector of 2 says nothing about LLC misses, so they are missing
+    let caps = Capabilities::from_cpuid(0x0228_0201, 0).unwrap();
+    assert!(caps.supports(Event::InstructionsRetired));
+    assert!(!caps.supports(Event::LlcMisses));
+    // no PMU, as under QEMU's TCG
+    assert_eq!(Capabilities::from_cpuid(0, 0), None);
+    assert_eq!(Capabilities::from_cpuid(0x0730_0004, 0), None);
+}
+
+#[test_case]
+fn test_counter_delta_wraps_at_width() {
+    let caps = Capabilities::from_cpuid(0x0730_0804, 0).unwrap();
+    assert_eq!(caps.delta(100, 250), 150);
+    assert_eq!(caps.delta((1 << 48) - 10, 5), 15);
+    let full = Capabilities { counter_bits: 64, ..caps };
+    assert_eq!(full.delta(u64::MAX, 1), 2);
+}
@@ -0,0 +1,205 @@
+//! Pipes: a ring buffer one end writes into and the other reads from,
+//! both ends being VFS file handles.
+//!
+//! A read waits while the pipe is empty and the write end is still open,
+//! and returns 0 once it is empty and closed. A write waits while the pipe
+//! is full and the read end is open, then takes what fits; once the read
+//! end is gone it fails with `VfsError::BrokenPipe`. Waiting is kernel
+//! waiting (`Waiter::Kernel`), so the other end has to be moved by
+//! another thread or an interrupt handler. The shell runs each stage of
+//! a pipeline on a kernel thread of its own, so a stage blocked on a full
+//! or empty pipe lets the others run.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use crate::sync::{IrqMutex, WaitQueue, Waiter};
+use crate::vfs::{FileHandle, VfsError};
+
+/// What `pipe` holds before a write waits: a page, since every stage of
+/// a pipeline has one on a heap of `allocator::HEAP_SIZE`.
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct Buffer {
+    data: Box<[u8]>,
+    /// Where the oldest byte is.
+    start: usize,
+    len: usize,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+impl Buffer {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.len);
+        for (i, byte) in buf[..n].iter_mut().enumerate() {
+            *byte = self.data[(self.start + i) % self.data.len()];
+        }
+        self.start = (self.start + n) % self.data.len();
+        self.len -= n;
+        n
+    }
+
+    fn write(&mut self, buf: &[u8]) -> usize {
+        let n = buf.len().min(self.data.len() - self.len);
+        for (i, &byte) in buf[..n].iter().enumerate() {
+            let at = (self.start + self.len + i) % self.data.len();
+            self.data[at] = byte;
+        }
+        self.len += n;
+        n
+    }
+}
+
+struct Shared {
+    buffer: IrqMutex<Buffer>,
+    /// Readers waiting for bytes or the write end closing.
+    readable: WaitQueue,
+    /// Writers waiting for room or the read end closing.
+    writable: WaitQueue,
+}
+
+pub struct PipeReader(Arc<Shared>);
+
+pub struct PipeWriter(Arc<Shared>);
+
+/// A pipe holding up to `PIPE_CAPACITY` bytes.
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    with_capacity(PIPE_CAPACITY)
+}
+
+/// A pipe holding up to `capacity` bytes, at least one.
+pub fn with_capacity(capacity: usize) -> (PipeReader, PipeWriter) {
+    let buffer = Buffer {
+        data: alloc::vec![0; capacity.max(1)].into_boxed_slice(),
+        start: 0,
+        len: 0,
+        reader_open: true,
+        writer_open: true,
+    };
+    let shared = Arc::new(Shared { buffer: IrqMutex::new(buffer), readable: WaitQueue::new(), writable: WaitQueue::new() });
+    (PipeReader(shared.clone()), PipeWriter(shared))
+}
+
+impl FileHandle for PipeReader {
+    /// Bytes waiting to be read.
+    fn size(&self) -> u64 {
+        self.0.buffer.lock().len as u64
+    }
+
+    /// The offset is ignored: a pipe is read in order.
+    fn read(&mut self, _offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut n = 0;
+        let _ = self.0.readable.wait_until(Waiter::Kernel, || {
+            let mut buffer = self.0.buffer.lock();
+            n = buffer.read(buf);
+            n > 0 || !buffer.writer_open
+        });
+        if n > 0 {
+            self.0.writable.notify_all();
+        }
+        Ok(n)
+    }
+}
+
+impl FileHandle for PipeWriter {
+    fn size(&self) -> u64 {
+        self.0.buffer.lock().len as u64
+    }
+
+    /// The offset is ignored; writes go after what is waiting. Returns
+    /// how much fitted, which is less than `buf` only if the pipe filled.
+    fn write(&mut self, _offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut result = Ok(0);
+        let _ = self.0.writable.wait_until(Waiter::Kernel, || {
+            let mut buffer = self.0.buffer.lock();
+            if !buffer.reader_open {
+                result = Err(VfsError::BrokenPipe);
+                return true;
+            }
+            result = Ok(buffer.write(buf));
+            result != Ok(0)
+        });
+        if matches!(result, Ok(n) if n > 0) {
+            self.0.readable.notify_all();
+        }
+        result
+    }
+
+    fn read(&mut self, _offset: u64, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::WriteOnly)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.buffer.lock().reader_open = false;
+        self.0.writable.notify_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.buffer.lock().writer_open = false;
+        self.0.readable.notify_all();
+    }
+}
+
+//test case
+#[test_case]
+fn test_pipe_in_order() {
+    let (mut reader, mut writer) = with_capacity(8);
+    assert_eq!(writer.write(0, b"hello"), Ok(5));
+    assert_eq!(reader.size(), 5);
+    let mut buf = [0u8; 3];
+    assert_eq!(reader.read(0, &mut buf), Ok(3));
+    assert_eq!(&buf, b"hel");
+    // wraps around the end of the buffer, and a full pipe takes what fits
+    assert_eq!(writer.write(0, b" world!"), Ok(6));
+    let mut buf = [0u8; 16];
+    assert_eq!(reader.read(0, &mut buf), Ok(8));
+    assert_eq!(&buf[..8], b"lo world");
+    assert_eq!(writer.read(0, &mut buf), Err(VfsError::WriteOnly));
+    drop(writer);
+    assert_eq!(reader.read(0, &mut buf), Ok(0));
+}
+
+#[test_case]
+fn test_pipe_closed_ends() {
+    let (reader, mut writer) = pipe();
+    drop(reader);
+    assert_eq!(writer.write(0, b"nobody"), Err(VfsError::BrokenPipe));
+    assert_eq!(writer.write(0, b""), Ok(0));
+}
+
+#[test_case]
+fn test_pipe_read_waits_for_a_writer() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use spin::Mutex;
+
+    // the timer interrupt writes while the reader halts
+    static WRITER: Mutex<Option<PipeWriter>> = Mutex::new(None);
+    static WROTE: AtomicBool = AtomicBool::new(false);
+    fn produce() {
+        if let Some(mut writer) = WRITER.try_lock().and_then(|mut writer| writer.take()) {
+            assert_eq!(writer.write(0, b"tick"), Ok(4));
+            WROTE.store(true, Ordering::Relaxed);
+        }
+    }
+    let (mut reader, writer) = with_capacity(16);
+    *WRITER.lock() = Some(writer);
+    crate::interrupts::set_tick_hook(produce);
+    let mut buf = [0u8; 16];
+    let n = reader.read(0, &mut buf);
+    crate::interrupts::set_tick_hook(|| {});
+    assert_eq!(n, Ok(4));
+    assert_eq!(&buf[..4], b"tick");
+    assert!(WROTE.load(Ordering::Relaxed));
+    // the writer was dropped in the interrupt after writing
+    assert_eq!(reader.read(0, &mut buf), Ok(0));
+}
@@ -0,0 +1,282 @@
+//! Power off through ACPI S5, with the emulator port hacks as a fallback,
+//! and reboot: 8042, reset-control register, then a triple fault.
+
+use core::fmt;
+use x86_64::instructions::port::Port;
+use crate::acpi::{self, aml::{self, AmlError}, AcpiError};
+use crate::println;
+
+/// SLP_EN bit in the PM1 control registers.
+const SLP_EN: u16 = 1 << 13;
+/// SCI_EN bit in PM1a_CNT: set once the firmware handed control to the OS.
+const SCI_EN: u16 = 1;
+/// PM1 control register reads before giving up on ACPI enable.
+const ENABLE_POLL_LIMIT: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiPowerError {
+    Acpi(AcpiError),
+    NoFadt,
+    NoPm1aControl,
+    S5(AmlError),
+    /// SCI_EN never came up after writing ACPI_ENABLE to the SMI port.
+    EnableTimeout,
+    /// ACPI mode is off and the FADT gives no way to turn it on.
+    CannotEnable,
+    /// The sleep command was written but the machine is still running.
+    StillRunning,
+}
+
+impl From<AcpiError> for AcpiPowerError {
+    fn from(err: AcpiError) -> Self {
+        AcpiPowerError::Acpi(err)
+    }
+}
+
+impl fmt::Display for AcpiPowerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiPowerError::Acpi(err) => write!(f, "{}", err),
+            AcpiPowerError::NoFadt => write!(f, "no FADT"),
+            AcpiPowerError::NoPm1aControl => write!(f, "FADT has no PM1a control block"),
+            AcpiPowerError::S5(err) => write!(f, "{}", err),
+            AcpiPowerError::EnableTimeout => write!(f, "timed out enabling ACPI mode"),
+            AcpiPowerError::CannotEnable => write!(f, "ACPI mode disabled and no SMI command port"),
+            AcpiPowerError::StillRunning => write!(f, "S5 request ignored"),
+        }
+    }
+}
+
+fn enable_acpi_mode(fadt: &acpi::Fadt) -> Result<(), AcpiPowerError> {
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    if unsafe { pm1a.read() } & SCI_EN != 0 {
+        return Ok(());
+    }
+    if fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return Err(AcpiPowerError::CannotEnable);
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable) };
+    for _ in 0..ENABLE_POLL_LIMIT {
+        if unsafe { pm1a.read() } & SCI_EN != 0 {
+            return Ok(());
+        }
+    }
+    Err(AcpiPowerError::EnableTimeout)
+}
+
+fn write_sleep(control_block: u32, slp_typ: u8) {
+    let mut port: Port<u16> = Port::new(control_block as u16);
+    unsafe {
+        let value = port.read() & !(0x7 << 10);
+        port.write(value | (u16::from(slp_typ) << 10) | SLP_EN);
+    }
+}
+
+/// Enters S5 (soft off) using the FADT and the `\_S5` object of the DSDT.
+/// Only returns if some step fails, so callers can try something else.
+pub fn acpi_shutdown() -> Result<!, AcpiPowerError> {
+    let tables = acpi::tables()?;
+    let fadt = tables.fadt.as_ref().ok_or(AcpiPowerError::NoFadt)?;
+    if fadt.pm1a_control_block == 0 {
+        return Err(AcpiPowerError::NoPm1aControl);
+    }
+    let (_, dsdt) = acpi::load_table(fadt.dsdt)?;
+    let (slp_typ_a, slp_typ_b) = aml::find_s5_sleep_types(&dsdt[acpi::SDT_HEADER_LEN..])
+        .map_err(AcpiPowerError::S5)?;
+
+    enable_acpi_mode(fadt)?;
+    x86_64::instructions::interrupts::disable();
+    write_sleep(fadt.pm1a_control_block, slp_typ_a);
+    if fadt.pm1b_control_block != 0 {
+        write_sleep(fadt.pm1b_control_block, slp_typ_b);
+    }
+
+    // Power should be gone by now; give slow chipsets a moment.
+    for _ in 0..ENABLE_POLL_LIMIT {
+        core::hint::spin_loop();
+    }
+    x86_64::instructions::interrupts::enable();
+    Err(AcpiPowerError::StillRunning)
+}
+
+/// Emulator shutdown ports: QEMU, Bochs/old QEMU and VirtualBox.
+const EMULATOR_SHUTDOWN_PORTS: [(u16, u16); 3] = [(0x604, 0x2000), (0xb004, 0x2000), (0x4004, 0x3400)];
+
+/// Tries ACPI first, then the emulator ports. Returns if nothing worked.
+pub fn shutdown() {
+    let Err(err) = acpi_shutdown();
+    println!("ACPI shutdown failed: {}", err);
+    for &(port, value) in &EMULATOR_SHUTDOWN_PORTS {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+}
+
+// ==== reboot ====
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 0x02;
+const KBC_PULSE_RESET: u8 = 0xfe;
+const KBC_POLL_LIMIT: usize = 100_000;
+
+const RESET_CONTROL: u16 = 0xcf9;
+/// System reset (bit 1) with full reset (bit 3), then the CPU reset
+/// trigger (bit 2) on the second write.
+const RESET_CONTROL_PREPARE: u8 = 0x0a;
+const RESET_CONTROL_RESET: u8 = 0x0e;
+
+/// Byte port access for the reset methods, so the chain can run against
+/// fakes in tests.
+pub trait ResetPorts {
+    fn read(&mut self, port: u16) -> u8;
+    fn write(&mut self, port: u16, value: u8);
+    /// Gives a reset that was just requested time to happen.
+    fn settle(&mut self);
+}
+
+pub struct HardwarePorts;
+
+impl ResetPorts for HardwarePorts {
+    fn read(&mut self, port: u16) -> u8 {
+        unsafe { Port::<u8>::new(port).read() }
+    }
+
+    fn write(&mut self, port: u16, value: u8) {
+        unsafe { Port::<u8>::new(port).write(value) }
+    }
+
+    fn settle(&mut self) {
+        for _ in 0..ENABLE_POLL_LIMIT {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMethod {
+    Keyboard8042,
+    ResetControl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetOutcome {
+    /// The request was written; if we are still here it did nothing.
+    Ignored,
+    /// The 8042 input buffer never drained, so the command was not sent.
+    Timeout,
+}
+
+/// Pulses the CPU reset line through the 8042 keyboard controller.
+pub fn reset_8042(ports: &mut dyn ResetPorts) -> ResetOutcome {
+    for _ in 0..KBC_POLL_LIMIT {
+        if ports.read(KBC_STATUS) & KBC_INPUT_FULL == 0 {
+            ports.write(KBC_STATUS, KBC_PULSE_RESET);
+            ports.settle();
+            return ResetOutcome::Ignored;
+        }
+    }
+    ResetOutcome::Timeout
+}
+
+/// Resets through the chipset's reset-control register at 0xCF9.
+pub fn reset_control(ports: &mut dyn ResetPorts) -> ResetOutcome {
+    ports.write(RESET_CONTROL, RESET_CONTROL_PREPARE);
+    ports.write(RESET_CONTROL, RESET_CONTROL_RESET);
+    ports.settle();
+    ResetOutcome::Ignored
+}
+
+/// Tries each port-based reset in order. Only returns (with what happened
+/// to each) if the machine is still running after all of them.
+pub fn try_port_resets(ports: &mut dyn ResetPorts) -> [(ResetMethod, ResetOutcome); 2] {
+    [
+        (ResetMethod::Keyboard8042, reset_8042(ports)),
+        (ResetMethod::ResetControl, reset_control(ports)),
+    ]
+}
+
+/// Restarts the machine: 8042, then 0xCF9, then a triple fault.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    let _ = try_port_resets(&mut HardwarePorts);
+    reboot_hard();
+}
+
+/// Guaranteed reset: with an empty IDT the `int3` cannot be delivered,
+/// neither can the resulting double fault, and the CPU shuts down (which
+/// the chipset turns into a reset, or QEMU into an exit with -no-reboot).
+pub fn reboot_hard() -> ! {
+    use x86_64::instructions::tables::lidt;
+    use x86_64::structures::DescriptorTablePointer;
+
+    use core::fmt::Write;
+
+    x86_64::instructions::interrupts::disable();
+    // we may be here because something wedged while holding a console lock
+    const MARKER: &str = "Rebooting (triple fault)...\n";
+    if let Some(mut writer) = crate::vga_buffer::WRITER.try_lock() {
+        let _ = writer.write_str(MARKER);
+    }
+    if let Some(mut serial) = crate::serial::SERIAL1.try_lock() {
+        let _ = serial.write_str(MARKER);
+    }
+    crate::serial::flush();
+    let empty = DescriptorTablePointer { limit: 0, base: x86_64::VirtAddr::new(0) };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    crate::hlt_loop();
+}
+
+//test case
+#[cfg(test)]
+#[derive(Default)]
+struct FakePorts {
+    /// The 8042 input buffer never drains.
+    kbc_stuck: bool,
+    writes: alloc::vec::Vec<(u16, u8)>,
+    settled: usize,
+}
+
+#[cfg(test)]
+impl ResetPorts for FakePorts {
+    fn read(&mut self, port: u16) -> u8 {
+        match port {
+            KBC_STATUS if self.kbc_stuck => KBC_INPUT_FULL,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8) {
+        self.writes.push((port, value));
+    }
+
+    fn settle(&mut self) {
+        self.settled += 1;
+    }
+}
+
+#[test_case]
+fn test_reset_chain_order() {
+    let mut ports = FakePorts::default();
+    let outcomes = try_port_resets(&mut ports);
+    assert_eq!(outcomes, [
+        (ResetMethod::Keyboard8042, ResetOutcome::Ignored),
+        (ResetMethod::ResetControl, ResetOutcome::Ignored),
+    ]);
+    assert_eq!(ports.writes, [
+        (KBC_STATUS, KBC_PULSE_RESET),
+        (RESET_CONTROL, RESET_CONTROL_PREPARE),
+        (RESET_CONTROL, RESET_CONTROL_RESET),
+    ]);
+    assert_eq!(ports.settled, 2);
+}
+
+#[test_case]
+fn test_reset_8042_timeout_falls_through() {
+    let mut ports = FakePorts { kbc_stuck: true, ..FakePorts::default() };
+    let outcomes = try_port_resets(&mut ports);
+    assert_eq!(outcomes[0], (ResetMethod::Keyboard8042, ResetOutcome::Timeout));
+    // nothing was sent to the stuck controller, 0xCF9 was still tried
+    assert_eq!(ports.writes, [(RESET_CONTROL, RESET_CONTROL_PREPARE), (RESET_CONTROL, RESET_CONTROL_RESET)]);
+}
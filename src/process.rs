@@ -0,0 +1,738 @@
+//! User processes and a round-robin scheduler for them.
+//!
+//! `spawn_from_elf` loads a program into its own address space and queues
+//! it as `Ready`. `run` enters the first ready process and only returns
+//! once none is left. Meanwhile the timer interrupt preempts the running
+//! process every `scheduler::quantum_ticks` and switches to the next
+//! ready one by rewriting the interrupt frame it returns through; `exit`
+//! and faults do the same when they take the running process away.
+//!
+//! `sleep_ticks` takes the running process off the CPU into a sleep queue
+//! ordered by wake-up tick, and the timer interrupt moves every process
+//! whose tick has come back to `Ready`. When nothing is ready but someone
+//! sleeps, the CPU halts in the kernel until the timer wakes a sleeper.
+//! A process waiting on a `sync::WaitQueue` is `Blocked` until notified;
+//! if only blocked processes are left, `run` returns without them.
+//!
+//! `fork` duplicates the running process with a copy-on-write clone of its
+//! address space (see `usermode`). A process spawned or forked by another
+//! one is its child; those the kernel spawns have no parent. `wait4` for pid -1
+//! waits for any child to finish, and collecting a child's status that
+//! way or by its pid disowns it, so it is reported only once.
+//!
+//! A finished process keeps its table entry, with its state and exit code,
+//! for `ps`. Its address space is freed as soon as CR3 has moved to
+//! another process and its kernel stack after the next switch, since the
+//! switch itself runs on that stack.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::VirtAddr;
+use crate::cpu::{self, FpuState};
+use crate::elf::{self, ElfError};
+use crate::interrupts::{ExceptionFrame, SavedRegisters};
+use crate::sync::WaitQueue;
+use crate::usermode::{self, AddressSpace};
+use crate::{gdt, println, syscall, time};
+
+/// Stack for interrupts and system calls taken in ring 3 (TSS.rsp0).
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+/// Finished processes kept around for `ps`.
+const MAX_FINISHED: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(pub u64);
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    /// In the sleep queue until its wake-up tick.
+    Sleeping,
+    /// Parked on a wait queue until notified.
+    Blocked,
+    Exited(u64),
+    /// Killed by an exception or `kill`.
+    Killed,
+}
+
+impl State {
+    pub fn finished(&self) -> bool {
+        matches!(self, State::Exited(_) | State::Killed)
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            State::Ready => f.write_str("ready"),
+            State::Running => f.write_str("running"),
+            State::Sleeping => f.write_str("sleeping"),
+            State::Blocked => f.write_str("blocked"),
+            State::Exited(code) => write!(f, "exited({})", code),
+            State::Killed => f.write_str("killed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    Elf(ElfError),
+    /// The program wants addresses outside the user region.
+    OutsideUserRegion,
+    /// The kernel itself has something mapped in the user region.
+    RegionInUse,
+    OutOfMemory,
+    /// The global frame allocator has not been installed yet.
+    NoPaging,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnError::Elf(err) => write!(f, "{}", err),
+            SpawnError::OutsideUserRegion => f.write_str("program lies outside the user region"),
+            SpawnError::RegionInUse => f.write_str("the kernel uses the user region"),
+            SpawnError::OutOfMemory => f.write_str("out of physical memory"),
+            SpawnError::NoPaging => f.write_str("paging is not available yet"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KillError {
+    NoSuchProcess,
+    AlreadyFinished,
+    /// Only a process that is not on the CPU can be killed from outside.
+    Running,
+}
+
+impl fmt::Display for KillError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KillError::NoSuchProcess => f.write_str("no such process"),
+            KillError::AlreadyFinished => f.write_str("process already finished"),
+            KillError::Running => f.write_str("process is running"),
+        }
+    }
+}
+
+/// User registers of a process that is off the CPU.
+#[derive(Debug, Clone, Copy, Default)]
+struct Context {
+    registers: SavedRegisters,
+    fpu: FpuState,
+    rip: u64,
+    rsp: u64,
+    rflags: u64,
+}
+
+struct Process {
+    pid: Pid,
+    parent: Option<Pid>,
+    state: State,
+    space: Option<AddressSpace>,
+    kernel_stack: Option<Box<[u8]>>,
+    context: Context,
+}
+
+impl Process {
+    fn kernel_stack_top(&self) -> VirtAddr {
+        let stack = self.kernel_stack.as_ref().expect("runnable process without a kernel stack");
+        (VirtAddr::from_ptr(stack.as_ptr()) + stack.len() as u64).align_down(16u64)
+    }
+
+    /// Frees the address space and kernel stack of a process that is not
+    /// on the CPU.
+    fn release(&mut self) {
+        if let Some(space) = self.space.take() {
+            space.destroy();
+        }
+        self.kernel_stack = None;
+    }
+}
+
+/// Whether `deadline` has come by tick `now`. Ticks are compared by their
+/// wrapping distance, so a deadline past `u64::MAX` still works.
+fn expired(deadline: u64, now: u64) -> bool {
+    now.wrapping_sub(deadline) as i64 >= 0
+}
+
+/// Sleeping processes by wake-up tick, earliest first; equal ticks wake in
+/// the order they went to sleep.
+#[derive(Debug, Default)]
+pub struct SleepQueue {
+    entries: Vec<(u64, Pid)>,
+}
+
+impl SleepQueue {
+    pub const fn new() -> Self {
+        SleepQueue { entries: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Queues `pid` to wake at `deadline`, which lies after `now`.
+    pub fn insert(&mut self, pid: Pid, deadline: u64, now: u64) {
+        let distance = |deadline: u64| deadline.wrapping_sub(now) as i64;
+        let at = self.entries.partition_point(|&(other, _)| distance(other) <= distance(deadline));
+        self.entries.insert(at, (deadline, pid));
+    }
+
+    /// Takes `pid` out before its time. Returns whether it was queued.
+    pub fn remove(&mut self, pid: Pid) -> bool {
+        match self.entries.iter().position(|&(_, other)| other == pid) {
+            Some(at) => {
+                self.entries.remove(at);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Dequeues every process whose deadline has come by `now`, in wake-up
+    /// order.
+    pub fn wake(&mut self, now: u64, mut wake: impl FnMut(Pid)) {
+        let due = self.entries.iter().take_while(|&&(deadline, _)| expired(deadline, now)).count();
+        for (_, pid) in self.entries.drain(..due) {
+            wake(pid);
+        }
+    }
+}
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+/// In spawn order. The timer only ever `try_lock`s it.
+static PROCESSES: Mutex<Vec<Process>> = Mutex::new(Vec::new());
+/// Always taken after `PROCESSES`.
+static SLEEPING: Mutex<SleepQueue> = Mutex::new(SleepQueue::new());
+/// Notified whenever a process finishes.
+pub static FINISHED: WaitQueue = WaitQueue::new();
+/// Set while `run` is in progress.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Pid of the process on the CPU, 0 for none.
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+/// Tick the running process got the CPU at.
+static SLICE_START: AtomicU64 = AtomicU64::new(0);
+static SWITCHES: AtomicU64 = AtomicU64::new(0);
+
+/// Loads `image`, an `ET_EXEC` linked inside the user region, into a fresh
+/// address space and queues it. It starts running with the next `run`.
+pub fn spawn_from_elf(image: &[u8]) -> Result<Pid, SpawnError> {
+    let file = elf::parse(image, false).map_err(SpawnError::Elf)?;
+    usermode::check_layout(&file)?;
+    let space = AddressSpace::new()?;
+    let entry = match without_interrupts(|| usermode::load(&space, &file)) {
+        Ok(entry) => entry,
+        Err(err) => {
+            space.destroy();
+            return Err(err);
+        }
+    };
+    let pid = Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed));
+    let process = Process {
+        pid,
+        parent: current(),
+        state: State::Ready,
+        space: Some(space),
+        kernel_stack: Some(vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice()),
+        context: Context {
+            rip: entry.as_u64(),
+            rsp: usermode::STACK_TOP,
+            rflags: usermode::USER_RFLAGS,
+            ..Context::default()
+        },
+    };
+    without_interrupts(|| PROCESSES.lock().push(process));
+    Ok(pid)
+}
+
+/// The `fork` system call: queues a copy of the running process, whose
+/// registers are in `frame`, with a copy-on-write clone of its address
+/// space. The copy resumes after the call with 0 in RAX. Returns its pid,
+/// or `None` if no process is running.
+pub(crate) fn fork(frame: &ExceptionFrame) -> Option<Result<Pid, SpawnError>> {
+    let parent = current()?;
+    let space = match AddressSpace::clone_active() {
+        Ok(space) => space,
+        Err(err) => return Some(Err(err)),
+    };
+    let pid = Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed));
+    let mut context = save(frame);
+    context.registers.rax = 0;
+    let process = Process {
+        pid,
+        parent: Some(parent),
+        state: State::Ready,
+        space: Some(space),
+        kernel_stack: Some(vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice()),
+        context,
+    };
+    without_interrupts(|| PROCESSES.lock().push(process));
+    Some(Ok(pid))
+}
+
+/// Runs ready processes until none is left. Returns `false`, without doing
+/// anything, if the scheduler is already running.
+pub fn run() -> bool {
+    if ACTIVE.swap(true, Ordering::Acquire) {
+        return false;
+    }
+    // the main loop can't pet the watchdog while processes run
+    let _watchdog = crate::watchdog::suspend();
+    let (kernel_l4, cr3_flags) = Cr3::read();
+    let first = without_interrupts(|| {
+        let mut table = PROCESSES.lock();
+        let next = next_ready(&table, None)?;
+        Some(switch_to(&mut table[next]))
+    });
+    if let Some(context) = first {
+        // only processes that never ran are ready here, so the zeroed
+        // registers enter_user starts with are the right ones
+        cpu::load_fpu(&context.fpu);
+        let (code, stack) = gdt::user_selectors();
+        unsafe { usermode::enter_user(context.rip, context.rsp, u64::from(code.0), u64::from(stack.0)) };
+    }
+    without_interrupts(|| {
+        unsafe { Cr3::write(kernel_l4, cr3_flags) };
+        CURRENT.store(0, Ordering::Relaxed);
+        reap(&mut PROCESSES.lock(), None);
+    });
+    ACTIVE.store(false, Ordering::Release);
+    true
+}
+
+pub fn state(pid: Pid) -> Option<State> {
+    without_interrupts(|| PROCESSES.lock().iter().find(|p| p.pid == pid).map(|p| p.state))
+}
+
+/// Every process still in the table, oldest first.
+pub fn list() -> Vec<(Pid, State)> {
+    without_interrupts(|| PROCESSES.lock().iter().map(|p| (p.pid, p.state)).collect())
+}
+
+/// The process that spawned `pid`, if it was not the kernel and has not
+/// collected its status yet.
+pub fn parent(pid: Pid) -> Option<Pid> {
+    without_interrupts(|| PROCESSES.lock().iter().find(|p| p.pid == pid).and_then(|p| p.parent))
+}
+
+/// The children of `pid` whose status it has not collected.
+pub fn children(pid: Pid) -> Vec<Pid> {
+    without_interrupts(|| PROCESSES.lock().iter().filter(|p| p.parent == Some(pid)).map(|p| p.pid).collect())
+}
+
+/// A finished child of `parent`, the oldest if there are several.
+pub(crate) fn finished_child(parent: Pid) -> Option<(Pid, State)> {
+    without_interrupts(|| {
+        PROCESSES
+            .lock()
+            .iter()
+            .find(|p| p.parent == Some(parent) && p.state.finished())
+            .map(|p| (p.pid, p.state))
+    })
+}
+
+/// Forgets whose child `pid` is, once its status was collected.
+pub(crate) fn disown(pid: Pid) {
+    without_interrupts(|| {
+        if let Some(process) = PROCESSES.lock().iter_mut().find(|p| p.pid == pid) {
+            process.parent = None;
+        }
+    });
+}
+
+/// Switches from one process to another since boot.
+pub fn context_switches() -> u64 {
+    SWITCHES.load(Ordering::Relaxed)
+}
+
+/// Kills a process that is waiting for the CPU and frees it right away.
+pub fn kill(pid: Pid) -> Result<(), KillError> {
+    let result = without_interrupts(|| {
+        let mut table = PROCESSES.lock();
+        let process = table.iter_mut().find(|p| p.pid == pid).ok_or(KillError::NoSuchProcess)?;
+        match process.state {
+            State::Ready | State::Blocked => {
+                process.state = State::Killed;
+                process.release();
+                Ok(())
+            }
+            State::Sleeping => {
+                SLEEPING.lock().remove(pid);
+                process.state = State::Killed;
+                process.release();
+                Ok(())
+            }
+            State::Running => Err(KillError::Running),
+            State::Exited(_) | State::Killed => Err(KillError::AlreadyFinished),
+        }
+    });
+    if result.is_ok() {
+        FINISHED.notify_all();
+    }
+    result
+}
+
+/// Called from the timer interrupt after the EOI. Once the running process
+/// has used up its quantum, hands the CPU to the next ready one.
+pub(crate) fn preempt(frame: &mut ExceptionFrame) {
+    if !ACTIVE.load(Ordering::Acquire) {
+        return;
+    }
+    // even in the kernel: it may be idling for a sleeper
+    wake_sleepers();
+    if frame.stack_frame.code_segment & 3 != 3 {
+        return;
+    }
+    if time::ticks() - SLICE_START.load(Ordering::Relaxed) < crate::scheduler::quantum_ticks() {
+        return;
+    }
+    let Some(mut table) = PROCESSES.try_lock() else { return };
+    let Some(current) = current_index(&table) else { return };
+    let Some(next) = next_ready(&table, Some(current)) else {
+        // nobody else wants the CPU: start a new quantum
+        SLICE_START.store(time::ticks(), Ordering::Relaxed);
+        return;
+    };
+    let outgoing = &mut table[current];
+    outgoing.context = save(frame);
+    outgoing.state = State::Ready;
+    let outgoing = outgoing.pid;
+    let context = switch_to(&mut table[next]);
+    restore(frame, &context);
+    reap(&mut table, Some(outgoing));
+}
+
+/// Moves sleepers whose tick has come back to `Ready`; if the tables are
+/// busy, the next tick does it, so nobody is woken early.
+fn wake_sleepers() {
+    let Some(mut table) = PROCESSES.try_lock() else { return };
+    let Some(mut sleeping) = SLEEPING.try_lock() else { return };
+    sleeping.wake(time::ticks(), |pid| {
+        if let Some(process) = table.iter_mut().find(|p| p.pid == pid) {
+            process.state = State::Ready;
+        }
+    });
+}
+
+/// Puts the running process to sleep for at least `ticks` timer ticks.
+/// Returns `false` if no process is running; otherwise `frame` now belongs
+/// to the next ready process, and the sleeper resumes with 0 in RAX.
+pub(crate) fn sleep_ticks(frame: &mut ExceptionFrame, ticks: u64) -> bool {
+    if !ACTIVE.load(Ordering::Acquire) {
+        return false;
+    }
+    let mut table = PROCESSES.lock();
+    let Some(current) = current_index(&table) else { return false };
+    let sleeper = &mut table[current];
+    sleeper.context = save(frame);
+    sleeper.context.registers.rax = 0;
+    sleeper.state = State::Sleeping;
+    let now = time::ticks();
+    SLEEPING.lock().insert(sleeper.pid, now.wrapping_add(ticks), now);
+    let (mut table, next) = wait_for_ready(table, current);
+    let next = next.expect("a sleeper vanished from the sleep queue");
+    let context = switch_to(&mut table[next]);
+    restore(frame, &context);
+    true
+}
+
+/// The process on the CPU, if any.
+pub fn current() -> Option<Pid> {
+    match CURRENT.load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(Pid(pid)),
+    }
+}
+
+/// Length of both `syscall` and `int 0x80`.
+const SYSCALL_LEN: u64 = 2;
+
+/// Parks the running process for a `WaitQueue`. It resumes at the system
+/// call it is in, which then checks its condition again. Returns `false`
+/// if no process is running; otherwise `frame` now belongs to the next
+/// ready process, or, if none is left that could run, `run` returns.
+pub(crate) fn block(frame: &mut ExceptionFrame) -> bool {
+    leave_syscall(frame, State::Blocked)
+}
+
+/// Like `block`, but the process stays ready: it makes the system call
+/// again once the others have had a turn. For a `WaitQueue` with no room
+/// left to park it.
+pub(crate) fn retry(frame: &mut ExceptionFrame) -> bool {
+    leave_syscall(frame, State::Ready)
+}
+
+/// Takes the running process off the CPU in `state`, to resume at the
+/// system call it is in.
+fn leave_syscall(frame: &mut ExceptionFrame, state: State) -> bool {
+    if !ACTIVE.load(Ordering::Acquire) {
+        return false;
+    }
+    let mut table = PROCESSES.lock();
+    let Some(current) = current_index(&table) else { return false };
+    let leaving = &mut table[current];
+    leaving.context = save(frame);
+    leaving.context.rip -= SYSCALL_LEN;
+    leaving.state = state;
+    let (mut table, next) = wait_for_ready(table, current);
+    match next {
+        Some(next) => {
+            let context = switch_to(&mut table[next]);
+            restore(frame, &context);
+            true
+        }
+        None => {
+            drop(table);
+            unsafe { usermode::return_to_kernel(0) }
+        }
+    }
+}
+
+/// Makes a blocked process ready again.
+pub(crate) fn unblock(pid: Pid) {
+    without_interrupts(|| {
+        if let Some(process) = PROCESSES.lock().iter_mut().find(|p| p.pid == pid && p.state == State::Blocked) {
+            process.state = State::Ready;
+        }
+    });
+}
+
+/// The next ready process after `after`. While there is none but someone
+/// sleeps, halts with interrupts enabled until the timer wakes a sleeper.
+/// `None` once nobody is ready or asleep.
+fn wait_for_ready(
+    mut table: MutexGuard<'static, Vec<Process>>,
+    after: usize,
+) -> (MutexGuard<'static, Vec<Process>>, Option<usize>) {
+    loop {
+        let next = next_ready(&table, Some(after));
+        if next.is_some() || SLEEPING.lock().is_empty() {
+            return (table, next);
+        }
+        drop(table);
+        x86_64::instructions::interrupts::enable_and_hlt();
+        x86_64::instructions::interrupts::disable();
+        table = PROCESSES.lock();
+    }
+}
+
+/// The `exit` system call. Returns `false` if no process is running;
+/// otherwise `frame` now belongs to the next process, or, if there is
+/// none, `run` returns and this never does.
+pub(crate) fn exit(frame: &mut ExceptionFrame, code: u64) -> bool {
+    end_current(frame, State::Exited(code))
+}
+
+/// Called by the exception handlers first. If the exception came from a
+/// process, reports it, kills the process and returns `true`: the handler
+/// must then return to the (rewritten) frame.
+pub(crate) fn user_fault(name: &str, frame: &mut ExceptionFrame, address: Option<VirtAddr>) -> bool {
+    if frame.stack_frame.code_segment & 3 != 3 || !ACTIVE.load(Ordering::Acquire) {
+        return false;
+    }
+    let rip = frame.stack_frame.instruction_pointer.as_u64();
+    let pid = CURRENT.load(Ordering::Relaxed);
+    match address {
+        Some(address) => println!(
+            "process {}: {} at {:#x} accessing {:#x}, killed",
+            pid, name, rip, address.as_u64()
+        ),
+        None => println!("process {}: {} at {:#x}, killed", pid, name, rip),
+    }
+    end_current(frame, State::Killed)
+}
+
+fn end_current(frame: &mut ExceptionFrame, state: State) -> bool {
+    if !ACTIVE.load(Ordering::Acquire) {
+        return false;
+    }
+    let mut table = PROCESSES.lock();
+    let Some(current) = current_index(&table) else { return false };
+    table[current].state = state;
+    let outgoing = table[current].pid;
+    // whoever waits for it may be the next to run
+    drop(table);
+    FINISHED.notify_all();
+    let (mut table, next) = wait_for_ready(PROCESSES.lock(), current);
+    match next {
+        Some(next) => {
+            let context = switch_to(&mut table[next]);
+            restore(frame, &context);
+            // CR3 has moved on; the kernel stack is still in use
+            if let Some(space) = table[current].space.take() {
+                space.destroy();
+            }
+            reap(&mut table, Some(outgoing));
+            true
+        }
+        None => {
+            drop(table);
+            // run() frees what is left once it is back on its own stack
+            unsafe { usermode::return_to_kernel(0) }
+        }
+    }
+}
+
+fn current_index(table: &[Process]) -> Option<usize> {
+    let pid = CURRENT.load(Ordering::Relaxed);
+    table.iter().position(|p| p.pid.0 == pid && p.state == State::Running)
+}
+
+/// The first ready process after `after`, wrapping around.
+fn next_ready(table: &[Process], after: Option<usize>) -> Option<usize> {
+    let start = after.map_or(0, |i| i + 1);
+    (0..table.len())
+        .map(|i| (start + i) % table.len())
+        .find(|&i| table[i].state == State::Ready)
+}
+
+/// Makes `process` the running one: its address space, its kernel stack
+/// for entries from ring 3, a fresh quantum. Returns where it resumes.
+fn switch_to(process: &mut Process) -> Context {
+    process.state = State::Running;
+    process.space.as_ref().expect("runnable process without an address space").activate();
+    gdt::set_kernel_stack(process.kernel_stack_top());
+    syscall::set_kernel_stack(process.kernel_stack_top());
+    CURRENT.store(process.pid.0, Ordering::Relaxed);
+    SLICE_START.store(time::ticks(), Ordering::Relaxed);
+    SWITCHES.fetch_add(1, Ordering::Relaxed);
+    process.context
+}
+
+fn save(frame: &ExceptionFrame) -> Context {
+    Context {
+        registers: frame.registers,
+        fpu: frame.fpu,
+        rip: frame.stack_frame.instruction_pointer.as_u64(),
+        rsp: frame.stack_frame.stack_pointer.as_u64(),
+        rflags: frame.stack_frame.cpu_flags,
+    }
+}
+
+fn restore(frame: &mut ExceptionFrame, context: &Context) {
+    let (code, stack) = gdt::user_selectors();
+    frame.registers = context.registers;
+    frame.fpu = context.fpu;
+    frame.stack_frame.instruction_pointer = VirtAddr::new(context.rip);
+    frame.stack_frame.code_segment = u64::from(code.0);
+    frame.stack_frame.cpu_flags = context.rflags;
+    frame.stack_frame.stack_pointer = VirtAddr::new(context.rsp);
+    frame.stack_frame.stack_segment = u64::from(stack.0);
+}
+
+/// Frees finished processes, except `keep`, whose kernel stack may still
+/// be in use, and drops the oldest entries beyond `MAX_FINISHED`.
+fn reap(table: &mut Vec<Process>, keep: Option<Pid>) {
+    for process in table.iter_mut() {
+        if process.state.finished() && Some(process.pid) != keep {
+            process.release();
+        }
+    }
+    let mut finished = table.iter().filter(|p| p.state.finished()).count();
+    table.retain(|p| {
+        let drop = finished > MAX_FINISHED && p.state.finished() && p.kernel_stack.is_none();
+        if drop {
+            finished -= 1;
+        }
+        !drop
+    });
+}
+
+//test case
+#[test_case]
+fn test_next_ready_round_robin() {
+    let process = |pid, state| Process {
+        pid: Pid(pid),
+        parent: None,
+        state,
+        space: None,
+        kernel_stack: None,
+        context: Context::default(),
+    };
+    let table = [
+        process(1, State::Ready),
+        process(2, State::Running),
+        process(3, State::Exited(0)),
+        process(4, State::Ready),
+    ];
+    assert_eq!(next_ready(&table, None), Some(0));
+    assert_eq!(next_ready(&table, Some(1)), Some(3));
+    assert_eq!(next_ready(&table, Some(3)), Some(0));
+    assert_eq!(next_ready(&table[1..3], Some(0)), None);
+}
+
+#[test_case]
+fn test_state_display() {
+    use alloc::string::ToString;
+    assert_eq!(State::Exited(42).to_string(), "exited(42)");
+    assert!(State::Killed.finished());
+    assert!(!State::Running.finished());
+}
+
+#[test_case]
+fn test_sleep_queue_wakes_in_order() {
+    // three sleepers under a made-up tick counter that wraps around
+    let start = u64::MAX - 3;
+    let mut queue = SleepQueue::new();
+    let durations = [(Pid(1), 5), (Pid(2), 2), (Pid(3), 9)];
+    for (pid, ticks) in durations {
+        queue.insert(pid, start.wrapping_add(ticks), start);
+    }
+    assert_eq!(queue.len(), 3);
+    let mut woken = Vec::new();
+    // what each got to do: a sleeper polls only once it is awake
+    let mut polls = [0u64; 3];
+    for elapsed in 0..=12 {
+        let now = start.wrapping_add(elapsed);
+        queue.wake(now, |pid| woken.push((pid, elapsed)));
+        for (i, (pid, ticks)) in durations.iter().enumerate() {
+            let awake = woken.iter().any(|(woken, _)| woken == pid);
+            assert_eq!(awake, elapsed >= *ticks, "{} at +{}", pid, elapsed);
+            if awake {
+                polls[i] += 1;
+            }
+        }
+    }
+    assert_eq!(woken, [(Pid(2), 2), (Pid(1), 5), (Pid(3), 9)]);
+    assert_eq!(polls, [8, 11, 4]);
+    assert!(queue.is_empty());
+}
+
+#[test_case]
+fn test_sleep_queue_removal() {
+    let mut queue = SleepQueue::new();
+    for (pid, deadline) in [(1, 30), (2, 10), (3, 20), (4, 20)] {
+        queue.insert(Pid(pid), deadline, 0);
+    }
+    assert!(queue.remove(Pid(3)));
+    assert!(!queue.remove(Pid(3)));
+    assert!(!queue.remove(Pid(9)));
+    let mut woken = Vec::new();
+    queue.wake(19, |pid| woken.push(pid));
+    assert_eq!(woken, [Pid(2)]);
+    queue.wake(u64::MAX / 2, |pid| woken.push(pid));
+    assert_eq!(woken, [Pid(2), Pid(4), Pid(1)]);
+    // a deadline after the wrap is not due just before it
+    assert!(!expired(5, u64::MAX));
+    assert!(expired(u64::MAX, 5));
+    assert!(expired(7, 7));
+}
@@ -0,0 +1,314 @@
+//! Kernel PRNG: SplitMix64 over an atomic state, seeded at boot from
+//! RDSEED or RDRAND when the CPU has them and from the TSC otherwise.
+//!
+//! A TSC read at boot is easy to guess, so the timer and keyboard
+//! interrupts also feed the low bits of the TSC at each event into an
+//! entropy pool. `reseed_from_pool` folds the pool into the state once
+//! `RESEED_EVENTS` new events have arrived; `next_u64` does it on its own
+//! the first time the threshold is reached. Nothing here takes a lock, so
+//! the interrupt handlers can feed the pool freely.
+//!
+//! Not a cryptographic generator: the output reveals the state. What has
+//! to be hard to guess (DHCP transaction ids, TCP sequence numbers,
+//! randomized addresses) takes `u64` or `fill` instead, which read RDRAND
+//! when the CPU has it and fall back to the PRNG, reseeded from the pool
+//! every time it fills, when it doesn't.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use crate::cpu::Feature;
+use crate::time;
+
+/// SplitMix64's increment, the golden ratio in 64 bits.
+const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+/// Events the pool needs since the last reseed before it is folded in.
+pub const RESEED_EVENTS: u64 = 256;
+const POOL_LANES: usize = 4;
+
+static STATE: AtomicU64 = AtomicU64::new(GAMMA);
+static POOL: Pool = Pool::new();
+/// Whether `next_u64` already did its automatic reseed.
+static AUTO_RESEEDED: AtomicBool = AtomicBool::new(false);
+/// Whether `init` found a working RDRAND for `u64`.
+static HARDWARE: AtomicBool = AtomicBool::new(false);
+/// The `Source` of the boot seed.
+static SEEDED_FROM: AtomicU8 = AtomicU8::new(Source::Jitter as u8);
+
+/// Where random bits come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Source {
+    /// The CPU's entropy source itself. Slow and quick to run dry, so only
+    /// the seed comes from it.
+    Rdseed = 0,
+    /// The CPU's generator, reseeded from that source in hardware.
+    Rdrand = 1,
+    /// The TSC at boot, then the interrupt timings in the pool.
+    Jitter = 2,
+}
+
+impl Source {
+    fn from_u8(value: u8) -> Source {
+        match value {
+            0 => Source::Rdseed,
+            1 => Source::Rdrand,
+            _ => Source::Jitter,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Source::Rdseed => "RDSEED",
+            Source::Rdrand => "RDRAND",
+            Source::Jitter => "timer and keyboard jitter",
+        })
+    }
+}
+
+/// SplitMix64's output function; a full-avalanche 64-bit mixer.
+pub fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Timing samples hashed into a few lanes. Each event is mixed with its
+/// sequence number, so equal samples still change the pool, and XORed
+/// into one lane: no branches and no compare-exchange loop.
+pub struct Pool {
+    lanes: [AtomicU64; POOL_LANES],
+    events: AtomicU64,
+    /// `events` at the last fold.
+    folded_at: AtomicU64,
+}
+
+impl Pool {
+    pub const fn new() -> Pool {
+        Pool {
+            lanes: [const { AtomicU64::new(0) }; POOL_LANES],
+            events: AtomicU64::new(0),
+            folded_at: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(&self, sample: u64) {
+        let index = self.events.fetch_add(1, Ordering::Relaxed);
+        let lane = &self.lanes[index as usize % POOL_LANES];
+        lane.fetch_xor(mix(sample ^ index.wrapping_mul(GAMMA)), Ordering::Relaxed);
+    }
+
+    pub fn events(&self) -> u64 {
+        self.events.load(Ordering::Relaxed)
+    }
+
+    /// Events added since the last fold.
+    pub fn pending(&self) -> u64 {
+        self.events().wrapping_sub(self.folded_at.load(Ordering::Relaxed))
+    }
+
+    /// The lanes condensed to one word, if `RESEED_EVENTS` events arrived
+    /// since the last fold. Only one caller gets each fold.
+    pub fn fold(&self) -> Option<u64> {
+        let folded_at = self.folded_at.load(Ordering::Relaxed);
+        let events = self.events();
+        if events.wrapping_sub(folded_at) < RESEED_EVENTS {
+            return None;
+        }
+        self.folded_at
+            .compare_exchange(folded_at, events, Ordering::Relaxed, Ordering::Relaxed)
+            .ok()?;
+        Some(self.lanes.iter().fold(0, |acc, lane| mix(acc ^ lane.load(Ordering::Relaxed))))
+    }
+}
+
+impl Default for Pool {
+    fn default() -> Pool {
+        Pool::new()
+    }
+}
+
+/// Called from interrupt handlers: feeds the TSC's low bits, the part
+/// that jitters, into the pool.
+pub(crate) fn add_interrupt_event() {
+    POOL.add(time::rdtsc() & 0xffff_ffff);
+}
+
+/// Interrupt events harvested since boot.
+pub fn entropy_events() -> u64 {
+    POOL.events()
+}
+
+fn has_rdrand() -> bool {
+    crate::cpu::has(Feature::Rdrand)
+}
+
+fn has_rdseed() -> bool {
+    crate::cpu::has(Feature::Rdseed)
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0;
+    // the instruction may run dry for a moment; Intel suggests 10 tries
+    for _ in 0..10 {
+        if core::arch::x86_64::_rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed() -> Option<u64> {
+    let mut value = 0;
+    // it fails whenever the entropy source has nothing new yet, so it gets
+    // more tries than RDRAND, with a pause between them
+    for _ in 0..100 {
+        if core::arch::x86_64::_rdseed64_step(&mut value) == 1 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+/// Seeds the state: RDSEED, else RDRAND, if present and working, else the
+/// TSC.
+pub fn init() {
+    let rdrand = has_rdrand().then(|| unsafe { rdrand() }).flatten();
+    HARDWARE.store(rdrand.is_some(), Ordering::Relaxed);
+    let (seed, source) = match (has_rdseed().then(|| unsafe { rdseed() }).flatten(), rdrand) {
+        (Some(seed), _) => (seed, Source::Rdseed),
+        (None, Some(seed)) => (seed, Source::Rdrand),
+        (None, None) => {
+            crate::info!("rng: no RDRAND, seeding from the TSC until the entropy pool fills");
+            (time::rdtsc(), Source::Jitter)
+        }
+    };
+    SEEDED_FROM.store(source as u8, Ordering::Relaxed);
+    STATE.fetch_xor(mix(seed), Ordering::Relaxed);
+}
+
+/// Where the boot seed came from.
+pub fn seed_source() -> Source {
+    Source::from_u8(SEEDED_FROM.load(Ordering::Relaxed))
+}
+
+/// Where `u64` and `fill` get their bits: RDRAND or the jitter pool.
+pub fn source() -> Source {
+    if HARDWARE.load(Ordering::Relaxed) {
+        Source::Rdrand
+    } else {
+        Source::Jitter
+    }
+}
+
+/// Folds the entropy pool into the state. `false` if fewer than
+/// `RESEED_EVENTS` events arrived since the last reseed.
+pub fn reseed_from_pool() -> bool {
+    match POOL.fold() {
+        Some(entropy) => {
+            STATE.fetch_xor(entropy, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn next_u64() -> u64 {
+    if !AUTO_RESEEDED.load(Ordering::Relaxed) && POOL.pending() >= RESEED_EVENTS && reseed_from_pool() {
+        AUTO_RESEEDED.store(true, Ordering::Relaxed);
+    }
+    mix(STATE.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA))
+}
+
+pub fn fill_bytes(buf: &mut [u8]) {
+    fill_with(buf, next_u64);
+}
+
+fn fill_with(buf: &mut [u8], mut next: impl FnMut() -> u64) {
+    for chunk in buf.chunks_mut(8) {
+        chunk.copy_from_slice(&next().to_le_bytes()[..chunk.len()]);
+    }
+}
+
+/// 64 bits from `source()`. Without RDRAND, or if it keeps failing, the
+/// PRNG first folds in the pool if another `RESEED_EVENTS` events arrived.
+pub fn u64() -> u64 {
+    if HARDWARE.load(Ordering::Relaxed) {
+        if let Some(value) = unsafe { rdrand() } {
+            return value;
+        }
+    }
+    reseed_from_pool();
+    next_u64()
+}
+
+/// Fills `buf` from `source()`, like `u64`.
+pub fn fill(buf: &mut [u8]) {
+    fill_with(buf, u64);
+}
+
+//test case
+#[test_case]
+fn test_mix_matches_splitmix64() {
+    // the first outputs of SplitMix64 seeded with 0
+    assert_eq!(mix(GAMMA), 0xe220_a839_7b1d_cdaf);
+    assert_eq!(mix(GAMMA.wrapping_mul(2)), 0x6e78_9e6a_a1b9_65f4);
+    assert_eq!(mix(0), 0);
+}
+
+#[test_case]
+fn test_pool_threshold() {
+    let pool = Pool::new();
+    for sample in 0..RESEED_EVENTS - 1 {
+        pool.add(sample * 37);
+    }
+    assert_eq!(pool.fold(), None);
+    pool.add(5);
+    assert_eq!(pool.events(), RESEED_EVENTS);
+    let first = pool.fold().expect("threshold reached");
+    // taken: the next fold waits for another full batch
+    assert_eq!(pool.pending(), 0);
+    assert_eq!(pool.fold(), None);
+    for _ in 0..RESEED_EVENTS {
+        pool.add(5);
+    }
+    assert_ne!(pool.fold(), Some(first));
+}
+
+#[test_case]
+fn test_pool_is_deterministic_and_order_sensitive() {
+    let script = |samples: &[u64]| {
+        let pool = Pool::new();
+        for _ in 0..RESEED_EVENTS / samples.len() as u64 {
+            for &sample in samples {
+                pool.add(sample);
+            }
+        }
+        pool.fold().unwrap()
+    };
+    assert_eq!(script(&[1, 2, 3, 4]), script(&[1, 2, 3, 4]));
+    assert_ne!(script(&[1, 2, 3, 4]), script(&[2, 1, 3, 4]));
+    // identical events still count: the sequence number goes into each
+    assert_ne!(script(&[7]), 0);
+    // one flipped sample bit changes the result
+    assert_ne!(script(&[1, 2, 3, 4]), script(&[1, 2, 3, 5]));
+}
+
+#[test_case]
+fn test_hardware_source() {
+    // init ran at boot: RDRAND is used exactly when the CPU has it
+    assert_eq!(source() == Source::Rdrand, has_rdrand());
+    if seed_source() == Source::Rdseed {
+        assert!(has_rdseed());
+    }
+    let mut first = [0; 13];
+    let mut second = [0; 13];
+    fill(&mut first);
+    fill(&mut second);
+    assert_ne!(first, second);
+    assert_ne!(u64(), u64());
+}
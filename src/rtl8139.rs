@@ -0,0 +1,441 @@
+//! Realtek RTL8139 Fast Ethernet driver (`-device rtl8139` in QEMU).
+//!
+//! The interrupt handler only acknowledges the chip and counts events;
+//! frames are pulled out of the receive ring by `receive()` in normal
+//! context so the IRQ path never allocates.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+use crate::interrupts;
+use crate::memory::{BootInfoFrameAllocator, DmaRegion};
+use crate::pci::{self, Bar};
+
+pub const VENDOR_ID: u16 = 0x10ec;
+pub const DEVICE_ID: u16 = 0x8139;
+
+const REG_IDR0: u16 = 0x00;
+const REG_TSD0: u16 = 0x10;
+const REG_TSAD0: u16 = 0x20;
+const REG_RBSTART: u16 = 0x30;
+const REG_CR: u16 = 0x37;
+const REG_CAPR: u16 = 0x38;
+const REG_IMR: u16 = 0x3c;
+const REG_ISR: u16 = 0x3e;
+const REG_RCR: u16 = 0x44;
+const REG_CONFIG1: u16 = 0x52;
+
+const CR_BUFE: u8 = 1 << 0;
+const CR_TE: u8 = 1 << 2;
+const CR_RE: u8 = 1 << 3;
+const CR_RST: u8 = 1 << 4;
+
+const INT_ROK: u16 = 1 << 0;
+const INT_RER: u16 = 1 << 1;
+const INT_TOK: u16 = 1 << 2;
+const INT_TER: u16 = 1 << 3;
+const INT_RX_OVERFLOW: u16 = 1 << 4;
+
+/// Accept broadcast, multicast, our MAC and (for experiments) everything.
+const RCR_ACCEPT_ALL: u32 = 0x0f;
+
+/// TSD: the NIC finished moving the buffer into its FIFO.
+const TSD_OWN: u32 = 1 << 13;
+
+/// Receive ring: 8 KiB plus 16 bytes of slack (RBLEN = 00, WRAP = 0), so
+/// packets that cross the end wrap around to the start of the buffer.
+pub const RX_RING_LEN: usize = 8192;
+const RX_BUFFER_PAGES: usize = 3;
+
+pub const TX_SLOTS: usize = 4;
+const TX_SLOT_LEN: usize = 2048;
+pub const MAX_FRAME_LEN: usize = 1792;
+const MIN_FRAME_LEN: usize = 60;
+const RX_QUEUE_LIMIT: usize = 64;
+
+/// Receive status bits in the per-packet header.
+const RX_STATUS_ROK: u16 = 1 << 0;
+const RX_STATUS_BAD: u16 = (1 << 1) | (1 << 2) | (1 << 3) | (1 << 4) | (1 << 5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxError {
+    NoDevice,
+    TooLarge,
+    /// All four transmit slots are still owned by the NIC.
+    Busy,
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxError::NoDevice => write!(f, "no network card present"),
+            TxError::TooLarge => write!(f, "frame longer than {} bytes", MAX_FRAME_LEN),
+            TxError::Busy => write!(f, "transmit slots busy"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RxError {
+    /// The header's status reports a CRC, alignment, runt or length error.
+    BadStatus(u16),
+    /// The length field is impossible (shorter than the CRC or longer than
+    /// the ring), which means we lost sync with the ring.
+    BadLength(u16),
+}
+
+/// One packet header inside the receive ring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RxPacket {
+    pub status: u16,
+    /// Frame length without the trailing CRC.
+    pub frame_len: usize,
+    /// Ring offset where the frame data starts.
+    pub data_offset: usize,
+    /// Ring offset of the next packet header.
+    pub next_offset: usize,
+}
+
+fn ring_u16(ring: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([ring[offset % RX_RING_LEN], ring[(offset + 1) % RX_RING_LEN]])
+}
+
+/// Decodes the packet header at `offset`. The header, the data and the next
+/// offset all wrap at `RX_RING_LEN`.
+pub fn parse_rx_packet(ring: &[u8], offset: usize) -> Result<RxPacket, RxError> {
+    let status = ring_u16(ring, offset);
+    let length = ring_u16(ring, offset + 2);
+    if status & RX_STATUS_BAD != 0 || status & RX_STATUS_ROK == 0 {
+        return Err(RxError::BadStatus(status));
+    }
+    let len = usize::from(length);
+    if !(4..=MAX_FRAME_LEN + 4).contains(&len) {
+        return Err(RxError::BadLength(length));
+    }
+    // next header is dword aligned after the 4-byte header and the frame+CRC
+    let next_offset = ((offset + 4 + len + 3) & !3) % RX_RING_LEN;
+    Ok(RxPacket {
+        status,
+        frame_len: len - 4,
+        data_offset: (offset + 4) % RX_RING_LEN,
+        next_offset,
+    })
+}
+
+/// Copies a frame out of the ring, following the wrap at the end.
+pub fn copy_rx_frame(ring: &[u8], packet: &RxPacket) -> Vec<u8> {
+    let start = packet.data_offset;
+    let first = packet.frame_len.min(RX_RING_LEN - start);
+    let mut frame = Vec::with_capacity(packet.frame_len);
+    frame.extend_from_slice(&ring[start..start + first]);
+    frame.extend_from_slice(&ring[..packet.frame_len - first]);
+    frame
+}
+
+/// CAPR lags the real read offset by 16 bytes (a documented chip quirk).
+pub fn capr_for(offset: usize) -> u16 {
+    (offset as u16).wrapping_sub(16)
+}
+
+/// Round-robin bookkeeping for the four transmit slots. The chip sends them
+/// in order, so the next slot is reusable once the NIC released it.
+#[derive(Debug, Default)]
+pub struct TxRotation {
+    next: usize,
+    in_flight: [bool; TX_SLOTS],
+}
+
+impl TxRotation {
+    /// Claims the next slot. `released(slot)` reports whether the hardware
+    /// gave that slot back (TSD.OWN set).
+    pub fn acquire(&mut self, released: impl Fn(usize) -> bool) -> Result<usize, TxError> {
+        let slot = self.next;
+        if self.in_flight[slot] && !released(slot) {
+            return Err(TxError::Busy);
+        }
+        self.in_flight[slot] = true;
+        self.next = (slot + 1) % TX_SLOTS;
+        Ok(slot)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NicStats {
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub interrupts: u64,
+}
+
+pub struct Rtl8139 {
+    io_base: u16,
+    mac: [u8; 6],
+    rx_buffer: DmaRegion,
+    rx_offset: usize,
+    tx_buffers: DmaRegion,
+    tx: TxRotation,
+    rx_queue: VecDeque<Vec<u8>>,
+    stats: NicStats,
+}
+
+static IO_BASE: AtomicU16 = AtomicU16::new(0);
+static RX_PENDING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static TX_OK: AtomicU64 = AtomicU64::new(0);
+
+fn interrupt_handler() {
+    let io_base = IO_BASE.load(Ordering::Relaxed);
+    let mut isr: Port<u16> = Port::new(io_base + REG_ISR);
+    let status = unsafe { isr.read() };
+    // write-one-to-clear
+    unsafe { isr.write(status) };
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    if status & (INT_ROK | INT_RER | INT_RX_OVERFLOW) != 0 {
+        RX_PENDING.store(true, Ordering::Release);
+    }
+    if status & INT_TOK != 0 {
+        TX_OK.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Rtl8139 {
+    fn new(
+        io_base: u16,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        physical_memory_offset: VirtAddr,
+    ) -> Option<Rtl8139> {
+        let rx_buffer = DmaRegion::allocate(frame_allocator, physical_memory_offset, RX_BUFFER_PAGES)?;
+        let tx_pages = TX_SLOTS * TX_SLOT_LEN / 4096;
+        let tx_buffers = DmaRegion::allocate(frame_allocator, physical_memory_offset, tx_pages)?;
+        let mut nic = Rtl8139 {
+            io_base,
+            mac: [0; 6],
+            rx_buffer,
+            rx_offset: 0,
+            tx_buffers,
+            tx: TxRotation::default(),
+            rx_queue: VecDeque::new(),
+            stats: NicStats::default(),
+        };
+        nic.reset()?;
+        Some(nic)
+    }
+
+    fn reset(&mut self) -> Option<()> {
+        unsafe {
+            // power on (LWAKE + LWPTN low)
+            Port::<u8>::new(self.io_base + REG_CONFIG1).write(0);
+            let mut cr: Port<u8> = Port::new(self.io_base + REG_CR);
+            cr.write(CR_RST);
+            let mut spins = 0;
+            while cr.read() & CR_RST != 0 {
+                spins += 1;
+                if spins > 1_000_000 {
+                    return None;
+                }
+            }
+
+            // The ID registers are loaded from the EEPROM on reset.
+            for (i, byte) in self.mac.iter_mut().enumerate() {
+                *byte = Port::<u8>::new(self.io_base + REG_IDR0 + i as u16).read();
+            }
+
+            Port::<u32>::new(self.io_base + REG_RBSTART).write(self.rx_buffer.phys_addr().as_u64() as u32);
+            for slot in 0..TX_SLOTS {
+                let phys = self.tx_buffers.phys_addr().as_u64() + (slot * TX_SLOT_LEN) as u64;
+                Port::<u32>::new(self.io_base + REG_TSAD0 + 4 * slot as u16).write(phys as u32);
+            }
+            Port::<u16>::new(self.io_base + REG_IMR).write(INT_ROK | INT_RER | INT_TOK | INT_TER | INT_RX_OVERFLOW);
+            Port::<u32>::new(self.io_base + REG_RCR).write(RCR_ACCEPT_ALL);
+            cr.write(CR_RE | CR_TE);
+        }
+        self.rx_offset = 0;
+        Some(())
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    pub fn stats(&self) -> NicStats {
+        NicStats {
+            tx_packets: TX_OK.load(Ordering::Relaxed),
+            interrupts: INTERRUPTS.load(Ordering::Relaxed),
+            ..self.stats
+        }
+    }
+
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), TxError> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(TxError::TooLarge);
+        }
+        let io_base = self.io_base;
+        let slot = self.tx.acquire(|slot| {
+            let tsd = unsafe { Port::<u32>::new(io_base + REG_TSD0 + 4 * slot as u16).read() };
+            tsd & TSD_OWN != 0
+        })?;
+
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(self.tx_buffers.as_mut_ptr().add(slot * TX_SLOT_LEN), TX_SLOT_LEN)
+        };
+        buffer[..frame.len()].copy_from_slice(frame);
+        // short frames are padded to the Ethernet minimum
+        let len = frame.len().max(MIN_FRAME_LEN);
+        buffer[frame.len()..len].fill(0);
+        // writing the size with OWN clear starts the transmission
+        unsafe { Port::<u32>::new(io_base + REG_TSD0 + 4 * slot as u16).write(len as u32) };
+        Ok(())
+    }
+
+    fn ring(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.rx_buffer.as_mut_ptr(), RX_RING_LEN) }
+    }
+
+    fn buffer_empty(&self) -> bool {
+        unsafe { Port::<u8>::new(self.io_base + REG_CR).read() & CR_BUFE != 0 }
+    }
+
+    /// Moves every frame waiting in the ring into the receive queue.
+    pub fn poll(&mut self) {
+        RX_PENDING.store(false, Ordering::Release);
+        while !self.buffer_empty() {
+            match parse_rx_packet(self.ring(), self.rx_offset) {
+                Ok(packet) => {
+                    let frame = copy_rx_frame(self.ring(), &packet);
+                    self.stats.rx_packets += 1;
+                    if self.rx_queue.len() >= RX_QUEUE_LIMIT {
+                        self.stats.rx_dropped += 1;
+                    } else {
+                        self.rx_queue.push_back(frame);
+                    }
+                    self.rx_offset = packet.next_offset;
+                    unsafe { Port::<u16>::new(self.io_base + REG_CAPR).write(capr_for(self.rx_offset)) };
+                }
+                Err(_) => {
+                    // Out of sync with the ring: the only safe recovery is a
+                    // receiver reset.
+                    self.stats.rx_errors += 1;
+                    let _ = self.reset();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Next received frame (destination MAC first, CRC stripped).
+    pub fn receive(&mut self) -> Option<Vec<u8>> {
+        if self.rx_queue.is_empty() {
+            self.poll();
+        }
+        self.rx_queue.pop_front()
+    }
+}
+
+pub fn rx_pending() -> bool {
+    RX_PENDING.load(Ordering::Acquire)
+}
+
+pub static NIC: Mutex<Option<Rtl8139>> = Mutex::new(None);
+
+pub struct MacAddr(pub [u8; 6]);
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", m[0], m[1], m[2], m[3], m[4], m[5])
+    }
+}
+
+/// Finds the card, resets it and hooks its IRQ. Returns the MAC address.
+pub fn init(frame_allocator: &mut BootInfoFrameAllocator, physical_memory_offset: VirtAddr) -> Option<[u8; 6]> {
+    let device = pci::find(VENDOR_ID, DEVICE_ID)?;
+    let io_base = match device.bar(0)? {
+        Bar::Io(port) => port,
+        Bar::Memory { .. } => return None,
+    };
+    device.enable_bus_mastering();
+    let nic = Rtl8139::new(io_base, frame_allocator, physical_memory_offset)?;
+    let mac = nic.mac();
+    IO_BASE.store(io_base, Ordering::Relaxed);
+    *NIC.lock() = Some(nic);
+    if device.interrupt_line < 16 {
+        interrupts::register_irq(device.interrupt_line, interrupt_handler);
+    }
+    Some(mac)
+}
+
+/// Sends through the global NIC.
+pub fn send(frame: &[u8]) -> Result<(), TxError> {
+    NIC.lock().as_mut().ok_or(TxError::NoDevice)?.send(frame)
+}
+
+//test case
+#[cfg(test)]
+fn ring_with(offset: usize, status: u16, payload: &[u8]) -> Vec<u8> {
+    let mut ring = alloc::vec![0u8; RX_RING_LEN];
+    let len = (payload.len() + 4) as u16;
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&status.to_le_bytes());
+    bytes.extend_from_slice(&len.to_le_bytes());
+    bytes.extend_from_slice(payload);
+    bytes.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]); // CRC
+    for (i, b) in bytes.iter().enumerate() {
+        ring[(offset + i) % RX_RING_LEN] = *b;
+    }
+    ring
+}
+
+#[test_case]
+fn test_rx_packet_parse() {
+    let payload: Vec<u8> = (0..64u8).collect();
+    let ring = ring_with(0, RX_STATUS_ROK, &payload);
+    let packet = parse_rx_packet(&ring, 0).unwrap();
+    assert_eq!(packet.frame_len, 64);
+    assert_eq!(packet.data_offset, 4);
+    // 4 + 64 + 4 = 72, already aligned
+    assert_eq!(packet.next_offset, 72);
+    assert_eq!(copy_rx_frame(&ring, &packet), payload);
+}
+
+#[test_case]
+fn test_rx_packet_alignment_and_wrap() {
+    let payload: Vec<u8> = (0..61u8).collect();
+    let offset = RX_RING_LEN - 32;
+    let ring = ring_with(offset, RX_STATUS_ROK, &payload);
+    let packet = parse_rx_packet(&ring, offset).unwrap();
+    assert_eq!(packet.frame_len, 61);
+    // 8160 + 4 + 65 = 8229 -> 8232 -> wraps to 40
+    assert_eq!(packet.next_offset, 40);
+    assert_eq!(copy_rx_frame(&ring, &packet), payload);
+}
+
+#[test_case]
+fn test_rx_packet_errors() {
+    let ring = ring_with(0, RX_STATUS_ROK | (1 << 2), &[0; 60]);
+    assert_eq!(parse_rx_packet(&ring, 0), Err(RxError::BadStatus(RX_STATUS_ROK | (1 << 2))));
+    let mut ring = ring_with(0, RX_STATUS_ROK, &[0; 60]);
+    ring[2] = 0xff;
+    ring[3] = 0x7f;
+    assert_eq!(parse_rx_packet(&ring, 0), Err(RxError::BadLength(0x7fff)));
+    assert_eq!(capr_for(0), 0xfff0);
+    assert_eq!(capr_for(72), 56);
+}
+
+#[test_case]
+fn test_tx_slot_rotation() {
+    let mut tx = TxRotation::default();
+    let none_released = |_| false;
+    for expected in 0..TX_SLOTS {
+        assert_eq!(tx.acquire(none_released), Ok(expected));
+    }
+    // slot 0 is still owned by the NIC
+    assert_eq!(tx.acquire(none_released), Err(TxError::Busy));
+    assert_eq!(tx.acquire(|slot| slot == 0), Ok(0));
+    assert_eq!(tx.acquire(|slot| slot == 2), Err(TxError::Busy));
+    assert_eq!(tx.acquire(|_| true), Ok(1));
+}
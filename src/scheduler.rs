@@ -0,0 +1,467 @@
+//! Kernel threads and a round-robin scheduler for them, on the bootstrap
+//! processor.
+//!
+//! `spawn` gives a function a stack of its own, a `memory::KernelStack`
+//! with an unmapped guard page below it, and queues it. Overflowing the
+//! stack hits the guard page, and the fault handlers name the thread
+//! through `stack_owner`. The watchdog watches each thread from its
+//! spawn, and a yield or a wakeup from blocking is a heartbeat.
+//! The timer interrupt takes the CPU from a thread every `quantum_ticks`
+//! and gives it to the next ready one, and `yield_now` does the same
+//! straight away; `exit` (or returning from the function) ends a thread.
+//! Whatever `kernel_main` runs on is the boot thread, which never ends.
+//!
+//! A thread waiting on a `sync::WaitQueue` is `Blocked` and gets no turns
+//! until a notify makes it ready again; `JoinHandle::join` waits that way
+//! for a thread to end. With every thread blocked the CPU halts in the
+//! switch until an interrupt readies one.
+//!
+//! A switch saves the outgoing thread's FPU and SSE registers in its entry
+//! with `fxsave64` and loads the next one's, pushes the callee-saved
+//! registers on the outgoing stack, saves RSP in the entry, loads the next
+//! one's and pops them back. It always happens with interrupts off: a
+//! thread taken off the CPU by the timer resumes inside the interrupt
+//! handler and returns through its `iretq`, one that yielded inside
+//! `without_interrupts`.
+//!
+//! While a user process runs (`process::run`) the timer leaves the kernel
+//! threads alone: the process scheduler owns the CPU until it returns. A
+//! finished thread stays in the table until a thread frees it, in the
+//! next `spawn`, `yield_now` or `join`: the switch that ends it still runs
+//! on its stack, and a switch may be the timer's, which must not reach
+//! the heap.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::VirtAddr;
+use crate::allocator::slab::{Cache, SlabBox};
+use crate::cpu::FpuState;
+use crate::interrupts::ExceptionFrame;
+use crate::memory::KernelStack;
+use crate::sync::{WaitQueue, Waiter};
+use crate::time;
+
+/// Ticks a thread or process runs before the next ready one gets the CPU,
+/// without `quantum=` on the command line.
+pub const DEFAULT_QUANTUM_TICKS: u64 = 2;
+
+static QUANTUM_TICKS: Once<u64> = Once::new();
+
+/// The time slice, `quantum=<ticks>` or `DEFAULT_QUANTUM_TICKS`. Read
+/// from the command line the first time it's asked for.
+pub fn quantum_ticks() -> u64 {
+    *QUANTUM_TICKS.call_once(|| match crate::cmdline::get_u64("quantum") {
+        None => DEFAULT_QUANTUM_TICKS,
+        Some(ticks @ 1..) => ticks,
+        Some(_) => {
+            crate::warn!("scheduler: quantum=0 ignored, using {} ticks", DEFAULT_QUANTUM_TICKS);
+            DEFAULT_QUANTUM_TICKS
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(pub u64);
+
+impl ThreadId {
+    /// `kernel_main`'s.
+    pub const BOOT: ThreadId = ThreadId(0);
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    /// Parked on a wait queue.
+    Blocked,
+    Finished,
+}
+
+impl fmt::Display for ThreadState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ThreadState::Ready => "ready",
+            ThreadState::Running => "running",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Finished => "finished",
+        })
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    state: ThreadState,
+    /// Only held to be freed with the thread; `None` for the boot thread,
+    /// which keeps the stack it came with.
+    stack: Option<KernelStack>,
+    /// Where `switch` left the stack pointer while the thread is off the
+    /// CPU.
+    rsp: u64,
+    /// Unblocked while still running, on its way to blocking: the next
+    /// `block` returns straight away.
+    wakeup: bool,
+    /// The FPU and SSE registers while the thread is off the CPU; a new
+    /// thread starts with the defaults.
+    fpu: FpuState,
+}
+
+impl Thread {
+    fn new(id: ThreadId, state: ThreadState, stack: Option<KernelStack>, rsp: u64) -> Thread {
+        Thread { id, state, stack, rsp, wakeup: false, fpu: FpuState::default() }
+    }
+}
+
+/// Threads live in a slab, like a `Box`, so `switch` can write a saved RSP
+/// and FPU state after the lock is gone; nothing else touches the table
+/// while interrupts are off.
+struct Threads {
+    list: Vec<SlabBox<Thread>>,
+    /// Index of the running thread.
+    current: usize,
+}
+
+static THREADS: Mutex<Threads> = Mutex::new(Threads { list: Vec::new(), current: 0 });
+static THREAD_CACHE: Cache<Thread> = Cache::new("threads");
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// Tick the running thread got the CPU at.
+static SLICE_START: AtomicU64 = AtomicU64::new(0);
+static SWITCHES: AtomicU64 = AtomicU64::new(0);
+/// Notified whenever a thread ends.
+static EXITED: WaitQueue = WaitQueue::new();
+
+/// Saves the FPU state of the running thread in `*old_fpu` and its
+/// callee-saved registers and RSP in `*old_rsp`, and resumes the one whose
+/// RSP is `new_rsp` with the FPU state in `*new_fpu`.
+#[unsafe(naked)]
+unsafe extern "C" fn switch(old_rsp: *mut u64, new_rsp: u64, old_fpu: *mut FpuState, new_fpu: *const FpuState) {
+    core::arch::naked_asm!(
+        "fxsave64 [rdx]",
+        "fxrstor64 [rcx]",
+        "push rbp", "push rbx", "push r12", "push r13", "push r14", "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15", "pop r14", "pop r13", "pop r12", "pop rbx", "pop rbp",
+        "ret",
+    );
+}
+
+/// Where a new thread's first switch returns to: calls `thread_main` with
+/// the function `spawn` left in R12.
+#[unsafe(naked)]
+unsafe extern "C" fn thread_start() -> ! {
+    core::arch::naked_asm!(
+        "mov rdi, r12",
+        "call {main}",
+        "ud2",
+        main = sym thread_main,
+    );
+}
+
+extern "C" fn thread_main(entry: usize) -> ! {
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    interrupts::enable();
+    entry();
+    exit()
+}
+
+/// The words `switch` pops off a new thread's stack, lowest first: R15,
+/// R14, R13, R12 (the entry function), RBX, RBP and the return address.
+/// `top` is 16-byte aligned, so `thread_start` calls with RSP aligned too.
+fn initial_stack(entry: fn()) -> [u64; 7] {
+    [0, 0, 0, entry as usize as u64, 0, 0, thread_start as *const () as u64]
+}
+
+/// A spawned thread, to wait for.
+#[derive(Debug)]
+#[must_use = "dropping the handle leaves the thread running unwatched"]
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        finished(self.id)
+    }
+
+    /// Blocks until the thread has ended.
+    pub fn join(self) {
+        let _ = EXITED.wait_until(Waiter::Kernel, || finished(self.id));
+        reap();
+    }
+}
+
+/// Starts `entry` in a thread of its own. It first runs at the next switch.
+pub fn spawn(entry: fn()) -> JoinHandle {
+    reap();
+    let stack = KernelStack::allocate().expect("no kernel stack for a thread");
+    let top = stack.top().as_u64();
+    let words = initial_stack(entry);
+    let rsp = top - core::mem::size_of_val(&words) as u64;
+    unsafe { (rsp as *mut [u64; 7]).write(words) };
+    let id = ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let thread = SlabBox::new(&THREAD_CACHE, Thread::new(id, ThreadState::Ready, Some(stack), rsp))
+        .expect("no memory for a thread");
+    without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        if threads.list.is_empty() {
+            // whoever spawns the first thread is the boot thread
+            let boot = Thread::new(ThreadId::BOOT, ThreadState::Running, None, 0);
+            threads.list.push(SlabBox::new(&THREAD_CACHE, boot).expect("no memory for a thread"));
+        }
+        threads.list.push(thread);
+    });
+    crate::watchdog::watch(id);
+    JoinHandle { id }
+}
+
+/// Gives the CPU to the next ready thread, if there is one. A yield is a
+/// heartbeat (see `watchdog`).
+pub fn yield_now() {
+    without_interrupts(|| switch_from_current(THREADS.lock(), ThreadState::Ready));
+    crate::watchdog::heartbeat();
+    reap();
+}
+
+/// Ends the running thread. The boot thread can't.
+pub fn exit() -> ! {
+    crate::watchdog::forget(current());
+    interrupts::disable();
+    // joiners only run after the switch below, which marks it finished
+    EXITED.notify_all();
+    let threads = THREADS.lock();
+    assert!(threads.current != 0, "the boot thread can't exit");
+    switch_from_current(threads, ThreadState::Finished);
+    unreachable!("a finished thread was resumed");
+}
+
+/// The thread on the CPU.
+pub fn current() -> ThreadId {
+    without_interrupts(|| {
+        let threads = THREADS.lock();
+        threads.list.get(threads.current).map_or(ThreadId::BOOT, |thread| thread.id)
+    })
+}
+
+/// The running thread, if a `WaitQueue` can block it: threads are in use
+/// and no process owns the CPU.
+pub(crate) fn parkable() -> Option<ThreadId> {
+    if crate::process::current().is_some() {
+        return None;
+    }
+    without_interrupts(|| {
+        let threads = THREADS.lock();
+        threads.list.get(threads.current).map(|thread| thread.id)
+    })
+}
+
+/// Takes the running thread off the CPU until `unblock`, unless it was
+/// unblocked already. Must be called with interrupts off.
+pub(crate) fn block() {
+    let mut threads = THREADS.lock();
+    let current = threads.current;
+    if core::mem::take(&mut threads.list[current].wakeup) {
+        return;
+    }
+    switch_from_current(threads, ThreadState::Blocked);
+    // the time spent waiting is not time stuck
+    crate::watchdog::heartbeat();
+}
+
+/// Makes a blocked thread ready. One that hasn't blocked yet doesn't when
+/// it gets there.
+pub(crate) fn unblock(id: ThreadId) {
+    without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let Some(thread) = threads.list.iter_mut().find(|thread| thread.id == id) else { return };
+        match thread.state {
+            ThreadState::Blocked => thread.state = ThreadState::Ready,
+            ThreadState::Running | ThreadState::Ready => thread.wakeup = true,
+            ThreadState::Finished => {}
+        }
+    });
+}
+
+/// The thread on the CPU, for fault handlers: `None` if the table is
+/// locked.
+pub fn try_current() -> Option<ThreadId> {
+    let threads = THREADS.try_lock()?;
+    Some(threads.list.get(threads.current).map_or(ThreadId::BOOT, |thread| thread.id))
+}
+
+/// The thread whose stack ends at `top` (see `memory::stack_guard_hit`).
+/// Fault handlers call it, so it gives up if the table is locked.
+pub fn stack_owner(top: VirtAddr) -> Option<ThreadId> {
+    let threads = THREADS.try_lock()?;
+    threads
+        .list
+        .iter()
+        .find(|thread| thread.stack.as_ref().is_some_and(|stack| stack.top() == top))
+        .map(|thread| thread.id)
+}
+
+/// `None` once a finished thread has been freed, or for one that never
+/// existed.
+pub fn state(id: ThreadId) -> Option<ThreadState> {
+    without_interrupts(|| THREADS.lock().list.iter().find(|thread| thread.id == id).map(|thread| thread.state))
+}
+
+/// Whether `id` has ended (or was never spawned).
+pub fn finished(id: ThreadId) -> bool {
+    state(id).is_none_or(|state| state == ThreadState::Finished)
+}
+
+/// Every thread still in the table, the boot thread first.
+pub fn list() -> Vec<(ThreadId, ThreadState)> {
+    without_interrupts(|| THREADS.lock().list.iter().map(|thread| (thread.id, thread.state)).collect())
+}
+
+/// Switches from one thread to another since boot.
+pub fn context_switches() -> u64 {
+    SWITCHES.load(Ordering::Relaxed)
+}
+
+/// Called from the timer interrupt after the EOI. Once the running thread
+/// has used up its quantum, hands the CPU to the next ready one.
+pub(crate) fn preempt(frame: &ExceptionFrame) {
+    if frame.stack_frame.code_segment & 3 != 0 || crate::process::current().is_some() {
+        return;
+    }
+    if time::ticks() - SLICE_START.load(Ordering::Relaxed) < quantum_ticks() {
+        return;
+    }
+    let Some(threads) = THREADS.try_lock() else { return };
+    // a thread halting in `switch_from_current` for someone to wake
+    if threads.list.get(threads.current).is_some_and(|thread| thread.state != ThreadState::Running) {
+        return;
+    }
+    switch_from_current(threads, ThreadState::Ready);
+}
+
+/// The first ready thread after `after`, wrapping around.
+fn next_ready(list: &[SlabBox<Thread>], after: usize) -> Option<usize> {
+    (1..=list.len())
+        .map(|i| (after + i) % list.len())
+        .find(|&i| list[i].state == ThreadState::Ready)
+}
+
+/// Puts the running thread in `outgoing` and switches to the next ready
+/// one. With nobody else ready a yield just starts a new quantum, and a
+/// thread that blocks or ends halts here until an interrupt readies one.
+/// Must be called with interrupts off; returns once this thread gets the
+/// CPU back.
+fn switch_from_current(mut threads: MutexGuard<'static, Threads>, outgoing: ThreadState) {
+    let next = crate::time_block!("sched: pick next", {
+        SLICE_START.store(time::ticks(), Ordering::Relaxed);
+        let current = threads.current;
+        threads.list[current].state = outgoing;
+        next_ready(&threads.list, current)
+    });
+    let current = threads.current;
+    let next = match next {
+        Some(next) => next,
+        None => loop {
+            drop(threads);
+            crate::cpu::enable_and_halt();
+            interrupts::disable();
+            threads = THREADS.lock();
+            if let Some(next) = next_ready(&threads.list, current) {
+                break next;
+            }
+        },
+    };
+    threads.list[next].state = ThreadState::Running;
+    if next == current {
+        return;
+    }
+    threads.current = next;
+    SWITCHES.fetch_add(1, Ordering::Relaxed);
+    let old_rsp: *mut u64 = &mut threads.list[current].rsp;
+    let old_fpu: *mut FpuState = &mut threads.list[current].fpu;
+    let new_rsp = threads.list[next].rsp;
+    let new_fpu: *const FpuState = &threads.list[next].fpu;
+    drop(threads);
+    unsafe { switch(old_rsp, new_rsp, old_fpu, new_fpu) };
+}
+
+/// Frees the finished threads. Dropping a stack and a slab slot reaches
+/// the heap and the page tables, so it happens here, in a thread with the
+/// table unlocked, one thread at a time.
+fn reap() {
+    while let Some(dead) = without_interrupts(|| take_finished(&mut THREADS.lock())) {
+        drop(dead);
+    }
+}
+
+/// Takes a finished thread other than the running one, which may be
+/// finishing right now, out of the table.
+fn take_finished(threads: &mut Threads) -> Option<SlabBox<Thread>> {
+    let current = threads.current;
+    let dead = threads
+        .list
+        .iter()
+        .enumerate()
+        .position(|(i, thread)| i != current && thread.state == ThreadState::Finished)?;
+    if dead < current {
+        threads.current -= 1;
+    }
+    Some(threads.list.remove(dead))
+}
+
+//test case
+#[test_case]
+fn test_next_ready_round_robin() {
+    let thread = |id, state| SlabBox::new(&THREAD_CACHE, Thread::new(ThreadId(id), state, None, 0)).unwrap();
+    let list = [
+        thread(0, ThreadState::Ready),
+        thread(1, ThreadState::Running),
+        thread(2, ThreadState::Finished),
+        thread(3, ThreadState::Ready),
+    ];
+    assert_eq!(next_ready(&list, 1), Some(3));
+    assert_eq!(next_ready(&list, 3), Some(0));
+    assert_eq!(next_ready(&list, 0), Some(3));
+    assert_eq!(next_ready(&list[1..3], 0), None);
+}
+
+#[test_case]
+fn test_take_finished_keeps_the_running_thread() {
+    let thread = |id, state| SlabBox::new(&THREAD_CACHE, Thread::new(ThreadId(id), state, None, 0)).unwrap();
+    let mut threads = Threads {
+        list: alloc::vec![
+            thread(0, ThreadState::Finished),
+            thread(1, ThreadState::Ready),
+            thread(2, ThreadState::Finished),
+            thread(3, ThreadState::Finished),
+        ],
+        current: 2,
+    };
+    assert_eq!(take_finished(&mut threads).map(|thread| thread.id), Some(ThreadId(0)));
+    assert_eq!(threads.list[threads.current].id, ThreadId(2));
+    assert_eq!(take_finished(&mut threads).map(|thread| thread.id), Some(ThreadId(3)));
+    assert!(take_finished(&mut threads).is_none());
+    assert_eq!(threads.list.len(), 2);
+    assert_eq!(threads.list[threads.current].id, ThreadId(2));
+}
+
+#[test_case]
+fn test_initial_stack() {
+    fn entry() {}
+    let words = initial_stack(entry);
+    assert_eq!(words[3], entry as *const () as u64);
+    assert_eq!(words[6], thread_start as *const () as u64);
+    // with the return address popped RSP is back at the aligned top
+    assert_eq!(core::mem::size_of_val(&words) % 16, 8);
+}
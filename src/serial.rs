@@ -2,6 +2,8 @@
 use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
@@ -11,10 +13,125 @@ lazy_static! {
     };
 }
 
+const COM1: u16 = 0x3F8;
+const LINE_STATUS: u16 = COM1 + 5;
+
+/// Waits until the UART has shifted out everything written so far.
+pub fn flush() {
+    const TRANSMITTER_EMPTY: u8 = 1 << 6;
+    let mut line_status: Port<u8> = Port::new(LINE_STATUS);
+    for _ in 0..100_000 {
+        if unsafe { line_status.read() } & TRANSMITTER_EMPTY != 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Returns the next received byte, if any. Goes straight to the UART so
+/// binary transfers don't contend with `SERIAL1`'s lock.
+pub fn try_read_byte() -> Option<u8> {
+    const DATA_READY: u8 = 1 << 0;
+    let mut line_status: Port<u8> = Port::new(LINE_STATUS);
+    let mut data: Port<u8> = Port::new(COM1);
+    unsafe {
+        if line_status.read() & DATA_READY != 0 {
+            Some(data.read())
+        } else {
+            None
+        }
+    }
+}
+
+/// Has the UART raise IRQ 4 when a byte arrives, and nothing else.
+pub fn enable_receive_interrupt() {
+    const RECEIVED_DATA_AVAILABLE: u8 = 1 << 0;
+    lazy_static::initialize(&SERIAL1);
+    let mut interrupt_enable: Port<u8> = Port::new(COM1 + 1);
+    unsafe { interrupt_enable.write(RECEIVED_DATA_AVAILABLE) };
+}
+
+/// Sends one byte without going through `SERIAL1`.
+pub fn write_byte_raw(byte: u8) {
+    const HOLDING_EMPTY: u8 = 1 << 5;
+    let mut line_status: Port<u8> = Port::new(LINE_STATUS);
+    let mut data: Port<u8> = Port::new(COM1);
+    unsafe {
+        while line_status.read() & HOLDING_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        data.write(byte);
+    }
+}
+
+/// Output straight to the UART, for when `SERIAL1` may be held by the
+/// code that was interrupted.
+pub struct RawSerial;
+
+impl core::fmt::Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        s.bytes().for_each(write_byte_raw);
+        Ok(())
+    }
+}
+
+/// Output with the console's NL to CRLF translation.
+struct Cooked<'a>(&'a mut SerialPort);
+
+impl core::fmt::Write for Cooked<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut result = Ok(());
+        crate::console::write_cooked(s, |part| result = result.and(self.0.write_str(part)));
+        result
+    }
+}
+
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+/// Whether the next byte `_print` sends starts a line. Kept while
+/// timestamps are off too, so turning them on mid-line waits for the next.
+static LINE_START: AtomicBool = AtomicBool::new(true);
+
+/// Starts or stops prefixing each line of `serial_print!` output with the
+/// seconds since boot.
+pub fn set_timestamps(on: bool) {
+    TIMESTAMPS.store(on, Ordering::Relaxed);
+}
+
+/// Output with `[    s.mmm] ` in front of each line, `stamp` being the
+/// milliseconds since boot, or nothing in front with no stamp.
+struct Stamped<'a, W> {
+    out: W,
+    stamp: Option<u64>,
+    line_start: &'a AtomicBool,
+}
+
+impl<W: core::fmt::Write> core::fmt::Write for Stamped<'_, W> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for line in s.split_inclusive('\n') {
+            if let Some(ms) = self.stamp.filter(|_| self.line_start.load(Ordering::Relaxed)) {
+                write!(self.out, "[{:>5}.{:03}] ", ms / 1000, ms % 1000)?;
+            }
+            self.out.write_str(line)?;
+            self.line_start.store(line.ends_with('\n'), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+    let stamp = TIMESTAMPS.load(Ordering::Relaxed).then(crate::time::uptime_ms);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut serial = SERIAL1.lock();
+        if crate::console::serial_output_cooked() {
+            Stamped { out: Cooked(&mut serial), stamp, line_start: &LINE_START }
+                .write_fmt(args).expect("Printing to serial failed");
+        } else {
+            Stamped { out: &mut *serial, stamp, line_start: &LINE_START }
+                .write_fmt(args).expect("Printing to serial failed");
+        }
+    });
 }
 
 
@@ -32,4 +149,30 @@ macro_rules! serial_println {
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
+
+//test case
+#[cfg(test)]
+fn stamped(pieces: &[&str], stamp: Option<u64>, line_start: &AtomicBool) -> alloc::string::String {
+    use core::fmt::Write;
+    let mut out = alloc::string::String::new();
+    let mut writer = Stamped { out: &mut out, stamp, line_start };
+    for piece in pieces {
+        writer.write_str(piece).unwrap();
+    }
+    out
+}
+
+#[test_case]
+fn test_timestamp_prefix() {
+    let line_start = AtomicBool::new(true);
+    assert_eq!(stamped(&["a\nb", "c\n", "\n"], Some(1234), &line_start),
+        "[    1.234] a\n[    1.234] bc\n[    1.234] \n");
+    assert!(line_start.load(Ordering::Relaxed));
+    // mid-line when turned on: the stamp waits for the next line
+    let line_start = AtomicBool::new(false);
+    assert_eq!(stamped(&["rest\nnext"], Some(98_765_001), &line_start), "rest\n[98765.001] next");
+    assert!(!line_start.load(Ordering::Relaxed));
+    assert_eq!(stamped(&["x\ny\n"], None, &line_start), "x\ny\n");
+    assert!(line_start.load(Ordering::Relaxed));
+}
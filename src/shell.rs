@@ -1,48 +1,2000 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::console::Completer;
+use crate::block::{BlockDevice, BlockError, OpenError, Registry};
+use crate::vfs::{FileHandle, MountTable, Source, VfsError};
+use crate::vga_buffer::Color;
 use crate::{print, println};
 
 pub struct Shell {
     input: String,
+    /// Command waiting for a `y` before it runs.
+    pending: Option<&'static str>,
+    /// Exit status of the command running now.
+    status: u8,
+    /// Exit status of the command before it, for `$?`.
+    last_status: u8,
+    /// Where relative paths start, normalized.
+    cwd: String,
+    /// What the running command reads, if its line gave it anything: a
+    /// file after `<` or the pipe from the stage before.
+    stdin: Option<Box<dyn FileHandle>>,
+    /// Where the running command writes.
+    stdout: Stdout,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Shell {
     pub fn new() -> Self {
         Shell {
             input: String::new(),
+            pending: None,
+            status: 0,
+            last_status: 0,
+            cwd: String::from(crate::fs::MOUNT_POINT),
+            stdin: None,
+            stdout: Stdout::terminal(),
+        }
+    }
+
+    /// The working directory.
+    pub fn cwd(&self) -> &str {
+        &self.cwd
+    }
+
+    fn resolve(&self, path: &str) -> String {
+        resolve_in(&self.cwd, path)
+    }
+
+    /// Runs a line the console finished; it has been echoed already.
+    pub fn handle_line(&mut self, line: &str) {
+        self.input.clear();
+        self.input.push_str(line);
+        self.execute();
+        prompt();
+    }
+
+    /// Ctrl+C at the prompt: a command waiting for confirmation is dropped.
+    pub fn interrupt(&mut self) {
+        if let Some(command) = self.pending.take() {
+            println!("{}: cancelled", command);
+        }
+        self.input.clear();
+        prompt();
+    }
+
+    fn execute(&mut self) {
+        if let Some(command) = self.pending.take() {
+            if self.input.trim().eq_ignore_ascii_case("y") {
+                self.run_confirmed(command);
+            } else {
+                println!("{}: cancelled", command);
+            }
+            self.input.clear();
+            return;
+        }
+        self.last_status = core::mem::take(&mut self.status);
+        let input = core::mem::take(&mut self.input);
+        match parse_pipeline(&input) {
+            Ok(stages) => self.run_pipeline(&stages),
+            Err(err) => self.finish("sh", Err(err)),
+        }
+        self.input.clear();
+    }
+
+    /// Opens every stage's input and output, then runs them: a single
+    /// command here, a pipeline with one kernel thread per stage, all at
+    /// once over pipes of `pipe::PIPE_CAPACITY`. A stage's output goes to
+    /// its `>` file, or into the pipe the next stage reads, or to the
+    /// terminal for the last; the line's status is the last stage's.
+    fn run_pipeline(&mut self, stages: &[Stage]) {
+        self.status = 0;
+        let mut ends = Vec::new();
+        let mut piped: Option<Box<dyn FileHandle>> = None;
+        for (i, stage) in stages.iter().enumerate() {
+            let stdin = match &stage.input {
+                Some(path) => match crate::vfs::open(&self.resolve(path)) {
+                    Ok(file) => Some(file),
+                    Err(err) => return self.finish("sh", Err(CommandError::Redirect(path.clone(), alloc::format!("{}", err)))),
+                },
+                None => piped.take(),
+            };
+            let stdout = match &stage.output {
+                Some((path, append)) => match open_output(&self.resolve(path), *append) {
+                    Ok(stdout) => stdout,
+                    Err(err) => return self.finish("sh", Err(CommandError::Redirect(path.clone(), err))),
+                },
+                None if i + 1 < stages.len() => {
+                    let (reader, writer) = crate::pipe::pipe();
+                    piped = Some(Box::new(reader));
+                    Stdout::to(Box::new(writer), 0)
+                }
+                None => Stdout::terminal(),
+            };
+            ends.push((stdin, stdout));
+        }
+        if let [(stdin, stdout)] = &mut ends[..] {
+            self.stdin = stdin.take();
+            self.stdout = core::mem::replace(stdout, Stdout::terminal());
+            self.run_stage(&stages[0].command);
+            return;
+        }
+        let mut running = Vec::new();
+        for (stage, (stdin, stdout)) in stages.iter().zip(ends) {
+            let shell = Shell { cwd: self.cwd.clone(), last_status: self.last_status, stdin, stdout, ..Shell::new() };
+            let done = Arc::new(Mutex::new(None));
+            let job = Job { shell, command: stage.command.clone(), done: done.clone() };
+            without_interrupts(|| JOBS.lock().push_back(job));
+            running.push((crate::scheduler::spawn(stage_thread), done));
+        }
+        for (thread, done) in running {
+            thread.join();
+            if let Some(shell) = done.lock().take() {
+                self.status = shell.status;
+                self.pending = self.pending.or(shell.pending);
+            }
         }
     }
 
-    pub fn handle_key(&mut self, key: char) {
-        match key {
-            '\n' => {
-                println!();
-                self.execute();
-                print!("> ");
+    /// Runs one stage with the input and output set up for it, then
+    /// closes both, which ends the pipes on either side.
+    fn run_stage(&mut self, command: &str) {
+        self.run_command(command);
+        self.stdin = None;
+        match core::mem::replace(&mut self.stdout, Stdout::terminal()).error {
+            // the next stage stopped reading, which is not this one failing
+            None | Some(VfsError::BrokenPipe) => {}
+            Some(err) => self.finish("sh", Err(CommandError::Vfs(err))),
+        }
+    }
+
+    fn run_command(&mut self, line: &str) {
+        let (name, args) = split_command(line);
+        if name.is_empty() {
+            return;
+        }
+        let registered = REGISTRY.lock().find(name);
+        match (find_command(name), registered) {
+            (Some(run), _) => run(self, args),
+            (None, Some(command)) => self.run_registered(command, args),
+            (None, None) => crate::println_colored!(Color::LightRed, "{}: unknown command, try help\nCommands: {}", name, command_names()),
+        }
+    }
+
+    /// Writes what a command produced, or prints why it failed, and keeps
+    /// its status. The failure reaches the terminal even if the output is
+    /// redirected.
+    fn finish(&mut self, command: &str, result: Result<String, CommandError>) {
+        match result {
+            Ok(output) => {
+                let _ = self.stdout.write_str(&output);
             }
-            '\x08' => {
-                self.input.pop();
-                print!("{}", key);
+            Err(err) => {
+                crate::println_colored!(Color::LightRed, "{}: {}", command, err);
+                self.status = err.status();
             }
-            c => {
-                self.input.push(c);
-                print!("{}", c);
+        }
+    }
+
+    /// All of `stdin`, up to `MAX_INPUT`; `None` if the command was given
+    /// nothing to read.
+    fn read_stdin(&mut self) -> Option<Result<Vec<u8>, CommandError>> {
+        self.stdin.as_mut().map(|file| read_all(&mut **file))
+    }
+
+    /// The lock is gone by now, so a command may register others.
+    fn run_registered(&mut self, command: RegisteredCommand, args: &str) {
+        let result = split_args(args).and_then(|args| {
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            (command.handler)(&args, &mut self.stdout)
+        });
+        if let Err(err) = result {
+            crate::println_colored!(Color::LightRed, "{}: {}", command.name, err);
+            self.status = err.status();
+        }
+    }
+
+    fn run_confirmed(&mut self, command: &str) {
+        if command == "run-serial" {
+            println!("Start the XMODEM upload now...");
+            match crate::loader::run_from_serial() {
+                Ok(result) => println!("run-serial: returned {:#x}", result),
+                Err(err) => println!("run-serial: {}", err),
             }
         }
     }
+}
 
-    fn execute(&mut self) {
-        match self.input.as_str() {
-            "help" => println!("Comandos: help, clear, echo"),
-            "clear" => {
-                for _ in 0..50 {
-                    println!();
+/// Where a command's output goes: the terminal, or the file or pipe its
+/// line sent it to.
+pub struct Stdout {
+    file: Option<Box<dyn FileHandle>>,
+    /// Where the next write lands in `file`; pipes ignore it.
+    offset: u64,
+    /// Why writing to `file` stopped. Nothing more is written after it.
+    error: Option<VfsError>,
+}
+
+impl Stdout {
+    fn terminal() -> Stdout {
+        Stdout { file: None, offset: 0, error: None }
+    }
+
+    fn to(file: Box<dyn FileHandle>, offset: u64) -> Stdout {
+        Stdout { file: Some(file), offset, error: None }
+    }
+}
+
+impl Write for Stdout {
+    /// A write to a full pipe waits for the next stage to read.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let Some(file) = self.file.as_mut() else {
+            print!("{}", s);
+            return Ok(());
+        };
+        if self.error.is_some() {
+            return Err(fmt::Error);
+        }
+        let mut data = s.as_bytes();
+        while !data.is_empty() {
+            match file.write(self.offset, data) {
+                Ok(0) => return Err(fmt::Error),
+                Ok(n) => {
+                    self.offset += n as u64;
+                    data = &data[n..];
+                }
+                Err(err) => {
+                    self.error = Some(err);
+                    return Err(fmt::Error);
                 }
             }
-            cmd if cmd.starts_with("echo ") => {
-                println!("{}", &cmd[5..]);
+        }
+        Ok(())
+    }
+}
+
+/// The output of a `>` or `>>`: the file at the absolute `path`, emptied
+/// first or with `append` written after what it holds, and created if it
+/// is not there.
+fn open_output(path: &str, append: bool) -> Result<Stdout, String> {
+    if on_ramfs(path) {
+        let file = crate::fs::open_writer(ramfs_name(path), append).map_err(|err| alloc::format!("{}", err))?;
+        let offset = file.size();
+        return Ok(Stdout::to(file, offset));
+    }
+    let file = match crate::vfs::open(path) {
+        Ok(file) if append => file,
+        Ok(_) | Err(VfsError::NotFound) => crate::vfs::create(path).map_err(|err| alloc::format!("{}", err))?,
+        Err(err) => return Err(alloc::format!("{}", err)),
+    };
+    let offset = file.size();
+    Ok(Stdout::to(file, offset))
+}
+
+/// A pipeline stage on its way to the thread that runs it: the shell it
+/// runs on, set up with the stage's input and output, and its command.
+/// The thread hands the shell back through `done` when it ends.
+struct Job {
+    shell: Shell,
+    command: String,
+    done: Arc<Mutex<Option<Shell>>>,
+}
+
+/// Stages waiting for their thread. Each thread takes one, whichever.
+static JOBS: Mutex<VecDeque<Job>> = Mutex::new(VecDeque::new());
+
+fn stage_thread() {
+    let Some(mut job) = without_interrupts(|| JOBS.lock().pop_front()) else { return };
+    job.shell.run_stage(&job.command);
+    *job.done.lock() = Some(job.shell);
+}
+
+/// The prompt, in its own color so it stands out from command output. The
+/// color is an escape sequence in it, so the console can type the prompt
+/// again after listing completions.
+pub const PROMPT: &str = "\x1b[92m> \x1b[0m";
+
+pub fn prompt() {
+    print!("{}", PROMPT);
+}
+
+/// Sets up Tab completion on the console.
+pub fn init() {
+    crate::console::set_completer(Completer { candidates: complete, prompt: PROMPT });
+}
+
+/// Commands whose first argument is a path.
+const PATH_COMMANDS: &[&str] = &["ls", "cat", "cd", "write", "append", "rm", "spawn", "run"];
+
+/// Tab completion: a command name for the first word, and for the one
+/// after `help`; a path for the first argument of `PATH_COMMANDS`. These
+/// are every possibility, the console keeps those that start with the word.
+fn complete(line: &str) -> Vec<String> {
+    let words: Vec<&str> = line.split(' ').filter(|word| !word.is_empty()).collect();
+    // after a space the word being completed is a new, empty one
+    let word = if line.ends_with(' ') { "" } else { words.last().copied().unwrap_or("") };
+    let index = if line.ends_with(' ') { words.len() } else { words.len().saturating_sub(1) };
+    match (index, words.first()) {
+        (0, _) | (1, Some(&"help")) => all_commands().into_iter().map(|(name, _)| String::from(name)).collect(),
+        (1, Some(command)) if PATH_COMMANDS.contains(command) => complete_path(word),
+        _ => Vec::new(),
+    }
+}
+
+/// The entries of the directory `word` is in, spelled the way `word`
+/// starts; a directory ends in `/`. A relative word is looked up from the
+/// shell's working directory, as the commands take it.
+fn complete_path(word: &str) -> Vec<String> {
+    let dir = word.rfind('/').map_or("", |i| &word[..=i]);
+    let cwd = crate::interrupts::shell_cwd().unwrap_or_else(|| String::from(crate::fs::MOUNT_POINT));
+    let Ok(entries) = crate::vfs::read_dir(&resolve_in(&cwd, dir)) else { return Vec::new() };
+    entries
+        .iter()
+        .map(|entry| {
+            let slash = if entry.metadata().is_dir() { "/" } else { "" };
+            alloc::format!("{}{}{}", dir, entry.name(), slash)
+        })
+        .collect()
+}
+
+/// What a command gets: the shell and everything after its name, trimmed.
+type Command = fn(&mut Shell, &str);
+
+/// `print!` for command output, which goes to a `Stdout`. A write that
+/// fails (the next stage stopped reading) is kept there, not returned.
+macro_rules! out {
+    ($out:expr, $($arg:tt)*) => {{
+        let _ = write!($out, $($arg)*);
+    }};
+}
+
+/// `println!` for command output.
+macro_rules! outln {
+    ($out:expr, $($arg:tt)*) => {{
+        let _ = writeln!($out, $($arg)*);
+    }};
+}
+
+/// The built-in commands with their help, in the order `help` lists them.
+/// Those registered by other modules come after them.
+const COMMANDS: &[(&str, &str, Command)] = &[
+    ("help", "[command]: the commands, or what one does", |shell, args| out!(shell.stdout, "{}", help(args))),
+    ("clear", "clears the screen", |_, _| crate::vga_buffer::clear_screen()),
+    ("echo", "<text>: prints the text, $? being the last status", |shell, args| outln!(shell.stdout, "{}", args.replace("$?", &alloc::format!("{}", shell.last_status)))),
+    ("info", "the kernel version", |shell, _| outln!(shell.stdout, "Kernel v0.1.0 | berryOS v0.1.0 - x86_64")),
+    ("ping", "<ip>: sends four echo requests", |shell, args| {
+        match args.parse() {
+            Ok(ip) => {
+                if let Err(err) = crate::net::ping(ip, 4, &mut shell.stdout) {
+                    outln!(shell.stdout, "ping: {}", err);
+                }
             }
-            _ => println!("Comando no encontrado: {}", self.input),
+            Err(()) => outln!(shell.stdout, "ping: invalid address"),
         }
-        self.input.clear();
+    }),
+    ("udp", "<ip> <port> <text>: sends the text, prints what comes back", |shell, args| {
+        let result = udp(args);
+        shell.finish("udp", result);
+    }),
+    ("dhcp", "asks a DHCP server for an address", |shell, _| {
+        let result = crate::net::dhcp()
+            .map(|config| alloc::format!("{} mask {} gateway {}\n", config.ip, config.netmask, config.gateway))
+            .map_err(CommandError::Net);
+        shell.finish("dhcp", result);
+    }),
+    ("sysinfo", "uptime, idle time, memory and dropped input", |shell, _| sysinfo(&mut shell.stdout)),
+    ("mem", "the heap and frame allocator", |shell, _| mem(&mut shell.stdout)),
+    ("slabinfo", "objects in each slab cache", |shell, _| out!(shell.stdout, "{}", slabinfo())),
+    ("free", "frames in total, in use and free", |shell, _| {
+        let result = crate::memory::stats().map(|stats| free(&stats)).ok_or(CommandError::NoPaging);
+        shell.finish("free", result);
+    }),
+    ("vmmap", "the kernel's tracked virtual memory regions", |shell, _| out!(shell.stdout, "{}", vmmap())),
+    ("random", "[bytes]: random bytes in hex, 16 by default", |shell, args| {
+        let result = random(args);
+        shell.finish("random", result);
+    }),
+    ("pmap", "<addr>: the physical address a virtual one maps to", |shell, args| {
+        let result = pmap(args);
+        shell.finish("pmap", result);
+    }),
+    ("peek", "<addr> [len]: a hex dump of mapped memory", |shell, args| {
+        let result = peek(args);
+        shell.finish("peek", result);
+    }),
+    ("poke", "<addr> <byte>: writes a byte to mapped, writable memory", |shell, args| {
+        let result = poke(args);
+        shell.finish("poke", result);
+    }),
+    ("lspci", "the devices on the PCI bus", |shell, _| {
+        for device in crate::pci::devices() {
+            outln!(shell.stdout, "{}", device);
+        }
+    }),
+    ("cpuinfo", "CPU model, features and the CPUs online", |shell, _| out!(shell.stdout, "{}", cpuinfo())),
+    ("boottime", "how long each boot stage took", |shell, _| {
+        let _ = crate::boottime::report_to(&mut shell.stdout);
+    }),
+    ("uptime", "time since boot", |shell, _| {
+        let uptime = crate::time::uptime_ms();
+        outln!(shell.stdout, "up {}.{:03} s", uptime / 1000, uptime % 1000);
+    }),
+    ("perf", "the performance counters", |shell, _| {
+        let _ = crate::perf::report(&mut shell.stdout);
+    }),
+    ("stats", "the kernel's event counters and cycle histograms", |shell, _| out!(shell.stdout, "{}", crate::stats::summary())),
+    ("profile", "start|stop|report: counters and histograms over a window", |shell, args| {
+        let result = profile(args);
+        shell.finish("profile", result);
+    }),
+    ("run-serial", "receives a program over COM1 and runs it in ring 0", |shell, _| {
+        outln!(shell.stdout, "Receive up to {} KiB over COM1 (XMODEM) and run it in ring 0? [y/N]",
+            crate::loader::MAX_FLAT_SIZE / 1024);
+        shell.pending = Some("run-serial");
+    }),
+    ("ring3", "runs the user mode demo", |shell, _| match crate::usermode::run_demo() {
+        Ok(code) => outln!(shell.stdout, "ring3: program exited with {}", code),
+        Err(err) => outln!(shell.stdout, "ring3: {}", err),
+    }),
+    ("sysbench", "times syscall against int 0x80", |shell, _| match crate::usermode::benchmark_syscalls(5) {
+        Ok((fast, slow)) => outln!(shell.stdout, "sysbench: syscall {} cycles, int 0x80 {} cycles", fast, slow),
+        Err(err) => outln!(shell.stdout, "sysbench: {}", err),
+    }),
+    ("spawn", "[path]: starts an ELF program as a process, the demo without one", |shell, args| {
+        let result = spawn(&shell.resolve(args), args.is_empty());
+        shell.finish("spawn", result);
+    }),
+    ("run", "[path]: runs an ELF program to its exit, or the processes until they all exit", |shell, args| {
+        if !args.is_empty() {
+            let result = run_program(&shell.resolve(args));
+            shell.finish("run", result);
+        } else if !crate::process::run() {
+            outln!(shell.stdout, "run: processes are already running");
+        }
+    }),
+    ("ps", "the processes, their parents and their states", |shell, _| {
+        outln!(shell.stdout, "  PID  PPID STATE");
+        for (pid, state) in crate::process::list() {
+            match crate::process::parent(pid) {
+                Some(parent) => outln!(shell.stdout, "{:>5} {:>5} {}", pid, parent, state),
+                None => outln!(shell.stdout, "{:>5}     - {}", pid, state),
+            }
+        }
+    }),
+    ("kill", "<pid>: ends a process", |shell, args| match args.parse() {
+        Ok(pid) => match crate::process::kill(crate::process::Pid(pid)) {
+            Ok(()) => outln!(shell.stdout, "killed process {}", pid),
+            Err(err) => outln!(shell.stdout, "kill: {}", err),
+        },
+        Err(_) => outln!(shell.stdout, "usage: kill <pid>"),
+    }),
+    ("kbrate", "<cps> <delay ms>: the keyboard repeat rate", |shell, args| kbrate(args, &mut shell.stdout)),
+    ("date", "[set <YYYY-MM-DD HH:MM:SS>]: shows or sets the RTC", |shell, args| date(args, &mut shell.stdout)),
+    ("loglevel", "[level]: shows or sets the most verbose level logged", |shell, args| loglevel(args, &mut shell.stdout)),
+    ("history", "the lines entered on this terminal", |shell, _| {
+        let lines = crate::console::history(crate::console::active_terminal());
+        for (i, line) in lines.iter().enumerate() {
+            outln!(shell.stdout, "{:>4}  {}", i + 1, line);
+        }
+    }),
+    ("dmesg", "[-c]: the kernel log, -c clearing it", |shell, args| match args {
+        "" => out!(shell.stdout, "{}", crate::log::dmesg()),
+        "-c" => {
+            out!(shell.stdout, "{}", crate::log::dmesg());
+            crate::log::clear_dmesg();
+        }
+        _ => outln!(shell.stdout, "usage: dmesg [-c]"),
+    }),
+    ("pagetable", "[addr]: the page tables, or how addr translates", |shell, args| pagetable(args, &mut shell.stdout)),
+    ("rdmsr", "<name|hex>: reads an MSR", |shell, args| rdmsr(args, &mut shell.stdout)),
+    ("wrmsr", "-f <name|hex> <hex value>: writes an MSR", |shell, args| wrmsr(args, &mut shell.stdout)),
+    ("savesettings", "keeps the settings in CMOS", |shell, _| {
+        let settings = crate::cmos::Settings::current();
+        crate::cmos::store_settings(&settings);
+        outln!(shell.stdout, "saved to CMOS: {:?}", settings);
+    }),
+    ("pwd", "the working directory", |shell, _| outln!(shell.stdout, "{}", shell.cwd)),
+    ("cd", "[path]: changes the working directory, by default to /ram", |shell, args| {
+        let result = cd(shell, args, &crate::vfs::MOUNTS);
+        shell.finish("cd", result);
+    }),
+    ("ls", "[path]: lists a directory", |shell, args| ls(&shell.resolve(args), &mut shell.stdout)),
+    ("cat", "[path]: prints a file, or what comes after < or |", |shell, args| {
+        let result = cat(shell, args);
+        shell.finish("cat", result);
+    }),
+    ("grep", "<text> [path]: the lines of a file, or of what comes after < or |, holding the text", |shell, args| {
+        let result = grep(shell, args);
+        shell.finish("grep", result);
+    }),
+    ("write", "<file> <text>: writes a file", |shell, args| write_file(shell, "write", args, false)),
+    ("append", "<file> <text>: appends to a file", |shell, args| write_file(shell, "append", args, true)),
+    ("rm", "<file>: removes a file", |shell, args| {
+        let path = shell.resolve(args);
+        let result = if on_ramfs(&path) {
+            crate::fs::RAMFS.lock().remove(ramfs_name(&path)).map_err(|err| alloc::format!("{}", err))
+        } else {
+            crate::vfs::remove(&path).map_err(|err| alloc::format!("{}", err))
+        };
+        if let Err(err) = result {
+            outln!(shell.stdout, "rm: {}: {}", args, err);
+        }
+    }),
+    ("mount", "[<device> <path>]: lists or adds mounts", |shell, args| {
+        let result = mount(args, &crate::block::DISKS.lock(), &crate::vfs::MOUNTS);
+        shell.finish("mount", result);
+    }),
+    ("umount", "<path>: removes a mount", |shell, args| {
+        let result = umount(args, &crate::vfs::MOUNTS);
+        shell.finish("umount", result);
+    }),
+    ("diskinfo", "the disks and their sizes", |shell, _| {
+        // the list first: a write can wait on the next stage for a while
+        let disks = diskinfo(&crate::block::DISKS.lock());
+        out!(shell.stdout, "{}", disks);
+    }),
+    ("ramdisk", "<sectors>: makes a disk on the heap", |shell, args| {
+        let result = ramdisk(args);
+        shell.finish("ramdisk", result);
+    }),
+    ("readsec", "[<device>] <lba>: dumps a sector", |shell, args| {
+        let result = readsec(args, &crate::block::DISKS.lock());
+        shell.finish("readsec", result);
+    }),
+    ("writesec", "[<device>] <lba> <text>: writes a sector", |shell, args| {
+        let result = writesec(args, &crate::block::DISKS.lock());
+        shell.finish("writesec", result);
+    }),
+    ("reboot", "restarts the machine", |_, _| crate::power::reboot()),
+    ("shutdown", "powers off", |_, _| shutdown()),
+    ("exit", "powers off", |_, _| shutdown()),
+];
+
+/// Splits a line into the command name and its arguments, with the
+/// whitespace around either dropped.
+fn split_command(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim_start()),
+        None => (line, ""),
+    }
+}
+
+fn find_command(name: &str) -> Option<Command> {
+    COMMANDS.iter().find(|&&(command, _, _)| command == name).map(|&(_, _, run)| run)
+}
+
+/// The built-in commands and then the registered ones, with their help.
+fn all_commands() -> Vec<(&'static str, &'static str)> {
+    let registered = REGISTRY.lock();
+    COMMANDS.iter().map(|&(name, help, _)| (name, help))
+        .chain(registered.commands().iter().map(|command| (command.name, command.help)))
+        .collect()
+}
+
+fn command_names() -> String {
+    let names: Vec<&str> = all_commands().into_iter().map(|(name, _)| name).collect();
+    names.join(", ")
+}
+
+/// `help` lists every command with its help; `help <command>` just that one.
+fn help(args: &str) -> String {
+    let mut output = String::new();
+    let commands = all_commands();
+    let wanted: Vec<_> = commands.iter().filter(|&&(name, _)| args.is_empty() || name == args).collect();
+    if wanted.is_empty() {
+        let _ = writeln!(output, "help: {}: no such command", args);
+    }
+    for (name, help) in wanted {
+        let _ = writeln!(output, "{:<13} {}", name, help);
     }
-}
\ No newline at end of file
+    if args.is_empty() {
+        output.push_str("A command's input can come from < file, its output go to > file or >> file, and | feeds it to the next one.\n");
+    }
+    output
+}
+
+/// Why a registered command failed, printed after its name. A usage error
+/// gets exit status 2, any other 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShellError {
+    Usage(&'static str),
+    InvalidArgument(String),
+    /// A quote in the line was never closed.
+    UnclosedQuote,
+    Failed(String),
+}
+
+impl ShellError {
+    fn status(&self) -> u8 {
+        match self {
+            ShellError::Usage(_) | ShellError::InvalidArgument(_) | ShellError::UnclosedQuote => 2,
+            ShellError::Failed(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShellError::Usage(usage) => write!(f, "usage: {}", usage),
+            ShellError::InvalidArgument(arg) => write!(f, "{}: invalid argument", arg),
+            ShellError::UnclosedQuote => write!(f, "unclosed quote"),
+            ShellError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// One command of a line, with where its input comes from and its
+/// output goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stage {
+    command: String,
+    /// The file after `<`.
+    input: Option<String>,
+    /// The file after `>`, or after `>>` with `true` for appending.
+    output: Option<(String, bool)>,
+}
+
+impl Stage {
+    fn new() -> Stage {
+        Stage { command: String::new(), input: None, output: None }
+    }
+}
+
+/// Splits a line into stages at `|` and takes their `<`, `>` and `>>`
+/// out. Quotes and backslashes work as in `split_args`: what they protect
+/// stays in the command, quotes included, for it to split. An unclosed
+/// quote runs to the end of the line, and `split_args` reports it.
+fn parse_pipeline(line: &str) -> Result<Vec<Stage>, CommandError> {
+    let mut stages = Vec::new();
+    let mut stage = Stage::new();
+    let mut quote = None;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => {
+                quote = None;
+                stage.command.push(c);
+            }
+            (Some('\''), c) => stage.command.push(c),
+            (_, '\\') => {
+                stage.command.push(c);
+                stage.command.extend(chars.next());
+            }
+            (Some(_), c) => stage.command.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                stage.command.push(c);
+            }
+            (None, '|') => {
+                if stage.command.trim().is_empty() {
+                    return Err(CommandError::Syntax("a command is missing around |"));
+                }
+                stages.push(core::mem::replace(&mut stage, Stage::new()));
+            }
+            (None, '<') => stage.input = Some(redirect_path(&mut chars)?),
+            (None, '>') => {
+                let append = chars.next_if_eq(&'>').is_some();
+                stage.output = Some((redirect_path(&mut chars)?, append));
+            }
+            (None, c) => stage.command.push(c),
+        }
+    }
+    if !stages.is_empty() && stage.command.trim().is_empty() {
+        return Err(CommandError::Syntax("a command is missing around |"));
+    }
+    stages.push(stage);
+    Ok(stages)
+}
+
+/// The word after `<` or `>`, without its quotes.
+fn redirect_path(chars: &mut core::iter::Peekable<core::str::Chars>) -> Result<String, CommandError> {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    let mut path = String::new();
+    let mut quote = None;
+    while let Some(&c) = chars.peek() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() || matches!(c, '|' | '<' | '>') => break,
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (_, c) => path.push(c),
+        }
+        chars.next();
+    }
+    if path.is_empty() {
+        return Err(CommandError::Syntax("a file name is missing after < or >"));
+    }
+    Ok(path)
+}
+
+/// Splits a command's arguments at whitespace. Text in double or single
+/// quotes is one argument, spaces and all, and a backslash outside single
+/// quotes takes the next character as it is.
+pub fn split_args(line: &str) -> Result<Vec<String>, ShellError> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => arg.get_or_insert_with(String::new).push(c),
+            (_, '\\') => arg.get_or_insert_with(String::new).push(chars.next().unwrap_or('\\')),
+            (Some(_), c) => arg.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                // `""` is an empty argument, not none
+                arg.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (None, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(ShellError::UnclosedQuote);
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// What a registered command gets: its arguments, split by `split_args`,
+/// and where to write its output.
+pub type Handler = fn(&[&str], &mut dyn fmt::Write) -> Result<(), ShellError>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RegisteredCommand {
+    pub name: &'static str,
+    /// One line for `help`: the arguments, if any, and what it does.
+    pub help: &'static str,
+    pub handler: Handler,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// Empty, or with whitespace in it.
+    InvalidName(&'static str),
+    /// A built-in or an already registered command has the name.
+    Taken(&'static str),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegisterError::InvalidName(name) => write!(f, "{:?} can't name a command", name),
+            RegisterError::Taken(name) => write!(f, "{}: a command has that name already", name),
+        }
+    }
+}
+
+/// Commands other modules add to the shell, like the diagnostics of a
+/// driver that only exist once it found its device.
+pub struct CommandRegistry {
+    commands: Vec<RegisteredCommand>,
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommandRegistry {
+    pub const fn new() -> Self {
+        CommandRegistry { commands: Vec::new() }
+    }
+
+    pub fn register(&mut self, name: &'static str, help: &'static str, handler: Handler) -> Result<(), RegisterError> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(RegisterError::InvalidName(name));
+        }
+        if self.find(name).is_some() {
+            return Err(RegisterError::Taken(name));
+        }
+        self.commands.push(RegisteredCommand { name, help, handler });
+        Ok(())
+    }
+
+    pub fn find(&self, name: &str) -> Option<RegisteredCommand> {
+        self.commands.iter().find(|command| command.name == name).copied()
+    }
+
+    /// In registration order.
+    pub fn commands(&self) -> &[RegisteredCommand] {
+        &self.commands
+    }
+}
+
+pub static REGISTRY: Mutex<CommandRegistry> = Mutex::new(CommandRegistry::new());
+
+/// Adds a command to the shell; a built-in keeps its name.
+pub fn register_command(name: &'static str, help: &'static str, handler: Handler) -> Result<(), RegisterError> {
+    if find_command(name).is_some() {
+        return Err(RegisterError::Taken(name));
+    }
+    REGISTRY.lock().register(name, help, handler)
+}
+
+fn shutdown() {
+    println!("shuting down...");
+    crate::power::shutdown();
+    println!("If it doesn't shut down in a second please, shutdown manually")
+}
+
+fn sysinfo(out: &mut impl Write) {
+    let uptime = crate::time::uptime_ms();
+    outln!(out, "uptime: {}.{:03} s", uptime / 1000, uptime % 1000);
+    let idle = crate::cpu::idle_stats();
+    outln!(out, "idle:   {}% halted recently, {} wakeups/s", idle.halted_pct_recent, idle.wakeups_per_sec);
+    match crate::time::tsc_frequency() {
+        Some(hz) => {
+            let halted = crate::time::cycles_to_nanos(idle.total_halted, hz) / 1_000_000;
+            outln!(out, "        {}.{:03} s halted since boot", halted / 1000, halted % 1000);
+        }
+        None => outln!(out, "        {} TSC cycles halted since boot", idle.total_halted),
+    }
+    if let Some((used, free)) = crate::memory::with_paging(|_, frames| (frames.allocated_count(), frames.frames_remaining())) {
+        outln!(out, "memory: {} KiB of frames in use, {} KiB free", used * 4, free * 4);
+    }
+    outln!(out, "keyboard: {} scancodes dropped", crate::keyboard::dropped_count());
+    outln!(out, "mouse:    {} bytes dropped", crate::mouse::dropped_count());
+    outln!(out, "serial:   {} bytes dropped", crate::console::serial_dropped_count());
+    let _ = crate::smbios::write_summary(out);
+}
+
+fn mem(out: &mut impl Write) {
+    use crate::memory::ByteSize;
+    let Some(stats) = crate::memory::stats() else {
+        outln!(out, "mem: the frame allocator is not set up yet");
+        return;
+    };
+    outln!(out, "Usable RAM:  {}", ByteSize(stats.usable_bytes));
+    outln!(out, "Frames used: {} ({}), {} freed since boot, {} left",
+        stats.frames_in_use(), ByteSize(stats.frames_in_use() as u64 * 4096), stats.frames_freed, stats.frames_remaining);
+    match stats.heap {
+        Some(heap) => outln!(out, "Heap:        {} / {}, {} free",
+            ByteSize(heap.used as u64), ByteSize(heap.size as u64), ByteSize(heap.free() as u64)),
+        None => outln!(out, "Heap:        not set up"),
+    }
+}
+
+/// `cpuinfo`: what CPUID says, then one line per CPU online, the one
+/// running it marked.
+fn cpuinfo() -> String {
+    let info = crate::cpu::info();
+    let mut out = String::new();
+    if let Some(brand) = info.brand() {
+        let _ = writeln!(out, "{}", brand);
+    }
+    let _ = writeln!(out, "{}, family {:#x} model {:#x} stepping {}", info.vendor(), info.family, info.model, info.stepping);
+    let _ = match info.tsc_hz() {
+        Some(hz) => writeln!(out, "TSC {}.{:03} MHz", hz / 1_000_000, hz / 1000 % 1000),
+        None => writeln!(out, "TSC frequency unknown"),
+    };
+    let _ = write!(out, "features:");
+    for feature in info.features() {
+        let _ = write!(out, " {}", feature);
+    }
+    let _ = writeln!(out);
+    let current = crate::percpu::current().index();
+    let _ = writeln!(out, "  CPU  APIC id");
+    for cpu in crate::percpu::online() {
+        let mark = if cpu.index() == current { '*' } else { ' ' };
+        let _ = writeln!(out, "{} {:>3}  {:>7}", mark, cpu.index(), cpu.apic_id());
+    }
+    let _ = writeln!(out, "{} online", crate::smp::online_cpus());
+    out
+}
+
+/// `slabinfo`: one line per slab cache.
+fn slabinfo() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<12}{:>6}{:>9}{:>7}{:>8}{:>7}{:>8}", "cache", "size", "per slab", "slabs", "active", "total", "allocs");
+    for cache in crate::allocator::slab::caches() {
+        let _ = writeln!(out, "{:<12}{:>6}{:>9}{:>7}{:>8}{:>7}{:>8}",
+            cache.name, cache.object_size, cache.per_slab, cache.slabs, cache.active, cache.total(), cache.allocations);
+    }
+    out
+}
+
+/// `free`: the frame allocator's frames, what the memory map offered and
+/// how the free frames are split into buddy blocks.
+fn free(stats: &crate::memory::MemoryStats) -> String {
+    use crate::memory::ByteSize;
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<8}{:>10}  size", "", "frames");
+    for (name, frames) in [("total", stats.frames_total), ("in use", stats.frames_in_use()), ("free", stats.frames_remaining)] {
+        let _ = writeln!(out, "{:<8}{:>10}  {}", name, frames, ByteSize(frames as u64 * 4096));
+    }
+    let _ = writeln!(out, "usable in the memory map: {}", ByteSize(stats.usable_bytes));
+    let _ = write!(out, "free blocks by order:");
+    for count in stats.free_blocks {
+        let _ = write!(out, " {}", count);
+    }
+    let _ = writeln!(out);
+    if let Some(order) = stats.largest_free_order() {
+        // order 9 is what a 2 MiB page needs
+        let _ = writeln!(out, "largest free block {}, {}% of free memory in pieces under 2 MiB",
+            ByteSize(4096 << order), stats.fragmentation(9));
+    }
+    out
+}
+
+/// `udp <ip> <port> <text>`: one datagram out and the first one back.
+/// Port 7 of another berryOS sends the text back as it went.
+fn udp(args: &str) -> Result<String, CommandError> {
+    const USAGE: &str = "udp <ip> <port> <text>";
+    let mut parts = args.splitn(3, ' ');
+    let ip = parts.next().and_then(|ip| ip.parse().ok()).ok_or(CommandError::Usage(USAGE))?;
+    let port = parts.next().and_then(|port| port.parse().ok()).ok_or(CommandError::Usage(USAGE))?;
+    let text = parts.next().unwrap_or("");
+    match crate::net::udp_exchange(ip, port, text.as_bytes()).map_err(CommandError::Net)? {
+        Some(message) => Ok(alloc::format!("{}:{}: {}\n", message.from, message.from_port, String::from_utf8_lossy(&message.data))),
+        None => Ok(String::from("no reply\n")),
+    }
+}
+
+/// A canonical virtual address, in decimal or hex.
+fn parse_addr(arg: &str) -> Option<x86_64::VirtAddr> {
+    crate::cmdline::parse_u64(arg).and_then(|addr| x86_64::VirtAddr::try_new(addr).ok())
+}
+
+/// `profile start`, `profile stop` and `profile report`.
+fn profile(args: &str) -> Result<String, CommandError> {
+    match args {
+        "start" => {
+            crate::stats::start();
+            Ok(String::from("profile started\n"))
+        }
+        "stop" => {
+            crate::stats::stop().map_err(CommandError::Profile)?;
+            Ok(String::from("profile stopped; `profile report` shows it\n"))
+        }
+        "report" => crate::stats::report().map_err(CommandError::Profile),
+        _ => Err(CommandError::Usage("profile start|stop|report")),
+    }
+}
+
+const MAX_RANDOM: u64 = 256;
+
+/// `random [bytes]`: bytes from `rng::fill` in hex, and where they came
+/// from.
+fn random(args: &str) -> Result<String, CommandError> {
+    const USAGE: &str = "random [bytes, up to 256]";
+    let len = match args {
+        "" => 16,
+        len => match crate::cmdline::parse_u64(len) {
+            Some(len @ 1..=MAX_RANDOM) => len as usize,
+            _ => return Err(CommandError::Usage(USAGE)),
+        },
+    };
+    let mut bytes = alloc::vec![0; len];
+    crate::rng::fill(&mut bytes);
+    let mut out: String = bytes.iter().map(|byte| alloc::format!("{:02x}", byte)).collect();
+    out.push_str(&alloc::format!("\nfrom {}, seeded from {}\n", crate::rng::source(), crate::rng::seed_source()));
+    Ok(out)
+}
+
+/// `vmmap`: every region the vmm mapped or was told about, then the lazy
+/// regions, which map their pages on first touch.
+fn vmmap() -> String {
+    use alloc::string::ToString;
+    use crate::memory::vmm::Backing;
+    use crate::memory::{ByteSize, EntryFlags};
+    let mut out = String::new();
+    for region in crate::memory::vmm::regions() {
+        let backing = match region.backing {
+            Backing::Anonymous => String::new(),
+            Backing::Physical(phys) => alloc::format!(" -> {:#x}", phys.as_u64()),
+            Backing::Fixed => String::from(" (fixed)"),
+        };
+        let _ = writeln!(out, "{:#016x}-{:#016x} {:>9}  {:<8} {}{}", region.start.as_u64(), region.end().as_u64(),
+            ByteSize(region.size).to_string(), EntryFlags(region.flags).to_string(), region.name, backing);
+    }
+    for region in crate::memory::lazy_regions() {
+        let _ = writeln!(out, "{:#016x}-{:#016x} {:>9}  {:<8} {} (lazy)", region.start.as_u64(), region.end().as_u64(),
+            ByteSize(region.size).to_string(), EntryFlags(region.flags).to_string(), region.name);
+    }
+    out
+}
+
+/// `pmap <addr>`: where `addr` lands in physical memory, and the flags of
+/// the entry mapping it.
+fn pmap(args: &str) -> Result<String, CommandError> {
+    use crate::memory;
+    let addr = parse_addr(args).ok_or(CommandError::Usage("pmap <virtual address>"))?;
+    let offset = memory::physical_memory_offset().ok_or(CommandError::NoPaging)?;
+    // the offset is the one `memory::init` was given
+    let phys = unsafe { memory::translate_addr(addr, offset) }.ok_or(CommandError::NotMapped(addr.as_u64()))?;
+    let flags = memory::page_flags(addr).unwrap_or(x86_64::structures::paging::PageTableFlags::empty());
+    Ok(alloc::format!("{:#x} -> {:#x}  {}\n", addr.as_u64(), phys.as_u64(), memory::EntryFlags(flags)))
+}
+
+/// Longest dump `peek` makes.
+const MAX_PEEK: u64 = 4096;
+
+/// `peek <addr> [len]`: `len` bytes from `addr` (64 if not given) as a
+/// hex dump, once every page they are on is known to be mapped.
+fn peek(args: &str) -> Result<String, CommandError> {
+    const USAGE: &str = "peek <addr> [len of up to 4096]";
+    let (addr, len) = match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [addr] => (parse_addr(addr), Some(64)),
+        [addr, len] => (parse_addr(addr), crate::cmdline::parse_u64(len)),
+        _ => (None, None),
+    };
+    let (Some(addr), Some(len @ 1..=MAX_PEEK)) = (addr, len) else { return Err(CommandError::Usage(USAGE)) };
+    let end = addr.as_u64().checked_add(len - 1).ok_or(CommandError::NotMapped(u64::MAX))?;
+    let mut page = addr.align_down(4096u64).as_u64();
+    while page <= end {
+        match x86_64::VirtAddr::try_new(page) {
+            Ok(start) if crate::memory::is_mapped(start) => {}
+            _ => return Err(CommandError::NotMapped(page.max(addr.as_u64()))),
+        }
+        page = match page.checked_add(4096) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    let data: Vec<u8> = (0..len).map(|i| unsafe { core::ptr::read_volatile((addr.as_u64() + i) as *const u8) }).collect();
+    Ok(hexdump(&data, addr.as_u64()))
+}
+
+/// `poke <addr> <byte>`: writes one byte where the page tables let the
+/// kernel write.
+fn poke(args: &str) -> Result<String, CommandError> {
+    use x86_64::structures::paging::PageTableFlags;
+    const USAGE: &str = "poke <addr> <byte>";
+    let (addr, value) = args.split_once(char::is_whitespace).ok_or(CommandError::Usage(USAGE))?;
+    let addr = parse_addr(addr).ok_or(CommandError::Usage(USAGE))?;
+    let value = crate::cmdline::parse_u64(value.trim()).and_then(|value| u8::try_from(value).ok()).ok_or(CommandError::Usage(USAGE))?;
+    let walk = crate::memory::walk(addr).ok_or(CommandError::NotMapped(addr.as_u64()))?;
+    if walk.phys.is_none() {
+        return Err(CommandError::NotMapped(addr.as_u64()));
+    }
+    if !walk.effective_flags().contains(PageTableFlags::WRITABLE) {
+        return Err(CommandError::ReadOnly(addr.as_u64()));
+    }
+    unsafe { core::ptr::write_volatile(addr.as_mut_ptr::<u8>(), value) };
+    Ok(alloc::format!("wrote {:#04x} to {:#x}\n", value, addr.as_u64()))
+}
+
+/// `kbrate <cps> <delay ms>`: sets the keyboard repeat rate and delay.
+fn ls(path: &str, out: &mut impl Write) {
+    match crate::vfs::read_dir(path) {
+        Ok(entries) => {
+            for entry in entries {
+                let metadata = entry.metadata();
+                if metadata.is_dir() {
+                    outln!(out, "{}/", entry.name());
+                } else if metadata.kind == crate::vfs::FileKind::CharDevice {
+                    outln!(out, "{:>10}  {}", "char", entry.name());
+                } else {
+                    outln!(out, "{:>10}  {}", metadata.size, entry.name());
+                }
+            }
+        }
+        Err(err) => outln!(out, "ls: {}: {}", path, err),
+    }
+}
+
+/// `path` made absolute from `cwd` and normalized. One that doesn't
+/// normalize is returned as it is, for the VFS to refuse.
+fn resolve_in(cwd: &str, path: &str) -> String {
+    let path = if path.starts_with('/') { String::from(path) } else { alloc::format!("{}/{}", cwd, path) };
+    crate::vfs::normalize(&path).unwrap_or(path)
+}
+
+/// `spawn [path]`: loads the program from the VFS, or the built-in demo.
+fn spawn(path: &str, demo: bool) -> Result<String, CommandError> {
+    let pid = if demo {
+        crate::process::spawn_from_elf(&crate::usermode::demo::PROGRAM)
+    } else {
+        let image = crate::vfs::read_to_end(path).map_err(CommandError::Vfs)?;
+        crate::process::spawn_from_elf(&image)
+    };
+    let name = if demo { "demo" } else { path };
+    let pid = pid.map_err(|err| CommandError::Spawn(String::from(name), err))?;
+    Ok(alloc::format!("spawned process {}\n", pid))
+}
+
+/// `run <path>`: runs the program at `path`, with any processes already
+/// queued, until they are all done, and reports its exit code.
+fn run_program(path: &str) -> Result<String, CommandError> {
+    let image = crate::vfs::read_to_end(path).map_err(CommandError::Vfs)?;
+    match crate::usermode::run(&image) {
+        Ok(code) => Ok(alloc::format!("{}: exited with {}\n", path, code)),
+        Err(crate::usermode::UserError::Spawn(err)) => Err(CommandError::Spawn(String::from(path), err)),
+        Err(err) => Err(CommandError::Run(String::from(path), err)),
+    }
+}
+
+/// `cd [path]`: the working directory becomes `path` if it is a directory
+/// in `mounts`, or `/ram` without one.
+fn cd(shell: &mut Shell, args: &str, mounts: &Mutex<MountTable>) -> Result<String, CommandError> {
+    let path = if args.is_empty() { String::from(crate::fs::MOUNT_POINT) } else { shell.resolve(args) };
+    if !crate::vfs::metadata_in(mounts, &path).map_err(CommandError::Vfs)?.is_dir() {
+        return Err(CommandError::Vfs(VfsError::NotADirectory));
+    }
+    shell.cwd = path;
+    Ok(String::new())
+}
+
+/// The ramfs name for `path`, taking `/ram/name` as well as `name`.
+fn ramfs_name(path: &str) -> &str {
+    path.strip_prefix(crate::fs::MOUNT_POINT).and_then(|rest| rest.strip_prefix('/')).unwrap_or(path)
+}
+
+/// Whether the absolute `path` names a ramfs file: those are written
+/// through `fs::RAMFS`, everything else through the VFS.
+fn on_ramfs(path: &str) -> bool {
+    ramfs_name(path) != path
+}
+
+/// `write <file> <text>` and `append <file> <text>`.
+fn write_file(shell: &mut Shell, command: &str, args: &str, append: bool) {
+    let (name, text) = split_command(args);
+    if name.is_empty() {
+        outln!(shell.stdout, "usage: {} <file> <text>", command);
+        return;
+    }
+    let mut data = alloc::vec::Vec::from(text.as_bytes());
+    data.push(b'\n');
+    if let Err(err) = write_path(&shell.resolve(name), &data, append) {
+        outln!(shell.stdout, "{}: {}: {}", command, name, err);
+    }
+}
+
+/// Writes `data` to the file at the absolute `path`, through `fs::RAMFS`
+/// or the VFS, replacing what it held or with `append` after it.
+fn write_path(path: &str, data: &[u8], append: bool) -> Result<(), String> {
+    if on_ramfs(path) {
+        crate::fs::RAMFS.lock().write(ramfs_name(path), data, append).map_err(|err| alloc::format!("{}", err))
+    } else {
+        write_vfs(&crate::vfs::MOUNTS, path, data, append).map_err(|err| alloc::format!("{}", err))
+    }
+}
+
+/// Replaces a file's contents with `data`, or adds it at the end if
+/// `append` is set; the file is created if it is not there.
+fn write_vfs(mounts: &Mutex<MountTable>, path: &str, data: &[u8], append: bool) -> Result<(), VfsError> {
+    let mut file = match crate::vfs::open_in(mounts, path) {
+        Ok(file) if append => file,
+        Ok(_) | Err(VfsError::NotFound) => crate::vfs::create_in(mounts, path)?,
+        Err(err) => return Err(err),
+    };
+    let mut done = 0;
+    while done < data.len() {
+        let offset = file.size();
+        done += file.write(offset, &data[done..])?;
+    }
+    Ok(())
+}
+
+/// The most a command reads of a file or its input: `/dev/zero` never
+/// ends, and the heap (`allocator::HEAP_SIZE`) can't hold it.
+const MAX_INPUT: usize = 32 * 1024;
+
+/// Reads `file` to its end in fixed-size chunks, failing once it holds
+/// more than `MAX_INPUT`.
+fn read_all(file: &mut dyn FileHandle) -> Result<Vec<u8>, CommandError> {
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        match file.read(data.len() as u64, &mut chunk).map_err(CommandError::Vfs)? {
+            0 => return Ok(data),
+            n if data.len() + n > MAX_INPUT => return Err(CommandError::TooLarge),
+            n => data.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+/// The file at the absolute `path`, with `read_all`'s limit.
+fn read_path(path: &str) -> Result<Vec<u8>, CommandError> {
+    let mut file = crate::vfs::open(path).map_err(CommandError::Vfs)?;
+    read_all(&mut *file)
+}
+
+/// `cat [path]`: the file, or with no path what the command reads.
+fn cat(shell: &mut Shell, args: &str) -> Result<String, CommandError> {
+    let data = match shell.read_stdin() {
+        Some(input) if args.is_empty() => input?,
+        _ => {
+            let mut data = read_path(&shell.resolve(args))?;
+            data.push(b'\n');
+            data
+        }
+    };
+    Ok(String::from(String::from_utf8_lossy(&data)))
+}
+
+/// `grep <text> [path]`: the lines holding `text`, of the file or of what
+/// the command reads.
+fn grep(shell: &mut Shell, args: &str) -> Result<String, CommandError> {
+    const USAGE: &str = "grep <text> [path]";
+    let args = split_args(args).map_err(|_| CommandError::Syntax("unclosed quote"))?;
+    let data = match args.as_slice() {
+        [_, path] => read_path(&shell.resolve(path))?,
+        [_] => shell.read_stdin().ok_or(CommandError::Usage(USAGE))??,
+        _ => return Err(CommandError::Usage(USAGE)),
+    };
+    let mut out = String::new();
+    for line in String::from_utf8_lossy(&data).lines().filter(|line| line.contains(args[0].as_str())) {
+        out.push_str(line);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Why a built-in command failed. Each kind of failure has its own exit
+/// status, so a script can tell a typo from a busy mount.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CommandError {
+    Usage(&'static str),
+    NoDevice(String),
+    /// The device exists but its partition table could not be read.
+    Device(String, OpenError),
+    UnknownFilesystem(String),
+    Busy(String),
+    Io(BlockError),
+    Vfs(VfsError),
+    /// The frame allocator and page tables are not set up yet.
+    NoPaging,
+    NotMapped(u64),
+    ReadOnly(u64),
+    /// The heap can't hold what the command needs.
+    NoMemory,
+    Spawn(String, crate::process::SpawnError),
+    /// The program could not run to its exit.
+    Run(String, crate::usermode::UserError),
+    Net(crate::net::NetError),
+    Profile(crate::stats::ProfileError),
+    /// The line's `|`, `<` or `>` are not where they can be.
+    Syntax(&'static str),
+    /// The file a stage's input or output was sent to, and why it failed.
+    Redirect(String, String),
+    /// A file or the command's input holds more than `MAX_INPUT`.
+    TooLarge,
+}
+
+impl CommandError {
+    fn status(&self) -> u8 {
+        match self {
+            CommandError::Usage(_) => 2,
+            CommandError::NoDevice(_) => 3,
+            CommandError::UnknownFilesystem(_) => 4,
+            CommandError::Busy(_) => 5,
+            CommandError::Device(..) | CommandError::Io(_) | CommandError::Vfs(_) => 1,
+            CommandError::NoPaging | CommandError::NotMapped(_) | CommandError::ReadOnly(_) => 1,
+            CommandError::NoMemory | CommandError::Spawn(..) | CommandError::Run(..) => 1,
+            CommandError::Net(_) | CommandError::Profile(_) | CommandError::Redirect(..) => 1,
+            CommandError::TooLarge => 1,
+            CommandError::Syntax(_) => 2,
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::NoDevice(name) => write!(f, "{}: no such device", name),
+            CommandError::Device(name, err) => write!(f, "{}: {}", name, err),
+            CommandError::UnknownFilesystem(name) => write!(f, "{}: no filesystem recognized", name),
+            CommandError::Busy(path) => write!(f, "{}: files are still open", path),
+            CommandError::Io(err) => write!(f, "{}", err),
+            CommandError::Vfs(err) => write!(f, "{}", err),
+            CommandError::NoPaging => write!(f, "paging is not set up"),
+            CommandError::NotMapped(addr) => write!(f, "{:#x}: not mapped", addr),
+            CommandError::ReadOnly(addr) => write!(f, "{:#x}: not writable", addr),
+            CommandError::NoMemory => write!(f, "out of memory"),
+            CommandError::Spawn(path, err) => write!(f, "{}: {}", path, err),
+            CommandError::Run(path, err) => write!(f, "{}: {}", path, err),
+            CommandError::Net(err) => write!(f, "{}", err),
+            CommandError::Profile(err) => write!(f, "{}", err),
+            CommandError::Syntax(reason) => write!(f, "syntax error: {}", reason),
+            CommandError::Redirect(path, err) => write!(f, "{}: {}", path, err),
+            CommandError::TooLarge => write!(f, "more than {} KiB to read", MAX_INPUT / 1024),
+        }
+    }
+}
+
+/// `mount` lists the mounts; `mount <device> <path>` mounts the filesystem
+/// on a disk or partition.
+fn mount<D: BlockDevice + Clone + Send + 'static>(
+    args: &str,
+    disks: &Registry<D>,
+    mounts: &Mutex<MountTable>,
+) -> Result<String, CommandError> {
+    let mut args = args.split_whitespace();
+    let (name, path) = match (args.next(), args.next(), args.next()) {
+        (None, ..) => return Ok(mount_list(&mounts.lock())),
+        (Some(name), Some(path), None) => (name, path),
+        _ => return Err(CommandError::Usage("mount [<device> <path>]")),
+    };
+    let mut device = disks.open(name).map_err(|err| match err {
+        OpenError::NoDevice | OpenError::NoPartition => CommandError::NoDevice(String::from(name)),
+        err => CommandError::Device(String::from(name), err),
+    })?;
+    let sectors = device.sector_count();
+    if !crate::fat::probe(&mut device).map_err(CommandError::Io)? {
+        return Err(CommandError::UnknownFilesystem(String::from(name)));
+    }
+    let source = Source { device: String::from(name), sectors };
+    let kind = crate::fat::mount_at(mounts, device, path, Some(source)).map_err(CommandError::Vfs)?;
+    Ok(alloc::format!("mounted {} ({:?}) at {}\n", name, kind, path))
+}
+
+fn mount_list(mounts: &MountTable) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<16} {:<6} {:<8} {:>10}", "PATH", "TYPE", "DEVICE", "SECTORS");
+    for mount in mounts.list() {
+        let _ = match mount.source {
+            Some(source) => writeln!(out, "{:<16} {:<6} {:<8} {:>10}", mount.path, mount.kind, source.device, source.sectors),
+            None => writeln!(out, "{:<16} {:<6} {:<8} {:>10}", mount.path, mount.kind, "-", "-"),
+        };
+    }
+    out
+}
+
+/// `umount <path>`: writes back what the filesystem holds and removes it.
+fn umount(args: &str, mounts: &Mutex<MountTable>) -> Result<String, CommandError> {
+    let mut args = args.split_whitespace();
+    let (Some(path), None) = (args.next(), args.next()) else {
+        return Err(CommandError::Usage("umount <path>"));
+    };
+    match mounts.lock().unmount(path) {
+        Ok(()) => Ok(alloc::format!("unmounted {}\n", path)),
+        Err(VfsError::Busy) => Err(CommandError::Busy(String::from(path))),
+        Err(err) => Err(CommandError::Vfs(err)),
+    }
+}
+
+/// `diskinfo`: every disk the drivers registered, with its size.
+fn diskinfo<D: BlockDevice + Clone>(disks: &Registry<D>) -> String {
+    use crate::memory::ByteSize;
+
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<8} {:>10} {:>10}", "DEVICE", "SECTORS", "SIZE");
+    for (name, sectors) in disks.disks() {
+        let size = alloc::format!("{}", ByteSize(sectors * crate::block::SECTOR_SIZE as u64));
+        let _ = writeln!(out, "{:<8} {:>10} {:>10}", name, sectors, size);
+    }
+    out
+}
+
+/// `ramdisk <sectors>`: a new zeroed disk, registered like a driver's.
+fn ramdisk(args: &str) -> Result<String, CommandError> {
+    let sectors = crate::cmdline::parse_u64(args).filter(|&sectors| sectors > 0).ok_or(CommandError::Usage("ramdisk <sectors>"))?;
+    let name = crate::block::ramdisk::create(sectors).ok_or(CommandError::NoMemory)?;
+    Ok(alloc::format!("{}: {} sectors\n", name, sectors))
+}
+
+/// The device and sector `readsec` and `writesec` work on, and what is left
+/// of the line. With no device named it is the first disk registered.
+fn sector_args<'a, D: BlockDevice + Clone>(
+    args: &'a str,
+    disks: &Registry<D>,
+    usage: &'static str,
+) -> Result<(crate::partitions::PartitionDevice<D>, u64, &'a str), CommandError> {
+    let (first, rest) = split_command(args);
+    let (name, lba, rest) = match crate::cmdline::parse_u64(first) {
+        Some(lba) => {
+            let name = disks.disks().into_iter().next().map(|(name, _)| name);
+            (name.ok_or_else(|| CommandError::NoDevice(String::from("disk")))?, lba, rest)
+        }
+        None => {
+            let (lba, rest) = split_command(rest);
+            let lba = crate::cmdline::parse_u64(lba).ok_or(CommandError::Usage(usage))?;
+            (String::from(first), lba, rest)
+        }
+    };
+    let device = disks.open(&name).map_err(|err| match err {
+        OpenError::NoDevice | OpenError::NoPartition => CommandError::NoDevice(name.clone()),
+        err => CommandError::Device(name.clone(), err),
+    })?;
+    Ok((device, lba, rest))
+}
+
+/// `readsec [<device>] <lba>`: a hex dump of one sector.
+fn readsec<D: BlockDevice + Clone>(args: &str, disks: &Registry<D>) -> Result<String, CommandError> {
+    const USAGE: &str = "readsec [<device>] <lba>";
+    let (mut device, lba, rest) = sector_args(args, disks, USAGE)?;
+    if !rest.is_empty() {
+        return Err(CommandError::Usage(USAGE));
+    }
+    let mut sector = [0u8; crate::block::SECTOR_SIZE];
+    device.read_sectors(lba, &mut sector).map_err(CommandError::Io)?;
+    Ok(hexdump(&sector, 0))
+}
+
+/// `writesec [<device>] <lba> <text>`: writes the text to one sector, the
+/// rest of it zeroed.
+fn writesec<D: BlockDevice + Clone>(args: &str, disks: &Registry<D>) -> Result<String, CommandError> {
+    const USAGE: &str = "writesec [<device>] <lba> <text of up to 512 bytes>";
+    let (mut device, lba, text) = sector_args(args, disks, USAGE)?;
+    let mut sector = [0u8; crate::block::SECTOR_SIZE];
+    if text.is_empty() || text.len() > sector.len() {
+        return Err(CommandError::Usage(USAGE));
+    }
+    sector[..text.len()].copy_from_slice(text.as_bytes());
+    device.write_sectors(lba, &sector).map_err(CommandError::Io)?;
+    Ok(alloc::format!("wrote {} bytes to sector {}\n", text.len(), lba))
+}
+
+/// Sixteen bytes a line: the offset, the bytes in hex, and the printable
+/// ones as text.
+fn hexdump(data: &[u8], base: u64) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{:04x} ", base + i as u64 * 16);
+        for byte in line {
+            let _ = write!(out, " {:02x}", byte);
+        }
+        let text: String = line.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        let _ = writeln!(out, "{:pad$}  |{}|", "", text, pad = (16 - line.len()) * 3);
+    }
+    out
+}
+
+fn kbrate(args: &str, out: &mut impl Write) {
+    use crate::keyboard::{self, RepeatDelay, RepeatRate};
+
+    let mut parts = args.split_whitespace();
+    let rate = parts.next().and_then(RepeatRate::parse);
+    let delay = parts.next().and_then(|ms| ms.parse().ok()).and_then(RepeatDelay::from_millis);
+    match (rate, delay, parts.next()) {
+        (Some(rate), Some(delay), None) => match keyboard::set_typematic(rate, delay) {
+            Ok(()) => outln!(out, "kbrate: {} after {} ms", rate, delay.millis()),
+            Err(err) => outln!(out, "kbrate: {}", err),
+        },
+        _ => {
+            outln!(out, "usage: kbrate <cps> <delay ms>");
+            outln!(out, "  cps from {} to {}, delay 250, 500, 750 or 1000",
+                RepeatRate::SLOWEST.tenths() / 10, RepeatRate::FASTEST.tenths() / 10);
+        }
+    }
+}
+
+/// `date [set <YYYY-MM-DD HH:MM:SS>]`: shows the time, or sets the RTC.
+fn date(args: &str, out: &mut impl Write) {
+    use crate::clock;
+
+    if let Some(time) = args.strip_prefix("set") {
+        match clock::parse(time) {
+            Some(time) => clock::set(time),
+            None => {
+                outln!(out, "usage: date set <YYYY-MM-DD HH:MM:SS>");
+                return;
+            }
+        }
+    } else if !args.is_empty() {
+        outln!(out, "usage: date [set <YYYY-MM-DD HH:MM:SS>]");
+        return;
+    }
+    match clock::now_datetime() {
+        Some(now) => outln!(out, "{}", now),
+        None => outln!(out, "date: the clock is not set"),
+    }
+    if let Some(correction) = clock::last_correction() {
+        outln!(out, "last RTC resync {}", correction);
+    }
+}
+
+/// `loglevel [level]`: shows or sets the most verbose level logged.
+fn loglevel(args: &str, out: &mut impl Write) {
+    use crate::log::{self, Level};
+
+    if args.is_empty() {
+        outln!(out, "{}", log::max_level());
+        return;
+    }
+    match Level::parse(args) {
+        Some(level) => log::set_max_level(level),
+        None => outln!(out, "usage: loglevel <error|warn|info|debug|trace|0-4>"),
+    }
+}
+
+/// `pagetable [addr]`: a summary of the page tables, down to the P3, or
+/// every entry the CPU reads to translate `addr`.
+fn pagetable(args: &str, out: &mut impl Write) {
+    use crate::memory;
+
+    let Some(offset) = memory::physical_memory_offset() else {
+        outln!(out, "pagetable: paging is not set up");
+        return;
+    };
+    if args.is_empty() {
+        let mut tables = String::new();
+        let _ = memory::dump_page_tables(offset, memory::DumpFilter::SUMMARY, &mut tables);
+        out!(out, "{}", tables);
+        return;
+    }
+    match crate::cmdline::parse_u64(args).and_then(|addr| x86_64::VirtAddr::try_new(addr).ok()).and_then(memory::walk) {
+        Some(walk) => out!(out, "{}", walk),
+        None => outln!(out, "usage: pagetable [<canonical address>]"),
+    }
+}
+
+/// `rdmsr <name|hex>`
+fn rdmsr(arg: &str, out: &mut impl Write) {
+    use crate::cpu;
+
+    let Some(msr) = cpu::parse_msr(arg) else {
+        outln!(out, "usage: rdmsr <name|hex>");
+        return;
+    };
+    let name = cpu::msr_name(msr).unwrap_or("MSR");
+    match cpu::try_rdmsr(msr) {
+        Ok(value) => outln!(out, "{} ({:#x}) = {:#018x}", name, msr, value),
+        Err(err) => outln!(out, "rdmsr: {:#x}: {}", msr, err),
+    }
+}
+
+/// `wrmsr -f <name|hex> <hex value>`: the flag is there so nobody pokes
+/// MSRs by accident.
+fn wrmsr(args: &str, out: &mut impl Write) {
+    use crate::cpu;
+
+    let mut parts = args.split_whitespace();
+    let forced = parts.next() == Some("-f");
+    let msr = parts.next().and_then(cpu::parse_msr);
+    let value = parts.next().and_then(|v| u64::from_str_radix(v.trim_start_matches("0x"), 16).ok());
+    match (forced, msr, value, parts.next()) {
+        (true, Some(msr), Some(value), None) => match unsafe { cpu::try_wrmsr(msr, value) } {
+            Ok(()) => outln!(out, "wrmsr: {:#x} = {:#018x}", msr, value),
+            Err(err) => outln!(out, "wrmsr: {:#x}: {}", msr, err),
+        },
+        (false, ..) => outln!(out, "wrmsr: writing MSRs can hang the machine; repeat with -f"),
+        _ => outln!(out, "usage: wrmsr -f <name|hex> <hex value>"),
+    }
+}
+
+//test case
+/// `ata0` with a FAT16 volume holding `/hello.txt` in its first partition,
+/// and a blank `virtio0`.
+#[cfg(test)]
+fn test_disks() -> Registry<crate::block::testdata::SharedDisk> {
+    use crate::block::testdata::{RamDisk, SharedDisk};
+    use crate::fat::{testdata as fat, FatFs, FatType};
+    use crate::partitions::{testdata as partitions, TYPE_FAT16};
+
+    let mut fs = FatFs::mount(fat::format(FatType::Fat16, fat::FAT16_SECTORS, 512)).unwrap();
+    fs.set_clock(|| crate::cmos::DateTime { year: 2024, month: 1, day: 1, hour: 0, minute: 0, second: 0 });
+    fs.create("/hello.txt").unwrap().write(0, b"hello").unwrap();
+    let volume = fs.unmount().unwrap();
+    let mut disk = partitions::mbr_disk(&[(TYPE_FAT16, 2048, fat::FAT16_SECTORS)]);
+    disk.place(2048, &volume);
+
+    let mut disks = Registry::new();
+    disks.register("ata", SharedDisk::new(disk));
+    disks.register("virtio", SharedDisk::new(RamDisk::new(64)));
+    disks
+}
+
+#[test_case]
+fn test_mount_command() {
+    let disks = test_disks();
+    let mounts = Mutex::new(MountTable::new());
+    assert_eq!(mount("", &disks, &mounts).unwrap().lines().count(), 1);
+    assert_eq!(mount("ata0p1 /disk", &disks, &mounts).as_deref(), Ok("mounted ata0p1 (Fat16) at /disk\n"));
+    let listing = mount("", &disks, &mounts).unwrap();
+    let line: alloc::vec::Vec<&str> = listing.lines().nth(1).unwrap().split_whitespace().collect();
+    assert_eq!(line, ["/disk", "fat16", "ata0p1", "8192"]);
+    assert_eq!(crate::vfs::open_in(&mounts, "/disk/hello.txt").unwrap().size(), 5);
+
+    // each failure has a status of its own
+    let failures = [
+        ("ata0p1", CommandError::Usage("mount [<device> <path>]"), 2),
+        ("ata7 /x", CommandError::NoDevice(String::from("ata7")), 3),
+        ("ata0p2 /x", CommandError::NoDevice(String::from("ata0p2")), 3),
+        ("virtio0p1 /x", CommandError::Device(String::from("virtio0p1"), OpenError::Table(crate::partitions::PartitionError::NoTable)), 1),
+        ("virtio0 /x", CommandError::UnknownFilesystem(String::from("virtio0")), 4),
+        ("ata0 /x", CommandError::UnknownFilesystem(String::from("ata0")), 4),
+        ("ata0p1 /disk", CommandError::Vfs(VfsError::AlreadyMounted), 1),
+    ];
+    for (args, err, status) in failures {
+        assert_eq!(mount(args, &disks, &mounts), Err(err.clone()), "mount {}", args);
+        assert_eq!(err.status(), status);
+    }
+    assert_eq!(mounts.lock().list().len(), 1);
+}
+
+#[test_case]
+fn test_umount_command() {
+    let disks = test_disks();
+    let mounts = Mutex::new(MountTable::new());
+    mount("ata0p1 /disk", &disks, &mounts).unwrap();
+
+    let mut file = crate::vfs::open_in(&mounts, "/disk/hello.txt").unwrap();
+    assert_eq!(umount("/disk", &mounts), Err(CommandError::Busy(String::from("/disk"))));
+    assert_eq!(CommandError::Busy(String::new()).status(), 5);
+    assert_eq!(file.write(5, b", disk"), Ok(6));
+    drop(file);
+    assert_eq!(umount("/disk", &mounts).as_deref(), Ok("unmounted /disk\n"));
+    assert_eq!(umount("/disk", &mounts), Err(CommandError::Vfs(VfsError::NotMounted)));
+    assert_eq!(umount("", &mounts), Err(CommandError::Usage("umount <path>")));
+
+    // everything the cache held reached the disk
+    let mut fs = crate::fat::FatFs::mount(disks.open("ata0p1").unwrap()).unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(fs.open("/hello.txt").unwrap().read(0, &mut buf), Ok(11));
+    assert_eq!(&buf[..11], b"hello, disk");
+}
+
+#[test_case]
+fn test_diskinfo_command() {
+    let listing = diskinfo(&test_disks());
+    let lines: alloc::vec::Vec<alloc::vec::Vec<&str>> = listing.lines().map(|line| line.split_whitespace().collect()).collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[2], ["virtio0", "64", "32", "KiB"]);
+}
+
+#[test_case]
+fn test_sector_commands() {
+    let disks = test_disks();
+    assert_eq!(writesec("virtio0 3 hello there", &disks).as_deref(), Ok("wrote 11 bytes to sector 3\n"));
+    let dump = readsec("virtio0 3", &disks).unwrap();
+    assert_eq!(dump.lines().count(), 32);
+    assert!(dump.starts_with("0000  68 65 6c 6c 6f 20 74 68 65 72 65 00 00 00 00 00  |hello there.....|\n"));
+
+    // with no device named, the first disk
+    writesec("0x10 first", &disks).unwrap();
+    let mut sector = [0u8; crate::block::SECTOR_SIZE];
+    disks.open("ata0").unwrap().read_sectors(16, &mut sector).unwrap();
+    assert_eq!(&sector[..6], b"first\0");
+    assert!(readsec("16", &disks).unwrap().contains("|first..........|"));
+
+    let failures = [
+        (readsec("", &disks), CommandError::Usage("readsec [<device>] <lba>")),
+        (readsec("virtio0 3 4", &disks), CommandError::Usage("readsec [<device>] <lba>")),
+        (readsec("virtio7 0", &disks), CommandError::NoDevice(String::from("virtio7"))),
+        (readsec("virtio0 64", &disks), CommandError::Io(BlockError::OutOfRange)),
+        (writesec("virtio0 1", &disks), CommandError::Usage("writesec [<device>] <lba> <text of up to 512 bytes>")),
+    ];
+    for (result, err) in failures {
+        assert_eq!(result, Err(err));
+    }
+    assert!(readsec("0", &Registry::<crate::block::testdata::SharedDisk>::new()).is_err());
+}
+
+#[test_case]
+fn test_hexdump() {
+    assert_eq!(hexdump(b"", 0), "");
+    assert_eq!(hexdump(b"AB\n", 0), alloc::format!("0000  41 42 0a{:39}  |AB.|\n", ""));
+    let dump = hexdump(&[0x7f; 17], 0);
+    assert_eq!(dump.lines().nth(1), Some(alloc::format!("0010  7f{:45}  |.|", "").as_str()));
+}
+
+#[test_case]
+fn test_split_command() {
+    assert_eq!(split_command("ls"), ("ls", ""));
+    assert_eq!(split_command("echo  hello "), ("echo", "hello"));
+    assert_eq!(split_command(" \tkbrate 10.9  500"), ("kbrate", "10.9  500"));
+    assert_eq!(split_command("   "), ("", ""));
+}
+
+#[test_case]
+fn test_ramfs_paths() {
+    assert_eq!(resolve_in("/ram", "notes"), "/ram/notes");
+    assert_eq!(resolve_in("/ram", "/init/hello.txt"), "/init/hello.txt");
+    assert_eq!(resolve_in("/disk/docs", "../a.txt"), "/disk/a.txt");
+    assert_eq!(resolve_in("/disk", "./x/"), "/disk/x");
+    assert_eq!(resolve_in("/disk", ""), "/disk");
+    assert_eq!(ramfs_name("/ram/notes"), "notes");
+    assert_eq!(ramfs_name("notes"), "notes");
+    assert_eq!(ramfs_name("/ramp"), "/ramp");
+    assert!(on_ramfs("/ram/notes"));
+    assert!(!on_ramfs("/ramp") && !on_ramfs("/disk/notes"));
+}
+
+#[test_case]
+fn test_command_table() {
+    for (i, &(name, help, _)) in COMMANDS.iter().enumerate() {
+        assert!(!name.is_empty() && !name.contains(' ') && !help.is_empty());
+        assert!(COMMANDS[..i].iter().all(|&(other, _, _)| other != name), "{} twice", name);
+    }
+    assert!(find_command("mount").is_some());
+    assert!(find_command("mountx").is_none());
+    assert!(find_command("").is_none());
+    assert!(command_names().starts_with("help, clear, echo"));
+}
+
+#[test_case]
+fn test_echo_sees_the_last_status() {
+    let mut shell = Shell::new();
+    shell.handle_line("umount");
+    // `umount` with no path failed with a usage error
+    assert_eq!(shell.last_status, 0);
+    assert_eq!(shell.status, 2);
+    shell.handle_line("   echo   status $? ");
+    assert_eq!(shell.last_status, 2);
+    assert_eq!(shell.status, 0);
+}
+
+#[test_case]
+fn test_split_args() {
+    let split = |line| split_args(line).unwrap();
+    assert_eq!(split("  a  bc\td "), ["a", "bc", "d"]);
+    assert_eq!(split(""), [] as [&str; 0]);
+    assert_eq!(split(r#"say "hello there" 'it''s'"#), ["say", "hello there", "its"]);
+    assert_eq!(split(r#"a\ b "\"q\"" '\n' x"" """#), ["a b", "\"q\"", "\\n", "x", ""]);
+    assert_eq!(split_args("'open"), Err(ShellError::UnclosedQuote));
+    assert_eq!(split_args(r#"a "b"#), Err(ShellError::UnclosedQuote));
+}
+
+#[test_case]
+fn test_command_registry() {
+    fn nothing(_: &[&str], _: &mut dyn fmt::Write) -> Result<(), ShellError> {
+        Ok(())
+    }
+    let mut registry = CommandRegistry::new();
+    assert_eq!(registry.register("probe", "does nothing", nothing), Ok(()));
+    assert_eq!(registry.register("probe", "again", nothing), Err(RegisterError::Taken("probe")));
+    assert_eq!(registry.register("two words", "", nothing), Err(RegisterError::InvalidName("two words")));
+    assert_eq!(registry.register("", "", nothing), Err(RegisterError::InvalidName("")));
+    assert_eq!(registry.find("probe").map(|command| command.help), Some("does nothing"));
+    assert!(registry.find("prob").is_none());
+    assert_eq!(registry.commands().len(), 1);
+}
+
+#[cfg(test)]
+static SEEN: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[test_case]
+fn test_registered_commands_run() {
+    fn record(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+        match args {
+            [] => Err(ShellError::Usage("test-record <arg>...")),
+            ["fail"] => Err(ShellError::Failed(String::from("told to"))),
+            _ => {
+                SEEN.lock().extend(args.iter().map(|&arg| String::from(arg)));
+                let _ = writeln!(out, "recorded {}", args.len());
+                Ok(())
+            }
+        }
+    }
+    assert_eq!(register_command("test-record", "keeps its arguments", record), Ok(()));
+    assert_eq!(register_command("ls", "", record), Err(RegisterError::Taken("ls")));
+    assert!(help("").contains("test-record   keeps its arguments\n"));
+    assert!(help("test-record").starts_with("test-record"));
+    assert_eq!(help("nothing"), "help: nothing: no such command\n");
+    assert!(command_names().ends_with("test-record"));
+
+    let mut shell = Shell::new();
+    shell.handle_line(r#"test-record one "two three""#);
+    assert_eq!(*SEEN.lock(), ["one", "two three"]);
+    assert_eq!(shell.status, 0);
+    shell.handle_line("test-record");
+    assert_eq!(shell.status, 2);
+    shell.handle_line("test-record fail");
+    assert_eq!(shell.status, 1);
+    shell.handle_line("test-record 'unclosed");
+    assert_eq!(shell.status, 2);
+    assert_eq!(SEEN.lock().len(), 2);
+}
+
+#[test_case]
+fn test_completion_candidates() {
+    let names = complete("");
+    assert!(names.iter().any(|name| name == "mount") && names.iter().any(|name| name == "history"));
+    assert_eq!(complete("mo"), names);
+    assert_eq!(complete("help um"), names);
+    assert!(complete("echo ").is_empty());
+    assert!(complete("help mount ").is_empty());
+    assert!(complete("write notes te").is_empty());
+    assert!(complete("cat /nowhere/x").is_empty());
+}
+
+#[test_case]
+fn test_free_output() {
+    let stats = crate::memory::MemoryStats {
+        usable_bytes: 130 * 1024 * 1024,
+        frames_total: 32768,
+        frames_allocated: 600,
+        frames_freed: 88,
+        frames_remaining: 32256,
+        // 512 frames in pieces, the rest in 4 MiB blocks
+        free_blocks: [2, 1, 1, 1, 1, 1, 1, 1, 1, 0, 31],
+        heap: None,
+    };
+    let output = free(&stats);
+    let lines: Vec<Vec<&str>> = output.lines().map(|line| line.split_whitespace().collect()).collect();
+    assert_eq!(lines[1], ["total", "32768", "128", "MiB"]);
+    assert_eq!(lines[2], ["in", "use", "512", "2", "MiB"]);
+    assert_eq!(lines[3], ["free", "32256", "126", "MiB"]);
+    assert_eq!(lines[4].last(), Some(&"MiB"));
+    assert_eq!(lines[5], ["free", "blocks", "by", "order:", "2", "1", "1", "1", "1", "1", "1", "1", "1", "0", "31"]);
+    assert_eq!(lines[6], ["largest", "free", "block", "4", "MiB,", "1%", "of", "free", "memory", "in", "pieces", "under", "2", "MiB"]);
+}
+
+#[test_case]
+fn test_vmmap_lists_the_heap() {
+    let heap = alloc::format!("{:#016x}-", crate::allocator::HEAP_START);
+    assert!(vmmap().lines().any(|line| line.starts_with(&heap) && line.ends_with("heap (fixed)")), "{}", vmmap());
+}
+
+#[test_case]
+fn test_random_command() {
+    let out = random("").unwrap();
+    let (hex, source) = out.split_once('\n').unwrap();
+    assert_eq!(hex.len(), 32);
+    assert!(hex.bytes().all(|byte| byte.is_ascii_hexdigit()));
+    assert!(source.starts_with("from "));
+    assert_eq!(random("3").unwrap().lines().next().map(str::len), Some(6));
+    assert!(matches!(random("0"), Err(CommandError::Usage(_))));
+    assert!(matches!(random("257"), Err(CommandError::Usage(_))));
+}
+
+#[test_case]
+fn test_profile_command() {
+    assert!(matches!(profile(""), Err(CommandError::Usage(_))));
+    assert!(profile("start").is_ok());
+    assert!(profile("report").unwrap().starts_with("profile of "));
+    assert!(profile("stop").is_ok());
+    assert!(matches!(profile("stop"), Err(CommandError::Profile(crate::stats::ProfileError::NotRunning))));
+    crate::stats::start();
+}
+
+#[test_case]
+fn test_peek_and_poke() {
+    let buffer = alloc::boxed::Box::new(*b"peek at this!...");
+    let addr = buffer.as_ptr() as u64;
+    let dump = peek(&alloc::format!("{:#x} 13", addr)).unwrap();
+    assert_eq!(dump, alloc::format!("{:04x}  70 65 65 6b 20 61 74 20 74 68 69 73 21{:9}  |peek at this!|\n", addr, ""));
+    assert_eq!(poke(&alloc::format!("{:#x} 0x50", addr)).unwrap(), alloc::format!("wrote 0x50 to {:#x}\n", addr));
+    assert_eq!(&buffer[..4], b"Peek");
+    assert_eq!(peek(&alloc::format!("{}", addr)).unwrap().lines().count(), 4);
+
+    assert_eq!(peek(""), Err(CommandError::Usage("peek <addr> [len of up to 4096]")));
+    assert_eq!(peek(&alloc::format!("{:#x} 0", addr)), Err(CommandError::Usage("peek <addr> [len of up to 4096]")));
+    assert_eq!(peek("0x123400000000"), Err(CommandError::NotMapped(0x1234_0000_0000)));
+    assert_eq!(poke("0x123400000000 1"), Err(CommandError::NotMapped(0x1234_0000_0000)));
+    assert_eq!(poke(&alloc::format!("{:#x} 256", addr)), Err(CommandError::Usage("poke <addr> <byte>")));
+    assert_eq!(pmap("0x123400000000"), Err(CommandError::NotMapped(0x1234_0000_0000)));
+    assert_eq!(pmap("nowhere"), Err(CommandError::Usage("pmap <virtual address>")));
+    // the physical memory window maps the frame at its offset
+    let offset = crate::memory::physical_memory_offset().unwrap().as_u64();
+    assert!(pmap(&alloc::format!("{:#x}", offset + 0xb8000)).unwrap().starts_with(&alloc::format!("{:#x} -> 0xb8000  P W", offset + 0xb8000)));
+}
+
+#[test_case]
+fn test_cpuinfo_output() {
+    let out = cpuinfo();
+    let bsp = alloc::format!("*   0  {:>7}", crate::percpu::current().apic_id());
+    assert!(out.lines().any(|line| line == bsp), "{}", out);
+    // every x86_64 CPU has SSE2 and long mode's NX bit
+    assert!(out.lines().any(|line| line.starts_with("features:") && line.contains(" sse2") && line.contains(" nx")), "{}", out);
+    assert!(out.ends_with(&alloc::format!("{} online\n", crate::smp::online_cpus())));
+}
+
+#[test_case]
+fn test_write_to_a_mount() {
+    let disks = test_disks();
+    let mounts = Mutex::new(MountTable::new());
+    mount("ata0p1 /disk", &disks, &mounts).unwrap();
+    write_vfs(&mounts, "/disk/hello.txt", b"replaced\n", false).unwrap();
+    write_vfs(&mounts, "/disk/hello.txt", b"more\n", true).unwrap();
+    write_vfs(&mounts, "/disk/A New File.txt", b"made\n", true).unwrap();
+    let read = |path| crate::vfs::open_in(&mounts, path).map(|mut file| {
+        let mut buf = [0u8; 32];
+        let n = file.read(0, &mut buf).unwrap();
+        alloc::vec::Vec::from(&buf[..n])
+    });
+    assert_eq!(read("/disk/hello.txt").as_deref(), Ok(&b"replaced\nmore\n"[..]));
+    assert_eq!(read("/disk/a new file.txt").as_deref(), Ok(&b"made\n"[..]));
+    crate::vfs::remove_in(&mounts, "/disk/A New File.txt").unwrap();
+    assert_eq!(read("/disk/A New File.txt").err(), Some(VfsError::NotFound));
+    assert_eq!(write_vfs(&mounts, "/nowhere/x", b"", false), Err(VfsError::NotMounted));
+}
+
+#[test_case]
+fn test_cd() {
+    let disks = test_disks();
+    let mounts = Mutex::new(MountTable::new());
+    mount("ata0p1 /disk", &disks, &mounts).unwrap();
+    let mut shell = Shell::new();
+    assert_eq!(shell.cwd(), "/ram");
+    assert_eq!(cd(&mut shell, "/disk", &mounts), Ok(String::new()));
+    assert_eq!(shell.resolve("hello.txt"), "/disk/hello.txt");
+    assert_eq!(cd(&mut shell, "hello.txt", &mounts), Err(CommandError::Vfs(VfsError::NotADirectory)));
+    assert_eq!(cd(&mut shell, "missing", &mounts), Err(CommandError::Vfs(VfsError::NotFound)));
+    assert_eq!(shell.cwd(), "/disk");
+    // `/` only exists for the mounts below it
+    assert_eq!(cd(&mut shell, "..", &mounts), Ok(String::new()));
+    assert_eq!(shell.cwd(), "/");
+    assert_eq!(cd(&mut shell, "", &mounts), Err(CommandError::Vfs(VfsError::NotMounted)));
+}
+
+#[test_case]
+fn test_spawn_from_a_path() {
+    assert_eq!(spawn("/nowhere/prog", false), Err(CommandError::Vfs(VfsError::NotMounted)));
+    assert_eq!(run_program("/nowhere/prog"), Err(CommandError::Vfs(VfsError::NotMounted)));
+    let err = CommandError::Spawn(String::from("/ram/x"), crate::process::SpawnError::Elf(crate::elf::ElfError::BadMagic));
+    assert_eq!(alloc::format!("{}", err), alloc::format!("/ram/x: {}", crate::elf::ElfError::BadMagic));
+    assert_eq!(err.status(), 1);
+}
+
+#[test_case]
+fn test_parse_pipeline() {
+    let stage = |command: &str, input: Option<&str>, output: Option<(&str, bool)>| Stage {
+        command: String::from(command),
+        input: input.map(String::from),
+        output: output.map(|(path, append)| (String::from(path), append)),
+    };
+    assert_eq!(parse_pipeline("ls /ram"), Ok(alloc::vec![stage("ls /ram", None, None)]));
+    assert_eq!(
+        parse_pipeline("cat<in | grep x >>\"out file\"|cat > last"),
+        Ok(alloc::vec![stage("cat", Some("in"), None), stage(" grep x ", None, Some(("out file", true))), stage("cat ", None, Some(("last", false)))]),
+    );
+    // quoted and escaped operators belong to the command
+    assert_eq!(parse_pipeline(r#"echo "a | b" \> c 'd<e'"#), Ok(alloc::vec![stage(r#"echo "a | b" \> c 'd<e'"#, None, None)]));
+    assert_eq!(parse_pipeline("echo it's"), Ok(alloc::vec![stage("echo it's", None, None)]));
+    assert_eq!(parse_pipeline("ls |"), Err(CommandError::Syntax("a command is missing around |")));
+    assert_eq!(parse_pipeline("| ls"), Err(CommandError::Syntax("a command is missing around |")));
+    assert_eq!(parse_pipeline("ls >"), Err(CommandError::Syntax("a file name is missing after < or >")));
+    assert_eq!(parse_pipeline("cat < | ls"), Err(CommandError::Syntax("a file name is missing after < or >")));
+}
+
+#[test_case]
+fn test_redirection_and_pipes() {
+    let _ = crate::fs::mount();
+    let read = |name| crate::fs::RAMFS.lock().read(name).map(|data| String::from(String::from_utf8_lossy(data)));
+    let mut shell = Shell::new();
+    shell.handle_line("echo first > test-redirect");
+    shell.handle_line("echo second line >> /ram/test-redirect");
+    assert_eq!(read("test-redirect").as_deref(), Some("first\nsecond line\n"));
+    shell.handle_line("cat < test-redirect | grep second | cat > test-piped");
+    assert_eq!(read("test-piped").as_deref(), Some("second line\n"));
+    assert_eq!(shell.status, 0);
+    shell.handle_line("help | grep grep > test-piped");
+    assert!(read("test-piped").unwrap().starts_with("grep "));
+    // errors reach the screen, not the file, and keep their status
+    shell.handle_line("grep > test-piped");
+    assert_eq!((shell.status, read("test-piped").as_deref()), (2, Some("")));
+    shell.handle_line("cat < test-missing");
+    assert_eq!(shell.status, 1);
+    shell.handle_line("echo x |");
+    assert_eq!(shell.status, 2);
+    // more than a pipe holds: cat only gets through it with grep reading
+    let big: String = (0..2 * crate::pipe::PIPE_CAPACITY / 16).map(|i| alloc::format!("line {:010}\n", i)).collect();
+    crate::fs::RAMFS.lock().write("test-redirect", big.as_bytes(), false).unwrap();
+    shell.handle_line("cat < test-redirect | grep line | cat > test-piped");
+    assert_eq!((shell.status, read("test-piped").map(|data| data.len())), (0, Some(big.len())));
+    shell.handle_line("cat < test-redirect | grep 0000000007 > test-piped");
+    assert_eq!(read("test-piped").as_deref(), Some("line 0000000007\n"));
+    for name in ["test-redirect", "test-piped"] {
+        crate::fs::RAMFS.lock().remove(name).unwrap();
+    }
+}
+
+#[test_case]
+fn test_endless_input_is_refused() {
+    struct Zeros;
+    impl FileHandle for Zeros {
+        fn size(&self) -> u64 {
+            0
+        }
+
+        fn read(&mut self, _offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+            buf.fill(0);
+            Ok(buf.len())
+        }
+    }
+    let mut shell = Shell::new();
+    shell.stdin = Some(Box::new(Zeros));
+    assert_eq!(cat(&mut shell, ""), Err(CommandError::TooLarge));
+    assert_eq!(grep(&mut shell, "x").err(), Some(CommandError::TooLarge));
+}
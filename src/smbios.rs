@@ -0,0 +1,426 @@
+//! SMBIOS: firmware, system and memory inventory.
+//!
+//! Finds the entry point in the BIOS area (the 64-bit "_SM3_" one when the
+//! firmware has it, else "_SM_"), checks its checksums and walks the
+//! structure table behind it. Each structure is a formatted area followed
+//! by its strings; fields name strings by 1-based index, 0 meaning "none".
+//! An index past the structure's strings means the table is corrupt and is
+//! reported, not guessed around. Firmware fills in only what it knows, so
+//! every field of `Info` is optional.
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Once;
+use crate::bytes::{checksum, le_u16, le_u32, le_u64};
+
+#[cfg(test)]
+mod testdata;
+
+/// Where the entry point lives, on a 16-byte boundary.
+const SCAN_START: u64 = 0xf0000;
+const SCAN_LEN: usize = 0x10000;
+/// Sanity limit for the table length read from the entry point.
+const MAX_TABLE_LEN: usize = 1 << 20;
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END: u8 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmbiosError {
+    NotFound,
+    BadChecksum,
+    Truncated,
+    /// A structure whose header or string set runs past the table.
+    BadStructure { handle: u16 },
+    /// A string index past the structure's strings.
+    BadStringIndex { handle: u16, index: u8 },
+    /// `smbios::init` has not run (or failed).
+    NotInitialized,
+}
+
+impl fmt::Display for SmbiosError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmbiosError::NotFound => write!(f, "no entry point"),
+            SmbiosError::BadChecksum => write!(f, "bad entry point checksum"),
+            SmbiosError::Truncated => write!(f, "truncated table"),
+            SmbiosError::BadStructure { handle } => write!(f, "structure {:#06x} is malformed", handle),
+            SmbiosError::BadStringIndex { handle, index } => {
+                write!(f, "structure {:#06x} names missing string {}", handle, index)
+            }
+            SmbiosError::NotInitialized => write!(f, "SMBIOS not initialized"),
+        }
+    }
+}
+
+// ==========================================================
+// Entry point
+// ==========================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryPoint {
+    pub major: u8,
+    pub minor: u8,
+    pub table_addr: u64,
+    /// Exact length for 2.x, an upper bound for 3.x.
+    pub table_len: usize,
+    /// Only given by 2.x entry points.
+    pub structure_count: Option<u16>,
+}
+
+impl EntryPoint {
+    pub const SIGNATURE: &'static [u8; 4] = b"_SM_";
+    pub const SIGNATURE_64: &'static [u8; 5] = b"_SM3_";
+    const INTERMEDIATE: &'static [u8; 5] = b"_DMI_";
+    const LEN: usize = 0x1f;
+    const LEN_64: usize = 0x18;
+
+    pub fn parse(bytes: &[u8]) -> Result<EntryPoint, SmbiosError> {
+        if bytes.starts_with(Self::SIGNATURE_64) {
+            let len = usize::from(*bytes.get(6).ok_or(SmbiosError::Truncated)?);
+            let entry = bytes.get(..len.max(Self::LEN_64)).ok_or(SmbiosError::Truncated)?;
+            if checksum(&entry[..len]) != 0 {
+                return Err(SmbiosError::BadChecksum);
+            }
+            return Ok(EntryPoint {
+                major: entry[7],
+                minor: entry[8],
+                table_addr: le_u64(entry, 0x10).ok_or(SmbiosError::Truncated)?,
+                table_len: le_u32(entry, 0x0c).ok_or(SmbiosError::Truncated)? as usize,
+                structure_count: None,
+            });
+        }
+        if !bytes.starts_with(Self::SIGNATURE) {
+            return Err(SmbiosError::NotFound);
+        }
+        let len = usize::from(*bytes.get(5).ok_or(SmbiosError::Truncated)?);
+        let entry = bytes.get(..len.max(Self::LEN)).ok_or(SmbiosError::Truncated)?;
+        if &entry[0x10..0x15] != Self::INTERMEDIATE {
+            return Err(SmbiosError::NotFound);
+        }
+        if checksum(&entry[..len]) != 0 || checksum(&entry[0x10..0x1f]) != 0 {
+            return Err(SmbiosError::BadChecksum);
+        }
+        Ok(EntryPoint {
+            major: entry[6],
+            minor: entry[7],
+            table_addr: u64::from(le_u32(entry, 0x18).ok_or(SmbiosError::Truncated)?),
+            table_len: usize::from(le_u16(entry, 0x16).ok_or(SmbiosError::Truncated)?),
+            structure_count: le_u16(entry, 0x1c),
+        })
+    }
+}
+
+/// Scans `area` on 16-byte boundaries for a valid entry point, preferring
+/// the 64-bit one, and returns its offset inside the area.
+pub fn find_entry_point_in(area: &[u8]) -> Option<(usize, EntryPoint)> {
+    let candidates = || {
+        (0..area.len().saturating_sub(EntryPoint::LEN_64 - 1))
+            .step_by(16)
+            .filter_map(|offset| EntryPoint::parse(&area[offset..]).ok().map(|entry| (offset, entry)))
+    };
+    candidates().find(|(_, entry)| entry.structure_count.is_none()).or_else(|| candidates().next())
+}
+
+// ==========================================================
+// Structures
+// ==========================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Structure<'a> {
+    pub kind: u8,
+    pub handle: u16,
+    /// The whole formatted area, header included, so field offsets match
+    /// the specification.
+    pub formatted: &'a [u8],
+    /// The string set without its final NUL.
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    pub fn byte(&self, offset: usize) -> Option<u8> {
+        self.formatted.get(offset).copied()
+    }
+
+    pub fn word(&self, offset: usize) -> Option<u16> {
+        le_u16(self.formatted, offset)
+    }
+
+    pub fn dword(&self, offset: usize) -> Option<u32> {
+        le_u32(self.formatted, offset)
+    }
+
+    /// The string at 1-based `index`; `None` for index 0.
+    pub fn string_at(&self, index: u8) -> Result<Option<&'a str>, SmbiosError> {
+        if index == 0 {
+            return Ok(None);
+        }
+        let bad = SmbiosError::BadStringIndex { handle: self.handle, index };
+        let raw = self
+            .strings
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .nth(usize::from(index - 1))
+            .ok_or(bad)?;
+        // firmware strings are meant to be ASCII
+        Ok(Some(core::str::from_utf8(raw).map_err(|_| bad)?.trim()))
+    }
+
+    /// The string named by the index byte at `offset`. A structure too old
+    /// to have the field has no string.
+    pub fn string(&self, offset: usize) -> Result<Option<&'a str>, SmbiosError> {
+        match self.byte(offset) {
+            Some(index) => self.string_at(index),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Walks a structure table up to the end-of-table structure or its end.
+pub struct Structures<'a> {
+    table: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+pub fn structures(table: &[u8]) -> Structures<'_> {
+    Structures { table, offset: 0, done: false }
+}
+
+impl<'a> Iterator for Structures<'a> {
+    type Item = Result<Structure<'a>, SmbiosError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset + 4 > self.table.len() {
+            return None;
+        }
+        let rest = &self.table[self.offset..];
+        let (kind, len) = (rest[0], usize::from(rest[1]));
+        let handle = le_u16(rest, 2).unwrap_or(0);
+        // the string set ends with a double NUL; with no strings it is
+        // just the two NULs
+        let strings_end = (len >= 4 && len < rest.len())
+            .then(|| rest[len..].windows(2).position(|pair| pair == [0, 0]))
+            .flatten();
+        let Some(strings_len) = strings_end else {
+            self.done = true;
+            return Some(Err(SmbiosError::BadStructure { handle }));
+        };
+        self.offset += len + strings_len + 2;
+        self.done = kind == TYPE_END;
+        Some(Ok(Structure { kind, handle, formatted: &rest[..len], strings: &rest[len..len + strings_len] }))
+    }
+}
+
+// ==========================================================
+// Info
+// ==========================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryDevice<'a> {
+    pub locator: Option<&'a str>,
+    /// `None` when the firmware doesn't know; 0 for an empty slot.
+    pub size_kib: Option<u64>,
+}
+
+impl MemoryDevice<'_> {
+    /// Decodes the Type 17 size word and, when it says so, the extended
+    /// size in MiB.
+    pub fn decode_size(size: u16, extended: Option<u32>) -> Option<u64> {
+        match size {
+            0xffff => None,
+            0x7fff => extended.map(|mib| u64::from(mib & 0x7fff_ffff) * 1024),
+            _ if size & 0x8000 != 0 => Some(u64::from(size & 0x7fff)),
+            _ => Some(u64::from(size) * 1024),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Info<'a> {
+    pub version: (u8, u8),
+    pub bios_vendor: Option<&'a str>,
+    pub bios_version: Option<&'a str>,
+    pub bios_date: Option<&'a str>,
+    pub system_manufacturer: Option<&'a str>,
+    pub system_product: Option<&'a str>,
+    pub system_version: Option<&'a str>,
+    pub memory_devices: Vec<MemoryDevice<'a>>,
+}
+
+impl<'a> Info<'a> {
+    pub fn parse(version: (u8, u8), table: &'a [u8]) -> Result<Info<'a>, SmbiosError> {
+        let mut info = Info { version, ..Info::default() };
+        for structure in structures(table) {
+            let structure = structure?;
+            match structure.kind {
+                TYPE_BIOS => {
+                    info.bios_vendor = structure.string(0x04)?;
+                    info.bios_version = structure.string(0x05)?;
+                    info.bios_date = structure.string(0x08)?;
+                }
+                TYPE_SYSTEM => {
+                    info.system_manufacturer = structure.string(0x04)?;
+                    info.system_product = structure.string(0x05)?;
+                    info.system_version = structure.string(0x06)?;
+                }
+                TYPE_MEMORY_DEVICE => info.memory_devices.push(MemoryDevice {
+                    locator: structure.string(0x10)?,
+                    size_kib: structure
+                        .word(0x0c)
+                        .and_then(|size| MemoryDevice::decode_size(size, structure.dword(0x1c))),
+                }),
+                _ => {}
+            }
+        }
+        Ok(info)
+    }
+
+    /// Installed memory the devices add up to, in KiB.
+    pub fn memory_kib(&self) -> u64 {
+        self.memory_devices.iter().filter_map(|device| device.size_kib).sum()
+    }
+}
+
+static INFO: Once<Info<'static>> = Once::new();
+
+/// Finds and parses the SMBIOS table. Needs `acpi::init` to have set up
+/// the physical memory mapping, and the heap.
+pub fn init() -> Result<&'static Info<'static>, SmbiosError> {
+    let area = crate::acpi::phys_bytes(SCAN_START, SCAN_LEN).ok_or(SmbiosError::NotInitialized)?;
+    let (_, entry) = find_entry_point_in(area).ok_or(SmbiosError::NotFound)?;
+    if entry.table_len > MAX_TABLE_LEN {
+        return Err(SmbiosError::Truncated);
+    }
+    let table = crate::acpi::phys_bytes(entry.table_addr, entry.table_len).ok_or(SmbiosError::NotInitialized)?;
+    let info = Info::parse((entry.major, entry.minor), table)?;
+    Ok(INFO.call_once(|| info))
+}
+
+pub fn info() -> Result<&'static Info<'static>, SmbiosError> {
+    INFO.get().ok_or(SmbiosError::NotInitialized)
+}
+
+/// Writes the firmware, the system and its memory slots to `out`.
+pub fn write_summary(out: &mut impl fmt::Write) -> fmt::Result {
+    let info = match info() {
+        Ok(info) => info,
+        Err(err) => return writeln!(out, "SMBIOS: {}", err),
+    };
+    let or_unknown = |field: Option<&'static str>| field.unwrap_or("?");
+    writeln!(out, "SMBIOS {}.{}", info.version.0, info.version.1)?;
+    writeln!(
+        out,
+        "  BIOS:   {} {} ({})",
+        or_unknown(info.bios_vendor), or_unknown(info.bios_version), or_unknown(info.bios_date)
+    )?;
+    writeln!(
+        out,
+        "  system: {} {} {}",
+        or_unknown(info.system_manufacturer), or_unknown(info.system_product), or_unknown(info.system_version)
+    )?;
+    for device in &info.memory_devices {
+        match device.size_kib {
+            Some(0) => writeln!(out, "  {}: empty", or_unknown(device.locator))?,
+            Some(kib) => writeln!(out, "  {}: {} MiB", or_unknown(device.locator), kib / 1024)?,
+            None => writeln!(out, "  {}: unknown size", or_unknown(device.locator))?,
+        }
+    }
+    Ok(())
+}
+
+//test case
+#[test_case]
+fn test_entry_points() {
+    let entry = EntryPoint::parse(&testdata::ENTRY_POINT).unwrap();
+    assert_eq!((entry.major, entry.minor), (2, 8));
+    assert_eq!(entry.table_addr, 0xf5a90);
+    assert_eq!(entry.table_len, testdata::TABLE.len());
+    assert_eq!(entry.structure_count, Some(6));
+
+    let entry = EntryPoint::parse(&testdata::ENTRY_POINT_64).unwrap();
+    assert_eq!((entry.major, entry.minor), (3, 0));
+    assert_eq!(entry.table_addr, 0x7fff_a000);
+    assert_eq!(entry.structure_count, None);
+
+    let mut bytes = testdata::ENTRY_POINT;
+    bytes[0x18] ^= 1;
+    assert_eq!(EntryPoint::parse(&bytes), Err(SmbiosError::BadChecksum));
+    assert_eq!(EntryPoint::parse(&testdata::ENTRY_POINT[..0x10]), Err(SmbiosError::Truncated));
+    assert_eq!(EntryPoint::parse(b"_SM_ but not really, only some text in memory"), Err(SmbiosError::NotFound));
+}
+
+#[test_case]
+fn test_scan_prefers_64_bit_entry() {
+    let mut area = [0u8; 96];
+    area[16..16 + 31].copy_from_slice(&testdata::ENTRY_POINT);
+    assert_eq!(find_entry_point_in(&area).map(|(offset, _)| offset), Some(16));
+    area[64..64 + 24].copy_from_slice(&testdata::ENTRY_POINT_64);
+    let (offset, entry) = find_entry_point_in(&area).unwrap();
+    assert_eq!((offset, entry.major), (64, 3));
+    // off the 16-byte grid it isn't found
+    let mut area = [0u8; 64];
+    area[8..8 + 24].copy_from_slice(&testdata::ENTRY_POINT_64);
+    assert_eq!(find_entry_point_in(&area), None);
+}
+
+#[test_case]
+fn test_structure_walk() {
+    let kinds: Vec<(u8, u16)> = structures(&testdata::TABLE).map(|s| s.map(|s| (s.kind, s.handle)).unwrap()).collect();
+    assert_eq!(kinds, [(0, 0), (1, 0x100), (16, 0x1000), (17, 0x1100), (17, 0x1101), (127, 0x7f00)]);
+    // nothing is read past the end-of-table structure
+    let mut padded = Vec::from(&testdata::TABLE[..]);
+    padded.extend_from_slice(&[0xff; 8]);
+    assert_eq!(structures(&padded).count(), 6);
+    // a string set that never ends
+    let cut = &testdata::TABLE[..30];
+    assert_eq!(structures(cut).last(), Some(Err(SmbiosError::BadStructure { handle: 0 })));
+}
+
+#[test_case]
+fn test_strings() {
+    let bios = structures(&testdata::TABLE).next().unwrap().unwrap();
+    assert_eq!(bios.string_at(0), Ok(None));
+    assert_eq!(bios.string_at(1), Ok(Some("berry")));
+    assert_eq!(bios.string_at(3), Ok(Some("04/01/2014")));
+    assert_eq!(bios.string_at(4), Err(SmbiosError::BadStringIndex { handle: 0, index: 4 }));
+    // past the formatted area: the field doesn't exist in this version
+    assert_eq!(bios.string(0x40), Ok(None));
+    let array = structures(&testdata::TABLE).nth(2).unwrap().unwrap();
+    assert_eq!(array.string_at(1), Err(SmbiosError::BadStringIndex { handle: 0x1000, index: 1 }));
+}
+
+#[test_case]
+fn test_info_from_qemu_table() {
+    let info = Info::parse((2, 8), &testdata::TABLE).unwrap();
+    assert_eq!(info.bios_vendor, Some("berry"));
+    assert_eq!(info.bios_version, Some("1.2"));
+    assert_eq!(info.bios_date, Some("04/01/2014"));
+    assert_eq!(info.system_manufacturer, Some("BerryCorp"));
+    assert_eq!(info.system_product, Some("Pi"));
+    assert_eq!(info.system_version, Some("pc-i440fx-8.2"));
+    assert_eq!(
+        info.memory_devices,
+        [
+            MemoryDevice { locator: Some("DIMM 0"), size_kib: Some(1024 * 1024) },
+            MemoryDevice { locator: Some("DIMM 1"), size_kib: Some(128 * 1024 * 1024) },
+        ]
+    );
+    assert_eq!(info.memory_kib(), 129 * 1024 * 1024);
+    assert_eq!(
+        Info::parse((2, 8), &testdata::BAD_STRING_INDEX),
+        Err(SmbiosError::BadStringIndex { handle: 0x100, index: 5 })
+    );
+}
+
+#[test_case]
+fn test_memory_device_size() {
+    assert_eq!(MemoryDevice::decode_size(0, None), Some(0));
+    assert_eq!(MemoryDevice::decode_size(512, None), Some(512 * 1024));
+    assert_eq!(MemoryDevice::decode_size(0x8000 | 640, None), Some(640));
+    assert_eq!(MemoryDevice::decode_size(0xffff, None), None);
+    assert_eq!(MemoryDevice::decode_size(0x7fff, Some(0x8000_4000)), Some(0x4000 * 1024));
+    assert_eq!(MemoryDevice::decode_size(0x7fff, None), None);
+}
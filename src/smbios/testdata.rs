@@ -0,0 +1,53 @@
+//! SMBIOS data as QEMU builds it for `-machine pc` with
+//! `-smbios type=0,vendor=berry,version=1.2 -smbios type=1,manufacturer=BerryCorp,product=Pi`
+//! and two DIMMs, the second one 128 GiB, used as fixtures for the parser
+//! tests. `TABLE` sits at 0xf5a90 for the 2.8 entry point and at
+//! 0x7fffa000 for the 3.0 one.
+
+pub static ENTRY_POINT: [u8; 31] = [
+    0x5f, 0x53, 0x4d, 0x5f, 0x51, 0x1f, 0x02, 0x08, 0x28, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x5f, 0x44, 0x4d, 0x49, 0x5f, 0x53, 0xee, 0x00,
+    0x90, 0x5a, 0x0f, 0x00, 0x06, 0x00, 0x28,
+];
+
+pub static ENTRY_POINT_64: [u8; 24] = [
+    0x5f, 0x53, 0x4d, 0x33, 0x5f, 0x47, 0x18, 0x03, 0x00, 0x00, 0x01, 0x00,
+    0xee, 0x00, 0x00, 0x00, 0x00, 0xa0, 0xff, 0x7f, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Types 0, 1, 16, 17, 17 and 127.
+pub static TABLE: [u8; 238] = [
+    0x00, 0x18, 0x00, 0x00, 0x01, 0x02, 0x00, 0xe8, 0x03, 0x00, 0x08, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff,
+    0x62, 0x65, 0x72, 0x72, 0x79, 0x00, 0x31, 0x2e, 0x32, 0x00, 0x30, 0x34,
+    0x2f, 0x30, 0x31, 0x2f, 0x32, 0x30, 0x31, 0x34, 0x00, 0x00, 0x01, 0x1b,
+    0x00, 0x01, 0x01, 0x02, 0x03, 0x00, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15,
+    0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f, 0x06, 0x00,
+    0x00, 0x42, 0x65, 0x72, 0x72, 0x79, 0x43, 0x6f, 0x72, 0x70, 0x00, 0x50,
+    0x69, 0x00, 0x70, 0x63, 0x2d, 0x69, 0x34, 0x34, 0x30, 0x66, 0x78, 0x2d,
+    0x38, 0x2e, 0x32, 0x00, 0x00, 0x10, 0x17, 0x00, 0x10, 0x01, 0x03, 0x06,
+    0x00, 0x00, 0x00, 0x02, 0xfe, 0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x11, 0x28, 0x00, 0x11, 0x00, 0x10,
+    0xfe, 0xff, 0x40, 0x00, 0x40, 0x00, 0x00, 0x04, 0x09, 0x00, 0x01, 0x00,
+    0x07, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x44, 0x49,
+    0x4d, 0x4d, 0x20, 0x30, 0x00, 0x51, 0x45, 0x4d, 0x55, 0x00, 0x00, 0x11,
+    0x28, 0x01, 0x11, 0x00, 0x10, 0xfe, 0xff, 0x40, 0x00, 0x40, 0x00, 0xff,
+    0x7f, 0x09, 0x00, 0x01, 0x00, 0x07, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x44, 0x49, 0x4d, 0x4d, 0x20, 0x31, 0x00, 0x51, 0x45,
+    0x4d, 0x55, 0x00, 0x00, 0x7f, 0x04, 0x00, 0x7f, 0x00, 0x00,
+];
+
+/// The type 1 structure names string 5 of 3 as the product.
+pub static BAD_STRING_INDEX: [u8; 106] = [
+    0x00, 0x18, 0x00, 0x00, 0x01, 0x02, 0x00, 0xe8, 0x03, 0x00, 0x08, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff,
+    0x62, 0x65, 0x72, 0x72, 0x79, 0x00, 0x31, 0x2e, 0x32, 0x00, 0x30, 0x34,
+    0x2f, 0x30, 0x31, 0x2f, 0x32, 0x30, 0x31, 0x34, 0x00, 0x00, 0x01, 0x1b,
+    0x00, 0x01, 0x01, 0x05, 0x03, 0x00, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15,
+    0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f, 0x06, 0x00,
+    0x00, 0x42, 0x65, 0x72, 0x72, 0x79, 0x43, 0x6f, 0x72, 0x70, 0x00, 0x70,
+    0x63, 0x2d, 0x69, 0x34, 0x34, 0x30, 0x66, 0x78, 0x2d, 0x38, 0x2e, 0x32,
+    0x00, 0x78, 0x00, 0x00, 0x7f, 0x04, 0x00, 0x7f, 0x00, 0x00,
+];
@@ -0,0 +1,199 @@
+//! Brings up the application processors (APs) listed in the MADT.
+//!
+//! APs are started one at a time with INIT-SIPI-SIPI. Each one gets a
+//! stack and a GDT/TSS prepared by the bootstrap processor (BSP), runs the
+//! real-mode trampoline into long mode, sets up its per-CPU block, reports
+//! in and parks in `hlt`. No work is scheduled on them yet, and no more
+//! than `percpu::MAX_CPUS` CPUs are started.
+//!
+//! The trampoline data block is shared, so the BSP only fills it in for the
+//! next AP once the previous one has signalled `AP_READY`, and stops
+//! starting APs if one never does.
+
+mod trampoline;
+
+use crate::acpi::madt::Cpu;
+use crate::apic::{self, LocalApic};
+use crate::gdt::CpuTables;
+use crate::{println, time, warn};
+use alloc::boxed::Box;
+use alloc::vec;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use trampoline::{TrampolineData, TRAMPOLINE_ADDR};
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const AP_STACK_SIZE: usize = 4096 * 4;
+
+/// Delay the MP spec asks for between INIT and the first startup IPI.
+const INIT_DELAY_US: u64 = 10_000;
+/// How long to wait for an AP after the first startup IPI before sending
+/// the second one.
+const SIPI_RETRY_TICKS: u64 = 2;
+const STARTUP_TIMEOUT_TICKS: u64 = crate::time::secs_to_ticks(1);
+
+/// The BSP counts as online from the start.
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+/// Set by the AP being started once it no longer needs the trampoline.
+static AP_READY: AtomicBool = AtomicBool::new(false);
+
+/// Handed to each AP through the trampoline.
+struct ApStart {
+    index: usize,
+    tables: &'static CpuTables,
+}
+
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+/// Starts every enabled AP in the MADT and returns the number of CPUs
+/// online, BSP included. Needs the heap, ACPI and running timer
+/// interrupts.
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> usize {
+    let topology = match crate::acpi::topology() {
+        Ok(topology) => topology,
+        Err(err) => {
+            warn!("SMP: {}", err);
+            return online_cpus();
+        }
+    };
+    if !x86_64::instructions::interrupts::are_enabled() {
+        warn!("SMP: timer interrupts are needed to start the other CPUs");
+        return online_cpus();
+    }
+    let (level_4, _) = Cr3::read();
+    if level_4.start_address().as_u64() > u64::from(u32::MAX) {
+        warn!("SMP: page tables above 4 GiB can't be loaded by the trampoline");
+        return online_cpus();
+    }
+    if let Err(err) = apic::init(PhysAddr::new(topology.local_apic_address), mapper, frame_allocator) {
+        warn!("SMP: could not map the local APIC: {:?}", err);
+        return online_cpus();
+    }
+    if !identity_map_trampoline(mapper, frame_allocator) {
+        warn!("SMP: could not identity-map the trampoline page");
+        return online_cpus();
+    }
+
+    let trampoline = mapper.phys_offset() + TRAMPOLINE_ADDR;
+    let blob = trampoline::blob();
+    unsafe { ptr::copy_nonoverlapping(blob.as_ptr(), trampoline.as_mut_ptr::<u8>(), blob.len()) };
+    let data = (trampoline + trampoline::data_offset() as u64).as_mut_ptr::<TrampolineData>();
+
+    let mut lapic = apic::local().expect("local APIC mapped");
+    let bsp_id = lapic.id();
+    let mut index = 1;
+    for cpu in topology.cpus.iter().filter(|cpu| cpu.enabled && cpu.apic_id != bsp_id) {
+        if cpu.apic_id > 0xfe {
+            warn!("SMP: CPU with APIC id {} needs x2APIC, skipped", cpu.apic_id);
+            continue;
+        }
+        if index == crate::percpu::MAX_CPUS {
+            warn!("SMP: only {} CPUs have per-CPU data, skipping the rest", index);
+            break;
+        }
+        if !start_ap(&mut lapic, data, cpu, index) {
+            warn!("SMP: CPU with APIC id {} did not start, giving up on the rest", cpu.apic_id);
+            break;
+        }
+        index += 1;
+    }
+    println!("SMP: {} of {} CPUs online", online_cpus(), topology.enabled_cpus());
+    online_cpus()
+}
+
+/// Maps the trampoline page to itself. Low memory is never handed out by
+/// the frame allocator, so the page only needs a mapping.
+fn identity_map_trampoline(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> bool {
+    let addr = VirtAddr::new(TRAMPOLINE_ADDR);
+    match mapper.translate_addr(addr) {
+        Some(phys) => phys.as_u64() == TRAMPOLINE_ADDR,
+        None => {
+            let page = Page::<Size4KiB>::containing_address(addr);
+            let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+    }
+}
+
+fn start_ap(lapic: &mut LocalApic, data: *mut TrampolineData, cpu: &Cpu, index: usize) -> bool {
+    let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+    let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+    let start: &'static ApStart = Box::leak(Box::new(ApStart { index, tables: CpuTables::allocate() }));
+
+    AP_READY.store(false, Ordering::Relaxed);
+    unsafe {
+        ptr::write_volatile(data, TrampolineData {
+            cr3: Cr3::read().0.start_address().as_u64(),
+            efer: Efer::read_raw(),
+            stack_top,
+            entry: ap_main as *const () as u64,
+            arg: start as *const ApStart as u64,
+        });
+    }
+    // everything above must be visible before the AP can run
+    fence(Ordering::SeqCst);
+
+    let vector = (TRAMPOLINE_ADDR >> 12) as u8;
+    lapic.send_init(cpu.apic_id);
+    time::delay_us(INIT_DELAY_US);
+    lapic.send_startup(cpu.apic_id, vector);
+    if wait_ticks(SIPI_RETRY_TICKS, ap_ready) {
+        return true;
+    }
+    lapic.send_startup(cpu.apic_id, vector);
+    wait_ticks(STARTUP_TIMEOUT_TICKS, ap_ready)
+}
+
+fn ap_ready() -> bool {
+    AP_READY.load(Ordering::Acquire)
+}
+
+/// Halts between timer ticks until `done` returns true or `ticks` have
+/// passed. Returns the last result of `done`.
+fn wait_ticks(ticks: u64, done: impl Fn() -> bool) -> bool {
+    let start = time::ticks();
+    while time::ticks() - start < ticks {
+        if done() {
+            return true;
+        }
+        x86_64::instructions::hlt();
+    }
+    done()
+}
+
+/// First Rust code an AP runs, on the stack the BSP allocated for it.
+extern "C" fn ap_main(start: &'static ApStart) -> ! {
+    crate::cpu::enable_sse();
+    start.tables.load();
+    crate::interrupts::init_idt();
+    crate::percpu::init(start.index);
+    let apic_id = apic::local().map_or(0, |lapic| lapic.id());
+    ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+    println!("CPU {} online (APIC id {})", start.index, apic_id);
+    AP_READY.store(true, Ordering::Release);
+    loop {
+        // interrupts stay disabled: nothing here handles them yet
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,115 @@
+//! Real-mode entry code for the application processors.
+//!
+//! A startup IPI starts the AP in 16-bit real mode at a page-aligned
+//! address below 1 MiB, so the code is copied to `TRAMPOLINE_ADDR` first.
+//! It is assembled to run at that address, not where the kernel image
+//! keeps it. The AP goes through protected mode into long mode with the
+//! BSP's page tables and EFER, loads the stack pointer the BSP left in
+//! `TrampolineData` and calls `entry(arg)`.
+//!
+//! The page tables must map the trampoline page to itself: the
+//! instruction after paging is switched on is fetched with the same
+//! address.
+
+use core::slice;
+
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// Filled in by the BSP before each startup IPI. Must match the layout of
+/// `ap_trampoline_data` below.
+#[repr(C)]
+pub struct TrampolineData {
+    /// Only the low 32 bits are loaded, in protected mode.
+    pub cr3: u64,
+    pub efer: u64,
+    pub stack_top: u64,
+    pub entry: u64,
+    pub arg: u64,
+}
+
+core::arch::global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".set ap_base, {base}",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_data",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    xorw %ax, %ax",
+    "    movw %ax, %ds",
+    "    lgdtl ap_base + ap_gdt_ptr - ap_trampoline_start",
+    "    movl %cr0, %eax",
+    "    orl $1, %eax",
+    "    movl %eax, %cr0",
+    "    ljmpl $0x08, $(ap_base + ap_protected - ap_trampoline_start)",
+    ".code32",
+    "ap_protected:",
+    "    movw $0x10, %ax",
+    "    movw %ax, %ds",
+    "    movw %ax, %es",
+    "    movw %ax, %ss",
+    // PAE
+    "    movl %cr4, %eax",
+    "    orl $0x20, %eax",
+    "    movl %eax, %cr4",
+    "    movl ap_base + ap_trampoline_data - ap_trampoline_start, %eax",
+    "    movl %eax, %cr3",
+    // EFER as the BSP has it: LME, plus NXE since the tables use NX
+    "    movl $0xc0000080, %ecx",
+    "    movl ap_base + ap_trampoline_data + 8 - ap_trampoline_start, %eax",
+    "    movl ap_base + ap_trampoline_data + 12 - ap_trampoline_start, %edx",
+    "    wrmsr",
+    // PG | WP
+    "    movl %cr0, %eax",
+    "    orl $0x80010000, %eax",
+    "    movl %eax, %cr0",
+    "    ljmpl $0x18, $(ap_base + ap_long - ap_trampoline_start)",
+    ".code64",
+    "ap_long:",
+    "    xorw %ax, %ax",
+    "    movw %ax, %ds",
+    "    movw %ax, %es",
+    "    movw %ax, %ss",
+    "    movq ap_base + ap_trampoline_data + 16 - ap_trampoline_start, %rsp",
+    "    movq ap_base + ap_trampoline_data + 32 - ap_trampoline_start, %rdi",
+    "    movq ap_base + ap_trampoline_data + 24 - ap_trampoline_start, %rax",
+    "    callq *%rax",
+    "1:  hlt",
+    "    jmp 1b",
+    ".balign 8",
+    "ap_gdt:",
+    "    .quad 0",
+    "    .quad 0x00cf9a000000ffff", // 32-bit code
+    "    .quad 0x00cf92000000ffff", // 32-bit data
+    "    .quad 0x00af9a000000ffff", // 64-bit code
+    "ap_gdt_ptr:",
+    "    .word ap_gdt_ptr - ap_gdt - 1",
+    "    .long ap_base + ap_gdt - ap_trampoline_start",
+    ".balign 8",
+    "ap_trampoline_data:",
+    "    .fill 5, 8, 0",
+    "ap_trampoline_end:",
+    ".popsection",
+    base = const TRAMPOLINE_ADDR,
+    options(att_syntax),
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// The trampoline code and its (zeroed) data block.
+pub fn blob() -> &'static [u8] {
+    let start = &raw const ap_trampoline_start;
+    let end = &raw const ap_trampoline_end;
+    unsafe { slice::from_raw_parts(start, end as usize - start as usize) }
+}
+
+/// Offset of `TrampolineData` within the blob.
+pub fn data_offset() -> usize {
+    &raw const ap_trampoline_data as usize - &raw const ap_trampoline_start as usize
+}
@@ -0,0 +1,360 @@
+//! Software counters and cycle histograms, cheap enough to leave in the
+//! hot paths: a relaxed atomic add per event, two `rdtsc` per timed block.
+//!
+//! A `Counter` is a running total some subsystem bumps: allocations,
+//! bytes printed. `COUNTERS` lists what `stats` shows, those together
+//! with what the kernel already counted (IRQs, context switches). A
+//! `Histogram` sorts TSC cycle counts into power-of-two buckets;
+//! `time_block!` times a block into one, which shows up in `histograms()`
+//! the first time it records something.
+//!
+//! `profile start` takes a snapshot of the counters and empties the
+//! histograms, `profile stop` takes a second snapshot and stops the
+//! histograms recording, and `profile report` prints what changed in
+//! between (up to now while the profile runs). The hardware counters are
+//! in `perf`.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::time;
+
+/// A total that only goes up.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Counter {
+        Counter::new()
+    }
+}
+
+pub static ALLOCATIONS: Counter = Counter::new();
+pub static DEALLOCATIONS: Counter = Counter::new();
+pub static ALLOCATED_BYTES: Counter = Counter::new();
+/// What `print!` wrote, counted once however many outputs it went to.
+pub static PRINTED_BYTES: Counter = Counter::new();
+
+fn irqs() -> u64 {
+    (0..16).map(crate::interrupts::irq_count).sum()
+}
+
+/// Subsystem, name and how to read the count.
+pub type Source = (&'static str, &'static str, fn() -> u64);
+
+/// What `stats` shows.
+pub const COUNTERS: [Source; 7] = [
+    ("interrupts", "irqs", irqs),
+    ("interrupts", "timer", || crate::interrupts::irq_count(0)),
+    ("alloc", "allocations", || ALLOCATIONS.get()),
+    ("alloc", "frees", || DEALLOCATIONS.get()),
+    ("alloc", "bytes", || ALLOCATED_BYTES.get()),
+    ("sched", "switches", crate::scheduler::context_switches),
+    ("console", "bytes printed", || PRINTED_BYTES.get()),
+];
+
+/// Every counter in `COUNTERS` at one moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub tsc: u64,
+    pub values: [u64; COUNTERS.len()],
+}
+
+impl Snapshot {
+    pub fn take() -> Snapshot {
+        Snapshot { tsc: time::rdtsc(), values: COUNTERS.map(|(_, _, read)| read()) }
+    }
+
+    /// How much each counter went up from `earlier`.
+    pub fn since(&self, earlier: &Snapshot) -> Snapshot {
+        let mut values = self.values;
+        for (value, earlier) in values.iter_mut().zip(earlier.values) {
+            *value = value.wrapping_sub(earlier);
+        }
+        Snapshot { tsc: self.tsc.wrapping_sub(earlier.tsc), values }
+    }
+}
+
+/// Bucket `i` holds counts of `2^i` up to `2^(i+1) - 1` cycles; 0 and 1
+/// both go in bucket 0.
+pub const BUCKETS: usize = 48;
+
+/// Cycle counts sorted into power-of-two buckets. Meant to live in a
+/// `static`, usually one `time_block!` makes.
+pub struct Histogram {
+    name: &'static str,
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+    registered: AtomicBool,
+}
+
+impl Histogram {
+    pub const fn new(name: &'static str) -> Histogram {
+        Histogram {
+            name,
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            count: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Adds one sample of `cycles`, unless a stopped profile froze the
+    /// histograms.
+    pub fn record(&'static self, cycles: u64) {
+        if !RECORDING.load(Ordering::Relaxed) {
+            return;
+        }
+        if !self.registered.swap(true, Ordering::AcqRel) {
+            register(self);
+        }
+        self.buckets[bucket(cycles)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(cycles, Ordering::Relaxed);
+        self.max.fetch_max(cycles, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn mean(&self) -> u64 {
+        self.total.load(Ordering::Relaxed).checked_div(self.count()).unwrap_or(0)
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// The upper end of the bucket holding the `percent`th percentile: at
+    /// least that share of the samples took no more cycles. 0 when empty.
+    pub fn percentile(&self, percent: u64) -> u64 {
+        let wanted = (self.count() * percent).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= wanted {
+                return ((2u64 << i) - 1).min(self.max());
+            }
+        }
+        0
+    }
+
+    pub fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+fn bucket(cycles: u64) -> usize {
+    (cycles.max(1).ilog2() as usize).min(BUCKETS - 1)
+}
+
+/// Histograms kept track of at most; later ones still record but are
+/// not listed.
+const MAX_HISTOGRAMS: usize = 32;
+
+// filled without a lock: `record` runs inside the allocator
+static HISTOGRAMS: [AtomicPtr<Histogram>; MAX_HISTOGRAMS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_HISTOGRAMS];
+static REGISTERED: AtomicUsize = AtomicUsize::new(0);
+static RECORDING: AtomicBool = AtomicBool::new(true);
+
+fn register(histogram: &'static Histogram) {
+    let slot = REGISTERED.fetch_add(1, Ordering::AcqRel);
+    if let Some(slot) = HISTOGRAMS.get(slot) {
+        slot.store(histogram as *const Histogram as *mut Histogram, Ordering::Release);
+    }
+}
+
+/// Every histogram that has recorded something, in the order they
+/// started.
+pub fn histograms() -> impl Iterator<Item = &'static Histogram> {
+    HISTOGRAMS.iter().map_while(|slot| unsafe { slot.load(Ordering::Acquire).as_ref() })
+}
+
+/// Runs a block and records the TSC cycles it took in a histogram named
+/// `$name`. The block's value is the macro's; a `return` or `?` out of
+/// it skips the sample.
+#[macro_export]
+macro_rules! time_block {
+    ($name:expr, $body:block) => {{
+        static HISTOGRAM: $crate::stats::Histogram = $crate::stats::Histogram::new($name);
+        let start = $crate::time::rdtsc();
+        let value = $body;
+        HISTOGRAM.record($crate::time::rdtsc().wrapping_sub(start));
+        value
+    }};
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileError {
+    NotStarted,
+    /// `stop` with no profile running.
+    NotRunning,
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProfileError::NotStarted => write!(f, "no profile taken; start one with `profile start`"),
+            ProfileError::NotRunning => write!(f, "no profile running"),
+        }
+    }
+}
+
+struct Profile {
+    start: Snapshot,
+    end: Option<Snapshot>,
+}
+
+static PROFILE: Mutex<Option<Profile>> = Mutex::new(None);
+
+/// Starts a profile, throwing away the last one and what the histograms
+/// held.
+pub fn start() {
+    for histogram in histograms() {
+        histogram.reset();
+    }
+    let start = Snapshot::take();
+    without_interrupts(|| *PROFILE.lock() = Some(Profile { start, end: None }));
+    RECORDING.store(true, Ordering::Relaxed);
+}
+
+/// Ends the running profile; the histograms keep what they saw.
+pub fn stop() -> Result<(), ProfileError> {
+    let end = Snapshot::take();
+    without_interrupts(|| match PROFILE.lock().as_mut() {
+        Some(profile) if profile.end.is_none() => {
+            profile.end = Some(end);
+            RECORDING.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+        _ => Err(ProfileError::NotRunning),
+    })
+}
+
+pub fn running() -> bool {
+    without_interrupts(|| PROFILE.lock().as_ref().is_some_and(|profile| profile.end.is_none()))
+}
+
+/// The last profile: how long it ran, what the counters did and the
+/// histograms.
+pub fn report() -> Result<String, ProfileError> {
+    let (start, end) = without_interrupts(|| PROFILE.lock().as_ref().map(|profile| (profile.start, profile.end)))
+        .ok_or(ProfileError::NotStarted)?;
+    let running = end.is_none();
+    let delta = end.unwrap_or_else(Snapshot::take).since(&start);
+    let mut out = String::new();
+    let _ = match time::tsc_frequency() {
+        Some(hz) => {
+            let ms = time::cycles_to_nanos(delta.tsc, hz) / 1_000_000;
+            write!(out, "profile of {}.{:03} s", ms / 1000, ms % 1000)
+        }
+        None => write!(out, "profile of {} cycles", delta.tsc),
+    };
+    let _ = writeln!(out, "{}", if running { ", still running" } else { "" });
+    write_counters(&mut out, &delta.values);
+    write_histograms(&mut out);
+    Ok(out)
+}
+
+/// The counters since boot and the histograms, for `stats`.
+pub fn summary() -> String {
+    let mut out = String::new();
+    write_counters(&mut out, &Snapshot::take().values);
+    write_histograms(&mut out);
+    out
+}
+
+fn write_counters(out: &mut String, values: &[u64]) {
+    for ((subsystem, name, _), value) in COUNTERS.iter().zip(values) {
+        let _ = writeln!(out, "{:<12}{:<15}{:>12}", subsystem, name, value);
+    }
+}
+
+fn write_histograms(out: &mut String) {
+    let mut histograms = histograms().filter(|histogram| histogram.count() > 0).peekable();
+    if histograms.peek().is_none() {
+        return;
+    }
+    let _ = writeln!(out, "{:<20}{:>9}{:>10}{:>10}{:>10}{:>10}  cycles", "histogram", "count", "mean", "p50", "p99", "max");
+    for histogram in histograms {
+        let _ = writeln!(out, "{:<20}{:>9}{:>10}{:>10}{:>10}{:>10}", histogram.name, histogram.count(),
+            histogram.mean(), histogram.percentile(50), histogram.percentile(99), histogram.max());
+    }
+}
+
+//test case
+#[cfg(test)]
+static TEST_HISTOGRAM: Histogram = Histogram::new("test");
+
+#[test_case]
+fn test_histogram_buckets() {
+    assert_eq!((bucket(0), bucket(1), bucket(2), bucket(3), bucket(1024)), (0, 0, 1, 1, 10));
+    assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    TEST_HISTOGRAM.reset();
+    assert_eq!(TEST_HISTOGRAM.percentile(50), 0);
+    for cycles in [100, 110, 120, 5000] {
+        TEST_HISTOGRAM.record(cycles);
+    }
+    assert_eq!((TEST_HISTOGRAM.count(), TEST_HISTOGRAM.mean(), TEST_HISTOGRAM.max()), (4, 1332, 5000));
+    // 100-120 are all in the 64-127 bucket
+    assert_eq!(TEST_HISTOGRAM.percentile(50), 127);
+    assert_eq!(TEST_HISTOGRAM.percentile(75), 127);
+    // capped at the largest sample
+    assert_eq!(TEST_HISTOGRAM.percentile(99), 5000);
+    assert!(histograms().any(|histogram| histogram.name() == "test"));
+}
+
+#[test_case]
+fn test_time_block_and_profile() {
+    let value = crate::time_block!("test: block", { 6 * 7 });
+    assert_eq!(value, 42);
+    assert!(histograms().any(|histogram| histogram.name() == "test: block" && histogram.count() > 0));
+
+    let before = Snapshot::take();
+    let boxed = alloc::boxed::Box::new([0u8; 100]);
+    drop(boxed);
+    let delta = Snapshot::take().since(&before);
+    assert!(delta.values[2] >= 1 && delta.values[3] >= 1 && delta.values[4] >= 100, "{:?}", delta);
+
+    start();
+    assert!(running());
+    crate::time_block!("test: block", {});
+    assert!(report().unwrap().contains(", still running"));
+    stop().unwrap();
+    assert_eq!(stop(), Err(ProfileError::NotRunning));
+    // stopped: the histograms keep the window's samples only
+    crate::time_block!("test: block", {});
+    let report = report().unwrap();
+    assert!(report.lines().any(|line| line.starts_with("test: block") && line.split_whitespace().nth(2) == Some("1")), "{}", report);
+    assert!(report.contains("alloc       allocations"));
+    RECORDING.store(true, Ordering::Relaxed);
+}
@@ -0,0 +1,711 @@
+//! Kernel symbol names for backtraces.
+//!
+//! `build.rs` embeds the function symbols of the previously linked kernel
+//! in the `ksyms` section. This kernel checks the table before it trusts
+//! it: the address that table records for `resolve` and for `etext` must
+//! match this kernel's own. If they don't, the table is stale, so it is
+//! ignored and addresses stay unresolved. That covers the first build,
+//! which embeds an empty table, and the test binaries as well.
+//!
+//! `Demangled` covers enough of the legacy and v0 Rust manglings for
+//! backtraces. Legacy names lose their hash suffix. v0 names lose their
+//! crate disambiguators and generic arguments. Any name it can't read is
+//! printed raw.
+
+use core::fmt::{self, Write};
+use crate::debug;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_LEN: usize = 24;
+const ENTRY_LEN: usize = 16;
+
+/// Limit on v0 path nesting, so a crafted backreference loop can't
+/// overflow the stack.
+const MAX_DEPTH: u32 = 64;
+
+#[used]
+#[link_section = "ksyms"]
+static mut KSYMS: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin")).len()] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin"));
+
+extern "C" {
+    // Provided by the linker for the `ksyms` section.
+    static __start_ksyms: u8;
+    static __stop_ksyms: u8;
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// A symbol table in the layout `build.rs` writes.
+#[derive(Debug, Clone, Copy)]
+pub struct Table<'a> {
+    bytes: &'a [u8],
+    count: usize,
+}
+
+impl<'a> Table<'a> {
+    /// `None` unless `bytes` holds a well-formed table.
+    pub fn parse(bytes: &'a [u8]) -> Option<Table<'a>> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+            return None;
+        }
+        let count = u32_at(bytes, 4) as usize;
+        let names = HEADER_LEN.checked_add(count.checked_mul(ENTRY_LEN)?)?;
+        if names > bytes.len() {
+            return None;
+        }
+        let table = Table { bytes, count };
+        let names_len = bytes.len() - names;
+        for i in 0..count {
+            let at = HEADER_LEN + i * ENTRY_LEN;
+            let end = u64::from(u32_at(bytes, at + 8)) + u64::from(u32_at(bytes, at + 12));
+            if end > names_len as u64 || core::str::from_utf8(table.name_bytes(i)).is_err() {
+                return None;
+            }
+            if i > 0 && table.address(i - 1) >= table.address(i) {
+                return None;
+            }
+        }
+        Some(table)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Address of `symbols::resolve` in the kernel the table came from.
+    pub fn marker(&self) -> u64 {
+        u64_at(self.bytes, 8)
+    }
+
+    /// `etext` in the kernel the table came from.
+    pub fn etext(&self) -> u64 {
+        u64_at(self.bytes, 16)
+    }
+
+    fn address(&self, i: usize) -> u64 {
+        u64_at(self.bytes, HEADER_LEN + i * ENTRY_LEN)
+    }
+
+    fn name_bytes(&self, i: usize) -> &'a [u8] {
+        let at = HEADER_LEN + i * ENTRY_LEN;
+        let start = HEADER_LEN + self.count * ENTRY_LEN + u32_at(self.bytes, at + 8) as usize;
+        &self.bytes[start..start + u32_at(self.bytes, at + 12) as usize]
+    }
+
+    fn name(&self, i: usize) -> &'a str {
+        // checked by `parse`
+        core::str::from_utf8(self.name_bytes(i)).unwrap_or("")
+    }
+
+    /// The symbol with the greatest address ≤ `addr`, and `addr`'s offset
+    /// from it.
+    pub fn resolve(&self, addr: u64) -> Option<(&'a str, u64)> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.address(mid) <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let i = low.checked_sub(1)?;
+        Some((self.name(i), addr - self.address(i)))
+    }
+}
+
+/// The embedded table, if it was built from this very kernel.
+pub fn table() -> Option<Table<'static>> {
+    let start = (&raw const __start_ksyms) as usize;
+    let len = (&raw const __stop_ksyms) as usize - start;
+    let table = Table::parse(unsafe { core::slice::from_raw_parts(start as *const u8, len) })?;
+    let current = table.marker() == resolve as *const () as u64 && table.etext() == debug::kernel_text().end;
+    (current && !table.is_empty()).then_some(table)
+}
+
+/// Symbol name (mangled) and offset for a kernel code address.
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    if !debug::kernel_text().contains(&addr) {
+        return None;
+    }
+    table()?.resolve(addr)
+}
+
+/// Displays a Rust symbol name demangled, or as is if it is not one.
+pub struct Demangled<'a>(pub &'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // LLVM appends suffixes like `.llvm.1234` to local copies
+        let name = self.0.find(".llvm.").map_or(self.0, |end| &self.0[..end]);
+        // dry run first, so a name that fails halfway isn't printed half
+        // demangled
+        if let Some(rest) = name.strip_prefix("_R") {
+            if V0::new(rest, &mut Discard).path_top().is_ok() {
+                return V0::new(rest, f).path_top().map_err(|_| fmt::Error);
+            }
+        } else if let Some(rest) = name.strip_prefix("_ZN") {
+            if legacy(rest, &mut Discard).is_ok() {
+                return legacy(rest, f).map_err(|_| fmt::Error);
+            }
+        }
+        f.write_str(self.0)
+    }
+}
+
+/// An unreadable name, or a write error.
+struct Invalid;
+
+impl From<fmt::Error> for Invalid {
+    fn from(_: fmt::Error) -> Invalid {
+        Invalid
+    }
+}
+
+struct Discard;
+
+impl Write for Discard {
+    fn write_str(&mut self, _: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+fn is_hash(segment: &str) -> bool {
+    segment.len() == 17
+        && segment.starts_with('h')
+        && segment[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// `_ZN` names: length-prefixed segments up to `E`, the last one a hash.
+fn legacy(mut rest: &str, out: &mut dyn Write) -> Result<(), Invalid> {
+    let mut first = true;
+    loop {
+        if let Some(tail) = rest.strip_prefix('E') {
+            return if tail.is_empty() { Ok(()) } else { Err(Invalid) };
+        }
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = rest[..digits].parse().map_err(|_| Invalid)?;
+        let segment = rest.get(digits..digits + len).ok_or(Invalid)?;
+        rest = &rest[digits + len..];
+        if rest == "E" && is_hash(segment) {
+            continue;
+        }
+        if !first {
+            out.write_str("::")?;
+        }
+        first = false;
+        legacy_segment(segment, out)?;
+    }
+}
+
+fn legacy_segment(mut segment: &str, out: &mut dyn Write) -> Result<(), Invalid> {
+    if segment.starts_with("_$") {
+        segment = &segment[1..];
+    }
+    while !segment.is_empty() {
+        if let Some(tail) = segment.strip_prefix("..") {
+            out.write_str("::")?;
+            segment = tail;
+        } else if let Some(tail) = segment.strip_prefix('$') {
+            let end = tail.find('$').ok_or(Invalid)?;
+            let decoded = match &tail[..end] {
+                "SP" => '@',
+                "BP" => '*',
+                "RF" => '&',
+                "LT" => '<',
+                "GT" => '>',
+                "LP" => '(',
+                "RP" => ')',
+                "C" => ',',
+                escape => {
+                    let code = escape.strip_prefix('u').ok_or(Invalid)?;
+                    u32::from_str_radix(code, 16).ok().and_then(char::from_u32).ok_or(Invalid)?
+                }
+            };
+            out.write_char(decoded)?;
+            segment = &tail[end + 1..];
+        } else {
+            let end = segment.find(['$', '.']).unwrap_or(segment.len()).max(1);
+            out.write_str(&segment[..end])?;
+            segment = &segment[end..];
+        }
+    }
+    Ok(())
+}
+
+/// Reader for v0 names, the part after `_R`. Backreferences are offsets
+/// into that part.
+struct V0<'s, 'w> {
+    sym: &'s str,
+    pos: usize,
+    out: &'w mut dyn Write,
+    /// Nonzero while reading something that isn't printed.
+    quiet: u32,
+    depth: u32,
+}
+
+impl<'s, 'w> V0<'s, 'w> {
+    fn new(sym: &'s str, out: &'w mut dyn Write) -> V0<'s, 'w> {
+        V0 { sym, pos: 0, out, quiet: 0, depth: 0 }
+    }
+
+    fn print(&mut self, s: &str) -> Result<(), Invalid> {
+        if self.quiet == 0 {
+            self.out.write_str(s)?;
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.sym.as_bytes().get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<u8, Invalid> {
+        let b = self.peek().ok_or(Invalid)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        let found = self.peek() == Some(b);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// `_` is 0; otherwise base-62 digits then `_`, plus one.
+    fn base62(&mut self) -> Result<u64, Invalid> {
+        if self.eat(b'_') {
+            return Ok(0);
+        }
+        let mut value: u64 = 0;
+        loop {
+            let digit = match self.next()? {
+                b'_' => return value.checked_add(1).ok_or(Invalid),
+                b @ b'0'..=b'9' => b - b'0',
+                b @ b'a'..=b'z' => b - b'a' + 10,
+                b @ b'A'..=b'Z' => b - b'A' + 36,
+                _ => return Err(Invalid),
+            };
+            value = value.checked_mul(62).and_then(|v| v.checked_add(u64::from(digit))).ok_or(Invalid)?;
+        }
+    }
+
+    fn disambiguator(&mut self) -> Result<(), Invalid> {
+        if self.eat(b's') {
+            self.base62()?;
+        }
+        Ok(())
+    }
+
+    fn decimal(&mut self) -> Result<usize, Invalid> {
+        let digits = self.sym[self.pos..].bytes().take_while(u8::is_ascii_digit).count();
+        let value = self.sym[self.pos..self.pos + digits].parse().map_err(|_| Invalid)?;
+        self.pos += digits;
+        Ok(value)
+    }
+
+    fn ident(&mut self) -> Result<&'s str, Invalid> {
+        if self.eat(b'u') {
+            // Punycode; the kernel has no such names
+            return Err(Invalid);
+        }
+        let len = self.decimal()?;
+        self.eat(b'_');
+        let ident = self.sym.get(self.pos..self.pos + len).ok_or(Invalid)?;
+        self.pos += len;
+        Ok(ident)
+    }
+
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Invalid>) -> Result<T, Invalid> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(Invalid);
+        }
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn quietly<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Invalid>) -> Result<T, Invalid> {
+        self.quiet += 1;
+        let result = f(self);
+        self.quiet -= 1;
+        result
+    }
+
+    /// Follows a `B` backreference, which must point backwards.
+    fn backref(&mut self, f: impl FnOnce(&mut Self) -> Result<(), Invalid>) -> Result<(), Invalid> {
+        let at = self.pos - 1;
+        let target = usize::try_from(self.base62()?).map_err(|_| Invalid)?;
+        if target >= at {
+            return Err(Invalid);
+        }
+        let resume = core::mem::replace(&mut self.pos, target);
+        let result = self.nested(f);
+        self.pos = resume;
+        result
+    }
+
+    /// The whole name: an optional encoding version, the path, and the
+    /// instantiating crate, which is not printed.
+    fn path_top(&mut self) -> Result<(), Invalid> {
+        if self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.decimal()?;
+        }
+        self.path()?;
+        if self.pos < self.sym.len() {
+            self.quietly(Self::path)?;
+        }
+        if self.pos == self.sym.len() { Ok(()) } else { Err(Invalid) }
+    }
+
+    fn path(&mut self) -> Result<(), Invalid> {
+        self.nested(|p| match p.next()? {
+            b'C' => {
+                p.disambiguator()?;
+                let name = p.ident()?;
+                p.print(name)
+            }
+            b'N' => {
+                let namespace = p.next()?;
+                p.path()?;
+                p.disambiguator()?;
+                let name = p.ident()?;
+                if namespace.is_ascii_uppercase() {
+                    p.print("::{")?;
+                    p.print(match namespace {
+                        b'C' => "closure",
+                        b'S' => "shim",
+                        _ => "?",
+                    })?;
+                    if !name.is_empty() {
+                        p.print(":")?;
+                        p.print(name)?;
+                    }
+                    p.print("}")
+                } else if name.is_empty() {
+                    Ok(())
+                } else {
+                    p.print("::")?;
+                    p.print(name)
+                }
+            }
+            b'M' => {
+                p.disambiguator()?;
+                p.quietly(Self::path)?;
+                p.print("<")?;
+                p.ty()?;
+                p.print(">")
+            }
+            b'X' => {
+                p.disambiguator()?;
+                p.quietly(Self::path)?;
+                p.trait_impl()
+            }
+            b'Y' => p.trait_impl(),
+            b'I' => {
+                p.path()?;
+                p.quietly(|p| {
+                    while !p.eat(b'E') {
+                        p.generic_arg()?;
+                    }
+                    Ok(())
+                })
+            }
+            b'B' => p.backref(Self::path),
+            _ => Err(Invalid),
+        })
+    }
+
+    /// `<Type as Trait>`.
+    fn trait_impl(&mut self) -> Result<(), Invalid> {
+        self.print("<")?;
+        self.ty()?;
+        self.print(" as ")?;
+        self.path()?;
+        self.print(">")
+    }
+
+    fn generic_arg(&mut self) -> Result<(), Invalid> {
+        if self.eat(b'L') {
+            self.base62().map(drop)
+        } else if self.eat(b'K') {
+            self.constant()
+        } else {
+            self.ty()
+        }
+    }
+
+    fn constant(&mut self) -> Result<(), Invalid> {
+        if self.eat(b'p') {
+            return self.print("_");
+        }
+        if self.eat(b'B') {
+            return self.backref(Self::constant);
+        }
+        let ty = self.next()?;
+        basic_type(ty).ok_or(Invalid)?;
+        let negative = self.eat(b'n');
+        let start = self.pos;
+        while self.next()? != b'_' {}
+        let value = u64::from_str_radix(&self.sym[start..self.pos - 1], 16).map_err(|_| Invalid)?;
+        if self.quiet == 0 {
+            match ty {
+                b'b' => self.out.write_str(if value == 0 { "false" } else { "true" })?,
+                b'c' => self.out.write_char(char::from_u32(value as u32).ok_or(Invalid)?)?,
+                _ if negative => write!(self.out, "-{}", value)?,
+                _ => write!(self.out, "{}", value)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn ty(&mut self) -> Result<(), Invalid> {
+        self.nested(|p| {
+            let tag = p.peek().ok_or(Invalid)?;
+            if let Some(name) = basic_type(tag) {
+                p.pos += 1;
+                return p.print(name);
+            }
+            match tag {
+                b'C' | b'N' | b'M' | b'X' | b'Y' | b'I' => return p.path(),
+                _ => p.pos += 1,
+            }
+            match tag {
+                b'R' | b'Q' => {
+                    p.print("&")?;
+                    if p.eat(b'L') {
+                        p.base62()?;
+                    }
+                    if tag == b'Q' {
+                        p.print("mut ")?;
+                    }
+                    p.ty()
+                }
+                b'P' => {
+                    p.print("*const ")?;
+                    p.ty()
+                }
+                b'O' => {
+                    p.print("*mut ")?;
+                    p.ty()
+                }
+                b'A' => {
+                    p.print("[")?;
+                    p.ty()?;
+                    p.print("; ")?;
+                    p.constant()?;
+                    p.print("]")
+                }
+                b'S' => {
+                    p.print("[")?;
+                    p.ty()?;
+                    p.print("]")
+                }
+                b'T' => {
+                    p.print("(")?;
+                    p.list(Self::ty)?;
+                    p.print(")")
+                }
+                b'F' => {
+                    if p.eat(b'G') {
+                        p.base62()?;
+                    }
+                    if p.eat(b'U') {
+                        p.print("unsafe ")?;
+                    }
+                    if p.eat(b'K') && !p.eat(b'C') {
+                        p.ident()?;
+                    }
+                    p.print("fn(")?;
+                    p.list(Self::ty)?;
+                    p.print(") -> ")?;
+                    p.ty()
+                }
+                b'D' => {
+                    if p.eat(b'G') {
+                        p.base62()?;
+                    }
+                    p.print("dyn ")?;
+                    p.list(|p| {
+                        p.path()?;
+                        while p.eat(b'p') {
+                            p.ident()?;
+                            p.quietly(Self::ty)?;
+                        }
+                        Ok(())
+                    })?;
+                    // the object lifetime
+                    if !p.eat(b'L') {
+                        return Err(Invalid);
+                    }
+                    p.base62().map(drop)
+                }
+                b'B' => p.backref(Self::ty),
+                _ => Err(Invalid),
+            }
+        })
+    }
+
+    /// Items up to `E`, separated by commas.
+    fn list(&mut self, mut item: impl FnMut(&mut Self) -> Result<(), Invalid>) -> Result<(), Invalid> {
+        let mut first = true;
+        while !self.eat(b'E') {
+            if !first {
+                self.print(", ")?;
+            }
+            first = false;
+            item(self)?;
+        }
+        Ok(())
+    }
+}
+
+fn basic_type(tag: u8) -> Option<&'static str> {
+    Some(match tag {
+        b'a' => "i8",
+        b'b' => "bool",
+        b'c' => "char",
+        b'd' => "f64",
+        b'e' => "str",
+        b'f' => "f32",
+        b'h' => "u8",
+        b'i' => "isize",
+        b'j' => "usize",
+        b'l' => "i32",
+        b'm' => "u32",
+        b'n' => "i128",
+        b'o' => "u128",
+        b's' => "i16",
+        b't' => "u16",
+        b'u' => "()",
+        b'v' => "...",
+        b'x' => "i64",
+        b'y' => "u64",
+        b'z' => "!",
+        b'p' => "_",
+        _ => return None,
+    })
+}
+
+//test case
+#[cfg(test)]
+fn fixture(symbols: &[(u64, &str)]) -> alloc::vec::Vec<u8> {
+    let mut table = MAGIC.to_vec();
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&0x1234u64.to_le_bytes());
+    table.extend_from_slice(&0x9000u64.to_le_bytes());
+    let mut names = alloc::vec::Vec::new();
+    for (address, name) in symbols {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+    table
+}
+
+#[test_case]
+fn test_resolve_fixture_table() {
+    let bytes = fixture(&[(0x1000, "start"), (0x1040, "translate"), (0x2000, "last")]);
+    let table = Table::parse(&bytes).unwrap();
+    assert_eq!((table.len(), table.marker(), table.etext()), (3, 0x1234, 0x9000));
+    assert_eq!(table.resolve(0xfff), None);
+    assert_eq!(table.resolve(0x1000), Some(("start", 0)));
+    assert_eq!(table.resolve(0x103f), Some(("start", 0x3f)));
+    assert_eq!(table.resolve(0x108f), Some(("translate", 0x4f)));
+    assert_eq!(table.resolve(0x2000), Some(("last", 0)));
+    assert_eq!(table.resolve(u64::MAX), Some(("last", u64::MAX - 0x2000)));
+}
+
+#[test_case]
+fn test_empty_and_malformed_tables() {
+    // what build.rs writes before the first kernel is linked
+    let empty = fixture(&[]);
+    let table = Table::parse(&empty).unwrap();
+    assert!(table.is_empty());
+    assert_eq!(table.resolve(0x1000), None);
+
+    let mut bytes = fixture(&[(0x1000, "a"), (0x2000, "b")]);
+    assert!(Table::parse(&bytes[..bytes.len() - 1]).is_none(), "name past the end");
+    assert!(Table::parse(&bytes[..HEADER_LEN + ENTRY_LEN]).is_none(), "entries cut short");
+    let unsorted = fixture(&[(0x2000, "a"), (0x1000, "b")]);
+    assert!(Table::parse(&unsorted).is_none());
+    bytes[0] = b'X';
+    assert!(Table::parse(&bytes).is_none());
+}
+
+#[test_case]
+fn test_embedded_table_is_current_or_ignored() {
+    // a test binary carries the main kernel's table, which doesn't match it
+    if let Some(table) = table() {
+        let (name, offset) = resolve(resolve as *const () as u64).unwrap();
+        assert_eq!(offset, 0);
+        assert!(name.contains("7symbols7resolve"), "{}", name);
+        assert!(!table.is_empty());
+    } else {
+        assert_eq!(resolve(resolve as *const () as u64), None);
+    }
+}
+
+#[test_case]
+fn test_demangle_v0() {
+    let demangle = |name| alloc::format!("{}", Demangled(name));
+    assert_eq!(
+        demangle("_RNvNtCs9KovEoJgkhb_11tutorial_os7syscall13syscall_entry"),
+        "tutorial_os::syscall::syscall_entry"
+    );
+    assert_eq!(
+        demangle("_RNvMs_NtCs9KovEoJgkhb_11tutorial_os5shellNtB4_5Shell7execute"),
+        "<tutorial_os::shell::Shell>::execute"
+    );
+    // generic arguments and the instantiating crate are dropped
+    assert_eq!(
+        demangle("_RINvMNtCs9ldYtUfDSBz_4core3stre5parsehECs9KovEoJgkhb_11tutorial_os"),
+        "<str>::parse"
+    );
+    assert_eq!(
+        demangle("_RNvYyNtNtCs9ldYtUfDSBz_4core3cmp3Ord3minCs9KovEoJgkhb_11tutorial_os"),
+        "<u64 as core::cmp::Ord>::min"
+    );
+    assert_eq!(
+        demangle("_RNCNvNtCs9KovEoJgkhb_11tutorial_os6memory4init0B5_.llvm.42"),
+        "tutorial_os::memory::init::{closure}"
+    );
+    assert_eq!(
+        demangle("_RNvXs0_NtCs1_3foo3barRShNtNtCs2_4core3fmt5Debug3fmt"),
+        "<&[u8] as core::fmt::Debug>::fmt"
+    );
+}
+
+#[test_case]
+fn test_demangle_legacy_and_fallback() {
+    let demangle = |name| alloc::format!("{}", Demangled(name));
+    assert_eq!(
+        demangle("_ZN11tutorial_os6memory20translate_addr_inner17h0123456789abcdefE"),
+        "tutorial_os::memory::translate_addr_inner"
+    );
+    assert_eq!(
+        demangle("_ZN4core3ptr42drop_in_place$LT$alloc..string..String$GT$17hfedcba9876543210E"),
+        "core::ptr::drop_in_place<alloc::string::String>"
+    );
+    assert_eq!(demangle("memcpy"), "memcpy");
+    assert_eq!(demangle("_ZN3foo"), "_ZN3foo");
+    assert_eq!(demangle("_RNvCs_3foo99bar"), "_RNvCs_3foo99bar");
+    // a backreference to itself
+    assert_eq!(demangle("_RB_"), "_RB_");
+}
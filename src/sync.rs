@@ -0,0 +1,972 @@
+//! Blocking on a condition until someone says it may have changed.
+//!
+//! `WaitQueue::wait_until` checks the condition and, while it is false,
+//! parks the caller; `notify_one` and `notify_all` wake parked waiters,
+//! which then check again. A notify may come from an interrupt handler.
+//! There are three kinds of waiter behind the one call:
+//!
+//! - Kernel code on a scheduler thread is taken off the CPU as `Blocked`
+//!   and made ready by the notify. Before any thread is spawned, or while
+//!   a process owns the CPU, it halts where it stands instead.
+//! - A process parks from a system call: it is taken off the CPU as
+//!   `Blocked` and, once notified, makes the same call again.
+//! - An async task leaves its waker and gets `Wait::Pending`; the executor
+//!   polls it again once a notify wakes it. `WaitQueue::wait` is the
+//!   future that does this.
+//!
+//! The check runs with interrupts disabled, and every notify starts a new
+//! generation; a waiter only parks while the generation it checked under
+//! is current, so a notify between the check and the park is never lost.
+//! A queue parks at most `MAX_PARKED` waiters, in place, so a notify from
+//! an interrupt handler never touches the heap; past that a waiter checks
+//! again without parking: a thread halts for the next interrupt, a task is
+//! polled again straight away and a process makes its call again after the
+//! others have had a turn.
+//!
+//! A `Ring` is how input gets from an interrupt handler to the task that
+//! reads it, without either side taking a lock; `ByteQueue` is the one
+//! for bytes.
+//!
+//! Data an interrupt handler shares with other code goes behind an
+//! `IrqMutex` or a `RwLock`: both keep interrupts off while held, so the
+//! handler can't spin on a lock the code it interrupted holds. With lock
+//! debugging on (`set_lock_debug`, the default in debug builds) they also
+//! remember who took them: taking one again on the CPU that holds it
+//! panics with both places, and one that spins for
+//! `CONTENTION_TIMEOUT_MS` prints its holder to the serial port.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::future::Future;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+use crate::interrupts::ExceptionFrame;
+use crate::process::{self, Pid};
+use crate::scheduler::{self, ThreadId};
+use crate::{serial, time};
+
+/// Who is waiting.
+pub enum Waiter<'a> {
+    /// Kernel code that may block: the running thread, or the CPU itself.
+    Kernel,
+    /// The running process, in a system call with this frame.
+    Process(&'a mut ExceptionFrame),
+    /// An async task, woken through this waker.
+    Task(&'a Waker),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub enum Wait {
+    /// The condition holds.
+    Done,
+    /// The process is parked and `frame` belongs to another one; the system
+    /// call must return without touching it.
+    Parked,
+    /// A process waiter, but no process is running.
+    NoProcess,
+    /// The task's waker is queued; poll again once it is woken.
+    Pending,
+}
+
+enum Parked {
+    Thread(ThreadId),
+    Process(Pid),
+    Task(Waker),
+}
+
+impl Parked {
+    fn wake(self) {
+        match self {
+            Parked::Thread(id) => scheduler::unblock(id),
+            Parked::Process(pid) => process::unblock(pid),
+            Parked::Task(waker) => waker.wake(),
+        }
+    }
+}
+
+/// Most waiters one `WaitQueue` parks at a time.
+pub const MAX_PARKED: usize = 16;
+
+/// Parked waiters, longest parked first, in a fixed array.
+struct ParkedList {
+    slots: [Option<Parked>; MAX_PARKED],
+    len: usize,
+}
+
+impl ParkedList {
+    const fn new() -> Self {
+        ParkedList { slots: [const { None }; MAX_PARKED], len: 0 }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    #[cfg(test)]
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gives `parked` back if the list is full.
+    fn push_back(&mut self, parked: Parked) -> Result<(), Parked> {
+        if self.len == MAX_PARKED {
+            return Err(parked);
+        }
+        self.slots[self.len] = Some(parked);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop_front(&mut self) -> Option<Parked> {
+        if self.len == 0 {
+            return None;
+        }
+        let first = self.slots[0].take();
+        self.slots[..self.len].rotate_left(1);
+        self.len -= 1;
+        first
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&Parked) -> bool) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            match self.slots[i].take() {
+                Some(parked) if keep(&parked) => {
+                    self.slots[kept] = Some(parked);
+                    kept += 1;
+                }
+                _ => {}
+            }
+        }
+        self.len = kept;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Parked> {
+        self.slots[..self.len].iter().flatten()
+    }
+
+    /// Every parked waiter, leaving the list empty.
+    fn take(&mut self) -> impl Iterator<Item = Parked> {
+        self.len = 0;
+        core::mem::replace(&mut self.slots, [const { None }; MAX_PARKED]).into_iter().flatten()
+    }
+}
+
+struct Waiters {
+    generation: u64,
+    parked: ParkedList,
+}
+
+pub struct WaitQueue {
+    waiters: Mutex<Waiters>,
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiters: Mutex::new(Waiters { generation: 0, parked: ParkedList::new() }) }
+    }
+
+    /// Returns once `condition` holds, parking `waiter` in between.
+    pub fn wait_until(&self, waiter: Waiter, condition: impl FnMut() -> bool) -> Wait {
+        match waiter {
+            Waiter::Kernel => {
+                self.block_until(condition);
+                Wait::Done
+            }
+            Waiter::Process(frame) => self.park(frame, condition),
+            Waiter::Task(waker) => self.register(waker, condition),
+        }
+    }
+
+    /// Ready once `condition` holds, for async tasks.
+    pub fn wait<F: FnMut() -> bool + Unpin>(&self, condition: F) -> WaitFor<'_, F> {
+        WaitFor { queue: self, condition }
+    }
+
+    fn generation(&self) -> u64 {
+        interrupts::without_interrupts(|| self.waiters.lock().generation)
+    }
+
+    fn block_until(&self, mut condition: impl FnMut() -> bool) {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        loop {
+            let generation = self.generation();
+            if condition() {
+                break;
+            }
+            before_park();
+            // `enable_and_hlt` takes a pending interrupt only after the
+            // halt, so one that notifies cannot slip in before it
+            while self.generation() == generation {
+                PARKS.fetch_add(1, Ordering::Relaxed);
+                if !self.block_thread(generation) {
+                    interrupts::enable_and_hlt();
+                    interrupts::disable();
+                }
+            }
+        }
+        if were_enabled {
+            interrupts::enable();
+        }
+    }
+
+    /// Blocks the running thread until a notify after `generation`.
+    /// `false` if there is no thread to block, or no room to park it.
+    fn block_thread(&self, generation: u64) -> bool {
+        let Some(thread) = scheduler::parkable() else { return false };
+        {
+            let mut waiters = self.waiters.lock();
+            if waiters.generation != generation {
+                return true;
+            }
+            if waiters.parked.push_back(Parked::Thread(thread)).is_err() {
+                return false;
+            }
+        }
+        scheduler::block();
+        self.waiters.lock().parked.retain(|parked| !matches!(parked, Parked::Thread(id) if *id == thread));
+        true
+    }
+
+    fn register(&self, waker: &Waker, mut condition: impl FnMut() -> bool) -> Wait {
+        loop {
+            let generation = self.generation();
+            if interrupts::without_interrupts(&mut condition) {
+                return Wait::Done;
+            }
+            before_park();
+            let queued = interrupts::without_interrupts(|| {
+                let mut waiters = self.waiters.lock();
+                if waiters.generation != generation {
+                    return false;
+                }
+                let known = waiters.parked.iter().any(|parked| matches!(parked, Parked::Task(other) if other.will_wake(waker)));
+                if !known && waiters.parked.push_back(Parked::Task(waker.clone())).is_err() {
+                    // no room: poll again rather than never be woken
+                    waker.wake_by_ref();
+                }
+                true
+            });
+            if queued {
+                return Wait::Pending;
+            }
+        }
+    }
+
+    /// System calls run with interrupts disabled, so nobody can notify
+    /// between the check and the process leaving the CPU.
+    fn park(&self, frame: &mut ExceptionFrame, mut condition: impl FnMut() -> bool) -> Wait {
+        if condition() {
+            return Wait::Done;
+        }
+        let Some(pid) = process::current() else {
+            return Wait::NoProcess;
+        };
+        before_park();
+        if self.waiters.lock().parked.push_back(Parked::Process(pid)).is_err() {
+            return if process::retry(frame) { Wait::Parked } else { Wait::NoProcess };
+        }
+        if process::block(frame) {
+            Wait::Parked
+        } else {
+            self.waiters.lock().parked.retain(|parked| !matches!(parked, Parked::Process(waiting) if *waiting == pid));
+            Wait::NoProcess
+        }
+    }
+
+    /// Wakes the longest-parked thread, process or task, and any halted
+    /// kernel code.
+    pub fn notify_one(&self) {
+        let woken = interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            waiters.generation += 1;
+            waiters.parked.pop_front()
+        });
+        if let Some(parked) = woken {
+            parked.wake();
+        }
+    }
+
+    pub fn notify_all(&self) {
+        let woken = interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            waiters.generation += 1;
+            waiters.parked.take()
+        });
+        woken.for_each(Parked::wake);
+    }
+}
+
+/// The future `WaitQueue::wait` returns.
+pub struct WaitFor<'a, F> {
+    queue: &'a WaitQueue,
+    condition: F,
+}
+
+impl<F: FnMut() -> bool + Unpin> Future for WaitFor<'_, F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        match this.queue.wait_until(Waiter::Task(cx.waker()), &mut this.condition) {
+            Wait::Done => Poll::Ready(()),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+/// Bytes `ByteQueue` holds before it drops; a key press and release on an
+/// extended key take four scancodes.
+pub const QUEUE_LEN: usize = 128;
+
+/// Bytes an interrupt handler received, waiting for a task.
+pub type ByteQueue = Ring<u8, QUEUE_LEN>;
+
+/// Up to `N` values an interrupt handler produced, waiting for a task.
+/// There is one producer, the handler, and one consumer, so each side
+/// moves its own index and neither ever waits for the other; when the
+/// ring is full the new value is dropped and counted. A second producer
+/// or consumer arriving while one is busy is turned away the same way,
+/// rather than racing it for a slot.
+pub struct Ring<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Next slot to read; only the consumer moves it.
+    head: AtomicUsize,
+    /// Next slot to write; only the producer moves it.
+    tail: AtomicUsize,
+    pushing: AtomicBool,
+    popping: AtomicBool,
+    dropped: AtomicU64,
+}
+
+// a slot is only touched by the side that owns it at the time
+unsafe impl<T: Send, const N: usize> Sync for Ring<T, N> {}
+
+impl<T: Copy, const N: usize> Default for Ring<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy, const N: usize> Ring<T, N> {
+    pub const fn new() -> Self {
+        Ring {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            pushing: AtomicBool::new(false),
+            popping: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queues `value`; `false`, and counted, if the ring is full.
+    pub fn push(&self, value: T) -> bool {
+        if self.pushing.swap(true, Ordering::Acquire) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let tail = self.tail.load(Ordering::Relaxed);
+        let full = tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N;
+        if full {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        } else {
+            unsafe { (*self.slots[tail % N].get()).write(value) };
+            self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.pushing.store(false, Ordering::Release);
+        !full
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        if self.popping.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        let head = self.head.load(Ordering::Relaxed);
+        let value = (head != self.tail.load(Ordering::Acquire)).then(|| {
+            let value = unsafe { (*self.slots[head % N].get()).assume_init() };
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+            value
+        });
+        self.popping.store(false, Ordering::Release);
+        value
+    }
+
+    /// Values dropped because the ring was full or busy.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// ==========================================================
+// Locks
+// ==========================================================
+
+/// How long a lock may spin before `report_contention` names its holder.
+pub const CONTENTION_TIMEOUT_MS: u64 = 1000;
+
+/// Whether the locks below keep track of their holder. On in debug
+/// builds.
+static LOCK_DEBUG: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+/// Contention reports printed since boot.
+static CONTENTION_REPORTS: AtomicU64 = AtomicU64::new(0);
+
+/// Turns holder tracking on or off: with it, taking a lock the same CPU
+/// already holds panics instead of spinning forever, and one that spins
+/// for `CONTENTION_TIMEOUT_MS` prints who holds it to the serial port.
+pub fn set_lock_debug(on: bool) {
+    LOCK_DEBUG.store(on, Ordering::Relaxed);
+}
+
+pub fn lock_debug() -> bool {
+    LOCK_DEBUG.load(Ordering::Relaxed)
+}
+
+const NO_CPU: usize = usize::MAX;
+
+/// Who holds a lock (for `RwLock`, who holds it for writing), while lock
+/// debugging is on.
+struct Holder {
+    cpu: AtomicUsize,
+    location: AtomicPtr<Location<'static>>,
+}
+
+impl Holder {
+    const fn new() -> Self {
+        Holder { cpu: AtomicUsize::new(NO_CPU), location: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    fn set(&self, location: &'static Location<'static>) {
+        if lock_debug() {
+            self.location.store(location as *const Location as *mut Location, Ordering::Relaxed);
+            self.cpu.store(crate::percpu::try_index().unwrap_or(0), Ordering::Release);
+        }
+    }
+
+    fn clear(&self) {
+        self.cpu.store(NO_CPU, Ordering::Release);
+    }
+
+    fn get(&self) -> Option<(usize, Option<&'static Location<'static>>)> {
+        match self.cpu.load(Ordering::Acquire) {
+            NO_CPU => None,
+            cpu => Some((cpu, unsafe { self.location.load(Ordering::Relaxed).as_ref() })),
+        }
+    }
+}
+
+/// With interrupts off, the lock being held on this very CPU means the
+/// holder is below us on the stack and will never let go.
+fn reentered(holder: Option<usize>, cpu: Option<usize>) -> bool {
+    holder.is_some() && holder == cpu
+}
+
+/// Spins on `attempt` until it takes the lock. Interrupts are off.
+#[track_caller]
+fn contend<G>(holder: &Holder, kind: &str, mut attempt: impl FnMut() -> Option<G>) -> G {
+    let caller = Location::caller();
+    let cpu = crate::percpu::try_index();
+    if lock_debug() {
+        let held = holder.get();
+        if reentered(held.map(|(cpu, _)| cpu), cpu) {
+            let taken = held.and_then(|(_, location)| location);
+            panic!("{} taken again on CPU {} at {}; it was taken at {}", kind, cpu.unwrap_or(0), caller, Where(taken));
+        }
+    }
+    let timeout = time::tsc_frequency().unwrap_or(1_000_000_000) / 1000 * CONTENTION_TIMEOUT_MS;
+    let start = time::rdtsc();
+    let mut reported = false;
+    loop {
+        if let Some(guard) = attempt() {
+            return guard;
+        }
+        core::hint::spin_loop();
+        if !reported && lock_debug() && time::rdtsc().wrapping_sub(start) >= timeout {
+            reported = true;
+            report_contention(&mut serial::RawSerial, kind, cpu, caller, holder.get());
+        }
+    }
+}
+
+/// A `#[track_caller]` location, or `?` for a holder taken before lock
+/// debugging was on.
+struct Where(Option<&'static Location<'static>>);
+
+impl fmt::Display for Where {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(location) => write!(f, "{}", location),
+            None => write!(f, "?"),
+        }
+    }
+}
+
+/// The line a lock that spun too long prints. Written straight to the
+/// port: the holder may be holding the serial lock.
+fn report_contention(
+    out: &mut impl fmt::Write,
+    kind: &str,
+    cpu: Option<usize>,
+    caller: &Location,
+    holder: Option<(usize, Option<&'static Location<'static>>)>,
+) {
+    CONTENTION_REPORTS.fetch_add(1, Ordering::Relaxed);
+    let _ = write!(out, "sync: {} at {} spun {} ms on CPU {}", kind, caller, CONTENTION_TIMEOUT_MS, cpu.unwrap_or(0));
+    let _ = match holder {
+        Some((cpu, location)) => writeln!(out, "; held by CPU {} since {}", cpu, Where(location)),
+        None => writeln!(out, "; holder unknown"),
+    };
+}
+
+/// Turns interrupts back on when dropped, if they were on when it was
+/// made.
+struct IrqState {
+    were_enabled: bool,
+}
+
+impl IrqState {
+    fn disable() -> Self {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqState { were_enabled }
+    }
+}
+
+impl Drop for IrqState {
+    fn drop(&mut self) {
+        if self.were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// A spinlock that keeps interrupts off while held, so a handler on the
+/// same CPU can never find it taken by the code it interrupted.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+    holder: Holder,
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    // dropped in this order: the lock first, then interrupts back on
+    guard: MutexGuard<'a, T>,
+    holder: &'a Holder,
+    _irq: IrqState,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex { inner: Mutex::new(value), holder: Holder::new() }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let irq = IrqState::disable();
+        let guard = match self.inner.try_lock() {
+            Some(guard) => guard,
+            None => contend(&self.holder, "IrqMutex", || self.inner.try_lock()),
+        };
+        self.holder.set(Location::caller());
+        IrqMutexGuard { guard, holder: &self.holder, _irq: irq }
+    }
+
+    /// The guard if nobody holds the lock, without spinning.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let irq = IrqState::disable();
+        let guard = self.inner.try_lock()?;
+        self.holder.set(Location::caller());
+        Some(IrqMutexGuard { guard, holder: &self.holder, _irq: irq })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.holder.clear();
+    }
+}
+
+/// Any number of readers or one writer, with interrupts off while either
+/// holds it. Nothing puts a waiting writer ahead of new readers, so it is
+/// for data that is read far more often than written.
+pub struct RwLock<T> {
+    inner: spin::RwLock<T>,
+    writer: Holder,
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    guard: spin::RwLockReadGuard<'a, T>,
+    _irq: IrqState,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    guard: spin::RwLockWriteGuard<'a, T>,
+    writer: &'a Holder,
+    _irq: IrqState,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock { inner: spin::RwLock::new(value), writer: Holder::new() }
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let irq = IrqState::disable();
+        let guard = match self.inner.try_read() {
+            Some(guard) => guard,
+            None => contend(&self.writer, "RwLock read", || self.inner.try_read()),
+        };
+        RwLockReadGuard { guard, _irq: irq }
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let irq = IrqState::disable();
+        let guard = match self.inner.try_write() {
+            Some(guard) => guard,
+            None => contend(&self.writer, "RwLock write", || self.inner.try_write()),
+        };
+        self.writer.set(Location::caller());
+        RwLockWriteGuard { guard, writer: &self.writer, _irq: irq }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let irq = IrqState::disable();
+        Some(RwLockReadGuard { guard: self.inner.try_read()?, _irq: irq })
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let irq = IrqState::disable();
+        let guard = self.inner.try_write()?;
+        self.writer.set(Location::caller());
+        Some(RwLockWriteGuard { guard, writer: &self.writer, _irq: irq })
+    }
+
+    /// Readers holding it now.
+    pub fn readers(&self) -> usize {
+        self.inner.reader_count()
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.writer.clear();
+    }
+}
+
+/// Times kernel waiters have halted or blocked, for the tests.
+static PARKS: AtomicU64 = AtomicU64::new(0);
+static BEFORE_PARK_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs `hook` after a waiter's condition failed and right before it
+/// parks, the spot a notify is easiest to lose. Meant for tests.
+pub fn set_before_park_hook(hook: Option<fn()>) {
+    BEFORE_PARK_HOOK.store(hook.map_or(0, |hook| hook as usize), Ordering::Release);
+}
+
+fn before_park() {
+    let hook = BEFORE_PARK_HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        let hook: fn() = unsafe { core::mem::transmute(hook) };
+        hook();
+    }
+}
+
+//test case
+#[cfg(test)]
+static QUEUE: WaitQueue = WaitQueue::new();
+#[cfg(test)]
+static ITEM: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn test_handoff_from_an_interrupt() {
+    // the producer is the timer interrupt, the consumer halts for it
+    fn produce() {
+        if ITEM.compare_exchange(0, 42, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            QUEUE.notify_one();
+        }
+    }
+    ITEM.store(0, Ordering::Relaxed);
+    let parks = PARKS.load(Ordering::Relaxed);
+    crate::interrupts::set_tick_hook(produce);
+    assert_eq!(QUEUE.wait_until(Waiter::Kernel, || ITEM.load(Ordering::Acquire) != 0), Wait::Done);
+    crate::interrupts::set_tick_hook(|| {});
+    assert_eq!(ITEM.load(Ordering::Relaxed), 42);
+    assert!(PARKS.load(Ordering::Relaxed) > parks);
+}
+
+#[test_case]
+fn test_notify_right_before_the_park() {
+    // the worst moment: the item and the notify arrive after the check
+    // failed but before the waiter halts
+    fn produce() {
+        ITEM.store(7, Ordering::Release);
+        QUEUE.notify_all();
+    }
+    ITEM.store(0, Ordering::Relaxed);
+    let parks = PARKS.load(Ordering::Relaxed);
+    set_before_park_hook(Some(produce));
+    let mut checks = 0;
+    let result = QUEUE.wait_until(Waiter::Kernel, || {
+        checks += 1;
+        ITEM.load(Ordering::Acquire) != 0
+    });
+    set_before_park_hook(None);
+    assert_eq!(result, Wait::Done);
+    // seen on the second check, without ever halting
+    assert_eq!(checks, 2);
+    assert_eq!(PARKS.load(Ordering::Relaxed), parks);
+}
+
+#[test_case]
+fn test_wait_for_what_already_holds() {
+    let parks = PARKS.load(Ordering::Relaxed);
+    assert_eq!(QUEUE.wait_until(Waiter::Kernel, || true), Wait::Done);
+    assert_eq!(PARKS.load(Ordering::Relaxed), parks);
+    // nobody parked: notifying is harmless
+    QUEUE.notify_one();
+    QUEUE.notify_all();
+}
+
+#[cfg(test)]
+struct TestWaker(AtomicU64);
+
+#[cfg(test)]
+impl alloc::task::Wake for TestWaker {
+    fn wake(self: alloc::sync::Arc<Self>) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_task_waiter() {
+    use alloc::sync::Arc;
+
+    let queue = WaitQueue::new();
+    let wakes = Arc::new(TestWaker(AtomicU64::new(0)));
+    let waker = Waker::from(wakes.clone());
+    let mut cx = Context::from_waker(&waker);
+    let ready = AtomicBool::new(false);
+    let mut future = queue.wait(|| ready.load(Ordering::Acquire));
+    assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+    // polled again before any notify, the waker is queued once
+    assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+    assert_eq!(queue.waiters.lock().parked.len(), 1);
+    ready.store(true, Ordering::Release);
+    queue.notify_one();
+    assert_eq!(wakes.0.load(Ordering::Relaxed), 1);
+    assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Ready(()));
+    assert!(queue.waiters.lock().parked.is_empty());
+}
+
+#[test_case]
+fn test_task_past_a_full_queue() {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    let queue = WaitQueue::new();
+    let wakes: Vec<_> = (0..=MAX_PARKED).map(|_| Arc::new(TestWaker(AtomicU64::new(0)))).collect();
+    for wake in &wakes {
+        let waker = Waker::from(wake.clone());
+        assert_eq!(queue.wait_until(Waiter::Task(&waker), || false), Wait::Pending);
+    }
+    assert_eq!(queue.waiters.lock().parked.len(), MAX_PARKED);
+    // the one that found no room was woken to poll again
+    assert_eq!(wakes[MAX_PARKED].0.load(Ordering::Relaxed), 1);
+    queue.notify_all();
+    assert!(wakes.iter().all(|wake| wake.0.load(Ordering::Relaxed) == 1));
+    assert!(queue.waiters.lock().parked.is_empty());
+}
+
+#[test_case]
+fn test_task_notified_right_before_it_parks() {
+    use alloc::sync::Arc;
+
+    fn produce() {
+        ITEM.store(3, Ordering::Release);
+        QUEUE.notify_all();
+    }
+    ITEM.store(0, Ordering::Relaxed);
+    let wakes = Arc::new(TestWaker(AtomicU64::new(0)));
+    let waker = Waker::from(wakes.clone());
+    set_before_park_hook(Some(produce));
+    let mut checks = 0;
+    let result = QUEUE.wait_until(Waiter::Task(&waker), || {
+        checks += 1;
+        ITEM.load(Ordering::Acquire) != 0
+    });
+    set_before_park_hook(None);
+    // the waker wasn't left behind: the second check saw the item
+    assert_eq!((result, checks), (Wait::Done, 2));
+    assert_eq!(wakes.0.load(Ordering::Relaxed), 0);
+    assert!(QUEUE.waiters.lock().parked.is_empty());
+}
+
+#[test_case]
+fn test_byte_queue_order() {
+    let queue = ByteQueue::new();
+    assert_eq!(queue.pop(), None);
+    // go round the ring a few times
+    for round in 0..3 * QUEUE_LEN / 5 {
+        for i in 0..5 {
+            assert!(queue.push((round * 5 + i) as u8));
+        }
+        for i in 0..5 {
+            assert_eq!(queue.pop(), Some((round * 5 + i) as u8));
+        }
+    }
+    assert_eq!(queue.pop(), None);
+    assert_eq!(queue.dropped(), 0);
+}
+
+#[test_case]
+fn test_full_byte_queue_drops() {
+    let queue = ByteQueue::new();
+    for i in 0..QUEUE_LEN {
+        assert!(queue.push(i as u8));
+    }
+    assert!(!queue.push(0xaa));
+    assert!(!queue.push(0xbb));
+    assert_eq!(queue.dropped(), 2);
+    assert_eq!(queue.pop(), Some(0));
+    // room for one more, which goes in at the end
+    assert!(queue.push(0xcc));
+    for i in 1..QUEUE_LEN {
+        assert_eq!(queue.pop(), Some(i as u8));
+    }
+    assert_eq!(queue.pop(), Some(0xcc));
+    assert_eq!(queue.pop(), None);
+    assert_eq!(queue.dropped(), 2);
+}
+
+#[test_case]
+fn test_ring_of_values() {
+    let ring: Ring<(u8, u64), 4> = Ring::new();
+    for i in 0..4 {
+        assert!(ring.push((i, u64::from(i) << 40)));
+    }
+    assert!(!ring.push((9, 9)));
+    assert_eq!(ring.pop(), Some((0, 0)));
+    assert!(ring.push((4, 4 << 40)));
+    let rest: alloc::vec::Vec<_> = core::iter::from_fn(|| ring.pop()).collect();
+    assert_eq!(rest, [(1, 1 << 40), (2, 2 << 40), (3, 3 << 40), (4, 4 << 40)]);
+    assert_eq!(ring.dropped(), 1);
+}
+
+#[test_case]
+fn test_irq_mutex_keeps_interrupts_off() {
+    let lock = IrqMutex::new(5);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.is_locked() && lock.try_lock().is_none());
+        if lock_debug() {
+            assert_eq!(lock.holder.get().map(|(cpu, _)| cpu), crate::percpu::try_index());
+        }
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(lock.holder.get(), None);
+    assert_eq!(*lock.try_lock().unwrap(), 6);
+    // taken with interrupts already off, it leaves them off
+    interrupts::without_interrupts(|| {
+        drop(lock.lock());
+        assert!(!interrupts::are_enabled());
+    });
+}
+
+#[test_case]
+fn test_rw_lock() {
+    let lock = RwLock::new(alloc::vec![1, 2]);
+    {
+        let (first, second) = (lock.read(), lock.read());
+        assert_eq!((first.len(), second[1], lock.readers()), (2, 2, 2));
+        assert!(lock.try_write().is_none());
+        assert!(!interrupts::are_enabled());
+    }
+    lock.write().push(3);
+    {
+        let _writer = lock.write();
+        assert!(lock.try_read().is_none() && lock.try_write().is_none());
+    }
+    assert_eq!(*lock.read(), [1, 2, 3]);
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_lock_debugging() {
+    // what makes `contend` panic rather than spin
+    assert!(reentered(Some(0), Some(0)));
+    assert!(!reentered(Some(1), Some(0)));
+    assert!(!reentered(None, Some(0)));
+    assert!(!reentered(None, None));
+
+    let (caller, held) = (Location::caller(), Location::caller());
+    let reports = CONTENTION_REPORTS.load(Ordering::Relaxed);
+    let mut line = alloc::string::String::new();
+    report_contention(&mut line, "IrqMutex", Some(1), caller, Some((0, Some(held))));
+    assert_eq!(line, alloc::format!("sync: IrqMutex at {} spun {} ms on CPU 1; held by CPU 0 since {}\n", caller, CONTENTION_TIMEOUT_MS, held));
+    line.clear();
+    report_contention(&mut line, "RwLock read", None, caller, None);
+    assert!(line.ends_with("on CPU 0; holder unknown\n"), "{}", line);
+    assert_eq!(CONTENTION_REPORTS.load(Ordering::Relaxed), reports + 2);
+}
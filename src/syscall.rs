@@ -0,0 +1,382 @@
+//! System calls, made from ring 3 with `syscall` or `int 0x80`: the number
+//! goes in RAX, the arguments in RDI, RSI and RDX, and the result comes
+//! back in RAX. `syscall` also clobbers RCX and R11, as on Linux. Numbers
+//! and errors follow Linux, with errors returned as `-errno`.
+//!
+//! Both paths end up in `handler` with the same `ExceptionFrame`:
+//! `syscall_entry` builds the frame `int 0x80` would have pushed, on the
+//! kernel stack it finds in the per-CPU block, and returns with `sysretq`
+//! unless the handler switched to another process.
+//!
+//! The calls are `read` (stdin only, without blocking), `write` (stdout
+//! and stderr), `nanosleep`, `fork`, `exit` and `wait4`, plus `spawn(path, len)`,
+//! which has no Linux counterpart: it starts the ELF at `path` in the VFS
+//! as a new process and returns its pid. Every pointer a program passes
+//! is checked against its own mapped user pages first, see
+//! `usermode::user_slice`. `usermode::usys` assembles programs that make
+//! these calls.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+use crate::cpu::{self, IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR};
+use crate::interrupts::registers::call_handler;
+use crate::interrupts::ExceptionFrame;
+use crate::percpu::{self, PerCpu};
+use crate::process::State;
+use crate::sync::{ByteQueue, Wait, Waiter};
+use crate::vfs::VfsError;
+use crate::{gdt, print, process, time, usermode};
+
+pub const VECTOR: u8 = 0x80;
+
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_NANOSLEEP: u64 = 35;
+pub const SYS_FORK: u64 = 57;
+pub const SYS_EXIT: u64 = 60;
+pub const SYS_WAIT4: u64 = 61;
+/// Past every Linux number.
+pub const SYS_SPAWN: u64 = 0x1000;
+
+pub const ENOENT: u64 = 2;
+pub const EIO: u64 = 5;
+pub const ENOEXEC: u64 = 8;
+pub const EBADF: u64 = 9;
+pub const ECHILD: u64 = 10;
+pub const EAGAIN: u64 = 11;
+pub const ENOMEM: u64 = 12;
+pub const EFAULT: u64 = 14;
+pub const ENOTDIR: u64 = 20;
+pub const EISDIR: u64 = 21;
+pub const EINVAL: u64 = 22;
+pub const ENAMETOOLONG: u64 = 36;
+pub const ENOSYS: u64 = 38;
+
+/// The status of a process that was killed rather than exiting.
+const SIGKILL: u32 = 9;
+
+const STDIN: u64 = 0;
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+/// How an error number looks in RAX.
+pub fn errno(code: u64) -> u64 {
+    code.wrapping_neg()
+}
+
+static WRITE_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Gets every buffer a program writes to stdout or stderr, after it has
+/// been printed. Meant for integration tests.
+pub fn set_write_hook(hook: fn(&[u8])) {
+    WRITE_HOOK.store(hook as usize, Ordering::Release);
+}
+
+/// Longest path `spawn` takes.
+pub const PATH_MAX: u64 = 4096;
+
+/// What `read` hands out on stdin.
+static INPUT: ByteQueue = ByteQueue::new();
+
+/// Queues `bytes` for programs reading stdin. Returns how many fit.
+pub fn feed_stdin(bytes: &[u8]) -> usize {
+    bytes.iter().take_while(|&&byte| INPUT.push(byte)).count()
+}
+
+/// EFER.SCE: SYSCALL and SYSRET are only valid with it.
+const EFER_SCE: u64 = 1 << 0;
+/// RFLAGS bits SYSCALL clears: IF, so the entry runs like an interrupt
+/// gate, plus TF and DF.
+const SYSCALL_FMASK: u64 = 0x200 | 0x100 | 0x400;
+
+/// Enables SYSCALL on this CPU, next to `int 0x80`. Needs the GDT loaded
+/// and the per-CPU block, where SYSCALL's missing stack switch finds the
+/// kernel stack.
+pub fn init() {
+    let (code, stack) = gdt::user_selectors();
+    let cpu = percpu::current();
+    cpu.user_cs.store(u64::from(code.0), Ordering::Relaxed);
+    cpu.user_ss.store(u64::from(stack.0), Ordering::Relaxed);
+    unsafe {
+        cpu::wrmsr(IA32_STAR, gdt::star());
+        cpu::wrmsr(IA32_LSTAR, syscall_entry as *const () as u64);
+        cpu::wrmsr(IA32_FMASK, SYSCALL_FMASK);
+        cpu::wrmsr(IA32_EFER, cpu::rdmsr(IA32_EFER) | EFER_SCE);
+    }
+}
+
+/// The stack `syscall_entry` switches to; the same as RSP0 for `int 0x80`.
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    percpu::current().syscall_kernel_rsp.store(top.as_u64(), Ordering::Relaxed);
+}
+
+/// IA32_LSTAR. Interrupts are off until the return (FMASK), so nothing can
+/// run between the `swapgs` pair or on the user stack before `sysretq`.
+#[unsafe(naked)]
+extern "C" fn syscall_entry() {
+    core::arch::naked_asm!(
+        "swapgs",
+        "mov gs:[{user_rsp}], rsp",
+        "mov rsp, gs:[{kernel_rsp}]",
+        // the frame `int 0x80` pushes: SS, RSP, RFLAGS, CS, RIP
+        "push qword ptr gs:[{user_ss}]",
+        "push qword ptr gs:[{user_rsp}]",
+        "push r11",
+        "push qword ptr gs:[{user_cs}]",
+        "push rcx",
+        // back to the program's GS: `call_handler` swaps again, as for
+        // every entry from ring 3
+        "swapgs",
+        "push 0",
+        call_handler!(),
+        // sysretq reloads RIP from RCX and RFLAGS from R11. If the frame
+        // still says what SYSCALL saved there, the program just continues;
+        // otherwise it belongs to another process and needs every register
+        "cmp rcx, [rsp]",
+        "jne 2f",
+        "cmp r11, [rsp + 16]",
+        "jne 2f",
+        "mov rsp, [rsp + 24]",
+        "sysretq",
+        "2:",
+        "iretq",
+        user_rsp = const core::mem::offset_of!(PerCpu, syscall_user_rsp),
+        kernel_rsp = const core::mem::offset_of!(PerCpu, syscall_kernel_rsp),
+        user_cs = const core::mem::offset_of!(PerCpu, user_cs),
+        user_ss = const core::mem::offset_of!(PerCpu, user_ss),
+        handler = sym handler,
+    );
+}
+
+/// Called by both entry stubs with the program's registers.
+pub(crate) extern "C" fn handler(frame: &mut ExceptionFrame) {
+    let regs = frame.registers;
+    // exit needs the frame to switch to the next process
+    if regs.rax == SYS_EXIT && process::exit(frame, regs.rdi) {
+        return;
+    }
+    // so does sleeping
+    if regs.rax == SYS_NANOSLEEP {
+        match sleep_ticks(regs.rdi) {
+            Ok(0) => frame.registers.rax = 0,
+            Ok(ticks) if process::sleep_ticks(frame, ticks) => {}
+            Ok(_) => frame.registers.rax = errno(ENOSYS),
+            Err(err) => frame.registers.rax = err,
+        }
+        return;
+    }
+    // fork copies it
+    if regs.rax == SYS_FORK {
+        if let Some(result) = process::fork(frame) {
+            frame.registers.rax = result.map_or_else(|err| errno(spawn_errno(err)), |pid| pid.0);
+            return;
+        }
+    }
+    // and waiting for another process
+    if regs.rax == SYS_WAIT4 {
+        if let Some(result) = wait4(frame, regs.rdi, regs.rsi) {
+            frame.registers.rax = result;
+        }
+        return;
+    }
+    frame.registers.rax = dispatch(regs.rax, regs.rdi, regs.rsi, regs.rdx);
+}
+
+pub fn dispatch(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    match number {
+        SYS_READ => read(arg0, arg1, arg2),
+        SYS_WRITE => write(arg0, arg1, arg2),
+        SYS_SPAWN => spawn(arg0, arg1),
+        SYS_NANOSLEEP => match sleep_ticks(arg0) {
+            Ok(0) => 0,
+            Ok(_) => errno(ENOSYS),
+            Err(err) => err,
+        },
+        // handled by `handler` whenever a process is running
+        SYS_EXIT | SYS_FORK | SYS_WAIT4 => errno(ENOSYS),
+        _ => errno(ENOSYS),
+    }
+}
+
+/// Ticks a `nanosleep` for the `struct timespec` at `ptr` sleeps: whole
+/// ticks covering the time plus one, since the current tick may end right
+/// away. The remaining-time argument is never written; sleeps are not
+/// interrupted.
+fn sleep_ticks(ptr: u64) -> Result<u64, u64> {
+    let timespec = usermode::user_slice(ptr, 16).ok_or(errno(EFAULT))?;
+    let secs = u64::from_le_bytes(timespec[..8].try_into().unwrap());
+    let nanos = u64::from_le_bytes(timespec[8..].try_into().unwrap());
+    if (secs as i64) < 0 || nanos >= 1_000_000_000 {
+        return Err(errno(EINVAL));
+    }
+    let total = secs.saturating_mul(1_000_000_000).saturating_add(nanos);
+    Ok(match total {
+        0 => 0,
+        nanos => time::nanos_to_ticks(nanos) + 1,
+    })
+}
+
+/// `wait4(pid, wstatus, ...)` for one process, any process but the caller,
+/// or with pid -1 for any child of the caller. Returns the pid of the one
+/// that finished and stores its status as Linux encodes it: the exit code
+/// in bits 8-15, or SIGKILL for a killed process. `None` while parked.
+fn wait4(frame: &mut ExceptionFrame, pid: u64, status_ptr: u64) -> Option<u64> {
+    let caller = process::current();
+    let target = match pid as i64 {
+        -1 => match caller {
+            Some(caller) if !process::children(caller).is_empty() => None,
+            _ => return Some(errno(ECHILD)),
+        },
+        _ if caller == Some(process::Pid(pid)) || process::state(process::Pid(pid)).is_none() => {
+            return Some(errno(ECHILD))
+        }
+        _ => Some(process::Pid(pid)),
+    };
+    let status = match status_ptr {
+        0 => None,
+        ptr => match usermode::user_slice_mut(ptr, 4) {
+            Some(status) => Some(status),
+            None => return Some(errno(EFAULT)),
+        },
+    };
+    let mut finished = None;
+    let wait = process::FINISHED.wait_until(Waiter::Process(frame), || {
+        finished = match target {
+            Some(target) => process::state(target).filter(State::finished).map(|state| (target, state)),
+            None => caller.and_then(process::finished_child),
+        };
+        finished.is_some()
+    });
+    match wait {
+        Wait::Done => {}
+        Wait::Parked => return None,
+        Wait::NoProcess => return Some(errno(ENOSYS)),
+        Wait::Pending => unreachable!("only task waiters are pending"),
+    }
+    let (pid, state) = finished.expect("wait_until returned before the condition held");
+    process::disown(pid);
+    let code = match state {
+        State::Exited(code) => ((code & 0xff) << 8) as u32,
+        _ => SIGKILL,
+    };
+    if let Some(status) = status {
+        status.copy_from_slice(&code.to_le_bytes());
+    }
+    Some(pid.0)
+}
+
+/// `read(0, buf, len)`: the bytes `feed_stdin` queued, up to `len`, or
+/// EAGAIN when there are none; programs never wait for input.
+fn read(fd: u64, ptr: u64, len: u64) -> u64 {
+    if fd != STDIN {
+        return errno(EBADF);
+    }
+    let Some(buf) = usermode::user_slice_mut(ptr, len) else {
+        return errno(EFAULT);
+    };
+    let mut done = 0;
+    while done < buf.len() {
+        let Some(byte) = INPUT.pop() else { break };
+        buf[done] = byte;
+        done += 1;
+    }
+    match done {
+        0 if len > 0 => errno(EAGAIN),
+        done => done as u64,
+    }
+}
+
+/// `spawn(path, len)`: loads the ELF at the absolute `path` and queues it
+/// as a process. Returns the pid.
+fn spawn(ptr: u64, len: u64) -> u64 {
+    if len > PATH_MAX {
+        return errno(ENAMETOOLONG);
+    }
+    let Some(path) = usermode::user_slice(ptr, len) else {
+        return errno(EFAULT);
+    };
+    let Ok(path) = core::str::from_utf8(path) else {
+        return errno(EINVAL);
+    };
+    let image = match crate::vfs::read_to_end(path) {
+        Ok(image) => image,
+        Err(err) => return errno(vfs_errno(err)),
+    };
+    match process::spawn_from_elf(&image) {
+        Ok(pid) => pid.0,
+        Err(err) => errno(spawn_errno(err)),
+    }
+}
+
+fn spawn_errno(err: process::SpawnError) -> u64 {
+    match err {
+        process::SpawnError::Elf(_) | process::SpawnError::OutsideUserRegion => ENOEXEC,
+        process::SpawnError::OutOfMemory => ENOMEM,
+        process::SpawnError::RegionInUse | process::SpawnError::NoPaging => EIO,
+    }
+}
+
+fn vfs_errno(err: VfsError) -> u64 {
+    match err {
+        VfsError::InvalidPath => EINVAL,
+        VfsError::NotMounted | VfsError::NotFound => ENOENT,
+        VfsError::NotADirectory => ENOTDIR,
+        VfsError::IsADirectory => EISDIR,
+        _ => EIO,
+    }
+}
+
+fn write(fd: u64, ptr: u64, len: u64) -> u64 {
+    if fd != STDOUT && fd != STDERR {
+        return errno(EBADF);
+    }
+    let Some(bytes) = usermode::user_slice(ptr, len) else {
+        return errno(EFAULT);
+    };
+    for chunk in bytes.utf8_chunks() {
+        print!("{}", chunk.valid());
+        if !chunk.invalid().is_empty() {
+            print!("\u{fffd}");
+        }
+    }
+    let hook = WRITE_HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        let hook: fn(&[u8]) = unsafe { core::mem::transmute(hook) };
+        hook(bytes);
+    }
+    len
+}
+
+//test case
+#[test_case]
+fn test_dispatch_errors() {
+    assert_eq!(dispatch(12345, 0, 0, 0), errno(ENOSYS));
+    assert_eq!(dispatch(SYS_WRITE, 7, 0, 0), errno(EBADF));
+    // kernel memory is not the program's to write out
+    assert_eq!(dispatch(SYS_WRITE, STDOUT, &WRITE_HOOK as *const AtomicUsize as u64, 4), errno(EFAULT));
+    // with no program running, exit has nothing to end
+    assert_eq!(dispatch(SYS_EXIT, 0, 0, 0), errno(ENOSYS));
+    assert_eq!(dispatch(SYS_FORK, 0, 0, 0), errno(ENOSYS));
+    assert_eq!(dispatch(SYS_NANOSLEEP, 0, 0, 0), errno(EFAULT));
+    assert_eq!(errno(EFAULT) as i64, -14);
+}
+
+#[test_case]
+fn test_read_and_spawn_check_their_arguments() {
+    assert_eq!(dispatch(SYS_READ, STDOUT, 0, 0), errno(EBADF));
+    assert_eq!(dispatch(SYS_READ, STDIN, &INPUT as *const ByteQueue as u64, 1), errno(EFAULT));
+    assert_eq!(dispatch(SYS_SPAWN, &WRITE_HOOK as *const AtomicUsize as u64, 4, 0), errno(EFAULT));
+    assert_eq!(dispatch(SYS_SPAWN, 0, PATH_MAX + 1, 0), errno(ENAMETOOLONG));
+    assert_eq!(vfs_errno(VfsError::NotMounted), ENOENT);
+    assert_eq!(vfs_errno(VfsError::ReadOnly), EIO);
+}
+
+#[test_case]
+fn test_syscall_msrs() {
+    // SYSCALL: CS 0x08, SS 0x10; SYSRET: SS 0x1b, CS 0x23
+    assert_eq!(gdt::star(), 0x0013_0008_0000_0000);
+    assert_eq!(unsafe { cpu::rdmsr(IA32_EFER) } & EFER_SCE, EFER_SCE);
+    assert_eq!(unsafe { cpu::rdmsr(IA32_LSTAR) }, syscall_entry as *const () as u64);
+    let (code, stack) = gdt::user_selectors();
+    assert_eq!((code.0, stack.0), (0x23, 0x1b));
+}
@@ -0,0 +1,271 @@
+//! POSIX ustar archives, the initrd format.
+//!
+//! The archive is parsed in place: file contents are handed out as slices
+//! of the archive itself. Each 512-byte header is checked (magic, octal
+//! checksum, sizes) before anything it describes is touched, and the first
+//! bad header ends iteration with an error, so a corrupt archive can never
+//! make the parser read outside it. Regular files and directories are
+//! materialized; links, devices and extended headers are listed with their
+//! type flag and no data. `fs::tarfs` shows an archive through the VFS.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(test)]
+pub(crate) mod testdata;
+
+const BLOCK_SIZE: usize = 512;
+const NAME: (usize, usize) = (0, 100);
+const SIZE: (usize, usize) = (124, 12);
+const CHECKSUM: (usize, usize) = (148, 8);
+const TYPEFLAG: usize = 156;
+const MAGIC: (usize, usize) = (257, 6);
+const PREFIX: (usize, usize) = (345, 155);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarError {
+    /// The header at this offset does not add up to its checksum.
+    BadChecksum(usize),
+    /// The header at this offset is not a ustar header.
+    BadMagic(usize),
+    /// A numeric field of the header at this offset is not octal.
+    BadField(usize),
+    /// The entry at this offset reaches past the end of the archive.
+    Truncated(usize),
+}
+
+impl fmt::Display for TarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TarError::BadChecksum(offset) => write!(f, "bad header checksum at {:#x}", offset),
+            TarError::BadMagic(offset) => write!(f, "no ustar header at {:#x}", offset),
+            TarError::BadField(offset) => write!(f, "bad numeric field in the header at {:#x}", offset),
+            TarError::Truncated(offset) => write!(f, "entry at {:#x} runs past the end of the archive", offset),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// Any other type flag: links, devices, FIFOs, extended headers.
+    Other(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarEntry<'a> {
+    /// The path without a leading `./` or `/` or a trailing `/`.
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: usize,
+    /// The contents for a regular file, empty for anything else.
+    pub data: &'a [u8],
+}
+
+/// Parses an octal field: optional leading spaces, then digits up to a
+/// NUL, a space or the end of the field.
+fn octal(field: &[u8]) -> Option<u64> {
+    let mut value: u64 = 0;
+    let digits = field.iter().skip_while(|&&b| b == b' ').take_while(|&&b| b != 0 && b != b' ');
+    for &b in digits {
+        if !(b'0'..=b'7').contains(&b) {
+            return None;
+        }
+        value = value.checked_mul(8)?.checked_add(u64::from(b - b'0'))?;
+    }
+    Some(value)
+}
+
+fn text(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+fn field(header: &[u8], (offset, len): (usize, usize)) -> &[u8] {
+    &header[offset..offset + len]
+}
+
+/// Drops a leading `./` or `/` and any trailing `/`.
+pub(crate) fn clean(path: &str) -> &str {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    path.trim_matches('/')
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TarArchive<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> TarArchive<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        TarArchive { data }
+    }
+
+    /// Every entry in order. A corrupt header yields one error and ends
+    /// the iteration.
+    pub fn entries(&self) -> Entries<'a> {
+        Entries { data: self.data, offset: 0, done: false }
+    }
+
+    /// Contents of the regular file at `path`, as a slice of the archive.
+    pub fn find(&self, path: &str) -> Option<&'a [u8]> {
+        let path = clean(path);
+        self.entries()
+            .map_while(Result::ok)
+            .find(|entry| entry.kind == EntryKind::File && entry.path == path)
+            .map(|entry| entry.data)
+    }
+}
+
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Entries<'a> {
+    fn parse(&self) -> Result<Option<(TarEntry<'a>, usize)>, TarError> {
+        let offset = self.offset;
+        let rest = &self.data[offset.min(self.data.len())..];
+        if rest.len() < BLOCK_SIZE {
+            // trailing bytes short of a block are only allowed as padding
+            return if rest.iter().all(|&b| b == 0) { Ok(None) } else { Err(TarError::Truncated(offset)) };
+        }
+        let header = &rest[..BLOCK_SIZE];
+        if header.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+        // "ustar\0" for POSIX, "ustar " for old GNU archives
+        if &field(header, MAGIC)[..5] != b"ustar" {
+            return Err(TarError::BadMagic(offset));
+        }
+        let stored = octal(field(header, CHECKSUM)).ok_or(TarError::BadField(offset))?;
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (CHECKSUM.0..CHECKSUM.0 + CHECKSUM.1).contains(&i) { u64::from(b' ') } else { u64::from(b) })
+            .sum();
+        if sum != stored {
+            return Err(TarError::BadChecksum(offset));
+        }
+
+        let size = octal(field(header, SIZE)).ok_or(TarError::BadField(offset))?;
+        let size = usize::try_from(size).map_err(|_| TarError::Truncated(offset))?;
+        let start = offset + BLOCK_SIZE;
+        let end = start.checked_add(size).filter(|&end| end <= self.data.len()).ok_or(TarError::Truncated(offset))?;
+        let next = start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+        let kind = match header[TYPEFLAG] {
+            b'0' | 0 | b'7' => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            flag => EntryKind::Other(flag),
+        };
+        let name = String::from_utf8_lossy(text(field(header, NAME)));
+        let prefix = String::from_utf8_lossy(text(field(header, PREFIX)));
+        let joined = if prefix.is_empty() { name.into_owned() } else { alloc::format!("{}/{}", prefix, name) };
+        let entry = TarEntry {
+            path: String::from(clean(&joined)),
+            kind,
+            size,
+            data: if kind == EntryKind::File { &self.data[start..end] } else { &[] },
+        };
+        Ok(Some((entry, next)))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<TarEntry<'a>, TarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.parse() {
+            Ok(Some((entry, next))) => {
+                self.offset = next;
+                Some(Ok(entry))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+//test case
+#[test_case]
+fn test_octal() {
+    assert_eq!(octal(b"00000001750\0"), Some(0o1750));
+    assert_eq!(octal(b"   644 \0"), Some(0o644));
+    assert_eq!(octal(b"\0\0\0\0"), Some(0));
+    assert_eq!(octal(b"0000009\0"), None);
+    assert_eq!(octal(b"77777777777777777777777"), None);
+}
+
+#[test_case]
+fn test_entries() {
+    let long_dir = "usr/share/doc/a-directory-name-long-enough-that-the-whole-path-no-longer-fits-the-name-field";
+    let long_path = alloc::format!("{}/{}", long_dir, "file.txt");
+    let archive = testdata::build(&[
+        ("./etc/", b'5', b""),
+        ("./etc/motd", b'0', b"welcome to berryOS\n"),
+        ("empty", b'0', b""),
+        ("block", b'0', &[0x42; 512]),
+        ("block-and-one", b'0', &[0x43; 513]),
+        ("etc/link", b'2', b""),
+        (&long_path, b'0', b"deep"),
+    ]);
+    let archive = TarArchive::new(&archive);
+    let entries: Vec<TarEntry> = archive.entries().map(Result::unwrap).collect();
+    let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+    assert_eq!(paths, ["etc", "etc/motd", "empty", "block", "block-and-one", "etc/link", long_path.as_str()]);
+    assert_eq!(entries[0].kind, EntryKind::Directory);
+    assert_eq!(entries[5].kind, EntryKind::Other(b'2'));
+    assert_eq!(entries[5].data, b"");
+
+    assert_eq!(archive.find("/etc/motd"), Some(&b"welcome to berryOS\n"[..]));
+    assert_eq!(archive.find("empty"), Some(&b""[..]));
+    assert_eq!(archive.find("block-and-one").map(<[u8]>::len), Some(513));
+    assert_eq!(archive.find(&long_path), Some(&b"deep"[..]));
+    assert_eq!(archive.find("etc"), None);
+    assert_eq!(archive.find("etc/link"), None);
+    assert_eq!(archive.find("missing"), None);
+}
+
+#[test_case]
+fn test_corrupt_archives() {
+    let good = testdata::build(&[("a", b'0', b"first"), ("b", b'0', b"second")]);
+    let collect = |data: &[u8]| -> Vec<Result<String, TarError>> {
+        TarArchive::new(data).entries().map(|entry| entry.map(|entry| entry.path)).collect()
+    };
+
+    let mut bad_sum = good.clone();
+    bad_sum[1024 + 1] ^= 1;
+    assert_eq!(collect(&bad_sum), [Ok(String::from("a")), Err(TarError::BadChecksum(1024))]);
+
+    // a size far past the end, with a matching checksum
+    let mut huge = good.clone();
+    testdata::set_size(&mut huge[1024..1536], 0o77777777777);
+    assert_eq!(collect(&huge), [Ok(String::from("a")), Err(TarError::Truncated(1024))]);
+
+    // cut inside the second file's data
+    assert_eq!(collect(&good[..1536 + 3]), [Ok(String::from("a")), Err(TarError::Truncated(1024))]);
+    let mut no_magic = good.clone();
+    no_magic[257] = b'x';
+    assert_eq!(collect(&no_magic), [Err(TarError::BadMagic(0))]);
+    // without the end-of-archive blocks, and with short zero padding
+    assert_eq!(collect(&good[..2048]).len(), 2);
+    assert_eq!(collect(&good[..2048 + 100]).len(), 2);
+    assert_eq!(collect(&[]), []);
+
+    let archive = TarArchive::new(&bad_sum);
+    assert_eq!(archive.find("b"), None);
+    assert_eq!(archive.find("a"), Some(&b"first"[..]));
+}
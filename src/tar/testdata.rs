@@ -0,0 +1,51 @@
+//! Builds ustar archives from known contents, header by header.
+
+use alloc::vec::Vec;
+
+fn put_octal(header: &mut [u8], offset: usize, len: usize, value: u64) {
+    // zero-padded digits and a terminating NUL, as GNU tar writes them
+    let digits = alloc::format!("{:0width$o}\0", value, width = len - 1);
+    header[offset..offset + len].copy_from_slice(digits.as_bytes());
+}
+
+fn seal(header: &mut [u8]) {
+    header[148..156].fill(b' ');
+    let sum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+    let digits = alloc::format!("{:06o}\0 ", sum);
+    header[148..156].copy_from_slice(digits.as_bytes());
+}
+
+/// Overwrites a header's size field and fixes its checksum.
+pub fn set_size(header: &mut [u8], size: u64) {
+    put_octal(header, 124, 12, size);
+    seal(header);
+}
+
+/// An archive of `(path, typeflag, contents)` entries, ended by two zero
+/// blocks. Paths over 100 bytes are split into the prefix field.
+pub fn build(entries: &[(&str, u8, &[u8])]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    for &(path, flag, contents) in entries {
+        let mut header = [0u8; 512];
+        let (prefix, name) = match path.len() {
+            0..=100 => ("", path),
+            _ => path.rsplit_once('/').unwrap(),
+        };
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        put_octal(&mut header, 100, 8, if flag == b'5' { 0o755 } else { 0o644 });
+        put_octal(&mut header, 108, 8, 0);
+        put_octal(&mut header, 116, 8, 0);
+        put_octal(&mut header, 124, 12, contents.len() as u64);
+        put_octal(&mut header, 136, 12, 0o14_000_000_000);
+        header[156] = flag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        seal(&mut header);
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(contents);
+        archive.resize(archive.len().div_ceil(512) * 512, 0);
+    }
+    archive.resize(archive.len() + 1024, 0);
+    archive
+}
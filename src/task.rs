@@ -0,0 +1,254 @@
+//! Cooperative multitasking with `async`.
+//!
+//! A `Task` is a boxed future the `Executor` polls whenever its waker has
+//! been called. Interrupt handlers hand work to tasks through an
+//! `AtomicWaker` (never allocating or blocking), and tasks wait for the
+//! timer with `timer::sleep`. There is no `futures` crate here, so `Stream`
+//! and `StreamExt::next` are the few pieces of it the tasks need.
+
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use spin::Mutex;
+
+pub mod executor;
+pub mod keyboard;
+pub mod mouse;
+pub mod timer;
+
+pub use executor::Executor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    waker: Arc<TaskWaker>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        // woken from the start, so the first run polls it
+        let waker = Arc::new(TaskWaker { woken: AtomicBool::new(true) });
+        Task { id: TaskId::new(), future: Box::pin(future), waker }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Whether the task was woken since the last call.
+    fn take_wake(&self) -> bool {
+        self.waker.woken.swap(false, Ordering::AcqRel)
+    }
+
+    fn was_woken(&self) -> bool {
+        self.waker.woken.load(Ordering::Acquire)
+    }
+
+    fn poll(&mut self) -> Poll<()> {
+        let waker = Waker::from(self.waker.clone());
+        self.future.as_mut().poll(&mut Context::from_waker(&waker))
+    }
+}
+
+/// Waking only sets a flag, so it is safe from an interrupt handler as long
+/// as the task still exists (the executor holds a reference until then).
+struct TaskWaker {
+    woken: AtomicBool,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+    }
+}
+
+/// One waker an interrupt handler can call while a task registers a new
+/// one. Registering runs with interrupts off and the handler only
+/// `try_lock`s, so neither waits for the other; the waker is left in place
+/// after a wake, since a spurious wake is harmless and dropping it in the
+/// handler might free memory there.
+pub struct AtomicWaker {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Default for AtomicWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AtomicWaker {
+    pub const fn new() -> Self {
+        AtomicWaker { waker: Mutex::new(None) }
+    }
+
+    pub fn register(&self, waker: &Waker) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut slot = self.waker.lock();
+            if !slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+                *slot = Some(waker.clone());
+            }
+        });
+    }
+
+    pub fn wake(&self) {
+        if let Some(slot) = self.waker.try_lock() {
+            if let Some(waker) = slot.as_ref() {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// A source of values over time, like `Iterator` for async code.
+pub trait Stream {
+    type Item;
+
+    /// The next value if there is one; `Pending`, with the waker
+    /// registered, if it is not there yet; `None` once there are no more.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>>;
+}
+
+pub trait StreamExt: Stream {
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Next { stream: self }
+    }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+//test case
+#[cfg(test)]
+use alloc::rc::Rc;
+#[cfg(test)]
+use core::cell::RefCell;
+
+/// Shared state one test task fills in and another waits on.
+#[cfg(test)]
+#[derive(Default)]
+struct Slot {
+    value: Option<u32>,
+    waiter: Option<Waker>,
+}
+
+#[cfg(test)]
+fn send(slot: &Rc<RefCell<Slot>>, value: u32) {
+    let mut slot = slot.borrow_mut();
+    slot.value = Some(value);
+    if let Some(waker) = slot.waiter.take() {
+        waker.wake();
+    }
+}
+
+#[cfg(test)]
+fn receive(slot: Rc<RefCell<Slot>>) -> impl Future<Output = u32> {
+    core::future::poll_fn(move |cx| {
+        let mut slot = slot.borrow_mut();
+        match slot.value.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                slot.waiter = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    })
+}
+
+#[test_case]
+fn test_task_waits_for_another() {
+    let slot = Rc::new(RefCell::new(Slot::default()));
+    let received = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    {
+        let (slot, received) = (slot.clone(), received.clone());
+        executor.spawn(Task::new(async move {
+            let value = receive(slot).await;
+            *received.borrow_mut() = Some(value);
+        }));
+    }
+    {
+        let slot = slot.clone();
+        executor.spawn(Task::new(async move {
+            // let the receiver park first
+            timer::sleep(2).await;
+            send(&slot, 42);
+        }));
+    }
+    executor.run_until_done();
+    assert_eq!(*received.borrow(), Some(42));
+    assert!(slot.borrow().waiter.is_none());
+}
+
+#[test_case]
+fn test_tasks_interleave() {
+    let log = Rc::new(RefCell::new(alloc::vec::Vec::new()));
+    let mut executor = Executor::new();
+    for name in ['a', 'b'] {
+        let log = log.clone();
+        executor.spawn(Task::new(async move {
+            for round in 0..3 {
+                log.borrow_mut().push((name, round));
+                timer::sleep(1).await;
+            }
+        }));
+    }
+    executor.run_until_done();
+    let log = log.borrow();
+    assert_eq!(log.len(), 6);
+    // each went one step at a time, in order
+    for name in ['a', 'b'] {
+        let rounds: alloc::vec::Vec<_> = log.iter().filter(|(n, _)| *n == name).map(|(_, r)| *r).collect();
+        assert_eq!(rounds, [0, 1, 2]);
+    }
+    assert_eq!(&log[..2], [('a', 0), ('b', 0)]);
+}
+
+#[test_case]
+fn test_atomic_waker() {
+    let task = Task::new(async {});
+    assert!(task.take_wake());
+    assert!(!task.was_woken());
+    let waker = Waker::from(task.waker.clone());
+    let atomic = AtomicWaker::new();
+    // nothing registered: nothing happens
+    atomic.wake();
+    atomic.register(&waker);
+    atomic.wake();
+    assert!(task.take_wake());
+    // still registered after the wake
+    atomic.wake();
+    assert!(task.take_wake());
+}
@@ -0,0 +1,67 @@
+//! Runs tasks until they finish, halting while none of them can move.
+
+use alloc::collections::BTreeMap;
+use core::task::Poll;
+use x86_64::instructions::interrupts;
+use super::{timer, Task, TaskId};
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor { tasks: BTreeMap::new() }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let id = task.id();
+        if self.tasks.insert(id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+    }
+
+    /// Runs the tasks forever.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.step();
+        }
+    }
+
+    /// Runs until every task has finished.
+    pub fn run_until_done(&mut self) {
+        while !self.tasks.is_empty() {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        timer::wake_expired();
+        self.run_ready_tasks();
+        self.sleep_if_idle();
+    }
+
+    /// Polls every task woken since it was last polled; finished ones are
+    /// dropped.
+    fn run_ready_tasks(&mut self) {
+        self.tasks.retain(|_, task| !(task.take_wake() && task.poll() == Poll::Ready(())));
+    }
+
+    /// Halts unless a task was woken after it was polled. The check runs
+    /// with interrupts off and the halt turns them back on, so a wake from
+    /// an interrupt in between is not slept through.
+    fn sleep_if_idle(&self) {
+        interrupts::disable();
+        if self.tasks.values().any(Task::was_woken) || timer::any_expired() {
+            interrupts::enable();
+        } else {
+            crate::cpu::enable_and_halt();
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Scancodes as a stream, and the task that turns them into shell input.
+
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use super::{AtomicWaker, Stream, StreamExt};
+use crate::keyboard;
+
+/// Woken by the keyboard interrupt after it queues a scancode.
+pub(crate) static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// The scancodes the keyboard interrupt queued. There is one queue, so
+/// there can only be one stream.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+        assert!(!TAKEN.swap(true, Ordering::Relaxed), "ScancodeStream::new should only be called once");
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        if let Some(scancode) = keyboard::next_scancode() {
+            return Poll::Ready(Some(scancode));
+        }
+        WAKER.register(cx.waker());
+        // one may have come in before the waker was there
+        match keyboard::next_scancode() {
+            Some(scancode) => Poll::Ready(Some(scancode)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Decodes key presses and feeds them to the console, which runs shell
+/// commands as lines are finished.
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    while let Some(scancode) = scancodes.next().await {
+        keyboard::handle_scancode(scancode);
+    }
+}
@@ -0,0 +1,144 @@
+//! Mouse bytes as a stream, the task that moves the cursor with them, and
+//! the decoded events for any task that wants them.
+//!
+//! Unlike the bytes, events have any number of readers: each `subscribe`
+//! gets its own queue, which the mouse task fills as it decodes packets.
+
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use spin::Mutex;
+use super::{AtomicWaker, Stream, StreamExt};
+use crate::mouse::{self, MouseEvent};
+
+/// Woken by the mouse interrupt after it queues a byte.
+pub(crate) static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// The bytes the mouse interrupt queued; like the scancodes, there is one
+/// queue and so one stream.
+pub struct MouseStream {
+    _private: (),
+}
+
+impl Default for MouseStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MouseStream {
+    pub fn new() -> Self {
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+        assert!(!TAKEN.swap(true, Ordering::Relaxed), "MouseStream::new should only be called once");
+        MouseStream { _private: () }
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        if let Some(byte) = mouse::next_byte() {
+            return Poll::Ready(Some(byte));
+        }
+        WAKER.register(cx.waker());
+        match mouse::next_byte() {
+            Some(byte) => Poll::Ready(Some(byte)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Decodes packets, moves the cursor and runs the click hook.
+pub async fn track_mouse() {
+    let mut bytes = MouseStream::new();
+    while let Some(byte) = bytes.next().await {
+        mouse::handle_byte(byte);
+    }
+}
+
+/// Events a subscriber has not read yet. Past this many the oldest go, so
+/// a task that stops reading doesn't hold on to every movement since.
+const QUEUE_LEN: usize = 64;
+
+struct Subscriber {
+    events: Mutex<VecDeque<MouseEvent>>,
+    waker: AtomicWaker,
+}
+
+/// Only the mouse task and subscribing tasks take these locks, never an
+/// interrupt handler.
+static SUBSCRIBERS: Mutex<Vec<Weak<Subscriber>>> = Mutex::new(Vec::new());
+
+/// The events decoded after the call, for as long as the stream is kept.
+pub struct MouseEvents {
+    subscriber: Arc<Subscriber>,
+}
+
+pub fn subscribe() -> MouseEvents {
+    let subscriber = Arc::new(Subscriber { events: Mutex::new(VecDeque::new()), waker: AtomicWaker::new() });
+    SUBSCRIBERS.lock().push(Arc::downgrade(&subscriber));
+    MouseEvents { subscriber }
+}
+
+/// Queues `event` for every subscriber, forgetting the dropped ones.
+pub(crate) fn publish(event: MouseEvent) {
+    let mut subscribers = SUBSCRIBERS.lock();
+    subscribers.retain(|subscriber| subscriber.strong_count() > 0);
+    for subscriber in subscribers.iter().filter_map(Weak::upgrade) {
+        let mut events = subscriber.events.lock();
+        if events.len() == QUEUE_LEN {
+            events.pop_front();
+        }
+        events.push_back(event);
+        drop(events);
+        subscriber.waker.wake();
+    }
+}
+
+impl Stream for MouseEvents {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        if let Some(event) = self.subscriber.events.lock().pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        self.subscriber.waker.register(cx.waker());
+        match self.subscriber.events.lock().pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Keeps the `mouse live` corner up to date.
+pub async fn show_state() {
+    let mut events = subscribe();
+    while let Some(event) = events.next().await {
+        mouse::show_live(event);
+    }
+}
+
+//test case
+#[test_case]
+fn test_subscribers_get_their_own_queues() {
+    use crate::mouse::Buttons;
+    let event = |dx| MouseEvent { dx, dy: 0, buttons: Buttons::default(), wheel: 0 };
+    let first = subscribe();
+    let second = subscribe();
+    publish(event(1));
+    assert_eq!(first.subscriber.events.lock().pop_front(), Some(event(1)));
+    drop(first);
+    // the dropped subscriber is forgotten, and a slow reader keeps only
+    // the newest events
+    for dx in 0..QUEUE_LEN as i16 {
+        publish(event(dx));
+    }
+    assert!(SUBSCRIBERS.lock().iter().all(|subscriber| subscriber.strong_count() > 0));
+    let queued = second.subscriber.events.lock();
+    assert_eq!(queued.len(), QUEUE_LEN);
+    assert_eq!((queued[0].dx, queued[QUEUE_LEN - 1].dx), (0, QUEUE_LEN as i16 - 1));
+}
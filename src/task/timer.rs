@@ -0,0 +1,64 @@
+//! Waiting for timer ticks. Sleeping tasks are kept in a list the executor
+//! checks after every wakeup rather than in the timer interrupt, which then
+//! needs no part in it: the halt ends on the tick anyway.
+
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::time;
+
+/// Tasks waiting for a tick, with the tick they wait for.
+static SLEEPERS: Mutex<Vec<(u64, Waker)>> = Mutex::new(Vec::new());
+
+/// Ready once `ticks` timer ticks have passed.
+pub fn sleep(ticks: u64) -> Sleep {
+    Sleep { until: time::ticks() + ticks }
+}
+
+/// Ready once at least `duration` has passed, rounded up to whole ticks.
+pub fn sleep_for(duration: Duration) -> Sleep {
+    sleep(time::duration_to_ticks(duration))
+}
+
+pub struct Sleep {
+    until: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if time::ticks() >= self.until {
+            return Poll::Ready(());
+        }
+        let until = self.until;
+        without_interrupts(|| SLEEPERS.lock().push((until, cx.waker().clone())));
+        Poll::Pending
+    }
+}
+
+/// Wakes every task whose tick has come.
+pub fn wake_expired() {
+    let now = time::ticks();
+    let mut expired = Vec::new();
+    without_interrupts(|| {
+        SLEEPERS.lock().retain(|(until, waker)| {
+            let due = *until <= now;
+            if due {
+                expired.push(waker.clone());
+            }
+            !due
+        })
+    });
+    expired.into_iter().for_each(Waker::wake);
+}
+
+/// Whether `wake_expired` has someone to wake.
+pub fn any_expired() -> bool {
+    let now = time::ticks();
+    without_interrupts(|| SLEEPERS.lock().iter().any(|(until, _)| *until <= now))
+}
@@ -0,0 +1,263 @@
+//! The test framework every test kernel shares: the library's own, the
+//! binary's and each `tests/*.rs` that uses `test_runner`.
+//!
+//! Each test runs with a deadline the timer interrupt checks, so one that
+//! hangs prints `[timeout]` and fails the run instead of leaving QEMU up
+//! until the bootimage timeout (a test that hangs with interrupts off still
+//! can). `test_timeout=<seconds>` on the command line changes the deadline,
+//! and `test_timeout=0` turns it off.
+//!
+//! A test that is meant to panic is declared with `should_panic!`. There is
+//! no unwinding, so when it does panic the panic handler picks the run up
+//! again at the next test, on top of the stack the test left behind. Any
+//! other panic ends the run.
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Once;
+use crate::vga_buffer::{Color, ANSI_RESET};
+use crate::{cmdline, debug, serial, serial_print, serial_println, time};
+
+/// How long one test may run without `test_timeout=`.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        let mut port = Port::new(0xf4);
+        port.write(exit_code as u32);
+    }
+}
+
+pub trait Testable: Sync {
+    fn run(&self);
+
+    fn name(&self) -> &'static str;
+
+    /// Passing means panicking.
+    fn should_panic(&self) -> bool {
+        false
+    }
+}
+
+impl<T> Testable for T
+where
+    T: Fn() + Sync,
+{
+    fn run(&self) {
+        self();
+    }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+}
+
+/// A test that passes by panicking; see `should_panic!`.
+pub struct ShouldPanic {
+    name: &'static str,
+    test: fn(),
+}
+
+impl ShouldPanic {
+    pub const fn new(name: &'static str, test: fn()) -> Self {
+        ShouldPanic { name, test }
+    }
+}
+
+impl Testable for ShouldPanic {
+    fn run(&self) {
+        (self.test)();
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn should_panic(&self) -> bool {
+        true
+    }
+}
+
+/// Declares a `#[test_case]` that passes only if it panics:
+///
+/// ```ignore
+/// tutorial_os::should_panic! {
+///     fn test_out_of_bounds() {
+///         let _ = [1, 2][usize::MAX];
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! should_panic {
+    ($(#[$attr:meta])* fn $name:ident() $body:block) => {
+        $(#[$attr])*
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static $name: $crate::testing::ShouldPanic =
+            $crate::testing::ShouldPanic::new(concat!(module_path!(), "::", stringify!($name)), || $body);
+    };
+}
+
+/// The run in progress: the harness hands `test_runner` a slice of
+/// statics, which the panic handler needs again to carry on.
+static TESTS: Once<&'static [&'static dyn Testable]> = Once::new();
+/// Index of the test running now.
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PASSED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+/// Tick the current test times out at; 0 between tests.
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+/// The tick a test starting now times out at; `test_timeout=0` means never.
+fn deadline() -> u64 {
+    match cmdline::get_u64("test_timeout").unwrap_or(DEFAULT_TIMEOUT_SECS) {
+        0 => u64::MAX,
+        // a year is plenty, and keeps the conversion from overflowing
+        secs => time::ticks().saturating_add(time::secs_to_ticks(secs.min(365 * 24 * 3600))),
+    }
+}
+
+fn selected(test: &dyn Testable) -> bool {
+    cmdline::get("test").is_none_or(|filter| test.name().contains(filter))
+}
+
+/// Runs every test, or with `test=<substring>` on the command line only
+/// those whose path contains it, then exits QEMU.
+pub fn test_runner(tests: &[&dyn Testable]) {
+    // SAFETY: the harness builds the slice out of statics
+    let tests: &'static [&'static dyn Testable] = unsafe { core::mem::transmute(tests) };
+    TESTS.call_once(|| tests);
+    let count = tests.iter().filter(|test| selected(**test)).count();
+    match cmdline::get("test") {
+        Some(filter) => {
+            serial_println!("Running {} of {} tests (filter '{}')", count, tests.len(), filter);
+        }
+        None => {
+            serial_println!("Running {} tests", tests.len());
+        }
+    }
+    run_from(0);
+}
+
+fn run_from(first: usize) -> ! {
+    let tests = TESTS.get().copied().unwrap_or(&[]);
+    for (index, test) in tests.iter().enumerate().skip(first) {
+        if !selected(*test) {
+            continue;
+        }
+        serial_print!("{}...\t", test.name());
+        CURRENT.store(index, Ordering::Relaxed);
+        DEADLINE.store(deadline(), Ordering::Release);
+        test.run();
+        DEADLINE.store(0, Ordering::Release);
+        if test.should_panic() {
+            serial_println!("{}[failed]{} (did not panic)", Color::LightRed.ansi(), ANSI_RESET);
+            FAILED.fetch_add(1, Ordering::Relaxed);
+        } else {
+            pass();
+        }
+    }
+    finish();
+}
+
+fn pass() {
+    serial_println!("{}[ok]{}", Color::Green.ansi(), ANSI_RESET);
+    PASSED.fetch_add(1, Ordering::Relaxed);
+}
+
+fn summary() -> (usize, usize) {
+    (PASSED.load(Ordering::Relaxed), FAILED.load(Ordering::Relaxed))
+}
+
+fn finish() -> ! {
+    let (passed, failed) = summary();
+    serial_println!("{} passed, {} failed", passed, failed);
+    exit_qemu(if failed == 0 { QemuExitCode::Success } else { QemuExitCode::Failed });
+    crate::hlt_loop();
+}
+
+/// The test running now, while `test_runner` is running one.
+fn current() -> Option<&'static dyn Testable> {
+    match DEADLINE.load(Ordering::Acquire) {
+        0 => None,
+        _ => TESTS.get().and_then(|tests| tests.get(CURRENT.load(Ordering::Relaxed)).copied()),
+    }
+}
+
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    let registers = debug::Registers::capture();
+    let test = current();
+    DEADLINE.store(0, Ordering::Release);
+    if test.is_some_and(|test| test.should_panic()) {
+        pass();
+        run_from(CURRENT.load(Ordering::Relaxed) + 1);
+    }
+    serial_println!("{}[failed]{}\n", Color::LightRed.ansi(), ANSI_RESET);
+    serial_println!("Error: {}\n", info);
+    serial_println!("{}", registers);
+    let _ = debug::write_backtrace(&mut *serial::SERIAL1.lock());
+    if test.is_some() {
+        FAILED.fetch_add(1, Ordering::Relaxed);
+        let (passed, failed) = summary();
+        serial_println!("{} passed, {} failed", passed, failed);
+    }
+    exit_qemu(QemuExitCode::Failed);
+    crate::hlt_loop();
+}
+
+/// Whether a test with this deadline has run out of time at `now`.
+fn expired(deadline: u64, now: u64) -> bool {
+    deadline != 0 && now >= deadline
+}
+
+/// Called from the timer interrupt: fails the run if the current test is
+/// past its deadline.
+pub fn check_timeout() {
+    if !expired(DEADLINE.load(Ordering::Acquire), time::ticks()) {
+        return;
+    }
+    DEADLINE.store(0, Ordering::Release);
+    FAILED.fetch_add(1, Ordering::Relaxed);
+    let (passed, failed) = summary();
+    // the hung test may be holding the serial lock
+    let _ = write!(serial::RawSerial, "{}[timeout]{}\n{} passed, {} failed\n", Color::LightRed.ansi(), ANSI_RESET, passed, failed);
+    exit_qemu(QemuExitCode::Failed);
+    crate::hlt_loop();
+}
+
+//test case
+#[test_case]
+fn test_deadlines() {
+    assert!(!expired(0, u64::MAX));
+    assert!(!expired(100, 99));
+    assert!(expired(100, 100));
+    assert!(expired(100, 250));
+    // the runner armed one for this test
+    let deadline = DEADLINE.load(Ordering::Acquire);
+    assert!(deadline > time::ticks());
+    assert_eq!(current().map(|test| test.name()), Some("tutorial_os::testing::test_deadlines"));
+}
+
+crate::should_panic! {
+    fn test_should_panic_passes_by_panicking() {
+        let empty: &[u8] = &[];
+        let _ = empty[core::hint::black_box(0)];
+    }
+}
+
+#[test_case]
+fn test_runs_after_a_should_panic_test() {
+    // the test before this one ended in the panic handler
+    assert!(PASSED.load(Ordering::Relaxed) >= 1);
+    assert_eq!(FAILED.load(Ordering::Relaxed), 0);
+}
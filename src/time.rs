@@ -0,0 +1,565 @@
+//! System tick counter driven by the timer interrupt, plus a TSC-based
+//! high-resolution clock once `calibrate_tsc` has measured its rate, and
+//! kernel callbacks the timer interrupt runs periodically.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+/// The PIT's input clock.
+pub const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+/// The tick rate `init_pit` asks for.
+pub const TICK_HZ: u64 = 100;
+/// Input clocks per tick. 100 does not divide 1193182, so the divisor is
+/// rounded to the nearest integer, 11932, and the timer really runs at
+/// 1193182 / 11932 ≈ 99.9985 Hz, 15 ppm slow. Conversions below use
+/// `PIT_FREQUENCY_HZ / PIT_DIVISOR`, the real rate, never `TICK_HZ`.
+pub const PIT_DIVISOR: u64 = (PIT_FREQUENCY_HZ + TICK_HZ / 2) / TICK_HZ;
+// channel 0 takes a 16-bit count, with 0 meaning 65536
+const _: () = assert!(PIT_DIVISOR >= 2 && PIT_DIVISOR <= 65536);
+
+/// Programs PIT channel 0 to raise IRQ 0 every `PIT_DIVISOR` clocks.
+pub fn init_pit() {
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel0 = Port::<u8>::new(0x40);
+    interrupts::without_interrupts(|| unsafe {
+        // channel 0, lobyte/hibyte, mode 3 (square wave)
+        command.write(0b0011_0110);
+        channel0.write(PIT_DIVISOR as u8);
+        channel0.write((PIT_DIVISOR >> 8) as u8);
+    });
+}
+
+pub const fn secs_to_ticks(secs: u64) -> u64 {
+    secs * PIT_FREQUENCY_HZ / PIT_DIVISOR
+}
+
+/// Whole ticks covering at least `millis` milliseconds.
+pub const fn millis_to_ticks(millis: u64) -> u64 {
+    (millis * PIT_FREQUENCY_HZ).div_ceil(PIT_DIVISOR * 1000)
+}
+
+pub fn ticks_to_millis(ticks: u64) -> u64 {
+    ticks * PIT_DIVISOR * 1000 / PIT_FREQUENCY_HZ
+}
+
+/// Whole ticks covering at least `nanos` nanoseconds.
+pub fn nanos_to_ticks(nanos: u64) -> u64 {
+    (u128::from(nanos) * u128::from(PIT_FREQUENCY_HZ)).div_ceil(u128::from(PIT_DIVISOR) * 1_000_000_000) as u64
+}
+
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    (u128::from(ticks) * u128::from(PIT_DIVISOR) * 1_000_000_000 / u128::from(PIT_FREQUENCY_HZ)) as u64
+}
+
+/// Whole ticks covering at least `duration`.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    nanos_to_ticks(u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX))
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Called from the timer interrupt handler only. Takes no lock, so it can
+/// interrupt anything.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Milliseconds since the timer started, at tick resolution.
+pub fn uptime_ms() -> u64 {
+    ticks_to_millis(ticks())
+}
+
+/// Time since the timer started, at tick resolution.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(ticks_to_nanos(ticks()))
+}
+
+/// Halts for at least `ms` milliseconds, like `sleep`.
+pub fn sleep_ms(ms: u64) {
+    sleep_ticks(millis_to_ticks(ms));
+}
+
+/// Halts for at least `duration`. Tasks on the executor wait with
+/// `task::timer::sleep_for` instead, which lets the others run.
+pub fn sleep(duration: Duration) {
+    sleep_ticks(duration_to_ticks(duration));
+}
+
+/// The deadline is checked with interrupts off and each halt turns them
+/// on, so the tick that ends the sleep cannot slip in between; a caller
+/// that had them off gets them back off afterwards, but they are on while
+/// it sleeps.
+fn sleep_ticks(count: u64) {
+    // +1: the next tick may be about to arrive
+    let end = ticks() + count + 1;
+    let were_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    while ticks() < end {
+        crate::cpu::enable_and_halt();
+        interrupts::disable();
+    }
+    if were_enabled {
+        interrupts::enable();
+    }
+}
+
+// ==========================================================
+// TSC
+// ==========================================================
+
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Calibration windows and their length in PIT input clocks (10 ms).
+const CALIBRATION_WINDOWS: usize = 5;
+const WINDOW_PIT_CLOCKS: u16 = 11_932;
+/// Windows may differ by at most this much from the median (SMIs and
+/// host preemption make single windows run long).
+const MAX_SPREAD_PPM: u64 = 5_000;
+
+/// TSC rate in Hz, 0 while uncalibrated.
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// TSC value at calibration and the tick count it corresponds to, so
+/// `now_ns` continues where tick-based time left off.
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+static TSC_BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationError {
+    /// CPUID does not report an invariant TSC, so its rate may change
+    /// with power states.
+    NotInvariant,
+    /// PIT channel 2 never reached terminal count.
+    NoPit,
+    /// The windows disagreed by more than `MAX_SPREAD_PPM`.
+    Unstable { spread_ppm: u64 },
+}
+
+impl core::fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            CalibrationError::NotInvariant => f.write_str("TSC is not invariant"),
+            CalibrationError::NoPit => f.write_str("PIT channel 2 did not respond"),
+            CalibrationError::Unstable { spread_ppm } => {
+                write!(f, "calibration windows spread by {} ppm", spread_ppm)
+            }
+        }
+    }
+}
+
+/// TSC rate in Hz, once calibrated.
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Whole TSC cycles per microsecond, once calibrated.
+pub fn tsc_cycles_per_us() -> Option<u64> {
+    tsc_frequency().map(|hz| hz / 1_000_000)
+}
+
+pub fn cycles_to_nanos(cycles: u64, hz: u64) -> u64 {
+    (u128::from(cycles) * 1_000_000_000 / u128::from(hz)) as u64
+}
+
+pub fn nanos_to_cycles(nanos: u64, hz: u64) -> u64 {
+    (u128::from(nanos) * u128::from(hz) / 1_000_000_000) as u64
+}
+
+fn invariant_tsc() -> bool {
+    crate::cpu::has(crate::cpu::Feature::InvariantTsc)
+}
+
+/// Counts TSC cycles while PIT channel 2 counts down `clocks` input
+/// clocks in one-shot mode. The speaker stays off. Needs no interrupts.
+fn measure_window(clocks: u16) -> Option<u64> {
+    let mut control = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+    unsafe {
+        let saved = control.read();
+        // gate on, speaker off
+        control.write((saved & !0x02) | 0x01);
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel2.write(clocks as u8);
+        channel2.write((clocks >> 8) as u8);
+        let start = rdtsc();
+        let mut polls = 0u32;
+        // OUT2 goes high at terminal count
+        while control.read() & 0x20 == 0 {
+            polls += 1;
+            if polls > 10_000_000 {
+                control.write(saved);
+                return None;
+            }
+        }
+        let cycles = rdtsc() - start;
+        control.write(saved);
+        Some(cycles)
+    }
+}
+
+/// Median of `samples` (sorted in place); for an even count, the lower of
+/// the two middle values.
+pub fn median(samples: &mut [u64]) -> u64 {
+    samples.sort_unstable();
+    samples[(samples.len() - 1) / 2]
+}
+
+/// Largest distance of any sample from `median`, in parts per million
+/// of the median.
+pub fn spread_ppm(samples: &[u64], median: u64) -> u64 {
+    let worst = samples.iter().map(|&s| s.abs_diff(median)).max().unwrap_or(0);
+    (u128::from(worst) * 1_000_000 / u128::from(median.max(1))) as u64
+}
+
+/// TSC rate from per-window cycle counts, each window `clocks` PIT input
+/// clocks long.
+pub fn frequency_from_samples(samples: &mut [u64], clocks: u16) -> Result<u64, CalibrationError> {
+    let median = median(samples);
+    let spread_ppm = spread_ppm(samples, median);
+    if spread_ppm > MAX_SPREAD_PPM {
+        return Err(CalibrationError::Unstable { spread_ppm });
+    }
+    Ok((u128::from(median) * u128::from(PIT_FREQUENCY_HZ) / u128::from(clocks)) as u64)
+}
+
+fn measure_frequency() -> Result<u64, CalibrationError> {
+    let mut samples = [0u64; CALIBRATION_WINDOWS];
+    for sample in samples.iter_mut() {
+        *sample = measure_window(WINDOW_PIT_CLOCKS).ok_or(CalibrationError::NoPit)?;
+    }
+    frequency_from_samples(&mut samples, WINDOW_PIT_CLOCKS)
+}
+
+/// Measures the TSC against the PIT. On failure time keeps coming from
+/// the tick counter.
+pub fn calibrate_tsc() -> Result<u64, CalibrationError> {
+    if !invariant_tsc() {
+        return Err(CalibrationError::NotInvariant);
+    }
+    let hz = measure_frequency()?;
+    TSC_BASE_NS.store(ticks_to_nanos(ticks()), Ordering::Relaxed);
+    TSC_BASE.store(rdtsc(), Ordering::Relaxed);
+    TSC_HZ.store(hz, Ordering::Release);
+    Ok(hz)
+}
+
+/// Nanoseconds since boot: from the TSC once calibrated, otherwise at
+/// tick resolution (10 ms).
+pub fn now_ns() -> u64 {
+    match TSC_HZ.load(Ordering::Acquire) {
+        0 => ticks_to_nanos(ticks()),
+        hz => {
+            let elapsed = rdtsc().saturating_sub(TSC_BASE.load(Ordering::Relaxed));
+            TSC_BASE_NS.load(Ordering::Relaxed) + cycles_to_nanos(elapsed, hz)
+        }
+    }
+}
+
+/// Waits at least `us` microseconds. Spins on the TSC when calibrated;
+/// otherwise halts for whole ticks, which needs interrupts enabled.
+pub fn delay_us(us: u64) {
+    match tsc_frequency() {
+        Some(hz) => {
+            let start = rdtsc();
+            let cycles = nanos_to_cycles(us * 1000, hz);
+            while rdtsc() - start < cycles {
+                core::hint::spin_loop();
+            }
+        }
+        None => {
+            let ticks_needed = (us * PIT_FREQUENCY_HZ).div_ceil(PIT_DIVISOR * 1_000_000);
+            // +1: the first tick may arrive right away
+            let end = ticks() + ticks_needed + 1;
+            while ticks() < end {
+                x86_64::instructions::hlt();
+            }
+        }
+    }
+}
+
+/// Longest single countdown on channel 2. A count of 0 would mean 65536,
+/// but chunks never hold 0 so the maximum stays representable.
+const MAX_PIT_CHUNK: u16 = u16::MAX;
+
+/// PIT input clocks covering at least `us` microseconds.
+pub fn pit_clocks_for_us(us: u64) -> u64 {
+    (u128::from(us) * u128::from(PIT_FREQUENCY_HZ)).div_ceil(1_000_000) as u64
+}
+
+/// Splits `clocks` into one-shot counts: as many full chunks as fit, then
+/// the remainder, never a zero count.
+pub fn pit_chunks(clocks: u64) -> impl Iterator<Item = u16> {
+    let full = clocks / u64::from(MAX_PIT_CHUNK);
+    let rest = (clocks % u64::from(MAX_PIT_CHUNK)) as u16;
+    (0..full).map(|_| MAX_PIT_CHUNK).chain((rest > 0).then_some(rest))
+}
+
+/// Waits at least `us` microseconds by counting down PIT channel 2, one
+/// chunk at a time. Works with interrupts disabled and before the TSC is
+/// calibrated; channel 0 and the speaker are left alone and port 0x61 is
+/// restored after every chunk. Returns `false` if the PIT never answered.
+pub fn pit_delay_us(us: u64) -> bool {
+    pit_chunks(pit_clocks_for_us(us)).all(|chunk| measure_window(chunk).is_some())
+}
+
+/// Measures the TSC again and prints how far it has drifted from the
+/// boot calibration.
+pub fn report_drift() {
+    let Some(calibrated) = tsc_frequency() else {
+        crate::println!("TSC: not calibrated, using timer ticks");
+        return;
+    };
+    match measure_frequency() {
+        Ok(hz) => {
+            let drift = (i128::from(hz) - i128::from(calibrated)) * 1_000_000 / i128::from(calibrated);
+            crate::println!("TSC: {} Hz at boot, {} Hz now ({:+} ppm)", calibrated, hz, drift);
+        }
+        Err(err) => crate::println!("TSC: recalibration failed: {}", err),
+    }
+}
+
+// ==========================================================
+// PERIODIC CALLBACKS
+// ==========================================================
+
+/// Callbacks that can be registered at once.
+pub const MAX_PERIODIC: usize = 8;
+
+struct Periodic {
+    /// Taken by a registration, before the callback is stored.
+    claimed: AtomicBool,
+    /// The `fn()`, 0 while the slot runs nothing.
+    callback: AtomicUsize,
+    period: AtomicU64,
+    /// Tick the callback runs next at.
+    next: AtomicU64,
+    /// Moves on at every cancel, so an old id can't cancel whoever took
+    /// the slot after it.
+    generation: AtomicU64,
+}
+
+impl Periodic {
+    const fn new() -> Self {
+        Periodic {
+            claimed: AtomicBool::new(false),
+            callback: AtomicUsize::new(0),
+            period: AtomicU64::new(0),
+            next: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+        }
+    }
+}
+
+static PERIODIC: [Periodic; MAX_PERIODIC] = [const { Periodic::new() }; MAX_PERIODIC];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeriodicId {
+    index: usize,
+    generation: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeriodicError {
+    /// `MAX_PERIODIC` callbacks are registered already.
+    Full,
+    ZeroPeriod,
+}
+
+impl core::fmt::Display for PeriodicError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            PeriodicError::Full => write!(f, "all {} periodic callbacks are taken", MAX_PERIODIC),
+            PeriodicError::ZeroPeriod => f.write_str("the period is zero"),
+        }
+    }
+}
+
+/// Runs `callback` from the timer interrupt every `period`, rounded up to
+/// whole ticks, the first time one period from now. It runs with
+/// interrupts off before the EOI, so it must be short and must not block
+/// or allocate.
+pub fn register_periodic(period: Duration, callback: fn()) -> Result<PeriodicId, PeriodicError> {
+    let period = duration_to_ticks(period);
+    if period == 0 {
+        return Err(PeriodicError::ZeroPeriod);
+    }
+    let index = PERIODIC
+        .iter()
+        .position(|slot| slot.claimed.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok())
+        .ok_or(PeriodicError::Full)?;
+    let slot = &PERIODIC[index];
+    slot.period.store(period, Ordering::Relaxed);
+    slot.next.store(ticks() + period, Ordering::Relaxed);
+    slot.callback.store(callback as *const () as usize, Ordering::Release);
+    Ok(PeriodicId { index, generation: slot.generation.load(Ordering::Relaxed) })
+}
+
+/// Stops a callback. Once this returns it does not run again. Returns
+/// `false` if `id` was cancelled already, even if its slot runs another
+/// callback now.
+pub fn cancel_periodic(id: PeriodicId) -> bool {
+    let slot = &PERIODIC[id.index];
+    if slot.generation.compare_exchange(id.generation, id.generation + 1, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+        return false;
+    }
+    slot.callback.store(0, Ordering::Release);
+    slot.claimed.store(false, Ordering::Release);
+    true
+}
+
+/// Called from the timer interrupt after `tick`. A callback that fell
+/// more than a period behind runs once, not once for every period missed.
+pub(crate) fn run_periodic() {
+    let now = ticks();
+    for slot in &PERIODIC {
+        let callback = slot.callback.load(Ordering::Acquire);
+        let next = slot.next.load(Ordering::Relaxed);
+        if callback == 0 || now < next {
+            continue;
+        }
+        let period = slot.period.load(Ordering::Relaxed);
+        slot.next.store(if now - next >= period { now + period } else { next + period }, Ordering::Relaxed);
+        let callback: fn() = unsafe { core::mem::transmute(callback) };
+        callback();
+    }
+}
+
+//test case
+#[test_case]
+fn test_tick_conversions() {
+    assert_eq!(PIT_DIVISOR, 11932);
+    // 15 ppm slow: ten seconds are a hair short of 1000 ticks
+    assert_eq!(secs_to_ticks(10), 999);
+    assert_eq!(ticks_to_millis(182), 1820);
+    assert_eq!(ticks_to_millis(0), 0);
+    assert_eq!(ticks_to_nanos(1), 10_000_150);
+    assert_eq!(nanos_to_ticks(0), 0);
+    assert_eq!(nanos_to_ticks(1), 1);
+    assert_eq!(nanos_to_ticks(10_000_150), 1);
+    assert_eq!(nanos_to_ticks(10_000_151), 2);
+    assert_eq!(nanos_to_ticks(10_000_000_000), 1000);
+    assert_eq!(millis_to_ticks(0), 0);
+    assert_eq!(millis_to_ticks(10), 1);
+    assert_eq!(millis_to_ticks(500), 50);
+    assert_eq!(duration_to_ticks(Duration::from_millis(500)), 50);
+    assert_eq!(duration_to_ticks(Duration::from_nanos(1)), 1);
+    assert_eq!(duration_to_ticks(Duration::MAX), nanos_to_ticks(u64::MAX));
+}
+
+#[test_case]
+fn test_calibration_median_rejects_outliers() {
+    // one window stretched by an SMI
+    let mut samples = [20_000_100, 20_000_000, 23_500_000, 19_999_900, 20_000_050];
+    assert_eq!(median(&mut samples.clone()), 20_000_050);
+    assert!(matches!(
+        frequency_from_samples(&mut samples, WINDOW_PIT_CLOCKS),
+        Err(CalibrationError::Unstable { .. })
+    ));
+
+    // 2 GHz measured over 10 ms windows
+    let mut samples = [20_002_000, 20_001_000, 20_000_500, 20_003_000, 20_001_500];
+    let hz = frequency_from_samples(&mut samples, WINDOW_PIT_CLOCKS).unwrap();
+    assert!(hz.abs_diff(2_000_000_000) < 1_000_000, "{} Hz", hz);
+    assert_eq!(spread_ppm(&[100, 101, 99], 100), 10_000);
+}
+
+#[test_case]
+fn test_cycle_conversions() {
+    assert_eq!(cycles_to_nanos(3_000_000_000, 3_000_000_000), 1_000_000_000);
+    assert_eq!(cycles_to_nanos(2_500, 2_500_000_000), 1_000);
+    assert_eq!(nanos_to_cycles(1_000, 2_500_000_000), 2_500);
+    assert_eq!(nanos_to_cycles(u64::MAX / 4, 4_000_000_000), u64::MAX / 4 * 4);
+}
+
+#[test_case]
+fn test_pit_delay_chunks() {
+    assert_eq!(pit_clocks_for_us(0), 0);
+    // 1 µs is just over one clock, so it rounds up to two
+    assert_eq!(pit_clocks_for_us(1), 2);
+    assert_eq!(pit_clocks_for_us(1_000_000), PIT_FREQUENCY_HZ);
+    assert_eq!(pit_clocks_for_us(10_000), 11_932);
+
+    assert_eq!(pit_chunks(0).count(), 0);
+    assert!(pit_chunks(100).eq([100]));
+    assert!(pit_chunks(65_535).eq([65_535]));
+    assert!(pit_chunks(65_536).eq([65_535, 1]));
+    // a second: 18 full chunks and the rest, nothing lost
+    let chunks = pit_chunks(PIT_FREQUENCY_HZ);
+    assert_eq!(chunks.map(u64::from).sum::<u64>(), PIT_FREQUENCY_HZ);
+    assert_eq!(pit_chunks(PIT_FREQUENCY_HZ).count(), 19);
+    assert!(pit_chunks(PIT_FREQUENCY_HZ).all(|chunk| chunk > 0));
+}
+
+#[test_case]
+fn test_sleep_ms() {
+    let before = ticks();
+    let start = uptime_ms();
+    sleep_ms(30);
+    assert!(ticks() >= before + millis_to_ticks(30), "{} -> {}", before, ticks());
+    assert!(uptime_ms() - start >= 30);
+    assert!(interrupts::are_enabled());
+    // off before, off after
+    interrupts::disable();
+    sleep_ms(10);
+    assert!(!interrupts::are_enabled());
+    interrupts::enable();
+
+    let before = uptime();
+    sleep(Duration::from_millis(20));
+    assert!(uptime() - before >= Duration::from_millis(20));
+}
+
+#[cfg(test)]
+static PERIODIC_RUNS: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn test_periodic_callbacks() {
+    fn count() {
+        PERIODIC_RUNS.fetch_add(1, Ordering::Relaxed);
+    }
+    fn nothing() {}
+    assert_eq!(register_periodic(Duration::ZERO, count), Err(PeriodicError::ZeroPeriod));
+    PERIODIC_RUNS.store(0, Ordering::Relaxed);
+    let id = register_periodic(Duration::from_millis(20), count).unwrap();
+    sleep_ms(200);
+    let runs = PERIODIC_RUNS.load(Ordering::Relaxed);
+    assert!((8..=11).contains(&runs), "{} runs", runs);
+    assert!(cancel_periodic(id));
+    assert!(!cancel_periodic(id));
+    sleep_ms(50);
+    assert_eq!(PERIODIC_RUNS.load(Ordering::Relaxed), runs);
+
+    // the slots are reused once cancelled
+    let ids: alloc::vec::Vec<_> = core::iter::from_fn(|| register_periodic(Duration::from_secs(60), nothing).ok()).collect();
+    assert!(!ids.is_empty() && ids.len() <= MAX_PERIODIC);
+    assert_eq!(register_periodic(Duration::from_secs(60), nothing), Err(PeriodicError::Full));
+    for id in ids {
+        assert!(cancel_periodic(id));
+    }
+}
+
+#[test_case]
+fn test_cancel_after_the_slot_is_reused() {
+    fn nothing() {}
+    let old = register_periodic(Duration::from_secs(60), nothing).unwrap();
+    assert!(cancel_periodic(old));
+    let new = register_periodic(Duration::from_secs(60), nothing).unwrap();
+    assert_eq!(new.index, old.index);
+    // the old id is stale and leaves the new callback alone
+    assert!(!cancel_periodic(old));
+    assert_ne!(PERIODIC[new.index].callback.load(Ordering::Relaxed), 0);
+    assert!(cancel_periodic(new));
+}
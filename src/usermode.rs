@@ -0,0 +1,405 @@
+//! Ring 3 building blocks for `process`.
+//!
+//! Each program gets a fresh address space: a new PML4 that shares every
+//! kernel entry and adds the user region, `USER_START` up to `USER_END`
+//! (one PML4 slot). The ELF is loaded there with USER_ACCESSIBLE pages
+//! next to a stack, and `enter_user` `iretq`s to it. Programs talk to the
+//! kernel through `syscall` or `int 0x80` (see `syscall`, and `usys` for
+//! programs that use it); once no process is left to run,
+//! `return_to_kernel` resumes whoever called `enter_user`.
+//!
+//! Kernel mappings added while a program runs must go into PML4 slots the
+//! kernel already uses, or they vanish with the address space.
+//!
+//! `fork` clones a user region without copying it: both address spaces map
+//! the same frames, counted in `memory::frame_refs`, and the writable ones
+//! become read-only and `memory::COPY_ON_WRITE` in both. The first write
+//! to such a page faults, and `handle_cow_fault` gives the writer its own
+//! copy, or the frame itself once nobody else maps it. A process only runs
+//! on the CPU that called `process::run`, so flushing that CPU's TLB is
+//! enough.
+
+use core::fmt;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::page::PageRangeInclusive;
+use x86_64::structures::paging::{FrameAllocator, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+use crate::elf;
+use crate::process::{self, SpawnError, State};
+use crate::{loader, memory};
+
+pub mod demo;
+pub mod usys;
+
+/// Start of the user region: PML4 slot 128, which the kernel leaves empty.
+pub const USER_START: u64 = 0x0000_4000_0000_0000;
+pub const USER_END: u64 = USER_START + (1 << 39);
+const USER_SLOT: usize = 128;
+
+/// Top of the user stack. The page right below the stack is left
+/// unmapped, so an overflow faults instead of running into other data.
+pub const STACK_TOP: u64 = USER_END - 0x10_0000;
+const STACK_PAGES: u64 = 4;
+
+/// RFLAGS for ring 3: only IF, so the timer keeps ticking.
+pub(crate) const USER_RFLAGS: u64 = 0x202;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserError {
+    Spawn(SpawnError),
+    /// Processes are already running.
+    Busy,
+    /// The program was killed, by an exception or `kill`.
+    Fault,
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UserError::Spawn(err) => write!(f, "{}", err),
+            UserError::Busy => f.write_str("processes are already running"),
+            UserError::Fault => f.write_str("killed"),
+        }
+    }
+}
+
+/// Runs the embedded demo program, which prints `demo::MESSAGE` and exits
+/// with `demo::EXIT_CODE`.
+pub fn run_demo() -> Result<u64, UserError> {
+    run(&demo::PROGRAM)
+}
+
+/// Round-trip cost of a system call in TSC cycles, through `syscall` and
+/// through `int 0x80`: the best of `runs` runs of the benchmark program.
+pub fn benchmark_syscalls(runs: u32) -> Result<(u64, u64), UserError> {
+    let measure = |fast| -> Result<u64, UserError> {
+        let program = demo::syscall_benchmark(fast);
+        let mut best = u64::MAX;
+        for _ in 0..runs {
+            best = best.min(run(&program)? / u64::from(demo::BENCHMARK_CALLS));
+        }
+        Ok(best)
+    };
+    Ok((measure(true)?, measure(false)?))
+}
+
+/// Spawns `image` as a process and runs the scheduler until every ready
+/// process is done. Returns the program's exit code.
+pub fn run(image: &[u8]) -> Result<u64, UserError> {
+    let pid = process::spawn_from_elf(image).map_err(UserError::Spawn)?;
+    if !process::run() {
+        let _ = process::kill(pid);
+        return Err(UserError::Busy);
+    }
+    match process::state(pid) {
+        Some(State::Exited(code)) => Ok(code),
+        _ => Err(UserError::Fault),
+    }
+}
+
+/// Checks `file` fits the user region below the stack.
+pub(crate) fn check_layout(file: &elf::ElfFile) -> Result<(), SpawnError> {
+    let inside = |s: &elf::Segment| s.vaddr >= USER_START && s.vaddr + s.mem_size <= STACK_TOP - (STACK_PAGES + 1) * 4096;
+    if file.kind != elf::Kind::Exec || !file.segments.iter().all(inside) {
+        return Err(SpawnError::OutsideUserRegion);
+    }
+    Ok(())
+}
+
+/// Maps `file` and a stack into `space` and returns the entry point.
+/// Switches to `space` for the copy, so it must be called with interrupts
+/// disabled.
+pub(crate) fn load(space: &AddressSpace, file: &elf::ElfFile) -> Result<VirtAddr, SpawnError> {
+    let (kernel_l4, cr3_flags) = Cr3::read();
+    unsafe { Cr3::write(space.l4, cr3_flags) };
+    let entry = memory::with_paging(|mapper, frame_allocator| {
+        let loaded = elf::load_with_flags(file, 0, PageTableFlags::USER_ACCESSIBLE, mapper, frame_allocator)
+            .map_err(SpawnError::Elf)?;
+        let top = Page::containing_address(VirtAddr::new(STACK_TOP));
+        let stack_flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+        loader::map_zeroed(Page::range_inclusive(top - STACK_PAGES, top - 1), stack_flags, mapper, frame_allocator)
+            .map_err(|_| SpawnError::OutOfMemory)?;
+        Ok(loaded.entry)
+    })
+    .unwrap_or(Err(SpawnError::NoPaging));
+    unsafe { Cr3::write(kernel_l4, cr3_flags) };
+    entry
+}
+
+/// A PML4 sharing the kernel's entries, with its own user slot.
+pub(crate) struct AddressSpace {
+    l4: PhysFrame,
+}
+
+impl AddressSpace {
+    pub(crate) fn new() -> Result<AddressSpace, SpawnError> {
+        let offset = memory::physical_memory_offset().ok_or(SpawnError::NoPaging)?;
+        let (current, _) = Cr3::read();
+        // in a system call the active tables are the caller's, which has
+        // the slot to itself
+        if process::current().is_none() && !table(offset, current)[USER_SLOT].is_unused() {
+            return Err(SpawnError::RegionInUse);
+        }
+        let l4 = memory::with_paging(|_, frame_allocator| loader::allocate_frame(frame_allocator))
+            .ok_or(SpawnError::NoPaging)?
+            .ok_or(SpawnError::OutOfMemory)?;
+        let (kernel, new) = (table(offset, current), table(offset, l4));
+        for (dest, src) in new.iter_mut().zip(kernel.iter()) {
+            *dest = src.clone();
+        }
+        new[USER_SLOT].set_unused();
+        Ok(AddressSpace { l4 })
+    }
+
+    /// A copy-on-write clone of the active address space, for `fork`.
+    /// Needs interrupts disabled.
+    pub(crate) fn clone_active() -> Result<AddressSpace, SpawnError> {
+        let offset = memory::physical_memory_offset().ok_or(SpawnError::NoPaging)?;
+        let space = AddressSpace::new()?;
+        let (current, _) = Cr3::read();
+        let source = table(offset, current)[USER_SLOT].clone();
+        let Ok(l3) = source.frame() else { return Ok(space) };
+        let copy = memory::with_paging(|_, frame_allocator| clone_tree(offset, l3, 3, frame_allocator))
+            .unwrap_or(Err(SpawnError::NoPaging));
+        // the caller's writable pages may just have become read-only
+        tlb::flush_all();
+        match copy {
+            Ok(copy) => {
+                table(offset, space.l4)[USER_SLOT].set_frame(copy, source.flags());
+                Ok(space)
+            }
+            Err(err) => {
+                space.destroy();
+                Err(err)
+            }
+        }
+    }
+
+    pub(crate) fn activate(&self) {
+        let (_, cr3_flags) = Cr3::read();
+        unsafe { Cr3::write(self.l4, cr3_flags) };
+    }
+
+    /// Gives every frame of the user region back to the loader, page
+    /// tables included. The address space must not be active.
+    pub(crate) fn destroy(self) {
+        let Some(offset) = memory::physical_memory_offset() else { return };
+        if let Ok(l3) = table(offset, self.l4)[USER_SLOT].frame() {
+            free_tree(offset, l3, 3);
+        }
+        loader::recycle_frame(self.l4);
+    }
+}
+
+fn table(offset: VirtAddr, frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *(offset + frame.start_address().as_u64()).as_mut_ptr::<PageTable>() }
+}
+
+/// Recycles the level-`level` table in `frame` and everything it maps,
+/// except pages another address space still maps.
+fn free_tree(offset: VirtAddr, frame: PhysFrame, level: u8) {
+    for entry in table(offset, frame).iter() {
+        if let Ok(child) = entry.frame() {
+            if level > 1 {
+                free_tree(offset, child, level - 1);
+            } else if memory::release_frame(child) {
+                loader::recycle_frame(child);
+            }
+        }
+    }
+    loader::recycle_frame(frame);
+}
+
+/// Copies the level-`level` table in `frame` and the tables below it; the
+/// pages themselves are shared, the writable ones as copy-on-write in
+/// both trees. On failure nothing is left of the copy.
+fn clone_tree(
+    offset: VirtAddr,
+    frame: PhysFrame,
+    level: u8,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<PhysFrame, SpawnError> {
+    let copy = loader::allocate_frame(frame_allocator).ok_or(SpawnError::OutOfMemory)?;
+    table(offset, copy).zero();
+    for (i, source) in table(offset, frame).iter_mut().enumerate() {
+        let Ok(child) = source.frame() else { continue };
+        let dest = &mut table(offset, copy)[i];
+        if level > 1 {
+            match clone_tree(offset, child, level - 1, frame_allocator) {
+                Ok(child) => dest.set_frame(child, source.flags()),
+                Err(err) => {
+                    free_tree(offset, copy, level);
+                    return Err(err);
+                }
+            }
+        } else {
+            let mut flags = source.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | memory::COPY_ON_WRITE;
+                source.set_flags(flags);
+            }
+            memory::share_frame(child);
+            dest.set_frame(child, flags);
+        }
+    }
+    Ok(copy)
+}
+
+/// The level 1 entry for `page` in the active address space.
+fn leaf_entry(offset: VirtAddr, page: Page) -> Option<&'static mut PageTableEntry> {
+    let (l4, _) = Cr3::read();
+    let l3 = table(offset, l4)[usize::from(page.p4_index())].frame().ok()?;
+    let l2 = table(offset, l3)[usize::from(page.p3_index())].frame().ok()?;
+    let l1 = table(offset, l2)[usize::from(page.p2_index())].frame().ok()?;
+    Some(&mut table(offset, l1)[usize::from(page.p1_index())])
+}
+
+/// Makes the copy-on-write `page` of the active address space writable,
+/// on a copy of its frame unless nobody else maps it anymore. `false` if
+/// the page is not copy-on-write or no frame is left for the copy.
+fn break_cow(page: Page, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> bool {
+    let Some(offset) = memory::physical_memory_offset() else { return false };
+    let Some(entry) = leaf_entry(offset, page) else { return false };
+    let Ok(frame) = entry.frame() else { return false };
+    if !entry.flags().contains(memory::COPY_ON_WRITE) {
+        return false;
+    }
+    let flags = (entry.flags() - memory::COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if memory::frame_refs(frame) == 1 {
+        entry.set_flags(flags);
+    } else {
+        let Some(copy) = loader::allocate_frame(frame_allocator) else { return false };
+        let (from, to) = (offset + frame.start_address().as_u64(), offset + copy.start_address().as_u64());
+        unsafe { core::ptr::copy_nonoverlapping(from.as_ptr::<u8>(), to.as_mut_ptr::<u8>(), 4096) };
+        entry.set_frame(copy, flags);
+        // the others may have let go of it since the count was read
+        if memory::release_frame(frame) {
+            loader::recycle_frame(frame);
+        }
+    }
+    tlb::flush(page.start_address());
+    true
+}
+
+/// Called by the page fault handler: resolves a write to a copy-on-write
+/// page of the user region. Returns `true` if the write can run again.
+pub(crate) fn handle_cow_fault(addr: VirtAddr, code: PageFaultErrorCode) -> bool {
+    let write = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !code.contains(write) || addr.as_u64() < USER_START || addr.as_u64() >= USER_END {
+        return false;
+    }
+    let page = Page::containing_address(addr);
+    // ring 3 holds no kernel lock, so it can wait for the page tables
+    let done = if code.contains(PageFaultErrorCode::USER_MODE) {
+        memory::with_paging(|_, frame_allocator| break_cow(page, frame_allocator))
+    } else {
+        memory::try_with_paging(|_, frame_allocator| break_cow(page, frame_allocator))
+    };
+    done.unwrap_or(false)
+}
+
+/// Whether the `len` bytes at `ptr` lie in the user region and are mapped
+/// with at least `needed` in the active address space.
+fn user_accessible(ptr: u64, len: u64, needed: PageTableFlags) -> bool {
+    let Some(end) = ptr.checked_add(len) else { return false };
+    if ptr < USER_START || end > USER_END {
+        return false;
+    }
+    if len == 0 {
+        return true;
+    }
+    let needed = needed | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    user_pages(ptr, len)
+        .into_iter()
+        .all(|page| memory::page_flags(page.start_address()).is_some_and(|flags| flags.contains(needed)))
+}
+
+/// The pages under `len` bytes at `ptr`, for a `len` of at least 1.
+fn user_pages(ptr: u64, len: u64) -> PageRangeInclusive {
+    let first = Page::containing_address(VirtAddr::new(ptr));
+    let last = Page::containing_address(VirtAddr::new(ptr + len - 1));
+    Page::range_inclusive(first, last)
+}
+
+/// The `len` bytes at `ptr` if they lie in the user region and are mapped
+/// for ring 3 in the active address space.
+pub fn user_slice(ptr: u64, len: u64) -> Option<&'static [u8]> {
+    user_accessible(ptr, len, PageTableFlags::empty())
+        .then(|| unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+/// Like `user_slice`, for memory ring 3 may also write. Copy-on-write
+/// pages there become the process's own first.
+pub fn user_slice_mut(ptr: u64, len: u64) -> Option<&'static mut [u8]> {
+    if len > 0 && user_accessible(ptr, len, PageTableFlags::empty()) {
+        for page in user_pages(ptr, len) {
+            if memory::page_flags(page.start_address()).is_some_and(|flags| flags.contains(memory::COPY_ON_WRITE)) {
+                memory::with_paging(|_, frame_allocator| break_cow(page, frame_allocator));
+            }
+        }
+    }
+    user_accessible(ptr, len, PageTableFlags::WRITABLE)
+        .then(|| unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) })
+}
+
+/// `enter_user`'s stack pointer after it saved the kernel's registers.
+static mut KERNEL_RSP: u64 = 0;
+
+/// Saves the callee-saved registers and RFLAGS, then drops to ring 3 at
+/// `entry` with `stack_top` as RSP. "Returns" when `return_to_kernel`
+/// runs, with its argument.
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn enter_user(entry: u64, stack_top: u64, code_selector: u64, stack_selector: u64) -> u64 {
+    core::arch::naked_asm!(
+        "push rbp", "push rbx", "push r12", "push r13", "push r14", "push r15",
+        "pushfq",
+        "mov [rip + {kernel_rsp}], rsp",
+        // interrupt frame: SS, RSP, RFLAGS, CS, RIP
+        "push rcx",
+        "push rsi",
+        "push {rflags}",
+        "push rdx",
+        "push rdi",
+        // start the program without kernel values in its registers
+        "xor eax, eax", "xor ebx, ebx", "xor ecx, ecx", "xor edx, edx",
+        "xor esi, esi", "xor edi, edi", "xor ebp, ebp",
+        "xor r8d, r8d", "xor r9d, r9d", "xor r10d, r10d", "xor r11d, r11d",
+        "xor r12d, r12d", "xor r13d, r13d", "xor r14d, r14d", "xor r15d, r15d",
+        // ring 3 runs on the program's GS base; no interrupt may see it
+        // before the switch
+        "cli",
+        "swapgs",
+        "iretq",
+        kernel_rsp = sym KERNEL_RSP,
+        rflags = const USER_RFLAGS,
+    );
+}
+
+/// Abandons the current stack (a process's kernel stack) and resumes
+/// after `enter_user` with `status` as its return value.
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn return_to_kernel(status: u64) -> ! {
+    core::arch::naked_asm!(
+        "mov rsp, [rip + {kernel_rsp}]",
+        "mov rax, rdi",
+        "popfq",
+        "pop r15", "pop r14", "pop r13", "pop r12", "pop rbx", "pop rbp",
+        "ret",
+        kernel_rsp = sym KERNEL_RSP,
+    );
+}
+
+//test case
+#[test_case]
+fn test_user_slice_rejects_kernel_addresses() {
+    let kernel = &demo::PROGRAM as *const [u8; 176] as u64;
+    assert_eq!(user_slice(kernel, 4), None);
+    assert_eq!(user_slice(USER_END - 2, 4), None);
+    assert_eq!(user_slice(u64::MAX, 2), None);
+    assert_eq!(user_slice_mut(kernel, 4), None);
+    // nothing runs, so the user region is empty
+    assert_eq!(user_slice(USER_START, 1), None);
+}
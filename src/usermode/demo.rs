@@ -0,0 +1,92 @@
+//! The program `run_demo` runs, as a hand-assembled ELF64: one read/execute
+//! segment at `USER_START` holding the headers, the code and the message.
+//!
+//! ```text
+//! entry:  lea  rsi, [rip + msg]
+//!         mov  edi, 1             ; stdout
+//!         mov  edx, 18
+//!         mov  eax, 1             ; write
+//!         syscall
+//!         mov  edi, 42
+//!         mov  eax, 60            ; exit
+//!         syscall
+//!         ud2
+//! msg:    "hola desde ring 3\n"
+//! ```
+
+pub const EXIT_CODE: u64 = 42;
+pub const MESSAGE: &str = "hola desde ring 3\n";
+
+pub static PROGRAM: [u8; 176] = [
+    0x7f, 0x45, 0x4c, 0x46, 0x02, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x3e, 0x00, 0x01, 0x00, 0x00, 0x00,
+    0x78, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x38, 0x00, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00,
+    0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xb0, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x48, 0x8d, 0x35, 0x1f, 0x00, 0x00, 0x00, 0xbf, 0x01, 0x00, 0x00, 0x00,
+    0xba, 0x12, 0x00, 0x00, 0x00, 0xb8, 0x01, 0x00, 0x00, 0x00, 0x0f, 0x05,
+    0xbf, 0x2a, 0x00, 0x00, 0x00, 0xb8, 0x3c, 0x00, 0x00, 0x00, 0x0f, 0x05,
+    0x0f, 0x0b, 0x68, 0x6f, 0x6c, 0x61, 0x20, 0x64, 0x65, 0x73, 0x64, 0x65,
+    0x20, 0x72, 0x69, 0x6e, 0x67, 0x20, 0x33, 0x0a,
+];
+
+/// Offset of the code in `PROGRAM`, which is also where the entry point
+/// is, and the room up to the end of the segment.
+pub const CODE_OFFSET: usize = 120;
+pub const CODE_ROOM: usize = PROGRAM.len() - CODE_OFFSET;
+
+/// System calls each benchmark run makes.
+pub const BENCHMARK_CALLS: u32 = 10_000;
+
+/// `PROGRAM` with its code replaced by a loop of `BENCHMARK_CALLS` calls
+/// to an unknown system call, through `syscall` or `int 0x80`. It exits
+/// with the TSC cycles the loop took.
+///
+/// ```text
+///         rdtsc
+///         shl  rdx, 32
+///         or   rax, rdx
+///         mov  r13, rax
+///         mov  r12d, BENCHMARK_CALLS
+/// again:  mov  eax, 0xffff        ; ENOSYS
+///         syscall / int 0x80
+///         dec  r12d
+///         jnz  again
+///         rdtsc
+///         shl  rdx, 32
+///         or   rax, rdx
+///         sub  rax, r13
+///         mov  rdi, rax
+///         mov  eax, 60            ; exit
+///         syscall / int 0x80
+/// ```
+pub fn syscall_benchmark(fast: bool) -> [u8; 176] {
+    let call: [u8; 2] = if fast { [0x0f, 0x05] } else { [0xcd, 0x80] };
+    let [n0, n1, n2, n3] = BENCHMARK_CALLS.to_le_bytes();
+    let code = [
+        0x0f, 0x31,
+        0x48, 0xc1, 0xe2, 0x20,
+        0x48, 0x09, 0xd0,
+        0x49, 0x89, 0xc5,
+        0x41, 0xbc, n0, n1, n2, n3,
+        0xb8, 0xff, 0xff, 0x00, 0x00,
+        call[0], call[1],
+        0x41, 0xff, 0xcc,
+        0x75, 0xf4,
+        0x0f, 0x31,
+        0x48, 0xc1, 0xe2, 0x20,
+        0x48, 0x09, 0xd0,
+        0x4c, 0x29, 0xe8,
+        0x48, 0x89, 0xc7,
+        0xb8, 0x3c, 0x00, 0x00, 0x00,
+        call[0], call[1],
+    ];
+    let mut program = PROGRAM;
+    program[CODE_OFFSET..CODE_OFFSET + code.len()].copy_from_slice(&code);
+    program
+}
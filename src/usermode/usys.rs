@@ -0,0 +1,160 @@
+//! A tiny assembler for test programs that make system calls, so they need
+//! not be hand-assembled like `demo`. A `Program` is a straight line of
+//! calls, each loading its arguments and running `syscall`; the bytes it
+//! passes (messages, paths, timespecs) go after the code, in the same
+//! read/execute segment at `USER_START`.
+//!
+//! ```text
+//! let mut program = Program::new();
+//! program.write(1, b"hola\n");
+//! program.exit_with_result();
+//! let image = program.build();
+//! ```
+
+use alloc::vec::Vec;
+use crate::syscall::{SYS_EXIT, SYS_FORK, SYS_NANOSLEEP, SYS_READ, SYS_SPAWN, SYS_WAIT4, SYS_WRITE};
+use super::demo::{CODE_OFFSET, PROGRAM};
+use super::USER_START;
+
+/// An argument for `Program::syscall`.
+#[derive(Debug, Clone, Copy)]
+pub enum Arg<'a> {
+    Value(u64),
+    /// The address of these bytes, stored with the program.
+    Bytes(&'a [u8]),
+    /// The address of this many bytes reserved on the stack, where the
+    /// kernel may write.
+    Stack(u32),
+    /// What the previous call returned.
+    Result,
+}
+
+/// `mov r64, imm64`, `mov r64, rsp` and `mov r64, rax` for RDI, RSI and
+/// RDX.
+const REGISTERS: [([u8; 2], [u8; 3], [u8; 3]); 3] = [
+    ([0x48, 0xbf], [0x48, 0x89, 0xe7], [0x48, 0x89, 0xc7]),
+    ([0x48, 0xbe], [0x48, 0x89, 0xe6], [0x48, 0x89, 0xc6]),
+    ([0x48, 0xba], [0x48, 0x89, 0xe2], [0x48, 0x89, 0xc2]),
+];
+
+/// `ud2`, after the code, should the last call return.
+const UD2: [u8; 2] = [0x0f, 0x0b];
+
+#[derive(Debug, Default)]
+pub struct Program {
+    code: Vec<u8>,
+    data: Vec<u8>,
+    /// Where an address in `data` goes in `code`, and its offset there.
+    fixups: Vec<(usize, usize)>,
+}
+
+impl Program {
+    pub fn new() -> Program {
+        Program::default()
+    }
+
+    /// `number(args)`, leaving the result in RAX.
+    pub fn syscall(&mut self, number: u64, args: [Arg; 3]) -> &mut Program {
+        for (arg, (mov_imm, mov_rsp, mov_rax)) in args.iter().zip(REGISTERS) {
+            match *arg {
+                Arg::Value(value) => {
+                    self.code.extend_from_slice(&mov_imm);
+                    self.code.extend_from_slice(&value.to_le_bytes());
+                }
+                Arg::Bytes(bytes) => {
+                    self.code.extend_from_slice(&mov_imm);
+                    self.fixups.push((self.code.len(), self.data.len()));
+                    self.code.extend_from_slice(&[0; 8]);
+                    self.data.extend_from_slice(bytes);
+                }
+                Arg::Stack(len) => {
+                    // sub rsp, len
+                    self.code.extend_from_slice(&[0x48, 0x81, 0xec]);
+                    self.code.extend_from_slice(&len.to_le_bytes());
+                    self.code.extend_from_slice(&mov_rsp);
+                }
+                Arg::Result => self.code.extend_from_slice(&mov_rax),
+            }
+        }
+        // mov eax, number
+        self.code.push(0xb8);
+        self.code.extend_from_slice(&(number as u32).to_le_bytes());
+        self.code.extend_from_slice(&[0x0f, 0x05]);
+        self
+    }
+
+    pub fn read(&mut self, fd: u64, len: u32) -> &mut Program {
+        self.syscall(SYS_READ, [Arg::Value(fd), Arg::Stack(len), Arg::Value(u64::from(len))])
+    }
+
+    pub fn write(&mut self, fd: u64, bytes: &[u8]) -> &mut Program {
+        self.syscall(SYS_WRITE, [Arg::Value(fd), Arg::Bytes(bytes), Arg::Value(bytes.len() as u64)])
+    }
+
+    pub fn sleep(&mut self, nanos: u64) -> &mut Program {
+        let mut timespec = [0u8; 16];
+        timespec[..8].copy_from_slice(&(nanos / 1_000_000_000).to_le_bytes());
+        timespec[8..].copy_from_slice(&(nanos % 1_000_000_000).to_le_bytes());
+        self.syscall(SYS_NANOSLEEP, [Arg::Bytes(&timespec), Arg::Value(0), Arg::Value(0)])
+    }
+
+    pub fn spawn(&mut self, path: &str) -> &mut Program {
+        self.syscall(SYS_SPAWN, [Arg::Bytes(path.as_bytes()), Arg::Value(path.len() as u64), Arg::Value(0)])
+    }
+
+    /// `fork()`: the rest of the program runs twice, with 0 in RAX in the
+    /// child.
+    pub fn fork(&mut self) -> &mut Program {
+        self.syscall(SYS_FORK, [Arg::Value(0), Arg::Value(0), Arg::Value(0)])
+    }
+
+    /// `wait4(pid)`, with the status on the stack; `u64::MAX` (-1) waits
+    /// for any child.
+    pub fn wait(&mut self, pid: u64) -> &mut Program {
+        self.syscall(SYS_WAIT4, [Arg::Value(pid), Arg::Stack(8), Arg::Value(0)])
+    }
+
+    pub fn exit(&mut self, code: u64) -> &mut Program {
+        self.syscall(SYS_EXIT, [Arg::Value(code), Arg::Value(0), Arg::Value(0)])
+    }
+
+    /// Exits with what the last call returned.
+    pub fn exit_with_result(&mut self) -> &mut Program {
+        self.syscall(SYS_EXIT, [Arg::Result, Arg::Value(0), Arg::Value(0)])
+    }
+
+    /// The ELF image: `demo`'s headers with the segment grown to fit.
+    pub fn build(&self) -> Vec<u8> {
+        let mut image = PROGRAM[..CODE_OFFSET].to_vec();
+        let data_start = (CODE_OFFSET + self.code.len() + UD2.len()) as u64;
+        let mut code = self.code.clone();
+        for &(at, offset) in &self.fixups {
+            let address = USER_START + data_start + offset as u64;
+            code[at..at + 8].copy_from_slice(&address.to_le_bytes());
+        }
+        image.extend_from_slice(&code);
+        image.extend_from_slice(&UD2);
+        image.extend_from_slice(&self.data);
+        let size = (image.len() as u64).to_le_bytes();
+        // p_filesz and p_memsz of the one program header
+        image[96..104].copy_from_slice(&size);
+        image[104..112].copy_from_slice(&size);
+        image
+    }
+}
+
+//test case
+#[test_case]
+fn test_program_layout() {
+    let mut program = Program::new();
+    program.write(1, b"hola\n").exit(7);
+    let image = program.build();
+    let file = crate::elf::parse(&image, false).expect("not an ELF");
+    assert_eq!(file.entry, USER_START + CODE_OFFSET as u64);
+    assert!(super::check_layout(&file).is_ok());
+    assert!(image.ends_with(b"hola\n"));
+    // the message's address, in `mov rsi, imm64` right after `mov rdi, 1`
+    let at = CODE_OFFSET + 12;
+    let address = u64::from_le_bytes(image[at..at + 8].try_into().unwrap());
+    assert_eq!(address, USER_START + (image.len() - 5) as u64);
+}
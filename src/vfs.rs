@@ -0,0 +1,600 @@
+//! Virtual filesystem: one path space over every mounted filesystem.
+//!
+//! Paths are absolute and normalized before anything else: empty and `.`
+//! components are dropped and `..` removes the component before it,
+//! stopping at the root. So `/disk/../init/x` is `/init/x`, and a `..` can
+//! never carry a lookup out of one mount into another behind the backend's
+//! back. The mount whose prefix matches the most whole components gets the
+//! rest of the path, which always starts with `/`; `/diskette` does not
+//! match a mount at `/disk`.
+//!
+//! A mount cannot be removed while a file opened through the VFS is still
+//! open on it: every handle `open` returns holds a token of its mount.
+//!
+//! Directories that exist only because something is mounted below them
+//! (`/` with nothing mounted on it) list their mount points, and every
+//! listing includes the mount points directly below it.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use crate::fat::FatError;
+use crate::tar::TarError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    /// The path is not absolute, or a component is not valid.
+    InvalidPath,
+    /// No filesystem is mounted at or above the path.
+    NotMounted,
+    AlreadyMounted,
+    /// Files on the mount are still open.
+    Busy,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    ReadOnly,
+    /// The write end of a pipe, read from.
+    WriteOnly,
+    /// A write to a pipe nobody reads any more.
+    BrokenPipe,
+    Fat(FatError),
+    Tar(TarError),
+}
+
+impl fmt::Display for VfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            VfsError::InvalidPath => "invalid path",
+            VfsError::NotMounted => "nothing is mounted there",
+            VfsError::AlreadyMounted => "something is already mounted there",
+            VfsError::Busy => "files are still open on it",
+            VfsError::NotFound => "no such file or directory",
+            VfsError::NotADirectory => "not a directory",
+            VfsError::IsADirectory => "is a directory",
+            VfsError::ReadOnly => "read-only filesystem",
+            VfsError::WriteOnly => "not open for reading",
+            VfsError::BrokenPipe => "broken pipe",
+            VfsError::Fat(err) => return write!(f, "{}", err),
+            VfsError::Tar(err) => return write!(f, "{}", err),
+        };
+        f.write_str(msg)
+    }
+}
+
+impl From<FatError> for VfsError {
+    fn from(err: FatError) -> Self {
+        match err {
+            FatError::NotFound => VfsError::NotFound,
+            FatError::NotADirectory => VfsError::NotADirectory,
+            FatError::IsADirectory => VfsError::IsADirectory,
+            FatError::InvalidName => VfsError::InvalidPath,
+            err => VfsError::Fat(err),
+        }
+    }
+}
+
+impl From<TarError> for VfsError {
+    fn from(err: TarError) -> Self {
+        VfsError::Tar(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    /// A `devfs` node: no size, and reads and writes ignore the offset.
+    CharDevice,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileKind,
+    pub size: u64,
+}
+
+impl Metadata {
+    pub const DIRECTORY: Metadata = Metadata { kind: FileKind::Directory, size: 0 };
+    pub const DEVICE: Metadata = Metadata { kind: FileKind::CharDevice, size: 0 };
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Directory
+    }
+}
+
+/// A mounted filesystem. Paths handed to it are normalized, relative to
+/// its mount point and start with `/`.
+pub trait FileSystem: Send + Sync {
+    /// The filesystem type, for the mount listing.
+    fn kind(&self) -> &'static str;
+
+    fn open(&self, path: &str) -> Result<Box<dyn FileHandle>, VfsError>;
+
+    fn read_dir(&self, path: &str) -> Result<Vec<Box<dyn DirEntry>>, VfsError>;
+
+    fn metadata(&self, path: &str) -> Result<Metadata, VfsError>;
+
+    /// Makes an empty file, or empties the one at `path`, and opens it.
+    fn create(&self, _path: &str) -> Result<Box<dyn FileHandle>, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Deletes a file.
+    fn remove(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Writes back anything the filesystem still holds; called on unmount.
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+pub trait FileHandle: Send {
+    fn size(&self) -> u64;
+
+    /// Reads from `offset`, returning how many bytes were read; 0 at the end.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError>;
+
+    fn write(&mut self, _offset: u64, _buf: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+}
+
+pub trait DirEntry {
+    fn name(&self) -> &str;
+
+    fn metadata(&self) -> Metadata;
+}
+
+/// A directory entry standing for a mount point.
+struct MountPoint(String);
+
+impl DirEntry for MountPoint {
+    fn name(&self) -> &str {
+        &self.0
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata::DIRECTORY
+    }
+}
+
+/// Normalizes an absolute path; see the module documentation.
+pub fn normalize(path: &str) -> Result<String, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    let mut normalized = String::new();
+    for component in components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// The rest of `path` below `prefix`, if `prefix` covers it. Both are
+/// normalized.
+fn strip_mount<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    if prefix == "/" {
+        return Some(path);
+    }
+    match path.strip_prefix(prefix)? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// A filesystem and a path inside it.
+type Backend = (Arc<dyn FileSystem>, String);
+
+/// The block device a filesystem was mounted from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub device: String,
+    pub sectors: u64,
+}
+
+/// A line of the mount listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    pub path: String,
+    pub kind: &'static str,
+    pub source: Option<Source>,
+}
+
+struct Mount {
+    prefix: String,
+    fs: Arc<dyn FileSystem>,
+    source: Option<Source>,
+    /// Cloned into every open file; more than one reference means busy.
+    open_files: Arc<()>,
+}
+
+#[derive(Default)]
+pub struct MountTable {
+    mounts: Vec<Mount>,
+}
+
+impl MountTable {
+    pub const fn new() -> Self {
+        MountTable { mounts: Vec::new() }
+    }
+
+    pub fn mount(&mut self, path: &str, fs: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+        self.mount_from(path, fs, None)
+    }
+
+    /// Mounts a filesystem and records the device it lives on.
+    pub fn mount_from(&mut self, path: &str, fs: Arc<dyn FileSystem>, source: Option<Source>) -> Result<(), VfsError> {
+        let prefix = normalize(path)?;
+        if self.mounts.iter().any(|mount| mount.prefix == prefix) {
+            return Err(VfsError::AlreadyMounted);
+        }
+        self.mounts.push(Mount { prefix, fs, source, open_files: Arc::new(()) });
+        Ok(())
+    }
+
+    /// Removes a mount after syncing it, unless files are open on it.
+    pub fn unmount(&mut self, path: &str) -> Result<(), VfsError> {
+        let prefix = normalize(path)?;
+        let index = self.mounts.iter().position(|mount| mount.prefix == prefix).ok_or(VfsError::NotMounted)?;
+        if Arc::strong_count(&self.mounts[index].open_files) > 1 {
+            return Err(VfsError::Busy);
+        }
+        self.mounts[index].fs.sync()?;
+        self.mounts.remove(index);
+        Ok(())
+    }
+
+    /// Every mount, in mount order.
+    pub fn list(&self) -> Vec<MountInfo> {
+        self.mounts
+            .iter()
+            .map(|mount| MountInfo { path: mount.prefix.clone(), kind: mount.fs.kind(), source: mount.source.clone() })
+            .collect()
+    }
+
+    /// The mount for a normalized path and the path inside it.
+    fn find(&self, path: &str) -> Result<(&Mount, String), VfsError> {
+        self.mounts
+            .iter()
+            .filter_map(|mount| strip_mount(path, &mount.prefix).map(|rest| (mount, rest)))
+            .max_by_key(|(mount, _)| mount.prefix.len())
+            .map(|(mount, rest)| (mount, String::from(rest)))
+            .ok_or(VfsError::NotMounted)
+    }
+
+    fn resolve(&self, path: &str) -> Result<Backend, VfsError> {
+        let (mount, rest) = self.find(path)?;
+        Ok((mount.fs.clone(), rest))
+    }
+
+    /// Names of the mount points directly below a normalized path.
+    fn mount_points_below(&self, path: &str) -> Vec<String> {
+        self.mounts
+            .iter()
+            .filter_map(|mount| strip_mount(&mount.prefix, path))
+            .filter_map(|rest| {
+                let name = rest.strip_prefix('/')?;
+                (!name.is_empty() && !name.contains('/')).then(|| String::from(name))
+            })
+            .collect()
+    }
+
+    /// Whether anything is mounted strictly below a normalized path.
+    fn has_mounts_below(&self, path: &str) -> bool {
+        self.mounts.iter().any(|mount| mount.prefix != path && strip_mount(&mount.prefix, path).is_some())
+    }
+
+    fn open(&self, path: &str) -> Result<(Backend, Arc<()>), VfsError> {
+        let path = normalize(path)?;
+        let (mount, rest) = self.find(&path)?;
+        Ok(((mount.fs.clone(), rest), mount.open_files.clone()))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<(Option<Backend>, Vec<String>), VfsError> {
+        let path = normalize(path)?;
+        let below = self.mount_points_below(&path);
+        match self.resolve(&path) {
+            Ok(backend) => Ok((Some(backend), below)),
+            Err(VfsError::NotMounted) if self.has_mounts_below(&path) => Ok((None, below)),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn metadata(&self, path: &str) -> Result<Option<Backend>, VfsError> {
+        let path = normalize(path)?;
+        match self.resolve(&path) {
+            // a mount point is a directory whatever the backend says
+            Ok(_) if self.mounts.iter().any(|mount| mount.prefix == path) => Ok(None),
+            Ok(backend) => Ok(Some(backend)),
+            Err(VfsError::NotMounted) if self.has_mounts_below(&path) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// The system's mount table. It is only held to look a path up; the
+/// backend runs after the lock is released.
+pub static MOUNTS: Mutex<MountTable> = Mutex::new(MountTable::new());
+
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+    MOUNTS.lock().mount(path, fs)
+}
+
+pub fn unmount(path: &str) -> Result<(), VfsError> {
+    MOUNTS.lock().unmount(path)
+}
+
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS.lock().list()
+}
+
+/// A handle and the token that keeps its mount busy.
+struct OpenFile {
+    handle: Box<dyn FileHandle>,
+    _token: Arc<()>,
+}
+
+impl FileHandle for OpenFile {
+    fn size(&self) -> u64 {
+        self.handle.size()
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        self.handle.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        self.handle.write(offset, buf)
+    }
+}
+
+pub fn open(path: &str) -> Result<Box<dyn FileHandle>, VfsError> {
+    open_in(&MOUNTS, path)
+}
+
+/// `open` on a mount table other than the system's.
+pub fn open_in(mounts: &Mutex<MountTable>, path: &str) -> Result<Box<dyn FileHandle>, VfsError> {
+    let ((fs, rest), token) = mounts.lock().open(path)?;
+    let handle = fs.open(&rest)?;
+    Ok(Box::new(OpenFile { handle, _token: token }))
+}
+
+pub fn create(path: &str) -> Result<Box<dyn FileHandle>, VfsError> {
+    create_in(&MOUNTS, path)
+}
+
+/// `create` on a mount table other than the system's.
+pub fn create_in(mounts: &Mutex<MountTable>, path: &str) -> Result<Box<dyn FileHandle>, VfsError> {
+    let ((fs, rest), token) = mounts.lock().open(path)?;
+    if rest == "/" {
+        return Err(VfsError::IsADirectory);
+    }
+    let handle = fs.create(&rest)?;
+    Ok(Box::new(OpenFile { handle, _token: token }))
+}
+
+pub fn remove(path: &str) -> Result<(), VfsError> {
+    remove_in(&MOUNTS, path)
+}
+
+/// `remove` on a mount table other than the system's. A mount point
+/// can't be removed.
+pub fn remove_in(mounts: &Mutex<MountTable>, path: &str) -> Result<(), VfsError> {
+    let ((fs, rest), _token) = mounts.lock().open(path)?;
+    if rest == "/" {
+        return Err(VfsError::IsADirectory);
+    }
+    fs.remove(&rest)
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<Box<dyn DirEntry>>, VfsError> {
+    let (backend, below) = MOUNTS.lock().read_dir(path)?;
+    list(backend, below)
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, VfsError> {
+    metadata_in(&MOUNTS, path)
+}
+
+/// `metadata` on a mount table other than the system's.
+pub fn metadata_in(mounts: &Mutex<MountTable>, path: &str) -> Result<Metadata, VfsError> {
+    let backend = mounts.lock().metadata(path)?;
+    match backend {
+        Some((fs, rest)) => fs.metadata(&rest),
+        None => Ok(Metadata::DIRECTORY),
+    }
+}
+
+/// A backend's listing with the mount points below it added, each name once.
+fn list(backend: Option<Backend>, below: Vec<String>) -> Result<Vec<Box<dyn DirEntry>>, VfsError> {
+    let mut entries = match backend {
+        Some((fs, rest)) => fs.read_dir(&rest)?,
+        None => Vec::new(),
+    };
+    for name in below {
+        if !entries.iter().any(|entry| entry.name() == name) {
+            entries.push(Box::new(MountPoint(name)));
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads a whole file, for callers that want its contents at once.
+pub fn read_to_end(path: &str) -> Result<Vec<u8>, VfsError> {
+    let mut file = open(path)?;
+    let mut data = alloc::vec![0u8; file.size() as usize];
+    let mut done = 0;
+    while done < data.len() {
+        match file.read(done as u64, &mut data[done..])? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    data.truncate(done);
+    Ok(data)
+}
+
+//test case
+/// Answers every request with its own name and the path it was given.
+#[cfg(test)]
+struct EchoFs(&'static str);
+
+#[cfg(test)]
+struct EchoHandle(String);
+
+#[cfg(test)]
+impl FileHandle for EchoHandle {
+    fn size(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let bytes = self.0.as_bytes().get(offset as usize..).unwrap_or(&[]);
+        let n = bytes.len().min(buf.len());
+        buf[..n].copy_from_slice(&bytes[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+impl FileSystem for EchoFs {
+    fn kind(&self) -> &'static str {
+        "echo"
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn FileHandle>, VfsError> {
+        Ok(Box::new(EchoHandle(alloc::format!("{}:{}", self.0, path))))
+    }
+
+    fn read_dir(&self, _path: &str) -> Result<Vec<Box<dyn DirEntry>>, VfsError> {
+        Ok(alloc::vec![Box::new(MountPoint(String::from(self.0))) as Box<dyn DirEntry>])
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, VfsError> {
+        Ok(Metadata { kind: FileKind::File, size: path.len() as u64 })
+    }
+}
+
+#[cfg(test)]
+fn echo(table: &MountTable, path: &str) -> Result<String, VfsError> {
+    let ((fs, rest), _) = table.open(path)?;
+    let mut handle = fs.open(&rest)?;
+    let mut buf = [0u8; 64];
+    let n = handle.read(0, &mut buf)?;
+    Ok(String::from(core::str::from_utf8(&buf[..n]).unwrap()))
+}
+
+#[test_case]
+fn test_normalize() {
+    assert_eq!(normalize("/").as_deref(), Ok("/"));
+    assert_eq!(normalize("//disk///a/./b/").as_deref(), Ok("/disk/a/b"));
+    assert_eq!(normalize("/disk/a/../../init").as_deref(), Ok("/init"));
+    assert_eq!(normalize("/../..").as_deref(), Ok("/"));
+    assert_eq!(normalize("disk/a"), Err(VfsError::InvalidPath));
+    assert_eq!(normalize(""), Err(VfsError::InvalidPath));
+}
+
+#[test_case]
+fn test_mount_resolution() {
+    let mut table = MountTable::new();
+    table.mount("/disk", Arc::new(EchoFs("disk"))).unwrap();
+    table.mount("/disk/usb/", Arc::new(EchoFs("usb"))).unwrap();
+    assert_eq!(table.mount("/disk/", Arc::new(EchoFs("again"))), Err(VfsError::AlreadyMounted));
+
+    // longest whole-component prefix wins
+    assert_eq!(echo(&table, "/disk/usb/a.txt").as_deref(), Ok("usb:/a.txt"));
+    assert_eq!(echo(&table, "/disk/usbstick").as_deref(), Ok("disk:/usbstick"));
+    assert_eq!(echo(&table, "/disk").as_deref(), Ok("disk:/"));
+    // `..` is resolved before any mount sees it
+    assert_eq!(echo(&table, "/disk/usb/../b").as_deref(), Ok("disk:/b"));
+    assert_eq!(echo(&table, "/disk/../disk/usb").as_deref(), Ok("usb:/"));
+    // nothing is mounted on `/`, or on a prefix that only looks like /disk
+    assert_eq!(echo(&table, "/diskette/x"), Err(VfsError::NotMounted));
+    assert_eq!(echo(&table, "/disk/../etc"), Err(VfsError::NotMounted));
+
+    table.mount("/", Arc::new(EchoFs("root"))).unwrap();
+    assert_eq!(echo(&table, "/disk/../etc").as_deref(), Ok("root:/etc"));
+    assert_eq!(echo(&table, "/diskette/x").as_deref(), Ok("root:/diskette/x"));
+    table.unmount("/disk").unwrap();
+    assert_eq!(echo(&table, "/disk/a").as_deref(), Ok("root:/disk/a"));
+    assert_eq!(echo(&table, "/disk/usb/a").as_deref(), Ok("usb:/a"));
+    assert_eq!(table.unmount("/disk"), Err(VfsError::NotMounted));
+    let listed: Vec<(String, &str)> = table.list().into_iter().map(|mount| (mount.path, mount.kind)).collect();
+    assert_eq!(listed, [(String::from("/disk/usb"), "echo"), (String::from("/"), "echo")]);
+}
+
+#[test_case]
+fn test_mount_point_listing() {
+    let mut table = MountTable::new();
+    table.mount("/init", Arc::new(EchoFs("init"))).unwrap();
+    table.mount("/disk", Arc::new(EchoFs("disk"))).unwrap();
+
+    // `/` exists only through its mount points
+    let (backend, below) = table.read_dir("/").unwrap();
+    assert!(backend.is_none());
+    assert_eq!(below, ["init", "disk"]);
+    let names: Vec<String> = list(backend, below).unwrap().iter().map(|e| String::from(e.name())).collect();
+    assert_eq!(names, ["init", "disk"]);
+    assert!(table.metadata("/").unwrap().is_none());
+    assert!(table.metadata("/disk").unwrap().is_none());
+    assert!(table.metadata("/disk/file").unwrap().is_some());
+    assert!(matches!(table.read_dir("/other"), Err(VfsError::NotMounted)));
+
+    // a backend listing gains the mount points below it
+    let (backend, below) = table.read_dir("/disk/").unwrap();
+    let names: Vec<String> = list(backend, below).unwrap().iter().map(|e| String::from(e.name())).collect();
+    assert_eq!(names, ["disk"]);
+    table.mount("/disk/usb", Arc::new(EchoFs("usb"))).unwrap();
+    let (backend, below) = table.read_dir("/disk").unwrap();
+    let names: Vec<String> = list(backend, below).unwrap().iter().map(|e| String::from(e.name())).collect();
+    assert_eq!(names, ["disk", "usb"]);
+}
+
+#[test_case]
+fn test_unmount_busy() {
+    let table = Mutex::new(MountTable::new());
+    let source = Source { device: String::from("ata0p1"), sectors: 2048 };
+    table.lock().mount_from("/disk", Arc::new(EchoFs("disk")), Some(source.clone())).unwrap();
+    assert_eq!(table.lock().list()[0].source, Some(source));
+
+    let file = open_in(&table, "/disk/a").unwrap();
+    assert_eq!(file.size(), 7);
+    assert_eq!(table.lock().unmount("/disk"), Err(VfsError::Busy));
+    drop(file);
+    table.lock().unmount("/disk").unwrap();
+    assert!(table.lock().list().is_empty());
+}
+
+#[test_case]
+fn test_create_and_remove() {
+    let mounts = Mutex::new(MountTable::new());
+    mounts.lock().mount("/disk", Arc::new(EchoFs("disk"))).unwrap();
+    // read-only unless the backend says otherwise
+    assert_eq!(create_in(&mounts, "/disk/new").err(), Some(VfsError::ReadOnly));
+    assert_eq!(remove_in(&mounts, "/disk/old"), Err(VfsError::ReadOnly));
+    // the mount point itself is neither
+    assert_eq!(create_in(&mounts, "/disk/").err(), Some(VfsError::IsADirectory));
+    assert_eq!(remove_in(&mounts, "/disk/x/.."), Err(VfsError::IsADirectory));
+    assert_eq!(remove_in(&mounts, "/elsewhere"), Err(VfsError::NotMounted));
+}
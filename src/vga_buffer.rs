@@ -1,7 +1,11 @@
 use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
+use crate::ansi;
+use crate::sync::IrqMutex;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -25,16 +29,60 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    /// The ANSI escape sequence for this foreground color.
+    pub fn ansi(self) -> &'static str {
+        const SEQUENCES: [&str; 16] = [
+            "\x1b[30m", "\x1b[34m", "\x1b[32m", "\x1b[36m", "\x1b[31m", "\x1b[35m", "\x1b[33m", "\x1b[37m",
+            "\x1b[90m", "\x1b[94m", "\x1b[92m", "\x1b[96m", "\x1b[91m", "\x1b[95m", "\x1b[93m", "\x1b[97m",
+        ];
+        SEQUENCES[self as usize]
+    }
+
+    /// The ANSI escape sequence for this background color.
+    pub fn ansi_background(self) -> &'static str {
+        const SEQUENCES: [&str; 16] = [
+            "\x1b[40m", "\x1b[44m", "\x1b[42m", "\x1b[46m", "\x1b[41m", "\x1b[45m", "\x1b[43m", "\x1b[47m",
+            "\x1b[100m", "\x1b[104m", "\x1b[102m", "\x1b[106m", "\x1b[101m", "\x1b[105m", "\x1b[103m", "\x1b[107m",
+        ];
+        SEQUENCES[self as usize]
+    }
+
+    fn from_nibble(nibble: u8) -> Color {
+        const COLORS: [Color; 16] = [
+            Color::Black, Color::Blue, Color::Green, Color::Cyan, Color::Red, Color::Magenta, Color::Brown, Color::LightGray,
+            Color::DarkGray, Color::LightBlue, Color::LightGreen, Color::LightCyan, Color::LightRed, Color::Pink, Color::Yellow, Color::White,
+        ];
+        COLORS[usize::from(nibble & 0x0f)]
+    }
+}
+
+/// Puts a terminal's colors back to its defaults.
+pub const ANSI_RESET: &str = "\x1b[0m";
+
+/// A foreground and a background color, as the VGA attribute byte holds
+/// them.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
-        ColorCode((background as u8) << 4 | (foreground as u8))
+    pub const fn new(foreground: Color, background: Color) -> ColorCode {
+        ColorCode(((background as u8) << 4) | (foreground as u8))
+    }
+
+    pub fn foreground(self) -> Color {
+        Color::from_nibble(self.0)
+    }
+
+    pub fn background(self) -> Color {
+        Color::from_nibble(self.0 >> 4)
     }
 }
 
+/// What the console starts with, and goes back to after `with_color`.
+pub const DEFAULT_COLOR: ColorCode = ColorCode::new(Color::Yellow, Color::Black);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 struct ScreenChar {
@@ -42,8 +90,82 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+
+/// CRT controller registers, and the two that hold the cursor position.
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CURSOR_START: u8 = 0x0a;
+const CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CURSOR_LOCATION_LOW: u8 = 0x0f;
+/// In the cursor start register: don't draw the cursor.
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+/// Most lines the scrollback can keep; `scrollback=<lines>` on the command
+/// line keeps fewer.
+pub const MAX_SCROLLBACK: usize = 500;
+const DEFAULT_SCROLLBACK: usize = 200;
+
+type Row = [ScreenChar; BUFFER_WIDTH];
+
+const BLANK_ROW: Row = [ScreenChar { ascii_character: b' ', color_code: DEFAULT_COLOR }; BUFFER_WIDTH];
+
+/// Rows that scrolled off the top of the screen, oldest first, in a ring
+/// of `limit` rows.
+///
+/// It lives apart from `WRITER`, in a static with a constant initializer:
+/// at 80 KiB, building it on the boot stack the way `lazy_static` builds
+/// the writer would not fit. Only the writer locks it, while holding its
+/// own lock.
+struct Scrollback {
+    rows: [Row; MAX_SCROLLBACK],
+    /// Index of the oldest row.
+    start: usize,
+    len: usize,
+    limit: usize,
+    /// The screen as it was when the view left the bottom, to put back.
+    live: [Row; BUFFER_HEIGHT],
+}
+
+impl Scrollback {
+    const fn new() -> Self {
+        Scrollback {
+            rows: [BLANK_ROW; MAX_SCROLLBACK],
+            start: 0,
+            len: 0,
+            limit: DEFAULT_SCROLLBACK,
+            live: [BLANK_ROW; BUFFER_HEIGHT],
+        }
+    }
+
+    fn push(&mut self, row: Row) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.len == self.limit {
+            self.rows[self.start] = row;
+            self.start = (self.start + 1) % self.limit;
+        } else {
+            self.rows[(self.start + self.len) % self.limit] = row;
+            self.len += 1;
+        }
+    }
+
+    /// The `index`th row kept, oldest first.
+    fn row(&self, index: usize) -> &Row {
+        &self.rows[(self.start + index) % self.limit]
+    }
+
+    /// Drops what is kept and keeps at most `limit` rows from now on.
+    fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.min(MAX_SCROLLBACK);
+        self.start = 0;
+        self.len = 0;
+    }
+}
+
+static SCROLLBACK: Mutex<Scrollback> = Mutex::new(Scrollback::new());
 
 #[repr(transparent)]
 struct Buffer {
@@ -51,21 +173,37 @@ struct Buffer {
 }
 
 pub struct Writer {
+    /// Where the next character goes. Output starts on the last row and
+    /// scrolls; after `clear_screen` it starts at the top.
+    row_position: usize,
     column_position: usize,
+    /// Rows above the last one that ran out of room rather than ending in
+    /// a newline, so backspace at column 0 can go back into them.
+    wrapped_rows: usize,
+    /// How many rows the view is scrolled back into the scrollback; 0
+    /// shows the live screen.
+    view_offset: usize,
     color_code: ColorCode,
+    /// Escape sequences in what is written; see `ansi`.
+    escape: ansi::Parser,
     buffer: &'static mut Buffer,
 }
 
 impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
-            b'\n' => self.new_line(),
+            b'\n' => {
+                self.new_line();
+                self.wrapped_rows = 0;
+            }
+            0x08 => self.backspace(),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
+                    self.wrapped_rows += 1;
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
@@ -79,15 +217,229 @@ impl Writer {
     }
 
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+        // anything new is shown where it goes
+        self.scroll_to_bottom();
+        for c in s.chars() {
+            match self.escape.feed(c) {
+                ansi::Output::Char(c) => self.write_byte(to_cp437(c)),
+                ansi::Output::Csi(csi) => self.run_escape(csi),
+                ansi::Output::Pending => {}
+            }
+        }
+        self.update_cursor();
+    }
+
+    /// Carries out an escape sequence the way a terminal would; ones the
+    /// writer doesn't know do nothing.
+    fn run_escape(&mut self, csi: ansi::Csi) {
+        use ansi::{Command, Erase};
+
+        let Some(command) = csi.command() else { return };
+        let (row, col) = (self.row_position, self.column_position.min(BUFFER_WIDTH - 1));
+        match command {
+            Command::Sgr => {
+                let current = (self.color_code.foreground(), self.color_code.background());
+                let default = (DEFAULT_COLOR.foreground(), DEFAULT_COLOR.background());
+                let (foreground, background) = ansi::apply_sgr(csi.params(), current, default);
+                self.color_code = ColorCode::new(foreground, background);
+            }
+            Command::CursorUp(count) => self.move_to(row.saturating_sub(count), col),
+            Command::CursorDown(count) => self.move_to(row.saturating_add(count), col),
+            Command::CursorForward(count) => self.move_to(row, col.saturating_add(count)),
+            Command::CursorBack(count) => self.move_to(row, col.saturating_sub(count)),
+            Command::CursorPosition { row, col } => self.move_to(row, col),
+            Command::EraseDisplay(Erase::ToEnd) => {
+                self.clear_region(row..row + 1, col..BUFFER_WIDTH);
+                self.clear_region(row + 1..BUFFER_HEIGHT, 0..BUFFER_WIDTH);
+            }
+            Command::EraseDisplay(Erase::ToCursor) => {
+                self.clear_region(0..row, 0..BUFFER_WIDTH);
+                self.clear_region(row..row + 1, 0..col + 1);
+            }
+            Command::EraseDisplay(Erase::All) => self.clear_region(0..BUFFER_HEIGHT, 0..BUFFER_WIDTH),
+            Command::EraseLine(Erase::ToEnd) => self.clear_region(row..row + 1, col..BUFFER_WIDTH),
+            Command::EraseLine(Erase::ToCursor) => self.clear_region(row..row + 1, 0..col + 1),
+            Command::EraseLine(Erase::All) => self.clear_region(row..row + 1, 0..BUFFER_WIDTH),
+        }
+    }
+
+    /// Blanks the character before the cursor and moves back onto it. At
+    /// column 0 of a row that continues a wrapped one it goes back to the
+    /// end of the row above; after a newline nothing happens.
+    pub fn backspace(&mut self) {
+        self.scroll_to_bottom();
+        if self.column_position == 0 {
+            if self.wrapped_rows == 0 || self.row_position == 0 {
+                return;
+            }
+            self.wrapped_rows -= 1;
+            self.row_position -= 1;
+            self.column_position = BUFFER_WIDTH;
+        }
+        self.column_position -= 1;
+        let blank = ScreenChar { ascii_character: b' ', color_code: self.color_code };
+        self.buffer.chars[self.row_position][self.column_position].write(blank);
+        self.update_cursor();
+    }
+
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    pub fn color_code(&self) -> ColorCode {
+        self.color_code
+    }
+
+    pub fn set_color_code(&mut self, color_code: ColorCode) {
+        self.color_code = color_code;
+    }
+
+    /// Row and column the next character goes to.
+    pub fn position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    /// Moves where the next character goes, and the hardware cursor with
+    /// it. Out of range values are clamped to the screen. What follows is
+    /// not a continuation of a wrapped row, so backspace stops at column 0.
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.scroll_to_bottom();
+        self.move_to(row, col);
+        self.update_cursor();
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.row_position = row.min(BUFFER_HEIGHT - 1);
+        self.column_position = col.min(BUFFER_WIDTH - 1);
+        self.wrapped_rows = 0;
+    }
+
+    /// Blanks the cells in `rows` and `cols`, in the current color, leaving
+    /// the position alone. Ranges past the screen are cut at its edge.
+    pub fn clear_region(&mut self, rows: core::ops::Range<usize>, cols: core::ops::Range<usize>) {
+        self.scroll_to_bottom();
+        let blank = ScreenChar { ascii_character: b' ', color_code: self.color_code };
+        for row in rows.start..rows.end.min(BUFFER_HEIGHT) {
+            for col in cols.start..cols.end.min(BUFFER_WIDTH) {
+                self.buffer.chars[row][col].write(blank);
+            }
+        }
+    }
+
+    /// Writes `s` from `row`, `col` in the current color, leaving the
+    /// position alone: for text that stays in one place while the rest of
+    /// the screen scrolls. Cut at the end of the row.
+    pub fn write_at(&mut self, row: usize, col: usize, s: &str) {
+        if row >= BUFFER_HEIGHT {
+            return;
+        }
+        self.scroll_to_bottom();
+        for (col, c) in (col..BUFFER_WIDTH).zip(s.chars()) {
+            self.buffer.chars[row][col].write(ScreenChar { ascii_character: to_cp437(c), color_code: self.color_code });
+        }
+    }
+
+    /// Blanks every cell in the current color and starts again at the
+    /// top left.
+    pub fn clear_screen(&mut self) {
+        self.scroll_to_bottom();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.row_position = 0;
+        self.column_position = 0;
+        self.wrapped_rows = 0;
+        self.update_cursor();
+    }
+
+    /// The color attribute of one cell: background in the high nibble,
+    /// foreground in the low one.
+    pub fn attribute(&self, row: usize, col: usize) -> u8 {
+        self.buffer.chars[row][col].read().color_code.0
+    }
+
+    /// Recolors one cell, keeping its character.
+    pub fn set_attribute(&mut self, row: usize, col: usize, attribute: u8) {
+        let mut cell = self.buffer.chars[row][col].read();
+        cell.color_code = ColorCode(attribute);
+        self.buffer.chars[row][col].write(cell);
+    }
+
+    /// Moves the view `lines` rows back into the scrollback, as far as it
+    /// goes.
+    pub fn scroll_up(&mut self, lines: usize) {
+        let mut scrollback = SCROLLBACK.lock();
+        if self.view_offset == 0 {
+            for (row, saved) in scrollback.live.iter_mut().enumerate() {
+                *saved = core::array::from_fn(|col| self.buffer.chars[row][col].read());
+            }
+        }
+        self.view_offset = (self.view_offset + lines).min(scrollback.len);
+        self.show(&scrollback);
+    }
+
+    /// Moves the view `lines` rows towards the live screen.
+    pub fn scroll_down(&mut self, lines: usize) {
+        if self.view_offset == 0 {
+            return;
+        }
+        let scrollback = SCROLLBACK.lock();
+        self.view_offset = self.view_offset.saturating_sub(lines);
+        self.show(&scrollback);
+    }
+
+    /// Back to the live screen.
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll_down(usize::MAX);
+    }
+
+    /// How many rows the view is scrolled back.
+    pub fn view_offset(&self) -> usize {
+        self.view_offset
+    }
+
+    /// Draws the screen `view_offset` rows back: the newest rows of the
+    /// scrollback, then the top of the live screen.
+    fn show(&mut self, scrollback: &Scrollback) {
+        let first = scrollback.len - self.view_offset;
+        for screen_row in 0..BUFFER_HEIGHT {
+            let index = first + screen_row;
+            let row = match index.checked_sub(scrollback.len) {
+                None => scrollback.row(index),
+                Some(live_row) => &scrollback.live[live_row],
+            };
+            for (col, &character) in row.iter().enumerate() {
+                self.buffer.chars[screen_row][col].write(character);
             }
         }
+        self.update_cursor();
+    }
+
+    /// Moves the blinking hardware cursor to where the next character goes,
+    /// or off the screen while the view is scrolled back.
+    fn update_cursor(&self) {
+        let position = match self.view_offset {
+            0 => (self.row_position * BUFFER_WIDTH + self.column_position.min(BUFFER_WIDTH - 1)) as u16,
+            _ => (BUFFER_HEIGHT * BUFFER_WIDTH) as u16,
+        };
+        let mut index = Port::<u8>::new(CRTC_INDEX);
+        let mut data = Port::<u8>::new(CRTC_DATA);
+        unsafe {
+            index.write(CURSOR_LOCATION_LOW);
+            data.write(position as u8);
+            index.write(CURSOR_LOCATION_HIGH);
+            data.write((position >> 8) as u8);
+        }
     }
 
     fn new_line(&mut self) {
+        self.column_position = 0;
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            return;
+        }
+        let top: Row = core::array::from_fn(|col| self.buffer.chars[0][col].read());
+        SCROLLBACK.lock().push(top);
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
@@ -95,7 +447,6 @@ impl Writer {
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -109,6 +460,34 @@ impl Writer {
     }
 }
 
+/// Code page 437 from 0x80 up, the characters the VGA text font draws.
+const CP437_HIGH: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»",
+    "░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}",
+);
+
+/// The byte the VGA font draws `c` with. Letters the font lacks lose their
+/// accent, and anything else becomes a square.
+pub fn to_cp437(c: char) -> u8 {
+    if matches!(c, ' '..='~' | '\n' | '\x08') {
+        return c as u8;
+    }
+    if let Some(index) = CP437_HIGH.chars().position(|high| high == c) {
+        return 0x80 + index as u8;
+    }
+    match c {
+        'Á' | 'À' | 'Â' => b'A',
+        'È' | 'Ë' | 'Ê' => b'E',
+        'Í' | 'Ì' | 'Ï' | 'Î' => b'I',
+        'Ó' | 'Ò' | 'Ô' => b'O',
+        'Ú' | 'Ù' | 'Û' => b'U',
+        '´' => b'\'',
+        '¨' => b'"',
+        _ => 0xfe,
+    }
+}
+
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_string(s);
@@ -117,9 +496,13 @@ impl fmt::Write for Writer {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: IrqMutex<Writer> = IrqMutex::new(Writer {
+        row_position: BUFFER_HEIGHT - 1,
         column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
+        wrapped_rows: 0,
+        view_offset: 0,
+        color_code: DEFAULT_COLOR,
+        escape: ansi::Parser::new(),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
     });
 }
@@ -135,10 +518,301 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Where `print!` output goes, chosen with `console=vga|serial|both`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Console {
+    Vga = 0,
+    Serial = 1,
+    Both = 2,
+}
+
+/// The set of sinks `print!` writes to. `Console` picks the VGA and serial
+/// part; `VIRTIO` adds the virtio-console on top. `VGA` is the screen: the
+/// text buffer, or the framebuffer console once `graphics::init` set one
+/// up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outputs(u8);
+
+impl Outputs {
+    pub const NONE: Outputs = Outputs(0);
+    pub const VGA: Outputs = Outputs(1);
+    pub const SERIAL: Outputs = Outputs(2);
+    pub const VIRTIO: Outputs = Outputs(4);
+
+    pub fn contains(self, other: Outputs) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn without(self, other: Outputs) -> Outputs {
+        Outputs(self.0 & !other.0)
+    }
+
+    /// A comma-separated list of `vga`, `serial`, `both` and `virtio`.
+    pub fn parse(list: &str) -> Option<Outputs> {
+        list.split(',').try_fold(Outputs::NONE, |outputs, name| {
+            Some(outputs | match name.trim() {
+                "vga" => Outputs::VGA,
+                "serial" => Outputs::SERIAL,
+                "both" => Outputs::VGA | Outputs::SERIAL,
+                "virtio" => Outputs::VIRTIO,
+                _ => return None,
+            })
+        })
+    }
+}
+
+impl core::ops::BitOr for Outputs {
+    type Output = Outputs;
+
+    fn bitor(self, other: Outputs) -> Outputs {
+        Outputs(self.0 | other.0)
+    }
+}
+
+/// Both by default, so a session with `-serial stdio` works as well as one
+/// on the screen.
+static OUTPUTS: AtomicU8 = AtomicU8::new(Outputs::VGA.0 | Outputs::SERIAL.0);
+
+pub fn outputs() -> Outputs {
+    Outputs(OUTPUTS.load(Ordering::Relaxed))
+}
+
+pub fn set_outputs(outputs: Outputs) {
+    OUTPUTS.store(outputs.0, Ordering::Relaxed);
+}
+
+pub fn console() -> Console {
+    let outputs = outputs();
+    match (outputs.contains(Outputs::VGA), outputs.contains(Outputs::SERIAL)) {
+        (true, true) => Console::Both,
+        (false, true) => Console::Serial,
+        _ => Console::Vga,
+    }
+}
+
+/// Switches between VGA and serial, leaving the virtio-console as it was.
+pub fn set_console(console: Console) {
+    let chosen = match console {
+        Console::Vga => Outputs::VGA,
+        Console::Serial => Outputs::SERIAL,
+        Console::Both => Outputs::VGA | Outputs::SERIAL,
+    };
+    let virtio = outputs().without(Outputs::VGA | Outputs::SERIAL);
+    set_outputs(chosen | virtio);
+}
+
+/// Applies `console=` and `scrollback=` from the command line.
+pub fn init_console() {
+    match crate::cmdline::get("console") {
+        None => {}
+        Some(list) => match Outputs::parse(list) {
+            Some(outputs) if outputs != Outputs::NONE => set_outputs(outputs),
+            _ => crate::warn!("console: unknown console '{}'", list),
+        },
+    }
+    match crate::cmdline::get_u64("scrollback") {
+        None => {}
+        Some(lines) if lines <= MAX_SCROLLBACK as u64 => set_scrollback(lines as usize),
+        Some(lines) => crate::warn!("console: scrollback={} is more than the {} lines kept", lines, MAX_SCROLLBACK),
+    }
+}
+
+/// Runs with interrupts off: the serial interrupt echoes through here, and
+/// must not find the writer held by the code it interrupted.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let outputs = outputs();
+        if outputs.contains(Outputs::VGA) {
+            on_screen(|console| Counted(console).write_fmt(args).unwrap(), |writer| Counted(writer).write_fmt(args).unwrap());
+        } else {
+            // formatted once more, only to be counted
+            let _ = Counted(&mut Sink).write_fmt(args);
+        }
+        if outputs.contains(Outputs::SERIAL) {
+            crate::serial::_print(args);
+        }
+        if outputs.contains(Outputs::VIRTIO) {
+            crate::virtio::console::_print(args);
+        }
+    });
+}
+
+/// Writes `s` on the screen whatever `outputs` says, for `/dev/tty`.
+pub fn write_screen(s: &str) {
+    use core::fmt::Write;
+    on_screen(|console| Counted(console).write_str(s).unwrap(), |writer| Counted(writer).write_str(s).unwrap());
+}
+
+/// Passes text on to `W`, adding its length to `stats::PRINTED_BYTES`.
+struct Counted<'a, W>(&'a mut W);
+
+impl<W: fmt::Write> fmt::Write for Counted<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::stats::PRINTED_BYTES.add(s.len() as u64);
+        self.0.write_str(s)
+    }
+}
+
+struct Sink;
+
+impl fmt::Write for Sink {
+    fn write_str(&mut self, _: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+/// Runs `framebuffer` on the framebuffer console if there is one, and
+/// `text` on the text buffer's writer otherwise.
+fn on_screen<R>(
+    framebuffer: impl FnOnce(&mut crate::graphics::console::FramebufferConsole<'static>) -> R,
+    text: impl FnOnce(&mut Writer) -> R,
+) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        crate::graphics::console::with(framebuffer).unwrap_or_else(|| text(&mut WRITER.lock()))
+    })
+}
+
+/// Erases the last character on the screen; `print!("\x08")` does the same
+/// on every console.
+pub fn backspace() {
+    on_screen(|console| console.backspace(), |writer| writer.backspace());
+}
+
+/// Scrolls the screen `lines` rows back into what scrolled off the top.
+/// Printing anything brings it back to the bottom.
+pub fn scroll_up(lines: usize) {
+    WRITER.lock().scroll_up(lines);
+}
+
+/// Scrolls the screen `lines` rows back towards the bottom.
+pub fn scroll_down(lines: usize) {
+    WRITER.lock().scroll_down(lines);
+}
+
+/// Keeps at most `lines` rows (up to `MAX_SCROLLBACK`) from now on, and
+/// drops the ones kept so far.
+pub fn set_scrollback(lines: usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.scroll_to_bottom();
+        SCROLLBACK.lock().set_limit(lines);
+    });
+}
+
+/// Rows kept in the scrollback.
+pub fn scrollback_len() -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| SCROLLBACK.lock().len)
+}
+
+/// Sets the color of what is printed from now on.
+pub fn set_color(foreground: Color, background: Color) {
+    set_color_code(ColorCode::new(foreground, background));
+}
+
+/// The color text is printed in now.
+pub fn color_code() -> ColorCode {
+    WRITER.lock().color_code()
+}
+
+/// Like `set_color`, with both colors in one value.
+pub fn set_color_code(color_code: ColorCode) {
+    WRITER.lock().set_color_code(color_code);
+    crate::graphics::console::with(|console| console.set_color_code(color_code));
+}
+
+/// Moves where the next character goes on the screen, and the blinking
+/// cursor with it.
+pub fn set_cursor(row: usize, col: usize) {
+    on_screen(|console| console.set_position(row, col), |writer| writer.set_position(row, col));
+}
+
+/// Row and column the next character goes to.
+pub fn cursor() -> (usize, usize) {
+    on_screen(|console| console.position(), |writer| writer.position())
+}
+
+/// Shows or hides the blinking cursor; where it is doesn't change.
+pub fn set_cursor_visible(visible: bool) {
+    let mut index = Port::<u8>::new(CRTC_INDEX);
+    let mut data = Port::<u8>::new(CRTC_DATA);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        index.write(CURSOR_START);
+        let start = data.read();
+        data.write(if visible { start & !CURSOR_DISABLE } else { start | CURSOR_DISABLE });
+    });
+}
+
+/// Blanks a rectangle of the screen: the cells in `rows` and `cols`.
+pub fn clear_region(rows: core::ops::Range<usize>, cols: core::ops::Range<usize>) {
+    let (framebuffer_rows, framebuffer_cols) = (rows.clone(), cols.clone());
+    on_screen(|console| console.clear_region(framebuffer_rows, framebuffer_cols), |writer| writer.clear_region(rows, cols));
+}
+
+/// Writes `s` at a fixed place on the screen; see `Writer::write_at`.
+pub fn write_at(row: usize, col: usize, s: &str) {
+    on_screen(|console| console.write_at(row, col, s), |writer| writer.write_at(row, col, s));
+}
+
+/// Clears the screen, and a terminal on the serial port if output goes
+/// there.
+pub fn clear_screen() {
+    on_screen(|console| console.clear(), |writer| writer.clear_screen());
+    if outputs().contains(Outputs::SERIAL) {
+        crate::serial_print!("\x1b[2J\x1b[H");
+    }
+}
+
+/// Runs `f` with `foreground` as the text color, on the screen and, as an
+/// ANSI sequence, on the serial port; the previous color comes back after.
+pub fn with_color<R>(foreground: Color, f: impl FnOnce() -> R) -> R {
+    with_colors(foreground, None, f)
+}
+
+/// Like `with_color`, and with `background` behind the text too when it is
+/// given.
+pub fn with_colors<R>(foreground: Color, background: Option<Color>, f: impl FnOnce() -> R) -> R {
+    let serial = outputs().contains(Outputs::SERIAL);
+    let previous = {
+        let mut writer = WRITER.lock();
+        let previous = writer.color_code;
+        let background = background.unwrap_or(previous.background());
+        writer.color_code = ColorCode::new(foreground, background);
+        previous
+    };
+    let screen = ColorCode::new(foreground, background.unwrap_or(previous.background()));
+    crate::graphics::console::with(|console| console.set_color_code(screen));
+    if serial {
+        crate::serial_print!("{}", foreground.ansi());
+        if let Some(background) = background {
+            crate::serial_print!("{}", background.ansi_background());
+        }
+    }
+    let result = f();
+    if serial {
+        crate::serial_print!("{}", ANSI_RESET);
+    }
+    WRITER.lock().color_code = previous;
+    crate::graphics::console::with(|console| console.set_color_code(previous));
+    result
+}
+
+/// Like `println!`, in the color given first.
+#[macro_export]
+macro_rules! println_colored {
+    ($color:expr, $($arg:tt)*) => {
+        $crate::vga_buffer::with_color($color, || $crate::println!($($arg)*))
+    };
+}
+
+/// Like `print!`, in the color given first.
+#[macro_export]
+macro_rules! print_colored {
+    ($color:expr, $($arg:tt)*) => {
+        $crate::vga_buffer::with_color($color, || $crate::print!($($arg)*))
+    };
 }
 
 //test case
@@ -152,3 +826,238 @@ fn test_println_output() {
     }
 }
 
+
+/// What the row being written holds in `cols`.
+#[cfg(test)]
+fn current_row(writer: &Writer, cols: core::ops::Range<usize>) -> alloc::string::String {
+    writer.buffer.chars[writer.row_position][cols].iter().map(|c| char::from(c.read().ascii_character)).collect()
+}
+
+#[test_case]
+fn test_backspace() {
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    writer.write_str("\nab").unwrap();
+    writer.backspace();
+    assert_eq!(current_row(&writer, 0..2), "a ");
+    assert_eq!(writer.column_position, 1);
+    writer.backspace();
+    // nothing before the start of the line
+    writer.backspace();
+    assert_eq!(writer.column_position, 0);
+    assert_eq!(current_row(&writer, 0..1), " ");
+}
+
+#[test_case]
+fn test_backspace_into_a_wrapped_row() {
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    let long: alloc::string::String = (0..BUFFER_WIDTH + 1).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+    writer.write_str("\n").unwrap();
+    writer.write_str(&long).unwrap();
+    assert_eq!(writer.column_position, 1);
+    writer.backspace();
+    writer.backspace();
+    // back on the first row, its last character gone
+    assert_eq!(writer.column_position, BUFFER_WIDTH - 1);
+    assert_eq!(current_row(&writer, 0..3), "abc");
+    assert_eq!(current_row(&writer, BUFFER_WIDTH - 2..BUFFER_WIDTH), "a ");
+}
+
+#[test_case]
+fn test_backspace_erases() {
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    writer.write_str("\nabc\x08\x08 \x08x").unwrap();
+    assert_eq!(current_row(&writer, 0..3), "ax ");
+    assert_eq!(writer.column_position, 2);
+}
+
+#[test_case]
+fn test_output_lists() {
+    assert_eq!(Outputs::parse("vga"), Some(Outputs::VGA));
+    assert_eq!(Outputs::parse("both"), Some(Outputs::VGA | Outputs::SERIAL));
+    assert_eq!(Outputs::parse("serial,virtio"), Some(Outputs::SERIAL | Outputs::VIRTIO));
+    assert_eq!(Outputs::parse("vga,tty"), None);
+
+    let saved = outputs();
+    set_outputs(Outputs::SERIAL | Outputs::VIRTIO);
+    assert_eq!(console(), Console::Serial);
+    set_console(Console::Both);
+    assert_eq!(outputs(), Outputs::VGA | Outputs::SERIAL | Outputs::VIRTIO);
+    set_outputs(saved);
+}
+
+#[test_case]
+fn test_cp437() {
+    assert_eq!(CP437_HIGH.chars().count(), 128);
+    assert_eq!(to_cp437('a'), b'a');
+    assert_eq!(to_cp437('\n'), b'\n');
+    assert_eq!(to_cp437('Ç'), 0x80);
+    assert_eq!(to_cp437('é'), 0x82);
+    assert_eq!(to_cp437('á'), 0xa0);
+    assert_eq!(to_cp437('ñ'), 0xa4);
+    assert_eq!(to_cp437('¿'), 0xa8);
+    assert_eq!(to_cp437('\u{a0}'), 0xff);
+    assert_eq!(to_cp437('Á'), b'A');
+    assert_eq!(to_cp437('\t'), 0xfe);
+    assert_eq!(to_cp437('€'), 0xfe);
+}
+
+#[test_case]
+fn test_colors() {
+    println_colored!(Color::LightRed, "in red");
+    let writer = WRITER.lock();
+    let red = writer.buffer.chars[writer.row_position - 1][0].read();
+    assert_eq!(red.color_code, ColorCode::new(Color::LightRed, Color::Black));
+    assert_eq!(writer.color_code, DEFAULT_COLOR);
+    assert_eq!(Color::Green.ansi(), "\x1b[32m");
+    assert_eq!(Color::White.ansi(), "\x1b[97m");
+}
+
+#[test_case]
+fn test_clear_screen() {
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    writer.write_str("something\nsomething else").unwrap();
+    writer.clear_screen();
+    let blank = ScreenChar { ascii_character: b' ', color_code: DEFAULT_COLOR };
+    assert!(writer.buffer.chars.iter().flatten().all(|cell| cell.read() == blank));
+    assert_eq!((writer.row_position, writer.column_position), (0, 0));
+    writer.write_str("top\nnext").unwrap();
+    assert_eq!(char::from(writer.buffer.chars[0][0].read().ascii_character), 't');
+    assert_eq!(current_row(&writer, 0..4), "next");
+
+    // back to the last row, where the other tests look
+    for _ in 0..BUFFER_HEIGHT {
+        writer.write_byte(b'\n');
+    }
+    assert_eq!(writer.row_position, BUFFER_HEIGHT - 1);
+}
+
+#[cfg(test)]
+fn row_text(row: &[ScreenChar]) -> alloc::string::String {
+    row.iter().map(|c| char::from(c.ascii_character)).collect()
+}
+
+/// What screen row `row` shows now.
+#[cfg(test)]
+fn screen_row(writer: &Writer, row: usize) -> alloc::string::String {
+    row_text(&writer.buffer.chars[row].iter().map(|c| c.read()).collect::<alloc::vec::Vec<_>>())
+}
+
+#[test_case]
+fn test_scrollback() {
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    for i in 0..BUFFER_HEIGHT + 3 {
+        write!(writer, "\nline {}", i).unwrap();
+    }
+    assert!(screen_row(&writer, 0).starts_with("line 3 "));
+    writer.scroll_up(1);
+    assert_eq!(writer.view_offset(), 1);
+    assert!(screen_row(&writer, 0).starts_with("line 2 "));
+    assert!(screen_row(&writer, 1).starts_with("line 3 "));
+    assert!(screen_row(&writer, BUFFER_HEIGHT - 1).starts_with("line 26 "));
+    // no further back than what is kept
+    writer.scroll_up(usize::MAX / 2);
+    assert_eq!(writer.view_offset(), SCROLLBACK.lock().len);
+    writer.scroll_down(usize::MAX);
+    assert_eq!(writer.view_offset(), 0);
+    assert!(screen_row(&writer, BUFFER_HEIGHT - 1).starts_with("line 27 "));
+
+    // printing brings the view back first
+    writer.scroll_up(2);
+    writer.write_str("x").unwrap();
+    assert_eq!(writer.view_offset(), 0);
+    assert!(screen_row(&writer, BUFFER_HEIGHT - 1).starts_with("line 27x"));
+}
+
+#[test_case]
+fn test_scrollback_ring() {
+    let _writer = WRITER.lock();
+    let mut scrollback = SCROLLBACK.lock();
+    scrollback.set_limit(3);
+    for c in b'a'..=b'e' {
+        let mut row = BLANK_ROW;
+        row[0].ascii_character = c;
+        scrollback.push(row);
+    }
+    assert_eq!(scrollback.len, 3);
+    let kept: alloc::string::String = (0..3).map(|i| char::from(scrollback.row(i)[0].ascii_character)).collect();
+    assert_eq!(kept, "cde");
+    scrollback.set_limit(0);
+    scrollback.push(BLANK_ROW);
+    assert_eq!(scrollback.len, 0);
+    scrollback.set_limit(DEFAULT_SCROLLBACK);
+}
+
+#[test_case]
+fn test_color_codes() {
+    let code = ColorCode::new(Color::LightGreen, Color::Blue);
+    assert_eq!(code.foreground(), Color::LightGreen);
+    assert_eq!(code.background(), Color::Blue);
+    assert_eq!(DEFAULT_COLOR.foreground(), Color::Yellow);
+    assert_eq!(Color::Black.ansi_background(), "\x1b[40m");
+    assert_eq!(Color::White.ansi_background(), "\x1b[107m");
+
+    with_colors(Color::White, Some(Color::Red), || {
+        assert_eq!(color_code(), ColorCode::new(Color::White, Color::Red));
+    });
+    assert_eq!(color_code(), DEFAULT_COLOR);
+}
+
+#[test_case]
+fn test_position_and_clear_region() {
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    writer.write_str("\nabcdef").unwrap();
+    let row = writer.row_position;
+    writer.set_position(row, 2);
+    writer.write_str("XY").unwrap();
+    assert_eq!(current_row(&writer, 0..6), "abXYef");
+    writer.clear_region(row..row + 1, 1..3);
+    assert_eq!(current_row(&writer, 0..6), "a  Yef");
+    // the position is where writing left it
+    assert_eq!(writer.position(), (row, 4));
+    writer.set_position(BUFFER_HEIGHT + 5, BUFFER_WIDTH + 5);
+    assert_eq!(writer.position(), (BUFFER_HEIGHT - 1, BUFFER_WIDTH - 1));
+    // past the edge is cut off quietly
+    writer.clear_region(BUFFER_HEIGHT - 1..BUFFER_HEIGHT + 3, 0..BUFFER_WIDTH * 2);
+    assert_eq!(current_row(&writer, 0..BUFFER_WIDTH).trim(), "");
+    writer.set_position(BUFFER_HEIGHT - 1, 0);
+}
+
+#[test_case]
+fn test_escape_sequences() {
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    writer.write_str("\n\x1b[31mred\x1b[0m plain").unwrap();
+    let row = writer.row_position;
+    assert_eq!(current_row(&writer, 0..9), "red plain");
+    assert_eq!(writer.attribute(row, 0), ColorCode::new(Color::Red, Color::Black).0);
+    assert_eq!(writer.attribute(row, 4), DEFAULT_COLOR.0);
+    assert_eq!(writer.color_code, DEFAULT_COLOR);
+
+    // a sequence split across writes still works
+    writer.write_str("\x1b[").unwrap();
+    writer.write_str("3D").unwrap();
+    writer.write_str("\x1b[K").unwrap();
+    assert_eq!(current_row(&writer, 0..9), "red pl   ");
+    writer.write_str("\x1b[2;5Hx").unwrap();
+    assert_eq!(char::from(writer.buffer.chars[1][4].read().ascii_character), 'x');
+    writer.write_str("\x1b[1K").unwrap();
+    assert_eq!(row_text(&[writer.buffer.chars[1][4].read()]), " ");
+    writer.set_position(BUFFER_HEIGHT - 1, 0);
+}
+
+#[test_case]
+fn test_write_at_keeps_the_position() {
+    let mut writer = WRITER.lock();
+    let position = writer.position();
+    writer.write_at(2, BUFFER_WIDTH - 3, "status");
+    assert_eq!(row_text(&writer.buffer.chars[2].each_ref().map(|c| c.read())[BUFFER_WIDTH - 3..]), "sta");
+    assert_eq!(writer.position(), position);
+    writer.write_at(BUFFER_HEIGHT, 0, "off the screen");
+    writer.clear_region(2..3, 0..BUFFER_WIDTH);
+}
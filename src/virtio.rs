@@ -0,0 +1,169 @@
+//! Virtio devices over the legacy PCI transport.
+//!
+//! We speak the legacy ("transitional", virtio 0.9.5) interface because QEMU
+//! exposes it by default with a plain I/O BAR and it needs no capability
+//! list walking: BAR0 holds the common registers below, immediately followed
+//! by the device-specific configuration (MSI-X stays disabled). Requests
+//! are completed by polling the used rings; only input the device pushes on
+//! its own (the console) waits for the interrupt.
+
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+use crate::memory::{BootInfoFrameAllocator, DmaRegion};
+use crate::pci::{Bar, PciDevice};
+use queue::{QueueLayout, Virtqueue};
+
+pub mod queue;
+pub mod blk;
+pub mod console;
+pub mod net;
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+/// Device status bits.
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FAILED: u8 = 128;
+
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+const REG_ISR_STATUS: u16 = 0x13;
+/// Device-specific configuration starts here when MSI-X is off.
+const REG_DEVICE_CONFIG: u16 = 0x14;
+
+/// Register window of a legacy virtio PCI function.
+pub struct LegacyTransport {
+    io_base: u16,
+    pub pci: PciDevice,
+}
+
+impl LegacyTransport {
+    /// Takes over `pci`; fails when BAR0 isn't an I/O BAR.
+    pub fn new(pci: PciDevice) -> Option<LegacyTransport> {
+        let io_base = match pci.bar(0)? {
+            Bar::Io(port) => port,
+            Bar::Memory { .. } => return None,
+        };
+        pci.enable_bus_mastering();
+        Some(LegacyTransport { io_base, pci })
+    }
+
+    fn read8(&self, reg: u16) -> u8 {
+        unsafe { Port::new(self.io_base + reg).read() }
+    }
+
+    fn write8(&self, reg: u16, value: u8) {
+        unsafe { Port::new(self.io_base + reg).write(value) }
+    }
+
+    fn read16(&self, reg: u16) -> u16 {
+        unsafe { Port::new(self.io_base + reg).read() }
+    }
+
+    fn write16(&self, reg: u16, value: u16) {
+        unsafe { Port::new(self.io_base + reg).write(value) }
+    }
+
+    fn read32(&self, reg: u16) -> u32 {
+        unsafe { Port::new(self.io_base + reg).read() }
+    }
+
+    fn write32(&self, reg: u16, value: u32) {
+        unsafe { Port::new(self.io_base + reg).write(value) }
+    }
+
+    pub fn status(&self) -> u8 {
+        self.read8(REG_DEVICE_STATUS)
+    }
+
+    pub fn set_status(&self, status: u8) {
+        self.write8(REG_DEVICE_STATUS, status)
+    }
+
+    /// Resets the device and runs the feature handshake: acknowledges it,
+    /// offers `wanted & device_features`, and returns what was accepted.
+    pub fn negotiate(&self, wanted: u32) -> u32 {
+        self.set_status(0);
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let accepted = self.read32(REG_DEVICE_FEATURES) & wanted;
+        self.write32(REG_GUEST_FEATURES, accepted);
+        accepted
+    }
+
+    /// Size the device chose for queue `index` (0 = queue absent).
+    pub fn queue_size(&self, index: u16) -> u16 {
+        self.write16(REG_QUEUE_SELECT, index);
+        self.read16(REG_QUEUE_SIZE)
+    }
+
+    /// Hands the page-aligned physical address of queue `index` to the device.
+    pub fn set_queue_address(&self, index: u16, phys_addr: u64) {
+        self.write16(REG_QUEUE_SELECT, index);
+        self.write32(REG_QUEUE_ADDRESS, (phys_addr / queue::QUEUE_ALIGN as u64) as u32);
+    }
+
+    pub fn notify(&self, index: u16) {
+        self.write16(REG_QUEUE_NOTIFY, index)
+    }
+
+    /// Reading the ISR register also acknowledges the interrupt.
+    pub fn ack_interrupt(&self) -> u8 {
+        self.read8(REG_ISR_STATUS)
+    }
+
+    pub fn config_read8(&self, offset: u16) -> u8 {
+        self.read8(REG_DEVICE_CONFIG + offset)
+    }
+
+    pub fn config_read32(&self, offset: u16) -> u32 {
+        self.read32(REG_DEVICE_CONFIG + offset)
+    }
+
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK)
+    }
+
+    pub fn fail(&self) {
+        self.set_status(self.status() | STATUS_FAILED)
+    }
+
+    /// Allocates queue `index` at the size the device chose and hands it
+    /// over. Returns the queue and the DMA memory it lives in, which must
+    /// outlive it; `None` when the queue is absent or out of memory.
+    pub fn setup_queue(
+        &self,
+        index: u16,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        physical_memory_offset: VirtAddr,
+    ) -> Option<(Virtqueue, DmaRegion)> {
+        let size = self.queue_size(index);
+        if size == 0 || !size.is_power_of_two() {
+            return None;
+        }
+        let pages = QueueLayout::new(size).total_size / 4096;
+        let memory = DmaRegion::allocate(frame_allocator, physical_memory_offset, pages)?;
+        let queue = unsafe { Virtqueue::new(memory.as_mut_ptr(), memory.phys_addr().as_u64(), size) };
+        self.set_queue_address(index, queue.phys_addr());
+        Some((queue, memory))
+    }
+}
+
+/// Spins until the device completes a chain on `queue`, for at most
+/// `limit` polls. Doesn't acknowledge the interrupt: on a device that also
+/// interrupts for input that would hide it from the handler.
+pub fn poll_used(queue: &mut Virtqueue, limit: usize) -> Option<(u16, u32)> {
+    for _ in 0..limit {
+        if let Some(done) = queue.pop_used() {
+            return Some(done);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
@@ -0,0 +1,165 @@
+//! virtio-blk driver (legacy PCI device 0x1AF4:0x1001).
+//!
+//! Requests are three-descriptor chains (header, data, status byte) built in
+//! a DMA bounce buffer owned by the driver; callers' buffers are copied in
+//! and out, so they can live anywhere. Completion is polled.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::memory::{BootInfoFrameAllocator, DmaRegion};
+use crate::pci;
+use super::queue::{Buffer, Virtqueue};
+use super::LegacyTransport;
+
+pub const DEVICE_ID: u16 = 0x1001;
+
+const FEATURE_RO: u32 = 1 << 5;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+/// Written before submitting so an untouched status byte is detectable.
+const STATUS_PENDING: u8 = 0xff;
+
+/// Pages of data area in the bounce buffer (after one page for header and
+/// status); larger transfers are split.
+const DATA_PAGES: usize = 8;
+const MAX_SECTORS_PER_REQUEST: usize = DATA_PAGES * 4096 / SECTOR_SIZE;
+const POLL_LIMIT: usize = 10_000_000;
+
+pub struct VirtioBlk {
+    transport: LegacyTransport,
+    queue: Virtqueue,
+    _queue_memory: DmaRegion,
+    bounce: DmaRegion,
+    capacity: u64,
+    read_only: bool,
+}
+
+impl VirtioBlk {
+    pub fn new(
+        transport: LegacyTransport,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        physical_memory_offset: VirtAddr,
+    ) -> Option<VirtioBlk> {
+        let features = transport.negotiate(FEATURE_RO);
+
+        let Some((queue, queue_memory)) = transport.setup_queue(0, frame_allocator, physical_memory_offset) else {
+            transport.fail();
+            return None;
+        };
+        let bounce = DmaRegion::allocate(frame_allocator, physical_memory_offset, 1 + DATA_PAGES)?;
+        transport.driver_ok();
+
+        let capacity = u64::from(transport.config_read32(0))
+            | (u64::from(transport.config_read32(4)) << 32);
+        Some(VirtioBlk {
+            transport,
+            queue,
+            _queue_memory: queue_memory,
+            bounce,
+            capacity,
+            read_only: features & FEATURE_RO != 0,
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Bounce buffer layout: header at 0, status byte at 16, data at 4096.
+    fn submit(&mut self, kind: u32, lba: u64, sectors: usize) -> Result<(), BlockError> {
+        let base = self.bounce.as_mut_ptr();
+        let phys = self.bounce.phys_addr().as_u64();
+        unsafe {
+            let header = base as *mut u32;
+            header.write_volatile(kind);
+            header.add(1).write_volatile(0);
+            (base.add(8) as *mut u64).write_volatile(lba);
+            base.add(16).write_volatile(STATUS_PENDING);
+        }
+
+        let chain = [
+            Buffer { phys_addr: phys, len: 16, device_writable: false },
+            Buffer {
+                phys_addr: phys + 4096,
+                len: (sectors * SECTOR_SIZE) as u32,
+                device_writable: kind == REQUEST_IN,
+            },
+            Buffer { phys_addr: phys + 16, len: 1, device_writable: true },
+        ];
+        let head = self.queue.add_chain(&chain).ok_or(BlockError::Io)?;
+        self.transport.notify(0);
+
+        let (done, _) = super::poll_used(&mut self.queue, POLL_LIMIT).ok_or(BlockError::Timeout)?;
+        self.transport.ack_interrupt();
+        debug_assert_eq!(done, head);
+        match unsafe { base.add(16).read_volatile() } {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(BlockError::Unsupported),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    fn data_area(&mut self, sectors: usize) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self.bounce.as_mut_ptr().add(4096), sectors * SECTOR_SIZE)
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self.capacity, lba, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE).enumerate() {
+            let sectors = chunk.len() / SECTOR_SIZE;
+            let chunk_lba = lba + (i * MAX_SECTORS_PER_REQUEST) as u64;
+            self.submit(REQUEST_IN, chunk_lba, sectors)?;
+            chunk.copy_from_slice(self.data_area(sectors));
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        block::check_request(self.capacity, lba, buf.len())?;
+        for (i, chunk) in buf.chunks(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE).enumerate() {
+            let sectors = chunk.len() / SECTOR_SIZE;
+            let chunk_lba = lba + (i * MAX_SECTORS_PER_REQUEST) as u64;
+            self.data_area(sectors).copy_from_slice(chunk);
+            self.submit(REQUEST_OUT, chunk_lba, sectors)?;
+        }
+        Ok(())
+    }
+}
+
+/// virtio-blk devices found by `init`, in PCI scan order.
+pub static DEVICES: Mutex<Vec<VirtioBlk>> = Mutex::new(Vec::new());
+
+/// Probes every virtio-blk function on the PCI bus. Returns how many were
+/// brought up.
+pub fn init(frame_allocator: &mut BootInfoFrameAllocator, physical_memory_offset: VirtAddr) -> usize {
+    let mut devices = DEVICES.lock();
+    for &pci_device in pci::devices() {
+        if pci_device.vendor_id != super::VENDOR_ID || pci_device.device_id != DEVICE_ID {
+            continue;
+        }
+        let device = LegacyTransport::new(pci_device)
+            .and_then(|transport| VirtioBlk::new(transport, frame_allocator, physical_memory_offset));
+        if let Some(device) = device {
+            crate::block::register("virtio", crate::block::Disk::VirtioBlk(devices.len()));
+            devices.push(device);
+        }
+    }
+    devices.len()
+}
@@ -0,0 +1,469 @@
+//! virtio-console driver (legacy PCI device 0x1AF4:0x1003), port 0 only.
+//!
+//! QEMU's virtio-serial offers `VIRTIO_CONSOLE_F_MULTIPORT`, and with it the
+//! device passes no data until the driver has answered the control queue:
+//! driver-ready, then port-ready for each port it announces, then
+//! port-open for the console port. The driver takes the feature so it can
+//! answer; other ports are turned down. Receive buffers stay posted on the
+//! port's input queue and the device interrupt hands what arrived to the
+//! shell. Output is copied into a DMA page and polled to completion, as
+//! virtio-blk does.
+//!
+//! ```text
+//! -device virtio-serial-pci -chardev stdio,id=vc0,mux=on -device virtconsole,chardev=vc0
+//! ```
+//!
+//! and `console=vga,virtio` (or `serial,virtio`) on the kernel command
+//! line sends `print!` there as well.
+
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+use crate::interrupts;
+use crate::memory::{BootInfoFrameAllocator, DmaRegion};
+use crate::pci;
+use super::queue::{Buffer, Virtqueue};
+use super::LegacyTransport;
+
+pub const DEVICE_ID: u16 = 0x1003;
+
+const FEATURE_MULTIPORT: u32 = 1 << 1;
+
+/// Queue numbers with multiport: port 0's input and output, then the
+/// control queues. Ports 1 and up follow, but aren't set up.
+const PORT0_RX: u16 = 0;
+const PORT0_TX: u16 = 1;
+const CONTROL_RX: u16 = 2;
+const CONTROL_TX: u16 = 3;
+
+pub const DEVICE_READY: u16 = 0;
+pub const DEVICE_ADD: u16 = 1;
+pub const DEVICE_REMOVE: u16 = 2;
+pub const PORT_READY: u16 = 3;
+pub const CONSOLE_PORT: u16 = 4;
+pub const RESIZE: u16 = 5;
+pub const PORT_OPEN: u16 = 6;
+pub const PORT_NAME: u16 = 7;
+
+const RX_BUFFER_SIZE: usize = 128;
+const CONTROL_BUFFER_SIZE: usize = 64;
+const POLL_LIMIT: usize = 10_000_000;
+
+/// `struct virtio_console_control`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlMessage {
+    pub id: u32,
+    pub event: u16,
+    pub value: u16,
+}
+
+impl ControlMessage {
+    pub const SIZE: usize = 8;
+
+    pub fn parse(bytes: &[u8]) -> Option<ControlMessage> {
+        let bytes = bytes.get(..Self::SIZE)?;
+        Some(ControlMessage {
+            id: u32::from_le_bytes(bytes[0..4].try_into().ok()?),
+            event: u16::from_le_bytes([bytes[4], bytes[5]]),
+            value: u16::from_le_bytes([bytes[6], bytes[7]]),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.id.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.event.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+}
+
+/// What the driver answers to a control message from the device: port 0
+/// is taken and opened as soon as it is a console, anything else refused.
+pub fn control_reply(message: ControlMessage) -> Option<ControlMessage> {
+    let reply = |event, value| Some(ControlMessage { id: message.id, event, value });
+    match message.event {
+        DEVICE_ADD => reply(PORT_READY, u16::from(message.id == 0)),
+        CONSOLE_PORT if message.id == 0 => reply(PORT_OPEN, 1),
+        _ => None,
+    }
+}
+
+/// Bytes received but not yet taken by the shell.
+pub struct InputRing {
+    bytes: [u8; 256],
+    head: usize,
+    len: usize,
+}
+
+impl InputRing {
+    pub const fn new() -> InputRing {
+        InputRing { bytes: [0; 256], head: 0, len: 0 }
+    }
+
+    /// Drops the byte when the ring is full.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.len == self.bytes.len() {
+            return false;
+        }
+        self.bytes[(self.head + self.len) % self.bytes.len()] = byte;
+        self.len += 1;
+        true
+    }
+
+    pub fn peek(&self) -> Option<u8> {
+        (self.len > 0).then(|| self.bytes[self.head])
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.head = (self.head + 1) % self.bytes.len();
+        self.len -= 1;
+        Some(byte)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for InputRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A queue with a page of buffers for it, cut into `slot_size` pieces.
+struct Channel {
+    queue: Virtqueue,
+    _queue_memory: DmaRegion,
+    buffers: DmaRegion,
+    slot_size: usize,
+}
+
+impl Channel {
+    fn new(
+        transport: &LegacyTransport,
+        index: u16,
+        slot_size: usize,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        physical_memory_offset: VirtAddr,
+    ) -> Option<Channel> {
+        let (queue, queue_memory) = transport.setup_queue(index, frame_allocator, physical_memory_offset)?;
+        let buffers = DmaRegion::allocate(frame_allocator, physical_memory_offset, 1)?;
+        Some(Channel { queue, _queue_memory: queue_memory, buffers, slot_size })
+    }
+
+    fn slot(&self, phys_addr: u64, len: usize) -> &[u8] {
+        let offset = (phys_addr - self.buffers.phys_addr().as_u64()) as usize;
+        let len = len.min(self.slot_size);
+        unsafe { core::slice::from_raw_parts(self.buffers.as_mut_ptr().add(offset), len) }
+    }
+
+    /// Posts every slot for the device to write into.
+    fn post_all(&mut self) {
+        let phys = self.buffers.phys_addr().as_u64();
+        let slots = (self.buffers.len() / self.slot_size).min(usize::from(self.queue.size()));
+        for slot in 0..slots {
+            self.repost(phys + (slot * self.slot_size) as u64);
+        }
+    }
+
+    fn repost(&mut self, phys_addr: u64) {
+        let buffer = Buffer { phys_addr, len: self.slot_size as u32, device_writable: true };
+        let _ = self.queue.add_chain(&[buffer]);
+    }
+
+    /// Takes the next filled buffer off the used ring, hands it to `f` and
+    /// posts it again.
+    fn receive(&mut self, f: impl FnOnce(&[u8])) -> bool {
+        let Some((head, len)) = self.queue.pop_used() else { return false };
+        // the descriptor still holds the buffer's address
+        let phys_addr = self.queue.descriptor(head).addr;
+        f(self.slot(phys_addr, len as usize));
+        self.repost(phys_addr);
+        true
+    }
+
+    /// Sends the first `len` bytes of the buffer page and waits for them.
+    fn send(&mut self, transport: &LegacyTransport, index: u16, len: usize) -> bool {
+        let buffer = Buffer { phys_addr: self.buffers.phys_addr().as_u64(), len: len as u32, device_writable: false };
+        if self.queue.add_chain(&[buffer]).is_none() {
+            return false;
+        }
+        transport.notify(index);
+        super::poll_used(&mut self.queue, POLL_LIMIT).is_some()
+    }
+
+    fn page(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.buffers.as_mut_ptr(), self.buffers.len()) }
+    }
+}
+
+pub struct VirtioConsole {
+    transport: LegacyTransport,
+    rx: Channel,
+    tx: Channel,
+    control_rx: Channel,
+    control_tx: Channel,
+    input: InputRing,
+    /// We told the device port 0 is open.
+    port_open: bool,
+    /// Whether something is connected on the host side, as last reported.
+    host_connected: bool,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+impl VirtioConsole {
+    pub fn new(
+        transport: LegacyTransport,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        physical_memory_offset: VirtAddr,
+    ) -> Option<VirtioConsole> {
+        if transport.negotiate(FEATURE_MULTIPORT) & FEATURE_MULTIPORT == 0 {
+            // without it the control queues don't exist; not handled
+            transport.fail();
+            return None;
+        }
+        let mut channel = |index, slot_size| {
+            Channel::new(&transport, index, slot_size, frame_allocator, physical_memory_offset)
+        };
+        let (Some(rx), Some(tx), Some(control_rx), Some(control_tx)) = (
+            channel(PORT0_RX, RX_BUFFER_SIZE),
+            channel(PORT0_TX, 4096),
+            channel(CONTROL_RX, CONTROL_BUFFER_SIZE),
+            channel(CONTROL_TX, ControlMessage::SIZE),
+        ) else {
+            transport.fail();
+            return None;
+        };
+        let mut console = VirtioConsole {
+            transport,
+            rx,
+            tx,
+            control_rx,
+            control_tx,
+            input: InputRing::new(),
+            port_open: false,
+            host_connected: false,
+            bytes_sent: 0,
+            bytes_received: 0,
+        };
+        console.rx.post_all();
+        console.control_rx.post_all();
+        console.transport.notify(PORT0_RX);
+        console.transport.notify(CONTROL_RX);
+        console.transport.driver_ok();
+
+        console.send_control(ControlMessage { id: 0, event: DEVICE_READY, value: 1 });
+        // QEMU answers each control message while the notify is still
+        // being handled, so the port is normally open after this
+        for _ in 0..16 {
+            if console.port_open || !console.process_control() {
+                break;
+            }
+        }
+        Some(console)
+    }
+
+    fn send_control(&mut self, message: ControlMessage) -> bool {
+        self.control_tx.page()[..ControlMessage::SIZE].copy_from_slice(&message.to_bytes());
+        self.control_tx.send(&self.transport, CONTROL_TX, ControlMessage::SIZE)
+    }
+
+    /// Answers the control messages that arrived. Returns whether there
+    /// were any.
+    fn process_control(&mut self) -> bool {
+        let mut any = false;
+        loop {
+            let mut message = None;
+            if !self.control_rx.receive(|bytes| message = ControlMessage::parse(bytes)) {
+                break;
+            }
+            any = true;
+            let Some(message) = message else { continue };
+            if message.id == 0 && message.event == PORT_OPEN {
+                self.host_connected = message.value != 0;
+            }
+            if let Some(reply) = control_reply(message) {
+                if reply.event == PORT_OPEN {
+                    self.port_open = true;
+                }
+                self.send_control(reply);
+            }
+        }
+        self.transport.notify(CONTROL_RX);
+        any
+    }
+
+    /// Moves what the device received into the input ring.
+    fn receive(&mut self) {
+        let input = &mut self.input;
+        let mut received = 0;
+        while self.rx.receive(|bytes| {
+            received += bytes.len() as u64;
+            bytes.iter().for_each(|&byte| {
+                input.push(byte);
+            });
+        }) {}
+        self.bytes_received += received;
+        if received > 0 {
+            self.transport.notify(PORT0_RX);
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.port_open
+    }
+
+    pub fn host_connected(&self) -> bool {
+        self.host_connected
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(self.tx.buffers.len()) {
+            self.tx.page()[..chunk.len()].copy_from_slice(chunk);
+            if self.tx.send(&self.transport, PORT0_TX, chunk.len()) {
+                self.bytes_sent += chunk.len() as u64;
+            }
+        }
+    }
+}
+
+/// Collects formatted output in the transmit page, sending it whenever
+/// the page fills and once at the end.
+struct TxWriter<'a> {
+    console: &'a mut VirtioConsole,
+    len: usize,
+}
+
+impl TxWriter<'_> {
+    fn flush(&mut self) {
+        if self.len > 0 && self.console.tx.send(&self.console.transport, PORT0_TX, self.len) {
+            self.console.bytes_sent += self.len as u64;
+        }
+        self.len = 0;
+    }
+}
+
+impl Write for TxWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == self.console.tx.buffers.len() {
+                self.flush();
+            }
+            let len = self.len;
+            self.console.tx.page()[len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// The console found by `init`. Only taken with interrupts off, since its
+/// interrupt handler takes it too.
+pub static CONSOLE: Mutex<Option<VirtioConsole>> = Mutex::new(None);
+
+/// Brings up the first virtio-console on the PCI bus and hooks its IRQ.
+/// Returns whether one was found.
+pub fn init(frame_allocator: &mut BootInfoFrameAllocator, physical_memory_offset: VirtAddr) -> bool {
+    let Some(pci_device) = pci::find(super::VENDOR_ID, DEVICE_ID) else { return false };
+    let interrupt_line = pci_device.interrupt_line;
+    let Some(console) = LegacyTransport::new(pci_device)
+        .and_then(|transport| VirtioConsole::new(transport, frame_allocator, physical_memory_offset))
+    else {
+        return false;
+    };
+    without_interrupts(|| *CONSOLE.lock() = Some(console));
+    if interrupt_line < 16 {
+        interrupts::register_irq(interrupt_line, interrupt_handler);
+    }
+    true
+}
+
+/// Runs `f` on the console, if there is one.
+pub fn with_console<R>(f: impl FnOnce(&mut VirtioConsole) -> R) -> Option<R> {
+    without_interrupts(|| CONSOLE.lock().as_mut().map(f))
+}
+
+fn interrupt_handler() {
+    with_console(|console| {
+        console.transport.ack_interrupt();
+        console.process_control();
+        console.receive();
+    });
+    deliver_input();
+}
+
+/// Hands buffered input to the console's line discipline, which passes
+/// finished lines on to the shell.
+pub fn deliver_input() {
+    while let Some(byte) = with_console(|console| console.input.pop()).flatten() {
+        crate::console::receive(crate::console::Terminal::Virtio, byte);
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    with_console(|console| {
+        let mut writer = TxWriter { console, len: 0 };
+        let _ = writer.write_fmt(args);
+        writer.flush();
+    });
+}
+
+//test case
+#[test_case]
+fn test_control_message_layout() {
+    let message = ControlMessage { id: 0x0102_0304, event: PORT_OPEN, value: 1 };
+    let bytes = message.to_bytes();
+    assert_eq!(bytes, [0x04, 0x03, 0x02, 0x01, 6, 0, 1, 0]);
+    assert_eq!(ControlMessage::parse(&bytes), Some(message));
+    assert_eq!(ControlMessage::parse(&bytes[..7]), None);
+}
+
+#[test_case]
+fn test_control_handshake() {
+    let from_device = |id, event, value| ControlMessage { id, event, value };
+    // port 0 is accepted, then opened once the device says it's a console
+    assert_eq!(control_reply(from_device(0, DEVICE_ADD, 0)), Some(from_device(0, PORT_READY, 1)));
+    assert_eq!(control_reply(from_device(0, CONSOLE_PORT, 1)), Some(from_device(0, PORT_OPEN, 1)));
+    // other ports are turned down
+    assert_eq!(control_reply(from_device(3, DEVICE_ADD, 0)), Some(from_device(3, PORT_READY, 0)));
+    assert_eq!(control_reply(from_device(3, CONSOLE_PORT, 1)), None);
+    // nothing to answer
+    assert_eq!(control_reply(from_device(0, PORT_OPEN, 1)), None);
+    assert_eq!(control_reply(from_device(0, PORT_NAME, 0)), None);
+    assert_eq!(control_reply(from_device(0, RESIZE, 0)), None);
+}
+
+#[test_case]
+fn test_input_ring() {
+    let mut ring = InputRing::new();
+    assert_eq!(ring.pop(), None);
+    for round in 0..3u8 {
+        for byte in 0..=255u8 {
+            assert!(ring.push(byte.wrapping_add(round)));
+        }
+        assert!(!ring.push(0), "a full ring takes no more");
+        assert_eq!(ring.peek(), Some(round));
+        for byte in 0..=255u8 {
+            assert_eq!(ring.pop(), Some(byte.wrapping_add(round)));
+        }
+        assert!(ring.is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+//! virtio-net driver (legacy PCI device 0x1AF4:0x1000), one receive and
+//! one transmit queue.
+//!
+//! No offloads and no `VIRTIO_NET_F_MRG_RXBUF` are negotiated, so every
+//! buffer starts with a 10-byte `virtio_net_hdr` that is all zeros on the
+//! way out and skipped on the way in, and a receive buffer holds a whole
+//! frame. Half-page receive buffers stay posted on queue 0; `receive`
+//! copies a frame out and posts its buffer again. Frames to send are
+//! copied behind a zeroed header in a DMA page and polled to completion,
+//! as virtio-blk does, and the interrupt is left alone: `net::poll` runs
+//! from the main loop.
+//!
+//! ```text
+//! -netdev user,id=n0 -device virtio-net-pci,netdev=n0
+//! ```
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+use crate::memory::{BootInfoFrameAllocator, DmaRegion};
+use crate::pci;
+use crate::rtl8139::TxError;
+use super::queue::{Buffer, Virtqueue};
+use super::LegacyTransport;
+
+pub const DEVICE_ID: u16 = 0x1000;
+
+const FEATURE_MAC: u32 = 1 << 5;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// `struct virtio_net_hdr` without the merged-buffers count.
+pub const HEADER_LEN: usize = 10;
+/// Destination, source, ethertype and 1500 bytes of payload.
+pub const MAX_FRAME_LEN: usize = 1514;
+const RX_SLOT_SIZE: usize = 2048;
+const RX_PAGES: usize = 8;
+const POLL_LIMIT: usize = 10_000_000;
+
+/// The frame in a filled receive buffer of `len` bytes, header and all.
+pub fn rx_frame(buffer: &[u8], len: usize) -> Option<&[u8]> {
+    buffer.get(HEADER_LEN..len.min(buffer.len())).filter(|frame| !frame.is_empty())
+}
+
+pub struct VirtioNet {
+    transport: LegacyTransport,
+    rx: Virtqueue,
+    _rx_memory: DmaRegion,
+    rx_buffers: DmaRegion,
+    tx: Virtqueue,
+    _tx_memory: DmaRegion,
+    tx_buffer: DmaRegion,
+    mac: [u8; 6],
+    rx_packets: u64,
+    tx_packets: u64,
+}
+
+impl VirtioNet {
+    /// Fails on devices that don't give a MAC address.
+    pub fn new(
+        transport: LegacyTransport,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        physical_memory_offset: VirtAddr,
+    ) -> Option<VirtioNet> {
+        let features = transport.negotiate(FEATURE_MAC);
+        if features & FEATURE_MAC == 0 {
+            transport.fail();
+            return None;
+        }
+        let queues = transport.setup_queue(RX_QUEUE, frame_allocator, physical_memory_offset).zip(
+            transport.setup_queue(TX_QUEUE, frame_allocator, physical_memory_offset),
+        );
+        let Some(((rx, rx_memory), (tx, tx_memory))) = queues else {
+            transport.fail();
+            return None;
+        };
+        let rx_buffers = DmaRegion::allocate(frame_allocator, physical_memory_offset, RX_PAGES)?;
+        let tx_buffer = DmaRegion::allocate(frame_allocator, physical_memory_offset, 1)?;
+        let mut mac = [0; 6];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = transport.config_read8(i as u16);
+        }
+        let mut device = VirtioNet {
+            transport,
+            rx,
+            _rx_memory: rx_memory,
+            rx_buffers,
+            tx,
+            _tx_memory: tx_memory,
+            tx_buffer,
+            mac,
+            rx_packets: 0,
+            tx_packets: 0,
+        };
+        let phys = device.rx_buffers.phys_addr().as_u64();
+        let slots = (device.rx_buffers.len() / RX_SLOT_SIZE).min(usize::from(device.rx.size()));
+        for slot in 0..slots {
+            device.post(phys + (slot * RX_SLOT_SIZE) as u64);
+        }
+        device.transport.driver_ok();
+        device.transport.notify(RX_QUEUE);
+        Some(device)
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// Frames received and sent so far.
+    pub fn packets(&self) -> (u64, u64) {
+        (self.rx_packets, self.tx_packets)
+    }
+
+    fn post(&mut self, phys_addr: u64) {
+        let buffer = Buffer { phys_addr, len: RX_SLOT_SIZE as u32, device_writable: true };
+        let _ = self.rx.add_chain(&[buffer]);
+    }
+
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), TxError> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(TxError::TooLarge);
+        }
+        let len = HEADER_LEN + frame.len();
+        unsafe {
+            let base = self.tx_buffer.as_mut_ptr();
+            core::ptr::write_bytes(base, 0, HEADER_LEN);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), base.add(HEADER_LEN), frame.len());
+        }
+        let buffer = Buffer { phys_addr: self.tx_buffer.phys_addr().as_u64(), len: len as u32, device_writable: false };
+        self.tx.add_chain(&[buffer]).ok_or(TxError::Busy)?;
+        self.transport.notify(TX_QUEUE);
+        super::poll_used(&mut self.tx, POLL_LIMIT).ok_or(TxError::Busy)?;
+        self.transport.ack_interrupt();
+        self.tx_packets += 1;
+        Ok(())
+    }
+
+    /// Next received frame (destination MAC first), if the device filled a
+    /// buffer.
+    pub fn receive(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let (head, len) = self.rx.pop_used()?;
+            // the descriptor still holds the buffer's address
+            let phys_addr = self.rx.descriptor(head).addr;
+            let offset = (phys_addr - self.rx_buffers.phys_addr().as_u64()) as usize;
+            let buffer = unsafe { core::slice::from_raw_parts(self.rx_buffers.as_mut_ptr().add(offset), RX_SLOT_SIZE) };
+            let frame = rx_frame(buffer, len as usize).map(<[u8]>::to_vec);
+            self.post(phys_addr);
+            self.transport.notify(RX_QUEUE);
+            if let Some(frame) = frame {
+                self.rx_packets += 1;
+                return Some(frame);
+            }
+        }
+    }
+}
+
+/// The device `init` brought up.
+pub static DEVICE: Mutex<Option<VirtioNet>> = Mutex::new(None);
+
+/// Brings up the first virtio-net function on the PCI bus. Returns its
+/// MAC address.
+pub fn init(frame_allocator: &mut BootInfoFrameAllocator, physical_memory_offset: VirtAddr) -> Option<[u8; 6]> {
+    let pci_device = pci::find(super::VENDOR_ID, DEVICE_ID)?;
+    let device = VirtioNet::new(LegacyTransport::new(pci_device)?, frame_allocator, physical_memory_offset)?;
+    let mac = device.mac();
+    without_interrupts(|| *DEVICE.lock() = Some(device));
+    Some(mac)
+}
+
+pub fn send(frame: &[u8]) -> Result<(), TxError> {
+    without_interrupts(|| DEVICE.lock().as_mut().ok_or(TxError::NoDevice)?.send(frame))
+}
+
+pub fn receive() -> Option<Vec<u8>> {
+    without_interrupts(|| DEVICE.lock().as_mut()?.receive())
+}
+
+//test case
+#[test_case]
+fn test_rx_frame() {
+    let mut buffer = [0u8; 64];
+    buffer[HEADER_LEN..HEADER_LEN + 6].copy_from_slice(&[0xff; 6]);
+    assert_eq!(rx_frame(&buffer, HEADER_LEN + 60).map(<[u8]>::len), Some(60));
+    assert_eq!(rx_frame(&buffer, HEADER_LEN + 60).unwrap()[..6], [0xff; 6]);
+    // the device never says more than the buffer holds, but if it did
+    assert_eq!(rx_frame(&buffer, 4096).map(<[u8]>::len), Some(64 - HEADER_LEN));
+    assert_eq!(rx_frame(&buffer, HEADER_LEN), None);
+    assert_eq!(rx_frame(&buffer, 4), None);
+}
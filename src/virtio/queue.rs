@@ -0,0 +1,322 @@
+//! Split virtqueue (legacy layout): descriptor table, available ring and
+//! used ring in one physically contiguous block.
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+/// The legacy interface places the used ring on the next 4 KiB boundary.
+pub const QUEUE_ALIGN: usize = 4096;
+
+pub const DESC_F_NEXT: u16 = 1;
+pub const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+/// Byte offsets of the three parts of a queue with `size` entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLayout {
+    pub avail_offset: usize,
+    pub used_offset: usize,
+    pub total_size: usize,
+}
+
+impl QueueLayout {
+    pub fn new(size: u16) -> QueueLayout {
+        let size = usize::from(size);
+        let desc_bytes = 16 * size;
+        // flags, idx, ring[size], used_event
+        let avail_bytes = 2 * (3 + size);
+        // flags, idx, ring[size] of (id, len), avail_event
+        let used_bytes = 2 * 3 + 8 * size;
+        let used_offset = align_up(desc_bytes + avail_bytes, QUEUE_ALIGN);
+        QueueLayout {
+            avail_offset: desc_bytes,
+            used_offset,
+            total_size: align_up(used_offset + used_bytes, QUEUE_ALIGN),
+        }
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// One buffer of a descriptor chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub phys_addr: u64,
+    pub len: u32,
+    /// The device writes into this buffer (as opposed to reading it).
+    pub device_writable: bool,
+}
+
+pub struct Virtqueue {
+    base: *mut u8,
+    phys_base: u64,
+    size: u16,
+    layout: QueueLayout,
+    free_head: u16,
+    num_free: u16,
+    /// Our copy of avail.idx; only we ever write it.
+    avail_idx: u16,
+    /// Next used ring entry we haven't consumed yet.
+    last_used_idx: u16,
+}
+
+// The raw pointer targets memory owned exclusively by this queue.
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    /// # Safety
+    /// `base` must point to `QueueLayout::new(size).total_size` zeroed bytes
+    /// that stay valid (and visible to the device at `phys_base`) for the
+    /// lifetime of the queue.
+    pub unsafe fn new(base: *mut u8, phys_base: u64, size: u16) -> Virtqueue {
+        assert!(size > 0 && size.is_power_of_two(), "virtqueue size must be a power of two");
+        let mut queue = Virtqueue {
+            base,
+            phys_base,
+            size,
+            layout: QueueLayout::new(size),
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        for i in 0..size {
+            let mut desc = queue.descriptor(i);
+            desc.next = if i + 1 < size { i + 1 } else { 0 };
+            queue.set_descriptor(i, desc);
+        }
+        queue
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.phys_base
+    }
+
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    fn desc_ptr(&self, index: u16) -> *mut Descriptor {
+        debug_assert!(index < self.size);
+        unsafe { (self.base as *mut Descriptor).add(usize::from(index)) }
+    }
+
+    pub fn descriptor(&self, index: u16) -> Descriptor {
+        unsafe { read_volatile(self.desc_ptr(index)) }
+    }
+
+    fn set_descriptor(&mut self, index: u16, desc: Descriptor) {
+        unsafe { write_volatile(self.desc_ptr(index), desc) }
+    }
+
+    fn avail_field(&self, index: usize) -> *mut u16 {
+        unsafe { (self.base.add(self.layout.avail_offset) as *mut u16).add(index) }
+    }
+
+    fn used_field(&self, offset: usize) -> *mut u8 {
+        unsafe { self.base.add(self.layout.used_offset + offset) }
+    }
+
+    /// avail.idx as the device sees it.
+    pub fn avail_index(&self) -> u16 {
+        unsafe { read_volatile(self.avail_field(1)) }
+    }
+
+    /// Head descriptor published in available ring slot `slot`.
+    pub fn avail_entry(&self, slot: u16) -> u16 {
+        unsafe { read_volatile(self.avail_field(2 + usize::from(slot % self.size))) }
+    }
+
+    pub fn used_index(&self) -> u16 {
+        unsafe { read_volatile(self.used_field(2) as *const u16) }
+    }
+
+    /// Links `buffers` into a descriptor chain and publishes its head in the
+    /// available ring. Returns the head index, or `None` when there aren't
+    /// enough free descriptors.
+    pub fn add_chain(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.num_free) {
+            return None;
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut desc = self.descriptor(index);
+            let next_free = desc.next;
+            let last = i + 1 == buffers.len();
+            desc.addr = buffer.phys_addr;
+            desc.len = buffer.len;
+            desc.flags = if buffer.device_writable { DESC_F_WRITE } else { 0 };
+            if !last {
+                desc.flags |= DESC_F_NEXT;
+                desc.next = next_free;
+            }
+            self.set_descriptor(index, desc);
+            self.free_head = next_free;
+            index = next_free;
+        }
+        self.num_free -= buffers.len() as u16;
+
+        let slot = 2 + usize::from(self.avail_idx % self.size);
+        unsafe { write_volatile(self.avail_field(slot), head) };
+        // The ring entry must be visible before the index that publishes it.
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe { write_volatile(self.avail_field(1), self.avail_idx) };
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Takes the next completed chain off the used ring, returning its head
+    /// and the number of bytes the device wrote, and frees its descriptors.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if self.used_index() == self.last_used_idx {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = usize::from(self.last_used_idx % self.size);
+        let entry = self.used_field(4 + 8 * slot);
+        let (id, len) = unsafe {
+            (read_volatile(entry as *const u32), read_volatile(entry.add(4) as *const u32))
+        };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        let head = id as u16;
+        self.free_chain(head);
+        Some((head, len))
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        let mut index = head;
+        loop {
+            let mut desc = self.descriptor(index);
+            let has_next = desc.flags & DESC_F_NEXT != 0;
+            let next = desc.next;
+            desc.flags = 0;
+            if !has_next {
+                desc.next = self.free_head;
+            }
+            self.set_descriptor(index, desc);
+            self.num_free += 1;
+            if !has_next {
+                break;
+            }
+            index = next;
+        }
+        self.free_head = head;
+    }
+
+    /// Test helper standing in for the device: completes `head` with `len`.
+    #[cfg(test)]
+    fn device_complete(&mut self, head: u16, len: u32) {
+        let used_idx = self.used_index();
+        let slot = usize::from(used_idx % self.size);
+        let entry = self.used_field(4 + 8 * slot);
+        unsafe {
+            write_volatile(entry as *mut u32, u32::from(head));
+            write_volatile(entry.add(4) as *mut u32, len);
+            write_volatile(self.used_field(2) as *mut u16, used_idx.wrapping_add(1));
+        }
+    }
+}
+
+//test case
+#[cfg(test)]
+fn test_queue(size: u16) -> (Virtqueue, alloc::boxed::Box<[u8]>) {
+    use alloc::alloc::{alloc_zeroed, Layout};
+    let total = QueueLayout::new(size).total_size;
+    let layout = Layout::from_size_align(total, QUEUE_ALIGN).unwrap();
+    let memory = unsafe {
+        let ptr = alloc_zeroed(layout);
+        alloc::boxed::Box::from_raw(core::ptr::slice_from_raw_parts_mut(ptr, total))
+    };
+    let queue = unsafe { Virtqueue::new(memory.as_ptr() as *mut u8, 0x10_0000, size) };
+    (queue, memory)
+}
+
+#[test_case]
+fn test_queue_layout() {
+    let layout = QueueLayout::new(256);
+    assert_eq!(layout.avail_offset, 4096);
+    assert_eq!(layout.used_offset, 8192);
+    assert_eq!(layout.total_size, 12288);
+    let small = QueueLayout::new(8);
+    assert_eq!(small.avail_offset, 128);
+    assert_eq!(small.used_offset, 4096);
+    assert_eq!(small.total_size, 8192);
+}
+
+#[test_case]
+fn test_descriptor_chaining() {
+    let (mut queue, _memory) = test_queue(8);
+    let buffers = [
+        Buffer { phys_addr: 0x1000, len: 16, device_writable: false },
+        Buffer { phys_addr: 0x2000, len: 512, device_writable: true },
+        Buffer { phys_addr: 0x3000, len: 1, device_writable: true },
+    ];
+    let head = queue.add_chain(&buffers).unwrap();
+    assert_eq!(head, 0);
+    assert_eq!(queue.num_free(), 5);
+
+    let first = queue.descriptor(0);
+    assert_eq!((first.addr, first.len, first.flags, first.next), (0x1000, 16, DESC_F_NEXT, 1));
+    let second = queue.descriptor(1);
+    assert_eq!(second.flags, DESC_F_NEXT | DESC_F_WRITE);
+    assert_eq!(second.next, 2);
+    let third = queue.descriptor(2);
+    assert_eq!(third.flags, DESC_F_WRITE);
+
+    assert_eq!(queue.avail_index(), 1);
+    assert_eq!(queue.avail_entry(0), 0);
+
+    queue.device_complete(head, 513);
+    assert_eq!(queue.pop_used(), Some((0, 513)));
+    assert_eq!(queue.pop_used(), None);
+    assert_eq!(queue.num_free(), 8);
+}
+
+#[test_case]
+fn test_queue_exhaustion_and_reuse() {
+    let (mut queue, _memory) = test_queue(4);
+    let buffer = Buffer { phys_addr: 0x1000, len: 8, device_writable: false };
+    assert!(queue.add_chain(&[buffer; 5]).is_none());
+    let a = queue.add_chain(&[buffer; 3]).unwrap();
+    assert!(queue.add_chain(&[buffer; 2]).is_none());
+    let b = queue.add_chain(&[buffer]).unwrap();
+    assert_eq!(queue.num_free(), 0);
+    queue.device_complete(a, 0);
+    assert_eq!(queue.pop_used(), Some((a, 0)));
+    // the freed chain is handed out again
+    assert_eq!(queue.add_chain(&[buffer; 3]), Some(a));
+    queue.device_complete(b, 0);
+    assert_eq!(queue.pop_used(), Some((b, 0)));
+}
+
+#[test_case]
+fn test_ring_index_wraparound() {
+    let (mut queue, _memory) = test_queue(4);
+    let buffer = Buffer { phys_addr: 0x1000, len: 8, device_writable: true };
+    // Drive avail.idx and used.idx across the u16 boundary.
+    for round in 0..70_000u32 {
+        let head = queue.add_chain(&[buffer]).unwrap();
+        let slot = queue.avail_index().wrapping_sub(1);
+        assert_eq!(queue.avail_entry(slot), head);
+        queue.device_complete(head, round);
+        assert_eq!(queue.pop_used(), Some((head, round)));
+    }
+    assert_eq!(queue.avail_index(), (70_000u32 % 65_536) as u16);
+    assert_eq!(queue.num_free(), 4);
+}
@@ -0,0 +1,547 @@
+//! Software watchdog for a wedged kernel.
+//!
+//! The main loop calls `pet()` every time around. The timer interrupt calls
+//! `check()`; once more than the timeout passes without a pet it prints
+//! what it can observe (the interrupted registers and stack, interrupt
+//! counters, which locks are held) and then panics or reboots.
+//!
+//! Kernel threads are watched one by one, from `scheduler::spawn` on. A
+//! thread beats whenever it yields or comes back from blocking, and can
+//! call `heartbeat()` in between (`pet()` is one for the thread it runs
+//! on); being preempted is no heartbeat. A thread the timer interrupt
+//! finds running, not halted, more than the stall threshold after its last
+//! heartbeat is reported on the serial port with its registers, the top of
+//! its stack and a backtrace, once per stall, and left running.
+//!
+//! Code spinning with interrupts off never sees the timer. With a local
+//! APIC and a PMU, `init` also has a cycle counter raise an NMI about once
+//! per second of busy CPU; one that finds the tick count where the last
+//! left it reports the interrupted code the same way. Any other NMI (QEMU's
+//! `nmi` monitor command) prints the report straight away.
+//!
+//! Nothing arms it except `init()`, which only `kernel_main` calls, so test
+//! kernels run without it unless a test calls `enable()` itself. Code that
+//! legitimately blocks for long holds a `suspend()` guard, which holds off
+//! the stall and NMI checks as well.
+//!
+//! Command line: `watchdog=<seconds>` (0 disables), `watchdog_action=panic|reboot`,
+//! `watchdog_stall=<seconds>` (0 stops watching threads).
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use spin::Once;
+use x86_64::structures::idt::InterruptStackFrameValue;
+use x86_64::VirtAddr;
+use crate::interrupts::registers::SavedRegisters;
+use crate::interrupts::ExceptionFrame;
+use crate::perf::{Event, Sampler};
+use crate::scheduler::{self, ThreadId};
+use crate::serial::RawSerial;
+use crate::{apic, cmdline, debug, info, interrupts, memory, symbols, time, vga_buffer, warn};
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_STALL_SECS: u64 = 5;
+
+/// Threads watched at once; one past these goes unwatched.
+const MAX_WATCHED: usize = 32;
+
+/// Words of the interrupted stack a report shows, from RSP up.
+const STACK_WORDS: u64 = 16;
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Action {
+    Panic = 0,
+    Reboot = 1,
+}
+
+/// Watchdog state. All methods take the current tick so tests can drive it
+/// with a fake clock.
+pub struct Watchdog {
+    last_pet: AtomicU64,
+    /// In ticks; 0 while disarmed.
+    timeout: AtomicU64,
+    suspended: AtomicUsize,
+    fired: AtomicBool,
+    action: AtomicU8,
+}
+
+impl Watchdog {
+    pub const fn new() -> Watchdog {
+        Watchdog {
+            last_pet: AtomicU64::new(0),
+            timeout: AtomicU64::new(0),
+            suspended: AtomicUsize::new(0),
+            fired: AtomicBool::new(false),
+            action: AtomicU8::new(Action::Panic as u8),
+        }
+    }
+
+    pub fn enable(&self, timeout_ticks: u64, action: Action, now: u64) {
+        self.last_pet.store(now, Ordering::Relaxed);
+        self.action.store(action as u8, Ordering::Relaxed);
+        self.fired.store(false, Ordering::Relaxed);
+        self.timeout.store(timeout_ticks, Ordering::Release);
+    }
+
+    pub fn disable(&self) {
+        self.timeout.store(0, Ordering::Release);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.timeout.load(Ordering::Acquire) != 0
+    }
+
+    pub fn action(&self) -> Action {
+        match self.action.load(Ordering::Relaxed) {
+            1 => Action::Reboot,
+            _ => Action::Panic,
+        }
+    }
+
+    pub fn pet(&self, now: u64) {
+        self.last_pet.store(now, Ordering::Relaxed);
+    }
+
+    /// Ticks since the last pet.
+    pub fn idle_ticks(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_pet.load(Ordering::Relaxed))
+    }
+
+    /// Returns true exactly once, the first time the timeout is exceeded
+    /// while armed and not suspended.
+    pub fn expired(&self, now: u64) -> bool {
+        let timeout = self.timeout.load(Ordering::Acquire);
+        if timeout == 0 || self.suspended.load(Ordering::Acquire) != 0 {
+            return false;
+        }
+        self.idle_ticks(now) > timeout && !self.fired.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::Acquire) != 0
+    }
+
+    /// Holds the watchdog off until the guard is dropped. Guards nest.
+    pub fn suspend<'a>(&'a self, now_fn: fn() -> u64) -> SuspendGuard<'a> {
+        self.suspended.fetch_add(1, Ordering::AcqRel);
+        SuspendGuard { watchdog: self, now_fn }
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SuspendGuard<'a> {
+    watchdog: &'a Watchdog,
+    now_fn: fn() -> u64,
+}
+
+impl Drop for SuspendGuard<'_> {
+    fn drop(&mut self) {
+        // the time spent suspended doesn't count against the next pet
+        self.watchdog.pet((self.now_fn)());
+        self.watchdog.suspended.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A slot nobody has.
+const FREE: u64 = u64::MAX;
+
+struct Heartbeat {
+    thread: AtomicU64,
+    last: AtomicU64,
+    reported: AtomicBool,
+}
+
+/// The last heartbeat of each watched thread. Like `Watchdog`, the methods
+/// take the current tick.
+pub struct Heartbeats {
+    slots: [Heartbeat; MAX_WATCHED],
+}
+
+impl Heartbeats {
+    pub const fn new() -> Heartbeats {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const SLOT: Heartbeat = Heartbeat { thread: AtomicU64::new(FREE), last: AtomicU64::new(0), reported: AtomicBool::new(false) };
+        Heartbeats { slots: [SLOT; MAX_WATCHED] }
+    }
+
+    fn find(&self, thread: ThreadId) -> Option<&Heartbeat> {
+        self.slots.iter().find(|slot| slot.thread.load(Ordering::Acquire) == thread.0)
+    }
+
+    /// Notes `thread` alive at `now`, watching it from now on. `false` if
+    /// it wasn't watched and every slot is taken.
+    pub fn beat(&self, thread: ThreadId, now: u64) -> bool {
+        let slot = self.find(thread).or_else(|| {
+            self.slots.iter().find(|slot| {
+                // the time goes first, so a check never sees a stale one
+                slot.thread.load(Ordering::Relaxed) == FREE && {
+                    slot.last.store(now, Ordering::Relaxed);
+                    slot.thread.compare_exchange(FREE, thread.0, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+                }
+            })
+        });
+        let Some(slot) = slot else { return false };
+        slot.last.store(now, Ordering::Relaxed);
+        slot.reported.store(false, Ordering::Relaxed);
+        true
+    }
+
+    /// Whether `thread` has been reported stalled since its last heartbeat.
+    pub fn is_reported(&self, thread: ThreadId) -> bool {
+        self.find(thread).is_some_and(|slot| slot.reported.load(Ordering::Acquire))
+    }
+
+    /// Stops watching `thread`.
+    pub fn forget(&self, thread: ThreadId) {
+        if let Some(slot) = self.find(thread) {
+            slot.thread.store(FREE, Ordering::Release);
+        }
+    }
+
+    /// Ticks since `thread`'s last heartbeat, if that is over `limit`; once
+    /// per stall, `None` again until the next heartbeat.
+    pub fn stalled(&self, thread: ThreadId, now: u64, limit: u64) -> Option<u64> {
+        let slot = self.find(thread)?;
+        let idle = now.saturating_sub(slot.last.load(Ordering::Relaxed));
+        (idle > limit && !slot.reported.swap(true, Ordering::AcqRel)).then_some(idle)
+    }
+}
+
+impl Default for Heartbeats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static WATCHDOG: Watchdog = Watchdog::new();
+static HEARTBEATS: Heartbeats = Heartbeats::new();
+/// In ticks; 0 while threads are not watched.
+static STALL_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The counter behind the NMI, once `init` started one.
+static SAMPLER: Once<Sampler> = Once::new();
+/// `time::ticks()` when the counter's last NMI came.
+static NMI_TICKS: AtomicU64 = AtomicU64::new(0);
+static NMI_REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Arms the watchdog from the command line (on by default).
+pub fn init() {
+    let secs = cmdline::get_u64("watchdog").unwrap_or(DEFAULT_TIMEOUT_SECS);
+    let action = match cmdline::get("watchdog_action") {
+        None | Some("panic") => Action::Panic,
+        Some("reboot") => Action::Reboot,
+        Some(other) => {
+            warn!("watchdog: unknown action '{}', using panic", other);
+            Action::Panic
+        }
+    };
+    if secs == 0 {
+        WATCHDOG.disable();
+        return;
+    }
+    enable(secs, action);
+    watch_threads(cmdline::get_u64("watchdog_stall").unwrap_or(DEFAULT_STALL_SECS));
+    start_nmi();
+}
+
+/// Reports threads that go `stall_secs` without a heartbeat; 0 stops.
+pub fn watch_threads(stall_secs: u64) {
+    STALL_TICKS.store(time::secs_to_ticks(stall_secs), Ordering::Relaxed);
+}
+
+/// Starts the cycle counter whose overflow NMI looks for a CPU stuck with
+/// interrupts off, with a period of about a second.
+fn start_nmi() {
+    let Some(mut apic) = apic::local() else {
+        info!("watchdog: no local APIC, so no NMI for code stuck with interrupts off");
+        return;
+    };
+    let period = crate::cpu::info().tsc_hz().unwrap_or(1_000_000_000);
+    let Some(sampler) = Sampler::start(Event::CoreCycles, period) else {
+        info!("watchdog: no free cycle counter, so no NMI for code stuck with interrupts off");
+        return;
+    };
+    NMI_TICKS.store(time::ticks(), Ordering::Relaxed);
+    SAMPLER.call_once(|| sampler);
+    apic.set_perfmon_nmi(false);
+    info!("watchdog: NMI every {} busy cycles", period.min(i32::MAX as u64));
+}
+
+pub fn enable(timeout_secs: u64, action: Action) {
+    WATCHDOG.enable(time::secs_to_ticks(timeout_secs).max(1), action, time::ticks());
+}
+
+pub fn disable() {
+    WATCHDOG.disable();
+}
+
+/// Also a `heartbeat` for the thread it runs on.
+pub fn pet() {
+    WATCHDOG.pet(time::ticks());
+    heartbeat();
+}
+
+/// Tells the watchdog the running thread is alive, and watches it from
+/// its first heartbeat on.
+pub fn heartbeat() {
+    if let Some(thread) = scheduler::try_current() {
+        HEARTBEATS.beat(thread, time::ticks());
+    }
+}
+
+/// Starts watching `thread`, as if it had just beat; the scheduler calls
+/// it when it spawns one.
+pub fn watch(thread: ThreadId) {
+    HEARTBEATS.beat(thread, time::ticks());
+}
+
+/// Stops watching `thread`; the scheduler calls it when a thread exits.
+pub fn forget(thread: ThreadId) {
+    HEARTBEATS.forget(thread);
+}
+
+/// Whether the timer has reported `thread` stalled since its last
+/// heartbeat.
+pub fn stall_reported(thread: ThreadId) -> bool {
+    HEARTBEATS.is_reported(thread)
+}
+
+pub fn suspend() -> SuspendGuard<'static> {
+    WATCHDOG.suspend(time::ticks)
+}
+
+/// Called from the timer interrupt.
+pub fn check(frame: &ExceptionFrame) {
+    let now = time::ticks();
+    check_stall(frame, now);
+    if !WATCHDOG.expired(now) {
+        return;
+    }
+    let _ = write_diagnostics(&mut EmergencyConsole, frame, WATCHDOG.idle_ticks(now));
+    match WATCHDOG.action() {
+        Action::Panic => panic!("watchdog: kernel wedged"),
+        Action::Reboot => crate::power::reboot(),
+    }
+}
+
+/// Reports the interrupted thread if it is past the stall threshold. One
+/// halted in a wait is not stuck, and neither is a process in ring 3.
+fn check_stall(frame: &ExceptionFrame, now: u64) {
+    let limit = STALL_TICKS.load(Ordering::Relaxed);
+    if limit == 0 || WATCHDOG.is_suspended() || frame.stack_frame.code_segment & 3 != 0 || after_hlt(&frame.stack_frame) {
+        return;
+    }
+    let Some(thread) = scheduler::try_current() else { return };
+    if let Some(idle) = HEARTBEATS.stalled(thread, now, limit) {
+        let mut out = RawSerial;
+        let _ = writeln!(out, "WATCHDOG: thread {} has gone {} ms without a heartbeat", thread, time::ticks_to_millis(idle));
+        let _ = write_context(&mut out, &frame.registers, &frame.stack_frame);
+    }
+}
+
+/// Whether the interrupt woke the CPU from a `hlt`.
+fn after_hlt(stack_frame: &InterruptStackFrameValue) -> bool {
+    const HLT: u8 = 0xf4;
+    let rip = stack_frame.instruction_pointer.as_u64();
+    debug::kernel_text().contains(&rip.wrapping_sub(1)) && unsafe { ((rip - 1) as *const u8).read() } == HLT
+}
+
+/// Called from the NMI handler, which may have interrupted anything, with
+/// any lock held and GS maybe still the program's: so no locks but the
+/// thread table's `try_lock`, and no per-CPU data.
+pub fn nmi(frame: &ExceptionFrame) {
+    let mut out = RawSerial;
+    let Some(sampler) = SAMPLER.get().filter(|sampler| sampler.overflowed()) else {
+        let _ = writeln!(out, "NMI");
+        let _ = write_context(&mut out, &frame.registers, &frame.stack_frame);
+        return;
+    };
+    let now = time::ticks();
+    let stuck = NMI_TICKS.swap(now, Ordering::Relaxed) == now && WATCHDOG.is_enabled() && !WATCHDOG.is_suspended();
+    if !stuck {
+        NMI_REPORTED.store(false, Ordering::Relaxed);
+    } else if !NMI_REPORTED.swap(true, Ordering::Relaxed) {
+        let _ = writeln!(out, "WATCHDOG: no timer tick since the last NMI, interrupts are off");
+        if let Some(thread) = scheduler::try_current() {
+            let _ = writeln!(out, "  on thread {}", thread);
+        }
+        let _ = write_context(&mut out, &frame.registers, &frame.stack_frame);
+    }
+    sampler.rearm();
+    if let Some(mut apic) = apic::local() {
+        apic.set_perfmon_nmi(false);
+    }
+}
+
+/// Where the interrupted code was, its registers, the words at the top of
+/// its stack and, in the kernel, its backtrace.
+fn write_context(out: &mut dyn Write, registers: &SavedRegisters, stack_frame: &InterruptStackFrameValue) -> fmt::Result {
+    let rip = stack_frame.instruction_pointer.as_u64();
+    write!(out, "  RIP {:#018x}", rip)?;
+    if let Some((name, offset)) = symbols::resolve(rip) {
+        write!(out, " {}+{:#x}", symbols::Demangled(name), offset)?;
+    }
+    let interrupts = if stack_frame.cpu_flags & RFLAGS_IF != 0 { "on" } else { "off" };
+    writeln!(out)?;
+    writeln!(out, "  RSP {:#018x} RFLAGS {:#x} (interrupts {})", stack_frame.stack_pointer.as_u64(), stack_frame.cpu_flags, interrupts)?;
+    write!(out, "{}", registers)?;
+    let rsp = stack_frame.stack_pointer.as_u64() & !7;
+    for row in (0..STACK_WORDS).step_by(4) {
+        write!(out, "  {:#018x}:", rsp.wrapping_add(row * 8))?;
+        for word in row..row + 4 {
+            match VirtAddr::try_new(rsp.wrapping_add(word * 8)) {
+                Ok(addr) if memory::is_mapped(addr) => write!(out, " {:016x}", unsafe { addr.as_ptr::<u64>().read_volatile() })?,
+                _ => write!(out, " ????????????????")?,
+            }
+        }
+        writeln!(out)?;
+    }
+    if stack_frame.code_segment & 3 != 0 {
+        return Ok(());
+    }
+    let mut frames = [0; debug::MAX_FRAMES];
+    // every frame pointer is checked before the walk reads it
+    let (count, stop) = unsafe { debug::walk_until(registers.rbp, &mut frames) };
+    debug::write_frames(out, &frames[..count], stop)
+}
+
+fn write_diagnostics(out: &mut dyn Write, frame: &ExceptionFrame, idle: u64) -> fmt::Result {
+    writeln!(out, "WATCHDOG: no pet for {} ticks ({} ms)", idle, time::ticks_to_millis(idle))?;
+    write_context(out, &frame.registers, &frame.stack_frame)?;
+    write!(out, "  IRQ counts:")?;
+    for irq in 0..16 {
+        let count = interrupts::irq_count(irq);
+        if count != 0 {
+            write!(out, " {}={}", irq, count)?;
+        }
+    }
+    writeln!(out)?;
+    let locks = [
+        ("vga writer", vga_buffer::WRITER.is_locked()),
+        ("serial", crate::serial::SERIAL1.is_locked()),
+        ("shell (command running)", interrupts::shell_busy()),
+        ("NIC", crate::rtl8139::NIC.is_locked()),
+        ("e1000", crate::e1000::NIC.is_locked()),
+        ("virtio-net", crate::virtio::net::DEVICE.is_locked()),
+        ("net interface", crate::net::INTERFACE.is_locked()),
+        ("virtio-blk", crate::virtio::blk::DEVICES.is_locked()),
+    ];
+    write!(out, "  locks held:")?;
+    let mut any = false;
+    for (name, _) in locks.iter().filter(|(_, held)| *held) {
+        write!(out, " [{}]", name)?;
+        any = true;
+    }
+    writeln!(out, "{}", if any { "" } else { " none" })?;
+    match scheduler::try_current() {
+        Some(thread) => writeln!(out, "  thread {} on the CPU", thread),
+        None => writeln!(out, "  thread table locked"),
+    }
+}
+
+/// Writes to the serial port directly and to the screen only if nobody
+/// holds the VGA writer, since the wedged code may own either lock.
+struct EmergencyConsole;
+
+impl Write for EmergencyConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut serial = unsafe { uart_16550::SerialPort::new(0x3F8) };
+        let _ = serial.write_str(s);
+        if let Some(mut writer) = vga_buffer::WRITER.try_lock() {
+            let _ = writer.write_str(s);
+        }
+        Ok(())
+    }
+}
+
+//test case
+#[test_case]
+fn test_watchdog_fires_after_missed_pet() {
+    let watchdog = Watchdog::new();
+    assert!(!watchdog.expired(1_000));
+    watchdog.enable(10, Action::Panic, 100);
+    watchdog.pet(105);
+    assert!(!watchdog.expired(115));
+    assert!(watchdog.expired(116));
+    // only reported once
+    assert!(!watchdog.expired(200));
+    watchdog.disable();
+    assert!(!watchdog.is_enabled());
+}
+
+#[cfg(test)]
+static FAKE_NOW: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn test_watchdog_suspend_guard() {
+    let watchdog = Watchdog::new();
+    let fake_now = || FAKE_NOW.load(Ordering::Relaxed);
+    watchdog.enable(10, Action::Reboot, 0);
+    {
+        let _outer = watchdog.suspend(fake_now);
+        let inner = watchdog.suspend(fake_now);
+        assert!(!watchdog.expired(50));
+        drop(inner);
+        assert!(!watchdog.expired(60));
+        FAKE_NOW.store(70, Ordering::Relaxed);
+    }
+    // dropping the last guard counts as a pet at tick 70
+    assert!(!watchdog.expired(80));
+    assert!(watchdog.expired(81));
+    assert_eq!(watchdog.action(), Action::Reboot);
+}
+
+#[test_case]
+fn test_thread_heartbeats() {
+    let heartbeats = Heartbeats::new();
+    let (thread, other) = (ThreadId(5), ThreadId(6));
+    // nothing is reported for a thread that never beat
+    assert_eq!(heartbeats.stalled(thread, 1_000, 10), None);
+    assert!(heartbeats.beat(thread, 100));
+    assert_eq!(heartbeats.stalled(thread, 110, 10), None);
+    assert_eq!(heartbeats.stalled(thread, 111, 10), Some(11));
+    // once per stall
+    assert_eq!(heartbeats.stalled(thread, 150, 10), None);
+    assert!(heartbeats.beat(thread, 150));
+    assert_eq!(heartbeats.stalled(thread, 170, 10), Some(20));
+    assert!(heartbeats.is_reported(thread) && !heartbeats.is_reported(other));
+    heartbeats.forget(thread);
+    assert_eq!(heartbeats.stalled(thread, 500, 10), None);
+    // every slot taken
+    for id in 100..100 + MAX_WATCHED as u64 {
+        assert!(heartbeats.beat(ThreadId(id), 0));
+    }
+    assert!(!heartbeats.beat(other, 0));
+}
+
+#[test_case]
+fn test_context_report() {
+    use alloc::string::String;
+
+    let stack = [0x1111_u64, 0x2222, 0x3333, 0x4444];
+    let registers = SavedRegisters { rax: 0xabcd, ..SavedRegisters::default() };
+    let stack_frame = InterruptStackFrameValue {
+        instruction_pointer: VirtAddr::new(check as *const () as u64),
+        code_segment: 8,
+        cpu_flags: 0x2,
+        stack_pointer: VirtAddr::from_ptr(stack.as_ptr()),
+        stack_segment: 0,
+    };
+    let mut out = String::new();
+    write_context(&mut out, &registers, &stack_frame).unwrap();
+    assert!(out.contains("(interrupts off)"), "{}", out);
+    assert!(out.contains("RAX=000000000000abcd"));
+    assert!(out.contains(" 0000000000001111 0000000000002222 0000000000003333 0000000000004444\n"));
+    // RBP is 0, the end of the chain
+    assert!(out.ends_with("Backtrace:\n  <no frames>\n"));
+    let halted = InterruptStackFrameValue { instruction_pointer: VirtAddr::new(0), ..stack_frame };
+    assert!(!after_hlt(&halted));
+}
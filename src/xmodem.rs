@@ -0,0 +1,264 @@
+//! XMODEM receiver (CRC-16 mode, 128- and 1024-byte blocks).
+//!
+//! The byte transport is abstracted behind `Channel` so the protocol can be
+//! driven from the serial port or, in tests, from a scripted buffer.
+
+use alloc::vec::Vec;
+use core::fmt;
+use crate::console::{self, Terminal};
+use crate::{serial, time};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Sent instead of NAK to ask for CRC-16 checksums.
+const CRC_REQUEST: u8 = b'C';
+
+/// Retries before giving up, for both the start and each block.
+const MAX_ERRORS: u32 = 10;
+/// ~3 s between start requests, ~1 s inside a block.
+const START_TIMEOUT_TICKS: u64 = crate::time::secs_to_ticks(3);
+const BYTE_TIMEOUT_TICKS: u64 = crate::time::secs_to_ticks(1);
+
+pub trait Channel {
+    /// Waits up to `timeout_ticks` for a byte.
+    fn read(&mut self, timeout_ticks: u64) -> Option<u8>;
+    fn write(&mut self, byte: u8);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmodemError {
+    /// The sender never started or stopped answering.
+    Timeout,
+    /// The sender cancelled the transfer.
+    Cancelled,
+    /// A block arrived out of order.
+    Sequence,
+    /// Too many corrupted blocks in a row.
+    TooManyErrors,
+    TooLarge,
+}
+
+impl fmt::Display for XmodemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            XmodemError::Timeout => "timed out",
+            XmodemError::Cancelled => "cancelled by sender",
+            XmodemError::Sequence => "block out of sequence",
+            XmodemError::TooManyErrors => "too many errors",
+            XmodemError::TooLarge => "transfer too large",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// CRC-16/XMODEM (polynomial 0x1021, initial value 0).
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Receives a file into `out`, refusing more than `max_len` bytes. The
+/// last block keeps its padding (the protocol doesn't carry the length).
+pub fn receive(channel: &mut impl Channel, out: &mut Vec<u8>, max_len: usize) -> Result<usize, XmodemError> {
+    let mut expected: u8 = 1;
+    let mut started = false;
+    let mut errors = 0;
+    let mut block = [0u8; 1024];
+    loop {
+        if !started {
+            channel.write(CRC_REQUEST);
+        }
+        let header = match channel.read(START_TIMEOUT_TICKS) {
+            Some(byte) => byte,
+            None => {
+                errors += 1;
+                if errors >= MAX_ERRORS {
+                    cancel(channel);
+                    return Err(XmodemError::Timeout);
+                }
+                if started {
+                    channel.write(NAK);
+                }
+                continue;
+            }
+        };
+        let len = match header {
+            SOH => 128,
+            STX => 1024,
+            EOT => {
+                channel.write(ACK);
+                return Ok(out.len());
+            }
+            CAN => return Err(XmodemError::Cancelled),
+            _ => {
+                purge(channel);
+                continue;
+            }
+        };
+        started = true;
+
+        match read_block(channel, &mut block[..len]) {
+            Some(number) if number == expected => {
+                if out.len() + len > max_len {
+                    cancel(channel);
+                    return Err(XmodemError::TooLarge);
+                }
+                out.extend_from_slice(&block[..len]);
+                expected = expected.wrapping_add(1);
+                errors = 0;
+                channel.write(ACK);
+            }
+            // our ACK got lost and the sender repeated the block
+            Some(number) if number == expected.wrapping_sub(1) => channel.write(ACK),
+            Some(_) => {
+                cancel(channel);
+                return Err(XmodemError::Sequence);
+            }
+            None => {
+                errors += 1;
+                if errors >= MAX_ERRORS {
+                    cancel(channel);
+                    return Err(XmodemError::TooManyErrors);
+                }
+                purge(channel);
+                channel.write(NAK);
+            }
+        }
+    }
+}
+
+/// Reads the rest of a block after its header byte. Returns the block
+/// number if the complement and CRC check out.
+fn read_block(channel: &mut impl Channel, data: &mut [u8]) -> Option<u8> {
+    let number = channel.read(BYTE_TIMEOUT_TICKS)?;
+    let complement = channel.read(BYTE_TIMEOUT_TICKS)?;
+    for byte in data.iter_mut() {
+        *byte = channel.read(BYTE_TIMEOUT_TICKS)?;
+    }
+    let crc = u16::from_be_bytes([channel.read(BYTE_TIMEOUT_TICKS)?, channel.read(BYTE_TIMEOUT_TICKS)?]);
+    (number ^ complement == 0xff && crc == crc16(data)).then_some(number)
+}
+
+/// Drops whatever is left of a damaged block.
+fn purge(channel: &mut impl Channel) {
+    while channel.read(1).is_some() {}
+}
+
+fn cancel(channel: &mut impl Channel) {
+    channel.write(CAN);
+    channel.write(CAN);
+}
+
+/// COM1, bypassing the console lock; reads take the raw bytes of the
+/// console's COM1 terminal, which the caller puts in raw mode for the
+/// transfer. Reads spin rather than halt, since
+/// the UART FIFO fills far quicker than the timer ticks; interrupts have
+/// to be enabled for the timeouts to advance.
+pub struct SerialChannel;
+
+impl Channel for SerialChannel {
+    fn read(&mut self, timeout_ticks: u64) -> Option<u8> {
+        let start = time::ticks();
+        loop {
+            if let Some(byte) = console::read_raw(Terminal::Serial) {
+                return Some(byte);
+            }
+            if time::ticks() - start >= timeout_ticks {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn write(&mut self, byte: u8) {
+        serial::write_byte_raw(byte);
+    }
+}
+
+/// Replays `input`; a `None` stands for the line going quiet for one
+/// timeout, as it does while the sender waits for our answer.
+#[cfg(test)]
+struct ScriptChannel {
+    input: alloc::collections::VecDeque<Option<u8>>,
+    output: Vec<u8>,
+}
+
+#[cfg(test)]
+impl ScriptChannel {
+    fn new(parts: &[&[u8]]) -> ScriptChannel {
+        let mut input = alloc::collections::VecDeque::new();
+        for (i, part) in parts.iter().enumerate() {
+            if i > 0 {
+                input.push_back(None);
+            }
+            input.extend(part.iter().copied().map(Some));
+        }
+        ScriptChannel { input, output: Vec::new() }
+    }
+}
+
+#[cfg(test)]
+impl Channel for ScriptChannel {
+    fn read(&mut self, _timeout_ticks: u64) -> Option<u8> {
+        self.input.pop_front().flatten()
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+}
+
+#[cfg(test)]
+fn packet(number: u8, data: &[u8; 128]) -> Vec<u8> {
+    let mut packet = alloc::vec![SOH, number, !number];
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(&crc16(data).to_be_bytes());
+    packet
+}
+
+//test case
+#[test_case]
+fn test_crc16() {
+    assert_eq!(crc16(b"123456789"), 0x31c3);
+    assert_eq!(crc16(&[]), 0);
+}
+
+#[test_case]
+fn test_receive_two_blocks() {
+    let mut input = packet(1, &[0xaa; 128]);
+    input.extend(packet(2, &[0xbb; 128]));
+    input.push(EOT);
+    let mut channel = ScriptChannel::new(&[&input]);
+    let mut out = Vec::new();
+    assert_eq!(receive(&mut channel, &mut out, 4096), Ok(256));
+    assert_eq!(&out[..128], &[0xaa; 128][..]);
+    assert_eq!(&out[128..], &[0xbb; 128][..]);
+    assert_eq!(channel.output, [CRC_REQUEST, ACK, ACK, ACK]);
+}
+
+#[test_case]
+fn test_receive_retries_bad_crc_and_duplicates() {
+    let mut corrupted = packet(1, &[0x11; 128]);
+    corrupted[10] ^= 0xff;
+    let mut rest = packet(1, &[0x11; 128]);
+    // sender missed our ACK and sends block 1 again
+    rest.extend(packet(1, &[0x11; 128]));
+    rest.push(EOT);
+    let mut channel = ScriptChannel::new(&[&corrupted, &rest]);
+    let mut out = Vec::new();
+    assert_eq!(receive(&mut channel, &mut out, 4096), Ok(128));
+    assert_eq!(channel.output, [CRC_REQUEST, NAK, ACK, ACK, ACK]);
+
+    let mut channel = ScriptChannel::new(&[&packet(1, &[0; 128])]);
+    assert_eq!(receive(&mut channel, &mut Vec::new(), 64), Err(XmodemError::TooLarge));
+}
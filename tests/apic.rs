@@ -0,0 +1,64 @@
+//! Moves the IRQs from the 8259s to the I/O APIC and checks the timer and
+//! a registered IRQ keep working through it, acknowledged at the local
+//! APIC.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use tutorial_os::{acpi, allocator, interrupts, ioapic, memory, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    let phys_mem_offset = boot_info.physical_memory_offset();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    acpi::init(phys_mem_offset, None).expect("ACPI tables not found");
+    memory::with_paging(interrupts::enable_apic).unwrap().expect("APIC setup failed");
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn routed_through_the_io_apic() {
+    assert!(interrupts::using_apic());
+    assert!(!ioapic::io_apics().is_empty());
+    // the PIT comes in on whatever pin the MADT says, unmasked
+    let timer = ioapic::isa_route(0).unwrap();
+    assert!(ioapic::io_apics().iter().any(|io_apic| (io_apic.gsi_base()..io_apic.gsi_base() + io_apic.pins()).contains(&timer.gsi)));
+    assert_eq!(ioapic::isa_masked(0), Some(false));
+    // enabling it twice changes nothing
+    assert!(memory::with_paging(interrupts::enable_apic).unwrap().is_ok());
+}
+
+#[test_case]
+fn the_timer_keeps_ticking() {
+    let ticks = time::ticks();
+    let irqs = interrupts::irq_count(0);
+    time::sleep_ms(100);
+    // an EOI missed would leave the timer stuck after the first tick
+    assert!(time::ticks() >= ticks + time::millis_to_ticks(100));
+    assert!(interrupts::irq_count(0) > irqs + 1);
+}
+
+#[test_case]
+fn masking_goes_to_the_io_apic() {
+    interrupts::mask_irq(1);
+    assert_eq!(ioapic::isa_masked(1), Some(true));
+    interrupts::unmask_irq(1);
+    assert_eq!(ioapic::isa_masked(1), Some(false));
+}
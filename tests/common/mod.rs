@@ -0,0 +1,26 @@
+//! What the disk driver tests share.
+
+use spin::Mutex;
+use tutorial_os::block::{BlockDevice, SECTOR_SIZE};
+
+/// Most sectors `read_across_commands` reads at once.
+const MAX_SECTORS: usize = 300;
+
+/// Static rather than on the heap, which can't hold a read this big.
+static WHOLE: Mutex<[u8; MAX_SECTORS * SECTOR_SIZE]> = Mutex::new([0; MAX_SECTORS * SECTOR_SIZE]);
+
+/// Reads a few more than `per_command` sectors from the start of `disk` in
+/// one request, which the driver splits into more than one command, and
+/// checks the sectors either side of the split against single-sector
+/// reads.
+pub fn read_across_commands(disk: &mut impl BlockDevice, per_command: usize) {
+    let count = (per_command + 16).min(MAX_SECTORS).min(disk.sector_count() as usize);
+    let mut whole = WHOLE.lock();
+    let whole = &mut whole[..count * SECTOR_SIZE];
+    disk.read_sectors(0, whole).expect("read failed");
+    let mut sector = [0u8; SECTOR_SIZE];
+    for lba in [0, per_command - 1, per_command, count - 1].into_iter().filter(|&lba| lba < count) {
+        disk.read_sectors(lba as u64, &mut sector).expect("read failed");
+        assert_eq!(&whole[lba * SECTOR_SIZE..(lba + 1) * SECTOR_SIZE], &sector[..], "sector {}", lba);
+    }
+}
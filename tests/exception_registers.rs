@@ -0,0 +1,66 @@
+//! Provokes a page fault with a sentinel in R12 and checks the exception
+//! report's register block shows it.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use tutorial_os::interrupts::{registers, ExceptionFrame};
+use tutorial_os::{exit_qemu, serial_println, QemuExitCode};
+
+const SENTINEL: u64 = 0xcafe_babe_dead_beef;
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    tutorial_os::init();
+    registers::set_fatal_hook(check_report);
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+/// Fixed-size text buffer, since the heap is not set up here.
+struct Buffer {
+    bytes: [u8; 512],
+    len: usize,
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+fn check_report(name: &str, frame: &ExceptionFrame) {
+    let mut text = Buffer { bytes: [0; 512], len: 0 };
+    write!(text, "{}", frame.registers).unwrap();
+    let text = core::str::from_utf8(&text.bytes[..text.len]).unwrap();
+    assert_eq!(name, "PAGE FAULT");
+    assert!(text.contains("R12=cafebabedeadbeef"), "register block:\n{}", text);
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[test_case]
+fn page_fault_reports_registers() {
+    unsafe {
+        core::arch::asm!(
+            "mov byte ptr [{addr}], 42",
+            addr = in(reg) 0xdeadbeef_u64,
+            in("r12") SENTINEL,
+        );
+    }
+    serial_println!("[no page fault]");
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -0,0 +1,86 @@
+//! Runs SSE arithmetic while the timer interrupt clobbers the same
+//! registers and checks the interrupted loop's accumulator survives.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use tutorial_os::{interrupts, time};
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    tutorial_os::init();
+    interrupts::set_tick_hook(float_in_interrupt);
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+static HOOK_RUNS: AtomicU64 = AtomicU64::new(0);
+static HOOK_RESULT: AtomicU64 = AtomicU64::new(0);
+
+/// A small computation in xmm0/xmm1, the registers the loop below keeps
+/// its state in. The kernel is soft-float, so nothing else here uses them
+/// and the asm can leave them changed: only the stubs' fxsave protects
+/// the interrupted loop.
+fn float_in_interrupt() {
+    let bits: u64;
+    unsafe {
+        core::arch::asm!(
+            "movq xmm0, {a}",
+            "movq xmm1, {b}",
+            "mulsd xmm0, xmm1",
+            "movq {out}, xmm0",
+            a = in(reg) 3.5f64.to_bits(),
+            b = in(reg) (-2.0f64).to_bits(),
+            out = lateout(reg) bits,
+            options(nomem, nostack),
+        );
+    }
+    HOOK_RESULT.store(bits, Ordering::Relaxed);
+    HOOK_RUNS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Adds 1.0 to an accumulator in xmm0 `iterations` times.
+fn sum_ones(iterations: u64) -> u64 {
+    let bits: u64;
+    unsafe {
+        core::arch::asm!(
+            "pxor xmm0, xmm0",
+            "movq xmm1, {one}",
+            "2:",
+            "addsd xmm0, xmm1",
+            "dec {n}",
+            "jnz 2b",
+            "movq {out}, xmm0",
+            one = in(reg) 1.0f64.to_bits(),
+            n = inout(reg) iterations => _,
+            out = lateout(reg) bits,
+            options(nomem, nostack),
+        );
+    }
+    bits
+}
+
+#[test_case]
+fn accumulator_survives_interrupts() {
+    const ITERATIONS: u64 = 20_000_000;
+    let runs = HOOK_RUNS.load(Ordering::Relaxed);
+    let ticks = time::ticks();
+    let mut rounds = 0;
+    // keep going until the timer hit at least a couple of loops
+    while HOOK_RUNS.load(Ordering::Relaxed) < runs + 2 {
+        assert_eq!(f64::from_bits(sum_ones(ITERATIONS)), ITERATIONS as f64);
+        rounds += 1;
+        assert!(rounds < 1000, "no timer interrupts since tick {}", ticks);
+    }
+    assert_eq!(f64::from_bits(HOOK_RESULT.load(Ordering::Relaxed)), -7.0);
+}
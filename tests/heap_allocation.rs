@@ -0,0 +1,108 @@
+//! The kernel heap: boxes, a vector spanning many pages, strings, a boxed
+//! buffer bigger than any fixed-size block, and freed blocks being handed
+//! out again while a long-lived allocation stays put.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use tutorial_os::allocator::{self, HEAP_SIZE, HEAP_START};
+use tutorial_os::{boot::BootInfo, entry_point, memory};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    tutorial_os::init();
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+fn on_heap<T: ?Sized>(value: &T) -> bool {
+    let addr = value as *const T as *const u8 as usize;
+    (HEAP_START..HEAP_START + HEAP_SIZE).contains(&addr)
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+    assert!(on_heap(&*heap_value_1) && on_heap(&*heap_value_2));
+}
+
+#[test_case]
+fn large_vec() {
+    // 64 KiB: sixteen pages of the heap
+    let n = 8192u64;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    assert!(on_heap(&vec[0]) && on_heap(&vec[n as usize - 1]));
+}
+
+#[test_case]
+fn growing_string() {
+    let mut text = String::new();
+    for i in 0..1000 {
+        core::fmt::Write::write_fmt(&mut text, format_args!("{},", i)).unwrap();
+    }
+    assert_eq!(text.len(), 10 + 90 * 2 + 900 * 3 + 1000);
+    assert!(text.starts_with("0,1,2,") && text.ends_with("998,999,"));
+    assert!(on_heap(text.as_str()));
+}
+
+#[test_case]
+fn large_boxed_buffer() {
+    // larger than the biggest block size, so it comes from the fallback
+    let mut buffer = alloc::vec![0u8; 32 * 1024].into_boxed_slice();
+    buffer[32 * 1024 - 1] = 0xaa;
+    assert!(buffer[..32 * 1024 - 1].iter().all(|&byte| byte == 0));
+    assert_eq!(buffer[32 * 1024 - 1], 0xaa);
+    assert!(on_heap(&buffer[0]) && on_heap(&buffer[32 * 1024 - 1]));
+    drop(buffer);
+    // and its memory can be had again
+    let again = alloc::vec![1u8; 32 * 1024].into_boxed_slice();
+    assert!(on_heap(&again[0]));
+}
+
+#[test_case]
+fn many_boxes() {
+    // far more than fits at once, so freed blocks have to be reused
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    let mut first = None;
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+        let addr = &*x as *const usize;
+        // every short-lived box gets the block the previous one freed
+        assert_eq!(*first.get_or_insert(addr), addr);
+    }
+    assert_eq!(*long_lived, 1);
+}
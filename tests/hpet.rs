@@ -0,0 +1,58 @@
+//! Switches the system tick to HPET comparator 0 and checks ticks arrive
+//! at the PIT-compatible rate, measured against the HPET main counter.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use tutorial_os::{acpi, allocator, hpet, memory, serial_println, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    let phys_mem_offset = boot_info.physical_memory_offset();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    acpi::init(phys_mem_offset, None).expect("ACPI tables not found");
+    memory::with_paging(hpet::init)
+        .unwrap()
+        .expect("HPET init failed");
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+const FS_PER_SEC: u128 = 1_000_000_000_000_000;
+
+#[test_case]
+fn counter_is_monotonic() {
+    let first = hpet::now_fs().unwrap();
+    let second = hpet::now_fs().unwrap();
+    assert!(second >= first);
+}
+
+#[test_case]
+fn ticks_arrive_at_the_configured_rate() {
+    hpet::use_for_ticks().expect("HPET can't drive the tick");
+    let start = hpet::now_fs().unwrap();
+    let start_ticks = time::ticks();
+    while hpet::now_fs().unwrap() - start < 2 * FS_PER_SEC {
+        x86_64::instructions::hlt();
+    }
+    let ticks = time::ticks() - start_ticks;
+    serial_println!("{} ticks in 2 s", ticks);
+    // 200 expected; allow for a tick of slack at either end
+    assert!((198..=202).contains(&ticks), "{} ticks in 2 s", ticks);
+}
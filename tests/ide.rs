@@ -0,0 +1,100 @@
+//! Reads and writes the test image through the ATA PIO driver. The test
+//! arguments in Cargo.toml put `target/storage-test.img` on the primary
+//! IDE bus as the slave, next to the boot disk, with `snapshot=on` so the
+//! writes never reach the file. Without a disk carrying the image's
+//! signature on that bus the tests are reported as skipped.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+// only the signature and the size are needed here
+#[allow(dead_code)]
+#[path = "storage/image.rs"]
+mod image;
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use spin::Once;
+use tutorial_os::block::{BlockDevice, BlockError, SECTOR_SIZE};
+use tutorial_os::ide;
+use tutorial_os::{allocator, memory, serial_println};
+
+/// Index in `ide::DEVICES` of the disk holding the test image.
+static TEST_DISK: Once<Option<usize>> = Once::new();
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    let _ = ide::init();
+    TEST_DISK.call_once(find_test_disk);
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+fn find_test_disk() -> Option<usize> {
+    let mut devices = ide::DEVICES.lock();
+    devices.iter_mut().position(|disk| {
+        let mut sector = [0u8; SECTOR_SIZE];
+        disk.read_sectors(0, &mut sector).is_ok() && sector.starts_with(image::SIGNATURE)
+    })
+}
+
+/// Runs `test` on the test disk, or reports the test as skipped.
+fn with_test_disk(test: impl FnOnce(&mut ide::IdeDisk)) {
+    match TEST_DISK.get().copied().flatten() {
+        Some(index) => test(&mut ide::DEVICES.lock()[index]),
+        None => serial_println!("[skipped: no test disk on the primary IDE bus]"),
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn identify_reports_the_image_size() {
+    with_test_disk(|disk| assert_eq!(disk.sector_count(), image::DISK_SECTORS));
+}
+
+#[test_case]
+fn write_then_read_back() {
+    with_test_disk(|disk| {
+        // sectors 1-2 sit between the MBR and the partition
+        let pattern: alloc::vec::Vec<u8> = (0..2 * SECTOR_SIZE).map(|i| (i * 13 + i / SECTOR_SIZE) as u8).collect();
+        disk.write_sectors(1, &pattern).expect("write failed");
+        let mut back = alloc::vec![0u8; pattern.len()];
+        disk.read_sectors(1, &mut back).expect("read failed");
+        assert_eq!(back, pattern);
+        // and the MBR next to them is untouched
+        let mut sector = [0u8; SECTOR_SIZE];
+        disk.read_sectors(0, &mut sector).expect("read failed");
+        assert!(sector.starts_with(image::SIGNATURE));
+    });
+}
+
+#[test_case]
+fn multi_command_read_matches_single_reads() {
+    with_test_disk(|disk| common::read_across_commands(disk, 256));
+}
+
+#[test_case]
+fn bad_requests_are_refused() {
+    with_test_disk(|disk| {
+        let mut sector = [0u8; SECTOR_SIZE];
+        assert_eq!(disk.read_sectors(image::DISK_SECTORS, &mut sector), Err(BlockError::OutOfRange));
+        assert_eq!(disk.write_sectors(image::DISK_SECTORS - 1, &[0; 2 * SECTOR_SIZE]), Err(BlockError::OutOfRange));
+        assert_eq!(disk.read_sectors(0, &mut sector[..100]), Err(BlockError::BufferSize));
+    });
+}
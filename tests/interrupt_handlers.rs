@@ -0,0 +1,74 @@
+//! The interrupt paths every other test leans on: `int3` returns, the
+//! timer ticks and counts, the tick hook runs in interrupt context, and a
+//! handler registered for a PIC line runs when its vector is raised.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use tutorial_os::interrupts::{self, PIC_1_OFFSET};
+use tutorial_os::time;
+
+/// A line nothing in this kernel drives, so only the test raises it.
+const TEST_IRQ: u8 = 5;
+
+static HOOK_RUNS: AtomicU64 = AtomicU64::new(0);
+static IRQ_RUNS: AtomicU64 = AtomicU64::new(0);
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    tutorial_os::init();
+    interrupts::set_tick_hook(count_hook);
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+fn count_hook() {
+    assert!(!x86_64::instructions::interrupts::are_enabled());
+    HOOK_RUNS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn count_irq() {
+    IRQ_RUNS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_breakpoint_returns() {
+    x86_64::instructions::interrupts::int3();
+}
+
+#[test_case]
+fn test_timer_ticks() {
+    let (ticks, count) = (time::ticks(), interrupts::irq_count(0));
+    time::sleep_ms(50);
+    assert!(time::ticks() > ticks);
+    assert!(interrupts::irq_count(0) > count);
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_tick_hook_runs() {
+    let runs = HOOK_RUNS.load(Ordering::Relaxed);
+    time::sleep_ms(50);
+    assert!(HOOK_RUNS.load(Ordering::Relaxed) > runs);
+}
+
+#[test_case]
+fn test_registered_irq_handler() {
+    interrupts::register_irq(TEST_IRQ, count_irq);
+    let count = interrupts::irq_count(TEST_IRQ);
+    // the vector raised by hand takes the same stub a real IRQ would
+    unsafe { core::arch::asm!("int {}", const PIC_1_OFFSET + TEST_IRQ) };
+    assert_eq!(IRQ_RUNS.load(Ordering::Relaxed), 1);
+    assert_eq!(interrupts::irq_count(TEST_IRQ), count + 1);
+    interrupts::mask_irq(TEST_IRQ);
+}
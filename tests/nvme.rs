@@ -0,0 +1,76 @@
+//! Reads sector 0 of the first NVMe namespace and checks it against the
+//! test image, which must start with `EXPECTED_SIGNATURE`, then reads
+//! across the PRP-list boundary. The test arguments in Cargo.toml attach it
+//! as `-drive file=<image>,if=none,id=nvm,format=raw -device nvme,serial=berry,drive=nvm`;
+//! without an NVMe disk the tests are reported as skipped.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use spin::Once;
+use tutorial_os::nvme;
+use tutorial_os::block::{BlockDevice, SECTOR_SIZE};
+use tutorial_os::{allocator, memory, serial_println};
+
+const EXPECTED_SIGNATURE: &[u8] = b"BERRYOS-TESTDISK";
+
+static DISKS: Once<usize> = Once::new();
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    let phys_mem_offset = boot_info.physical_memory_offset();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    let disks = memory::with_paging(|mapper, frame_allocator| nvme::init(mapper, frame_allocator, phys_mem_offset))
+        .and_then(Result::ok)
+        .unwrap_or(0);
+    DISKS.call_once(|| disks);
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+/// Whether there is a disk to test, saying so if not.
+fn attached() -> bool {
+    let attached = DISKS.get() != Some(&0);
+    if !attached {
+        serial_println!("[skipped: no NVMe disk attached]");
+    }
+    attached
+}
+
+#[test_case]
+fn read_sector_zero() {
+    if !attached() {
+        return;
+    }
+    let mut devices = nvme::DEVICES.lock();
+    let disk = &mut devices[0];
+    let mut sector = [0u8; SECTOR_SIZE];
+    disk.read_sectors(0, &mut sector).expect("read failed");
+    assert_eq!(&sector[..EXPECTED_SIGNATURE.len()], EXPECTED_SIGNATURE);
+}
+
+#[test_case]
+fn multi_command_read_matches_single_reads() {
+    if !attached() {
+        return;
+    }
+    common::read_across_commands(&mut nvme::DEVICES.lock()[0], 64);
+}
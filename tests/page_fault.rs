@@ -0,0 +1,79 @@
+//! Writes to a read-only kernel page and checks the page fault handler
+//! runs and sees a protection violation, not a missing page, at the right
+//! address.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use tutorial_os::interrupts::{registers, ExceptionFrame};
+use tutorial_os::{allocator, memory};
+use tutorial_os::{boot::BootInfo, entry_point, exit_qemu, serial_println, QemuExitCode};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr2};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// Where the read-only page goes; nothing else uses it.
+const READ_ONLY: u64 = 0x_5555_7000_0000;
+
+static EXPECTED_RIP: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    memory::with_paging(|mapper, frame_allocator| {
+        memory::map_range(VirtAddr::new(READ_ONLY), 4096, PageTableFlags::PRESENT, mapper, frame_allocator)
+    })
+    .unwrap()
+    .expect("mapping the read-only page failed");
+    // ring 0 ignores read-only pages unless WP is set
+    unsafe { Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT)) };
+    registers::set_fatal_hook(check_fault);
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+fn check_fault(name: &str, frame: &ExceptionFrame) {
+    let code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    assert_eq!(name, "PAGE FAULT");
+    assert_eq!(Cr2::read(), VirtAddr::new(READ_ONLY + 8));
+    assert_eq!(code, PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE);
+    assert_eq!(frame.stack_frame.instruction_pointer.as_u64(), EXPECTED_RIP.load(Ordering::Relaxed));
+    let flags = memory::page_flags(VirtAddr::new(READ_ONLY)).expect("the page is mapped");
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[test_case]
+fn write_to_read_only_page() {
+    // reading is fine
+    let _ = unsafe { ((READ_ONLY + 8) as *const u64).read_volatile() };
+    unsafe {
+        core::arch::asm!(
+            "lea {rip}, [rip + 2f]",
+            "mov [{expected}], {rip}",
+            "2:",
+            "mov qword ptr [{addr}], 1",
+            rip = out(reg) _,
+            expected = in(reg) EXPECTED_RIP.as_ptr(),
+            addr = in(reg) READ_ONLY + 8,
+        );
+    }
+    serial_println!("[no page fault]");
+    exit_qemu(QemuExitCode::Failed);
+}
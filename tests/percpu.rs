@@ -0,0 +1,73 @@
+//! Checks the GS bases stay right while ring-3 programs make system calls
+//! through both `syscall` and `int 0x80` and the timer interrupts them:
+//! every check in the kernel must find the per-CPU block in IA32_GS_BASE
+//! and the program's GS base in IA32_KERNEL_GS_BASE.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use tutorial_os::cpu::{self, IA32_GS_BASE, IA32_KERNEL_GS_BASE};
+use tutorial_os::usermode::{self, demo};
+use tutorial_os::{allocator, interrupts, memory, percpu, syscall};
+
+/// What the programs' GS base is set to; any canonical address would do.
+const USER_GS_BASE: u64 = 0x0000_7fff_1234_5000;
+
+static GOOD: AtomicU64 = AtomicU64::new(0);
+static BAD: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+fn check_gs() {
+    let cpu = percpu::current();
+    let (gs, kernel_gs) = unsafe { (cpu::rdmsr(IA32_GS_BASE), cpu::rdmsr(IA32_KERNEL_GS_BASE)) };
+    if cpu.index() == 0 && gs == cpu.address() && kernel_gs == USER_GS_BASE {
+        GOOD.fetch_add(1, Ordering::Relaxed);
+    } else {
+        BAD.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn gs_bases_survive_ring_3() {
+    unsafe { cpu::wrmsr(IA32_KERNEL_GS_BASE, USER_GS_BASE) };
+    interrupts::set_tick_hook(check_gs);
+    syscall::set_write_hook(|_| check_gs());
+    for _ in 0..10 {
+        assert_eq!(usermode::run_demo(), Ok(demo::EXIT_CODE));
+        for fast in [true, false] {
+            assert!(usermode::run(&demo::syscall_benchmark(fast)).is_ok());
+        }
+    }
+    interrupts::set_tick_hook(|| {});
+    syscall::set_write_hook(|_| {});
+
+    assert_eq!(BAD.load(Ordering::Relaxed), 0);
+    assert!(GOOD.load(Ordering::Relaxed) >= 10);
+    let cpu = percpu::current();
+    assert_eq!(unsafe { cpu::rdmsr(IA32_GS_BASE) }, cpu.address());
+    assert_eq!(unsafe { cpu::rdmsr(IA32_KERNEL_GS_BASE) }, USER_GS_BASE);
+    unsafe { cpu::wrmsr(IA32_KERNEL_GS_BASE, 0) };
+}
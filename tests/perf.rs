@@ -0,0 +1,63 @@
+//! Counts a loop with a known instruction mix and checks the counters
+//! agree with it, when the CPU has architectural performance monitoring.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use tutorial_os::perf::{self, Counter, Event};
+use tutorial_os::serial_println;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+const ITERATIONS: u64 = 1_000_000;
+
+/// `dec` and `jnz`: two instructions per iteration.
+fn spin(iterations: u64) {
+    unsafe {
+        core::arch::asm!("2:", "dec {0}", "jnz 2b", inout(reg) iterations => _, options(nomem, nostack));
+    }
+}
+
+#[test_case]
+fn instructions_per_iteration() {
+    let (_, count) = perf::measure(Event::InstructionsRetired, || spin(ITERATIONS));
+    let hardware = perf::capabilities().is_some_and(|caps| caps.supports(Event::InstructionsRetired));
+    if !hardware {
+        serial_println!("(no PMU, TSC fallback) ");
+        assert!(count > 0);
+        return;
+    }
+    // timer interrupts add a few thousand instructions at most
+    let per_iteration_x100 = count * 100 / ITERATIONS;
+    assert!((200..=210).contains(&per_iteration_x100), "{} instructions", count);
+}
+
+#[test_case]
+fn counters_are_released() {
+    for _ in 0..20 {
+        let counter = Counter::start(Event::CoreCycles);
+        spin(1000);
+        assert!(counter.stop() > 0);
+    }
+    if let Some(caps) = perf::capabilities().filter(|caps| caps.supports(Event::CoreCycles)) {
+        // nothing leaked: all of them can be running at once
+        let counters: [Counter; 2] = core::array::from_fn(|_| Counter::start(Event::CoreCycles));
+        assert!(caps.counters < 2 || counters.iter().all(Counter::hardware));
+    }
+}
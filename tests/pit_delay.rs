@@ -0,0 +1,64 @@
+//! Checks `time::pit_delay_us` against the calibrated TSC, across single
+//! countdowns and delays spanning several PIT rollovers.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use tutorial_os::time;
+use x86_64::instructions::port::Port;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+/// Microseconds `pit_delay_us(us)` took by the TSC.
+fn measured_us(us: u64) -> u64 {
+    let hz = time::tsc_frequency().expect("TSC not calibrated");
+    let start = time::rdtsc();
+    assert!(time::pit_delay_us(us), "PIT channel 2 did not respond");
+    time::cycles_to_nanos(time::rdtsc() - start, hz) / 1000
+}
+
+#[test_case]
+fn short_delays_last_long_enough() {
+    for us in [100, 1_000, 10_000] {
+        let took = measured_us(us);
+        // never early; port I/O overhead dominates the slack
+        assert!(took >= us, "{} µs took {} µs", us, took);
+        assert!(took < us + us / 10 + 200, "{} µs took {} µs", us, took);
+    }
+}
+
+#[test_case]
+fn long_delays_span_rollovers() {
+    // 150 ms is three full countdowns plus a remainder
+    let took = measured_us(150_000);
+    assert!(took >= 150_000, "took {} µs", took);
+    assert!(took < 150_000 * 11 / 10, "took {} µs", took);
+}
+
+#[test_case]
+fn leaves_port_0x61_and_the_tick_alone() {
+    let mut control = Port::<u8>::new(0x61);
+    // bits 4-7 are status; only the gate and speaker bits are ours to keep
+    let before = unsafe { control.read() } & 0x03;
+    let ticks = time::ticks();
+    assert!(time::pit_delay_us(120_000));
+    assert_eq!(unsafe { control.read() } & 0x03, before);
+    // channel 0 kept running at 100 Hz during the 120 ms
+    assert!(time::ticks() > ticks);
+}
@@ -0,0 +1,214 @@
+//! Spawns processes, lets them time-share the CPU, sleep and wait for each
+//! other, and checks that exiting gives every frame back. A program also
+//! spawns a child from the ramfs and waits for it, and another forks.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use tutorial_os::process::{self, KillError, State};
+use tutorial_os::usermode::demo;
+use tutorial_os::usermode::usys::Program;
+use tutorial_os::{allocator, fs, memory, serial_println, syscall, time, usermode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    fs::mount().expect("could not mount the ramfs");
+    fs::RAMFS.lock().write("child", &demo::PROGRAM, false).expect("could not store the child");
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+/// The demo with its code replaced by a long busy loop and `exit(7)`.
+fn spinner() -> [u8; 176] {
+    let mut program = demo::PROGRAM;
+    let code = [
+        0xb9, 0x00, 0x00, 0x00, 0x10, // mov ecx, 0x1000_0000
+        0xe2, 0xfe, // loop .
+        0xbf, 0x07, 0x00, 0x00, 0x00, // mov edi, 7
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60
+        0xcd, 0x80, // int 0x80
+    ];
+    program[120..120 + code.len()].copy_from_slice(&code);
+    program
+}
+
+/// The demo with its code replaced by `nanosleep` for `SLEEP_NS` and an
+/// exit with what the call returned.
+fn sleeper() -> [u8; 176] {
+    let mut program = demo::PROGRAM;
+    let [n0, n1, n2, n3] = (SLEEP_NS as u32).to_le_bytes();
+    let code = [
+        0x68, n0, n1, n2, n3, // push SLEEP_NS          ; tv_nsec
+        0x6a, 0x00, // push 0                            ; tv_sec
+        0x48, 0x89, 0xe7, // mov rdi, rsp
+        0x31, 0xf6, // xor esi, esi
+        0xb8, 0x23, 0x00, 0x00, 0x00, // mov eax, 35
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60
+        0x0f, 0x05, // syscall
+    ];
+    program[demo::CODE_OFFSET..demo::CODE_OFFSET + code.len()].copy_from_slice(&code);
+    program
+}
+
+const SLEEP_NS: u64 = 300_000_000;
+
+/// The demo with its code replaced by `wait4(pid)` and an exit with the
+/// exit code it reported.
+fn waiter(pid: process::Pid) -> [u8; 176] {
+    let mut program = demo::PROGRAM;
+    let [p0, p1, p2, p3] = (pid.0 as u32).to_le_bytes();
+    let code = [
+        0xbf, p0, p1, p2, p3, // mov edi, pid
+        0x48, 0x83, 0xec, 0x08, // sub rsp, 8
+        0x48, 0x89, 0xe6, // mov rsi, rsp
+        0x31, 0xd2, // xor edx, edx
+        0x45, 0x31, 0xd2, // xor r10d, r10d
+        0xb8, 0x3d, 0x00, 0x00, 0x00, // mov eax, 61
+        0x0f, 0x05, // syscall
+        0x8b, 0x3c, 0x24, // mov edi, [rsp]
+        0xc1, 0xef, 0x08, // shr edi, 8
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60
+        0x0f, 0x05, // syscall
+    ];
+    program[demo::CODE_OFFSET..demo::CODE_OFFSET + code.len()].copy_from_slice(&code);
+    program
+}
+
+fn frames_allocated() -> usize {
+    memory::with_paging(|_, frame_allocator| frame_allocator.allocated_count()).unwrap()
+}
+
+#[test_case]
+fn processes_time_share() {
+    let program = spinner();
+    let pids = [
+        process::spawn_from_elf(&program).unwrap(),
+        process::spawn_from_elf(&program).unwrap(),
+        process::spawn_from_elf(&demo::PROGRAM).unwrap(),
+    ];
+    let switches = process::context_switches();
+    assert!(process::run());
+    let switched = process::context_switches() - switches;
+    serial_println!("{} context switches", switched);
+    assert_eq!(process::state(pids[0]), Some(State::Exited(7)));
+    assert_eq!(process::state(pids[1]), Some(State::Exited(7)));
+    assert_eq!(process::state(pids[2]), Some(State::Exited(demo::EXIT_CODE)));
+    // more switches than processes means someone was preempted
+    assert!(switched > 3, "only {} switches", switched);
+}
+
+#[test_case]
+fn kill_frees_a_ready_process() {
+    let pid = process::spawn_from_elf(&demo::PROGRAM).unwrap();
+    assert_eq!(process::kill(pid), Ok(()));
+    assert_eq!(process::state(pid), Some(State::Killed));
+    assert_eq!(process::kill(pid), Err(KillError::AlreadyFinished));
+    assert!(process::run());
+    assert_eq!(process::state(pid), Some(State::Killed));
+}
+
+#[test_case]
+fn exiting_leaks_no_frames() {
+    // the first rounds fill the loader's pool of recycled frames
+    for _ in 0..2 {
+        process::spawn_from_elf(&demo::PROGRAM).unwrap();
+        process::run();
+    }
+    let before = frames_allocated();
+    for _ in 0..20 {
+        let pid = process::spawn_from_elf(&demo::PROGRAM).unwrap();
+        assert!(process::run());
+        assert_eq!(process::state(pid), Some(State::Exited(demo::EXIT_CODE)));
+    }
+    assert_eq!(frames_allocated(), before);
+}
+
+#[test_case]
+fn sleepers_wake_on_time() {
+    let program = sleeper();
+    let pids = [process::spawn_from_elf(&program).unwrap(), process::spawn_from_elf(&program).unwrap()];
+    let start = time::ticks();
+    assert!(process::run());
+    let slept = time::ticks() - start;
+    serial_println!("slept {} ticks", slept);
+    for pid in pids {
+        assert_eq!(process::state(pid), Some(State::Exited(0)));
+    }
+    // never early; both sleep at once, so not one after the other either
+    assert!(slept >= time::nanos_to_ticks(SLEEP_NS), "woke after {} ticks", slept);
+    assert!(slept < 2 * time::nanos_to_ticks(SLEEP_NS), "slept {} ticks", slept);
+}
+
+#[test_case]
+fn sleepers_leave_the_cpu_to_others() {
+    let pids = [process::spawn_from_elf(&sleeper()).unwrap(), process::spawn_from_elf(&spinner()).unwrap()];
+    assert!(process::run());
+    assert_eq!(process::state(pids[0]), Some(State::Exited(0)));
+    assert_eq!(process::state(pids[1]), Some(State::Exited(7)));
+}
+
+#[test_case]
+fn waiting_for_a_process_blocks_until_it_exits() {
+    let spinner = process::spawn_from_elf(&spinner()).unwrap();
+    // it first runs when the spinner is preempted, long before it is done
+    let waiter = process::spawn_from_elf(&waiter(spinner)).unwrap();
+    assert!(process::run());
+    assert_eq!(process::state(spinner), Some(State::Exited(7)));
+    assert_eq!(process::state(waiter), Some(State::Exited(7)));
+}
+
+#[test_case]
+fn parents_wait_for_their_children() {
+    let mut program = Program::new();
+    program.spawn("/ram/child").wait(u64::MAX).exit_with_result();
+    let parent = process::spawn_from_elf(&program.build()).unwrap();
+    assert!(process::run());
+    // the parent exits with the pid wait4 returned, that of its child
+    let Some(State::Exited(child)) = process::state(parent) else { panic!("parent did not exit") };
+    let child = process::Pid(child);
+    assert_eq!(process::state(child), Some(State::Exited(demo::EXIT_CODE)));
+    // collecting its status disowned it
+    assert_eq!(process::parent(child), None);
+    assert!(process::children(parent).is_empty());
+}
+
+#[test_case]
+fn waiting_without_children_fails() {
+    let mut program = Program::new();
+    program.spawn("/ram/child").wait(u64::MAX).wait(u64::MAX).exit_with_result();
+    assert_eq!(usermode::run(&program.build()), Ok(syscall::errno(syscall::ECHILD)));
+}
+
+#[test_case]
+fn fork_shares_memory_until_written() {
+    // the parent waits for its copy and exits with its pid; the copy has
+    // no children and exits with ECHILD
+    let mut program = Program::new();
+    program.fork().wait(u64::MAX).exit_with_result();
+    let parent = process::spawn_from_elf(&program.build()).unwrap();
+    assert!(process::run());
+    let Some(State::Exited(child)) = process::state(parent) else { panic!("parent did not exit") };
+    assert_eq!(process::state(process::Pid(child)), Some(State::Exited(syscall::errno(syscall::ECHILD))));
+    // a wait4 status written to the stack gave the parent its own page,
+    // and nothing is shared once both are gone
+    assert_eq!(memory::shared_frames(), 0);
+}
@@ -0,0 +1,194 @@
+//! Kernel threads: ones that yield and exit, one that never yields and is
+//! taken off the CPU by the timer (and reported stalled by the watchdog),
+//! ones blocked on a wait queue, and the boot thread carrying on between
+//! them, each with FPU state of its own. The heap is small, so no more than two threads exist at a time.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use tutorial_os::channel::{self, Sender};
+use tutorial_os::scheduler::{self, ThreadId, ThreadState};
+use tutorial_os::{allocator, memory, time, watchdog};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+static RELEASE: AtomicBool = AtomicBool::new(false);
+/// The boot thread's state, as a thread saw it.
+static BOOT_SEEN: Mutex<Option<ThreadState>> = Mutex::new(None);
+static NUMBERS: Mutex<Option<Sender<u64>>> = Mutex::new(None);
+/// The MXCSR a thread found after a yield.
+static MXCSR_SEEN: AtomicU64 = AtomicU64::new(0);
+/// MXCSR with every exception masked and rounding toward zero.
+const ROUND_TO_ZERO: u32 = 0x7f80;
+
+fn mxcsr() -> u32 {
+    let mut mxcsr = 0u32;
+    unsafe { core::arch::asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags)) };
+    mxcsr
+}
+
+fn set_mxcsr(mxcsr: u32) {
+    unsafe { core::arch::asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack, readonly, preserves_flags)) };
+}
+
+fn count_and_yield() {
+    for _ in 0..10 {
+        COUNTER.fetch_add(1, Ordering::Relaxed);
+        scheduler::yield_now();
+    }
+}
+
+fn count_and_exit() {
+    COUNTER.fetch_add(100, Ordering::Relaxed);
+    scheduler::exit();
+}
+
+fn look_at_the_boot_thread() {
+    *BOOT_SEEN.lock() = scheduler::state(ThreadId::BOOT);
+}
+
+/// Sends 1 to 50, more than the channel holds, so it waits for room.
+fn send_numbers() {
+    let sender = NUMBERS.lock().take().unwrap();
+    for number in 1..=50 {
+        sender.send(number).unwrap();
+    }
+}
+
+fn round_to_zero_and_yield() {
+    set_mxcsr(ROUND_TO_ZERO);
+    scheduler::yield_now();
+    MXCSR_SEEN.store(mxcsr() as u64, Ordering::Relaxed);
+}
+
+/// Never yields: only the timer gets the boot thread back on the CPU.
+fn spin_until_released() {
+    while !RELEASE.load(Ordering::Acquire) {
+        COUNTER.fetch_add(1, Ordering::Relaxed);
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn threads_run_and_return() {
+    COUNTER.store(0, Ordering::Relaxed);
+    let first = scheduler::spawn(count_and_yield);
+    let second = scheduler::spawn(count_and_yield);
+    assert_ne!(first.id(), second.id());
+    assert_eq!(scheduler::state(first.id()), Some(ThreadState::Ready));
+    first.join();
+    second.join();
+    assert_eq!(COUNTER.load(Ordering::Relaxed), 20);
+    assert_eq!(scheduler::current(), ThreadId::BOOT);
+}
+
+#[test_case]
+fn exit_ends_a_thread() {
+    COUNTER.store(0, Ordering::Relaxed);
+    let thread = scheduler::spawn(count_and_exit);
+    let id = thread.id();
+    thread.join();
+    assert_eq!(COUNTER.load(Ordering::Relaxed), 100);
+    // joining frees it
+    assert_eq!(scheduler::state(id), None);
+    assert_eq!(scheduler::list(), [(ThreadId::BOOT, ThreadState::Running)]);
+}
+
+#[test_case]
+fn each_thread_keeps_its_fpu_state() {
+    let boot = mxcsr();
+    assert_ne!(boot, ROUND_TO_ZERO);
+    MXCSR_SEEN.store(0, Ordering::Relaxed);
+    scheduler::spawn(round_to_zero_and_yield).join();
+    assert_eq!(MXCSR_SEEN.load(Ordering::Relaxed), ROUND_TO_ZERO as u64);
+    // the thread's rounding mode didn't follow the switch back
+    assert_eq!(mxcsr(), boot);
+}
+
+#[test_case]
+fn the_timer_preempts_a_spinning_thread() {
+    COUNTER.store(0, Ordering::Relaxed);
+    RELEASE.store(false, Ordering::Release);
+    let switches = scheduler::context_switches();
+    let thread = scheduler::spawn(spin_until_released);
+    // the boot thread only spins too, and both get turns
+    let deadline = time::ticks() + time::secs_to_ticks(5);
+    while COUNTER.load(Ordering::Relaxed) == 0 {
+        assert!(time::ticks() < deadline, "the spinning thread never ran");
+        core::hint::spin_loop();
+    }
+    assert!(scheduler::context_switches() > switches);
+    RELEASE.store(true, Ordering::Release);
+    thread.join();
+}
+
+#[test_case]
+fn join_blocks_the_joiner() {
+    *BOOT_SEEN.lock() = None;
+    let thread = scheduler::spawn(look_at_the_boot_thread);
+    let switches = scheduler::context_switches();
+    thread.join();
+    // the boot thread was off the CPU, not halted in place
+    assert_eq!(*BOOT_SEEN.lock(), Some(ThreadState::Blocked));
+    assert!(scheduler::context_switches() > switches);
+    assert_eq!(scheduler::state(ThreadId::BOOT), Some(ThreadState::Running));
+}
+
+#[test_case]
+fn bounded_channel_between_threads() {
+    let (sender, receiver) = channel::channel(4);
+    *NUMBERS.lock() = Some(sender);
+    let thread = scheduler::spawn(send_numbers);
+    let deadline = time::ticks() + time::secs_to_ticks(5);
+    let mut sum = 0;
+    while let Some(number) = receiver.recv() {
+        assert!(time::ticks() < deadline, "the sender stalled");
+        assert!(receiver.len() <= 4);
+        sum += number;
+    }
+    // `None` came once the sender thread ended and dropped its end
+    assert_eq!(sum, 50 * 51 / 2);
+    thread.join();
+}
+
+#[test_case]
+fn a_stalled_thread_is_reported() {
+    RELEASE.store(false, Ordering::Release);
+    watchdog::watch_threads(1);
+    let thread = scheduler::spawn(spin_until_released);
+    // watched from its spawn, which counts as a heartbeat
+    assert!(!watchdog::stall_reported(thread.id()));
+    let deadline = time::ticks() + time::secs_to_ticks(5);
+    while !watchdog::stall_reported(thread.id()) {
+        assert!(time::ticks() < deadline, "the spinning thread was never reported");
+        // halted, the boot thread is never the one the timer finds stuck
+        x86_64::instructions::hlt();
+    }
+    RELEASE.store(true, Ordering::Release);
+    thread.join();
+    watchdog::watch_threads(0);
+}
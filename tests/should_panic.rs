@@ -0,0 +1,30 @@
+//! A failed assertion reaches the panic handler, which is the pass here.
+//! Runs without the test harness, whose own `should_panic!` tests rely on
+//! this path working.
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use tutorial_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    serial_print!("should_panic::should_fail...\t");
+    tutorial_os::init();
+    should_fail();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    tutorial_os::hlt_loop();
+}
+
+fn should_fail() {
+    assert_eq!(core::hint::black_box(0), 1);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    tutorial_os::hlt_loop();
+}
@@ -0,0 +1,44 @@
+//! Starts the application processors and checks every enabled CPU in the
+//! MADT comes online. Run with `-smp 4` (the default test arguments) to
+//! exercise more than the BSP.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use spin::Once;
+use tutorial_os::{acpi, allocator, memory, smp};
+
+static STARTED: Once<usize> = Once::new();
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    let phys_mem_offset = boot_info.physical_memory_offset();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    acpi::init(phys_mem_offset, None).expect("ACPI tables not found");
+    STARTED.call_once(|| memory::with_paging(smp::init).unwrap());
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn all_enabled_cpus_online() {
+    let topology = acpi::topology().expect("no MADT");
+    assert_eq!(STARTED.get(), Some(&topology.enabled_cpus()));
+    assert_eq!(smp::online_cpus(), topology.enabled_cpus());
+}
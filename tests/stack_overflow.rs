@@ -0,0 +1,46 @@
+//! Recurses until the kernel stack runs into its guard page and checks
+//! the double fault lands on its own IST stack instead of triple-faulting.
+//! Runs without the test harness: getting back from the recursion at all
+//! is the failure.
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use tutorial_os::interrupts::{registers, ExceptionFrame};
+use tutorial_os::{boot::BootInfo, entry_point, exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_overflow::stack_overflow...\t");
+    tutorial_os::init();
+    registers::set_fatal_hook(check_double_fault);
+
+    stack_overflow();
+
+    serial_println!("[no double fault]");
+    exit_qemu(QemuExitCode::Failed);
+    tutorial_os::hlt_loop();
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    // the read after the call keeps it from becoming a loop
+    volatile::Volatile::new(0).read();
+}
+
+fn check_double_fault(name: &str, _frame: &ExceptionFrame) {
+    if name != "DOUBLE FAULT" {
+        serial_println!("[failed]\n{} instead of a double fault", name);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
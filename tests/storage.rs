@@ -0,0 +1,147 @@
+//! The storage stack end to end, on the disk `build.rs` writes to
+//! `target/storage-test.img` and the test arguments in Cargo.toml attach
+//! to an AHCI port: sector reads, the partition table, FAT directories,
+//! file contents and a write that a fresh mount reads back. The drive is in
+//! snapshot mode, so every run starts from the same image. Without an AHCI
+//! disk every test is reported as skipped.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+#[path = "storage/image.rs"]
+mod image;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use spin::Once;
+use tutorial_os::block::{BlockDevice, DISKS, SECTOR_SIZE};
+use tutorial_os::fat::{FatFs, FatType};
+use tutorial_os::partitions::{self, PartitionKind};
+use tutorial_os::{ahci, allocator, memory, serial_println};
+use tutorial_os::{boot::BootInfo, entry_point};
+
+static DISKS_FOUND: Once<usize> = Once::new();
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    let phys_mem_offset = boot_info.physical_memory_offset();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    let disks = memory::with_paging(|mapper, frame_allocator| ahci::init(mapper, frame_allocator, phys_mem_offset))
+        .and_then(Result::ok)
+        .unwrap_or(0);
+    DISKS_FOUND.call_once(|| disks);
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+/// Whether the test disk is there; AHCI only times out on a port with a
+/// link, so an absent drive is noticed before any transfer.
+fn attached() -> bool {
+    DISKS_FOUND.get() != Some(&0)
+}
+
+fn mount() -> FatFs<partitions::PartitionDevice<tutorial_os::block::Disk>> {
+    FatFs::mount(DISKS.lock().open("ata0p1").expect("no partition 1")).expect("mount failed")
+}
+
+fn read_all(fs: &mut FatFs<impl BlockDevice>, path: &str) -> Vec<u8> {
+    let mut file = fs.open(path).expect(path);
+    let mut data = alloc::vec![0u8; file.size() as usize];
+    assert_eq!(file.read(0, &mut data), Ok(data.len()));
+    data
+}
+
+#[test_case]
+fn ata_reads() {
+    if !attached() {
+        serial_println!("[skipped: no storage test disk attached]");
+        return;
+    }
+    let mut disk = DISKS.lock().open("ata0").expect("no ata0");
+    assert_eq!(disk.sector_count(), image::DISK_SECTORS);
+    let mut sector = [0u8; SECTOR_SIZE];
+    disk.read_sectors(0, &mut sector).expect("read failed");
+    assert_eq!(&sector[..image::SIGNATURE.len()], image::SIGNATURE);
+    disk.read_sectors(image::PARTITION_START, &mut sector).expect("read failed");
+    assert_eq!(&sector[43..54], image::LABEL);
+    assert_eq!(&sector[54..62], b"FAT16   ");
+}
+
+#[test_case]
+fn partition_scan() {
+    if !attached() {
+        return;
+    }
+    let mut disk = DISKS.lock().open("ata0").expect("no ata0");
+    let table = partitions::scan(&mut disk).expect("no partition table");
+    assert_eq!(table.len(), 1);
+    assert_eq!(table[0].number, 1);
+    assert_eq!((table[0].start_lba, table[0].sectors), (image::PARTITION_START, image::PARTITION_SECTORS));
+    assert_eq!(table[0].kind, PartitionKind::Mbr(image::PARTITION_TYPE));
+    assert_eq!(DISKS.lock().volumes(), ["ata0", "ata0p1"]);
+}
+
+#[test_case]
+fn directory_walk() {
+    if !attached() {
+        return;
+    }
+    let mut fs = mount();
+    assert_eq!(fs.fat_type(), FatType::Fat16);
+    let names: Vec<String> = fs.read_dir("/").expect("root").into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["HELLO.TXT", "DOCS", "BIG.BIN", image::LONG_NAME]);
+    let docs = fs.read_dir("/DOCS").expect("DOCS");
+    assert_eq!(docs.len(), 1);
+    assert_eq!(docs[0].name, "README.TXT");
+    assert!(fs.stat("/docs").expect("stat").is_dir());
+    assert_eq!(fs.stat("/big.bin").expect("stat").size, image::BIG_SIZE as u32);
+}
+
+#[test_case]
+fn file_contents() {
+    if !attached() {
+        return;
+    }
+    let mut fs = mount();
+    assert_eq!(read_all(&mut fs, "/HELLO.TXT"), image::HELLO);
+    assert_eq!(read_all(&mut fs, "/DOCS/README.TXT"), image::README);
+    assert_eq!(read_all(&mut fs, "/long file name.txt"), image::LONG_CONTENTS);
+    let big = read_all(&mut fs, "/BIG.BIN");
+    assert!(big.iter().enumerate().all(|(i, &byte)| byte == image::big_byte(i)));
+}
+
+#[test_case]
+fn write_then_reparse() {
+    if !attached() {
+        return;
+    }
+    let mut fs = mount();
+    let free = fs.free_clusters().expect("free clusters");
+    let data: Vec<u8> = (0..1500).map(|i| (i % 251) as u8).collect();
+    let mut file = fs.create("/DOCS/Written Here.bin").expect("create");
+    assert_eq!(file.write(0, &data), Ok(data.len()));
+    drop(file);
+    fs.unmount().expect("unmount");
+
+    // nothing of the first mount survives but what reached the disk
+    let mut fs = mount();
+    assert_eq!(read_all(&mut fs, "/docs/written here.bin"), data);
+    assert_eq!(fs.free_clusters(), Ok(free - 3));
+    assert_eq!(read_all(&mut fs, "/HELLO.TXT"), image::HELLO);
+    assert_eq!(partitions::scan(&mut DISKS.lock().open("ata0").unwrap()).map(|table| table.len()), Ok(1));
+}
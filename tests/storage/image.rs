@@ -0,0 +1,26 @@
+//! Layout and contents of the storage test disk. `build.rs` writes the
+//! image from these and `tests/storage.rs` checks what the kernel reads
+//! against them, so the two cannot drift apart.
+
+/// The first bytes of the MBR's boot code, as the AHCI and virtio tests
+/// expect of any test disk.
+pub const SIGNATURE: &[u8; 16] = b"BERRYOS-TESTDISK";
+
+pub const PARTITION_TYPE: u8 = 0x06;
+pub const PARTITION_START: u64 = 2048;
+/// Enough one-sector clusters for FAT16.
+pub const PARTITION_SECTORS: u64 = 8192;
+pub const DISK_SECTORS: u64 = PARTITION_START + PARTITION_SECTORS;
+
+pub const LABEL: &[u8; 11] = b"BERRYTEST  ";
+pub const HELLO: &[u8] = b"Hello from the berryOS test disk!\n";
+pub const README: &[u8] = b"Files in DOCS live in a subdirectory cluster.\n";
+pub const LONG_NAME: &str = "Long File Name.txt";
+pub const LONG_CONTENTS: &[u8] = b"found by its long name\n";
+/// Six clusters, the last one partly used.
+pub const BIG_SIZE: usize = 3000;
+
+/// Byte `i` of BIG.BIN; no two clusters are alike.
+pub fn big_byte(i: usize) -> u8 {
+    (i * 7 + i / 512) as u8
+}
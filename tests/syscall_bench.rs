@@ -0,0 +1,56 @@
+//! Times system call round trips through `syscall` and `int 0x80`, and
+//! checks the SYSCALL path copes with processes switching under it.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use tutorial_os::process::{self, State};
+use tutorial_os::usermode::{self, demo};
+use tutorial_os::{allocator, memory, serial_print};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn compare_round_trips() {
+    let (fast, slow) = usermode::benchmark_syscalls(5).expect("benchmark failed");
+    serial_print!("(syscall {} cycles, int 0x80 {} cycles) ", fast, slow);
+    assert!(fast > 0 && slow > 0);
+}
+
+#[test_case]
+fn demo_runs_over_syscall() {
+    assert_eq!(usermode::run_demo(), Ok(demo::EXIT_CODE));
+}
+
+#[test_case]
+fn switching_processes_from_syscall() {
+    // each one gets preempted in its loop and exits through SYSCALL into
+    // the other, which then resumes through iretq
+    let programs = [demo::syscall_benchmark(true), demo::syscall_benchmark(false), demo::syscall_benchmark(true)];
+    let pids = programs.map(|program| process::spawn_from_elf(&program).unwrap());
+    assert!(process::run());
+    for pid in pids {
+        assert!(matches!(process::state(pid), Some(State::Exited(cycles)) if cycles > 0));
+    }
+}
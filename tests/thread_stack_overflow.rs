@@ -0,0 +1,66 @@
+//! Overflows a kernel thread's stack and checks the double fault finds the
+//! thread through the guard page below the stack.
+//! Runs without the test harness: getting back from the recursion at all
+//! is the failure.
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::Cr2;
+use tutorial_os::interrupts::{registers, ExceptionFrame};
+use tutorial_os::scheduler::{self, ThreadId};
+use tutorial_os::{allocator, memory};
+use tutorial_os::{boot::BootInfo, entry_point, exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+static THREAD: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("thread_stack_overflow::thread_stack_overflow...\t");
+    tutorial_os::init();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    registers::set_fatal_hook(check_double_fault);
+
+    let thread = scheduler::spawn(overflow);
+    THREAD.store(thread.id().0, Ordering::Relaxed);
+    loop {
+        scheduler::yield_now();
+        if thread.is_finished() {
+            break;
+        }
+    }
+
+    serial_println!("[no double fault]");
+    exit_qemu(QemuExitCode::Failed);
+    tutorial_os::hlt_loop();
+}
+
+fn overflow() {
+    stack_overflow();
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    // the read after the call keeps it from becoming a loop
+    volatile::Volatile::new(0).read();
+}
+
+fn check_double_fault(name: &str, _frame: &ExceptionFrame) {
+    let owner = memory::stack_guard_hit(Cr2::read()).and_then(scheduler::stack_owner);
+    if name != "DOUBLE FAULT" || owner != Some(ThreadId(THREAD.load(Ordering::Relaxed))) {
+        serial_println!("[failed]\n{} at {:?}, in the stack of {:?}", name, Cr2::read(), owner);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
@@ -0,0 +1,86 @@
+//! Runs the embedded ring-3 demo and checks its output and exit code, and
+//! that a faulting program is killed without taking the kernel down, then
+//! runs `usys` programs through the other system calls.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use tutorial_os::usermode::usys::Program;
+use tutorial_os::usermode::{self, demo, UserError};
+use tutorial_os::vga_buffer::{self, Console};
+use tutorial_os::{allocator, memory, serial_println, syscall};
+
+static OUTPUT: Mutex<String> = Mutex::new(String::new());
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    // the program's output has to reach the serial log
+    vga_buffer::set_console(Console::Both);
+    syscall::set_write_hook(|bytes| OUTPUT.lock().push_str(core::str::from_utf8(bytes).unwrap_or("<invalid>")));
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn demo_prints_and_exits() {
+    OUTPUT.lock().clear();
+    let code = usermode::run_demo().expect("demo failed");
+    serial_println!("exit code {}", code);
+    assert_eq!(code, demo::EXIT_CODE);
+    assert_eq!(OUTPUT.lock().as_str(), demo::MESSAGE);
+}
+
+#[test_case]
+fn fault_kills_only_the_program() {
+    let mut program = demo::PROGRAM;
+    // replace the first instruction with mov al, [0]
+    program[120..127].copy_from_slice(&[0x8a, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(usermode::run(&program), Err(UserError::Fault));
+    // the kernel, and the user region, survive
+    assert_eq!(usermode::run_demo(), Ok(demo::EXIT_CODE));
+}
+
+#[test_case]
+fn usys_program_writes_and_sleeps() {
+    OUTPUT.lock().clear();
+    let mut program = Program::new();
+    program.write(1, b"uno ").sleep(1_000_000).write(2, b"dos\n").exit(3);
+    assert_eq!(usermode::run(&program.build()), Ok(3));
+    assert_eq!(OUTPUT.lock().as_str(), "uno dos\n");
+}
+
+#[test_case]
+fn read_takes_what_stdin_has() {
+    assert_eq!(syscall::feed_stdin(b"abc"), 3);
+    let mut program = Program::new();
+    program.read(0, 16).exit_with_result();
+    assert_eq!(usermode::run(&program.build()), Ok(3));
+    // nothing is left, and programs do not wait for more
+    assert_eq!(usermode::run(&program.build()), Ok(syscall::errno(syscall::EAGAIN)));
+}
+
+#[test_case]
+fn spawn_reports_missing_programs() {
+    let mut program = Program::new();
+    program.spawn("/nowhere/prog").exit_with_result();
+    assert_eq!(usermode::run(&program.build()), Ok(syscall::errno(syscall::ENOENT)));
+}
@@ -0,0 +1,74 @@
+//! Reads sector 0 of the first virtio-blk disk and checks it against the
+//! test image, which must start with `EXPECTED_SIGNATURE`. The test
+//! arguments in Cargo.toml attach it as
+//! `-drive file=<image>,if=none,id=vblk -device virtio-blk-pci,drive=vblk`;
+//! without a disk the tests are reported as skipped.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use spin::Once;
+use tutorial_os::block::{BlockDevice, SECTOR_SIZE};
+use tutorial_os::virtio::blk;
+use tutorial_os::{allocator, memory, serial_println};
+
+const EXPECTED_SIGNATURE: &[u8] = b"BERRYOS-TESTDISK";
+
+static DISKS: Once<usize> = Once::new();
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    let phys_mem_offset = boot_info.physical_memory_offset();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    DISKS.call_once(|| memory::with_paging(|_, frame_allocator| blk::init(frame_allocator, phys_mem_offset)).unwrap());
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+/// Whether there is a disk to test, saying so if not.
+fn attached() -> bool {
+    let attached = DISKS.get() != Some(&0);
+    if !attached {
+        serial_println!("[skipped: no virtio disk attached]");
+    }
+    attached
+}
+
+#[test_case]
+fn read_sector_zero() {
+    if !attached() {
+        return;
+    }
+    let mut devices = blk::DEVICES.lock();
+    let disk = &mut devices[0];
+    let mut sector = [0u8; SECTOR_SIZE];
+    disk.read_sectors(0, &mut sector).expect("read failed");
+    assert_eq!(&sector[..EXPECTED_SIGNATURE.len()], EXPECTED_SIGNATURE);
+}
+
+#[test_case]
+fn out_of_range_read_is_rejected() {
+    if !attached() {
+        return;
+    }
+    let mut devices = blk::DEVICES.lock();
+    let disk = &mut devices[0];
+    let mut sector = [0u8; SECTOR_SIZE];
+    assert!(disk.read_sectors(disk.sector_count(), &mut sector).is_err());
+}
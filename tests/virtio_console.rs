@@ -0,0 +1,96 @@
+//! Runs a shell command through the virtio-console: the host types it into
+//! the port, it comes in on the receive queue and the device interrupt, and
+//! the echo and output must leave through the device. The test arguments
+//! in Cargo.toml attach it as
+//! `-device virtio-serial-pci -chardev file,id=vcon,path=target/virtio-console.out,input-path=target/virtio-console.in
+//! -device virtconsole,chardev=vcon`; build.rs writes `echo virtio-ok` into
+//! the input file, and afterwards the output file holds the echo and its
+//! output. Without the device the tests are reported as skipped.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tutorial_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use tutorial_os::{boot::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use spin::Once;
+use tutorial_os::vga_buffer::{self, Outputs};
+use tutorial_os::virtio::console;
+use tutorial_os::{allocator, memory, serial_println, time};
+
+/// What build.rs put in `virtio-console.in`.
+const COMMAND: &str = "echo virtio-ok\r";
+
+static FOUND: Once<bool> = Once::new();
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    tutorial_os::init();
+    let phys_mem_offset = boot_info.physical_memory_offset();
+    unsafe { memory::init_global(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    // the host's line can arrive as soon as the port opens
+    vga_buffer::set_outputs(Outputs::SERIAL | Outputs::VIRTIO);
+    FOUND.call_once(|| memory::with_paging(|_, frame_allocator| console::init(frame_allocator, phys_mem_offset)).unwrap());
+
+    test_main();
+    tutorial_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tutorial_os::test_panic_handler(info)
+}
+
+/// Whether there is a console to test, saying so if not.
+fn attached() -> bool {
+    let attached = FOUND.get() == Some(&true);
+    if !attached {
+        serial_println!("[skipped: no virtio-console attached]");
+    }
+    attached
+}
+
+fn counters() -> (u64, u64) {
+    console::with_console(|console| (console.bytes_received(), console.bytes_sent())).unwrap()
+}
+
+/// Halts until `done` holds or `millis` pass; returns whether it held.
+fn wait_for(millis: u64, done: impl Fn() -> bool) -> bool {
+    let deadline = time::ticks() + time::millis_to_ticks(millis);
+    while !done() {
+        if time::ticks() > deadline {
+            return false;
+        }
+        x86_64::instructions::hlt();
+    }
+    true
+}
+
+#[test_case]
+fn port_is_opened() {
+    if !attached() {
+        return;
+    }
+    assert_eq!(console::with_console(|console| console.is_open()), Some(true));
+}
+
+#[test_case]
+fn shell_runs_over_virtio() {
+    if !attached() {
+        return;
+    }
+    // the echoed command and newline, the output line and the prompt
+    let expected = ("echo virtio-ok\n".len() + "virtio-ok\n".len() + "> ".len()) as u64;
+    let arrived = wait_for(5_000, || {
+        let (received, sent) = counters();
+        received >= COMMAND.len() as u64 && sent >= expected
+    });
+    let (received, sent) = counters();
+    assert!(arrived, "{} bytes came in and {} went out", received, sent);
+}